@@ -0,0 +1,48 @@
+//! Benchmarks of the paths that go through Postgres: persisting gossip, and generating snapshots
+//! from it.
+//!
+//! These require a Postgres instance, which is configured through the same
+//! `RAPID_GOSSIP_SYNC_SERVER_DB_*` environment variables as the server itself, so they're only
+//! built with the `db-benches` feature: `cargo bench --features db-benches --bench database`.
+
+use std::time::{Duration, Instant};
+
+use criterion::{Criterion, Throughput, criterion_group, criterion_main};
+use rapid_gossip_sync_server::test_utils::bench::{BenchDatabase, network_graph};
+use rapid_gossip_sync_server::test_utils::synthetic::{SyntheticNetwork, SyntheticNetworkParams};
+
+fn persistence(c: &mut Criterion) {
+	let network = SyntheticNetwork::generate(&SyntheticNetworkParams::default());
+	let network_graph = network_graph(&network);
+
+	let mut group = c.benchmark_group("persistence");
+	group.throughput(Throughput::Elements((network.announcements.len() + network.updates.len()) as u64));
+	group.sample_size(10);
+	group.bench_function("batch_insert", |b| b.iter_custom(|iterations| {
+		let mut elapsed = Duration::ZERO;
+		for _ in 0..iterations {
+			// every iteration inserts into empty tables, which includes creating them
+			let database = BenchDatabase::new(std::sync::Arc::clone(&network_graph));
+			let started_at = Instant::now();
+			database.persist(&network.announcements, &network.updates);
+			elapsed += started_at.elapsed();
+		}
+		elapsed
+	}));
+	group.finish();
+}
+
+fn snapshot_generation(c: &mut Criterion) {
+	let network = SyntheticNetwork::generate(&SyntheticNetworkParams::default());
+	let database = BenchDatabase::new(network_graph(&network));
+	let delta_sync_timestamp = database.populate(&network);
+
+	let mut group = c.benchmark_group("snapshot_generation");
+	group.sample_size(10);
+	group.bench_function("full", |b| b.iter(|| database.generate_snapshot(0)));
+	group.bench_function("delta", |b| b.iter(|| database.generate_snapshot(delta_sync_timestamp)));
+	group.finish();
+}
+
+criterion_group!(benches, persistence, snapshot_generation);
+criterion_main!(benches);
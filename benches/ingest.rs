@@ -0,0 +1,35 @@
+//! Benchmarks of the paths gossip takes in memory: ingesting channel updates through the router,
+//! and serializing snapshots.
+//!
+//! Run with `cargo bench --bench ingest`.
+
+use criterion::{BatchSize, Criterion, Throughput, criterion_group, criterion_main};
+use rapid_gossip_sync_server::test_utils::bench::{IngestBench, serialize_full_snapshot};
+use rapid_gossip_sync_server::test_utils::synthetic::{SyntheticNetwork, SyntheticNetworkParams};
+
+fn ingest(c: &mut Criterion) {
+	let network = SyntheticNetwork::generate(&SyntheticNetworkParams::default());
+	let ingest_bench = IngestBench::new(&network);
+
+	let mut group = c.benchmark_group("ingest");
+	group.throughput(Throughput::Elements(network.updates.len() as u64));
+	group.sample_size(10);
+	group.bench_function("channel_updates", |b| b.iter_batched(
+		|| ingest_bench.router(),
+		|router| assert_eq!(router.ingest(&network.updates), network.updates.len()),
+		BatchSize::PerIteration,
+	));
+	group.finish();
+}
+
+fn serialization(c: &mut Criterion) {
+	let network = SyntheticNetwork::generate(&SyntheticNetworkParams::default());
+
+	let mut group = c.benchmark_group("serialization");
+	group.throughput(Throughput::Elements(network.announcements.len() as u64));
+	group.bench_function("full_snapshot", |b| b.iter(|| serialize_full_snapshot(&network)));
+	group.finish();
+}
+
+criterion_group!(benches, ingest, serialization);
+criterion_main!(benches);
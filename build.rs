@@ -0,0 +1,57 @@
+//! Generates the Markdown description of the snapshot format from the documented types in
+//! src/snapshot_format.rs.
+
+use std::env;
+use std::fs;
+use std::path::Path;
+
+const FORMAT_SOURCE: &str = "src/snapshot_format.rs";
+
+fn main() {
+	println!("cargo:rerun-if-changed={}", FORMAT_SOURCE);
+
+	let source = fs::read_to_string(FORMAT_SOURCE).expect("Failed to read snapshot format source");
+	let markdown = generate_markdown(&source);
+
+	let output_path = Path::new(&env::var("OUT_DIR").unwrap()).join("snapshot_format.md");
+	fs::write(output_path, markdown).expect("Failed to write snapshot format description");
+}
+
+fn generate_markdown(source: &str) -> String {
+	let mut output = String::from("# Snapshot Format\n");
+	let mut pending_docs: Vec<String> = Vec::new();
+	let mut is_in_struct = false;
+
+	for line in source.lines() {
+		let line = line.trim();
+		if let Some(doc) = line.strip_prefix("///") {
+			pending_docs.push(doc.trim().to_string());
+			continue;
+		}
+
+		if let Some(struct_name) = line.strip_prefix("pub(crate) struct ").and_then(|rest| rest.strip_suffix(" {")) {
+			output.push_str(&format!("\n## {}\n\n", struct_name));
+			output.push_str(&format!("{}\n\n", strip_intra_doc_links(&pending_docs.join(" "))));
+			output.push_str("| Field | Type | Description |\n| --- | --- | --- |\n");
+			is_in_struct = true;
+		} else if is_in_struct && line == "}" {
+			is_in_struct = false;
+		} else if is_in_struct {
+			if let Some((field_name, field_type)) = line.strip_suffix(',').and_then(|field| field.split_once(": ")) {
+				let description = strip_intra_doc_links(&pending_docs.join(" "));
+				output.push_str(&format!("| `{}` | `{}` | {} |\n", field_name, field_type, description));
+			}
+		}
+
+		if !line.starts_with("#[") {
+			pending_docs.clear();
+		}
+	}
+
+	output
+}
+
+/// Turn rustdoc links like [`Header`] into plain code spans
+fn strip_intra_doc_links(doc: &str) -> String {
+	doc.replace("[`", "`").replace("`]", "`")
+}
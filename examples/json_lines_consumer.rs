@@ -0,0 +1,50 @@
+//! Runs the server as usual, while also appending every gossip message it persists to a file as a
+//! line of JSON, as a starting point for teeing the gossip stream into other pipelines.
+//!
+//! Usage: cargo run --example json_lines_consumer -- <path>
+
+use std::env;
+use std::fs::OpenOptions;
+use std::io::{BufWriter, Write};
+use std::process;
+use std::sync::Arc;
+
+use rapid_gossip_sync_server::{GossipEvent, OverflowPolicy, RapidSyncProcessor};
+use rapid_gossip_sync_server::types::RGSSLogger;
+
+/// Enough to ride out the file system stalling for a moment during the initial sync
+const CONSUMER_CAPACITY: usize = 100_000;
+
+#[tokio::main]
+async fn main() {
+	let path = env::args().nth(1).unwrap_or_else(|| {
+		eprintln!("Usage: json_lines_consumer <path>");
+		process::exit(2);
+	});
+	let file = OpenOptions::new().create(true).append(true).open(&path).unwrap_or_else(|error| {
+		eprintln!("Failed to open {}: {}", path, error);
+		process::exit(1);
+	});
+
+	let logger = Arc::new(RGSSLogger::new());
+	let mut processor = RapidSyncProcessor::new(logger);
+	// an analytics pipeline falling behind shouldn't hold up the server, so it rather misses messages
+	let mut consumer = processor.add_gossip_consumer(CONSUMER_CAPACITY, OverflowPolicy::Drop);
+
+	tokio::spawn(async move {
+		let mut output = BufWriter::new(file);
+		while let Some(event) = consumer.recv().await {
+			let line = match event {
+				GossipEvent::Message(message) => serde_json::to_string(&message).expect("gossip messages serialize to JSON"),
+				GossipEvent::InitialSyncCompleted => format!("{{\"type\":\"initial_sync_completed\",\"dropped\":{}}}", consumer.dropped_count()),
+			};
+			if let Err(error) = writeln!(output, "{}", line).and_then(|_| output.flush()) {
+				eprintln!("Failed to write to {}: {}", path, error);
+				return;
+			}
+		}
+	});
+
+	let result = processor.start_sync().await;
+	rapid_gossip_sync_server::exit_after_stopping(result);
+}
@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io;
+use std::ops::Deref;
+
+use arrow2::array::{Array, BooleanArray, PrimitiveArray, Utf8Array};
+use arrow2::chunk::Chunk;
+use arrow2::datatypes::{DataType, Field, Schema};
+use arrow2::io::parquet::write::{transverse, Encoding, FileWriter, RowGroupIterator, WriteOptions};
+use lightning::ln::msgs::DecodeError;
+use lightning::routing::gossip::NetworkGraph;
+use lightning::util::logger::Logger;
+use parquet2::compression::CompressionOptions;
+use parquet2::write::Version;
+use thiserror::Error;
+
+use crate::reachability::ReachabilityScore;
+
+/// Everything that can prevent the network graph from being exported
+#[derive(Debug, Error)]
+pub enum ExportError {
+	/// The cached network graph or the export file could not be accessed
+	#[error("failed to access {path}: {source}")]
+	Io {
+		path: String,
+		#[source]
+		source: io::Error,
+	},
+	/// The cached network graph could not be decoded
+	#[error("failed to decode the network graph cache {path}: {error}")]
+	InvalidGraph {
+		path: String,
+		error: DecodeError,
+	},
+	/// The graph could not be encoded as Parquet
+	#[error("failed to encode the network graph as parquet: {0}")]
+	Encoding(#[from] arrow2::error::Error),
+}
+
+/// The exported graph in columnar form, with one row per channel direction. `node1` is the node
+/// forwarding payments in that direction, and `node2` the one receiving them, so the same channel
+/// appears twice with the nodes swapped. The update columns are null for directions without a
+/// known channel update, and the reachability scores are null if they couldn't be calculated.
+#[derive(Debug, Default, PartialEq)]
+pub(crate) struct ChannelDirectionColumns {
+	pub(crate) scid: Vec<u64>,
+	pub(crate) node1: Vec<String>,
+	pub(crate) node2: Vec<String>,
+	pub(crate) capacity_sats: Vec<Option<u64>>,
+	pub(crate) last_update_ts: Vec<Option<u32>>,
+	pub(crate) base_fee_msat: Vec<Option<u32>>,
+	pub(crate) fee_rate_ppm: Vec<Option<u32>>,
+	pub(crate) htlc_max_msat: Vec<Option<u64>>,
+	pub(crate) is_disabled: Vec<Option<bool>>,
+	pub(crate) reachability_score: Vec<Option<f64>>,
+}
+
+pub(crate) fn collect_channel_directions<L: Deref>(graph: &NetworkGraph<L>, reachability: Option<&HashMap<(u64, bool), ReachabilityScore>>) -> ChannelDirectionColumns where L::Target: Logger {
+	let mut columns = ChannelDirectionColumns::default();
+	let read_only_graph = graph.read_only();
+	// iterate in scid order, so that repeated exports of the same graph are identical
+	for (scid, channel) in read_only_graph.channels().range(..) {
+		let directions = [
+			(&channel.node_one, &channel.node_two, &channel.one_to_two, false),
+			(&channel.node_two, &channel.node_one, &channel.two_to_one, true),
+		];
+		for (source, destination, update, direction) in directions {
+			columns.scid.push(*scid);
+			columns.node1.push(source.to_string());
+			columns.node2.push(destination.to_string());
+			columns.capacity_sats.push(channel.capacity_sats);
+			columns.last_update_ts.push(update.as_ref().map(|update| update.last_update));
+			columns.base_fee_msat.push(update.as_ref().map(|update| update.fees.base_msat));
+			columns.fee_rate_ppm.push(update.as_ref().map(|update| update.fees.proportional_millionths));
+			columns.htlc_max_msat.push(update.as_ref().map(|update| update.htlc_maximum_msat));
+			columns.is_disabled.push(update.as_ref().map(|update| !update.enabled));
+			// directions without any recent updates aren't scored, and are entirely unreachable
+			columns.reachability_score.push(reachability.map(|scores| scores.get(&(*scid, direction)).map_or(0.0, |score| score.score)));
+		}
+	}
+	columns
+}
+
+fn export_schema() -> Schema {
+	Schema::from(vec![
+		Field::new("scid", DataType::UInt64, false),
+		Field::new("node1", DataType::Utf8, false),
+		Field::new("node2", DataType::Utf8, false),
+		Field::new("capacity_sats", DataType::UInt64, true),
+		Field::new("last_update_ts", DataType::UInt32, true),
+		Field::new("base_fee_msat", DataType::UInt32, true),
+		Field::new("fee_rate_ppm", DataType::UInt32, true),
+		Field::new("htlc_max_msat", DataType::UInt64, true),
+		Field::new("is_disabled", DataType::Boolean, true),
+		Field::new("reachability_score", DataType::Float64, true),
+	])
+}
+
+/// Write the channels of the network graph to a Parquet file at `path`, so that the graph can be
+/// analyzed without running a Lightning node or parsing snapshots
+pub(crate) fn export_parquet<L: Deref>(graph: &NetworkGraph<L>, reachability: Option<&HashMap<(u64, bool), ReachabilityScore>>, path: &str) -> Result<(), ExportError> where L::Target: Logger {
+	let columns = collect_channel_directions(graph, reachability);
+	let chunk = Chunk::try_new(vec![
+		PrimitiveArray::<u64>::from_vec(columns.scid).boxed(),
+		Utf8Array::<i32>::from_slice(&columns.node1).boxed(),
+		Utf8Array::<i32>::from_slice(&columns.node2).boxed(),
+		PrimitiveArray::<u64>::from(columns.capacity_sats).boxed(),
+		PrimitiveArray::<u32>::from(columns.last_update_ts).boxed(),
+		PrimitiveArray::<u32>::from(columns.base_fee_msat).boxed(),
+		PrimitiveArray::<u32>::from(columns.fee_rate_ppm).boxed(),
+		PrimitiveArray::<u64>::from(columns.htlc_max_msat).boxed(),
+		BooleanArray::from(columns.is_disabled).boxed(),
+		PrimitiveArray::<f64>::from(columns.reachability_score).boxed(),
+	] as Vec<Box<dyn Array>>)?;
+
+	let schema = export_schema();
+	let options = WriteOptions {
+		write_statistics: true,
+		compression: CompressionOptions::Snappy,
+		version: Version::V2,
+		data_pagesize_limit: None,
+	};
+	let encodings = schema.fields.iter().map(|field| transverse(&field.data_type, |_| Encoding::Plain)).collect();
+	let row_groups = RowGroupIterator::try_new(std::iter::once(Ok(chunk)), &schema, options, encodings)?;
+
+	// write to a pending file first so a failed export never leaves a truncated file behind
+	let pending_path = format!("{}.pending", path);
+	let file = File::create(&pending_path).map_err(|source| ExportError::Io { path: pending_path.clone(), source })?;
+	let mut writer = FileWriter::try_new(file, schema, options)?;
+	for row_group in row_groups {
+		writer.write(row_group?)?;
+	}
+	writer.end(None)?;
+	fs::rename(&pending_path, path).map_err(|source| ExportError::Io { path: path.to_string(), source })?;
+	Ok(())
+}
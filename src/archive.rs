@@ -0,0 +1,383 @@
+use std::cmp;
+use std::collections::{BTreeMap, BTreeSet};
+use std::io::Cursor;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+
+use bitcoin::blockdata::constants::ChainHash;
+use bitcoin::secp256k1::PublicKey;
+use lightning::{io, log_debug, log_warn};
+use lightning::events::MessageSendEvent;
+use lightning::ln::msgs::{ChannelAnnouncement, ChannelUpdate, NodeAnnouncement, QueryChannelRange, QueryShortChannelIds, ReplyChannelRange, ReplyShortChannelIdsEnd};
+use lightning::ln::wire::Type;
+use lightning::routing::gossip::{NetworkGraph, NodeId};
+use lightning::util::logger::Logger;
+use lightning::util::ser::{Readable, Writeable, Writer};
+use tokio::sync::Semaphore;
+
+use crate::config;
+use crate::error::{ErrorContext, ProcessorError};
+use crate::tables::Tables;
+use crate::types::GossipPeerManager;
+
+/// The maximum number of short channel ids in a single reply_channel_range, matching LDK's
+const MAX_SCIDS_PER_REPLY: usize = 8000;
+/// The highest block height a short channel id can encode
+const MAX_SCID_BLOCK: u32 = 0x00ffffff;
+/// BOLT 7 message types of the replies LDK can't send itself
+const NODE_ANNOUNCEMENT_TYPE: u16 = 257;
+const REPLY_SHORT_CHANNEL_IDS_END_TYPE: u16 = 262;
+
+/// A channel announcement along with the latest update in each direction
+type ArchivedChannel = (ChannelAnnouncement, Option<ChannelUpdate>, Option<ChannelUpdate>);
+
+/// Replies to be sent to individual peers, which LDK only has message send events for
+/// broadcasting, if at all
+pub(crate) type QueryReplyQueue = Arc<Mutex<Vec<(PublicKey, QueryReply)>>>;
+
+/// Gossip query replies that are sent as custom messages, which the peer manager writes out with
+/// their regular message types
+#[derive(Debug)]
+pub(crate) enum QueryReply {
+	NodeAnnouncement(NodeAnnouncement),
+	ShortChannelIdsEnd(ReplyShortChannelIdsEnd),
+}
+
+impl Writeable for QueryReply {
+	fn write<W: Writer>(&self, writer: &mut W) -> Result<(), io::Error> {
+		match self {
+			QueryReply::NodeAnnouncement(announcement) => announcement.write(writer),
+			QueryReply::ShortChannelIdsEnd(end) => end.write(writer),
+		}
+	}
+}
+
+impl Type for QueryReply {
+	fn type_id(&self) -> u16 {
+		match self {
+			QueryReply::NodeAnnouncement(_) => NODE_ANNOUNCEMENT_TYPE,
+			QueryReply::ShortChannelIdsEnd(_) => REPLY_SHORT_CHANNEL_IDS_END_TYPE,
+		}
+	}
+}
+
+/// Answers peers' gossip queries from the database as well as the network graph, so that channels
+/// the graph has already forgotten about are still served.
+///
+/// Database lookups run in the background, and their replies are queued up until the peer manager
+/// next collects pending messages. To protect ingestion, only a limited number of queries is
+/// answered from the database at once, and any queries beyond that are left to the graph alone.
+pub(crate) struct ArchivalResponder<L: Deref + Clone + Send + Sync + 'static> where L::Target: Logger {
+	network_graph: Arc<NetworkGraph<L>>,
+	chain_hash: ChainHash,
+	permits: Arc<Semaphore>,
+	pending_events: Arc<Mutex<Vec<MessageSendEvent>>>,
+	/// Whether query_short_channel_ids replies include node announcements and the closing
+	/// reply_short_channel_ids_end, as BOLT 7 requires
+	complete_replies: bool,
+	query_replies: QueryReplyQueue,
+	peer_handler: Mutex<Option<GossipPeerManager<L>>>,
+	logger: L,
+}
+
+impl<L: Deref + Clone + Send + Sync + 'static> ArchivalResponder<L> where L::Target: Logger {
+	pub(crate) fn new(network_graph: Arc<NetworkGraph<L>>, logger: L) -> Self {
+		Self {
+			network_graph,
+			chain_hash: ChainHash::using_genesis_block(config::network()),
+			permits: Arc::new(Semaphore::new(config::archival_query_concurrency())),
+			pending_events: Arc::new(Mutex::new(Vec::new())),
+			complete_replies: config::respond_to_queries_enabled(),
+			query_replies: Arc::new(Mutex::new(Vec::new())),
+			peer_handler: Mutex::new(None),
+			logger,
+		}
+	}
+
+	pub(crate) fn set_ph(&self, peer_handler: GossipPeerManager<L>) {
+		*self.peer_handler.lock().expect("peer handler lock poisoned") = Some(peer_handler);
+	}
+
+	/// The queue of replies to be sent as custom messages by the peer manager's custom message
+	/// handler
+	pub(crate) fn query_replies(&self) -> QueryReplyQueue {
+		Arc::clone(&self.query_replies)
+	}
+
+	pub(crate) fn get_and_clear_pending_msg_events(&self) -> Vec<MessageSendEvent> {
+		std::mem::take(&mut *self.pending_events.lock().expect("archival event lock poisoned"))
+	}
+
+	/// Reply with the channels known to either the graph or the database, returning false if the
+	/// query is left to the graph alone, because it's invalid or too many are in flight already.
+	pub(crate) fn answer_channel_range(&self, their_node_id: &PublicKey, msg: &QueryChannelRange) -> bool {
+		if msg.chain_hash != self.chain_hash || msg.number_of_blocks == 0 || msg.first_blocknum > MAX_SCID_BLOCK {
+			// the graph replies to invalid queries just as well
+			return false;
+		}
+		let permit = match Arc::clone(&self.permits).try_acquire_owned() {
+			Ok(permit) => permit,
+			Err(_) => return false,
+		};
+
+		let start_scid = (msg.first_blocknum as u64) << 40;
+		let end_scid = (cmp::min(msg.end_blocknum(), MAX_SCID_BLOCK) as u64) << 40;
+		let mut short_channel_ids: BTreeSet<u64> = self.network_graph.read_only().channels().unordered_iter()
+			.filter(|(short_channel_id, channel)| (start_scid..end_scid).contains(*short_channel_id) && channel.announcement_message.is_some())
+			.map(|(short_channel_id, _)| *short_channel_id)
+			.collect();
+
+		let their_node_id = *their_node_id;
+		let msg = msg.clone();
+		let pending_events = Arc::clone(&self.pending_events);
+		let peer_handler = self.peer_handler.lock().expect("peer handler lock poisoned").clone();
+		let logger = self.logger.clone();
+		tokio::spawn(async move {
+			match fetch_short_channel_ids(start_scid, end_scid).await {
+				Ok(archived_short_channel_ids) => short_channel_ids.extend(archived_short_channel_ids),
+				Err(error) => log_warn!(logger, "Failed to look up archived channels, replying from the graph alone: {}", error),
+			}
+			drop(permit);
+
+			log_debug!(logger, "Replying to query_channel_range from {} with {} channels", their_node_id, short_channel_ids.len());
+			let replies = channel_range_replies(&msg, short_channel_ids.into_iter().collect());
+			pending_events.lock().expect("archival event lock poisoned").extend(replies.into_iter()
+				.map(|reply| MessageSendEvent::SendReplyChannelRange { node_id: their_node_id, msg: reply }));
+			if let Some(peer_handler) = peer_handler { peer_handler.process_events(); }
+		});
+		true
+	}
+
+	/// Send the announcement and latest updates of each of the requested channels, from the graph
+	/// if it still knows the channel, and from the database otherwise. Returns false if the query
+	/// can't be answered right now.
+	///
+	/// LDK has no way of sending the closing reply_short_channel_ids_end, or node announcements to
+	/// a single peer, so unless complete replies are enabled, they're omitted, just as the query
+	/// would go unanswered entirely without this. Complete replies are instead sent as custom
+	/// messages, and are given from the graph alone rather than not at all while too many queries
+	/// are being looked up.
+	pub(crate) fn answer_short_channel_ids(&self, their_node_id: &PublicKey, msg: &QueryShortChannelIds) -> bool {
+		if msg.chain_hash != self.chain_hash {
+			if !self.complete_replies {
+				return false;
+			}
+			// we don't know anything about other chains
+			let end = ReplyShortChannelIdsEnd { chain_hash: msg.chain_hash, full_information: false };
+			self.query_replies.lock().expect("query reply lock poisoned").push((*their_node_id, QueryReply::ShortChannelIdsEnd(end)));
+			if let Some(peer_handler) = self.peer_handler.lock().expect("peer handler lock poisoned").as_ref() { peer_handler.process_events(); }
+			return true;
+		}
+		let permit = Arc::clone(&self.permits).try_acquire_owned().ok();
+		if permit.is_none() && !self.complete_replies {
+			return false;
+		}
+
+		let mut channels = BTreeMap::new();
+		let mut archived_short_channel_ids = Vec::new();
+		{
+			let read_only_graph = self.network_graph.read_only();
+			for short_channel_id in &msg.short_channel_ids {
+				let channel = read_only_graph.channel(*short_channel_id);
+				match channel.and_then(|channel| channel.announcement_message.clone().map(|announcement| (channel, announcement))) {
+					Some((channel, announcement)) => {
+						let updates = [&channel.one_to_two, &channel.two_to_one]
+							.map(|direction| direction.as_ref().and_then(|info| info.last_update_message.clone()));
+						channels.insert(*short_channel_id, (announcement, updates[0].clone(), updates[1].clone()));
+					},
+					None => archived_short_channel_ids.push(*short_channel_id),
+				}
+			}
+		}
+
+		let their_node_id = *their_node_id;
+		let requested_count = msg.short_channel_ids.len();
+		let chain_hash = self.chain_hash;
+		let complete_replies = self.complete_replies;
+		let network_graph = Arc::clone(&self.network_graph);
+		let pending_events = Arc::clone(&self.pending_events);
+		let query_replies = Arc::clone(&self.query_replies);
+		let peer_handler = self.peer_handler.lock().expect("peer handler lock poisoned").clone();
+		let logger = self.logger.clone();
+		tokio::spawn(async move {
+			if permit.is_some() && !archived_short_channel_ids.is_empty() {
+				match fetch_archived_channels(&archived_short_channel_ids).await {
+					Ok(archived_channels) => channels.extend(archived_channels),
+					Err(error) => log_warn!(logger, "Failed to look up archived channels, replying from the graph alone: {}", error),
+				}
+			}
+
+			let mut node_announcements = Vec::new();
+			if complete_replies {
+				let node_ids: BTreeSet<NodeId> = channels.values()
+					.flat_map(|(announcement, _, _)| [announcement.contents.node_id_1, announcement.contents.node_id_2])
+					.collect();
+				let mut archived_node_ids = Vec::new();
+				{
+					let read_only_graph = network_graph.read_only();
+					for node_id in node_ids {
+						let announcement = read_only_graph.node(&node_id)
+							.and_then(|node| node.announcement_info.as_ref())
+							.and_then(|info| info.announcement_message.clone());
+						match announcement {
+							Some(announcement) => node_announcements.push(announcement),
+							None => archived_node_ids.push(node_id),
+						}
+					}
+				}
+				if permit.is_some() && !archived_node_ids.is_empty() {
+					match fetch_archived_node_announcements(&archived_node_ids).await {
+						Ok(archived_announcements) => node_announcements.extend(archived_announcements),
+						Err(error) => log_warn!(logger, "Failed to look up archived node announcements, replying from the graph alone: {}", error),
+					}
+				}
+			}
+			drop(permit);
+
+			log_debug!(logger, "Replying to query_short_channel_ids from {} with {} of {} channels and {} nodes", their_node_id, channels.len(), requested_count, node_announcements.len());
+			{
+				let mut pending_events = pending_events.lock().expect("archival event lock poisoned");
+				for (announcement, update_1, update_2) in channels.into_values() {
+					// an announcement can only be sent to a single peer along with an update
+					let (first_update, second_update) = match (update_1, update_2) {
+						(Some(update_1), update_2) => (update_1, update_2),
+						(None, Some(update_2)) => (update_2, None),
+						(None, None) => continue,
+					};
+					pending_events.push(MessageSendEvent::SendChannelAnnouncement { node_id: their_node_id, msg: announcement, update_msg: first_update });
+					if let Some(second_update) = second_update {
+						pending_events.push(MessageSendEvent::SendChannelUpdate { node_id: their_node_id, msg: second_update });
+					}
+				}
+			}
+			if complete_replies {
+				// the peer manager sends custom messages after all pending events, so these are
+				// queued second to follow the channels they refer to
+				let mut query_replies = query_replies.lock().expect("query reply lock poisoned");
+				query_replies.extend(node_announcements.into_iter().map(|announcement| (their_node_id, QueryReply::NodeAnnouncement(announcement))));
+				query_replies.push((their_node_id, QueryReply::ShortChannelIdsEnd(ReplyShortChannelIdsEnd { chain_hash, full_information: true })));
+			}
+			if let Some(peer_handler) = peer_handler { peer_handler.process_events(); }
+		});
+		true
+	}
+}
+
+/// Split the short channel ids, which must be sorted, into replies the way LDK does, which
+/// includes working around older c-lightning versions' expectations of the block ranges.
+pub(crate) fn channel_range_replies(msg: &QueryChannelRange, short_channel_ids: Vec<u64>) -> Vec<ReplyChannelRange> {
+	let mut batches: Vec<Vec<u64>> = short_channel_ids.chunks(MAX_SCIDS_PER_REPLY).map(|batch| batch.to_vec()).collect();
+	if batches.is_empty() {
+		batches.push(Vec::new());
+	}
+
+	let batch_count = batches.len();
+	let mut previous_batch_end_block = msg.first_blocknum;
+	batches.into_iter().enumerate().map(|(batch_index, batch)| {
+		let first_blocknum = previous_batch_end_block;
+		let (sync_complete, number_of_blocks) = if batch_index == batch_count - 1 {
+			(true, msg.end_blocknum() - first_blocknum)
+		} else {
+			(false, (*batch.last().expect("batches are never empty") >> 40) as u32 - first_blocknum)
+		};
+		previous_batch_end_block = first_blocknum + number_of_blocks;
+		ReplyChannelRange { chain_hash: msg.chain_hash, first_blocknum, number_of_blocks, sync_complete, short_channel_ids: batch }
+	}).collect()
+}
+
+async fn fetch_short_channel_ids(start_scid: u64, end_scid: u64) -> Result<Vec<u64>, ProcessorError> {
+	let client = crate::connect_to_db().await?;
+	let tables = Tables::from_config();
+	let rows = client.query(&format!("SELECT short_channel_id FROM {} WHERE short_channel_id >= $1 AND short_channel_id < $2",
+		tables.channel_announcements()), &[&(start_scid as i64), &(end_scid as i64)]).await.context("Failed to fetch archived short channel ids")?;
+	Ok(rows.iter().map(|row| row.get::<_, i64>("short_channel_id") as u64).collect())
+}
+
+/// Reconstruct the requested channels' messages from their persisted signed serializations
+async fn fetch_archived_channels(short_channel_ids: &[u64]) -> Result<BTreeMap<u64, ArchivedChannel>, ProcessorError> {
+	let client = crate::connect_to_db().await?;
+	let tables = Tables::from_config();
+	let short_channel_ids: Vec<i64> = short_channel_ids.iter().map(|short_channel_id| *short_channel_id as i64).collect();
+
+	let mut channels = BTreeMap::new();
+	let announcement_rows = client.query(&format!("SELECT announcement_signed FROM {} WHERE short_channel_id = any($1)",
+		tables.channel_announcements()), &[&short_channel_ids]).await.context("Failed to fetch archived channel announcements")?;
+	for row in announcement_rows {
+		let blob: Vec<u8> = row.get("announcement_signed");
+		let announcement = ChannelAnnouncement::read(&mut Cursor::new(blob)).context("Failed to decode persisted channel announcement")?;
+		channels.insert(announcement.contents.short_channel_id, (announcement, None, None));
+	}
+
+	let update_rows = client.query(&format!("
+		SELECT DISTINCT ON (short_channel_id, direction) blob_signed
+		FROM {}
+		WHERE short_channel_id = any($1)
+		ORDER BY short_channel_id ASC, direction ASC, timestamp DESC
+		", tables.channel_updates()), &[&short_channel_ids]).await.context("Failed to fetch archived channel updates")?;
+	for row in update_rows {
+		let blob: Vec<u8> = row.get("blob_signed");
+		let update = ChannelUpdate::read(&mut Cursor::new(blob)).context("Failed to decode persisted channel update")?;
+		if let Some((_, update_1, update_2)) = channels.get_mut(&update.contents.short_channel_id) {
+			if update.contents.flags & 1 == 0 {
+				*update_1 = Some(update);
+			} else {
+				*update_2 = Some(update);
+			}
+		}
+	}
+	Ok(channels)
+}
+
+/// Reconstruct the latest persisted announcements of the given nodes
+async fn fetch_archived_node_announcements(node_ids: &[NodeId]) -> Result<Vec<NodeAnnouncement>, ProcessorError> {
+	let client = crate::connect_to_db().await?;
+	let tables = Tables::from_config();
+	let public_keys: Vec<&[u8]> = node_ids.iter().map(|node_id| node_id.as_slice()).collect();
+
+	let rows = client.query(&format!("
+		SELECT DISTINCT ON (public_key) announcement_signed
+		FROM {}
+		WHERE public_key = any($1) AND announcement_signed IS NOT NULL
+		ORDER BY public_key ASC, timestamp DESC
+		", tables.node_announcements()), &[&public_keys]).await.context("Failed to fetch archived node announcements")?;
+	rows.into_iter().map(|row| {
+		let blob: Vec<u8> = row.get("announcement_signed");
+		NodeAnnouncement::read(&mut Cursor::new(blob)).context("Failed to decode persisted node announcement")
+	}).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use bitcoin::Network;
+
+	use super::*;
+
+	fn query(first_blocknum: u32, number_of_blocks: u32) -> QueryChannelRange {
+		QueryChannelRange { chain_hash: ChainHash::using_genesis_block(Network::Bitcoin), first_blocknum, number_of_blocks }
+	}
+
+	#[test]
+	fn test_query_reply_encoding() {
+		let end = ReplyShortChannelIdsEnd { chain_hash: ChainHash::using_genesis_block(Network::Bitcoin), full_information: true };
+		let reply = QueryReply::ShortChannelIdsEnd(end.clone());
+		// the peer manager writes the type, so the payload must be the message alone
+		assert_eq!(reply.type_id(), 262);
+		assert_eq!(reply.encode(), end.encode());
+	}
+
+	#[test]
+	fn test_channel_range_replies() {
+		let replies = channel_range_replies(&query(100, 50), vec![]);
+		assert_eq!(replies.len(), 1);
+		assert_eq!((replies[0].first_blocknum, replies[0].number_of_blocks, replies[0].sync_complete), (100, 50, true));
+
+		// the replies' block ranges must be contiguous and cover the entire query
+		let short_channel_ids: Vec<u64> = (0..MAX_SCIDS_PER_REPLY as u64 + 10).map(|index| (110 + index / 1000) << 40 | index).collect();
+		let replies = channel_range_replies(&query(100, 50), short_channel_ids.clone());
+		assert_eq!(replies.len(), 2);
+		assert_eq!((replies[0].first_blocknum, replies[0].number_of_blocks, replies[0].sync_complete), (100, 17, false));
+		assert_eq!((replies[1].first_blocknum, replies[1].number_of_blocks, replies[1].sync_complete), (117, 33, true));
+		let replied_short_channel_ids: Vec<u64> = replies.into_iter().flat_map(|reply| reply.short_channel_ids).collect();
+		assert_eq!(replied_short_channel_ids, short_channel_ids);
+	}
+}
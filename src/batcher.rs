@@ -0,0 +1,98 @@
+use std::mem;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+use crate::config;
+use crate::error::ProcessorError;
+use crate::gossip_consumers::GossipConsumers;
+use crate::types::GossipMessage;
+
+/// Accumulates gossip messages and forwards them to the persister in batches, either once the
+/// batch size is reached, or once the flush interval has elapsed, whichever happens first.
+pub(crate) struct MessageBatcher {
+	/// Pending messages, and when each of them was received
+	buffer: Mutex<(Vec<GossipMessage>, Vec<Instant>)>,
+	sender: mpsc::Sender<GossipMessage>,
+	/// Receive every message as it's added, in the order batches hold them in
+	consumers: Option<Arc<GossipConsumers>>,
+	batch_size: usize,
+	flush_interval: Duration,
+}
+
+impl MessageBatcher {
+	pub(crate) fn new(sender: mpsc::Sender<GossipMessage>) -> Self {
+		Self::with_limits(sender, config::batch_size(), config::batch_flush_interval())
+	}
+
+	pub(crate) fn with_limits(sender: mpsc::Sender<GossipMessage>, batch_size: usize, flush_interval: Duration) -> Self {
+		Self {
+			buffer: Mutex::new((Vec::with_capacity(batch_size), Vec::with_capacity(batch_size))),
+			sender,
+			consumers: None,
+			batch_size,
+			flush_interval,
+		}
+	}
+
+	pub(crate) fn set_consumers(&mut self, consumers: Arc<GossipConsumers>) {
+		self.consumers = Some(consumers);
+	}
+
+	/// Add a message to the current batch, forwarding the batch if it's full.
+	///
+	/// This must be called from within a multi-threaded Tokio runtime, as it may block on the
+	/// persistence channel having capacity.
+	pub(crate) fn push(&self, message: GossipMessage, received_at: Instant) {
+		let (full_batch, receipt_times) = {
+			let mut buffer = self.buffer.lock().expect("batch buffer lock poisoned");
+			// publishing under the lock keeps the consumers' order that of the batches
+			if let Some(consumers) = &self.consumers {
+				consumers.publish(&message);
+			}
+			buffer.0.push(message);
+			buffer.1.push(received_at);
+			if buffer.0.len() < self.batch_size {
+				return;
+			}
+			mem::replace(&mut *buffer, (Vec::with_capacity(self.batch_size), Vec::with_capacity(self.batch_size)))
+		};
+
+		let gossip_message = GossipMessage::Batch(full_batch, receipt_times);
+		if let Err(err) = self.sender.try_send(gossip_message) {
+			let gossip_message = match err { TrySendError::Full(msg)|TrySendError::Closed(msg) => msg };
+			tokio::task::block_in_place(move || { tokio::runtime::Handle::current().block_on(async move {
+				// the persister only stops upon failing, which is reported to the caller of
+				// `start_sync`, so there is nowhere left to send the batch to
+				let _ = self.sender.send(gossip_message).await;
+			})});
+		}
+	}
+
+	/// Forward whatever has accumulated so far, if anything.
+	pub(crate) async fn flush(&self) -> Result<(), ProcessorError> {
+		let (pending_batch, receipt_times) = {
+			let mut buffer = self.buffer.lock().expect("batch buffer lock poisoned");
+			if buffer.0.is_empty() {
+				return Ok(());
+			}
+			mem::replace(&mut *buffer, (Vec::with_capacity(self.batch_size), Vec::with_capacity(self.batch_size)))
+		};
+		self.sender.send(GossipMessage::Batch(pending_batch, receipt_times)).await
+			.map_err(|_| ProcessorError::ChannelClosed("gossip persistence"))
+	}
+
+	/// Flush partial batches at the configured interval, so that no message is delayed longer than
+	/// that while gossip is trickling in slowly. Stops once the persister does.
+	pub(crate) async fn flush_periodically(batcher: Arc<Self>) {
+		let mut interval = tokio::time::interval(batcher.flush_interval);
+		loop {
+			interval.tick().await;
+			if batcher.flush().await.is_err() {
+				return;
+			}
+		}
+	}
+}
@@ -0,0 +1,77 @@
+//! Prints the records of a gossip archive, one per line as the unix timestamp of their receipt, the
+//! message type, and the hex-encoded wire message, optionally filtered by time range and message
+//! type. Exits with 1 if the archive can't be read, and 2 if the arguments are invalid.
+
+use std::env;
+use std::fs::File;
+use std::io::{self, BufReader, Write};
+use std::process;
+
+use hex_conservative::display::DisplayHex;
+use rapid_gossip_sync_server::{ArchiveReader, ArchivedMessageType};
+
+const USAGE: &str = "Usage: rgs-archive-reader --file <path> [--since <unix timestamp>] [--until <unix timestamp>] [--type <channel_announcement|node_announcement|channel_update>]";
+
+const EXIT_UNREADABLE: i32 = 1;
+const EXIT_USAGE: i32 = 2;
+
+fn main() {
+	let mut file = None;
+	let mut since = 0;
+	let mut until = u32::MAX;
+	let mut message_type = None;
+	let mut args = env::args().skip(1);
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"--file" => file = args.next(),
+			"--since" => since = args.next().and_then(|timestamp| timestamp.parse::<u32>().ok()).unwrap_or_else(|| exit_with_usage()),
+			"--until" => until = args.next().and_then(|timestamp| timestamp.parse::<u32>().ok()).unwrap_or_else(|| exit_with_usage()),
+			"--type" => match args.next().map(|name| name.parse::<ArchivedMessageType>()) {
+				Some(Ok(parsed_type)) => message_type = Some(parsed_type),
+				Some(Err(error)) => {
+					eprintln!("{}", error);
+					exit_with_usage();
+				},
+				None => exit_with_usage(),
+			},
+			_ => exit_with_usage(),
+		}
+	}
+	let file = file.unwrap_or_else(|| exit_with_usage());
+
+	let reader = match File::open(&file) {
+		Ok(archive) => ArchiveReader::new(BufReader::new(archive)),
+		Err(error) => {
+			eprintln!("Failed to open {}: {}", file, error);
+			process::exit(EXIT_UNREADABLE);
+		}
+	};
+	let stdout = io::stdout();
+	let mut output = stdout.lock();
+	for record in reader {
+		let record = match record {
+			Ok(record) => record,
+			Err(error) => {
+				eprintln!("Failed to read {}: {}", file, error);
+				process::exit(EXIT_UNREADABLE);
+			}
+		};
+		if record.timestamp < since || record.timestamp > until {
+			continue;
+		}
+		let record_type = ArchivedMessageType::from_wire_type(record.message_type);
+		if message_type.is_some() && record_type != message_type {
+			continue;
+		}
+		let type_name = record_type.map_or_else(|| record.message_type.to_string(), |record_type| record_type.to_string());
+		// stops quietly once the output is closed, as when piped into head
+		if writeln!(output, "{} {} {}", record.timestamp, type_name, record.message.as_hex()).is_err() {
+			return;
+		}
+	}
+}
+
+fn exit_with_usage() -> ! {
+	eprintln!("{}", USAGE);
+	process::exit(EXIT_USAGE);
+}
@@ -0,0 +1,50 @@
+//! Checks that a deployed server serves a valid and recent full snapshot, exiting with 0 if it's
+//! healthy, 1 if its data is too old, 2 if the snapshot fails its checksum, and 3 if it can't be
+//! downloaded, or the arguments are invalid.
+
+use std::env;
+use std::process;
+use std::time::Duration;
+
+use rapid_gossip_sync_server::HealthStatus;
+
+const USAGE: &str = "Usage: rgs-healthcheck --server <url> [--max-age-hours <hours>]";
+
+/// The exit code for invalid arguments, which like network errors mean the server wasn't checked
+const EXIT_USAGE: i32 = 3;
+
+#[tokio::main]
+async fn main() {
+	let mut server = None;
+	let mut max_age_hours = 24;
+	let mut args = env::args().skip(1);
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"--server" => server = args.next(),
+			"--max-age-hours" => match args.next().and_then(|hours| hours.parse::<u64>().ok()) {
+				Some(hours) => max_age_hours = hours,
+				None => exit_with_usage(),
+			},
+			_ => exit_with_usage(),
+		}
+	}
+	let server = server.unwrap_or_else(|| exit_with_usage());
+
+	let report = match rapid_gossip_sync_server::check_server_health(&server, Duration::from_secs(max_age_hours * 3600)).await {
+		Ok(report) => report,
+		Err(error) => {
+			eprintln!("Invalid server URL: {}", error);
+			process::exit(EXIT_USAGE);
+		}
+	};
+	print!("{}", report.to_text());
+	if report.status != HealthStatus::Healthy {
+		eprintln!("{} is unhealthy", report.server);
+	}
+	process::exit(report.status.exit_code());
+}
+
+fn exit_with_usage() -> ! {
+	eprintln!("{}", USAGE);
+	process::exit(EXIT_USAGE);
+}
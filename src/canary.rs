@@ -0,0 +1,93 @@
+use std::ops::Deref;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lightning::{log_info, log_warn};
+use lightning::routing::gossip::NetworkGraph;
+use lightning::util::logger::Logger;
+
+use crate::{config, webhook};
+
+const CANARY_CHECK_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Canary channels whose latest update is older than this are considered stale
+pub(crate) const CANARY_MAX_UPDATE_AGE: u32 = 3600 * 24 * 14; // two weeks
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum CanaryFailure {
+	/// The channel is not in the network graph at all
+	Missing(u64),
+	/// The channel has no update in either direction newer than the maximum age. The last update
+	/// timestamp is zero if no update was ever received.
+	Stale { short_channel_id: u64, last_update: u32 },
+}
+
+/// Checks that a set of known-stable channels keep showing up in the network graph with recent
+/// updates.
+///
+/// Peers staying connected and gossip counters increasing doesn't guarantee that messages actually
+/// make it into the graph, so this validates the pipeline end to end.
+pub(crate) struct CanaryValidator<L: Deref + Clone + Send + Sync + 'static> where L::Target: Logger {
+	network_graph: Arc<NetworkGraph<L>>,
+	short_channel_ids: Vec<u64>,
+	logger: L,
+}
+
+impl<L: Deref + Clone + Send + Sync + 'static> CanaryValidator<L> where L::Target: Logger {
+	pub(crate) fn new(network_graph: Arc<NetworkGraph<L>>, logger: L) -> Self {
+		Self { network_graph, short_channel_ids: config::canary_scids(), logger }
+	}
+
+	pub(crate) async fn validate_periodically(&self) {
+		if self.short_channel_ids.is_empty() {
+			return;
+		}
+		log_info!(self.logger, "Monitoring {} canary channels", self.short_channel_ids.len());
+
+		let mut interval = tokio::time::interval(CANARY_CHECK_INTERVAL);
+		loop {
+			interval.tick().await;
+
+			let current_time = SystemTime::now().duration_since(UNIX_EPOCH).expect("Time must be > 1970").as_secs() as u32;
+			let failures = check_canaries(&self.network_graph, &self.short_channel_ids, current_time);
+			for failure in &failures {
+				match failure {
+					CanaryFailure::Missing(scid) => {
+						log_warn!(self.logger, "Canary channel {} is missing from the network graph!", scid);
+					},
+					CanaryFailure::Stale { short_channel_id, last_update } => {
+						log_warn!(self.logger, "Canary channel {} has not been updated since {}!", short_channel_id, last_update);
+					}
+				}
+			}
+
+			if !failures.is_empty() {
+				let reason = format!("{} of {} canary channels are missing or stale", failures.len(), self.short_channel_ids.len());
+				webhook::notify_stall(&reason, self.logger.clone()).await;
+			}
+		}
+	}
+}
+
+pub(crate) fn check_canaries<L: Deref>(network_graph: &NetworkGraph<L>, short_channel_ids: &[u64], current_time: u32) -> Vec<CanaryFailure> where L::Target: Logger {
+	let read_only_graph = network_graph.read_only();
+	let mut failures = Vec::new();
+	for scid in short_channel_ids {
+		let channel = match read_only_graph.channels().get(scid) {
+			Some(channel) => channel,
+			None => {
+				failures.push(CanaryFailure::Missing(*scid));
+				continue;
+			}
+		};
+		let last_update = [&channel.one_to_two, &channel.two_to_one].into_iter()
+			.flatten()
+			.map(|info| info.last_update)
+			.max()
+			.unwrap_or(0);
+		if current_time.saturating_sub(last_update) > CANARY_MAX_UPDATE_AGE {
+			failures.push(CanaryFailure::Stale { short_channel_id: *scid, last_update });
+		}
+	}
+	failures
+}
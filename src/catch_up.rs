@@ -0,0 +1,192 @@
+use std::time::Duration;
+
+/// Below this many new messages per tick, we consider ourselves caught up with gossip
+const DEFAULT_NEW_MESSAGE_THRESHOLD: u64 = 20;
+
+/// After this long without any new gossip, something is likely wrong with our peers
+const STALE_GOSSIP_THRESHOLD: Duration = Duration::from_secs(600);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum CatchUpEvent {
+	/// The gossip stream has slowed down enough for us to be considered caught up
+	BecameCaughtUp,
+	/// New messages are once again arriving faster than the caught-up threshold
+	FellBehind,
+	/// No new gossip has been received for the contained duration
+	StaleGossip(Duration),
+}
+
+/// Decides, one tick at a time, whether we're caught up with the network's gossip.
+pub(crate) struct CatchUpTracker {
+	new_message_threshold: u64,
+	previous_announcement_count: u64,
+	previous_update_count: u64,
+	new_message_count: u64,
+	is_caught_up: bool,
+	time_since_new_gossip: Duration,
+	/// The silence duration at which the next [`CatchUpEvent::StaleGossip`] fires
+	next_stale_warning: Duration,
+}
+
+impl CatchUpTracker {
+	pub(crate) fn new() -> Self {
+		Self::with_threshold(DEFAULT_NEW_MESSAGE_THRESHOLD)
+	}
+
+	pub(crate) fn with_threshold(new_message_threshold: u64) -> Self {
+		Self {
+			new_message_threshold,
+			previous_announcement_count: 0,
+			previous_update_count: 0,
+			new_message_count: 0,
+			is_caught_up: false,
+			time_since_new_gossip: Duration::ZERO,
+			next_stale_warning: STALE_GOSSIP_THRESHOLD,
+		}
+	}
+
+	pub(crate) fn is_caught_up(&self) -> bool {
+		self.is_caught_up
+	}
+
+	/// The number of messages received during the latest tick
+	pub(crate) fn new_message_count(&self) -> u64 {
+		self.new_message_count
+	}
+
+	/// How long no new gossip has been received for, as of the latest tick
+	pub(crate) fn time_since_new_gossip(&self) -> Duration {
+		self.time_since_new_gossip
+	}
+
+	/// Feed the cumulative announcement and update counts, the number of connected peers, and the
+	/// time elapsed since the previous tick.
+	pub(crate) fn tick(&mut self, announcement_count: u64, update_count: u64, _connected_peers: usize, elapsed: Duration) -> Vec<CatchUpEvent> {
+		let mut events = Vec::new();
+
+		let total_message_count = announcement_count + update_count;
+		self.new_message_count = total_message_count - self.previous_announcement_count - self.previous_update_count;
+
+		let was_caught_up = self.is_caught_up;
+		// TODO: make new message threshold adjust based on connected peer count
+		self.is_caught_up = self.new_message_count < self.new_message_threshold
+			&& self.previous_announcement_count > 0 && self.previous_update_count > 0;
+
+		if self.is_caught_up && !was_caught_up {
+			events.push(CatchUpEvent::BecameCaughtUp);
+		} else if !self.is_caught_up && was_caught_up {
+			events.push(CatchUpEvent::FellBehind);
+		}
+
+		if self.new_message_count > 0 {
+			self.time_since_new_gossip = Duration::ZERO;
+			self.next_stale_warning = STALE_GOSSIP_THRESHOLD;
+		} else {
+			self.time_since_new_gossip += elapsed;
+			if self.time_since_new_gossip > self.next_stale_warning {
+				events.push(CatchUpEvent::StaleGossip(self.time_since_new_gossip));
+				self.next_stale_warning += STALE_GOSSIP_THRESHOLD;
+			}
+		}
+
+		self.previous_announcement_count = announcement_count;
+		self.previous_update_count = update_count;
+
+		events
+	}
+}
+
+/// Whether the initial sync is still catching up past its time limit, if it has one
+pub(crate) fn is_catch_up_overdue(is_initial_sync: bool, time_since_connecting: Duration, max_catch_up_time: Option<Duration>) -> bool {
+	is_initial_sync && max_catch_up_time.map_or(false, |max_catch_up_time| time_since_connecting > max_catch_up_time)
+}
+
+/// Why a graph of the given size doesn't count as caught up, if it doesn't, as our peers are likely
+/// to only know of part of the network
+pub(crate) fn check_caught_up_channel_count(channel_count: u64, min_channel_count: u64) -> Result<(), String> {
+	if channel_count < min_channel_count {
+		return Err(format!("caught up with only {} channels, below the minimum of {}", channel_count, min_channel_count));
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const TICK: Duration = Duration::from_secs(5);
+
+	#[test]
+	fn test_not_caught_up_before_any_gossip() {
+		let mut tracker = CatchUpTracker::new();
+		assert!(tracker.tick(0, 0, 1, TICK).is_empty());
+		// a quiet first tick with gossip doesn't count, as we had nothing to compare it against
+		assert!(tracker.tick(5, 5, 1, TICK).is_empty());
+		assert!(!tracker.is_caught_up());
+	}
+
+	#[test]
+	fn test_catch_up_transitions() {
+		let mut tracker = CatchUpTracker::new();
+		assert!(tracker.tick(1000, 5000, 1, TICK).is_empty());
+		assert_eq!(tracker.new_message_count(), 6000);
+
+		assert_eq!(tracker.tick(1005, 5010, 1, TICK), vec![CatchUpEvent::BecameCaughtUp]);
+		assert!(tracker.is_caught_up());
+		// staying caught up is not an event
+		assert!(tracker.tick(1010, 5014, 1, TICK).is_empty());
+
+		assert_eq!(tracker.tick(1100, 5100, 1, TICK), vec![CatchUpEvent::FellBehind]);
+		assert!(!tracker.is_caught_up());
+		assert!(tracker.tick(1200, 5200, 1, TICK).is_empty());
+
+		// catching up again must be reported again
+		assert_eq!(tracker.tick(1201, 5201, 1, TICK), vec![CatchUpEvent::BecameCaughtUp]);
+	}
+
+	#[test]
+	fn test_threshold_boundary() {
+		let mut tracker = CatchUpTracker::with_threshold(10);
+		tracker.tick(100, 100, 1, TICK);
+		assert!(tracker.tick(105, 105, 1, TICK).is_empty());
+		assert_eq!(tracker.tick(105, 114, 1, TICK), vec![CatchUpEvent::BecameCaughtUp]);
+	}
+
+	#[test]
+	fn test_stale_gossip() {
+		let mut tracker = CatchUpTracker::new();
+		tracker.tick(10, 10, 1, TICK);
+		assert_eq!(tracker.tick(10, 10, 1, TICK), vec![CatchUpEvent::BecameCaughtUp]);
+
+		let mut stale_events = Vec::new();
+		for _ in 0..240 {
+			stale_events.extend(tracker.tick(10, 10, 1, TICK));
+		}
+		// 1205 seconds of silence warrant two warnings, not one per tick
+		assert_eq!(stale_events, vec![
+			CatchUpEvent::StaleGossip(Duration::from_secs(605)),
+			CatchUpEvent::StaleGossip(Duration::from_secs(1205)),
+		]);
+
+		// new gossip resets the staleness timer
+		assert!(tracker.tick(11, 10, 1, TICK).is_empty());
+		for _ in 0..120 {
+			assert!(tracker.tick(11, 10, 1, TICK).is_empty());
+		}
+		assert_eq!(tracker.tick(11, 10, 1, TICK), vec![CatchUpEvent::StaleGossip(Duration::from_secs(605))]);
+	}
+
+	#[test]
+	fn test_catch_up_validity() {
+		let max_catch_up_time = Some(Duration::from_secs(600));
+		assert!(!is_catch_up_overdue(true, Duration::from_secs(600), max_catch_up_time));
+		assert!(is_catch_up_overdue(true, Duration::from_secs(601), max_catch_up_time));
+		// only the initial sync is limited, and only if there's a limit
+		assert!(!is_catch_up_overdue(false, Duration::from_secs(601), max_catch_up_time));
+		assert!(!is_catch_up_overdue(true, Duration::from_secs(u32::MAX as u64), None));
+
+		assert_eq!(check_caught_up_channel_count(10_000, 10_000), Ok(()));
+		assert_eq!(check_caught_up_channel_count(9_999, 10_000), Err("caught up with only 9999 channels, below the minimum of 10000".to_string()));
+		assert_eq!(check_caught_up_channel_count(0, 0), Ok(()));
+	}
+}
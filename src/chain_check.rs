@@ -0,0 +1,254 @@
+//! Delta chain verification: a client that applied the previous generation's full snapshot and then
+//! the newest generation's delta must know the same channels, with the same policies, as one that
+//! applied the newest full snapshot. Both graphs are built by LDK's own client, so this exercises
+//! the lookups and the serialization end to end, which is where a delta silently dropping an
+//! update would otherwise go unnoticed. That client only applies version 1 snapshots, so those are
+//! what the chain is verified with. Both versions are serialized from the same lookups.
+
+use std::sync::Arc;
+
+use lightning::routing::gossip::{ChannelUpdateInfo, NetworkGraph};
+use lightning::util::logger::{Logger, Record};
+use lightning_rapid_gossip_sync::RapidGossipSync;
+
+use crate::{config, GOSSIP_PREFIX};
+use crate::error::ProcessorError;
+use crate::scid::ShortChannelId;
+use crate::serialization::FeeDamping;
+
+/// How many diverging channels reports list
+const LISTED_DIVERGENCE_COUNT: usize = 20;
+
+/// The only snapshot version LDK's client applies
+const APPLICABLE_VERSION: u8 = 1;
+
+/// The scratch graphs log every channel they learn about, which nobody needs to read
+struct DiscardingLogger;
+
+impl Logger for DiscardingLogger {
+	fn log(&self, _record: Record) {}
+}
+
+/// How a channel in the chained graph differs from the same channel in the full snapshot
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChannelDivergence {
+	/// The full snapshot has the channel, but the chained graph doesn't
+	MissingFromChain,
+	/// The channel connects different nodes
+	Nodes,
+	/// The policy of the given direction differs, or only one of the graphs has one
+	Policy { direction: u8 },
+}
+
+impl ChannelDivergence {
+	fn describe(&self) -> String {
+		match self {
+			ChannelDivergence::MissingFromChain => "missing from the chain".to_string(),
+			ChannelDivergence::Nodes => "different nodes".to_string(),
+			ChannelDivergence::Policy { direction } => format!("different direction {} policy", direction),
+		}
+	}
+}
+
+/// The outcome of comparing a chain of snapshots with a full snapshot
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChainReport {
+	/// The latest seen timestamp of the previous full snapshot, which clients request the delta for
+	pub previous_latest_seen: u32,
+	/// The latest seen timestamp of the newest full snapshot
+	pub latest_seen: u32,
+	/// The channels the newest full snapshot holds
+	pub channel_count: usize,
+	/// Channels only the chained graph knows. Deltas can't remove channels, so clients keep them
+	/// until they prune them as stale, and they don't count as divergences.
+	pub lingering_channel_count: usize,
+	/// Fee-only differences small enough for fee damping to have left them out of the delta
+	pub damped_fee_count: usize,
+	/// The diverging channels, by short channel id, ascending
+	pub divergences: Vec<(u64, ChannelDivergence)>,
+}
+
+impl ChainReport {
+	pub fn is_intact(&self) -> bool {
+		self.divergences.is_empty()
+	}
+
+	/// The first few diverging channels and how they diverge, for logs and alerts
+	pub fn describe_divergences(&self) -> String {
+		let mut listed: Vec<String> = self.divergences.iter().take(LISTED_DIVERGENCE_COUNT)
+			.map(|(short_channel_id, divergence)| format!("{} ({})", ShortChannelId::from(*short_channel_id), divergence.describe()))
+			.collect();
+		if self.divergences.len() > LISTED_DIVERGENCE_COUNT {
+			listed.push(format!("and {} more", self.divergences.len() - LISTED_DIVERGENCE_COUNT));
+		}
+		listed.join(", ")
+	}
+
+	pub fn to_text(&self) -> String {
+		let mut text = String::new();
+		text.push_str(&format!("delta applied from: {}\n", self.previous_latest_seen));
+		text.push_str(&format!("full snapshot latest seen: {}\n", self.latest_seen));
+		text.push_str(&format!("channels: {}\n", self.channel_count));
+		text.push_str(&format!("lingering channels: {}\n", self.lingering_channel_count));
+		text.push_str(&format!("damped fee differences: {}\n", self.damped_fee_count));
+		if self.is_intact() {
+			text.push_str("status: intact\n");
+		} else {
+			text.push_str(&format!("status: {} channels diverge\n", self.divergences.len()));
+			for (short_channel_id, divergence) in &self.divergences {
+				text.push_str(&format!("\t{}: {}\n", ShortChannelId::from(*short_channel_id), divergence.describe()));
+			}
+		}
+		text
+	}
+
+	pub fn to_json(&self) -> String {
+		let divergences: Vec<String> = self.divergences.iter()
+			.map(|(short_channel_id, divergence)| format!("{{\"scid\":\"{}\",\"divergence\":\"{}\"}}", ShortChannelId::from(*short_channel_id), divergence.describe()))
+			.collect();
+		format!("{{\"previous_latest_seen\":{},\"latest_seen\":{},\"channels\":{},\"lingering_channels\":{},\"damped_fee_differences\":{},\"intact\":{},\"divergences\":[{}]}}\n",
+			self.previous_latest_seen, self.latest_seen, self.channel_count, self.lingering_channel_count, self.damped_fee_count, self.is_intact(), divergences.join(","))
+	}
+}
+
+/// The latest seen timestamp in a snapshot's header, which is what clients request their next
+/// delta for
+pub(crate) fn latest_seen_timestamp(snapshot: &[u8]) -> Option<u32> {
+	if !snapshot.starts_with(&GOSSIP_PREFIX) {
+		return None;
+	}
+	// the prefix and the version are followed by the chain hash
+	let timestamp_bytes = snapshot.get(4 + 32..4 + 32 + 4)?;
+	Some(u32::from_be_bytes(timestamp_bytes.try_into().expect("the slice is four bytes long")))
+}
+
+/// Apply the previous full snapshot and the delta to one scratch graph, the full snapshot to
+/// another, and compare them channel by channel. Fee-only differences that the fee damping policy
+/// may have left out of the delta are tolerated.
+pub(crate) fn verify_chain(previous_full_snapshot: &[u8], delta: &[u8], full_snapshot: &[u8], fee_damping: &FeeDamping) -> Result<ChainReport, ProcessorError> {
+	check_version(previous_full_snapshot, "previous full snapshot")?;
+	check_version(delta, "delta")?;
+	check_version(full_snapshot, "full snapshot")?;
+
+	let chained_graph = NetworkGraph::new(config::network(), Arc::new(DiscardingLogger));
+	let chained_sync = RapidGossipSync::new(&chained_graph, Arc::new(DiscardingLogger));
+	// the snapshots may be arbitrarily old, so their age must not matter
+	let previous_latest_seen = chained_sync.update_network_graph_no_std(previous_full_snapshot, None)
+		.map_err(|error| ProcessorError::SnapshotRejected { context: "Failed to apply the previous full snapshot".to_string(), reason: format!("{:?}", error) })?;
+	chained_sync.update_network_graph_no_std(delta, None)
+		.map_err(|error| ProcessorError::SnapshotRejected { context: "Failed to apply the delta".to_string(), reason: format!("{:?}", error) })?;
+
+	let full_graph = NetworkGraph::new(config::network(), Arc::new(DiscardingLogger));
+	let latest_seen = RapidGossipSync::new(&full_graph, Arc::new(DiscardingLogger)).update_network_graph_no_std(full_snapshot, None)
+		.map_err(|error| ProcessorError::SnapshotRejected { context: "Failed to apply the full snapshot".to_string(), reason: format!("{:?}", error) })?;
+
+	let chained_channels = chained_graph.read_only();
+	let full_channels = full_graph.read_only();
+	let mut damped_fee_count = 0;
+	let mut divergences = Vec::new();
+	for (short_channel_id, full_channel) in full_channels.channels().unordered_iter() {
+		let chained_channel = match chained_channels.channel(*short_channel_id) {
+			Some(chained_channel) => chained_channel,
+			None => {
+				divergences.push((*short_channel_id, ChannelDivergence::MissingFromChain));
+				continue;
+			}
+		};
+		if (chained_channel.node_one, chained_channel.node_two) != (full_channel.node_one, full_channel.node_two) {
+			divergences.push((*short_channel_id, ChannelDivergence::Nodes));
+			continue;
+		}
+		let directions = [(0, &chained_channel.one_to_two, &full_channel.one_to_two), (1, &chained_channel.two_to_one, &full_channel.two_to_one)];
+		for (direction, chained_policy, full_policy) in directions {
+			match (chained_policy, full_policy) {
+				(Some(chained_policy), Some(full_policy)) if routing_policy(chained_policy) == routing_policy(full_policy) => {},
+				(Some(chained_policy), Some(full_policy)) if non_fee_policy(chained_policy) == non_fee_policy(full_policy)
+					&& fee_damping.damps_fee_change(fees(chained_policy), fees(full_policy)) => damped_fee_count += 1,
+				(None, None) => {},
+				_ => divergences.push((*short_channel_id, ChannelDivergence::Policy { direction })),
+			}
+		}
+	}
+	divergences.sort_unstable_by_key(|(short_channel_id, _)| *short_channel_id);
+	let lingering_channel_count = chained_channels.channels().unordered_iter()
+		.filter(|(short_channel_id, _)| full_channels.channel(**short_channel_id).is_none())
+		.count();
+
+	Ok(ChainReport {
+		previous_latest_seen,
+		latest_seen,
+		channel_count: full_channels.channels().len(),
+		lingering_channel_count,
+		damped_fee_count,
+		divergences,
+	})
+}
+
+/// Reject snapshots of versions LDK's client can't apply up front, rather than reporting its
+/// unknown version error. Malformed prefixes are left for the client to report.
+fn check_version(snapshot: &[u8], description: &str) -> Result<(), ProcessorError> {
+	if !snapshot.starts_with(&GOSSIP_PREFIX) {
+		return Ok(());
+	}
+	match snapshot.get(GOSSIP_PREFIX.len()) {
+		Some(version) if *version != APPLICABLE_VERSION => Err(ProcessorError::SnapshotRejected {
+			context: format!("Failed to apply the {}", description),
+			reason: format!("LDK's client only applies version {} snapshots, not version {}", APPLICABLE_VERSION, version),
+		}),
+		_ => Ok(()),
+	}
+}
+
+/// Everything about a direction's policy that routing depends on, leaving out the update
+/// timestamps, which clients backdate to the snapshot they learned the update from
+fn routing_policy(policy: &ChannelUpdateInfo) -> ((bool, u16, u64, u64), (u32, u32)) {
+	(non_fee_policy(policy), fees(policy))
+}
+
+fn non_fee_policy(policy: &ChannelUpdateInfo) -> (bool, u16, u64, u64) {
+	(policy.enabled, policy.cltv_expiry_delta, policy.htlc_minimum_msat, policy.htlc_maximum_msat)
+}
+
+fn fees(policy: &ChannelUpdateInfo) -> (u32, u32) {
+	(policy.fees.base_msat, policy.fees.proportional_millionths)
+}
+
+#[cfg(test)]
+mod tests {
+	use crate::test_utils::bench;
+	use crate::test_utils::synthetic::{SyntheticNetwork, SyntheticNetworkParams};
+
+	use super::*;
+
+	#[test]
+	fn test_chain_verification() {
+		let network = SyntheticNetwork::generate(&SyntheticNetworkParams { node_count: 10, channel_count: 20, updates_per_direction: 1, ..Default::default() });
+		let full_snapshot = bench::serialize_full_v1_snapshot(&network);
+		let latest_seen = latest_seen_timestamp(&full_snapshot).unwrap();
+
+		// a full snapshot is a valid delta from any point in time
+		let report = verify_chain(&full_snapshot, &full_snapshot, &full_snapshot, &FeeDamping::default()).unwrap();
+		assert!(report.is_intact(), "{}", report.to_text());
+		assert_eq!((report.channel_count, report.lingering_channel_count, report.latest_seen), (20, 0, latest_seen));
+
+		// an empty delta loses every channel
+		let empty_snapshot = crate::serialize_empty_blob(latest_seen as u64);
+		let report = verify_chain(&empty_snapshot, &empty_snapshot, &full_snapshot, &FeeDamping::default()).unwrap();
+		assert_eq!(report.divergences.len(), 20);
+		assert!(report.divergences.iter().all(|(_, divergence)| *divergence == ChannelDivergence::MissingFromChain));
+		assert!(report.divergences.windows(2).all(|pair| pair[0].0 < pair[1].0));
+		assert!(!report.describe_divergences().contains(" more"));
+		assert!(report.to_json().contains("\"intact\":false,\"divergences\":[{\"scid\":\""));
+
+		// channels the full snapshot no longer has merely linger
+		let report = verify_chain(&full_snapshot, &empty_snapshot, &empty_snapshot, &FeeDamping::default()).unwrap();
+		assert!(report.is_intact());
+		assert_eq!((report.channel_count, report.lingering_channel_count), (0, 20));
+
+		assert!(verify_chain(&full_snapshot, &full_snapshot[..full_snapshot.len() - 1], &full_snapshot, &FeeDamping::default()).is_err());
+		// version 2 snapshots are turned away before reaching LDK's client
+		let error = verify_chain(&full_snapshot, &full_snapshot, &bench::serialize_full_snapshot(&network), &FeeDamping::default()).unwrap_err();
+		assert_eq!(error.to_string(), "Failed to apply the full snapshot: LDK's client only applies version 1 snapshots, not version 2");
+		assert_eq!(latest_seen_timestamp(b"RGSX"), None);
+	}
+}
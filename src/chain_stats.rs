@@ -0,0 +1,139 @@
+use std::collections::VecDeque;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::{config, stats};
+
+/// The number of most recent requests the rolling statistics are calculated over
+const ROLLING_WINDOW_SIZE: usize = 1000;
+
+/// Tracks the latency and failure rate of requests to the chain backend, i. e. bitcoind's REST
+/// interface, which tends to be the bottleneck of the initial sync
+pub(crate) struct ChainBackendStats {
+	/// The duration and success of the most recent requests, oldest first
+	recent_requests: Mutex<VecDeque<(Duration, bool)>>,
+	/// Lookups taking longer than this are logged individually
+	pub(crate) slow_lookup_threshold: Duration,
+	total_requests: AtomicU64,
+	total_errors: AtomicU64,
+}
+
+impl ChainBackendStats {
+	pub(crate) fn new() -> Self {
+		Self {
+			recent_requests: Mutex::new(VecDeque::with_capacity(ROLLING_WINDOW_SIZE)),
+			slow_lookup_threshold: config::slow_chain_lookup_threshold(),
+			total_requests: AtomicU64::new(0),
+			total_errors: AtomicU64::new(0),
+		}
+	}
+
+	pub(crate) fn record(&self, duration: Duration, succeeded: bool) {
+		{
+			let mut recent_requests = self.recent_requests.lock().expect("chain backend stats lock poisoned");
+			if recent_requests.len() >= ROLLING_WINDOW_SIZE {
+				recent_requests.pop_front();
+			}
+			recent_requests.push_back((duration, succeeded));
+		}
+		self.total_requests.fetch_add(1, Ordering::Relaxed);
+		if !succeeded {
+			self.total_errors.fetch_add(1, Ordering::Relaxed);
+		}
+	}
+
+	pub(crate) fn summary(&self) -> ChainBackendSummary {
+		let (durations, error_count) = {
+			let recent_requests = self.recent_requests.lock().expect("chain backend stats lock poisoned");
+			let durations: Vec<Duration> = recent_requests.iter().map(|(duration, _)| *duration).collect();
+			let error_count = recent_requests.iter().filter(|(_, succeeded)| !succeeded).count();
+			(durations, error_count)
+		};
+		ChainBackendSummary::from_window(durations, error_count, self.total_requests.load(Ordering::Relaxed), self.total_errors.load(Ordering::Relaxed))
+	}
+}
+
+/// Rolling latency percentiles and error rate of the chain backend, along with lifetime totals
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct ChainBackendSummary {
+	/// The number of requests the rolling statistics cover
+	pub(crate) window_size: usize,
+	pub(crate) p50_latency: Option<Duration>,
+	pub(crate) p99_latency: Option<Duration>,
+	/// The share of failed requests within the rolling window, between 0 and 1
+	pub(crate) error_rate: f64,
+	pub(crate) total_requests: u64,
+	pub(crate) total_errors: u64,
+}
+
+impl ChainBackendSummary {
+	fn from_window(mut durations: Vec<Duration>, error_count: usize, total_requests: u64, total_errors: u64) -> Self {
+		durations.sort_unstable();
+		let window_size = durations.len();
+		let error_rate = if window_size == 0 { 0.0 } else { error_count as f64 / window_size as f64 };
+		Self {
+			window_size,
+			p50_latency: (window_size > 0).then(|| stats::percentile(&durations, 50)),
+			p99_latency: (window_size > 0).then(|| stats::percentile(&durations, 99)),
+			error_rate,
+			total_requests,
+			total_errors,
+		}
+	}
+
+	/// Serialize the statistics as a Prometheus summary in the text exposition format
+	pub(crate) fn to_prometheus(&self) -> String {
+		let mut output = String::new();
+		output.push_str("# HELP rgs_chain_backend_request_seconds Latency of the most recent chain backend requests\n");
+		output.push_str("# TYPE rgs_chain_backend_request_seconds summary\n");
+		for (quantile, latency) in [("0.5", self.p50_latency), ("0.99", self.p99_latency)] {
+			if let Some(latency) = latency {
+				output.push_str(&format!("rgs_chain_backend_request_seconds{{quantile=\"{}\"}} {}\n", quantile, latency.as_secs_f64()));
+			}
+		}
+		output.push_str(&format!("rgs_chain_backend_request_seconds_count {}\n", self.total_requests));
+		output.push_str("# HELP rgs_chain_backend_request_errors_total Chain backend requests that failed\n");
+		output.push_str("# TYPE rgs_chain_backend_request_errors_total counter\n");
+		output.push_str(&format!("rgs_chain_backend_request_errors_total {}\n", self.total_errors));
+		output.push_str("# HELP rgs_chain_backend_error_ratio Share of the most recent chain backend requests that failed\n");
+		output.push_str("# TYPE rgs_chain_backend_error_ratio gauge\n");
+		output.push_str(&format!("rgs_chain_backend_error_ratio {}\n", self.error_rate));
+		output
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_chain_backend_summary() {
+		let stats = ChainBackendStats::new();
+		assert_eq!(stats.summary(), ChainBackendSummary::default());
+
+		for millis in 1..=100 {
+			stats.record(Duration::from_millis(millis), millis % 10 != 0);
+		}
+		let summary = stats.summary();
+		assert_eq!(summary.window_size, 100);
+		assert_eq!(summary.p50_latency, Some(Duration::from_millis(50)));
+		assert_eq!(summary.p99_latency, Some(Duration::from_millis(99)));
+		assert_eq!(summary.error_rate, 0.1);
+		assert_eq!((summary.total_requests, summary.total_errors), (100, 10));
+
+		// the rolling window only retains the most recent requests, unlike the totals
+		for _ in 0..ROLLING_WINDOW_SIZE {
+			stats.record(Duration::from_secs(2), true);
+		}
+		let summary = stats.summary();
+		assert_eq!(summary.window_size, ROLLING_WINDOW_SIZE);
+		assert_eq!(summary.p50_latency, Some(Duration::from_secs(2)));
+		assert_eq!(summary.error_rate, 0.0);
+		assert_eq!((summary.total_requests, summary.total_errors), (100 + ROLLING_WINDOW_SIZE as u64, 10));
+
+		let export = summary.to_prometheus();
+		assert!(export.contains("rgs_chain_backend_request_seconds{quantile=\"0.99\"} 2\n"));
+		assert!(export.contains("rgs_chain_backend_request_errors_total 10\n"));
+	}
+}
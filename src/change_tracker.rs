@@ -0,0 +1,89 @@
+//! Tracks how much the graph changed since the latest snapshot generation, so that significant
+//! changes, such as a major node going offline, don't wait for the next scheduled generation.
+
+use std::collections::HashSet;
+use std::sync::Mutex;
+
+/// How much the graph must change for snapshots to be generated ahead of schedule. Thresholds of
+/// zero disable their trigger.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct SnapshotTriggers {
+	pub(crate) new_channels: u64,
+	/// The share of the graph's channels whose fees changed in either direction
+	pub(crate) policy_changes_percent: f64,
+}
+
+#[derive(Default)]
+struct GraphChanges {
+	new_channel_count: u64,
+	/// Channels counted once, no matter how often or in how many directions their fees changed
+	policy_changed_channels: HashSet<u64>,
+}
+
+pub(crate) struct ChangeTracker {
+	triggers: SnapshotTriggers,
+	changes: Mutex<GraphChanges>,
+}
+
+impl ChangeTracker {
+	pub(crate) fn new(triggers: SnapshotTriggers) -> Self {
+		Self { triggers, changes: Mutex::new(GraphChanges::default()) }
+	}
+
+	pub(crate) fn record_new_channel(&self) {
+		self.changes.lock().expect("change tracker lock poisoned").new_channel_count += 1;
+	}
+
+	pub(crate) fn record_policy_change(&self, short_channel_id: u64) {
+		self.changes.lock().expect("change tracker lock poisoned").policy_changed_channels.insert(short_channel_id);
+	}
+
+	/// Describe why the changes since the latest generation warrant generating snapshots ahead of
+	/// schedule, if they do, given the graph's current channel count
+	pub(crate) fn significant_change(&self, channel_count: usize) -> Option<String> {
+		let changes = self.changes.lock().expect("change tracker lock poisoned");
+		if self.triggers.new_channels > 0 && changes.new_channel_count > self.triggers.new_channels {
+			return Some(format!("{} new channels", changes.new_channel_count));
+		}
+		let policy_changes_percent = changes.policy_changed_channels.len() as f64 * 100.0 / channel_count.max(1) as f64;
+		if self.triggers.policy_changes_percent > 0.0 && policy_changes_percent > self.triggers.policy_changes_percent {
+			return Some(format!("fee changes on {:.1}% of channels", policy_changes_percent));
+		}
+		None
+	}
+
+	/// Start tracking anew, as of a generation starting
+	pub(crate) fn reset(&self) {
+		*self.changes.lock().expect("change tracker lock poisoned") = GraphChanges::default();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_significant_changes() {
+		let tracker = ChangeTracker::new(SnapshotTriggers { new_channels: 2, policy_changes_percent: 5.0 });
+		tracker.record_new_channel();
+		tracker.record_new_channel();
+		assert_eq!(tracker.significant_change(100), None);
+		tracker.record_new_channel();
+		assert_eq!(tracker.significant_change(100), Some("3 new channels".to_string()));
+
+		tracker.reset();
+		for short_channel_id in 0..5 {
+			tracker.record_policy_change(short_channel_id);
+			// repeated changes of a channel count once
+			tracker.record_policy_change(short_channel_id);
+		}
+		assert_eq!(tracker.significant_change(100), None);
+		tracker.record_policy_change(5);
+		assert_eq!(tracker.significant_change(100), Some("fee changes on 6.0% of channels".to_string()));
+
+		let disabled_tracker = ChangeTracker::new(SnapshotTriggers { new_channels: 0, policy_changes_percent: 0.0 });
+		disabled_tracker.record_new_channel();
+		disabled_tracker.record_policy_change(1);
+		assert_eq!(disabled_tracker.significant_change(1), None);
+	}
+}
@@ -0,0 +1,200 @@
+//! Aggregate statistics of the snapshot requests served by the HTTP front end.
+//!
+//! Requests only ever increment counters, so nothing identifying a client is kept, not even
+//! transiently.
+
+use std::collections::BTreeMap;
+use std::ops::Deref;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lightning::{log_info, log_warn};
+use lightning::util::logger::Logger;
+
+use crate::{config, snapshot, stats};
+
+/// How often the usage statistics are published next to the other statistics
+const PUBLICATION_INTERVAL: Duration = Duration::from_secs(600);
+
+/// The upper bounds of the staleness histogram's buckets, in seconds, which mirror the default
+/// snapshot scopes closely enough to tell whether clients sync more or less often than expected
+const STALENESS_BUCKET_BOUNDS: [u64; 7] = [3600, 6 * 3600, 24 * 3600, 3 * 24 * 3600, 7 * 24 * 3600, 14 * 24 * 3600, 28 * 24 * 3600];
+
+/// Cumulative usage counters since the server started
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ClientUsageTotals {
+	/// Requests by the scope of the snapshot serving them, in seconds, with the full snapshot's
+	/// scope being `u64::MAX`
+	pub requests_by_scope: BTreeMap<u64, u64>,
+	/// Requests answered with a snapshot, i. e. with a 200
+	pub ok_responses: u64,
+	/// Requests answered with a 304, because the client already had the snapshot
+	pub not_modified_responses: u64,
+	pub bytes_served: u64,
+	/// Requests without a last sync timestamp, which are left out of the staleness histogram
+	pub full_syncs: u64,
+	/// Requests by how long ago the client last synced, bucketed by [`STALENESS_BUCKET_BOUNDS`],
+	/// with the last bucket counting anything older
+	pub staleness_buckets: [u64; STALENESS_BUCKET_BOUNDS.len() + 1],
+}
+
+impl ClientUsageTotals {
+	fn scope_label(scope: u64) -> String {
+		if scope == u64::MAX { "full".to_string() } else { scope.to_string() }
+	}
+
+	fn staleness_label(bucket_index: usize) -> String {
+		STALENESS_BUCKET_BOUNDS.get(bucket_index).map_or("+Inf".to_string(), |bound| bound.to_string())
+	}
+
+	pub fn to_json(&self) -> String {
+		let requests_by_scope: Vec<String> = self.requests_by_scope.iter()
+			.map(|(scope, count)| format!("\"{}\":{}", Self::scope_label(*scope), count))
+			.collect();
+		let staleness_buckets: Vec<String> = self.staleness_buckets.iter().enumerate()
+			.map(|(bucket_index, count)| format!("\"{}\":{}", Self::staleness_label(bucket_index), count))
+			.collect();
+		format!(
+			"{{\"requests_by_scope\":{{{}}},\"ok_responses\":{},\"not_modified_responses\":{},\"bytes_served\":{},\"full_syncs\":{},\"staleness_secs\":{{{}}}}}",
+			requests_by_scope.join(","),
+			self.ok_responses,
+			self.not_modified_responses,
+			self.bytes_served,
+			self.full_syncs,
+			staleness_buckets.join(","),
+		)
+	}
+
+	/// Serialize the totals as Prometheus counters and a histogram in the text exposition format
+	pub fn to_prometheus(&self) -> String {
+		let mut output = String::new();
+		output.push_str("# HELP rgs_client_requests_total Snapshot requests by the scope of the snapshot serving them, in seconds\n");
+		output.push_str("# TYPE rgs_client_requests_total counter\n");
+		for (scope, count) in &self.requests_by_scope {
+			output.push_str(&format!("rgs_client_requests_total{{scope=\"{}\"}} {}\n", Self::scope_label(*scope), count));
+		}
+		output.push_str("# HELP rgs_client_responses_total Snapshot requests by response status\n");
+		output.push_str("# TYPE rgs_client_responses_total counter\n");
+		output.push_str(&format!("rgs_client_responses_total{{status=\"200\"}} {}\n", self.ok_responses));
+		output.push_str(&format!("rgs_client_responses_total{{status=\"304\"}} {}\n", self.not_modified_responses));
+		output.push_str("# HELP rgs_client_bytes_served_total Snapshot bytes served to clients\n");
+		output.push_str("# TYPE rgs_client_bytes_served_total counter\n");
+		output.push_str(&format!("rgs_client_bytes_served_total {}\n", self.bytes_served));
+		output.push_str("# HELP rgs_client_full_syncs_total Snapshot requests without a last sync timestamp\n");
+		output.push_str("# TYPE rgs_client_full_syncs_total counter\n");
+		output.push_str(&format!("rgs_client_full_syncs_total {}\n", self.full_syncs));
+		output.push_str("# HELP rgs_client_staleness_seconds Time since the requesting client last synced\n");
+		output.push_str("# TYPE rgs_client_staleness_seconds histogram\n");
+		let mut cumulative_count = 0;
+		for (bucket_index, count) in self.staleness_buckets.iter().enumerate() {
+			cumulative_count += count;
+			output.push_str(&format!("rgs_client_staleness_seconds_bucket{{le=\"{}\"}} {}\n", Self::staleness_label(bucket_index), cumulative_count));
+		}
+		output.push_str(&format!("rgs_client_staleness_seconds_count {}\n", cumulative_count));
+		output
+	}
+}
+
+/// Collects the usage statistics of the snapshot requests the HTTP front end reports
+pub struct ClientUsage {
+	snapshot_scopes: Vec<u64>,
+	totals: Mutex<ClientUsageTotals>,
+}
+
+impl ClientUsage {
+	pub(crate) fn new() -> Self {
+		Self::with_snapshot_scopes(snapshot::tiered_snapshot_scopes(config::snapshot_generation_interval() as u64, config::full_snapshot_age()))
+	}
+
+	fn with_snapshot_scopes(snapshot_scopes: Vec<u64>) -> Self {
+		Self { snapshot_scopes, totals: Mutex::new(ClientUsageTotals::default()) }
+	}
+
+	/// Record a snapshot request from a client that last synced at `last_sync_timestamp`, which is
+	/// 0 for clients that never did. `bytes_served` is the size of the response body, i. e. 0 for
+	/// requests answered with a 304.
+	pub fn record_request(&self, last_sync_timestamp: u32, is_not_modified: bool, bytes_served: u64) {
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch").as_secs();
+		self.record_request_at(now, last_sync_timestamp, is_not_modified, bytes_served);
+	}
+
+	fn record_request_at(&self, now: u64, last_sync_timestamp: u32, is_not_modified: bool, bytes_served: u64) {
+		let staleness = now.saturating_sub(last_sync_timestamp as u64);
+		let scope = if last_sync_timestamp == 0 { u64::MAX } else { snapshot::referenced_scope(&self.snapshot_scopes, staleness) };
+
+		let mut totals = self.totals.lock().expect("client usage lock poisoned");
+		*totals.requests_by_scope.entry(scope).or_insert(0) += 1;
+		if is_not_modified {
+			totals.not_modified_responses += 1;
+		} else {
+			totals.ok_responses += 1;
+		}
+		totals.bytes_served += bytes_served;
+		if last_sync_timestamp == 0 {
+			totals.full_syncs += 1;
+		} else {
+			let bucket_index = STALENESS_BUCKET_BOUNDS.iter().position(|bound| staleness <= *bound).unwrap_or(STALENESS_BUCKET_BOUNDS.len());
+			totals.staleness_buckets[bucket_index] += 1;
+		}
+	}
+
+	pub fn totals(&self) -> ClientUsageTotals {
+		self.totals.lock().expect("client usage lock poisoned").clone()
+	}
+
+	/// Periodically publish the usage statistics next to the fee statistics, so that they can be
+	/// served as `/stats/client_usage` and scraped by Prometheus' textfile collector
+	pub(crate) async fn publish_periodically<L: Deref>(&self, logger: L) where L::Target: Logger {
+		let mut interval = tokio::time::interval(PUBLICATION_INTERVAL);
+		loop {
+			interval.tick().await;
+			let totals = self.totals();
+			log_info!(logger, "Client usage: {}", totals.to_json());
+			if let Err(error) = stats::write_stats_file(&config::stats_path(), "client_usage", &totals.to_json(), &totals.to_prometheus()) {
+				log_warn!(logger, "Failed to persist client usage stats: {}", error);
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const DAY: u64 = 24 * 3600;
+
+	#[test]
+	fn test_request_aggregation() {
+		let usage = ClientUsage::with_snapshot_scopes(vec![DAY, 2 * DAY, 4 * DAY, u64::MAX]);
+		let now = 100 * DAY;
+		usage.record_request_at(now, (now - 3600) as u32, false, 1_000);
+		usage.record_request_at(now, (now - 3 * DAY) as u32, false, 5_000);
+		usage.record_request_at(now, (now - 3 * DAY) as u32, true, 0);
+		usage.record_request_at(now, (now - 50 * DAY) as u32, false, 20_000);
+		usage.record_request_at(now, 0, false, 20_000);
+
+		let totals = usage.totals();
+		assert_eq!(totals.requests_by_scope, BTreeMap::from([(DAY, 1), (4 * DAY, 2), (u64::MAX, 2)]));
+		assert_eq!((totals.ok_responses, totals.not_modified_responses), (4, 1));
+		assert_eq!(totals.bytes_served, 46_000);
+		assert_eq!(totals.full_syncs, 1);
+		assert_eq!(totals.staleness_buckets, [1, 0, 0, 2, 0, 0, 0, 1]);
+	}
+
+	#[test]
+	fn test_usage_formats() {
+		let usage = ClientUsage::with_snapshot_scopes(vec![DAY, u64::MAX]);
+		let now = 100 * DAY;
+		usage.record_request_at(now, (now - 600) as u32, true, 0);
+		usage.record_request_at(now, 0, false, 300);
+
+		let totals = usage.totals();
+		assert_eq!(totals.to_json(), "{\"requests_by_scope\":{\"86400\":1,\"full\":1},\"ok_responses\":1,\"not_modified_responses\":1,\"bytes_served\":300,\"full_syncs\":1,\
+			\"staleness_secs\":{\"3600\":1,\"21600\":0,\"86400\":0,\"259200\":0,\"604800\":0,\"1209600\":0,\"2419200\":0,\"+Inf\":0}}");
+		let prometheus = totals.to_prometheus();
+		assert!(prometheus.contains("rgs_client_requests_total{scope=\"full\"} 1\n"));
+		assert!(prometheus.contains("rgs_client_responses_total{status=\"304\"} 1\n"));
+		assert!(prometheus.contains("rgs_client_staleness_seconds_bucket{le=\"+Inf\"} 1\n"));
+		assert!(prometheus.contains("rgs_client_staleness_seconds_count 1\n"));
+	}
+}
@@ -0,0 +1,73 @@
+//! The single notion of "now" that snapshot lookups and serialization go by.
+//!
+//! Pinning it to a past time, along with the gossip considered, regenerates snapshots as they would
+//! have been generated back then, and keeps tests independent of the wall clock.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct SnapshotClock {
+	/// The unix timestamp snapshots are calculated at, or `None` to read the wall clock
+	pinned_at: Option<u64>,
+	/// The unix timestamp up to which persisted gossip is considered, if not all of it is
+	seen_until: Option<u64>,
+}
+
+impl SnapshotClock {
+	/// The current time, considering all persisted gossip
+	pub(crate) fn wall_clock() -> Self {
+		Self { pinned_at: None, seen_until: None }
+	}
+
+	/// A past time, considering only the gossip that had been seen by then, including during its
+	/// final second
+	pub(crate) fn as_of(timestamp: u64) -> Self {
+		Self { pinned_at: Some(timestamp), seen_until: Some(timestamp) }
+	}
+
+	/// This clock pinned to a generation's reference timestamp, considering the same gossip
+	pub(crate) fn at_reference(&self, reference_timestamp: u64) -> Self {
+		Self { pinned_at: Some(reference_timestamp), seen_until: self.seen_until }
+	}
+
+	pub(crate) fn now(&self) -> u64 {
+		self.pinned_at.unwrap_or_else(|| SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch").as_secs())
+	}
+
+	/// Whether only gossip seen up to a past time is considered
+	pub(crate) fn is_historical(&self) -> bool {
+		self.seen_until.is_some()
+	}
+
+	/// An SQL condition limiting a `seen` column to the gossip this clock considers, which is
+	/// always true unless the clock is historical
+	pub(crate) fn seen_condition(&self, column: &str) -> String {
+		match self.seen_until {
+			// the bound is a timestamp, so it's safe to format into the query
+			Some(seen_until) => format!("{} < TO_TIMESTAMP({})", column, seen_until + 1),
+			None => "TRUE".to_string(),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_clock_pinning() {
+		let clock = SnapshotClock::as_of(1_700_000_123);
+		assert_eq!(clock.now(), 1_700_000_123);
+		assert!(clock.is_historical());
+		assert_eq!(clock.seen_condition("seen"), "seen < TO_TIMESTAMP(1700000124)");
+
+		let reference_clock = clock.at_reference(1_700_000_100);
+		assert_eq!(reference_clock.now(), 1_700_000_100);
+		assert_eq!(reference_clock.seen_condition("seen"), "seen < TO_TIMESTAMP(1700000124)");
+
+		let live_clock = SnapshotClock::wall_clock().at_reference(1_700_000_100);
+		assert_eq!(live_clock.now(), 1_700_000_100);
+		assert!(!live_clock.is_historical());
+		assert_eq!(live_clock.seen_condition("seen"), "TRUE");
+	}
+}
@@ -0,0 +1,244 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Cursor;
+use std::mem;
+use std::ops::Deref;
+
+use lightning::ln::msgs::ChannelAnnouncement;
+use lightning::routing::gossip::{ChannelInfo, ChannelUpdateInfo, NetworkGraph, NodeId, NodeInfo};
+use lightning::util::logger::Logger;
+use lightning::util::ser::Readable;
+use serde::Serialize;
+use tokio_postgres::GenericClient;
+
+use crate::clock::SnapshotClock;
+use crate::error::{ErrorContext, ProcessorError};
+use crate::htlc_maximum;
+use crate::scid::ShortChannelId;
+use crate::tables::Tables;
+
+/// How old a direction's latest update may be before the network graph prunes it, as LDK does
+const STALE_UPDATE_AGE_SECS: u64 = 14 * 24 * 3600;
+
+/// The parts of a channel direction's latest update that snapshot generation needs
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct CompactPolicy {
+	pub(crate) last_update: u32,
+	pub(crate) enabled: bool,
+	pub(crate) cltv_expiry_delta: u16,
+	pub(crate) htlc_minimum_msat: u64,
+	pub(crate) htlc_maximum_msat: u64,
+	pub(crate) fee_base_msat: u32,
+	pub(crate) fee_proportional_millionths: u32,
+}
+
+impl CompactPolicy {
+	/// Copy a direction's policy from the network graph, which keeps the HTLC maximum its update
+	/// advertised, applying the substitution for absent ones that persisted updates got
+	fn from_update_info(info: &ChannelUpdateInfo, capacity_sats: Option<u64>) -> Self {
+		Self {
+			last_update: info.last_update,
+			enabled: info.enabled,
+			cltv_expiry_delta: info.cltv_expiry_delta,
+			htlc_minimum_msat: info.htlc_minimum_msat,
+			htlc_maximum_msat: htlc_maximum::effective_htlc_maximum_msat(info.htlc_maximum_msat, capacity_sats),
+			fee_base_msat: info.fees.base_msat,
+			fee_proportional_millionths: info.fees.proportional_millionths,
+		}
+	}
+}
+
+/// A channel without the signed messages, features, and node details the network graph keeps
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct CompactChannel {
+	pub(crate) node1: NodeId,
+	pub(crate) node2: NodeId,
+	pub(crate) capacity_sats: Option<u64>,
+	pub(crate) direction_0: Option<CompactPolicy>,
+	pub(crate) direction_1: Option<CompactPolicy>,
+}
+
+impl CompactChannel {
+	pub(crate) fn is_bidirectional(&self) -> bool {
+		self.direction_0.is_some() && self.direction_1.is_some()
+	}
+}
+
+/// A copy of the announced channels in the network graph, taken once per snapshot generation so
+/// that the lookups for each scope don't need to hold the graph's lock or walk its full structure
+pub(crate) struct CompactGraph {
+	channels: HashMap<ShortChannelId, CompactChannel>,
+}
+
+impl CompactGraph {
+	/// Copy the channels of the graph whose announcement it still has, which are the only ones
+	/// snapshots can include
+	pub(crate) fn from_network_graph<L: Deref>(network_graph: &NetworkGraph<L>) -> Self where L::Target: Logger {
+		let read_only_graph = network_graph.read_only();
+		let channels = read_only_graph.channels().unordered_iter()
+			.filter(|(_, channel)| channel.announcement_message.is_some())
+			.map(|(scid, channel)| (ShortChannelId::from(*scid), CompactChannel {
+				node1: channel.node_one,
+				node2: channel.node_two,
+				capacity_sats: channel.capacity_sats,
+				direction_0: channel.one_to_two.as_ref().map(|info| CompactPolicy::from_update_info(info, channel.capacity_sats)),
+				direction_1: channel.two_to_one.as_ref().map(|info| CompactPolicy::from_update_info(info, channel.capacity_sats)),
+			}))
+			.collect();
+		Self { channels }
+	}
+
+	/// Reconstruct the announced channels the network graph held at the clock's time from the
+	/// persisted gossip seen by then. Channels removed by then are left out, as are directions whose
+	/// latest update was stale, which the graph would have pruned.
+	pub(crate) async fn from_database<C: GenericClient>(client: &C, tables: &Tables, clock: SnapshotClock) -> Result<Self, ProcessorError> {
+		let announcement_rows = client.query(&format!("
+			SELECT announcement_signed, capacity_sats FROM {announcements} AS announcements
+			WHERE {announcement_seen} AND NOT EXISTS (
+				SELECT 1 FROM {removals} AS removals
+				WHERE removals.short_channel_id = announcements.short_channel_id AND removals.seen >= announcements.seen AND {removal_seen}
+			)
+			", announcements = tables.channel_announcements(), removals = tables.channel_removals(),
+			announcement_seen = clock.seen_condition("announcements.seen"), removal_seen = clock.seen_condition("removals.seen")), &[]).await
+			.context("Failed to fetch historical channel announcements")?;
+
+		let mut channels = HashMap::with_capacity(announcement_rows.len());
+		for row in announcement_rows {
+			let blob: Vec<u8> = row.get("announcement_signed");
+			let announcement = ChannelAnnouncement::read(&mut Cursor::new(blob)).context("Failed to decode persisted channel announcement")?.contents;
+			channels.insert(ShortChannelId::from(announcement.short_channel_id), CompactChannel {
+				node1: announcement.node_id_1,
+				node2: announcement.node_id_2,
+				capacity_sats: row.get::<_, Option<i64>>("capacity_sats").map(|capacity| capacity as u64),
+				direction_0: None,
+				direction_1: None,
+			});
+		}
+
+		// like the graph, keep the update with the newest timestamp rather than the latest seen
+		let update_rows = client.query(&format!("
+			SELECT DISTINCT ON (short_channel_id, direction) short_channel_id, direction, timestamp, disable, cltv_expiry_delta,
+				htlc_minimum_msat, fee_base_msat, fee_proportional_millionths, htlc_maximum_msat
+			FROM {}
+			WHERE timestamp >= $1 AND {}
+			ORDER BY short_channel_id ASC, direction ASC, timestamp DESC
+			", tables.channel_updates(), clock.seen_condition("seen")), &[&(clock.now().saturating_sub(STALE_UPDATE_AGE_SECS) as i64)]).await
+			.context("Failed to fetch historical channel updates")?;
+
+		for row in update_rows {
+			let channel = match channels.get_mut(&ShortChannelId::from(row.get::<_, i64>("short_channel_id") as u64)) {
+				Some(channel) => channel,
+				None => continue,
+			};
+			let policy = CompactPolicy {
+				last_update: row.get::<_, i64>("timestamp") as u32,
+				enabled: !row.get::<_, bool>("disable"),
+				cltv_expiry_delta: row.get::<_, i32>("cltv_expiry_delta") as u16,
+				htlc_minimum_msat: row.get::<_, i64>("htlc_minimum_msat") as u64,
+				htlc_maximum_msat: row.get::<_, i64>("htlc_maximum_msat") as u64,
+				// fees are persisted as the signed reinterpretation of their u32
+				fee_base_msat: row.get::<_, i32>("fee_base_msat") as u32,
+				fee_proportional_millionths: row.get::<_, i32>("fee_proportional_millionths") as u32,
+			};
+			if row.get::<_, bool>("direction") {
+				channel.direction_1 = Some(policy);
+			} else {
+				channel.direction_0 = Some(policy);
+			}
+		}
+		Ok(Self { channels })
+	}
+
+	pub(crate) fn channel(&self, short_channel_id: ShortChannelId) -> Option<&CompactChannel> {
+		self.channels.get(&short_channel_id)
+	}
+
+	pub(crate) fn channel_count(&self) -> usize {
+		self.channels.len()
+	}
+
+	/// The channels that have been updated in both directions, which snapshots are limited to
+	pub(crate) fn bidirectional_channel_ids(&self) -> Vec<i64> {
+		self.channels.iter()
+			.filter(|(_, channel)| channel.is_bidirectional())
+			.map(|(scid, _)| u64::from(*scid) as i64)
+			.collect()
+	}
+
+	pub(crate) fn directional_coverage(&self) -> DirectionalCoverage {
+		DirectionalCoverage::count(self.channels.values().map(|channel| (channel.direction_0.is_some(), channel.direction_1.is_some())))
+	}
+
+	/// The approximate number of bytes the channel map occupies, including its spare capacity
+	pub(crate) fn estimated_memory_bytes(&self) -> usize {
+		// hashbrown keeps one control byte per bucket next to each entry
+		self.channels.capacity() * (mem::size_of::<ShortChannelId>() + mem::size_of::<CompactChannel>() + 1)
+	}
+}
+
+/// How many announced channels have updates in which directions. Only channels updated in both
+/// directions are routable, and thus included in snapshots.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub(crate) struct DirectionalCoverage {
+	pub(crate) bidirectional: u64,
+	pub(crate) direction_0_only: u64,
+	pub(crate) direction_1_only: u64,
+	/// Channels that were announced, but never updated
+	pub(crate) none: u64,
+}
+
+impl DirectionalCoverage {
+	/// Count the channels of a network graph whose announcement it still has, like
+	/// [`CompactGraph::from_network_graph`]
+	pub(crate) fn from_network_graph<L: Deref>(network_graph: &NetworkGraph<L>) -> Self where L::Target: Logger {
+		let read_only_graph = network_graph.read_only();
+		Self::count(read_only_graph.channels().unordered_iter()
+			.filter(|(_, channel)| channel.announcement_message.is_some())
+			.map(|(_, channel)| (channel.one_to_two.is_some(), channel.two_to_one.is_some())))
+	}
+
+	/// Tally channels by whether they have an update in direction 0 and in direction 1
+	fn count<I: Iterator<Item = (bool, bool)>>(directions: I) -> Self {
+		let mut coverage = Self::default();
+		for direction_updates in directions {
+			match direction_updates {
+				(true, true) => coverage.bidirectional += 1,
+				(true, false) => coverage.direction_0_only += 1,
+				(false, true) => coverage.direction_1_only += 1,
+				(false, false) => coverage.none += 1,
+			}
+		}
+		coverage
+	}
+
+	pub(crate) fn channel_count(&self) -> u64 {
+		self.bidirectional + self.direction_0_only + self.direction_1_only + self.none
+	}
+
+	pub(crate) fn values(&self) -> [u64; 4] {
+		[self.bidirectional, self.direction_0_only, self.direction_1_only, self.none]
+	}
+}
+
+impl fmt::Display for DirectionalCoverage {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let percent = |count: u64| count as f64 * 100.0 / self.channel_count().max(1) as f64;
+		write!(f, "{} bidirectional ({:.1}%), {} in direction 0 only ({:.1}%), {} in direction 1 only ({:.1}%), {} without updates ({:.1}%)",
+			self.bidirectional, percent(self.bidirectional),
+			self.direction_0_only, percent(self.direction_0_only),
+			self.direction_1_only, percent(self.direction_1_only),
+			self.none, percent(self.none))
+	}
+}
+
+/// The approximate number of bytes a network graph's channel and node maps occupy, for comparison
+/// with [`CompactGraph::estimated_memory_bytes`]. Heap allocations hanging off the entries, such as
+/// each node's channel list and unknown message data, aren't included, so this is a lower bound.
+pub(crate) fn estimate_network_graph_memory_bytes<L: Deref>(network_graph: &NetworkGraph<L>) -> usize where L::Target: Logger {
+	let read_only_graph = network_graph.read_only();
+	let channel_bytes = read_only_graph.channels().len() * (mem::size_of::<u64>() + mem::size_of::<ChannelInfo>());
+	let node_bytes = read_only_graph.nodes().unordered_iter()
+		.map(|(_, node)| mem::size_of::<NodeId>() + mem::size_of::<NodeInfo>() + node.channels.capacity() * mem::size_of::<u64>())
+		.sum::<usize>();
+	channel_bytes + node_bytes
+}
@@ -1,20 +1,40 @@
+use crate::change_tracker::SnapshotTriggers;
+use crate::dormancy::DormancyPolicy;
+use crate::error::{ErrorContext, ProcessorError};
 use crate::hex_utils;
+use crate::htlc_maximum;
+use crate::pacing::LookupPacing;
+use crate::role::ServerRole;
+use crate::scid::ShortChannelId;
+use crate::peer_registry::LocalBindAddresses;
+use crate::maintenance::MaintenanceWindow;
+use crate::serialization::FeeDamping;
+use crate::snapshot::SnapshotRetentionPolicy;
+use crate::supervisor::{FailurePolicy, RestartBudget};
+use crate::tables::Tables;
+use crate::verifier::RestBinaryResponse;
 
 use std::env;
+use std::fs;
 use std::io::Cursor;
 use std::net::{SocketAddr, ToSocketAddrs};
 use std::time::Duration;
 
 use bitcoin::Network;
+use bitcoin::blockdata::constants::genesis_block;
+use bitcoin::hashes::Hash;
 use bitcoin::hashes::hex::FromHex;
 use bitcoin::secp256k1::PublicKey;
 use futures::stream::{FuturesUnordered, StreamExt};
+use hex_conservative::DisplayHex;
 use lightning::ln::msgs::ChannelAnnouncement;
 use lightning::util::ser::Readable;
-use lightning_block_sync::http::HttpEndpoint;
+use lightning_block_sync::BlockSource;
+use lightning_block_sync::http::{BinaryResponse, HttpEndpoint};
+use lightning_block_sync::rest::RestClient;
 use tokio_postgres::Config;
 
-pub(crate) const SCHEMA_VERSION: i32 = 14;
+pub(crate) const SCHEMA_VERSION: i32 = 28;
 pub(crate) const SYMLINK_GRANULARITY_INTERVAL: u32 = 3600 * 3; // three hours
 pub(crate) const MAX_SNAPSHOT_SCOPE: u32 = 3600 * 24 * 21; // three weeks
 // generate symlinks based on a 3-hour-granularity
@@ -26,11 +46,23 @@ pub(crate) const CHANNEL_REMINDER_AGE: Duration = Duration::from_secs(6 * 24 * 6
 /// Maximum number of default features to calculate for node announcements
 pub(crate) const NODE_DEFAULT_FEATURE_COUNT: u8 = 6;
 
+/// Nodes that haven't re-broadcast their announcement in this long are omitted from full snapshots
+pub(crate) const NODE_ANNOUNCEMENT_MAX_AGE: Duration = Duration::from_secs(14 * 24 * 60 * 60);
+
+/// How long startup validation waits for the database and the chain backend to respond
+const VALIDATION_TIMEOUT: Duration = Duration::from_secs(10);
+
 /// The number of successful peer connections to await prior to continuing to gossip storage.
 /// The application will still work if the number of specified peers is lower, as long as there is
 /// at least one successful peer connection, but it may result in long startup times.
 pub(crate) const CONNECTED_PEER_ASSERTION_LIMIT: usize = 5;
 pub(crate) const DOWNLOAD_NEW_GOSSIP: bool = true;
+/// The default number of peer connection attempts made at once
+pub(crate) const PEER_CONNECT_CONCURRENCY: usize = 8;
+/// The default number of channels assumed per connected peer, roughly mainnet's channel count
+const DEFAULT_AVG_CHANNELS_PER_PEER: u64 = 75_000;
+/// A fraction of mainnet's channel count that any reasonably connected peer set knows of
+const DEFAULT_MAINNET_MIN_CHANNELS: u64 = 10_000;
 
 pub(crate) fn snapshot_generation_interval() -> u32 {
 	let interval = env::var("RAPID_GOSSIP_SYNC_SERVER_SNAPSHOT_INTERVAL").unwrap_or(SYMLINK_GRANULARITY_INTERVAL.to_string())
@@ -41,6 +73,407 @@ pub(crate) fn snapshot_generation_interval() -> u32 {
 	interval
 }
 
+pub(crate) fn snapshot_retention_policy() -> SnapshotRetentionPolicy {
+	let max_files = env::var("LDK_RGS_MAX_SNAPSHOT_FILES").unwrap_or("1000".to_string())
+		.parse::<usize>()
+		.expect("LDK_RGS_MAX_SNAPSHOT_FILES env variable must be a usize.");
+	let max_age_days = env::var("LDK_RGS_SNAPSHOT_MAX_AGE_DAYS").unwrap_or("30".to_string())
+		.parse::<u32>()
+		.expect("LDK_RGS_SNAPSHOT_MAX_AGE_DAYS env variable must be a u32.");
+	let min_free_bytes = env::var("LDK_RGS_MIN_FREE_DISK_BYTES").unwrap_or("0".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_MIN_FREE_DISK_BYTES env variable must be a u64.");
+	SnapshotRetentionPolicy { max_files, max_age_days, min_free_bytes }
+}
+
+/// Channels without an update this recent in both directions are omitted from full snapshots, as
+/// clients would prune them right away. Defaults to LDK's stale channel horizon of two weeks.
+pub(crate) fn snapshot_stale_channel_horizon() -> Option<Duration> {
+	let horizon_secs = env::var("LDK_RGS_SNAPSHOT_STALE_CHANNEL_HORIZON_SECS").unwrap_or((14 * 24 * 60 * 60).to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_SNAPSHOT_STALE_CHANNEL_HORIZON_SECS env variable must be a u64.");
+	Some(Duration::from_secs(horizon_secs)).filter(|horizon| !horizon.is_zero())
+}
+
+/// Whether every generation round moves the superseded generation's snapshots to the archive,
+/// which the snapshot retention policy prunes, instead of deleting them
+pub(crate) fn snapshot_archive_enabled() -> bool {
+	env::var("LDK_RGS_SNAPSHOT_ARCHIVE").unwrap_or("false".to_string())
+		.parse::<bool>()
+		.expect("LDK_RGS_SNAPSHOT_ARCHIVE env variable must be true or false.")
+}
+
+/// Whether every generation also writes extended snapshots, which carry the capacity of each
+/// announced channel, alongside the standard ones
+pub(crate) fn extended_snapshots_enabled() -> bool {
+	env::var("LDK_RGS_SNAPSHOT_CAPACITIES").unwrap_or("false".to_string())
+		.parse::<bool>()
+		.expect("LDK_RGS_SNAPSHOT_CAPACITIES env variable must be true or false.")
+}
+
+/// Whether every generation round ends by checking that the newest delta chains onto the previous
+/// generation's full snapshot
+pub(crate) fn chain_verification_enabled() -> bool {
+	env::var("LDK_RGS_VERIFY_CHAIN").unwrap_or("false".to_string())
+		.parse::<bool>()
+		.expect("LDK_RGS_VERIFY_CHAIN env variable must be true or false.")
+}
+
+/// How much the graph must change for snapshots to be generated ahead of schedule
+pub(crate) fn snapshot_triggers() -> SnapshotTriggers {
+	let new_channels = env::var("LDK_RGS_SNAPSHOT_TRIGGER_NEW_CHANNELS").unwrap_or("100".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_SNAPSHOT_TRIGGER_NEW_CHANNELS env variable must be a u64.");
+	let policy_changes_percent = env::var("LDK_RGS_SNAPSHOT_TRIGGER_POLICY_CHANGES_PCT").unwrap_or("5.0".to_string())
+		.parse::<f64>()
+		.expect("LDK_RGS_SNAPSHOT_TRIGGER_POLICY_CHANGES_PCT env variable must be a number.");
+	assert!((0.0..=100.0).contains(&policy_changes_percent), "LDK_RGS_SNAPSHOT_TRIGGER_POLICY_CHANGES_PCT must be between 0 and 100");
+	SnapshotTriggers { new_channels, policy_changes_percent }
+}
+
+/// The thresholds below which fee-only changes are left out of delta snapshots, all of which
+/// default to zero, which disables damping
+pub(crate) fn fee_damping() -> FeeDamping {
+	let base_fee_msat = env::var("LDK_RGS_FEE_DAMPING_BASE_MSAT").unwrap_or("0".to_string())
+		.parse::<u32>()
+		.expect("LDK_RGS_FEE_DAMPING_BASE_MSAT env variable must be a u32.");
+	let fee_rate_ppm = env::var("LDK_RGS_FEE_DAMPING_PPM").unwrap_or("0".to_string())
+		.parse::<u32>()
+		.expect("LDK_RGS_FEE_DAMPING_PPM env variable must be a u32.");
+	let relative_percent = env::var("LDK_RGS_FEE_DAMPING_PERCENT").unwrap_or("0".to_string())
+		.parse::<u32>()
+		.expect("LDK_RGS_FEE_DAMPING_PERCENT env variable must be a u32.");
+	assert!(relative_percent <= 100, "LDK_RGS_FEE_DAMPING_PERCENT must be at most 100");
+	FeeDamping { base_fee_msat, fee_rate_ppm, relative_percent }
+}
+
+/// Clients that last synced longer ago than this are served the full snapshot rather than a delta,
+/// so no delta snapshots with larger scopes are generated. Defaults to generating them all.
+pub(crate) fn full_snapshot_age() -> Option<u64> {
+	let age_days = env::var("LDK_RGS_FULL_SNAPSHOT_AGE_DAYS").ok()?
+		.parse::<u64>()
+		.expect("LDK_RGS_FULL_SNAPSHOT_AGE_DAYS env variable must be a u64.");
+	assert!(age_days > 0, "LDK_RGS_FULL_SNAPSHOT_AGE_DAYS must be at least 1");
+	Some(age_days * 24 * 3600)
+}
+
+pub(crate) fn lookup_pacing() -> LookupPacing {
+	let max_rows_per_second = env::var("LDK_RGS_LOOKUP_MAX_ROWS_PER_SEC").unwrap_or("0".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_LOOKUP_MAX_ROWS_PER_SEC env variable must be a u64.");
+	let statement_timeout_ms = env::var("LDK_RGS_LOOKUP_STATEMENT_TIMEOUT_MS").unwrap_or("0".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_LOOKUP_STATEMENT_TIMEOUT_MS env variable must be a u64.");
+	let work_mem = env::var("LDK_RGS_LOOKUP_WORK_MEM").unwrap_or_default();
+	LookupPacing {
+		max_rows_per_second: Some(max_rows_per_second).filter(|rate| *rate > 0),
+		statement_timeout: Some(Duration::from_millis(statement_timeout_ms)).filter(|timeout| !timeout.is_zero()),
+		work_mem: Some(work_mem).filter(|work_mem| !work_mem.is_empty()),
+	}
+}
+
+pub(crate) fn max_snapshot_blob_bytes() -> u64 {
+	env::var("LDK_RGS_MAX_SNAPSHOT_BYTES").unwrap_or((16 * 1024 * 1024).to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_MAX_SNAPSHOT_BYTES env variable must be a u64.")
+}
+
+pub(crate) fn batch_size() -> usize {
+	let batch_size = env::var("LDK_RGS_BATCH_SIZE").unwrap_or("100".to_string())
+		.parse::<usize>()
+		.expect("LDK_RGS_BATCH_SIZE env variable must be a usize.");
+	assert!(batch_size > 0, "LDK_RGS_BATCH_SIZE must be positive");
+	batch_size
+}
+
+pub(crate) fn batch_flush_interval() -> Duration {
+	let interval = env::var("LDK_RGS_BATCH_FLUSH_MS").unwrap_or("50".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_BATCH_FLUSH_MS env variable must be a u64.");
+	assert!(interval > 0, "LDK_RGS_BATCH_FLUSH_MS must be positive");
+	Duration::from_millis(interval)
+}
+
+/// The local addresses outbound peer connections are bound to, at most one IPv4 and one IPv6
+/// address
+pub(crate) fn local_bind_addresses() -> LocalBindAddresses {
+	let addresses = env::var("LN_LOCAL_BIND_ADDR").unwrap_or_default();
+	LocalBindAddresses::parse(&addresses).unwrap_or_else(|error| panic!("LN_LOCAL_BIND_ADDR env variable must be a comma separated list of IP addresses: {}", error))
+}
+
+pub(crate) fn peer_connect_concurrency() -> usize {
+	let concurrency = env::var("LDK_RGS_PEER_CONNECT_CONCURRENCY").unwrap_or(PEER_CONNECT_CONCURRENCY.to_string())
+		.parse::<usize>()
+		.expect("LDK_RGS_PEER_CONNECT_CONCURRENCY env variable must be a usize.");
+	assert!(concurrency > 0, "LDK_RGS_PEER_CONNECT_CONCURRENCY must be positive");
+	concurrency
+}
+
+/// The number of threads gossip signatures are initially verified on, half the CPU count by default
+pub(crate) fn validation_threads() -> usize {
+	let default_threads = std::thread::available_parallelism().map_or(1, |parallelism| (parallelism.get() / 2).max(1));
+	let threads = env::var("LDK_RGS_VALIDATION_THREADS").unwrap_or(default_threads.to_string())
+		.parse::<usize>()
+		.expect("LDK_RGS_VALIDATION_THREADS env variable must be a usize.");
+	assert!(threads > 0, "LDK_RGS_VALIDATION_THREADS must be positive");
+	threads
+}
+
+/// Whether the server starts out publishing snapshots, or as a warm standby that only does so
+/// once promoted
+pub(crate) fn server_role() -> ServerRole {
+	env::var("LDK_RGS_ROLE").unwrap_or("active".to_string())
+		.parse::<ServerRole>()
+		.expect("LDK_RGS_ROLE env variable must be active or standby.")
+}
+
+/// What to do once the named component stops, configured through
+/// `LDK_RGS_{component}_FAILURE_POLICY`
+pub(crate) fn failure_policy(component: &str) -> FailurePolicy {
+	let variable = format!("LDK_RGS_{}_FAILURE_POLICY", component.to_uppercase());
+	env::var(&variable).unwrap_or("restart".to_string())
+		.parse::<FailurePolicy>()
+		.unwrap_or_else(|_| panic!("{} env variable must be restart or exit.", variable))
+}
+
+pub(crate) fn component_restart_budget() -> RestartBudget {
+	let max_restarts = env::var("LDK_RGS_COMPONENT_RESTART_LIMIT").unwrap_or("3".to_string())
+		.parse::<usize>()
+		.expect("LDK_RGS_COMPONENT_RESTART_LIMIT env variable must be a usize.");
+	let window_mins = env::var("LDK_RGS_COMPONENT_RESTART_WINDOW_MINS").unwrap_or("60".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_COMPONENT_RESTART_WINDOW_MINS env variable must be a u64.");
+	assert!(window_mins > 0, "LDK_RGS_COMPONENT_RESTART_WINDOW_MINS must be positive");
+	RestartBudget { max_restarts, window: Duration::from_secs(window_mins * 60) }
+}
+
+pub(crate) fn brotli_enabled() -> bool {
+	let enabled = env::var("LDK_RGS_ENABLE_BROTLI").unwrap_or("0".to_string())
+		.parse::<u8>()
+		.expect("LDK_RGS_ENABLE_BROTLI env variable must be 0 or 1.");
+	assert!(enabled <= 1, "LDK_RGS_ENABLE_BROTLI must be 0 or 1");
+	enabled == 1
+}
+
+/// Where to append all received gossip to, if archiving it is enabled
+pub(crate) fn gossip_archive_path() -> Option<String> {
+	let enabled = env::var("LDK_RGS_ENABLE_ARCHIVE").unwrap_or("0".to_string())
+		.parse::<u8>()
+		.expect("LDK_RGS_ENABLE_ARCHIVE env variable must be 0 or 1.");
+	assert!(enabled <= 1, "LDK_RGS_ENABLE_ARCHIVE must be 0 or 1");
+	(enabled == 1).then(|| env::var("LDK_RGS_ARCHIVE_FILE").unwrap_or(format!("{}/gossip_archive.bin", cache_path())))
+}
+
+pub(crate) fn archival_queries_enabled() -> bool {
+	env::var("LDK_RGS_ARCHIVAL_QUERIES").unwrap_or("false".to_string())
+		.parse::<bool>()
+		.expect("LDK_RGS_ARCHIVAL_QUERIES env variable must be true or false.")
+}
+
+pub(crate) fn max_updates_per_scid_per_hour() -> usize {
+	let max_updates = env::var("LDK_RGS_MAX_UPDATES_PER_SCID_PER_HOUR").unwrap_or("12".to_string())
+		.parse::<usize>()
+		.expect("LDK_RGS_MAX_UPDATES_PER_SCID_PER_HOUR env variable must be a usize.");
+	assert!(max_updates > 0, "LDK_RGS_MAX_UPDATES_PER_SCID_PER_HOUR must be positive");
+	max_updates
+}
+
+/// How far ahead of the current time channel update timestamps may be before updates are rejected
+pub(crate) fn max_future_timestamp_secs() -> u64 {
+	env::var("LDK_RGS_MAX_FUTURE_TIMESTAMP_SECS").unwrap_or("7200".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_MAX_FUTURE_TIMESTAMP_SECS env variable must be a u64.")
+}
+
+/// How long a peer may go without sending anything before it's pinged to check it's still alive
+pub(crate) fn peer_silence_threshold_secs() -> u64 {
+	let threshold_secs = env::var("LDK_RGS_PEER_SILENCE_THRESHOLD_SECS").unwrap_or("600".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_PEER_SILENCE_THRESHOLD_SECS env variable must be a u64.");
+	assert!(threshold_secs > 0, "LDK_RGS_PEER_SILENCE_THRESHOLD_SECS must be positive");
+	threshold_secs
+}
+
+/// How long a peer may go without sending a single byte, not even answering pings, before it's
+/// disconnected
+pub(crate) fn max_peer_idle_secs() -> u64 {
+	let idle_secs = env::var("LDK_RGS_MAX_PEER_IDLE_SECS").unwrap_or("1800".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_MAX_PEER_IDLE_SECS env variable must be a u64.");
+	assert!(idle_secs > 0, "LDK_RGS_MAX_PEER_IDLE_SECS must be positive");
+	idle_secs
+}
+
+/// The median delay between channel updates' timestamps and their receipt above which the
+/// propagation is considered lagging
+pub(crate) fn propagation_lag_threshold_secs() -> u64 {
+	let threshold_secs = env::var("LDK_RGS_PROPAGATION_LAG_THRESHOLD_SECS").unwrap_or("1800".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_PROPAGATION_LAG_THRESHOLD_SECS env variable must be a u64.");
+	assert!(threshold_secs > 0, "LDK_RGS_PROPAGATION_LAG_THRESHOLD_SECS must be positive");
+	threshold_secs
+}
+
+/// How long channel updates arriving before their channel's announcement are held for it, unless
+/// that's disabled by setting it to 0
+pub(crate) fn early_update_window() -> Option<Duration> {
+	let window_secs = env::var("LDK_RGS_EARLY_UPDATE_WINDOW_SECS").unwrap_or("60".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_EARLY_UPDATE_WINDOW_SECS env variable must be a u64.");
+	Some(Duration::from_secs(window_secs)).filter(|window| !window.is_zero())
+}
+
+/// The rate at which gossip may be relayed to peers in bytes per second, if it's limited at all
+pub(crate) fn relay_bytes_per_second() -> Option<u64> {
+	let bytes_per_second = env::var("LDK_RGS_RELAY_BYTES_PER_SEC").unwrap_or("0".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_RELAY_BYTES_PER_SEC env variable must be a u64.");
+	Some(bytes_per_second).filter(|bytes_per_second| *bytes_per_second > 0)
+}
+
+pub(crate) fn relay_burst_bytes() -> u64 {
+	let burst_bytes = env::var("LDK_RGS_RELAY_BURST_BYTES").unwrap_or("1048576".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_RELAY_BURST_BYTES env variable must be a u64.");
+	assert!(burst_bytes > 0, "LDK_RGS_RELAY_BURST_BYTES must be positive");
+	burst_bytes
+}
+
+/// How far the newest published snapshot may fall behind before the stall webhook is notified, by
+/// default twice the snapshot interval
+pub(crate) fn max_publication_lag() -> Duration {
+	let default_lag = 2 * snapshot_generation_interval() as u64;
+	let lag = env::var("LDK_RGS_MAX_PUBLICATION_LAG_SECS").unwrap_or(default_lag.to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_MAX_PUBLICATION_LAG_SECS env variable must be a u64.");
+	Duration::from_secs(lag)
+}
+
+/// The number of channels each connected peer is assumed to know of when estimating how far the
+/// catch-up has progressed
+pub(crate) fn avg_channels_per_peer() -> u64 {
+	env::var("LDK_RGS_AVG_CHANNELS_PER_PEER").unwrap_or(DEFAULT_AVG_CHANNELS_PER_PEER.to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_AVG_CHANNELS_PER_PEER env variable must be a u64.")
+}
+
+/// How long after connecting to peers the initial sync may take to catch up with gossip before
+/// it's considered failed, unless that's disabled by setting it to 0
+pub(crate) fn max_catch_up_time() -> Option<Duration> {
+	let max_secs = env::var("LDK_RGS_MAX_CATCHUP_SECS").unwrap_or("0".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_MAX_CATCHUP_SECS env variable must be a u64.");
+	Some(Duration::from_secs(max_secs)).filter(|max_time| !max_time.is_zero())
+}
+
+/// The fewest channels a graph may hold once caught up with gossip, as fewer suggest our peers
+/// only know of part of the network. Mainnet defaults to 10,000, the test networks to no minimum.
+pub(crate) fn min_channels_for_valid_graph() -> u64 {
+	let default_min_channels = match network() {
+		Network::Bitcoin => DEFAULT_MAINNET_MIN_CHANNELS,
+		_ => 0,
+	};
+	env::var("LDK_RGS_MIN_CHANNELS_FOR_VALID_GRAPH").unwrap_or(default_min_channels.to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_MIN_CHANNELS_FOR_VALID_GRAPH env variable must be a u64.")
+}
+
+/// When configured peers that keep failing to connect are only retried daily
+pub(crate) fn dormancy_policy() -> DormancyPolicy {
+	let failure_threshold = env::var("LDK_RGS_DORMANT_AFTER_FAILURES").unwrap_or("20".to_string())
+		.parse::<u32>()
+		.expect("LDK_RGS_DORMANT_AFTER_FAILURES env variable must be a u32.");
+	assert!(failure_threshold > 0, "LDK_RGS_DORMANT_AFTER_FAILURES must be positive");
+	let min_failing_days = env::var("LDK_RGS_DORMANT_AFTER_DAYS").unwrap_or("7".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_DORMANT_AFTER_DAYS env variable must be a u64.");
+	DormancyPolicy {
+		failure_threshold,
+		min_failing_duration: Duration::from_secs(min_failing_days * 24 * 60 * 60),
+		target_connected_peers: CONNECTED_PEER_ASSERTION_LIMIT,
+	}
+}
+
+/// Whether peers' gossip queries are answered completely, as BOLT 7 requires, which implies
+/// answering them from the database, too
+pub(crate) fn respond_to_queries_enabled() -> bool {
+	let enabled = env::var("LDK_RGS_RESPOND_TO_QUERIES").unwrap_or("0".to_string())
+		.parse::<u8>()
+		.expect("LDK_RGS_RESPOND_TO_QUERIES env variable must be 0 or 1.");
+	assert!(enabled <= 1, "LDK_RGS_RESPOND_TO_QUERIES must be 0 or 1");
+	enabled == 1
+}
+
+pub(crate) fn archival_query_concurrency() -> usize {
+	let concurrency = env::var("LDK_RGS_ARCHIVAL_QUERY_CONCURRENCY").unwrap_or("1".to_string())
+		.parse::<usize>()
+		.expect("LDK_RGS_ARCHIVAL_QUERY_CONCURRENCY env variable must be a usize.");
+	assert!(concurrency > 0, "LDK_RGS_ARCHIVAL_QUERY_CONCURRENCY must be positive");
+	concurrency
+}
+
+/// Whether gossip that can't be persisted is kept in the dead letter table for later inspection
+pub(crate) fn dead_letters_enabled() -> bool {
+	env::var("LDK_RGS_DEAD_LETTERS").unwrap_or("true".to_string())
+		.parse::<bool>()
+		.expect("LDK_RGS_DEAD_LETTERS env variable must be true or false.")
+}
+
+pub(crate) fn max_p99_latency() -> Duration {
+	let latency = env::var("LDK_RGS_MAX_P99_LATENCY_MS").unwrap_or("100".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_MAX_P99_LATENCY_MS env variable must be a u64.");
+	Duration::from_millis(latency)
+}
+
+pub(crate) fn exact_delta_enabled() -> bool {
+	env::var("LDK_RGS_EXACT_DELTA").unwrap_or("false".to_string())
+		.parse::<bool>()
+		.expect("LDK_RGS_EXACT_DELTA env variable must be true or false.")
+}
+
+pub(crate) fn exact_delta_time_budget() -> Duration {
+	let budget = env::var("LDK_RGS_EXACT_DELTA_BUDGET_MS").unwrap_or("2000".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_EXACT_DELTA_BUDGET_MS env variable must be a u64.");
+	Duration::from_millis(budget)
+}
+
+pub(crate) fn exact_delta_concurrency() -> usize {
+	env::var("LDK_RGS_EXACT_DELTA_CONCURRENCY").unwrap_or("2".to_string())
+		.parse::<usize>()
+		.expect("LDK_RGS_EXACT_DELTA_CONCURRENCY env variable must be a usize.")
+}
+
+pub(crate) fn min_funding_confirmations() -> u32 {
+	env::var("LDK_RGS_MIN_FUNDING_CONFIRMATIONS").unwrap_or("6".to_string())
+		.parse::<u32>()
+		.expect("LDK_RGS_MIN_FUNDING_CONFIRMATIONS env variable must be a u32.")
+}
+
+pub(crate) fn slow_chain_lookup_threshold() -> Duration {
+	let threshold = env::var("LDK_RGS_SLOW_CHAIN_LOOKUP_MS").unwrap_or("1000".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_SLOW_CHAIN_LOOKUP_MS env variable must be a u64.");
+	Duration::from_millis(threshold)
+}
+
+/// Restarts with a gossip watermark younger than this only request the gossip missed in between,
+/// rather than performing a full initial sync
+pub(crate) fn max_gossip_watermark_age() -> Duration {
+	let minutes = env::var("LDK_RGS_MAX_WATERMARK_AGE_MINS").unwrap_or("120".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_MAX_WATERMARK_AGE_MINS env variable must be a u64.");
+	Duration::from_secs(minutes * 60)
+}
+
+pub(crate) fn max_chain_backend_probe_latency() -> Duration {
+	let latency = env::var("LDK_RGS_MAX_CHAIN_PROBE_LATENCY_MS").unwrap_or("250".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_MAX_CHAIN_PROBE_LATENCY_MS env variable must be a u64.");
+	Duration::from_millis(latency)
+}
+
 pub(crate) fn network() -> Network {
 	let network = env::var("RAPID_GOSSIP_SYNC_SERVER_NETWORK").unwrap_or("bitcoin".to_string()).to_lowercase();
 	match network.as_str() {
@@ -70,6 +503,48 @@ pub(crate) fn network_graph_cache_path() -> String {
 	format!("{}/network_graph.bin", cache_path())
 }
 
+pub(crate) fn stats_path() -> String {
+	format!("{}/stats", cache_path())
+}
+
+pub(crate) fn fee_stats_interval() -> Duration {
+	let interval = env::var("LDK_RGS_FEE_STATS_INTERVAL_SECS").unwrap_or("3600".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_FEE_STATS_INTERVAL_SECS env variable must be a u64.");
+	assert!(interval > 0, "LDK_RGS_FEE_STATS_INTERVAL_SECS must be positive");
+	Duration::from_secs(interval)
+}
+
+pub(crate) fn stats_history_interval() -> Duration {
+	let interval = env::var("LDK_RGS_STATS_HISTORY_INTERVAL_MINS").unwrap_or("60".to_string())
+		.parse::<u64>()
+		.expect("LDK_RGS_STATS_HISTORY_INTERVAL_MINS env variable must be a u64.");
+	assert!(interval > 0, "LDK_RGS_STATS_HISTORY_INTERVAL_MINS must be positive");
+	Duration::from_secs(interval * 60)
+}
+
+pub(crate) fn stats_history_retention_days() -> u32 {
+	env::var("LDK_RGS_STATS_HISTORY_RETENTION_DAYS").unwrap_or("365".to_string())
+		.parse::<u32>()
+		.expect("LDK_RGS_STATS_HISTORY_RETENTION_DAYS env variable must be a u32.")
+}
+
+/// Whether the gossip tables' bloat is estimated along with every stats history entry
+pub(crate) fn db_bloat_reporting_enabled() -> bool {
+	env::var("LDK_RGS_DB_BLOAT_REPORTING").unwrap_or("false".to_string())
+		.parse::<bool>()
+		.expect("LDK_RGS_DB_BLOAT_REPORTING env variable must be true or false.")
+}
+
+/// The hours of the day during which the gossip tables may be reindexed and vacuumed, if at all
+pub(crate) fn db_maintenance_window() -> Option<MaintenanceWindow> {
+	let window = env::var("LDK_RGS_DB_MAINTENANCE_WINDOW").ok()?;
+	if window.trim().is_empty() {
+		return None;
+	}
+	Some(MaintenanceWindow::parse(&window).expect("LDK_RGS_DB_MAINTENANCE_WINDOW must be two different UTC hours, such as 3-5."))
+}
+
 pub(crate) fn cache_path() -> String {
 	let path = env::var("RAPID_GOSSIP_SYNC_SERVER_CACHES_PATH").unwrap_or("./res".to_string()).to_lowercase();
 	path
@@ -95,6 +570,36 @@ pub(crate) fn db_connection_config() -> Config {
 	config
 }
 
+/// The schema holding the gossip tables, if not the connection's default
+/// The shared library to load a persistence backend plugin from, if any
+pub(crate) fn backend_plugin_path() -> Option<String> {
+	env::var("LDK_RGS_BACKEND_PLUGIN").ok().filter(|path| !path.trim().is_empty())
+}
+
+pub(crate) fn db_schema() -> Option<String> {
+	let schema = env::var("LDK_RGS_DB_SCHEMA").ok()?.trim().to_lowercase();
+	if schema.is_empty() {
+		return None;
+	}
+	assert!(is_valid_identifier(&schema), "LDK_RGS_DB_SCHEMA may only contain lowercase ASCII letters, digits, and underscores");
+	Some(schema)
+}
+
+pub(crate) fn db_table_prefix() -> String {
+	#[cfg(test)]
+	if let Some(prefix) = crate::tests::db_test_table_prefix() {
+		return prefix;
+	}
+	let prefix = env::var("LDK_RGS_DB_TABLE_PREFIX").unwrap_or_default().trim().to_lowercase();
+	assert!(is_valid_identifier(&prefix), "LDK_RGS_DB_TABLE_PREFIX may only contain lowercase ASCII letters, digits, and underscores");
+	prefix
+}
+
+/// Whether a schema name or table prefix can be interpolated into queries without quoting
+pub(crate) fn is_valid_identifier(identifier: &str) -> bool {
+	identifier.chars().all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+}
+
 pub(crate) fn bitcoin_rest_endpoint() -> HttpEndpoint {
 	let host = env::var("BITCOIN_REST_DOMAIN").unwrap_or("127.0.0.1".to_string());
 	let port = env::var("BITCOIN_REST_PORT")
@@ -105,24 +610,27 @@ pub(crate) fn bitcoin_rest_endpoint() -> HttpEndpoint {
 	HttpEndpoint::for_host(host).with_port(port).with_path(path)
 }
 
-pub(crate) fn db_config_table_creation_query() -> &'static str {
-	"CREATE TABLE IF NOT EXISTS config (
+pub(crate) fn db_config_table_creation_query(tables: &Tables) -> String {
+	format!("CREATE TABLE IF NOT EXISTS {} (
 		id SERIAL PRIMARY KEY,
-		db_schema integer
-	)"
+		db_schema integer,
+		gossip_watermark timestamp
+	)", tables.config())
 }
 
-pub(crate) fn db_announcement_table_creation_query() -> &'static str {
-	"CREATE TABLE IF NOT EXISTS channel_announcements (
+pub(crate) fn db_announcement_table_creation_query(tables: &Tables) -> String {
+	format!("CREATE TABLE IF NOT EXISTS {} (
 		id SERIAL PRIMARY KEY,
 		short_channel_id bigint NOT NULL UNIQUE,
 		announcement_signed BYTEA,
-		seen timestamp NOT NULL DEFAULT NOW()
-	)"
+		seen timestamp NOT NULL DEFAULT NOW(),
+		funding_block_time timestamp,
+		capacity_sats bigint
+	)", tables.channel_announcements())
 }
 
-pub(crate) fn db_channel_update_table_creation_query() -> &'static str {
-	"CREATE TABLE IF NOT EXISTS channel_updates (
+pub(crate) fn db_channel_update_table_creation_query(tables: &Tables) -> String {
+	format!("CREATE TABLE IF NOT EXISTS {} (
 		id SERIAL PRIMARY KEY,
 		short_channel_id bigint NOT NULL,
 		timestamp bigint NOT NULL,
@@ -136,48 +644,137 @@ pub(crate) fn db_channel_update_table_creation_query() -> &'static str {
 		htlc_maximum_msat bigint NOT NULL,
 		blob_signed BYTEA NOT NULL,
 		seen timestamp NOT NULL DEFAULT NOW()
-	)"
+	)", tables.channel_updates())
 }
 
-pub(crate) fn db_node_announcement_table_creation_query() -> &'static str {
-	"CREATE TABLE IF NOT EXISTS node_announcements (
+pub(crate) fn db_node_announcement_table_creation_query(tables: &Tables) -> String {
+	format!("CREATE TABLE IF NOT EXISTS {} (
 		id SERIAL PRIMARY KEY,
-		public_key varchar(66) NOT NULL,
+		public_key BYTEA NOT NULL,
 		features BYTEA NOT NULL,
 		socket_addresses BYTEA NOT NULL,
 		timestamp bigint NOT NULL,
 		announcement_signed BYTEA,
 		seen timestamp NOT NULL DEFAULT NOW()
-	)"
+	)", tables.node_announcements())
 }
 
-pub(crate) fn db_index_creation_query() -> &'static str {
-	"
-	CREATE INDEX IF NOT EXISTS channel_updates_seen_scid ON channel_updates(seen, short_channel_id);
-	CREATE INDEX IF NOT EXISTS channel_updates_scid_dir_seen_asc ON channel_updates(short_channel_id, direction, seen);
-	CREATE INDEX IF NOT EXISTS channel_updates_scid_dir_seen_desc_with_id ON channel_updates(short_channel_id ASC, direction ASC, seen DESC) INCLUDE (id);
-	CREATE UNIQUE INDEX IF NOT EXISTS channel_updates_key ON channel_updates (short_channel_id, direction, timestamp);
-	CREATE INDEX IF NOT EXISTS channel_updates_seen ON channel_updates(seen);
-	CREATE INDEX IF NOT EXISTS channel_updates_scid_asc_timestamp_desc ON channel_updates(short_channel_id ASC, timestamp DESC);
-	"
+pub(crate) fn db_channel_removal_table_creation_query(tables: &Tables) -> String {
+	format!("CREATE TABLE IF NOT EXISTS {} (
+		id SERIAL PRIMARY KEY,
+		short_channel_id bigint NOT NULL,
+		seen timestamp NOT NULL DEFAULT NOW()
+	)", tables.channel_removals())
 }
 
-pub(crate) async fn upgrade_db(schema: i32, client: &mut tokio_postgres::Client) {
+pub(crate) fn db_stats_history_table_creation_query(tables: &Tables) -> String {
+	format!("CREATE TABLE IF NOT EXISTS {} (
+		id SERIAL PRIMARY KEY,
+		recorded_at timestamp NOT NULL DEFAULT NOW(),
+		started_at timestamp NOT NULL,
+		node_announcements bigint NOT NULL,
+		channel_announcements bigint NOT NULL,
+		channel_updates bigint NOT NULL,
+		channel_updates_without_htlc_max_msats bigint NOT NULL,
+		channel_announcements_with_mismatched_scripts bigint NOT NULL,
+		ignored_onion_messages bigint NOT NULL,
+		ignored_custom_messages bigint NOT NULL,
+		connected_peers bigint NOT NULL,
+		channel_announcement_rows bigint NOT NULL,
+		channel_update_rows bigint NOT NULL,
+		node_announcement_rows bigint NOT NULL,
+		bidirectional_channels bigint NOT NULL DEFAULT 0,
+		direction_0_only_channels bigint NOT NULL DEFAULT 0,
+		direction_1_only_channels bigint NOT NULL DEFAULT 0,
+		channels_without_updates bigint NOT NULL DEFAULT 0,
+		dead_rows bigint,
+		index_bytes bigint,
+		snapshot_downloads bigint,
+		not_modified_responses bigint,
+		bytes_served bigint
+	)", tables.stats_history())
+}
+
+pub(crate) fn db_peer_state_table_creation_query(tables: &Tables) -> String {
+	format!("CREATE TABLE IF NOT EXISTS {} (
+		public_key BYTEA PRIMARY KEY,
+		address text,
+		learned_at timestamp DEFAULT NOW(),
+		consecutive_failures integer NOT NULL DEFAULT 0,
+		failing_since timestamp,
+		last_success_at timestamp
+	)", tables.peer_state())
+}
+
+pub(crate) fn db_snapshot_coverage_table_creation_query(tables: &Tables) -> String {
+	format!("CREATE TABLE IF NOT EXISTS {} (
+		reference_timestamp bigint PRIMARY KEY,
+		newest_update_timestamp bigint NOT NULL
+	)", tables.snapshot_coverage())
+}
+
+pub(crate) fn db_sync_totals_table_creation_query(tables: &Tables) -> String {
+	format!("CREATE TABLE IF NOT EXISTS {} (
+		id SERIAL PRIMARY KEY,
+		completed_at timestamp NOT NULL DEFAULT NOW(),
+		estimated_channel_count bigint,
+		channel_count bigint NOT NULL
+	)", tables.sync_totals())
+}
+
+pub(crate) fn db_dead_letter_table_creation_query(tables: &Tables) -> String {
+	format!("CREATE TABLE IF NOT EXISTS {} (
+		id SERIAL PRIMARY KEY,
+		failed_at timestamp NOT NULL DEFAULT NOW(),
+		message_type text NOT NULL,
+		message_signed BYTEA NOT NULL,
+		error text NOT NULL
+	)", tables.dead_letters())
+}
+
+pub(crate) fn db_index_creation_query(tables: &Tables) -> String {
+	format!("
+	CREATE INDEX IF NOT EXISTS {prefix}channel_updates_seen_scid ON {channel_updates}(seen, short_channel_id);
+	CREATE INDEX IF NOT EXISTS {prefix}channel_updates_scid_dir_seen_asc ON {channel_updates}(short_channel_id, direction, seen);
+	CREATE INDEX IF NOT EXISTS {prefix}channel_updates_scid_dir_seen_desc_with_id ON {channel_updates}(short_channel_id ASC, direction ASC, seen DESC) INCLUDE (id);
+	CREATE UNIQUE INDEX IF NOT EXISTS {prefix}channel_updates_key ON {channel_updates} (short_channel_id, direction, timestamp);
+	CREATE INDEX IF NOT EXISTS {prefix}channel_updates_seen ON {channel_updates}(seen);
+	CREATE INDEX IF NOT EXISTS {prefix}channel_updates_scid_asc_timestamp_desc ON {channel_updates}(short_channel_id ASC, timestamp DESC);
+	CREATE INDEX IF NOT EXISTS {prefix}stats_history_recorded_at ON {stats_history}(recorded_at);
+	", prefix = tables.prefix(), channel_updates = tables.channel_updates(), stats_history = tables.stats_history())
+}
+
+pub(crate) async fn upgrade_db(schema: i32, client: &mut tokio_postgres::Client, tables: &Tables) -> Result<(), ProcessorError> {
+	migrate_db(schema, client, tables).await.context(format!("Failed to upgrade db from schema {}", schema))?;
+	if schema <= 1 || schema > SCHEMA_VERSION {
+		return Err(ProcessorError::UnknownSchema { found: schema, supported: SCHEMA_VERSION });
+	}
+	// PostgreSQL (at least v13, but likely later versions as well) handles insert-only tables
+	// *very* poorly. After some number of inserts, it refuses to rely on indexes, assuming them to
+	// be possibly-stale, until a VACUUM happens. Thus, we set the vacuum factor really low here,
+	// pushing PostgreSQL to vacuum often.
+	// See https://www.cybertec-postgresql.com/en/postgresql-autovacuum-insert-only-tables/
+	let _ = client.execute(&format!("ALTER TABLE {} SET ( autovacuum_vacuum_insert_scale_factor = 0.005 );", tables.channel_updates()), &[]).await;
+	let _ = client.execute(&format!("ALTER TABLE {} SET ( autovacuum_vacuum_insert_scale_factor = 0.005 );", tables.channel_announcements()), &[]).await;
+	Ok(())
+}
+
+async fn migrate_db(schema: i32, client: &mut tokio_postgres::Client, tables: &Tables) -> Result<(), tokio_postgres::Error> {
 	if schema == 1 {
-		let tx = client.transaction().await.unwrap();
-		tx.execute("ALTER TABLE channel_updates DROP COLUMN chain_hash", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_announcements DROP COLUMN chain_hash", &[]).await.unwrap();
-		tx.execute("UPDATE config SET db_schema = 2 WHERE id = 1", &[]).await.unwrap();
-		tx.commit().await.unwrap();
+		let tx = client.transaction().await?;
+		tx.execute(&format!("ALTER TABLE {} DROP COLUMN chain_hash", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} DROP COLUMN chain_hash", tables.channel_announcements()), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 2 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
 	}
 	if schema == 1 || schema == 2 {
-		let tx = client.transaction().await.unwrap();
-		tx.execute("ALTER TABLE channel_updates DROP COLUMN short_channel_id", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_updates ADD COLUMN short_channel_id bigint DEFAULT null", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_updates DROP COLUMN direction", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_updates ADD COLUMN direction boolean DEFAULT null", &[]).await.unwrap();
+		let tx = client.transaction().await?;
+		tx.execute(&format!("ALTER TABLE {} DROP COLUMN short_channel_id", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ADD COLUMN short_channel_id bigint DEFAULT null", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} DROP COLUMN direction", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ADD COLUMN direction boolean DEFAULT null", tables.channel_updates()), &[]).await?;
 		loop {
-			let rows = tx.query("SELECT id, composite_index FROM channel_updates WHERE short_channel_id IS NULL LIMIT 50000", &[]).await.unwrap();
+			let rows = tx.query(&format!("SELECT id, composite_index FROM {} WHERE short_channel_id IS NULL LIMIT 50000", tables.channel_updates()), &[]).await?;
 			if rows.is_empty() { break; }
 			let mut updates = FuturesUnordered::new();
 			for row in rows {
@@ -186,32 +783,32 @@ pub(crate) async fn upgrade_db(schema: i32, client: &mut tokio_postgres::Client)
 				let tx_ref = &tx;
 				updates.push(async move {
 					let mut index_iter = index.split(":");
-					let scid_hex = index_iter.next().unwrap();
-					index_iter.next().unwrap();
-					let direction_str = index_iter.next().unwrap();
+					let scid_hex = index_iter.next().expect("composite index must start with the short channel id");
+					index_iter.next().expect("composite index must contain a timestamp");
+					let direction_str = index_iter.next().expect("composite index must end with the direction");
 					assert!(direction_str == "1" || direction_str == "0");
 					let direction = direction_str == "1";
-					let scid_be_bytes = hex_utils::to_vec(scid_hex).unwrap();
-					let scid = i64::from_be_bytes(scid_be_bytes.try_into().unwrap());
+					let scid_be_bytes = hex_utils::to_vec(scid_hex).expect("composite index short channel id must be hex");
+					let scid = i64::from_be_bytes(scid_be_bytes.try_into().expect("composite index short channel id must be 8 bytes"));
 					assert!(scid > 0); // Will roll over in some 150 years or so
-					tx_ref.execute("UPDATE channel_updates SET short_channel_id = $1, direction = $2 WHERE id = $3", &[&scid, &direction, &id]).await.unwrap();
+					tx_ref.execute(&format!("UPDATE {} SET short_channel_id = $1, direction = $2 WHERE id = $3", tables.channel_updates()), &[&scid, &direction, &id]).await
 				});
 			}
-			while let Some(_) = updates.next().await {}
+			while let Some(result) = updates.next().await { result?; }
 		}
-		tx.execute("ALTER TABLE channel_updates ALTER short_channel_id DROP DEFAULT", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_updates ALTER short_channel_id SET NOT NULL", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_updates ALTER direction DROP DEFAULT", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_updates ALTER direction SET NOT NULL", &[]).await.unwrap();
-		tx.execute("UPDATE config SET db_schema = 3 WHERE id = 1", &[]).await.unwrap();
-		tx.commit().await.unwrap();
+		tx.execute(&format!("ALTER TABLE {} ALTER short_channel_id DROP DEFAULT", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ALTER short_channel_id SET NOT NULL", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ALTER direction DROP DEFAULT", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ALTER direction SET NOT NULL", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 3 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
 	}
 	if schema >= 1 && schema <= 3 {
-		let tx = client.transaction().await.unwrap();
-		tx.execute("ALTER TABLE channel_announcements DROP COLUMN short_channel_id", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_announcements ADD COLUMN short_channel_id bigint DEFAULT null", &[]).await.unwrap();
+		let tx = client.transaction().await?;
+		tx.execute(&format!("ALTER TABLE {} DROP COLUMN short_channel_id", tables.channel_announcements()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ADD COLUMN short_channel_id bigint DEFAULT null", tables.channel_announcements()), &[]).await?;
 		loop {
-			let rows = tx.query("SELECT id, announcement_signed FROM channel_announcements WHERE short_channel_id IS NULL LIMIT 10000", &[]).await.unwrap();
+			let rows = tx.query(&format!("SELECT id, announcement_signed FROM {} WHERE short_channel_id IS NULL LIMIT 10000", tables.channel_announcements()), &[]).await?;
 			if rows.is_empty() { break; }
 			let mut updates = FuturesUnordered::new();
 			for row in rows {
@@ -219,132 +816,365 @@ pub(crate) async fn upgrade_db(schema: i32, client: &mut tokio_postgres::Client)
 				let announcement: Vec<u8> = row.get("announcement_signed");
 				let tx_ref = &tx;
 				updates.push(async move {
-					let scid = ChannelAnnouncement::read(&mut Cursor::new(announcement)).unwrap().contents.short_channel_id as i64;
+					let scid = ChannelAnnouncement::read(&mut Cursor::new(announcement)).expect("persisted channel announcements must be valid").contents.short_channel_id as i64;
 					assert!(scid > 0); // Will roll over in some 150 years or so
-					tx_ref.execute("UPDATE channel_announcements SET short_channel_id = $1 WHERE id = $2", &[&scid, &id]).await.unwrap();
+					tx_ref.execute(&format!("UPDATE {} SET short_channel_id = $1 WHERE id = $2", tables.channel_announcements()), &[&scid, &id]).await
 				});
 			}
-			while let Some(_) = updates.next().await {}
+			while let Some(result) = updates.next().await { result?; }
 		}
-		tx.execute("ALTER TABLE channel_announcements ADD CONSTRAINT channel_announcements_short_channel_id_key UNIQUE (short_channel_id)", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_announcements ALTER short_channel_id DROP DEFAULT", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_announcements ALTER short_channel_id SET NOT NULL", &[]).await.unwrap();
-		tx.execute("UPDATE config SET db_schema = 4 WHERE id = 1", &[]).await.unwrap();
-		tx.commit().await.unwrap();
+		tx.execute(&format!("ALTER TABLE {} ADD CONSTRAINT {} UNIQUE (short_channel_id)", tables.channel_announcements(), tables.index("channel_announcements_short_channel_id_key")), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ALTER short_channel_id DROP DEFAULT", tables.channel_announcements()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ALTER short_channel_id SET NOT NULL", tables.channel_announcements()), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 4 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
 	}
 	if schema >= 1 && schema <= 4 {
-		let tx = client.transaction().await.unwrap();
-		tx.execute("ALTER TABLE channel_updates ALTER composite_index SET DATA TYPE character(29)", &[]).await.unwrap();
-		tx.execute("UPDATE config SET db_schema = 5 WHERE id = 1", &[]).await.unwrap();
-		tx.commit().await.unwrap();
+		let tx = client.transaction().await?;
+		tx.execute(&format!("ALTER TABLE {} ALTER composite_index SET DATA TYPE character(29)", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 5 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
 	}
 	if schema >= 1 && schema <= 5 {
-		let tx = client.transaction().await.unwrap();
-		tx.execute("ALTER TABLE channel_updates ALTER channel_flags SET DATA TYPE smallint", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_announcements DROP COLUMN block_height", &[]).await.unwrap();
-		tx.execute("UPDATE config SET db_schema = 6 WHERE id = 1", &[]).await.unwrap();
-		tx.commit().await.unwrap();
+		let tx = client.transaction().await?;
+		tx.execute(&format!("ALTER TABLE {} ALTER channel_flags SET DATA TYPE smallint", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} DROP COLUMN block_height", tables.channel_announcements()), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 6 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
 	}
 	if schema >= 1 && schema <= 6 {
-		let tx = client.transaction().await.unwrap();
-		tx.execute("ALTER TABLE channel_updates DROP COLUMN composite_index", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_updates ALTER timestamp SET NOT NULL", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_updates ALTER channel_flags SET NOT NULL", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_updates ALTER disable SET NOT NULL", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_updates ALTER cltv_expiry_delta SET NOT NULL", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_updates ALTER htlc_minimum_msat SET NOT NULL", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_updates ALTER fee_base_msat SET NOT NULL", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_updates ALTER fee_proportional_millionths SET NOT NULL", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_updates ALTER htlc_maximum_msat SET NOT NULL", &[]).await.unwrap();
-		tx.execute("ALTER TABLE channel_updates ALTER blob_signed SET NOT NULL", &[]).await.unwrap();
-		tx.execute("CREATE UNIQUE INDEX channel_updates_key ON channel_updates (short_channel_id, direction, timestamp)", &[]).await.unwrap();
-		tx.execute("UPDATE config SET db_schema = 7 WHERE id = 1", &[]).await.unwrap();
-		tx.commit().await.unwrap();
+		let tx = client.transaction().await?;
+		tx.execute(&format!("ALTER TABLE {} DROP COLUMN composite_index", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ALTER timestamp SET NOT NULL", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ALTER channel_flags SET NOT NULL", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ALTER disable SET NOT NULL", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ALTER cltv_expiry_delta SET NOT NULL", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ALTER htlc_minimum_msat SET NOT NULL", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ALTER fee_base_msat SET NOT NULL", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ALTER fee_proportional_millionths SET NOT NULL", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ALTER htlc_maximum_msat SET NOT NULL", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ALTER blob_signed SET NOT NULL", tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("CREATE UNIQUE INDEX {} ON {} (short_channel_id, direction, timestamp)", tables.index("channel_updates_key"), tables.channel_updates()), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 7 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
 	}
 	if schema >= 1 && schema <= 7 {
-		let tx = client.transaction().await.unwrap();
-		tx.execute("DROP INDEX IF EXISTS channels_seen", &[]).await.unwrap();
-		tx.execute("DROP INDEX IF EXISTS channel_updates_scid", &[]).await.unwrap();
-		tx.execute("DROP INDEX IF EXISTS channel_updates_direction", &[]).await.unwrap();
-		tx.execute("DROP INDEX IF EXISTS channel_updates_seen", &[]).await.unwrap();
-		tx.execute("DROP INDEX IF EXISTS channel_updates_scid_seen", &[]).await.unwrap();
-		tx.execute("DROP INDEX IF EXISTS channel_updates_scid_dir_seen", &[]).await.unwrap();
-		tx.execute("UPDATE config SET db_schema = 8 WHERE id = 1", &[]).await.unwrap();
-		tx.commit().await.unwrap();
+		let tx = client.transaction().await?;
+		tx.execute("DROP INDEX IF EXISTS channels_seen", &[]).await?;
+		tx.execute(&format!("DROP INDEX IF EXISTS {}", tables.index("channel_updates_scid")), &[]).await?;
+		tx.execute(&format!("DROP INDEX IF EXISTS {}", tables.index("channel_updates_direction")), &[]).await?;
+		tx.execute(&format!("DROP INDEX IF EXISTS {}", tables.index("channel_updates_seen")), &[]).await?;
+		tx.execute(&format!("DROP INDEX IF EXISTS {}", tables.index("channel_updates_scid_seen")), &[]).await?;
+		tx.execute(&format!("DROP INDEX IF EXISTS {}", tables.index("channel_updates_scid_dir_seen")), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 8 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
 	}
 	if schema >= 1 && schema <= 8 {
-		let tx = client.transaction().await.unwrap();
-		tx.execute("DROP INDEX IF EXISTS channel_updates_seen", &[]).await.unwrap();
-		tx.execute("DROP INDEX IF EXISTS channel_updates_scid_seen", &[]).await.unwrap();
-		tx.execute("UPDATE config SET db_schema = 9 WHERE id = 1", &[]).await.unwrap();
-		tx.commit().await.unwrap();
+		let tx = client.transaction().await?;
+		tx.execute(&format!("DROP INDEX IF EXISTS {}", tables.index("channel_updates_seen")), &[]).await?;
+		tx.execute(&format!("DROP INDEX IF EXISTS {}", tables.index("channel_updates_scid_seen")), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 9 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
 	}
 	if schema >= 1 && schema <= 9 {
-		let tx = client.transaction().await.unwrap();
-		tx.execute("DROP INDEX IF EXISTS channel_updates_scid_dir_seen", &[]).await.unwrap();
-		tx.execute("UPDATE config SET db_schema = 10 WHERE id = 1", &[]).await.unwrap();
-		tx.commit().await.unwrap();
+		let tx = client.transaction().await?;
+		tx.execute(&format!("DROP INDEX IF EXISTS {}", tables.index("channel_updates_scid_dir_seen")), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 10 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
 	}
 	if schema >= 1 && schema <= 10 {
-		let tx = client.transaction().await.unwrap();
-		tx.execute("DROP INDEX IF EXISTS channel_updates_id_with_scid_dir_blob", &[]).await.unwrap();
-		tx.execute("UPDATE config SET db_schema = 11 WHERE id = 1", &[]).await.unwrap();
-		tx.commit().await.unwrap();
+		let tx = client.transaction().await?;
+		tx.execute(&format!("DROP INDEX IF EXISTS {}", tables.index("channel_updates_id_with_scid_dir_blob")), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 11 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
 	}
 	if schema >= 1 && schema <= 11 {
-		let tx = client.transaction().await.unwrap();
-		tx.execute("DROP INDEX IF EXISTS channel_updates_seen_with_id_direction_blob", &[]).await.unwrap();
-		tx.execute("UPDATE config SET db_schema = 12 WHERE id = 1", &[]).await.unwrap();
-		tx.commit().await.unwrap();
+		let tx = client.transaction().await?;
+		tx.execute(&format!("DROP INDEX IF EXISTS {}", tables.index("channel_updates_seen_with_id_direction_blob")), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 12 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
 	}
 	if schema >= 1 && schema <= 12 {
-		let tx = client.transaction().await.unwrap();
-		tx.execute("DROP INDEX IF EXISTS channel_updates_timestamp_desc", &[]).await.unwrap();
-		tx.execute("UPDATE config SET db_schema = 13 WHERE id = 1", &[]).await.unwrap();
-		tx.commit().await.unwrap();
+		let tx = client.transaction().await?;
+		tx.execute(&format!("DROP INDEX IF EXISTS {}", tables.index("channel_updates_timestamp_desc")), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 13 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
 	}
 	if schema >= 1 && schema <= 13 {
-		let tx = client.transaction().await.unwrap();
-		tx.execute("UPDATE config SET db_schema = 14 WHERE id = 1", &[]).await.unwrap();
-		tx.commit().await.unwrap();
+		let tx = client.transaction().await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 14 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
 	}
-	if schema <= 1 || schema > SCHEMA_VERSION {
-		panic!("Unknown schema in db: {}, we support up to {}", schema, SCHEMA_VERSION);
+	if schema >= 1 && schema <= 14 {
+		// the stats history table is created along with all other tables
+		let tx = client.transaction().await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 15 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
 	}
-	// PostgreSQL (at least v13, but likely later versions as well) handles insert-only tables
-	// *very* poorly. After some number of inserts, it refuses to rely on indexes, assuming them to
-	// be possibly-stale, until a VACUUM happens. Thus, we set the vacuum factor really low here,
-	// pushing PostgreSQL to vacuum often.
-	// See https://www.cybertec-postgresql.com/en/postgresql-autovacuum-insert-only-tables/
-	let _ = client.execute("ALTER TABLE channel_updates SET ( autovacuum_vacuum_insert_scale_factor = 0.005 );", &[]).await;
-	let _ = client.execute("ALTER TABLE channel_announcements SET ( autovacuum_vacuum_insert_scale_factor = 0.005 );", &[]).await;
+	if schema >= 1 && schema <= 15 {
+		let tx = client.transaction().await?;
+		tx.execute(&format!("ALTER TABLE {} ADD COLUMN IF NOT EXISTS gossip_watermark timestamp", tables.config()), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 16 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
+	}
+	if schema >= 1 && schema <= 16 {
+		// the peer state table is created along with all other tables
+		let tx = client.transaction().await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 17 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
+	}
+	if schema >= 1 && schema <= 17 {
+		// the snapshot coverage table is created along with all other tables
+		let tx = client.transaction().await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 18 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
+	}
+	if schema >= 1 && schema <= 18 {
+		// the sync totals table is created along with all other tables
+		let tx = client.transaction().await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 19 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
+	}
+	if schema >= 1 && schema <= 19 {
+		// the dead letter table is created along with all other tables
+		let tx = client.transaction().await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 20 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
+	}
+	if schema >= 1 && schema <= 20 {
+		// the stats history table only exists yet if it was created before schema 15
+		let tx = client.transaction().await?;
+		for column in ["bidirectional_channels", "direction_0_only_channels", "direction_1_only_channels", "channels_without_updates"] {
+			tx.execute(&format!("ALTER TABLE IF EXISTS {} ADD COLUMN IF NOT EXISTS {} bigint NOT NULL DEFAULT 0", tables.stats_history(), column), &[]).await?;
+		}
+		tx.execute(&format!("UPDATE {} SET db_schema = 21 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
+	}
+	if schema >= 1 && schema <= 21 {
+		// announcements persisted before stay without a funding block time
+		let tx = client.transaction().await?;
+		tx.execute(&format!("ALTER TABLE {} ADD COLUMN IF NOT EXISTS funding_block_time timestamp", tables.channel_announcements()), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 22 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
+	}
+	if schema >= 1 && schema <= 22 {
+		// snapshots fall back to the network graph's capacities for announcements persisted before
+		let tx = client.transaction().await?;
+		tx.execute(&format!("ALTER TABLE {} ADD COLUMN IF NOT EXISTS capacity_sats bigint", tables.channel_announcements()), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 23 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
+	}
+	if schema >= 1 && schema <= 23 {
+		// left empty unless bloat reporting is enabled
+		let tx = client.transaction().await?;
+		tx.execute(&format!("ALTER TABLE {} ADD COLUMN IF NOT EXISTS dead_rows bigint", tables.stats_history()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ADD COLUMN IF NOT EXISTS index_bytes bigint", tables.stats_history()), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 24 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
+	}
+	if schema >= 1 && schema <= 24 {
+		// node ids were persisted as 66 hex characters, rather than their 33 bytes
+		let tx = client.transaction().await?;
+		tx.execute(&format!("ALTER TABLE {} ALTER COLUMN public_key TYPE BYTEA USING decode(public_key, 'hex')", tables.node_announcements()), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 25 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
+	}
+	if schema >= 1 && schema <= 25 {
+		// left empty unless the HTTP front end reports the snapshot requests it serves
+		let tx = client.transaction().await?;
+		tx.execute(&format!("ALTER TABLE {} ADD COLUMN IF NOT EXISTS snapshot_downloads bigint", tables.stats_history()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ADD COLUMN IF NOT EXISTS not_modified_responses bigint", tables.stats_history()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ADD COLUMN IF NOT EXISTS bytes_served bigint", tables.stats_history()), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 26 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
+	}
+	if schema >= 1 && schema <= 26 {
+		// updates persisted before the substitution policy keep the zero their peer sent
+		let tx = client.transaction().await?;
+		tx.execute(&htlc_maximum::backfill_sql(tables), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 27 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
+	}
+	if schema >= 1 && schema <= 27 {
+		// peers only ever learned addresses before, and now also get rows for their failure histories
+		let tx = client.transaction().await?;
+		tx.execute(&format!("ALTER TABLE {} ALTER COLUMN address DROP NOT NULL, ALTER COLUMN learned_at DROP NOT NULL", tables.peer_state()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ADD COLUMN IF NOT EXISTS consecutive_failures integer NOT NULL DEFAULT 0", tables.peer_state()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ADD COLUMN IF NOT EXISTS failing_since timestamp", tables.peer_state()), &[]).await?;
+		tx.execute(&format!("ALTER TABLE {} ADD COLUMN IF NOT EXISTS last_success_at timestamp", tables.peer_state()), &[]).await?;
+		tx.execute(&format!("UPDATE {} SET db_schema = 28 WHERE id = 1", tables.config()), &[]).await?;
+		tx.commit().await?;
+	}
+	Ok(())
 }
 
-pub(crate) fn ln_peers() -> Vec<(PublicKey, SocketAddr)> {
+/// Read the peer list from the file at `LDK_RGS_PEERS_FILE` if set, or from `LN_PEERS` otherwise.
+/// Peers configured by address only lack their public key, which is looked up through the DNS seed.
+///
+/// Invalid peer lists are reported rather than panicking, as the list is reloaded while running.
+pub(crate) fn load_ln_peers() -> Result<Vec<(Option<PublicKey>, SocketAddr)>, String> {
+	if let Ok(path) = env::var("LDK_RGS_PEERS_FILE") {
+		let list = fs::read_to_string(&path).map_err(|error| format!("Failed to read peers file {}: {}", path, error))?;
+		return parse_peer_list(&list, &path);
+	}
 	const WALLET_OF_SATOSHI: &str = "035e4ff418fc8b5554c5d9eea66396c227bd429a3251c8cbc711002ba215bfc226@170.75.163.209:9735";
 	let list = env::var("LN_PEERS").unwrap_or(WALLET_OF_SATOSHI.to_string());
+	parse_peer_list(&list, "LN_PEERS")
+}
+
+/// Parse a comma or newline separated list of peers
+fn parse_peer_list(list: &str, source: &str) -> Result<Vec<(Option<PublicKey>, SocketAddr)>, String> {
 	let mut peers = Vec::new();
-	for (item, peer_info) in list.split(',').enumerate() {
+	for (item, peer_info) in list.split(|separator: char| separator == ',' || separator == '\n').enumerate() {
 		// Ignore leading or trailing whitespace
 		let trimmed_peer_info = peer_info.trim();
 		// Ignore trailing or repeated commas
 		if !trimmed_peer_info.is_empty() {
-			peers.push(resolve_peer_info(trimmed_peer_info).unwrap_or_else(|_| {
-				panic!("Invalid peer info in LN_PEERS at item {}: {}", item, peer_info)
-			}));
+			peers.push(resolve_peer_info(trimmed_peer_info).map_err(|_| {
+				format!("Invalid peer info in {} at item {}: {}", source, item, peer_info)
+			})?);
+		}
+	}
+	Ok(peers)
+}
+
+/// The BOLT 10 DNS seed the public keys of peers configured by address only are looked up through
+pub(crate) fn dns_seed() -> String {
+	env::var("LDK_RGS_DNS_SEED").unwrap_or("nodes.lightning.directory".to_string())
+}
+
+pub(crate) fn canary_scids() -> Vec<u64> {
+	let list = env::var("LDK_RGS_CANARY_SCIDS").unwrap_or_default();
+	let mut scids = Vec::new();
+	for (item, scid) in list.split(',').enumerate() {
+		let trimmed_scid = scid.trim();
+		if !trimmed_scid.is_empty() {
+			let short_channel_id = trimmed_scid.parse::<ShortChannelId>().unwrap_or_else(|error| {
+				panic!("Invalid short channel id in LDK_RGS_CANARY_SCIDS at item {}: {}", item, error)
+			});
+			scids.push(u64::from(short_channel_id));
 		}
 	}
-	peers
+	scids
+}
+
+/// The endpoint notified whenever gossip processing appears to have stalled
+pub(crate) fn stall_webhook_endpoint() -> Option<HttpEndpoint> {
+	let url = env::var("LDK_RGS_STALL_WEBHOOK_URL").ok()?;
+	let url = url.trim();
+	if url.is_empty() {
+		return None;
+	}
+	let address = url.strip_prefix("http://").expect("LDK_RGS_STALL_WEBHOOK_URL must be an http:// URL");
+	let (authority, path) = match address.find('/') {
+		Some(index) => (&address[..index], &address[index..]),
+		None => (address, "/"),
+	};
+	let (host, port) = match authority.rsplit_once(':') {
+		Some((host, port)) => (host, port.parse::<u16>().expect("LDK_RGS_STALL_WEBHOOK_URL port must be a u16.")),
+		None => (authority, 80),
+	};
+	Some(HttpEndpoint::for_host(host.to_string()).with_port(port).with_path(path.to_string()))
+}
+
+/// The Redis instance shared with other servers to deduplicate gossip, if any
+pub(crate) fn redis_client() -> Option<redis::Client> {
+	let url = env::var("LDK_RGS_REDIS_URL").ok()?;
+	let url = url.trim();
+	if url.is_empty() {
+		return None;
+	}
+	Some(redis::Client::open(url).expect("LDK_RGS_REDIS_URL must be a valid redis:// URL"))
+}
+
+/// A problem with the configuration, found before connecting to any peers
+#[derive(Debug, PartialEq)]
+pub(crate) enum ConfigError {
+	/// The server can't start
+	Fatal(String),
+	/// The server can't start, as the database can't be connected to
+	DatabaseUnreachable(String),
+	/// The server can't start, as the chain backend follows a different chain than the network's
+	WrongChain(String),
+	/// The server can start, but with degraded functionality
+	Warning(String),
+}
+
+/// Check the configured peers, the cache directory, and the connections to the database and the
+/// chain backend, so that misconfiguration surfaces at startup rather than minutes later.
+/// `peers_override` replaces the configured peers if set.
+pub(crate) async fn validate(peers_override: Option<&[(PublicKey, SocketAddr)]>, chain_backend: HttpEndpoint) -> Vec<ConfigError> {
+	let mut errors = Vec::new();
+	errors.extend(validate_peers(peers_override));
+	errors.extend(validate_cache_directory(&cache_path()));
+
+	match tokio::time::timeout(VALIDATION_TIMEOUT, crate::connect_to_db()).await {
+		Ok(Ok(_)) => {},
+		Ok(Err(ProcessorError::DatabaseUnreachable(message))) => errors.push(ConfigError::DatabaseUnreachable(message)),
+		Ok(Err(error)) => errors.push(ConfigError::Fatal(error.to_string())),
+		Err(_) => errors.push(ConfigError::DatabaseUnreachable(format!("Connecting to the database timed out after {:?}", VALIDATION_TIMEOUT))),
+	}
+
+	// without a chain backend, gossip is still collected, but channel announcements can't be verified
+	match RestClient::new(chain_backend) {
+		Ok(client) => match tokio::time::timeout(VALIDATION_TIMEOUT, client.get_best_block()).await {
+			Ok(Ok(_)) => errors.extend(validate_chain(&client).await),
+			Ok(Err(error)) => errors.push(ConfigError::Warning(format!("Failed to reach the chain backend, so channel announcements can't be verified: {}", error.into_inner()))),
+			Err(_) => errors.push(ConfigError::Warning(format!("Reaching the chain backend timed out after {:?}, so channel announcements can't be verified", VALIDATION_TIMEOUT))),
+		},
+		Err(error) => errors.push(ConfigError::Warning(format!("BITCOIN_REST_DOMAIN, BITCOIN_REST_PORT, and BITCOIN_REST_PATH must form a valid REST endpoint: {}", error))),
+	}
+	errors
+}
+
+/// Make sure the chain backend's genesis block is the configured network's, as otherwise every
+/// channel announcement would fail verification
+async fn validate_chain(client: &RestClient) -> Option<ConfigError> {
+	let expected_genesis_hash = genesis_block(network()).block_hash();
+	let genesis_hash = match tokio::time::timeout(VALIDATION_TIMEOUT, client.request_resource::<BinaryResponse, RestBinaryResponse>("blockhashbyheight/0.bin")).await {
+		Ok(Ok(RestBinaryResponse(genesis_hash))) => genesis_hash,
+		// the best block was just retrieved, so this is most likely a missing `-rest` flag
+		Ok(Err(error)) => return Some(ConfigError::Warning(format!("Failed to look up the chain backend's genesis block: {}", error))),
+		Err(_) => return Some(ConfigError::Warning(format!("Looking up the chain backend's genesis block timed out after {:?}", VALIDATION_TIMEOUT))),
+	};
+	if genesis_hash != expected_genesis_hash.to_byte_array() {
+		return Some(ConfigError::WrongChain(format!("The chain backend's genesis block is {}, but {:?}'s is {}", genesis_hash.as_hex(), network(), expected_genesis_hash)));
+	}
+	None
+}
+
+fn validate_peers(peers_override: Option<&[(PublicKey, SocketAddr)]>) -> Option<ConfigError> {
+	let peer_count = match peers_override {
+		Some(peers) => peers.len(),
+		None => match load_ln_peers() {
+			Ok(peers) => peers.len(),
+			Err(error) => return Some(ConfigError::Fatal(error)),
+		},
+	};
+	if peer_count == 0 {
+		return Some(ConfigError::Fatal("No Lightning peers are configured".to_string()));
+	}
+	None
 }
 
-fn resolve_peer_info(peer_info: &str) -> Result<(PublicKey, SocketAddr), &str> {
-	let mut peer_info = peer_info.splitn(2, '@');
+fn validate_cache_directory(path: &str) -> Option<ConfigError> {
+	let probe_path = format!("{}/.write_probe", path);
+	let result = fs::create_dir_all(path)
+		.and_then(|_| fs::write(&probe_path, b""))
+		.and_then(|_| fs::remove_file(&probe_path));
+	result.err().map(|error| ConfigError::Fatal(format!("Cache directory {} is not writable: {}", path, error)))
+}
 
-	let pubkey = peer_info.next().ok_or("Invalid peer info. Should be formatted as: `pubkey@host:port`")?;
-	let pubkey = Vec::from_hex(pubkey).map_err(|_| "Invalid node pubkey")?;
-	let pubkey = PublicKey::from_slice(&pubkey).map_err(|_| "Invalid node pubkey")?;
+fn resolve_peer_info(peer_info: &str) -> Result<(Option<PublicKey>, SocketAddr), &str> {
+	let (pubkey, socket_address) = match peer_info.split_once('@') {
+		Some((pubkey, socket_address)) => {
+			let pubkey = Vec::from_hex(pubkey).map_err(|_| "Invalid node pubkey")?;
+			(Some(PublicKey::from_slice(&pubkey).map_err(|_| "Invalid node pubkey")?), socket_address)
+		},
+		// the public key is looked up later, as that requires network access
+		None => (None, peer_info),
+	};
 
-	let socket_address = peer_info.next().ok_or("Invalid peer info. Should be formatted as: `pubkey@host:port`")?;
 	let socket_address = socket_address
 		.to_socket_addrs()
 		.map_err(|_| "Cannot resolve node address")?
@@ -360,12 +1190,32 @@ mod tests {
 	use hex_conservative::DisplayHex;
 	use std::str::FromStr;
 
+	#[test]
+	fn test_validate_peers() {
+		let (pubkey, socket_address) = resolve_peer_info("035e4ff418fc8b5554c5d9eea66396c227bd429a3251c8cbc711002ba215bfc226@170.75.163.209:9735").unwrap();
+		assert_eq!(validate_peers(Some(&[(pubkey.unwrap(), socket_address)])), None);
+		assert_eq!(validate_peers(Some(&[])), Some(ConfigError::Fatal("No Lightning peers are configured".to_string())));
+	}
+
+	#[test]
+	fn test_validate_cache_directory() {
+		let directory = format!("{}/rgs_config_validation_{}", env::temp_dir().display(), std::process::id());
+		assert_eq!(validate_cache_directory(&directory), None);
+		assert!(fs::read_dir(&directory).unwrap().next().is_none());
+
+		// a file can't be used as a directory
+		let file_path = format!("{}/file", directory);
+		fs::write(&file_path, b"").unwrap();
+		assert!(matches!(validate_cache_directory(&file_path), Some(ConfigError::Fatal(_))));
+		fs::remove_dir_all(&directory).unwrap();
+	}
+
 	#[test]
 	fn test_resolve_peer_info() {
 		let wallet_of_satoshi = "035e4ff418fc8b5554c5d9eea66396c227bd429a3251c8cbc711002ba215bfc226@170.75.163.209:9735";
 		let (pubkey, socket_address) = resolve_peer_info(wallet_of_satoshi).unwrap();
 		assert_eq!(
-			pubkey.serialize().to_lower_hex_string(),
+			pubkey.unwrap().serialize().to_lower_hex_string(),
 			"035e4ff418fc8b5554c5d9eea66396c227bd429a3251c8cbc711002ba215bfc226"
 		);
 		assert_eq!(socket_address.to_string(), "170.75.163.209:9735");
@@ -373,7 +1223,7 @@ mod tests {
 		let ipv6 = "033d8656219478701227199cbd6f670335c8d408a92ae88b962c49d4dc0e83e025@[2001:db8::1]:80";
 		let (pubkey, socket_address) = resolve_peer_info(ipv6).unwrap();
 		assert_eq!(
-			pubkey.serialize().to_lower_hex_string(),
+			pubkey.unwrap().serialize().to_lower_hex_string(),
 			"033d8656219478701227199cbd6f670335c8d408a92ae88b962c49d4dc0e83e025"
 		);
 		assert_eq!(socket_address.to_string(), "[2001:db8::1]:80");
@@ -381,32 +1231,71 @@ mod tests {
 		let localhost = "033d8656219478701227199cbd6f670335c8d408a92ae88b962c49d4dc0e83e025@localhost:9735";
 		let (pubkey, socket_address) = resolve_peer_info(localhost).unwrap();
 		assert_eq!(
-			pubkey.serialize().to_lower_hex_string(),
+			pubkey.unwrap().serialize().to_lower_hex_string(),
 			"033d8656219478701227199cbd6f670335c8d408a92ae88b962c49d4dc0e83e025"
 		);
 		let socket_address = socket_address.to_string();
 		assert!(socket_address == "127.0.0.1:9735" || socket_address == "[::1]:9735");
+
+		// the public key may be left to be looked up
+		let (pubkey, socket_address) = resolve_peer_info("170.75.163.209:9735").unwrap();
+		assert_eq!(pubkey, None);
+		assert_eq!(socket_address.to_string(), "170.75.163.209:9735");
 	}
 
 	#[test]
 	fn test_ln_peers() {
 		// Set the environment variable, including a repeated comma, leading space, and trailing comma.
 		std::env::set_var("LN_PEERS", "035e4ff418fc8b5554c5d9eea66396c227bd429a3251c8cbc711002ba215bfc226@170.75.163.209:9735,, 035e4ff418fc8b5554c5d9eea66396c227bd429a3251c8cbc711002ba215bfc227@170.75.163.210:9735,");
-		let peers = ln_peers();
+		let peers = load_ln_peers().unwrap();
 
 		// Assert output is as expected
 		assert_eq!(
 			peers,
 			vec![
 				(
-					PublicKey::from_str("035e4ff418fc8b5554c5d9eea66396c227bd429a3251c8cbc711002ba215bfc226").unwrap(),
+					Some(PublicKey::from_str("035e4ff418fc8b5554c5d9eea66396c227bd429a3251c8cbc711002ba215bfc226").unwrap()),
 					SocketAddr::from_str("170.75.163.209:9735").unwrap()
 				),
 				(
-					PublicKey::from_str("035e4ff418fc8b5554c5d9eea66396c227bd429a3251c8cbc711002ba215bfc227").unwrap(),
+					Some(PublicKey::from_str("035e4ff418fc8b5554c5d9eea66396c227bd429a3251c8cbc711002ba215bfc227").unwrap()),
 					SocketAddr::from_str("170.75.163.210:9735").unwrap()
 				)
 			]
 		);
 	}
+
+	#[test]
+	fn test_parse_peer_list() {
+		let list = "035e4ff418fc8b5554c5d9eea66396c227bd429a3251c8cbc711002ba215bfc226@170.75.163.209:9735\n\n035e4ff418fc8b5554c5d9eea66396c227bd429a3251c8cbc711002ba215bfc227@170.75.163.210:9735\n";
+		let peers = parse_peer_list(list, "peers.txt").unwrap();
+		assert_eq!(peers.len(), 2);
+		assert_eq!(peers[1].1, SocketAddr::from_str("170.75.163.210:9735").unwrap());
+
+		let error = parse_peer_list("035e4ff418fc8b5554c5d9eea66396c227bd429a3251c8cbc711002ba215bfc226", "peers.txt").unwrap_err();
+		assert_eq!(error, "Invalid peer info in peers.txt at item 0: 035e4ff418fc8b5554c5d9eea66396c227bd429a3251c8cbc711002ba215bfc226");
+	}
+
+	#[test]
+	fn test_canary_scids() {
+		std::env::set_var("LDK_RGS_CANARY_SCIDS", "1, 700000x1234x1,");
+		assert_eq!(canary_scids(), vec![1, 700000 << 40 | 1234 << 16 | 1]);
+		std::env::set_var("LDK_RGS_CANARY_SCIDS", "700000x1234");
+		assert!(std::panic::catch_unwind(canary_scids).is_err());
+		std::env::set_var("LDK_RGS_CANARY_SCIDS", "700000x1234x70000");
+		assert!(std::panic::catch_unwind(canary_scids).is_err());
+		std::env::remove_var("LDK_RGS_CANARY_SCIDS");
+		assert!(canary_scids().is_empty());
+	}
+
+	#[test]
+	fn test_db_schema() {
+		std::env::set_var("LDK_RGS_DB_SCHEMA", " Gossip_Sync ");
+		assert_eq!(db_schema(), Some("gossip_sync".to_string()));
+		std::env::set_var("LDK_RGS_DB_SCHEMA", "");
+		assert_eq!(db_schema(), None);
+		std::env::remove_var("LDK_RGS_DB_SCHEMA");
+		assert_eq!(db_schema(), None);
+		assert!(!is_valid_identifier("gossip; DROP TABLE config"));
+	}
 }
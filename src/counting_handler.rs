@@ -0,0 +1,130 @@
+use std::collections::HashSet;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, RwLock};
+
+use bitcoin::secp256k1::PublicKey;
+use lightning::{io, log_info, log_trace};
+use lightning::ln::features::{InitFeatures, NodeFeatures};
+use lightning::events::{EventHandler, EventsProvider};
+use lightning::ln::msgs::{DecodeError, Init, LightningError, OnionMessage, OnionMessageHandler};
+use lightning::ln::peer_handler::CustomMessageHandler;
+use lightning::ln::wire::CustomMessageReader;
+use lightning::util::logger::Logger;
+
+use crate::archive::{QueryReply, QueryReplyQueue};
+use crate::downloader::GossipCounter;
+
+#[derive(Clone, Copy, Debug, Hash, PartialEq, Eq)]
+enum IgnoredMessageKind {
+	Onion,
+	Custom(u16),
+}
+
+/// A stand-in for [`lightning::ln::peer_handler::IgnoringMessageHandler`] for onion and custom
+/// messages, which still ignores them, but keeps count.
+///
+/// Peers sending message types we don't expect may indicate a version mismatch or
+/// misconfiguration, so the first occurrence of each type is logged at info level.
+///
+/// As the custom message handler, it's also what sends the gossip query replies LDK has no message
+/// send events for.
+pub(crate) struct CountingMessageHandler<L: Deref> where L::Target: Logger {
+	counter: Arc<RwLock<GossipCounter>>,
+	seen_message_kinds: Mutex<HashSet<IgnoredMessageKind>>,
+	query_replies: Option<QueryReplyQueue>,
+	logger: L,
+}
+
+impl<L: Deref> CountingMessageHandler<L> where L::Target: Logger {
+	pub(crate) fn new(counter: Arc<RwLock<GossipCounter>>, logger: L) -> Self {
+		Self {
+			counter,
+			seen_message_kinds: Mutex::new(HashSet::new()),
+			query_replies: None,
+			logger,
+		}
+	}
+
+	pub(crate) fn set_query_replies(&mut self, query_replies: QueryReplyQueue) {
+		self.query_replies = Some(query_replies);
+	}
+
+	fn record(&self, kind: IgnoredMessageKind) {
+		{
+			let mut counter = self.counter.write().expect("gossip counter lock poisoned");
+			match kind {
+				IgnoredMessageKind::Onion => counter.ignored_onion_messages += 1,
+				IgnoredMessageKind::Custom(_) => counter.ignored_custom_messages += 1,
+			}
+		}
+		let is_novel = self.seen_message_kinds.lock().expect("seen message kinds lock poisoned").insert(kind);
+		if is_novel {
+			log_info!(self.logger, "Ignoring first {:?} message", kind);
+		} else {
+			log_trace!(self.logger, "Ignoring {:?} message", kind);
+		}
+	}
+}
+
+impl<L: Deref> EventsProvider for CountingMessageHandler<L> where L::Target: Logger {
+	fn process_pending_events<H: Deref>(&self, _handler: H) where H::Target: EventHandler {}
+}
+
+impl<L: Deref> OnionMessageHandler for CountingMessageHandler<L> where L::Target: Logger {
+	fn handle_onion_message(&self, peer_node_id: &PublicKey, _msg: &OnionMessage) {
+		log_trace!(self.logger, "Received onion message from {}", peer_node_id);
+		self.record(IgnoredMessageKind::Onion);
+	}
+	fn next_onion_message_for_peer(&self, _peer_node_id: PublicKey) -> Option<OnionMessage> { None }
+	fn peer_connected(&self, _their_node_id: &PublicKey, _init: &Init, _inbound: bool) -> Result<(), ()> { Ok(()) }
+	fn peer_disconnected(&self, _their_node_id: &PublicKey) {}
+	fn timer_tick_occurred(&self) {}
+	fn provided_node_features(&self) -> NodeFeatures { NodeFeatures::empty() }
+	fn provided_init_features(&self, _their_node_id: &PublicKey) -> InitFeatures { InitFeatures::empty() }
+}
+
+impl<L: Deref> CustomMessageReader for CountingMessageHandler<L> where L::Target: Logger {
+	type CustomMessage = QueryReply;
+
+	fn read<R: io::Read>(&self, message_type: u16, _buffer: &mut R) -> Result<Option<Self::CustomMessage>, DecodeError> {
+		// we never decode custom messages, so this is the only place to observe them
+		self.record(IgnoredMessageKind::Custom(message_type));
+		Ok(None)
+	}
+}
+
+impl<L: Deref> CustomMessageHandler for CountingMessageHandler<L> where L::Target: Logger {
+	fn handle_custom_message(&self, _msg: QueryReply, _sender_node_id: &PublicKey) -> Result<(), LightningError> {
+		// custom messages are never decoded, so there's nothing to handle
+		Ok(())
+	}
+	fn get_and_clear_pending_msg(&self) -> Vec<(PublicKey, QueryReply)> {
+		self.query_replies.as_ref().map_or(Vec::new(), |query_replies| std::mem::take(&mut *query_replies.lock().expect("query reply lock poisoned")))
+	}
+	fn provided_node_features(&self) -> NodeFeatures { NodeFeatures::empty() }
+	fn provided_init_features(&self, _their_node_id: &PublicKey) -> InitFeatures { InitFeatures::empty() }
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::types::tests::TestLogger;
+
+	#[test]
+	fn test_custom_message_counting() {
+		let logger = Arc::new(TestLogger::with_id("test_custom_message_counting".to_string()));
+		let counter = Arc::new(RwLock::new(GossipCounter::new()));
+		let handler = CountingMessageHandler::new(Arc::clone(&counter), Arc::clone(&logger));
+
+		for message_type in [32769, 32769, 32771] {
+			let read_result = handler.read(message_type, &mut io::Cursor::new(Vec::new()));
+			assert!(matches!(read_result, Ok(None)));
+		}
+
+		assert_eq!(counter.read().unwrap().ignored_custom_messages, 3);
+		assert_eq!(counter.read().unwrap().ignored_onion_messages, 0);
+		logger.assert_log_contains("rapid_gossip_sync_server::counting_handler", "Ignoring first Custom(32769) message", 1);
+		logger.assert_log_contains("rapid_gossip_sync_server::counting_handler", "Ignoring first Custom(32771) message", 1);
+		logger.assert_log_contains("rapid_gossip_sync_server::counting_handler", "Ignoring Custom(32769) message", 1);
+	}
+}
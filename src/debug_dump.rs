@@ -0,0 +1,341 @@
+use std::fs;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use hex_conservative::display::DisplayHex;
+use lightning::{log_info, log_warn};
+use lightning::util::logger::Logger;
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::chain_stats::ChainBackendStats;
+use crate::compact_graph::DirectionalCoverage;
+use crate::config;
+use crate::downloader::GossipCounter;
+use crate::error::ProcessorError;
+use crate::latency::LatencyHistogram;
+use crate::peer_registry::PeerConnectionStats;
+use crate::snapshot::GenerationReport;
+use crate::types::GossipMessage;
+
+/// Handles on the state of all components, which the debug dump is assembled from. Components
+/// only ever hold its locks for as long as it takes to copy a few fields in or out, so dumping
+/// never holds up gossip processing.
+pub(crate) struct DebugState {
+	gossip_counter: Arc<RwLock<GossipCounter>>,
+	latency_histogram: Arc<LatencyHistogram>,
+	chain_backend_stats: Arc<ChainBackendStats>,
+	peer_stats: Mutex<Option<Arc<PeerConnectionStats>>>,
+	/// Weak, so that the dump doesn't keep the persister's channel open
+	persistence_queue: Mutex<Option<mpsc::WeakSender<GossipMessage>>>,
+	persisted_message_count: AtomicU64,
+	in_flight_insert_count: AtomicUsize,
+	catch_up: Mutex<Option<CatchUpDebugInfo>>,
+	verifier: Mutex<Option<VerifierDebugInfo>>,
+	last_snapshot_round: Mutex<Option<SnapshotRoundSummary>>,
+}
+
+impl DebugState {
+	pub(crate) fn new(gossip_counter: Arc<RwLock<GossipCounter>>, latency_histogram: Arc<LatencyHistogram>, chain_backend_stats: Arc<ChainBackendStats>) -> Self {
+		Self {
+			gossip_counter,
+			latency_histogram,
+			chain_backend_stats,
+			peer_stats: Mutex::new(None),
+			persistence_queue: Mutex::new(None),
+			persisted_message_count: AtomicU64::new(0),
+			in_flight_insert_count: AtomicUsize::new(0),
+			catch_up: Mutex::new(None),
+			verifier: Mutex::new(None),
+			last_snapshot_round: Mutex::new(None),
+		}
+	}
+
+	pub(crate) fn set_peer_stats(&self, peer_stats: Arc<PeerConnectionStats>) {
+		*self.peer_stats.lock().expect("debug state lock poisoned") = Some(peer_stats);
+	}
+
+	/// The peers' connection records, once the gossip download started
+	pub(crate) fn peer_stats(&self) -> Option<Arc<PeerConnectionStats>> {
+		self.peer_stats.lock().expect("debug state lock poisoned").clone()
+	}
+
+	pub(crate) fn set_persistence_queue(&self, persistence_queue: mpsc::WeakSender<GossipMessage>) {
+		*self.persistence_queue.lock().expect("debug state lock poisoned") = Some(persistence_queue);
+	}
+
+	pub(crate) fn insert_started(&self) {
+		self.in_flight_insert_count.fetch_add(1, Ordering::Relaxed);
+	}
+
+	pub(crate) fn insert_finished(&self, persisted_message_count: u64) {
+		self.in_flight_insert_count.fetch_sub(1, Ordering::Relaxed);
+		self.persisted_message_count.fetch_add(persisted_message_count, Ordering::Relaxed);
+	}
+
+	/// Record the state the tracking loop determined during its latest iteration
+	pub(crate) fn record_tracking_iteration(&self, catch_up: CatchUpDebugInfo, verifier: VerifierDebugInfo) {
+		*self.catch_up.lock().expect("debug state lock poisoned") = Some(catch_up);
+		*self.verifier.lock().expect("debug state lock poisoned") = Some(verifier);
+	}
+
+	pub(crate) fn record_snapshot_round(&self, summary: SnapshotRoundSummary) {
+		*self.last_snapshot_round.lock().expect("debug state lock poisoned") = Some(summary);
+	}
+
+	pub(crate) fn collect(&self) -> DebugDump {
+		let gossip_counter = self.gossip_counter.read().expect("gossip counter lock poisoned").clone();
+		let peer_stats = self.peer_stats.lock().expect("debug state lock poisoned").clone();
+		let peers = peer_stats.map_or(Vec::new(), |peer_stats| peer_stats.snapshot().into_iter()
+			.map(|(pubkey, record)| PeerDebugInfo {
+				pubkey: pubkey.serialize().to_lower_hex_string(),
+				address: record.address.map(|address| address.to_string()),
+				connected: record.connected_since.is_some(),
+				connected_since: record.connected_since,
+				last_disconnected_at: record.last_disconnected_at,
+				reconnect_count: record.reconnect_count,
+				last_connection_error: record.last_connection_error,
+				last_message_at: record.last_message_at,
+			})
+			.collect());
+
+		let persistence_queue = self.persistence_queue.lock().expect("debug state lock poisoned").as_ref().and_then(|queue| queue.upgrade());
+		let latency_snapshot = self.latency_histogram.snapshot();
+		let persistence = PersistenceDebugInfo {
+			queue_depth: persistence_queue.as_ref().map(|queue| queue.max_capacity() - queue.capacity()),
+			queue_capacity: persistence_queue.as_ref().map(|queue| queue.max_capacity()),
+			in_flight_insert_count: self.in_flight_insert_count.load(Ordering::Relaxed),
+			persisted_message_count: self.persisted_message_count.load(Ordering::Relaxed),
+			failed_message_count: gossip_counter.persistence_failures,
+			p50_latency_ms: latency_snapshot.latency_percentile(50).map(duration_millis),
+			p99_latency_ms: latency_snapshot.latency_percentile(99).map(duration_millis),
+		};
+
+		let backend_summary = self.chain_backend_stats.summary();
+		let chain_backend = ChainBackendDebugInfo {
+			window_size: backend_summary.window_size,
+			p50_latency_ms: backend_summary.p50_latency.map(duration_millis),
+			p99_latency_ms: backend_summary.p99_latency.map(duration_millis),
+			error_rate: backend_summary.error_rate,
+			total_requests: backend_summary.total_requests,
+			total_errors: backend_summary.total_errors,
+		};
+
+		let tokio = match tokio::runtime::Handle::try_current() {
+			Ok(handle) => {
+				let metrics = handle.metrics();
+				TokioDebugInfo { worker_count: Some(metrics.num_workers()), alive_task_count: Some(metrics.num_alive_tasks()) }
+			},
+			Err(_) => TokioDebugInfo { worker_count: None, alive_task_count: None },
+		};
+
+		DebugDump {
+			generated_at: unix_timestamp(SystemTime::now()),
+			gossip_counter,
+			peers,
+			persistence,
+			verifier: self.verifier.lock().expect("debug state lock poisoned").clone(),
+			chain_backend,
+			catch_up: self.catch_up.lock().expect("debug state lock poisoned").clone(),
+			last_snapshot_round: self.last_snapshot_round.lock().expect("debug state lock poisoned").clone(),
+			tokio,
+		}
+	}
+
+	/// Write a dump to the debug directory within the cache directory, returning its path
+	pub(crate) fn write_dump(&self) -> Result<String, ProcessorError> {
+		let dump = self.collect();
+		let debug_directory = format!("{}/debug", config::cache_path());
+		let dump_path = format!("{}/dump_{}.json", debug_directory, dump.generated_at);
+		let json = serde_json::to_vec_pretty(&dump).map_err(|error| ProcessorError::Io { context: "Failed to serialize debug dump".to_string(), source: error.into() })?;
+		fs::create_dir_all(&debug_directory)
+			.and_then(|_| fs::write(&dump_path, json))
+			.map_err(|source| ProcessorError::Io { context: format!("Failed to write debug dump {}", dump_path), source })?;
+		Ok(dump_path)
+	}
+
+	/// Write a dump whenever the process receives SIGUSR2
+	pub(crate) async fn dump_on_user_signal<L: Deref>(state: Arc<Self>, logger: L) where L::Target: Logger {
+		let mut user_signal = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined2()) {
+			Ok(signal) => signal,
+			Err(error) => {
+				log_warn!(logger, "Failed to listen for SIGUSR2, debug dumps cannot be requested: {}", error);
+				return;
+			}
+		};
+		while user_signal.recv().await.is_some() {
+			match state.write_dump() {
+				Ok(dump_path) => log_info!(logger, "Received SIGUSR2, wrote debug dump to {}", dump_path),
+				Err(error) => log_warn!(logger, "{}", error),
+			}
+		}
+	}
+}
+
+/// A point-in-time view of the server's internals for diagnosing problems in production
+#[derive(Serialize)]
+pub(crate) struct DebugDump {
+	pub(crate) generated_at: u64,
+	pub(crate) gossip_counter: GossipCounter,
+	pub(crate) peers: Vec<PeerDebugInfo>,
+	pub(crate) persistence: PersistenceDebugInfo,
+	/// As of the latest tracking iteration, if there was one yet
+	pub(crate) verifier: Option<VerifierDebugInfo>,
+	pub(crate) chain_backend: ChainBackendDebugInfo,
+	/// As of the latest tracking iteration, if there was one yet
+	pub(crate) catch_up: Option<CatchUpDebugInfo>,
+	pub(crate) last_snapshot_round: Option<SnapshotRoundSummary>,
+	pub(crate) tokio: TokioDebugInfo,
+}
+
+#[derive(Serialize)]
+pub(crate) struct PeerDebugInfo {
+	pub(crate) pubkey: String,
+	pub(crate) address: Option<String>,
+	pub(crate) connected: bool,
+	pub(crate) connected_since: Option<u64>,
+	pub(crate) last_disconnected_at: Option<u64>,
+	pub(crate) reconnect_count: u32,
+	pub(crate) last_connection_error: Option<String>,
+	/// Only handshakes and gossip queries and their replies count, as LDK doesn't tell which peer
+	/// other gossip came from
+	pub(crate) last_message_at: Option<u64>,
+}
+
+#[derive(Serialize)]
+pub(crate) struct PersistenceDebugInfo {
+	/// The number of messages waiting for the persister, unless it isn't running
+	pub(crate) queue_depth: Option<usize>,
+	pub(crate) queue_capacity: Option<usize>,
+	pub(crate) in_flight_insert_count: usize,
+	pub(crate) persisted_message_count: u64,
+	pub(crate) failed_message_count: u64,
+	pub(crate) p50_latency_ms: Option<u64>,
+	pub(crate) p99_latency_ms: Option<u64>,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct VerifierDebugInfo {
+	/// Announcements waiting for their funding transactions to be confirmed deeply enough
+	pub(crate) parked_announcement_count: usize,
+	/// Messages submitted for signature verification, but not yet verified
+	pub(crate) pending_validation_count: usize,
+	pub(crate) validation_thread_count: usize,
+	pub(crate) reorgs_detected: u64,
+	pub(crate) channels_invalidated: u64,
+}
+
+#[derive(Serialize)]
+pub(crate) struct ChainBackendDebugInfo {
+	pub(crate) window_size: usize,
+	pub(crate) p50_latency_ms: Option<u64>,
+	pub(crate) p99_latency_ms: Option<u64>,
+	pub(crate) error_rate: f64,
+	pub(crate) total_requests: u64,
+	pub(crate) total_errors: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct CatchUpDebugInfo {
+	pub(crate) iteration: u32,
+	pub(crate) is_caught_up: bool,
+	pub(crate) is_initial_sync: bool,
+	/// The number of messages received during the latest tracking iteration
+	pub(crate) new_message_count: u64,
+	pub(crate) seconds_since_new_gossip: u64,
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct SnapshotRoundSummary {
+	pub(crate) started_at: u64,
+	pub(crate) duration_ms: u64,
+	pub(crate) error: Option<String>,
+	pub(crate) failed_rounds_since_startup: u64,
+	/// Empty if the round failed
+	pub(crate) scopes: Vec<SnapshotScopeSummary>,
+	/// `None` if the round failed
+	pub(crate) directional_coverage: Option<DirectionalCoverage>,
+}
+
+impl SnapshotRoundSummary {
+	pub(crate) fn new(started_at: SystemTime, result: &Result<GenerationReport, ProcessorError>, failed_rounds_since_startup: u64) -> Self {
+		let (scopes, error) = match result {
+			Ok(generation_report) => (generation_report.size_reports.iter().map(|report| SnapshotScopeSummary {
+				scope: report.scope,
+				size_bytes: report.size_bytes,
+				oversized: report.oversized,
+				rows_read: report.lookup_load.rows_read,
+				lookup_ms: duration_millis(report.lookup_load.wall_time),
+				htlc_maximum_substitutions: report.htlc_maximum_substitution_count,
+			}).collect(), None),
+			Err(error) => (Vec::new(), Some(error.to_string())),
+		};
+		Self {
+			started_at: unix_timestamp(started_at),
+			duration_ms: started_at.elapsed().map_or(0, duration_millis),
+			error,
+			failed_rounds_since_startup,
+			scopes,
+			directional_coverage: result.as_ref().ok().map(|generation_report| generation_report.directional_coverage),
+		}
+	}
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct SnapshotScopeSummary {
+	pub(crate) scope: u64,
+	pub(crate) size_bytes: u64,
+	pub(crate) oversized: bool,
+	pub(crate) rows_read: u64,
+	pub(crate) lookup_ms: u64,
+	/// The number of updates whose absent HTLC maximum was substituted with the channel capacity
+	pub(crate) htlc_maximum_substitutions: usize,
+}
+
+#[derive(Serialize)]
+pub(crate) struct TokioDebugInfo {
+	pub(crate) worker_count: Option<usize>,
+	pub(crate) alive_task_count: Option<usize>,
+}
+
+fn duration_millis(duration: Duration) -> u64 {
+	duration.as_millis() as u64
+}
+
+fn unix_timestamp(time: SystemTime) -> u64 {
+	time.duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch").as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+	use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+
+	use super::*;
+
+	#[tokio::test]
+	async fn test_dump_top_level_keys() {
+		let gossip_counter = Arc::new(RwLock::new(GossipCounter::new()));
+		let state = DebugState::new(Arc::clone(&gossip_counter), Arc::new(LatencyHistogram::new()), Arc::new(ChainBackendStats::new()));
+		let peer_stats = Arc::new(PeerConnectionStats::new());
+		let pubkey = PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[42; 32]).unwrap());
+		peer_stats.record_message(pubkey);
+		state.set_peer_stats(peer_stats);
+		let (sender, _receiver) = mpsc::channel(100);
+		sender.send(GossipMessage::Batch(Vec::new(), Vec::new())).await.unwrap();
+		state.set_persistence_queue(sender.downgrade());
+		gossip_counter.write().unwrap().channel_updates = 7;
+
+		let dump = serde_json::to_value(state.collect()).unwrap();
+		let mut keys: Vec<&str> = dump.as_object().unwrap().keys().map(|key| key.as_str()).collect();
+		keys.sort_unstable();
+		assert_eq!(keys, vec!["catch_up", "chain_backend", "generated_at", "gossip_counter", "last_snapshot_round", "peers", "persistence", "tokio", "verifier"]);
+		assert_eq!(dump["gossip_counter"]["channel_updates"], 7);
+		assert_eq!(dump["peers"][0]["pubkey"], pubkey.serialize().to_lower_hex_string());
+		assert_eq!(dump["peers"][0]["connected"], false);
+		assert_eq!(dump["persistence"]["queue_depth"], 1);
+		assert!(dump["catch_up"].is_null());
+		assert!(dump["tokio"]["worker_count"].is_u64());
+	}
+}
@@ -0,0 +1,213 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Display;
+use std::ops::Deref;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use lightning::{log_info, log_warn};
+use lightning::util::logger::Logger;
+use lightning::util::ser::Writeable;
+use redis::aio::MultiplexedConnection;
+
+use crate::config;
+use crate::types::GossipMessage;
+
+/// The number of message IDs the local cache remembers
+const LOCAL_CACHE_CAPACITY: usize = 100_000;
+/// How long message IDs are kept in Redis. Other servers receive the same gossip within minutes.
+const REDIS_ID_TTL: Duration = Duration::from_secs(24 * 60 * 60);
+/// Messages are only forwarded to persistence once Redis has been asked, so it mustn't hold them
+/// up for long
+const REDIS_TIMEOUT: Duration = Duration::from_millis(200);
+/// After Redis fails, it's not retried for this long
+const REDIS_RETRY_INTERVAL: Duration = Duration::from_secs(30);
+
+pub(crate) type MessageId = [u8; 32];
+
+/// The hash of a message's signed serialization
+pub(crate) fn message_id(message: &GossipMessage) -> MessageId {
+	let (message_type, mut serialization) = match message {
+		GossipMessage::NodeAnnouncement(announcement, _) => (0u8, announcement.encode()),
+		GossipMessage::ChannelAnnouncement(announcement, _) => (1, announcement.encode()),
+		GossipMessage::ChannelUpdate(update, _) => (2, update.encode()),
+		GossipMessage::Batch(..) => unreachable!("batches have no ID"),
+	};
+	serialization.insert(0, message_type);
+	Sha256::hash(&serialization).to_byte_array()
+}
+
+/// The Redis cache shared with other servers, if one is configured
+pub(crate) fn shared_cache_from_config<L: Deref + Send + Sync>(logger: L) -> Option<RedisDeduplicationCache<L>> where L::Target: Logger {
+	config::redis_client().map(|client| RedisDeduplicationCache::new(client, logger))
+}
+
+struct LruState {
+	/// The index of the most recent use of each ID
+	last_used: HashMap<MessageId, u64>,
+	/// IDs in order of use. An ID is only live at its most recent use, and stale otherwise.
+	usage_order: VecDeque<(MessageId, u64)>,
+	use_count: u64,
+}
+
+/// Remembers which messages this server has already forwarded to persistence, evicting the least
+/// recently seen message IDs once its capacity is reached
+pub(crate) struct LocalDeduplicationCache {
+	capacity: usize,
+	state: Mutex<LruState>,
+}
+
+impl LocalDeduplicationCache {
+	pub(crate) fn new() -> Self {
+		Self::with_capacity(LOCAL_CACHE_CAPACITY)
+	}
+
+	pub(crate) fn with_capacity(capacity: usize) -> Self {
+		Self {
+			capacity,
+			state: Mutex::new(LruState { last_used: HashMap::new(), usage_order: VecDeque::new(), use_count: 0 }),
+		}
+	}
+
+	/// Record the message as seen, returning whether it had been seen before
+	pub(crate) fn check_and_insert(&self, message_id: &MessageId) -> bool {
+		let mut state = self.state.lock().expect("deduplication cache lock poisoned");
+		state.use_count += 1;
+		let use_index = state.use_count;
+		let was_seen = state.last_used.insert(*message_id, use_index).is_some();
+		state.usage_order.push_back((*message_id, use_index));
+
+		while state.last_used.len() > self.capacity {
+			let (evicted_id, evicted_use) = state.usage_order.pop_front().expect("every live ID is in the usage order");
+			if state.last_used.get(&evicted_id) == Some(&evicted_use) {
+				state.last_used.remove(&evicted_id);
+			}
+		}
+		if state.usage_order.len() > 2 * self.capacity {
+			let LruState { last_used, usage_order, .. } = &mut *state;
+			usage_order.retain(|(id, use_index)| last_used.get(id) == Some(use_index));
+		}
+		was_seen
+	}
+}
+
+struct RedisState {
+	connection: Option<MultiplexedConnection>,
+	/// While set, Redis is considered unavailable until then
+	retry_at: Option<Instant>,
+}
+
+/// Shares seen message IDs with other servers through Redis, so that each message is only
+/// persisted by whichever server forwards it first. Every check is a round trip to Redis, so it's
+/// awaited off the gossip handlers, on a connection which all of them share.
+pub(crate) struct RedisDeduplicationCache<L: Deref + Send + Sync> where L::Target: Logger {
+	client: redis::Client,
+	/// Held across connecting, so that concurrent checks don't each connect separately
+	state: tokio::sync::Mutex<RedisState>,
+	logger: L,
+}
+
+impl<L: Deref + Send + Sync> RedisDeduplicationCache<L> where L::Target: Logger {
+	pub(crate) fn new(client: redis::Client, logger: L) -> Self {
+		Self {
+			client,
+			state: tokio::sync::Mutex::new(RedisState { connection: None, retry_at: None }),
+			logger,
+		}
+	}
+
+	/// Record the message as seen, returning whether another server (or this one) had already
+	/// seen it, or None if Redis is unavailable
+	pub(crate) async fn check_and_insert(&self, message_id: &MessageId) -> Option<bool> {
+		let mut connection = self.connection().await?;
+		let key = format!("rgs:seen:{}", Sha256::from_byte_array(*message_id));
+		// setting the key only if it doesn't exist yet checks and records the ID atomically
+		let query = redis::cmd("SET").arg(key).arg(1).arg("NX").arg("EX").arg(REDIS_ID_TTL.as_secs()).query_async::<_, Option<String>>(&mut connection);
+		let reply = match tokio::time::timeout(REDIS_TIMEOUT, query).await {
+			Ok(Ok(reply)) => reply,
+			Ok(Err(error)) => return self.record_failure(error).await,
+			Err(_) => return self.record_failure("timed out").await,
+		};
+
+		let mut state = self.state.lock().await;
+		if state.retry_at.take().is_some() {
+			log_info!(self.logger, "Redis is available again, resuming shared gossip deduplication");
+		}
+		Some(reply.is_none())
+	}
+
+	/// The shared connection, which is established first if needed, or None while Redis is
+	/// considered unavailable
+	async fn connection(&self) -> Option<MultiplexedConnection> {
+		let mut state = self.state.lock().await;
+		if state.retry_at.map_or(false, |retry_at| Instant::now() < retry_at) {
+			return None;
+		}
+		if state.connection.is_none() {
+			match tokio::time::timeout(REDIS_TIMEOUT, self.client.get_multiplexed_tokio_connection()).await {
+				Ok(Ok(connection)) => state.connection = Some(connection),
+				Ok(Err(error)) => return Self::mark_unavailable(&mut state, error, &self.logger),
+				Err(_) => return Self::mark_unavailable(&mut state, "timed out", &self.logger),
+			}
+		}
+		state.connection.clone()
+	}
+
+	async fn record_failure<E: Display>(&self, error: E) -> Option<bool> {
+		let mut state = self.state.lock().await;
+		Self::mark_unavailable(&mut state, error, &self.logger)
+	}
+
+	/// Drop the connection and stop asking Redis for the retry interval, unless a concurrent check
+	/// failing already did
+	fn mark_unavailable<E: Display, T>(state: &mut RedisState, error: E, logger: &L) -> Option<T> {
+		state.connection = None;
+		if state.retry_at.map_or(true, |retry_at| retry_at <= Instant::now()) {
+			log_warn!(logger, "Redis is unavailable, deduplicating gossip locally for the next {}s: {}", REDIS_RETRY_INTERVAL.as_secs(), error);
+			state.retry_at = Some(Instant::now() + REDIS_RETRY_INTERVAL);
+		}
+		None
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use crate::types::tests::TestLogger;
+	use super::*;
+
+	#[test]
+	fn test_local_eviction() {
+		let cache = LocalDeduplicationCache::with_capacity(2);
+		assert!(!cache.check_and_insert(&[1; 32]));
+		assert!(!cache.check_and_insert(&[2; 32]));
+		assert!(cache.check_and_insert(&[1; 32]));
+
+		// the second ID is now the least recently seen one
+		assert!(!cache.check_and_insert(&[3; 32]));
+		assert!(cache.check_and_insert(&[1; 32]));
+		assert!(!cache.check_and_insert(&[2; 32]));
+
+		// repeated sightings mustn't grow the cache unboundedly
+		for _ in 0..10 {
+			cache.check_and_insert(&[1; 32]);
+		}
+		assert!(cache.state.lock().unwrap().usage_order.len() <= 4);
+	}
+
+	#[tokio::test]
+	async fn test_redis_unavailable() {
+		let logger = Arc::new(TestLogger::with_id("dedup".to_string()));
+		// nothing listens on the discard port
+		let client = redis::Client::open("redis://127.0.0.1:9/").unwrap();
+		let cache = RedisDeduplicationCache::new(client, logger.clone());
+
+		assert_eq!(cache.check_and_insert(&[1; 32]).await, None);
+		assert_eq!(cache.check_and_insert(&[1; 32]).await, None);
+		// Redis isn't retried until the retry interval has passed
+		logger.assert_log_contains("rapid_gossip_sync_server::dedup", "Redis is unavailable", 1);
+		assert!(cache.state.lock().await.retry_at.is_some());
+	}
+}
@@ -0,0 +1,245 @@
+//! Looking up the public keys of peers configured by address only, through a BOLT 10 DNS seed.
+//!
+//! Seeds don't support looking nodes up by address, but answer SRV queries with a sample of the
+//! nodes they know, as virtual hostnames encoding the node ids, and the ports they listen on. A
+//! peer's key is found if the sample includes a node listening on its port, whose hostname resolves
+//! to its address, so this works best for well-known nodes, and is retried a few times.
+
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Deref;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcoin::bech32::{self, FromBase32};
+use bitcoin::secp256k1::PublicKey;
+use lightning::log_info;
+use lightning::util::logger::Logger;
+use tokio::net::UdpSocket;
+
+use crate::config;
+
+/// How long a single query may take
+const QUERY_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How many samples of the seed's nodes are searched for a peer
+const QUERY_ATTEMPTS: usize = 3;
+
+const SRV_RECORD_TYPE: u16 = 33;
+const INTERNET_CLASS: u16 = 1;
+
+/// Look up the public key of the node listening at `addr` through the configured DNS seed,
+/// returning `None` if the seed's answers don't include it
+pub(crate) async fn resolve_peer_pubkey(addr: &SocketAddr) -> Option<PublicKey> {
+	let nameserver = system_nameserver()?;
+	// the `a` condition limits the answers to nodes with addresses of the peer's type
+	let address_types = if addr.is_ipv4() { 2 } else { 4 };
+	let query_name = format!("a{}.{}", address_types, config::dns_seed());
+	for _ in 0..QUERY_ATTEMPTS {
+		let records = match tokio::time::timeout(QUERY_TIMEOUT, query_srv_records(nameserver, &query_name)).await {
+			Ok(Some(records)) => records,
+			_ => continue,
+		};
+		for (port, target) in records {
+			if port != addr.port() {
+				continue;
+			}
+			let pubkey = match decode_node_hostname(&target) {
+				Some(pubkey) => pubkey,
+				None => continue,
+			};
+			let resolved_addresses = match tokio::time::timeout(QUERY_TIMEOUT, tokio::net::lookup_host((target.as_str(), port))).await {
+				Ok(Ok(resolved_addresses)) => resolved_addresses,
+				_ => continue,
+			};
+			if resolved_addresses.into_iter().any(|resolved_address| resolved_address == *addr) {
+				return Some(pubkey);
+			}
+		}
+	}
+	None
+}
+
+/// Fill in the public keys of the peers configured without one, logging those that were looked up,
+/// and failing if any can't be
+pub(crate) async fn resolve_peer_list<L: Deref>(peers: Vec<(Option<PublicKey>, SocketAddr)>, logger: L) -> Result<Vec<(PublicKey, SocketAddr)>, String> where L::Target: Logger {
+	let mut resolved_peers = Vec::with_capacity(peers.len());
+	for (pubkey, address) in peers {
+		let pubkey = match pubkey {
+			Some(pubkey) => pubkey,
+			None => {
+				let pubkey = resolve_peer_pubkey(&address).await
+					.ok_or_else(|| format!("Cannot look up the public key of peer {} through DNS seed {}", address, config::dns_seed()))?;
+				log_info!(logger, "Peer {} was configured without a public key, using {} from DNS seed {}", address, pubkey, config::dns_seed());
+				pubkey
+			},
+		};
+		resolved_peers.push((pubkey, address));
+	}
+	Ok(resolved_peers)
+}
+
+/// The first nameserver in `/etc/resolv.conf`
+fn system_nameserver() -> Option<SocketAddr> {
+	let resolv_conf = fs::read_to_string("/etc/resolv.conf").ok()?;
+	resolv_conf.lines()
+		.filter_map(|line| line.trim().strip_prefix("nameserver"))
+		.filter_map(|nameserver| nameserver.trim().parse::<IpAddr>().ok())
+		.map(|ip| SocketAddr::new(ip, 53))
+		.next()
+}
+
+async fn query_srv_records(nameserver: SocketAddr, name: &str) -> Option<Vec<(u16, String)>> {
+	let local_address: SocketAddr = if nameserver.is_ipv4() { "0.0.0.0:0" } else { "[::]:0" }.parse().expect("the wildcard address is valid");
+	let socket = UdpSocket::bind(local_address).await.ok()?;
+	socket.connect(nameserver).await.ok()?;
+	let id = SystemTime::now().duration_since(UNIX_EPOCH).ok()?.subsec_nanos() as u16;
+	socket.send(&encode_query(id, name, SRV_RECORD_TYPE)).await.ok()?;
+	let mut response = vec![0u8; 4096];
+	let response_length = socket.recv(&mut response).await.ok()?;
+	parse_srv_answers(&response[..response_length], id)
+}
+
+/// Decode the node id from a seed's virtual hostname, whose first label is its bech32 encoding
+fn decode_node_hostname(hostname: &str) -> Option<PublicKey> {
+	let label = hostname.split('.').next()?;
+	let (hrp, data, _) = bech32::decode(label).ok()?;
+	if hrp != "ln" {
+		return None;
+	}
+	PublicKey::from_slice(&Vec::<u8>::from_base32(&data).ok()?).ok()
+}
+
+fn encode_query(id: u16, name: &str, record_type: u16) -> Vec<u8> {
+	let mut query = Vec::with_capacity(name.len() + 18);
+	query.extend_from_slice(&id.to_be_bytes());
+	// a standard query, with recursion desired
+	query.extend_from_slice(&0x0100u16.to_be_bytes());
+	// one question, and no other records
+	query.extend_from_slice(&[0, 1, 0, 0, 0, 0, 0, 0]);
+	for label in name.split('.').filter(|label| !label.is_empty()) {
+		query.push(label.len() as u8);
+		query.extend_from_slice(label.as_bytes());
+	}
+	query.push(0);
+	query.extend_from_slice(&record_type.to_be_bytes());
+	query.extend_from_slice(&INTERNET_CLASS.to_be_bytes());
+	query
+}
+
+/// The ports and targets of the SRV records among a response's answers
+fn parse_srv_answers(response: &[u8], id: u16) -> Option<Vec<(u16, String)>> {
+	let read_u16 = |offset: usize| response.get(offset..offset + 2).map(|bytes| u16::from_be_bytes([bytes[0], bytes[1]]));
+	if read_u16(0)? != id {
+		return None;
+	}
+	// only responses without errors are used
+	if read_u16(2)? & 0x800f != 0x8000 {
+		return None;
+	}
+	let question_count = read_u16(4)?;
+	let answer_count = read_u16(6)?;
+
+	let mut offset = 12;
+	for _ in 0..question_count {
+		read_name(response, &mut offset)?;
+		offset += 4;
+	}
+	let mut records = Vec::new();
+	for _ in 0..answer_count {
+		read_name(response, &mut offset)?;
+		let record_type = read_u16(offset)?;
+		let data_length = read_u16(offset + 8)? as usize;
+		let data_offset = offset + 10;
+		offset = data_offset + data_length;
+		if record_type != SRV_RECORD_TYPE {
+			continue;
+		}
+		// priority and weight precede the port
+		let port = read_u16(data_offset + 4)?;
+		let mut target_offset = data_offset + 6;
+		records.push((port, read_name(response, &mut target_offset)?));
+	}
+	Some(records)
+}
+
+/// Read a possibly compressed domain name, advancing the offset past it
+fn read_name(message: &[u8], offset: &mut usize) -> Option<String> {
+	let mut labels = Vec::new();
+	let mut position = *offset;
+	let mut jumped = false;
+	// bounds the pointers followed, lest a malicious response loop forever
+	for _ in 0..128 {
+		let length = *message.get(position)? as usize;
+		if length & 0xc0 == 0xc0 {
+			let pointer = ((length & 0x3f) << 8) | *message.get(position + 1)? as usize;
+			if !jumped {
+				*offset = position + 2;
+				jumped = true;
+			}
+			position = pointer;
+			continue;
+		}
+		if length == 0 {
+			if !jumped {
+				*offset = position + 1;
+			}
+			return Some(labels.join("."));
+		}
+		let label = message.get(position + 1..position + 1 + length)?;
+		labels.push(String::from_utf8(label.to_vec()).ok()?);
+		position += 1 + length;
+	}
+	None
+}
+
+#[cfg(test)]
+mod tests {
+	use std::str::FromStr;
+
+	use bitcoin::bech32::{ToBase32, Variant};
+
+	use super::*;
+
+	const NODE_ID: &str = "035e4ff418fc8b5554c5d9eea66396c227bd429a3251c8cbc711002ba215bfc226";
+
+	#[test]
+	fn test_srv_response_parsing() {
+		let pubkey = PublicKey::from_str(NODE_ID).unwrap();
+		let node_label = bech32::encode("ln", pubkey.serialize().to_base32(), Variant::Bech32).unwrap();
+
+		let mut response = encode_query(42, "a2.nodes.lightning.directory", SRV_RECORD_TYPE);
+		// mark it as a response with one answer
+		response[2..4].copy_from_slice(&0x8180u16.to_be_bytes());
+		response[6..8].copy_from_slice(&1u16.to_be_bytes());
+		// the answer's name points to the question's
+		response.extend_from_slice(&[0xc0, 12]);
+		response.extend_from_slice(&SRV_RECORD_TYPE.to_be_bytes());
+		response.extend_from_slice(&INTERNET_CLASS.to_be_bytes());
+		response.extend_from_slice(&60u32.to_be_bytes());
+		let mut data = vec![0, 10, 0, 10];
+		data.extend_from_slice(&9735u16.to_be_bytes());
+		data.push(node_label.len() as u8);
+		data.extend_from_slice(node_label.as_bytes());
+		// the rest of the target is the seed's name, pointed to within the question
+		data.extend_from_slice(&[0xc0, 15]);
+		response.extend_from_slice(&(data.len() as u16).to_be_bytes());
+		response.extend_from_slice(&data);
+
+		let records = parse_srv_answers(&response, 42).unwrap();
+		assert_eq!(records, vec![(9735, format!("{}.nodes.lightning.directory", node_label))]);
+		assert_eq!(decode_node_hostname(&records[0].1), Some(pubkey));
+
+		// responses to other queries are ignored
+		assert!(parse_srv_answers(&response, 43).is_none());
+		// as are truncated ones
+		assert!(parse_srv_answers(&response[..response.len() - 3], 42).is_none());
+	}
+
+	#[test]
+	fn test_pointer_loops() {
+		let mut message = vec![0u8; 12];
+		message.extend_from_slice(&[0xc0, 12]);
+		let mut offset = 12;
+		assert!(read_name(&message, &mut offset).is_none());
+	}
+}
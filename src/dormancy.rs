@@ -0,0 +1,277 @@
+//! Keeps configured peers that have been unreachable for a long time from being retried as often
+//! as the rest.
+//!
+//! A peer becomes dormant once a number of consecutive connection rounds failed, spanning at least
+//! a number of days, and is then retried only once a day, until any connection succeeds. The
+//! failure streaks and the times of the latest successes are kept in the `peer_state` table, so
+//! that dormancy survives restarts. Dormant peers are retried regardless of their schedule while
+//! fewer than the targeted number of peers are connected, lest dormancy starve the server of
+//! gossip.
+
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use bitcoin::secp256k1::PublicKey;
+use hex_conservative::display::DisplayHex;
+use lightning::log_warn;
+use lightning::util::logger::Logger;
+use tokio_postgres::Client;
+
+use crate::error::{ErrorContext, ProcessorError};
+use crate::persistence;
+use crate::tables::Tables;
+
+/// Dormant peers are retried at most this often while enough other peers are connected
+pub(crate) const DORMANT_RETRY_INTERVAL: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// When peers become dormant, and how many connected peers dormant ones may be left out for
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct DormancyPolicy {
+	/// The number of consecutive failed connection rounds after which a peer may become dormant
+	pub(crate) failure_threshold: u32,
+	/// How long a peer's failures must have been going on for it to become dormant
+	pub(crate) min_failing_duration: Duration,
+	/// Below this many connected peers, dormant peers are retried as often as any other
+	pub(crate) target_connected_peers: usize,
+}
+
+/// A peer's history of connection attempts, with times as unix timestamps
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct FailureHistory {
+	pub(crate) consecutive_failures: u32,
+	/// When the first of the consecutive failures happened
+	pub(crate) failing_since: Option<u64>,
+	pub(crate) last_success_at: Option<u64>,
+	/// When the peer was last tried, which isn't persisted, so that dormant peers are tried once
+	/// after every restart
+	pub(crate) last_attempt_at: Option<u64>,
+}
+
+impl FailureHistory {
+	pub(crate) fn is_dormant(&self, policy: &DormancyPolicy, now: u64) -> bool {
+		let failing_duration = self.failing_since.map_or(0, |failing_since| now.saturating_sub(failing_since));
+		self.consecutive_failures >= policy.failure_threshold && failing_duration >= policy.min_failing_duration.as_secs()
+	}
+
+	/// Whether the peer may be tried at the given time, given the number of connected peers
+	fn is_attempt_due(&self, policy: &DormancyPolicy, now: u64, connected_peers: usize) -> bool {
+		if !self.is_dormant(policy, now) || connected_peers < policy.target_connected_peers {
+			return true;
+		}
+		self.last_attempt_at.map_or(true, |last_attempt_at| now.saturating_sub(last_attempt_at) >= DORMANT_RETRY_INTERVAL.as_secs())
+	}
+
+	/// Record a failed connection round, returning whether the peer just became dormant
+	fn record_failure(&mut self, policy: &DormancyPolicy, now: u64) -> bool {
+		let was_dormant = self.is_dormant(policy, now);
+		self.consecutive_failures += 1;
+		self.failing_since.get_or_insert(now);
+		self.last_attempt_at = Some(now);
+		!was_dormant && self.is_dormant(policy, now)
+	}
+
+	/// Record a successful connection, returning whether the peer was dormant until now
+	fn record_success(&mut self, policy: &DormancyPolicy, now: u64) -> bool {
+		let was_dormant = self.is_dormant(policy, now);
+		self.consecutive_failures = 0;
+		self.failing_since = None;
+		self.last_success_at = Some(now);
+		self.last_attempt_at = Some(now);
+		was_dormant
+	}
+}
+
+/// What happened to a peer's dormancy with a connection attempt
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum DormancyChange {
+	BecameDormant,
+	Reactivated,
+}
+
+/// The failure histories of all configured peers
+pub(crate) struct DormancyTracker {
+	policy: DormancyPolicy,
+	histories: Mutex<HashMap<PublicKey, FailureHistory>>,
+	/// Where histories are persisted to, unless they're only kept in memory
+	tables: Option<Tables>,
+}
+
+impl DormancyTracker {
+	/// A tracker that keeps the histories in memory only
+	pub(crate) fn new(policy: DormancyPolicy) -> Self {
+		Self { policy, histories: Mutex::new(HashMap::new()), tables: None }
+	}
+
+	/// A tracker that persists every change to a history to the `peer_state` table
+	pub(crate) fn persisted(policy: DormancyPolicy, tables: Tables) -> Self {
+		Self { policy, histories: Mutex::new(HashMap::new()), tables: Some(tables) }
+	}
+
+	pub(crate) fn policy(&self) -> &DormancyPolicy {
+		&self.policy
+	}
+
+	/// Whether the peer may be tried now, given the number of connected peers
+	pub(crate) fn is_attempt_due(&self, pubkey: &PublicKey, now: u64, connected_peers: usize) -> bool {
+		let histories = self.histories.lock().expect("dormancy lock poisoned");
+		histories.get(pubkey).map_or(true, |history| history.is_attempt_due(&self.policy, now, connected_peers))
+	}
+
+	pub(crate) fn record_failure<L: Deref + Clone + Send + Sync + 'static>(&self, pubkey: PublicKey, now: u64, logger: L) -> Option<DormancyChange> where L::Target: Logger {
+		let (became_dormant, history) = {
+			let mut histories = self.histories.lock().expect("dormancy lock poisoned");
+			let history = histories.entry(pubkey).or_default();
+			(history.record_failure(&self.policy, now), history.clone())
+		};
+		self.persist(pubkey, history, logger);
+		Some(DormancyChange::BecameDormant).filter(|_| became_dormant)
+	}
+
+	pub(crate) fn record_success<L: Deref + Clone + Send + Sync + 'static>(&self, pubkey: PublicKey, now: u64, logger: L) -> Option<DormancyChange> where L::Target: Logger {
+		let (was_dormant, history) = {
+			let mut histories = self.histories.lock().expect("dormancy lock poisoned");
+			let history = histories.entry(pubkey).or_default();
+			(history.record_success(&self.policy, now), history.clone())
+		};
+		self.persist(pubkey, history, logger);
+		Some(DormancyChange::Reactivated).filter(|_| was_dormant)
+	}
+
+	/// The histories of the peers that are dormant at the given time, longest failing first
+	pub(crate) fn dormant_peers(&self, now: u64) -> Vec<(PublicKey, FailureHistory)> {
+		let histories = self.histories.lock().expect("dormancy lock poisoned");
+		let mut dormant_peers: Vec<(PublicKey, FailureHistory)> = histories.iter()
+			.filter(|(_, history)| history.is_dormant(&self.policy, now))
+			.map(|(pubkey, history)| (*pubkey, history.clone()))
+			.collect();
+		dormant_peers.sort_unstable_by_key(|(pubkey, history)| (history.failing_since, pubkey.serialize()));
+		dormant_peers
+	}
+
+	/// Restore the histories persisted before a restart
+	pub(crate) async fn load(&self, client: &Client, tables: &Tables) -> Result<usize, ProcessorError> {
+		let rows = client.query(&format!("SELECT public_key, consecutive_failures, \
+			EXTRACT(EPOCH FROM failing_since)::bigint AS failing_since, EXTRACT(EPOCH FROM last_success_at)::bigint AS last_success_at \
+			FROM {} WHERE consecutive_failures > 0 OR last_success_at IS NOT NULL", tables.peer_state()), &[]).await
+			.context("Failed to read peer failure histories")?;
+		let mut histories = self.histories.lock().expect("dormancy lock poisoned");
+		let mut loaded_count = 0;
+		for row in rows {
+			let pubkey = <[u8; 33]>::try_from(row.get::<_, &[u8]>("public_key")).ok()
+				.and_then(|bytes| persistence::bytes_to_pubkey(&bytes).ok());
+			if let Some(pubkey) = pubkey {
+				histories.insert(pubkey, FailureHistory {
+					consecutive_failures: row.get::<_, i32>("consecutive_failures") as u32,
+					failing_since: row.get::<_, Option<i64>>("failing_since").map(|timestamp| timestamp as u64),
+					last_success_at: row.get::<_, Option<i64>>("last_success_at").map(|timestamp| timestamp as u64),
+					last_attempt_at: None,
+				});
+				loaded_count += 1;
+			}
+		}
+		Ok(loaded_count)
+	}
+
+	fn persist<L: Deref + Clone + Send + Sync + 'static>(&self, pubkey: PublicKey, history: FailureHistory, logger: L) where L::Target: Logger {
+		let tables = match &self.tables {
+			Some(tables) => tables.clone(),
+			None => return,
+		};
+		tokio::spawn(async move {
+			let result = async {
+				let client = crate::connect_to_db().await?;
+				persist_failure_history(&client, &tables, &pubkey, &history).await
+			}.await;
+			if let Err(error) = result {
+				log_warn!(logger, "Failed to persist the failure history of peer {}: {}", pubkey.serialize().to_lower_hex_string(), error);
+			}
+		});
+	}
+}
+
+/// Record a peer's failure history, leaving any learned address alone
+pub(crate) async fn persist_failure_history(client: &Client, tables: &Tables, pubkey: &PublicKey, history: &FailureHistory) -> Result<(), ProcessorError> {
+	let failing_since = history.failing_since.map(|timestamp| timestamp as f64);
+	let last_success_at = history.last_success_at.map(|timestamp| timestamp as f64);
+	client.execute(&format!("INSERT INTO {} (public_key, learned_at, consecutive_failures, failing_since, last_success_at) VALUES ($1, NULL, $2, TO_TIMESTAMP($3), TO_TIMESTAMP($4))
+		ON CONFLICT (public_key) DO UPDATE SET consecutive_failures = EXCLUDED.consecutive_failures, failing_since = EXCLUDED.failing_since, last_success_at = EXCLUDED.last_success_at", tables.peer_state()),
+		&[&persistence::pubkey_to_bytes(pubkey).to_vec(), &(history.consecutive_failures as i32), &failing_since, &last_success_at]).await
+		.context("Failed to persist peer failure history")?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const DAY: u64 = 24 * 60 * 60;
+	const POLICY: DormancyPolicy = DormancyPolicy {
+		failure_threshold: 3,
+		min_failing_duration: Duration::from_secs(2 * DAY),
+		target_connected_peers: 2,
+	};
+
+	#[test]
+	fn test_dormancy_transitions() {
+		let mut history = FailureHistory::default();
+		let start = 1_700_000_000;
+		assert!(!history.record_failure(&POLICY, start));
+		assert!(!history.record_failure(&POLICY, start + 60));
+		// enough failures, but not for long enough
+		assert!(!history.record_failure(&POLICY, start + 120));
+		assert!(!history.is_dormant(&POLICY, start + 120));
+		assert!(history.record_failure(&POLICY, start + 2 * DAY));
+		assert!(history.is_dormant(&POLICY, start + 2 * DAY));
+		// staying dormant is not a transition
+		assert!(!history.record_failure(&POLICY, start + 3 * DAY));
+		assert_eq!((history.consecutive_failures, history.failing_since), (5, Some(start)));
+
+		// any success reactivates the peer and starts its failures over
+		assert!(history.record_success(&POLICY, start + 4 * DAY));
+		assert!(!history.is_dormant(&POLICY, start + 4 * DAY));
+		assert_eq!((history.consecutive_failures, history.failing_since, history.last_success_at), (0, None, Some(start + 4 * DAY)));
+		assert!(!history.record_success(&POLICY, start + 5 * DAY));
+	}
+
+	#[test]
+	fn test_dormant_retry_schedule() {
+		let mut history = FailureHistory { consecutive_failures: 10, failing_since: Some(0), last_success_at: None, last_attempt_at: None };
+		let now = 10 * DAY;
+		// dormant peers loaded after a restart are tried once right away
+		assert!(history.is_attempt_due(&POLICY, now, 5));
+		history.record_failure(&POLICY, now);
+		assert!(!history.is_attempt_due(&POLICY, now + DAY - 1, 5));
+		assert!(history.is_attempt_due(&POLICY, now + DAY, 5));
+		// too few connected peers override the schedule
+		assert!(history.is_attempt_due(&POLICY, now + 60, 1));
+
+		// peers that aren't dormant are always due
+		assert!(FailureHistory::default().is_attempt_due(&POLICY, now, 5));
+	}
+
+	#[test]
+	fn test_dormant_peer_listing() {
+		let tracker = DormancyTracker::new(POLICY);
+		let logger = std::sync::Arc::new(crate::types::tests::TestLogger::with_id("test_dormant_peer_listing".to_string()));
+		let secp_context = bitcoin::secp256k1::Secp256k1::new();
+		let pubkey = |byte: u8| bitcoin::secp256k1::SecretKey::from_slice(&[byte; 32]).unwrap().public_key(&secp_context);
+		let (dormant_peer, failing_peer, reachable_peer) = (pubkey(1), pubkey(2), pubkey(3));
+		for day in 0..3 {
+			assert_eq!(tracker.record_failure(dormant_peer, day * DAY, logger.clone()), Some(DormancyChange::BecameDormant).filter(|_| day == 2));
+			tracker.record_failure(failing_peer, day * 60, logger.clone());
+		}
+		tracker.record_success(reachable_peer, 0, logger.clone());
+
+		let dormant_peers = tracker.dormant_peers(3 * DAY);
+		assert_eq!(dormant_peers.len(), 1);
+		assert_eq!(dormant_peers[0].0, dormant_peer);
+		assert!(!tracker.is_attempt_due(&dormant_peer, 2 * DAY + 60, 2));
+		assert!(tracker.is_attempt_due(&failing_peer, 2 * DAY + 60, 2));
+		assert!(tracker.is_attempt_due(&reachable_peer, 2 * DAY + 60, 2));
+
+		assert_eq!(tracker.record_success(dormant_peer, 3 * DAY, logger), Some(DormancyChange::Reactivated));
+		assert!(tracker.dormant_peers(3 * DAY).is_empty());
+	}
+}
@@ -1,24 +1,94 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::net::SocketAddr;
 use std::ops::Deref;
-use std::sync::{Arc, RwLock};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use bitcoin::blockdata::constants::ChainHash;
 use bitcoin::secp256k1::PublicKey;
+use hex_conservative::display::DisplayHex;
+use lightning::{log_debug, log_info, log_warn};
 use lightning::events::{MessageSendEvent, MessageSendEventsProvider};
 use lightning::ln::features::{InitFeatures, NodeFeatures};
-use lightning::ln::msgs::{ChannelAnnouncement, ChannelUpdate, Init, LightningError, NodeAnnouncement, QueryChannelRange, QueryShortChannelIds, ReplyChannelRange, ReplyShortChannelIdsEnd, RoutingMessageHandler};
+use lightning::ln::msgs::{ChannelAnnouncement, ChannelUpdate, ErrorAction, Init, LightningError, NodeAnnouncement, QueryChannelRange, QueryShortChannelIds, ReplyChannelRange, ReplyShortChannelIdsEnd, RoutingMessageHandler};
 use lightning::routing::gossip::{NetworkGraph, NodeId, P2PGossipSync};
 use lightning::util::logger::Logger;
+use lightning::util::ser::Writeable;
+use lightning_block_sync::http::HttpEndpoint;
+use serde::Serialize;
 use tokio::sync::mpsc;
-use tokio::sync::mpsc::error::TrySendError;
 
+use crate::archive::{ArchivalResponder, QueryReplyQueue};
+use crate::batcher::MessageBatcher;
+use crate::chain_stats::ChainBackendStats;
+use crate::change_tracker::ChangeTracker;
+use crate::config;
+use crate::dedup::{self, LocalDeduplicationCache, RedisDeduplicationCache};
+use crate::early_updates::EarlyUpdateBuffer;
+use crate::gossip_archive::{ArchivedMessageType, GossipArchive};
+use crate::gossip_consumers::GossipConsumers;
+use crate::htlc_maximum;
+use crate::peer_registry::{self, AddressBook, PeerConnectionStats, PendingMessageQueue};
+use crate::persistence;
+use crate::propagation::PropagationDelays;
+use crate::rate_limiter::{RelayBudget, UpdateRateLimiter};
+use crate::sync_progress::ChannelCountEstimate;
+use crate::tables::Tables;
 use crate::types::{GossipMessage, GossipChainAccess, GossipPeerManager};
+use crate::validation::{ValidationPool, ValidationResult};
 use crate::verifier::ChainVerifier;
 
+/// The number of message count samples kept for calculating message rates, which covers an hour
+/// at the tracking loop's five-second interval
+const MESSAGE_RATE_SAMPLE_CAPACITY: usize = 720;
+
+#[derive(Clone, Serialize)]
 pub(crate) struct GossipCounter {
 	pub(crate) node_announcements: u64,
 	pub(crate) channel_announcements: u64,
 	pub(crate) channel_updates: u64,
 	pub(crate) channel_updates_without_htlc_max_msats: u64,
-	pub(crate) channel_announcements_with_mismatched_scripts: u64
+	pub(crate) channel_announcements_with_mismatched_scripts: u64,
+	/// Validly signed channel announcements claiming different nodes than the known announcement of
+	/// their channel
+	pub(crate) conflicting_announcements: u64,
+	pub(crate) ignored_onion_messages: u64,
+	pub(crate) ignored_custom_messages: u64,
+	/// Channel updates dropped for exceeding their channel direction's hourly limit
+	pub(crate) rate_limited_updates: u64,
+	/// Messages that were processed but not relayed to peers for exceeding the relay bandwidth
+	pub(crate) rate_limited_relays: u64,
+	/// Channel updates that arrived before their channel's announcement, and were processed once
+	/// it was accepted
+	pub(crate) replayed_early_updates: u64,
+	/// Channel updates held for an announcement that didn't arrive in time
+	pub(crate) expired_early_updates: u64,
+	/// Channel updates rejected for timestamps too far in the future
+	pub(crate) future_timestamped_updates: u64,
+	/// Messages the persister gave up on, which were logged and possibly dead-lettered instead
+	pub(crate) persistence_failures: u64,
+	/// Silent peers pinged to check whether they're still alive
+	pub(crate) peers_pinged: u64,
+	/// Pinged peers that were disconnected for not answering in time
+	pub(crate) peers_dropped_after_ping_timeout: u64,
+	/// Peers that were disconnected for not sending a single byte for too long
+	pub(crate) peers_dropped_idle: u64,
+	/// The time between accepted channel updates' timestamps and their receipt, once caught up
+	pub(crate) propagation_delays: PropagationDelays,
+	/// Warnings that the median propagation delay exceeded its threshold for several windows
+	pub(crate) propagation_lag_warnings: u64,
+	/// The number of connected peers as of the latest tracking iteration
+	pub(crate) connected_peers: usize,
+	/// Whether gossip was caught up on as of the latest tracking iteration
+	pub(crate) is_caught_up: bool,
+	/// Announcements and updates received per second during the latest tracking iteration
+	pub(crate) recent_message_rate: f64,
+	/// The unix timestamp at which the latest announcement or update was received
+	pub(crate) last_message_received_at: Option<u64>,
+	/// The cumulative message count as of each of the latest tracking iterations, oldest first
+	#[serde(skip)]
+	message_count_samples: VecDeque<(Instant, u64)>,
 }
 
 impl GossipCounter {
@@ -29,116 +99,535 @@ impl GossipCounter {
 			channel_updates: 0,
 			channel_updates_without_htlc_max_msats: 0,
 			channel_announcements_with_mismatched_scripts: 0,
+			conflicting_announcements: 0,
+			ignored_onion_messages: 0,
+			ignored_custom_messages: 0,
+			rate_limited_updates: 0,
+			rate_limited_relays: 0,
+			replayed_early_updates: 0,
+			expired_early_updates: 0,
+			future_timestamped_updates: 0,
+			persistence_failures: 0,
+			peers_pinged: 0,
+			peers_dropped_after_ping_timeout: 0,
+			peers_dropped_idle: 0,
+			propagation_delays: PropagationDelays::default(),
+			propagation_lag_warnings: 0,
+			connected_peers: 0,
+			is_caught_up: false,
+			recent_message_rate: 0.0,
+			last_message_received_at: None,
+			message_count_samples: VecDeque::with_capacity(MESSAGE_RATE_SAMPLE_CAPACITY),
+		}
+	}
+
+	fn message_count(&self) -> u64 {
+		self.node_announcements + self.channel_announcements + self.channel_updates
+	}
+
+	/// Record the cumulative message count for calculating message rates, dropping the oldest
+	/// sample once the buffer is full
+	pub(crate) fn sample_message_count(&mut self, now: Instant) {
+		if self.message_count_samples.len() >= MESSAGE_RATE_SAMPLE_CAPACITY {
+			self.message_count_samples.pop_front();
+		}
+		let message_count = self.message_count();
+		self.message_count_samples.push_back((now, message_count));
+	}
+
+	/// The average number of messages received per second over the last `window_secs` before the
+	/// latest sample, or over as much of it as has been sampled
+	pub(crate) fn message_rate_hz(&self, window_secs: u64) -> f64 {
+		let (latest_sampled_at, latest_count) = match self.message_count_samples.back() {
+			Some(latest_sample) => *latest_sample,
+			None => return 0.0,
+		};
+		let window = Duration::from_secs(window_secs);
+		let earliest_sample = self.message_count_samples.iter()
+			.find(|(sampled_at, _)| latest_sampled_at.saturating_duration_since(*sampled_at) <= window);
+		match earliest_sample {
+			Some((sampled_at, count)) if *sampled_at < latest_sampled_at => {
+				(latest_count - count) as f64 / latest_sampled_at.duration_since(*sampled_at).as_secs_f64()
+			},
+			_ => 0.0,
 		}
 	}
+
+	fn record_receipt(&mut self) {
+		self.last_message_received_at = Some(current_timestamp());
+	}
+}
+
+fn current_timestamp() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch").as_secs()
+}
+
+/// Whether a timestamp is more than `max_future_secs` ahead of `now`
+fn is_future_dated(timestamp: u32, now: u64, max_future_secs: u64) -> bool {
+	timestamp as u64 > now.saturating_add(max_future_secs)
+}
+
+impl fmt::Display for GossipCounter {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		writeln!(f, "catch-up status: {}", if self.is_caught_up { "caught up" } else { "catching up" })?;
+		writeln!(f, "messages: {}", self.node_announcements + self.channel_announcements + self.channel_updates)?;
+		writeln!(f, "\tnode announcements: {}", self.node_announcements)?;
+		writeln!(f, "\tchannel announcements: {}", self.channel_announcements)?;
+		writeln!(f, "\tchannel updates: {}", self.channel_updates)?;
+		writeln!(f, "connected peers: {}", self.connected_peers)?;
+		match self.last_message_received_at {
+			Some(timestamp) => write!(f, "last message received at: {}", timestamp),
+			None => write!(f, "last message received at: never"),
+		}
+	}
+}
+
+impl fmt::Debug for GossipCounter {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		fmt::Display::fmt(self, f)
+	}
 }
 
 pub(crate) struct GossipRouter<L: Deref + Clone + Send + Sync + 'static> where L::Target: Logger {
 	native_router: P2PGossipSync<Arc<NetworkGraph<L>>, GossipChainAccess<L>, L>,
-	pub(crate) counter: RwLock<GossipCounter>,
-	sender: mpsc::Sender<GossipMessage>,
-	verifier: Arc<ChainVerifier<L>>,
+	pub(crate) counter: Arc<RwLock<GossipCounter>>,
+	pub(crate) batcher: Arc<MessageBatcher>,
+	pub(crate) verifier: Arc<ChainVerifier<L>>,
 	outbound_gossiper: Arc<P2PGossipSync<Arc<NetworkGraph<L>>, GossipChainAccess<L>, L>>,
+	pub(crate) validation_pool: Arc<ValidationPool<L>>,
+	/// Messages this server already forwarded to persistence
+	deduplication_cache: LocalDeduplicationCache,
+	/// Messages other servers sharing the database already forwarded to persistence, if configured
+	shared_deduplication_cache: Option<Arc<RedisDeduplicationCache<L>>>,
+	/// Answers gossip queries from the database too, if enabled
+	archival_responder: Option<ArchivalResponder<L>>,
+	/// If set, peers are only asked for gossip from this unix timestamp onwards
+	resume_timestamp: Option<u32>,
+	/// The configured peers' addresses, as learned from their node announcements
+	pub(crate) address_book: Arc<AddressBook>,
+	/// The configured peers' connection records, which the router adds the latest message times to
+	pub(crate) peer_stats: Arc<PeerConnectionStats>,
+	pub(crate) rate_limiter: UpdateRateLimiter,
+	/// How far ahead of the current time channel update timestamps may be
+	max_future_timestamp_secs: u64,
+	/// Caps the bandwidth spent relaying gossip to peers, if configured. Replies to peers' gossip
+	/// queries aren't relayed gossip, so they aren't subject to it.
+	relay_budget: Option<RelayBudget>,
+	/// The network's channel count according to peers' channel range replies, for reporting the
+	/// initial sync's progress
+	pub(crate) channel_count_estimate: ChannelCountEstimate,
+	/// Channel range queries to be sent to newly connected peers, and to silent peers as pings
+	pub(crate) pending_range_queries: PendingMessageQueue,
+	/// Updates for channels whose announcement hasn't been received yet, if enabled
+	pub(crate) early_updates: Option<EarlyUpdateBuffer>,
+	/// Where new channels and fee changes are counted towards generating snapshots ahead of
+	/// schedule
+	change_tracker: Arc<ChangeTracker>,
+	/// Where every received message is appended to, whether it's accepted or not, if enabled
+	gossip_archive: Option<Arc<GossipArchive>>,
+	logger: L,
 }
 
 impl<L: Deref + Clone + Send + Sync> GossipRouter<L> where L::Target: Logger {
-	pub(crate) fn new(network_graph: Arc<NetworkGraph<L>>, sender: mpsc::Sender<GossipMessage>, logger: L) -> Self {
+	pub(crate) fn new(network_graph: Arc<NetworkGraph<L>>, sender: mpsc::Sender<GossipMessage>, counter: Arc<RwLock<GossipCounter>>, chain_backend: HttpEndpoint, backend_stats: Arc<ChainBackendStats>, logger: L) -> Self {
 		let outbound_gossiper = Arc::new(P2PGossipSync::new(Arc::clone(&network_graph), None, logger.clone()));
-		let verifier = Arc::new(ChainVerifier::new(Arc::clone(&network_graph), Arc::clone(&outbound_gossiper), logger.clone()));
+		let verifier = Arc::new(ChainVerifier::new(Arc::clone(&network_graph), Arc::clone(&outbound_gossiper), chain_backend, backend_stats, logger.clone()));
 		Self {
+			validation_pool: Arc::new(ValidationPool::new(Arc::clone(&network_graph))),
+			deduplication_cache: LocalDeduplicationCache::new(),
+			shared_deduplication_cache: dedup::shared_cache_from_config(logger.clone()).map(Arc::new),
+			archival_responder: (config::archival_queries_enabled() || config::respond_to_queries_enabled()).then(|| ArchivalResponder::new(Arc::clone(&network_graph), logger.clone())),
 			native_router: P2PGossipSync::new(network_graph, Some(Arc::clone(&verifier)), logger.clone()),
 			outbound_gossiper,
-			counter: RwLock::new(GossipCounter::new()),
-			sender,
-			verifier
+			counter,
+			batcher: Arc::new(MessageBatcher::new(sender)),
+			verifier,
+			resume_timestamp: None,
+			address_book: Arc::new(AddressBook::new()),
+			peer_stats: Arc::new(PeerConnectionStats::new()),
+			rate_limiter: UpdateRateLimiter::new(config::max_updates_per_scid_per_hour()),
+			max_future_timestamp_secs: config::max_future_timestamp_secs(),
+			relay_budget: config::relay_bytes_per_second().map(|bytes_per_second| RelayBudget::new(bytes_per_second, config::relay_burst_bytes(), Instant::now())),
+			channel_count_estimate: ChannelCountEstimate::new(),
+			pending_range_queries: Arc::new(Mutex::new(Vec::new())),
+			early_updates: config::early_update_window().map(EarlyUpdateBuffer::new),
+			change_tracker: Arc::new(ChangeTracker::new(config::snapshot_triggers())),
+			gossip_archive: None,
+			logger,
 		}
 	}
 
+	pub(crate) fn set_change_tracker(&mut self, change_tracker: Arc<ChangeTracker>) {
+		self.change_tracker = change_tracker;
+	}
+
+	pub(crate) fn set_gossip_archive(&mut self, gossip_archive: Arc<GossipArchive>) {
+		self.gossip_archive = Some(gossip_archive);
+	}
+
+	pub(crate) fn set_gossip_consumers(&mut self, consumers: Arc<GossipConsumers>) {
+		Arc::get_mut(&mut self.batcher).expect("the batcher is only shared once the router is").set_consumers(consumers);
+	}
+
+	fn archive<M: Writeable>(&self, message_type: ArchivedMessageType, msg: &M) {
+		if let Some(gossip_archive) = &self.gossip_archive {
+			gossip_archive.record(message_type, msg);
+		}
+	}
+
+	/// Request gossip from the given unix timestamp onwards from all peers, instead of having the
+	/// first few peers send everything they know
+	pub(crate) fn set_resume_timestamp(&mut self, resume_timestamp: u32) {
+		self.resume_timestamp = Some(resume_timestamp);
+	}
+
 	pub(crate) fn set_pm(&self, peer_handler: GossipPeerManager<L>) {
+		if let Some(archival_responder) = self.archival_responder.as_ref() {
+			archival_responder.set_ph(Arc::clone(&peer_handler));
+		}
 		self.verifier.set_ph(peer_handler);
 	}
 
-	fn new_channel_announcement(&self, msg: ChannelAnnouncement) {
+	/// A point-in-time copy of the gossip counter, for logging or reporting it without holding its
+	/// lock
+	pub(crate) fn counter_snapshot(&self) -> GossipCounter {
+		self.counter.read().expect("gossip counter lock poisoned").clone()
+	}
+
+	/// The replies the archival responder leaves to the custom message handler to send, if any
+	pub(crate) fn query_replies(&self) -> Option<QueryReplyQueue> {
+		self.archival_responder.as_ref().map(|archival_responder| archival_responder.query_replies())
+	}
+
+	/// Feed all parked announcements whose funding outputs have since reached the required
+	/// confirmation depth, along with their updates, through the regular processing pipeline.
+	pub(crate) fn release_parked_announcements(&self) {
+		for parked_announcement in self.verifier.take_matured_announcements() {
+			// asynchronous verification is reported as an error, so the results are ignored
+			let _ = self.handle_channel_announcement(&parked_announcement.announcement);
+			for update in [parked_announcement.updates.0, parked_announcement.updates.1].into_iter().flatten() {
+				let _ = self.handle_channel_update(&update);
+			}
+		}
+	}
+
+	/// Count the held early updates that expired without their announcement arriving
+	pub(crate) fn expire_early_updates(&self) {
+		let expired_count = match self.early_updates.as_ref() {
+			Some(early_updates) => early_updates.expire_held_updates(Instant::now()),
+			None => return,
+		};
+		if expired_count > 0 {
+			log_debug!(self.logger, "{} channel updates expired before their channel's announcement arrived", expired_count);
+			self.counter.write().expect("gossip counter lock poisoned").expired_early_updates += expired_count as u64;
+		}
+	}
+
+	/// Process the updates that arrived before the announcement of a channel, now that the
+	/// announcement has been accepted or is being verified, which the native router holds updates
+	/// for in turn. Replayed updates aren't relayed, as peers received them long ago.
+	fn replay_early_updates(&self, short_channel_id: u64) {
+		let updates = match self.early_updates.as_ref() {
+			Some(early_updates) => early_updates.take(short_channel_id, Instant::now()),
+			None => return,
+		};
+		if updates.is_empty() {
+			return;
+		}
+		log_debug!(self.logger, "Replaying {} channel updates that arrived before the announcement of channel {}", updates.len(), short_channel_id);
+		self.counter.write().expect("gossip counter lock poisoned").replayed_early_updates += updates.len() as u64;
+		for update in updates {
+			let _ = self.process_channel_update(&update, false);
+		}
+	}
+
+	/// Process a channel update, holding it for its channel's announcement if `hold_if_early` is
+	/// set and the graph doesn't know the channel yet
+	fn process_channel_update(&self, msg: &ChannelUpdate, hold_if_early: bool) -> Result<bool, LightningError> {
+		let received_at = Instant::now();
+		let channel_direction = (msg.contents.short_channel_id, msg.contents.flags & 1);
+		if is_future_dated(msg.contents.timestamp, current_timestamp(), self.max_future_timestamp_secs) {
+			// clients would refuse legitimate updates with lower timestamps after applying it
+			log_info!(self.logger, "Rejecting update for channel {} direction {} with timestamp {}, which is more than {}s in the future", channel_direction.0, channel_direction.1, msg.contents.timestamp, self.max_future_timestamp_secs);
+			self.counter.write().expect("gossip counter lock poisoned").future_timestamped_updates += 1;
+			return Err(LightningError { err: "channel update timestamp is too far in the future".to_string(), action: ErrorAction::IgnoreError });
+		}
+		if self.rate_limiter.is_limited(channel_direction, received_at) {
+			self.counter.write().expect("gossip counter lock poisoned").rate_limited_updates += 1;
+			return Ok(false);
+		}
+		if self.verifier.park_update(msg) {
+			return Ok(false);
+		}
+		let previous_fees = self.directional_fees(msg);
+		let res = match self.native_router.handle_channel_update(msg) {
+			Ok(res) => res,
+			Err(error) => {
+				let early_updates = self.early_updates.as_ref().filter(|_| hold_if_early);
+				if let Some(early_updates) = early_updates {
+					let is_channel_known = self.native_router.network_graph().read_only().channel(msg.contents.short_channel_id).is_some();
+					if !is_channel_known && early_updates.hold(msg.clone(), received_at) {
+						return Ok(false);
+					}
+				}
+				return Err(error);
+			},
+		};
+		// only updates the graph accepted count, lest forged ones use up a channel's budget
+		self.rate_limiter.record(channel_direction, received_at);
+		// a channel's first update in a direction sets its fees rather than changing them
+		if previous_fees.map_or(false, |fees| fees != (msg.contents.fee_base_msat, msg.contents.fee_proportional_millionths)) {
+			self.change_tracker.record_policy_change(msg.contents.short_channel_id);
+		}
+		self.new_channel_update(msg.clone(), received_at);
+		Ok(self.should_relay(res, msg))
+	}
+
+	/// The base fee and fee rate the graph knows for the update's channel direction, if any
+	fn directional_fees(&self, msg: &ChannelUpdate) -> Option<(u32, u32)> {
+		let read_only_graph = self.native_router.network_graph().read_only();
+		let channel = read_only_graph.channel(msg.contents.short_channel_id)?;
+		let direction = if msg.contents.flags & 1 == 0 { channel.one_to_two.as_ref() } else { channel.two_to_one.as_ref() };
+		direction.map(|direction| (direction.fees.base_msat, direction.fees.proportional_millionths))
+	}
+
+	/// Hand a message to the batcher, unless it has already been persisted
+	fn forward(&self, gossip_message: GossipMessage, received_at: Instant) {
+		let message_id = dedup::message_id(&gossip_message);
+		if self.deduplication_cache.check_and_insert(&message_id) {
+			return;
+		}
+		let shared_deduplication_cache = match &self.shared_deduplication_cache {
+			Some(shared_deduplication_cache) => Arc::clone(shared_deduplication_cache),
+			None => {
+				self.batcher.push(gossip_message, received_at);
+				return;
+			},
+		};
+		// Redis is awaited off the gossip handler, so messages reach the batcher in the order their
+		// checks complete, which is fine as the gossip tables don't reference each other. While
+		// Redis is unavailable, the local cache's verdict stands.
+		let batcher = Arc::clone(&self.batcher);
+		tokio::spawn(async move {
+			if shared_deduplication_cache.check_and_insert(&message_id).await != Some(true) {
+				batcher.push(gossip_message, received_at);
+			}
+		});
+	}
+
+	fn new_channel_announcement(&self, msg: ChannelAnnouncement, received_at: Instant) {
 		{
-			let mut counter = self.counter.write().unwrap();
+			let mut counter = self.counter.write().expect("gossip counter lock poisoned");
 			counter.channel_announcements += 1;
+			counter.record_receipt();
 		}
+		self.change_tracker.record_new_channel();
 
 		let gossip_message = GossipMessage::ChannelAnnouncement(msg, None);
-		if let Err(err) = self.sender.try_send(gossip_message) {
-			let gossip_message = match err { TrySendError::Full(msg)|TrySendError::Closed(msg) => msg };
-			tokio::task::block_in_place(move || { tokio::runtime::Handle::current().block_on(async move {
-				self.sender.send(gossip_message).await.unwrap();
-			})});
-		}
+		self.forward(gossip_message, received_at);
 	}
 
-	fn new_node_announcement(&self, msg: NodeAnnouncement) {
+	fn new_node_announcement(&self, msg: NodeAnnouncement, received_at: Instant) {
 		{
-			let mut counter = self.counter.write().unwrap();
+			let mut counter = self.counter.write().expect("gossip counter lock poisoned");
 			counter.node_announcements += 1;
+			counter.record_receipt();
 		}
 
 		let gossip_message = GossipMessage::NodeAnnouncement(msg, None);
-		if let Err(err) = self.sender.try_send(gossip_message) {
-			let gossip_message = match err { TrySendError::Full(msg)|TrySendError::Closed(msg) => msg };
-			tokio::task::block_in_place(move || { tokio::runtime::Handle::current().block_on(async move {
-				self.sender.send(gossip_message).await.unwrap();
-			})});
+		self.forward(gossip_message, received_at);
+	}
+
+	/// Connect to a configured peer at the address it now advertises from now on, and remember
+	/// that address across restarts
+	fn learn_peer_address(&self, pubkey: PublicKey, address: SocketAddr) {
+		let pubkey_hex = pubkey.serialize().to_lower_hex_string();
+		log_info!(self.logger, "Peer {} now advertises {}, which will be tried first when reconnecting", pubkey_hex, address);
+		let logger = self.logger.clone();
+		tokio::spawn(async move {
+			let result = async {
+				let client = crate::connect_to_db().await?;
+				peer_registry::persist_learned_address(&client, &Tables::from_config(), &pubkey, address).await
+			}.await;
+			if let Err(error) = result {
+				log_warn!(logger, "Failed to persist the address of peer {}: {}", pubkey_hex, error);
+			}
+		});
+	}
+
+	/// Record a channel announcement claiming different nodes than the known one, once its
+	/// signatures check out, lest forgeries flood the logs and the database. The native router then
+	/// checks the funding output against the new announcement's keys as usual, and only replaces
+	/// the known announcement if they match.
+	fn record_conflicting_announcement(&self, msg: &ChannelAnnouncement, known_nodes: (NodeId, NodeId)) {
+		let validation = self.validation_pool.validate_async(GossipMessage::ChannelAnnouncement(msg.clone(), None));
+		let announcement = msg.clone();
+		let counter = Arc::clone(&self.counter);
+		let logger = self.logger.clone();
+		tokio::spawn(async move {
+			if let ValidationResult::Invalid(_) = validation.await {
+				return;
+			}
+			let contents = &announcement.contents;
+			// the routing message handler interface doesn't tell which peer sent a message
+			log_warn!(logger, "Channel {} is known between nodes {} and {}, but was announced between {} and {}, which is only accepted if its funding output pays to the new keys",
+				contents.short_channel_id, known_nodes.0, known_nodes.1, contents.node_id_1, contents.node_id_2);
+			counter.write().expect("gossip counter lock poisoned").conflicting_announcements += 1;
+
+			let reason = format!("conflicts with the known announcement between {} and {}", known_nodes.0, known_nodes.1);
+			let result = async {
+				let client = crate::connect_to_db().await?;
+				persistence::insert_dead_letter(&client, &Tables::from_config(), &GossipMessage::ChannelAnnouncement(announcement.clone(), None), &reason).await
+			}.await;
+			if let Err(error) = result {
+				log_warn!(logger, "Failed to record the conflicting announcement for channel {}: {}", announcement.contents.short_channel_id, error);
+			}
+		});
+	}
+
+	/// The nodes of the known channel an announcement claims different nodes for, if any
+	fn conflicting_nodes(&self, msg: &ChannelAnnouncement) -> Option<(NodeId, NodeId)> {
+		let read_only_graph = self.native_router.network_graph().read_only();
+		let channel = read_only_graph.channel(msg.contents.short_channel_id)?;
+		if (channel.node_one, channel.node_two) == (msg.contents.node_id_1, msg.contents.node_id_2) {
+			return None;
 		}
+		Some((channel.node_one, channel.node_two))
 	}
 
-	fn new_channel_update(&self, msg: ChannelUpdate) {
-		self.counter.write().unwrap().channel_updates += 1;
-		let gossip_message = GossipMessage::ChannelUpdate(msg, None);
+	/// Whether a message the native router would have relayed fits the relay budget, which is
+	/// charged for sending it to every connected peer. Messages beyond the budget are dropped from
+	/// relaying rather than queued, after having been processed and persisted all the same.
+	fn should_relay<M: Writeable>(&self, is_relayable: bool, msg: &M) -> bool {
+		let relay_budget = match (is_relayable, self.relay_budget.as_ref()) {
+			(false, _) => return false,
+			(true, None) => return true,
+			(true, Some(relay_budget)) => relay_budget,
+		};
+		let connected_peers = self.counter.read().expect("gossip counter lock poisoned").connected_peers.max(1) as u64;
+		if relay_budget.try_consume(msg.serialized_length() as u64 * connected_peers, Instant::now()) {
+			return true;
+		}
+		self.counter.write().expect("gossip counter lock poisoned").rate_limited_relays += 1;
+		false
+	}
 
-		if let Err(err) = self.sender.try_send(gossip_message) {
-			let gossip_message = match err { TrySendError::Full(msg)|TrySendError::Closed(msg) => msg };
-			tokio::task::block_in_place(move || { tokio::runtime::Handle::current().block_on(async move {
-				self.sender.send(gossip_message).await.unwrap();
-			})});
+	fn new_channel_update(&self, msg: ChannelUpdate, received_at: Instant) {
+		{
+			let mut counter = self.counter.write().expect("gossip counter lock poisoned");
+			counter.channel_updates += 1;
+			if htlc_maximum::is_absent(msg.contents.htlc_maximum_msat) {
+				counter.channel_updates_without_htlc_max_msats += 1;
+			}
+			counter.record_receipt();
+			// gossip backfilled while catching up is old by nature, so it doesn't tell how far
+			// behind the network the server runs
+			if counter.is_caught_up {
+				counter.propagation_delays.record_update(msg.contents.timestamp, current_timestamp());
+			}
 		}
+		let gossip_message = GossipMessage::ChannelUpdate(msg, None);
+		self.forward(gossip_message, received_at);
+	}
+}
+
+impl<L: Deref + Clone + Send + Sync> fmt::Display for GossipRouter<L> where L::Target: Logger {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		fmt::Display::fmt(&self.counter_snapshot(), f)
 	}
 }
 
 impl<L: Deref + Clone + Send + Sync> MessageSendEventsProvider for GossipRouter<L> where L::Target: Logger {
 	fn get_and_clear_pending_msg_events(&self) -> Vec<MessageSendEvent> {
 		let gossip_evs = self.outbound_gossiper.get_and_clear_pending_msg_events();
+		let received_at = Instant::now();
 		for ev in gossip_evs {
 			match ev {
 				MessageSendEvent::BroadcastChannelAnnouncement { msg, .. } => {
-					self.new_channel_announcement(msg);
+					// the native router held the latest update per direction during the funding
+					// output lookup, as did the early update buffer, whose replay catches up on
+					// any others and keeps them from being counted as expired
+					let short_channel_id = msg.contents.short_channel_id;
+					self.new_channel_announcement(msg, received_at);
+					self.replay_early_updates(short_channel_id);
 				},
 				MessageSendEvent::BroadcastNodeAnnouncement { msg } => {
-					self.new_node_announcement(msg);
+					self.new_node_announcement(msg, received_at);
 				},
 				MessageSendEvent::BroadcastChannelUpdate { msg } => {
-					self.new_channel_update(msg);
+					self.new_channel_update(msg, received_at);
 				},
 				_ => { unreachable!() },
 			}
 		}
-		self.native_router.get_and_clear_pending_msg_events()
+		let mut msg_events = self.native_router.get_and_clear_pending_msg_events();
+		msg_events.append(&mut self.pending_range_queries.lock().expect("range query lock poisoned"));
+		if let Some(archival_responder) = self.archival_responder.as_ref() {
+			msg_events.extend(archival_responder.get_and_clear_pending_msg_events());
+		}
+		if let Some(resume_timestamp) = self.resume_timestamp {
+			for ev in msg_events.iter_mut() {
+				if let MessageSendEvent::SendGossipTimestampFilter { msg, .. } = ev {
+					// the filter range is unbounded, so only its start needs moving
+					msg.first_timestamp = resume_timestamp;
+				}
+			}
+		}
+		msg_events
 	}
 }
 
 impl<L: Deref + Clone + Send + Sync> RoutingMessageHandler for GossipRouter<L> where L::Target: Logger {
 	fn handle_node_announcement(&self, msg: &NodeAnnouncement) -> Result<bool, LightningError> {
+		let received_at = Instant::now();
+		self.archive(ArchivedMessageType::NodeAnnouncement, msg);
 		let res = self.native_router.handle_node_announcement(msg)?;
-		self.new_node_announcement(msg.clone());
-		Ok(res)
+		if let Ok(pubkey) = msg.contents.node_id.as_pubkey() {
+			if let Some(address) = self.address_book.observe_announcement(&pubkey, &msg.contents.addresses) {
+				self.learn_peer_address(pubkey, address);
+			}
+		}
+		self.new_node_announcement(msg.clone(), received_at);
+		Ok(self.should_relay(res, msg))
 	}
 
 	fn handle_channel_announcement(&self, msg: &ChannelAnnouncement) -> Result<bool, LightningError> {
-		let res = self.native_router.handle_channel_announcement(msg)?;
-		self.new_channel_announcement(msg.clone());
-		Ok(res)
+		let received_at = Instant::now();
+		self.archive(ArchivedMessageType::ChannelAnnouncement, msg);
+		if let Some(known_nodes) = self.conflicting_nodes(msg) {
+			self.record_conflicting_announcement(msg, known_nodes);
+		}
+		if self.verifier.is_insufficiently_confirmed(msg.contents.short_channel_id) {
+			// deferring is not a rejection, but there's no point in holding on to forgeries. The
+			// announcement is parked right away so that its updates are held back along with it,
+			// and discarded again should its signatures turn out to be invalid.
+			self.verifier.park_announcement(msg.clone());
+			let validation = self.validation_pool.validate_async(GossipMessage::ChannelAnnouncement(msg.clone(), None));
+			let verifier = Arc::clone(&self.verifier);
+			let short_channel_id = msg.contents.short_channel_id;
+			tokio::spawn(async move {
+				if let ValidationResult::Invalid(reason) = validation.await {
+					verifier.discard_parked_announcement(short_channel_id, &reason);
+				}
+			});
+			// the early updates are parked along with the announcement
+			self.replay_early_updates(short_channel_id);
+			return Ok(false);
+		}
+		let res = self.native_router.handle_channel_announcement(msg);
+		// the native router reports announcements awaiting their funding output lookup as ignored,
+		// and holds updates for them until the lookup completes
+		if let Ok(_) | Err(LightningError { action: ErrorAction::IgnoreError | ErrorAction::IgnoreAndLog(_) | ErrorAction::IgnoreDuplicateGossip, .. }) = &res {
+			self.replay_early_updates(msg.contents.short_channel_id);
+		}
+		let res = res?;
+		self.new_channel_announcement(msg.clone(), received_at);
+		Ok(self.should_relay(res, msg))
 	}
 
 	fn handle_channel_update(&self, msg: &ChannelUpdate) -> Result<bool, LightningError> {
-		let res = self.native_router.handle_channel_update(msg)?;
-		self.new_channel_update(msg.clone());
-		Ok(res)
+		self.archive(ArchivedMessageType::ChannelUpdate, msg);
+		self.process_channel_update(msg, true)
 	}
 
 	fn processing_queue_high(&self) -> bool {
@@ -154,22 +643,46 @@ impl<L: Deref + Clone + Send + Sync> RoutingMessageHandler for GossipRouter<L> w
 	}
 
 	fn peer_connected(&self, their_node_id: &PublicKey, init: &Init, inbound: bool) -> Result<(), ()> {
-		self.native_router.peer_connected(their_node_id, init, inbound)
+		self.native_router.peer_connected(their_node_id, init, inbound)?;
+		self.peer_stats.record_message(*their_node_id);
+		let is_caught_up = self.counter.read().expect("gossip counter lock poisoned").is_caught_up;
+		if !is_caught_up && init.features.supports_gossip_queries() {
+			// the replies only serve to estimate how many channels the initial sync has to go
+			self.channel_count_estimate.query_sent(*their_node_id);
+			self.pending_range_queries.lock().expect("range query lock poisoned").push(MessageSendEvent::SendChannelRangeQuery {
+				node_id: *their_node_id,
+				msg: QueryChannelRange { chain_hash: ChainHash::using_genesis_block(config::network()), first_blocknum: 0, number_of_blocks: u32::MAX },
+			});
+		}
+		Ok(())
 	}
 
 	fn handle_reply_channel_range(&self, their_node_id: &PublicKey, msg: ReplyChannelRange) -> Result<(), LightningError> {
+		self.peer_stats.record_message(*their_node_id);
+		if let Some(channel_count) = self.channel_count_estimate.record_reply(their_node_id, msg.short_channel_ids.len(), msg.sync_complete) {
+			log_debug!(self.logger, "Peer {} knows of {} channels", their_node_id, channel_count);
+		}
 		self.native_router.handle_reply_channel_range(their_node_id, msg)
 	}
 
 	fn handle_reply_short_channel_ids_end(&self, their_node_id: &PublicKey, msg: ReplyShortChannelIdsEnd) -> Result<(), LightningError> {
+		self.peer_stats.record_message(*their_node_id);
 		self.native_router.handle_reply_short_channel_ids_end(their_node_id, msg)
 	}
 
 	fn handle_query_channel_range(&self, their_node_id: &PublicKey, msg: QueryChannelRange) -> Result<(), LightningError> {
+		self.peer_stats.record_message(*their_node_id);
+		if self.archival_responder.as_ref().map_or(false, |responder| responder.answer_channel_range(their_node_id, &msg)) {
+			return Ok(());
+		}
 		self.native_router.handle_query_channel_range(their_node_id, msg)
 	}
 
 	fn handle_query_short_channel_ids(&self, their_node_id: &PublicKey, msg: QueryShortChannelIds) -> Result<(), LightningError> {
+		self.peer_stats.record_message(*their_node_id);
+		if self.archival_responder.as_ref().map_or(false, |responder| responder.answer_short_channel_ids(their_node_id, &msg)) {
+			return Ok(());
+		}
 		self.native_router.handle_query_short_channel_ids(their_node_id, msg)
 	}
 
@@ -181,3 +694,60 @@ impl<L: Deref + Clone + Send + Sync> RoutingMessageHandler for GossipRouter<L> w
 		self.native_router.provided_node_features()
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_counter_display() {
+		let mut counter = GossipCounter::new();
+		assert!(counter.to_string().ends_with("last message received at: never"));
+
+		counter.node_announcements = 1;
+		counter.channel_announcements = 2;
+		counter.channel_updates = 3;
+		counter.connected_peers = 4;
+		counter.is_caught_up = true;
+		counter.last_message_received_at = Some(1700000000);
+		assert_eq!(counter.to_string(), "catch-up status: caught up\nmessages: 6\n\tnode announcements: 1\n\tchannel announcements: 2\n\tchannel updates: 3\nconnected peers: 4\nlast message received at: 1700000000");
+		assert_eq!(format!("{:?}", counter), counter.to_string());
+	}
+
+	#[test]
+	fn test_message_rate() {
+		let mut counter = GossipCounter::new();
+		let start = Instant::now();
+		assert_eq!(counter.message_rate_hz(60), 0.0);
+		counter.sample_message_count(start);
+		assert_eq!(counter.message_rate_hz(60), 0.0);
+
+		// 600 messages over the first minute, and 60 over the second
+		counter.channel_updates = 500;
+		counter.channel_announcements = 100;
+		counter.sample_message_count(start + Duration::from_secs(60));
+		counter.node_announcements = 60;
+		counter.sample_message_count(start + Duration::from_secs(120));
+		assert_eq!(counter.message_rate_hz(60), 1.0);
+		assert_eq!(counter.message_rate_hz(120), 5.5);
+		// windows reaching beyond the first sample are averaged over the sampled time
+		assert_eq!(counter.message_rate_hz(3600), 5.5);
+
+		for i in 0..MESSAGE_RATE_SAMPLE_CAPACITY as u64 {
+			counter.sample_message_count(start + Duration::from_secs(180 + i));
+		}
+		assert_eq!(counter.message_count_samples.len(), MESSAGE_RATE_SAMPLE_CAPACITY);
+		assert_eq!(counter.message_rate_hz(3600), 0.0);
+	}
+
+	#[test]
+	fn test_future_dated_timestamps() {
+		let now = 1_700_000_000;
+		assert!(!is_future_dated(now as u32, now, 7200));
+		assert!(!is_future_dated(now as u32 + 7200, now, 7200));
+		assert!(is_future_dated(now as u32 + 7201, now, 7200));
+		// updates from 2038 onwards
+		assert!(is_future_dated(i32::MAX as u32 + 1, now, 7200));
+		assert!(!is_future_dated(u32::MAX, now, u64::MAX));
+	}
+}
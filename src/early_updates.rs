@@ -0,0 +1,146 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use lightning::ln::msgs::ChannelUpdate;
+
+/// The maximum number of updates held across all channels, beyond which further early updates are
+/// dropped as they would have been without the buffer
+const MAX_EARLY_UPDATES: usize = 10_000;
+
+#[derive(Default)]
+struct HeldUpdates {
+	/// The held updates by channel, in the order they were received
+	by_channel: HashMap<u64, Vec<(Instant, ChannelUpdate)>>,
+	/// The channels of all held updates, in the order they were received, for expiring them
+	arrival_order: VecDeque<(Instant, u64)>,
+	count: usize,
+	/// Updates expired since the count was last reported
+	unreported_expiries: usize,
+}
+
+/// Holds channel updates that arrive before their channel's announcement, which happens regularly
+/// as peers relay them independently, so that they can be processed once the announcement is.
+/// Updates whose announcement doesn't arrive within the window are expired.
+pub(crate) struct EarlyUpdateBuffer {
+	window: Duration,
+	held_updates: Mutex<HeldUpdates>,
+}
+
+impl EarlyUpdateBuffer {
+	pub(crate) fn new(window: Duration) -> Self {
+		Self { window, held_updates: Mutex::new(HeldUpdates::default()) }
+	}
+
+	/// Hold an update for a channel the graph doesn't know yet, returning whether there was room
+	pub(crate) fn hold(&self, update: ChannelUpdate, now: Instant) -> bool {
+		let mut held_updates = self.held_updates.lock().expect("early update lock poisoned");
+		Self::expire(&mut held_updates, self.window, now);
+		if held_updates.count >= MAX_EARLY_UPDATES {
+			return false;
+		}
+		let short_channel_id = update.contents.short_channel_id;
+		held_updates.by_channel.entry(short_channel_id).or_default().push((now, update));
+		held_updates.arrival_order.push_back((now, short_channel_id));
+		held_updates.count += 1;
+		true
+	}
+
+	/// Take the unexpired updates held for a channel whose announcement was just accepted, ordered
+	/// by their timestamps, as older updates would be rejected once a newer one is processed
+	pub(crate) fn take(&self, short_channel_id: u64, now: Instant) -> Vec<ChannelUpdate> {
+		let mut held_updates = self.held_updates.lock().expect("early update lock poisoned");
+		Self::expire(&mut held_updates, self.window, now);
+		let updates = match held_updates.by_channel.remove(&short_channel_id) {
+			Some(updates) => updates,
+			None => return Vec::new(),
+		};
+		held_updates.count -= updates.len();
+		// the channel's entries in the arrival order are skipped once they come up for expiry
+		let mut updates: Vec<ChannelUpdate> = updates.into_iter().map(|(_, update)| update).collect();
+		updates.sort_by_key(|update| update.contents.timestamp);
+		updates
+	}
+
+	/// Drop the updates held for longer than the window, returning how many updates expired since
+	/// the previous call, including those dropped while holding or taking others
+	pub(crate) fn expire_held_updates(&self, now: Instant) -> usize {
+		let mut held_updates = self.held_updates.lock().expect("early update lock poisoned");
+		Self::expire(&mut held_updates, self.window, now);
+		std::mem::take(&mut held_updates.unreported_expiries)
+	}
+
+	fn expire(held_updates: &mut HeldUpdates, window: Duration, now: Instant) {
+		while let Some((held_at, short_channel_id)) = held_updates.arrival_order.front().cloned() {
+			if now.saturating_duration_since(held_at) < window {
+				break;
+			}
+			held_updates.arrival_order.pop_front();
+			let channel_updates = match held_updates.by_channel.get_mut(&short_channel_id) {
+				Some(channel_updates) => channel_updates,
+				// taken already
+				None => continue,
+			};
+			// a channel's updates are held in arrival order too, so the expired one comes first
+			if channel_updates.first().map_or(false, |(first_held_at, _)| *first_held_at == held_at) {
+				channel_updates.remove(0);
+				held_updates.count -= 1;
+				held_updates.unreported_expiries += 1;
+			}
+			if channel_updates.is_empty() {
+				held_updates.by_channel.remove(&short_channel_id);
+			}
+		}
+	}
+
+	pub(crate) fn held_update_count(&self) -> usize {
+		self.held_updates.lock().expect("early update lock poisoned").count
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::tests::generate_update;
+
+	#[test]
+	fn test_replay_ordering() {
+		let buffer = EarlyUpdateBuffer::new(Duration::from_secs(60));
+		let start = Instant::now();
+		assert!(buffer.hold(generate_update(1, false, 300, 0, 0, 0, 0, 0), start));
+		assert!(buffer.hold(generate_update(2, false, 100, 0, 0, 0, 0, 0), start));
+		assert!(buffer.hold(generate_update(1, true, 200, 0, 0, 0, 0, 0), start + Duration::from_secs(1)));
+		assert!(buffer.hold(generate_update(1, false, 100, 0, 0, 0, 0, 0), start + Duration::from_secs(2)));
+
+		let replayed = buffer.take(1, start + Duration::from_secs(3));
+		let timestamps: Vec<u32> = replayed.iter().map(|update| update.contents.timestamp).collect();
+		assert_eq!(timestamps, vec![100, 200, 300]);
+		assert!(buffer.take(1, start + Duration::from_secs(3)).is_empty());
+		assert_eq!(buffer.held_update_count(), 1);
+		assert_eq!(buffer.expire_held_updates(start + Duration::from_secs(3)), 0);
+	}
+
+	#[test]
+	fn test_expiry() {
+		let buffer = EarlyUpdateBuffer::new(Duration::from_secs(60));
+		let start = Instant::now();
+		buffer.hold(generate_update(1, false, 100, 0, 0, 0, 0, 0), start);
+		buffer.hold(generate_update(2, false, 100, 0, 0, 0, 0, 0), start);
+		buffer.hold(generate_update(1, true, 100, 0, 0, 0, 0, 0), start + Duration::from_secs(30));
+		assert_eq!(buffer.take(2, start + Duration::from_secs(10)).len(), 1);
+
+		// the taken channel's update isn't counted as expired
+		assert_eq!(buffer.expire_held_updates(start + Duration::from_secs(60)), 1);
+		assert_eq!(buffer.held_update_count(), 1);
+		// updates past the window aren't replayed, even if they weren't expired yet
+		assert!(buffer.take(1, start + Duration::from_secs(90)).is_empty());
+		assert_eq!(buffer.held_update_count(), 0);
+		assert_eq!(buffer.expire_held_updates(start + Duration::from_secs(120)), 1);
+
+		// holding an update expires those past the window, too
+		buffer.hold(generate_update(3, false, 100, 0, 0, 0, 0, 0), start);
+		buffer.hold(generate_update(4, false, 100, 0, 0, 0, 0, 0), start + Duration::from_secs(60));
+		assert_eq!(buffer.held_update_count(), 1);
+		assert_eq!(buffer.expire_held_updates(start + Duration::from_secs(60)), 1);
+	}
+}
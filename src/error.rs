@@ -0,0 +1,153 @@
+use std::io;
+
+use lightning::ln::msgs::DecodeError;
+use thiserror::Error;
+
+/// The server was shut down on purpose
+pub const EXIT_CLEAN_SHUTDOWN: i32 = 0;
+/// The server stopped for a reason that restarting it may well fix
+pub const EXIT_FAILURE: i32 = 1;
+/// The configuration is invalid, so restarting the server won't help until it's fixed
+pub const EXIT_CONFIG_ERROR: i32 = 2;
+/// The database couldn't be connected to
+pub const EXIT_DATABASE_UNREACHABLE: i32 = 3;
+/// The chain backend follows a different chain than the configured network
+pub const EXIT_WRONG_CHAIN: i32 = 4;
+/// None of the configured peers could be connected to
+pub const EXIT_NO_PEERS_CONNECTED: i32 = 5;
+
+/// Everything that can cause the server to stop syncing or snapshotting gossip
+#[derive(Debug, Error)]
+pub enum ProcessorError {
+	/// A database query failed, or the connection was lost
+	#[error("{context}: {source}")]
+	Database {
+		context: String,
+		#[source]
+		source: tokio_postgres::Error,
+	},
+	/// Reading or writing snapshots, symlinks, or caches failed
+	#[error("{context}: {source}")]
+	Io {
+		context: String,
+		#[source]
+		source: io::Error,
+	},
+	/// Gossip read back from the database could not be decoded
+	#[error("{context}: {error}")]
+	Decode {
+		context: String,
+		error: DecodeError,
+	},
+	/// The environment contains an invalid setting
+	#[error("invalid configuration: {0}")]
+	Config(String),
+	/// The database was created by a newer version of the server
+	#[error("unknown schema in db: {found}, we support up to {supported}")]
+	UnknownSchema {
+		found: i32,
+		supported: i32,
+	},
+	/// The database couldn't be connected to
+	#[error("failed to connect to the database: {0}")]
+	DatabaseUnreachable(String),
+	/// The chain backend's genesis block isn't the configured network's
+	#[error("the chain backend follows the wrong chain: {0}")]
+	WrongChain(String),
+	/// A database operation took longer than allowed
+	#[error("{0} timed out")]
+	Timeout(&'static str),
+	/// None of the configured peers could be connected to
+	#[error("failed to connect to any peer")]
+	NoPeersConnected,
+	/// The gossip download stopped before the initial sync completed
+	#[error("initial gossip sync failed")]
+	SyncFailed,
+	/// The initial sync didn't catch up with gossip in time, or caught up with too small a graph
+	#[error("failed to catch up with gossip: {0}")]
+	CatchUpFailed(String),
+	/// A task the server depends on stopped receiving messages
+	#[error("the {0} channel closed")]
+	ChannelClosed(&'static str),
+	/// A snapshot file could not be parsed
+	#[error("malformed snapshot at byte {offset}: {reason}")]
+	MalformedSnapshot {
+		offset: u64,
+		reason: String,
+	},
+	/// LDK's client refused to apply a snapshot
+	#[error("{context}: {reason}")]
+	SnapshotRejected {
+		context: String,
+		reason: String,
+	},
+	/// Another instance of the server is using the same directory or database tables
+	#[error("{resource} is in use by another instance of the server ({holder})")]
+	InstanceLocked {
+		resource: String,
+		holder: String,
+	},
+	/// A supervised component stopped, and wasn't, or couldn't be, restarted
+	#[error("the {component} component stopped: {reason}")]
+	ComponentStopped {
+		component: &'static str,
+		reason: String,
+		/// The error the component failed with, unless it panicked or returned
+		#[source]
+		cause: Option<Box<ProcessorError>>,
+	},
+}
+
+impl ProcessorError {
+	/// The code the process exits with when stopping for this error, which tells whatever
+	/// orchestrates the server whether restarting it is worthwhile
+	pub fn exit_code(&self) -> i32 {
+		match self {
+			Self::Config(_) => EXIT_CONFIG_ERROR,
+			Self::DatabaseUnreachable(_) => EXIT_DATABASE_UNREACHABLE,
+			Self::WrongChain(_) => EXIT_WRONG_CHAIN,
+			Self::NoPeersConnected => EXIT_NO_PEERS_CONNECTED,
+			Self::ComponentStopped { cause: Some(cause), .. } => cause.exit_code(),
+			_ => EXIT_FAILURE,
+		}
+	}
+}
+
+/// Attach a description of what was being attempted to a lower-level error
+pub(crate) trait ErrorContext<T> {
+	fn context<C: Into<String>>(self, context: C) -> Result<T, ProcessorError>;
+}
+
+impl<T> ErrorContext<T> for Result<T, tokio_postgres::Error> {
+	fn context<C: Into<String>>(self, context: C) -> Result<T, ProcessorError> {
+		self.map_err(|source| ProcessorError::Database { context: context.into(), source })
+	}
+}
+
+impl<T> ErrorContext<T> for Result<T, io::Error> {
+	fn context<C: Into<String>>(self, context: C) -> Result<T, ProcessorError> {
+		self.map_err(|source| ProcessorError::Io { context: context.into(), source })
+	}
+}
+
+impl<T> ErrorContext<T> for Result<T, DecodeError> {
+	fn context<C: Into<String>>(self, context: C) -> Result<T, ProcessorError> {
+		self.map_err(|error| ProcessorError::Decode { context: context.into(), error })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_error_context() {
+		let result: Result<(), io::Error> = Err(io::Error::new(io::ErrorKind::NotFound, "no such file"));
+		let error = result.context("Failed to read snapshot").unwrap_err();
+		assert!(matches!(error, ProcessorError::Io { .. }));
+		assert_eq!(error.to_string(), "Failed to read snapshot: no such file");
+
+		let result: Result<(), DecodeError> = Err(DecodeError::ShortRead);
+		assert_eq!(result.context("Failed to decode announcement").unwrap_err().to_string(), "Failed to decode announcement: Packet extended beyond the provided bytes");
+	}
+}
@@ -0,0 +1,335 @@
+use std::collections::HashMap;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use lightning::{log_info, log_warn};
+use lightning::routing::gossip::NetworkGraph;
+use lightning::util::logger::Logger;
+use tokio::sync::{OnceCell, Semaphore};
+
+use crate::clock::SnapshotClock;
+use crate::config;
+use crate::error::ProcessorError;
+
+/// Requested timestamps are rounded down to this granularity, so that clients syncing at around
+/// the same time can share a cached delta
+const EXACT_DELTA_GRANULARITY: u32 = 300; // five minutes
+
+/// Cached deltas go stale as new gossip comes in, so they're only served for this long
+const EXACT_DELTA_CACHE_TTL: Duration = Duration::from_secs(300);
+
+const EXACT_DELTA_CACHE_CAPACITY: usize = 64;
+
+/// How a delta request should be answered
+pub enum DeltaResponse {
+	/// A delta computed for (a rounded-down version of) the requested timestamp
+	Exact(Arc<Vec<u8>>),
+	/// The pre-generated snapshot at this path, relative to the cache directory, should be served
+	Bucketed(String),
+}
+
+/// Counters for monitoring how often deltas are served exactly, and how long computing them takes
+#[derive(Clone, Debug, Default)]
+pub struct ExactDeltaMetrics {
+	pub exact_served: u64,
+	pub bucketed_served: u64,
+	/// The number of exact delta requests answered by a computation another request started,
+	/// including ones still in progress when the request came in
+	pub cache_hits: u64,
+	/// The number of requests that fell back to a bucketed snapshot because too many deltas were
+	/// being computed already
+	pub concurrency_fallbacks: u64,
+	/// The number of requests that fell back to a bucketed snapshot because computing their delta
+	/// exceeded the time budget
+	pub budget_fallbacks: u64,
+	pub computation_count: u64,
+	pub computation_time_total: Duration,
+	pub computation_time_max: Duration,
+}
+
+impl ExactDeltaMetrics {
+	/// Serialize the metrics in the Prometheus text exposition format
+	pub fn to_prometheus(&self) -> String {
+		let mut output = String::new();
+		output.push_str("# HELP rgs_delta_requests_total Delta requests by how they were served\n");
+		output.push_str("# TYPE rgs_delta_requests_total counter\n");
+		output.push_str(&format!("rgs_delta_requests_total{{mode=\"exact\"}} {}\n", self.exact_served));
+		output.push_str(&format!("rgs_delta_requests_total{{mode=\"bucketed\"}} {}\n", self.bucketed_served));
+		output.push_str("# HELP rgs_exact_delta_fallbacks_total Exact delta requests served from a bucket instead\n");
+		output.push_str("# TYPE rgs_exact_delta_fallbacks_total counter\n");
+		output.push_str(&format!("rgs_exact_delta_fallbacks_total{{reason=\"concurrency\"}} {}\n", self.concurrency_fallbacks));
+		output.push_str(&format!("rgs_exact_delta_fallbacks_total{{reason=\"budget\"}} {}\n", self.budget_fallbacks));
+		output.push_str("# HELP rgs_exact_delta_cache_hits_total Exact delta requests served from the cache\n");
+		output.push_str("# TYPE rgs_exact_delta_cache_hits_total counter\n");
+		output.push_str(&format!("rgs_exact_delta_cache_hits_total {}\n", self.cache_hits));
+		output.push_str("# HELP rgs_exact_delta_computation_seconds Time spent computing exact deltas on demand\n");
+		output.push_str("# TYPE rgs_exact_delta_computation_seconds summary\n");
+		output.push_str(&format!("rgs_exact_delta_computation_seconds_sum {}\n", self.computation_time_total.as_secs_f64()));
+		output.push_str(&format!("rgs_exact_delta_computation_seconds_count {}\n", self.computation_count));
+		output.push_str("# HELP rgs_exact_delta_computation_seconds_max Longest exact delta computation\n");
+		output.push_str("# TYPE rgs_exact_delta_computation_seconds_max gauge\n");
+		output.push_str(&format!("rgs_exact_delta_computation_seconds_max {}\n", self.computation_time_max.as_secs_f64()));
+		output
+	}
+}
+
+/// Why a delta couldn't be computed exactly, and the bucketed snapshot had to be served instead
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Fallback {
+	Concurrency,
+	Budget,
+	Failure,
+}
+
+/// The outcome of computing a delta, shared by all requests waiting for it
+type DeltaCell = Arc<OnceCell<Result<Arc<Vec<u8>>, Fallback>>>;
+
+struct CachedDelta {
+	cell: DeltaCell,
+	created_at: Instant,
+	last_used: u64,
+}
+
+/// A small least-recently-used cache of serialized deltas, keyed by rounded timestamp and
+/// serialization version.
+///
+/// Entries are created as soon as a delta is first requested, and each computes its delta at most
+/// once. Requests for a delta that is still being computed wait for that computation rather than
+/// starting their own, while deltas for different keys are computed concurrently, as the cache's
+/// lock is only held for looking up entries.
+struct DeltaCache {
+	entries: HashMap<(u32, u8), CachedDelta>,
+	use_counter: u64,
+}
+
+impl DeltaCache {
+	fn new() -> Self {
+		Self { entries: HashMap::with_capacity(EXACT_DELTA_CACHE_CAPACITY), use_counter: 0 }
+	}
+
+	/// The entry for a key, which is created if there's none yet, or if the one there went stale
+	fn entry(&mut self, key: (u32, u8)) -> DeltaCell {
+		self.use_counter += 1;
+		let use_counter = self.use_counter;
+		if let Some(entry) = self.entries.get_mut(&key) {
+			// deltas still being computed can't be stale yet
+			if !entry.cell.initialized() || entry.created_at.elapsed() < EXACT_DELTA_CACHE_TTL {
+				entry.last_used = use_counter;
+				return Arc::clone(&entry.cell);
+			}
+			self.entries.remove(&key);
+		}
+
+		if self.entries.len() >= EXACT_DELTA_CACHE_CAPACITY {
+			let least_recently_used = self.entries.iter().min_by_key(|(_, entry)| entry.last_used).map(|(key, _)| *key);
+			if let Some(evicted_key) = least_recently_used {
+				// requests waiting for an evicted computation hold on to its cell regardless
+				self.entries.remove(&evicted_key);
+			}
+		}
+		let cell = Arc::new(OnceCell::new());
+		self.entries.insert(key, CachedDelta { cell: Arc::clone(&cell), created_at: Instant::now(), last_used: use_counter });
+		cell
+	}
+
+	/// Forget a computation that fell back, so that the next request for its key tries again,
+	/// unless the entry was replaced in the meantime
+	fn remove(&mut self, key: (u32, u8), cell: &DeltaCell) {
+		if self.entries.get(&key).map_or(false, |entry| Arc::ptr_eq(&entry.cell, cell)) {
+			self.entries.remove(&key);
+		}
+	}
+}
+
+/// Answers delta requests for arbitrary timestamps, computing deltas on demand for timestamps
+/// recent enough that the smallest pre-generated snapshot would be wastefully large.
+///
+/// Computation is limited in concurrency and time. Whenever either limit is hit, or exact mode is
+/// disabled, the request should be answered with the bucketed snapshot instead.
+pub struct ExactDeltaServer<L: Deref + Clone> where L::Target: Logger {
+	network_graph: Arc<NetworkGraph<L>>,
+	is_enabled: bool,
+	time_budget: Duration,
+	computation_limiter: Semaphore,
+	cache: Mutex<DeltaCache>,
+	metrics: Mutex<ExactDeltaMetrics>,
+	logger: L,
+}
+
+impl<L: Deref + Clone> ExactDeltaServer<L> where L::Target: Logger {
+	pub(crate) fn new(network_graph: Arc<NetworkGraph<L>>, logger: L) -> Self {
+		Self {
+			network_graph,
+			is_enabled: config::exact_delta_enabled(),
+			time_budget: config::exact_delta_time_budget(),
+			computation_limiter: Semaphore::new(config::exact_delta_concurrency()),
+			cache: Mutex::new(DeltaCache::new()),
+			metrics: Mutex::new(ExactDeltaMetrics::default()),
+			logger,
+		}
+	}
+
+	pub fn metrics(&self) -> ExactDeltaMetrics {
+		self.metrics.lock().expect("exact delta metrics lock poisoned").clone()
+	}
+
+	/// Determine the response to a client whose last sync happened at `last_sync_timestamp`
+	pub async fn serve(&self, last_sync_timestamp: u32, serialization_version: u8) -> DeltaResponse {
+		let current_time = SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch").as_secs() as u32;
+		let is_within_smallest_bucket = last_sync_timestamp > 0 && last_sync_timestamp <= current_time
+			&& current_time - last_sync_timestamp < config::snapshot_generation_interval();
+		if !self.is_enabled || !is_within_smallest_bucket {
+			return self.serve_bucketed(last_sync_timestamp, serialization_version);
+		}
+
+		let rounded_timestamp = last_sync_timestamp - last_sync_timestamp % EXACT_DELTA_GRANULARITY;
+		let cache_key = (rounded_timestamp, serialization_version);
+		let cell = self.cache.lock().expect("exact delta cache lock poisoned").entry(cache_key);
+		let mut is_computed_here = false;
+		let is_computed_here_ref = &mut is_computed_here;
+		let outcome = cell.get_or_init(|| async move {
+			*is_computed_here_ref = true;
+			self.compute_delta(rounded_timestamp, serialization_version).await
+		}).await.clone();
+
+		let data = match outcome {
+			Ok(data) => data,
+			Err(fallback) => {
+				self.cache.lock().expect("exact delta cache lock poisoned").remove(cache_key, &cell);
+				{
+					let mut metrics = self.metrics.lock().expect("exact delta metrics lock poisoned");
+					match fallback {
+						Fallback::Concurrency => metrics.concurrency_fallbacks += 1,
+						Fallback::Budget => metrics.budget_fallbacks += 1,
+						Fallback::Failure => {},
+					}
+				}
+				return self.serve_bucketed(last_sync_timestamp, serialization_version);
+			}
+		};
+		let mut metrics = self.metrics.lock().expect("exact delta metrics lock poisoned");
+		if !is_computed_here {
+			metrics.cache_hits += 1;
+		}
+		metrics.exact_served += 1;
+		DeltaResponse::Exact(data)
+	}
+
+	async fn compute_delta(&self, rounded_timestamp: u32, serialization_version: u8) -> Result<Arc<Vec<u8>>, Fallback> {
+		let _permit = self.computation_limiter.try_acquire().map_err(|_| Fallback::Concurrency)?;
+
+		let start = Instant::now();
+		let computation = async {
+			let delta = crate::calculate_delta(Arc::clone(&self.network_graph), rounded_timestamp, SnapshotClock::wall_clock(), self.logger.clone()).await?;
+			Ok::<_, ProcessorError>(crate::serialize_delta(&delta, serialization_version, self.logger.clone()).data)
+		};
+		let data = match tokio::time::timeout(self.time_budget, computation).await {
+			Ok(Ok(data)) => Arc::new(data),
+			Ok(Err(error)) => {
+				log_warn!(self.logger, "Exact delta computation for {} failed: {}", rounded_timestamp, error);
+				return Err(Fallback::Failure);
+			},
+			Err(_) => {
+				log_warn!(self.logger, "Exact delta computation for {} exceeded the time budget of {:?}", rounded_timestamp, self.time_budget);
+				return Err(Fallback::Budget);
+			}
+		};
+		let computation_time = start.elapsed();
+		log_info!(self.logger, "Computed exact delta for {} in {:?}", rounded_timestamp, computation_time);
+
+		let mut metrics = self.metrics.lock().expect("exact delta metrics lock poisoned");
+		metrics.computation_count += 1;
+		metrics.computation_time_total += computation_time;
+		metrics.computation_time_max = metrics.computation_time_max.max(computation_time);
+		Ok(data)
+	}
+
+	fn serve_bucketed(&self, last_sync_timestamp: u32, serialization_version: u8) -> DeltaResponse {
+		self.metrics.lock().expect("exact delta metrics lock poisoned").bucketed_served += 1;
+		DeltaResponse::Bucketed(bucketed_snapshot_path(last_sync_timestamp, serialization_version))
+	}
+}
+
+/// The symlink, relative to the cache directory, that serves the pre-generated snapshot for a
+/// given last sync timestamp
+fn bucketed_snapshot_path(last_sync_timestamp: u32, serialization_version: u8) -> String {
+	let canonical_timestamp = last_sync_timestamp - last_sync_timestamp % config::SYMLINK_GRANULARITY_INTERVAL;
+	let version_suffix = if serialization_version >= 2 { "/v2" } else { "" };
+	format!("symlinks{}/{}.bin", version_suffix, canonical_timestamp)
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use super::*;
+
+	#[test]
+	fn test_delta_cache_eviction() {
+		let mut cache = DeltaCache::new();
+		for timestamp in 0..EXACT_DELTA_CACHE_CAPACITY as u32 {
+			cache.entry((timestamp, 2)).set(Ok(Arc::new(vec![timestamp as u8]))).unwrap();
+		}
+		// touch the oldest entry, so that the second-oldest one gets evicted
+		assert!(cache.entry((0, 2)).initialized());
+		cache.entry((1000, 2)).set(Ok(Arc::new(vec![]))).unwrap();
+		assert_eq!(cache.entries.len(), EXACT_DELTA_CACHE_CAPACITY);
+		assert!(cache.entry((0, 2)).initialized());
+		assert!(!cache.entry((1, 2)).initialized());
+		assert!(cache.entry((1000, 2)).initialized());
+		assert!(!cache.entry((1000, 1)).initialized());
+	}
+
+	#[test]
+	fn test_delta_cache_fallback_removal() {
+		let mut cache = DeltaCache::new();
+		let failed_cell = cache.entry((0, 2));
+		failed_cell.set(Err(Fallback::Budget)).unwrap();
+		cache.remove((0, 2), &failed_cell);
+		let retry_cell = cache.entry((0, 2));
+		assert!(!retry_cell.initialized());
+		// a late removal of the failed computation leaves the retry alone
+		cache.remove((0, 2), &failed_cell);
+		assert!(Arc::ptr_eq(&retry_cell, &cache.entry((0, 2))));
+	}
+
+	#[tokio::test]
+	async fn test_concurrent_delta_requests() {
+		const COMPUTATION_TIME: Duration = Duration::from_millis(200);
+		let cache = Arc::new(Mutex::new(DeltaCache::new()));
+		let computation_count = Arc::new(AtomicUsize::new(0));
+
+		// 100 clients requesting 10 different timestamps
+		let start = Instant::now();
+		let requests = (0..100u32).map(|client| {
+			let cache = Arc::clone(&cache);
+			let computation_count = Arc::clone(&computation_count);
+			tokio::spawn(async move {
+				let timestamp = client % 10 * EXACT_DELTA_GRANULARITY;
+				let cell = cache.lock().unwrap().entry((timestamp, 2));
+				let outcome = cell.get_or_init(|| async move {
+					computation_count.fetch_add(1, Ordering::SeqCst);
+					tokio::time::sleep(COMPUTATION_TIME).await;
+					Ok(Arc::new(timestamp.to_be_bytes().to_vec()))
+				}).await.clone();
+				assert_eq!(*outcome.unwrap(), timestamp.to_be_bytes());
+			})
+		}).collect::<Vec<_>>();
+		for request in requests {
+			request.await.unwrap();
+		}
+
+		// each timestamp is computed once, and the different timestamps at the same time rather than
+		// one after the other, as they would be behind a lock held throughout the computation
+		assert_eq!(computation_count.load(Ordering::SeqCst), 10);
+		assert!(start.elapsed() < COMPUTATION_TIME * 5);
+	}
+
+	#[test]
+	fn test_bucketed_snapshot_path() {
+		assert_eq!(bucketed_snapshot_path(1700000000, 1), "symlinks/1699995600.bin");
+		assert_eq!(bucketed_snapshot_path(1700000000, 2), "symlinks/v2/1699995600.bin");
+		assert_eq!(bucketed_snapshot_path(0, 2), "symlinks/v2/0.bin");
+	}
+}
@@ -0,0 +1,124 @@
+use tokio_postgres::GenericClient;
+
+use crate::tables::Tables;
+
+/// The upper bounds of the histogram's buckets are 0 and the powers of two up to this one, followed
+/// by a bucket for anything larger
+const MAX_BUCKET_EXPONENT: u32 = 24;
+const BUCKET_COUNT: usize = MAX_BUCKET_EXPONENT as usize + 3;
+
+/// The distribution of the fees charged by channel directions active within a time range, with
+/// each direction counted once, at the latest fees it advertised within the range
+#[derive(Clone, Debug, PartialEq)]
+pub struct FeeHistogram {
+	pub since: u64,
+	pub until: u64,
+	pub direction_count: u64,
+	/// Directions by base fee, with bucket `i` counting fees of at most `Self::bucket_bound(i)` msat
+	pub base_fee_msat_buckets: [u64; BUCKET_COUNT],
+	/// Directions by proportional fee, in the same buckets as the base fees
+	pub fee_rate_ppm_buckets: [u64; BUCKET_COUNT],
+}
+
+/// The first bucket whose bound is at least the fee
+fn bucket_index(fee: u32) -> usize {
+	if fee == 0 {
+		return 0;
+	}
+	// the exponent of the smallest power of two that is at least the fee, plus one for the 0 bucket
+	let bucket_index = (32 - (fee - 1).leading_zeros()) as usize + 1;
+	bucket_index.min(BUCKET_COUNT - 1)
+}
+
+impl FeeHistogram {
+	/// The inclusive upper bound of a bucket, which is `None` for the last one
+	pub fn bucket_bound(bucket_index: usize) -> Option<u32> {
+		match bucket_index {
+			0 => Some(0),
+			_ if bucket_index < BUCKET_COUNT - 1 => Some(1 << (bucket_index - 1)),
+			_ => None,
+		}
+	}
+
+	fn new(since: u64, until: u64) -> Self {
+		Self { since, until, direction_count: 0, base_fee_msat_buckets: [0; BUCKET_COUNT], fee_rate_ppm_buckets: [0; BUCKET_COUNT] }
+	}
+
+	fn insert(&mut self, base_fee_msat: u32, fee_rate_ppm: u32) {
+		self.direction_count += 1;
+		self.base_fee_msat_buckets[bucket_index(base_fee_msat)] += 1;
+		self.fee_rate_ppm_buckets[bucket_index(fee_rate_ppm)] += 1;
+	}
+
+	fn buckets_to_json(buckets: &[u64; BUCKET_COUNT]) -> String {
+		let buckets: Vec<String> = buckets.iter().enumerate()
+			.map(|(bucket_index, count)| format!("\"{}\":{}", Self::bucket_bound(bucket_index).map_or("+Inf".to_string(), |bound| bound.to_string()), count))
+			.collect();
+		format!("{{{}}}", buckets.join(","))
+	}
+
+	/// Serialize the histogram for `GET /stats/fee-histogram`, with each bucket keyed by its
+	/// inclusive upper bound
+	pub fn to_json(&self) -> String {
+		format!(
+			"{{\"since\":{},\"until\":{},\"direction_count\":{},\"fee_base_msat\":{},\"fee_proportional_millionths\":{}}}",
+			self.since,
+			self.until,
+			self.direction_count,
+			Self::buckets_to_json(&self.base_fee_msat_buckets),
+			Self::buckets_to_json(&self.fee_rate_ppm_buckets),
+		)
+	}
+}
+
+/// Compute the fee histogram of the channel directions whose latest update as of `until` was
+/// issued at or after `since`, and doesn't disable the direction. Both are unix timestamps.
+pub(crate) async fn fetch_fee_histogram<C: GenericClient>(client: &C, tables: &Tables, since: u64, until: u64) -> Result<FeeHistogram, tokio_postgres::Error> {
+	let rows = client.query(&format!("
+		SELECT fee_base_msat, fee_proportional_millionths FROM (
+			SELECT DISTINCT ON (short_channel_id, direction) fee_base_msat, fee_proportional_millionths, timestamp, disable
+			FROM {}
+			WHERE timestamp <= $2
+			ORDER BY short_channel_id ASC, direction ASC, timestamp DESC
+		) AS latest_updates
+		WHERE timestamp >= $1 AND NOT disable
+		", tables.channel_updates()), &[&(since as i64), &(until as i64)]).await?;
+
+	let mut histogram = FeeHistogram::new(since, until);
+	for row in rows {
+		// fees are persisted as the signed reinterpretation of their u32
+		histogram.insert(row.get::<_, i32>(0) as u32, row.get::<_, i32>(1) as u32);
+	}
+	Ok(histogram)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_bucket_boundaries() {
+		assert_eq!(bucket_index(0), 0);
+		assert_eq!(bucket_index(1), 1);
+		assert_eq!(bucket_index(2), 2);
+		assert_eq!(bucket_index(3), 3);
+		assert_eq!(bucket_index(1000), 11);
+		assert_eq!(FeeHistogram::bucket_bound(11), Some(1024));
+		assert_eq!(bucket_index(1 << 24), BUCKET_COUNT - 2);
+		assert_eq!(bucket_index((1 << 24) + 1), BUCKET_COUNT - 1);
+		assert_eq!(bucket_index(u32::MAX), BUCKET_COUNT - 1);
+		assert_eq!(FeeHistogram::bucket_bound(BUCKET_COUNT - 2), Some(1 << 24));
+		assert_eq!(FeeHistogram::bucket_bound(BUCKET_COUNT - 1), None);
+	}
+
+	#[test]
+	fn test_histogram_json() {
+		let mut histogram = FeeHistogram::new(100, 200);
+		histogram.insert(1000, 0);
+		histogram.insert(0, 1 << 30);
+		let json = histogram.to_json();
+		assert!(json.starts_with("{\"since\":100,\"until\":200,\"direction_count\":2,\"fee_base_msat\":{\"0\":1,\"1\":0,\"2\":0,\"4\":0,"));
+		assert!(json.contains("\"512\":0,\"1024\":1,"));
+		assert!(json.ends_with("\"16777216\":0,\"+Inf\":1}}"));
+	}
+}
@@ -0,0 +1,258 @@
+use std::collections::BTreeMap;
+use std::ops::Deref;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lightning::log_warn;
+use lightning::util::logger::Logger;
+use tokio_postgres::GenericClient;
+
+use crate::config;
+use crate::error::ErrorContext;
+use crate::role::ServerRole;
+use crate::tables::Tables;
+use crate::webhook;
+
+/// How often the newest timestamps in the database are queried, so that scraping the freshness
+/// metrics never hits the database
+const REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The newest gossip timestamp is only sought among the channel updates seen within this long of
+/// the latest one, which keeps the query on the index of the seen column
+const GOSSIP_TIMESTAMP_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// The most recently published snapshot of a scope
+#[derive(Clone, Debug, PartialEq)]
+pub struct SnapshotFreshness {
+	/// The snapshot's scope in seconds, or `u64::MAX` for the full snapshot
+	pub scope: u64,
+	/// The timestamp clients applying the snapshot are told to sync from next time
+	pub reference_timestamp: u64,
+	/// The newest seen timestamp of the gossip included in the snapshot
+	pub latest_seen_timestamp: u64,
+}
+
+/// How fresh the gossip in the database and the published snapshots are, as of `now`
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FreshnessReport {
+	pub now: u64,
+	/// The newest timestamp of any channel update in the database
+	pub max_gossip_timestamp: Option<u64>,
+	/// When the newest channel announcement or update in the database was received
+	pub max_seen_timestamp: Option<u64>,
+	/// When the database was last queried for the above
+	pub refreshed_at: Option<u64>,
+	/// The snapshots published since startup, by ascending scope
+	pub snapshots: Vec<SnapshotFreshness>,
+	/// Standbys don't publish snapshots until they're promoted
+	pub role: ServerRole,
+}
+
+impl FreshnessReport {
+	pub fn gossip_lag(&self) -> Option<u64> {
+		self.max_gossip_timestamp.map(|timestamp| self.now.saturating_sub(timestamp))
+	}
+
+	pub fn seen_lag(&self) -> Option<u64> {
+		self.max_seen_timestamp.map(|timestamp| self.now.saturating_sub(timestamp))
+	}
+
+	/// How far the newest published snapshot's reference timestamp is behind, which keeps growing
+	/// if snapshot publication stops
+	pub fn publication_lag(&self) -> Option<u64> {
+		self.snapshots.iter().map(|snapshot| snapshot.reference_timestamp).max()
+			.map(|reference_timestamp| self.now.saturating_sub(reference_timestamp))
+	}
+
+	/// Serialize the timestamps and lags as Prometheus gauges in the text exposition format
+	pub fn to_prometheus(&self) -> String {
+		let mut output = String::new();
+		let database_gauges = [
+			("rgs_max_gossip_timestamp_seconds", "Newest channel update timestamp in the database", self.max_gossip_timestamp),
+			("rgs_gossip_lag_seconds", "Age of the newest channel update timestamp in the database", self.gossip_lag()),
+			("rgs_max_seen_timestamp_seconds", "When the newest gossip in the database was received", self.max_seen_timestamp),
+			("rgs_seen_lag_seconds", "Time since the newest gossip in the database was received", self.seen_lag()),
+		];
+		for (name, help, value) in database_gauges {
+			if let Some(value) = value {
+				output.push_str(&format!("# HELP {} {}\n# TYPE {} gauge\n{} {}\n", name, help, name, name, value));
+			}
+		}
+		if self.snapshots.is_empty() {
+			return output;
+		}
+		let snapshot_gauges: [(&str, &str, fn(&SnapshotFreshness, u64) -> u64); 4] = [
+			("rgs_snapshot_reference_timestamp_seconds", "Reference timestamp of the most recently published snapshot", |snapshot, _| snapshot.reference_timestamp),
+			("rgs_snapshot_reference_lag_seconds", "Age of the reference timestamp of the most recently published snapshot", |snapshot, now| now.saturating_sub(snapshot.reference_timestamp)),
+			("rgs_snapshot_latest_seen_timestamp_seconds", "When the newest gossip in the most recently published snapshot was received", |snapshot, _| snapshot.latest_seen_timestamp),
+			("rgs_snapshot_latest_seen_lag_seconds", "Time since the newest gossip in the most recently published snapshot was received", |snapshot, now| now.saturating_sub(snapshot.latest_seen_timestamp)),
+		];
+		for (name, help, value) in snapshot_gauges {
+			output.push_str(&format!("# HELP {} {}\n# TYPE {} gauge\n", name, help, name));
+			for snapshot in &self.snapshots {
+				let scope = if snapshot.scope == u64::MAX { "full".to_string() } else { snapshot.scope.to_string() };
+				output.push_str(&format!("{}{{scope=\"{}\"}} {}\n", name, scope, value(snapshot, self.now)));
+			}
+		}
+		output
+	}
+}
+
+#[derive(Default)]
+struct FreshnessState {
+	max_gossip_timestamp: Option<u64>,
+	max_seen_timestamp: Option<u64>,
+	refreshed_at: Option<u64>,
+	snapshots: BTreeMap<u64, SnapshotFreshness>,
+	/// Whether the stall webhook was notified of the current publication stall
+	is_publication_stalled: bool,
+}
+
+/// Caches the newest timestamps of the gossip in the database and of the published snapshots
+pub(crate) struct FreshnessMonitor {
+	state: RwLock<FreshnessState>,
+}
+
+impl FreshnessMonitor {
+	pub(crate) fn new() -> Self {
+		Self { state: RwLock::new(FreshnessState::default()) }
+	}
+
+	pub(crate) fn record_database_timestamps(&self, max_gossip_timestamp: Option<u64>, max_seen_timestamp: Option<u64>, now: u64) {
+		let mut state = self.state.write().expect("freshness lock poisoned");
+		state.max_gossip_timestamp = max_gossip_timestamp;
+		state.max_seen_timestamp = max_seen_timestamp;
+		state.refreshed_at = Some(now);
+	}
+
+	/// Record a generation's newly published snapshots. Scopes whose previous snapshot kept being
+	/// served, e. g. for the new one being oversized, keep their previous record.
+	pub(crate) fn record_publication(&self, snapshots: Vec<SnapshotFreshness>) {
+		let mut state = self.state.write().expect("freshness lock poisoned");
+		for snapshot in snapshots {
+			state.snapshots.insert(snapshot.scope, snapshot);
+		}
+	}
+
+	pub(crate) fn report(&self, now: u64) -> FreshnessReport {
+		let state = self.state.read().expect("freshness lock poisoned");
+		FreshnessReport {
+			now,
+			max_gossip_timestamp: state.max_gossip_timestamp,
+			max_seen_timestamp: state.max_seen_timestamp,
+			refreshed_at: state.refreshed_at,
+			snapshots: state.snapshots.values().cloned().collect(),
+			// only the processor knows its role
+			role: ServerRole::default(),
+		}
+	}
+
+	/// Whether snapshot publication newly stalled while gossip is still being ingested, i. e. the
+	/// newest snapshot is further behind than `max_lag`, but the newest gossip isn't. Ingestion
+	/// stalls are reported separately.
+	pub(crate) fn check_publication_stall(&self, now: u64, max_lag: Duration) -> Option<FreshnessReport> {
+		let report = self.report(now);
+		let max_lag = max_lag.as_secs();
+		let is_stalled = report.publication_lag().map_or(false, |lag| lag > max_lag)
+			&& report.seen_lag().map_or(false, |lag| lag <= max_lag);
+		let mut state = self.state.write().expect("freshness lock poisoned");
+		let was_stalled = state.is_publication_stalled;
+		state.is_publication_stalled = is_stalled;
+		if is_stalled && !was_stalled { Some(report) } else { None }
+	}
+
+	/// Refresh the database timestamps every minute, and notify the stall webhook if snapshot
+	/// publication stalls
+	pub(crate) async fn refresh_periodically<L: Deref + Clone>(monitor: Arc<Self>, logger: L) where L::Target: Logger {
+		let max_publication_lag = config::max_publication_lag();
+		let mut interval = tokio::time::interval(REFRESH_INTERVAL);
+		loop {
+			interval.tick().await;
+			let result = async {
+				let client = crate::connect_to_db().await?;
+				fetch_database_timestamps(&client, &Tables::from_config()).await
+					.context("Failed to fetch the newest gossip timestamps")
+			}.await;
+			let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch").as_secs();
+			match result {
+				Ok((max_gossip_timestamp, max_seen_timestamp)) => monitor.record_database_timestamps(max_gossip_timestamp, max_seen_timestamp, now),
+				// the previous timestamps remain, and their age is apparent from the refresh time
+				Err(error) => log_warn!(logger, "{}", error),
+			}
+
+			if let Some(report) = monitor.check_publication_stall(now, max_publication_lag) {
+				let publication_lag = report.publication_lag().unwrap_or_default();
+				log_warn!(logger, "The newest snapshot is {} seconds behind, even though gossip was received {} seconds ago. Has snapshot publication stopped?", publication_lag, report.seen_lag().unwrap_or_default());
+				webhook::notify_publication_stall(publication_lag, report.seen_lag().unwrap_or_default(), logger.clone()).await;
+			}
+		}
+	}
+}
+
+/// The newest channel update timestamp and the newest seen timestamp in the database
+pub(crate) async fn fetch_database_timestamps<C: GenericClient>(client: &C, tables: &Tables) -> Result<(Option<u64>, Option<u64>), tokio_postgres::Error> {
+	let window = GOSSIP_TIMESTAMP_WINDOW.as_secs_f64();
+	let row = client.query_one(&format!("
+		WITH latest_update AS (SELECT MAX(seen) AS seen FROM {channel_updates})
+		SELECT
+			(SELECT MAX(timestamp) FROM {channel_updates} WHERE seen >= (SELECT seen FROM latest_update) - MAKE_INTERVAL(secs => $1)),
+			CAST(EXTRACT('epoch' from GREATEST((SELECT seen FROM latest_update), (SELECT MAX(seen) FROM {channel_announcements}))) AS BIGINT)
+		", channel_updates = tables.channel_updates(), channel_announcements = tables.channel_announcements()), &[&window]).await?;
+	let max_gossip_timestamp = row.get::<_, Option<i64>>(0).map(|timestamp| timestamp as u64);
+	let max_seen_timestamp = row.get::<_, Option<i64>>(1).map(|timestamp| timestamp as u64);
+	Ok((max_gossip_timestamp, max_seen_timestamp))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn snapshot(scope: u64, reference_timestamp: u64) -> SnapshotFreshness {
+		SnapshotFreshness { scope, reference_timestamp, latest_seen_timestamp: reference_timestamp - 30 }
+	}
+
+	#[test]
+	fn test_freshness_report() {
+		let monitor = FreshnessMonitor::new();
+		let report = monitor.report(1_000_000);
+		assert_eq!((report.gossip_lag(), report.seen_lag(), report.publication_lag()), (None, None, None));
+		assert_eq!(report.to_prometheus(), "");
+
+		monitor.record_database_timestamps(Some(999_900), Some(999_950), 999_990);
+		monitor.record_publication(vec![snapshot(86_400, 990_000), snapshot(u64::MAX, 990_000)]);
+		// the oversized full snapshot kept being served
+		monitor.record_publication(vec![snapshot(86_400, 995_000)]);
+		let report = monitor.report(1_000_000);
+		assert_eq!(report.gossip_lag(), Some(100));
+		assert_eq!(report.seen_lag(), Some(50));
+		assert_eq!(report.publication_lag(), Some(5_000));
+		assert_eq!(report.snapshots, vec![snapshot(86_400, 995_000), snapshot(u64::MAX, 990_000)]);
+
+		let metrics = report.to_prometheus();
+		assert!(metrics.contains("rgs_gossip_lag_seconds 100\n"));
+		assert!(metrics.contains("rgs_seen_lag_seconds 50\n"));
+		assert!(metrics.contains("rgs_snapshot_reference_lag_seconds{scope=\"86400\"} 5000\n"));
+		assert!(metrics.contains("rgs_snapshot_reference_lag_seconds{scope=\"full\"} 10000\n"));
+		assert!(metrics.contains("rgs_snapshot_latest_seen_timestamp_seconds{scope=\"full\"} 989970\n"));
+	}
+
+	#[test]
+	fn test_publication_stall() {
+		let monitor = FreshnessMonitor::new();
+		let max_lag = Duration::from_secs(3_600);
+		monitor.record_database_timestamps(Some(999_900), Some(999_950), 999_990);
+		// nothing was published yet
+		assert_eq!(monitor.check_publication_stall(1_000_000, max_lag), None);
+
+		monitor.record_publication(vec![snapshot(u64::MAX, 998_000)]);
+		assert_eq!(monitor.check_publication_stall(1_000_000, max_lag), None);
+		// only the onset of a stall is reported
+		assert!(monitor.check_publication_stall(1_002_000, max_lag).is_some());
+		assert_eq!(monitor.check_publication_stall(1_002_060, max_lag), None);
+
+		// once ingestion stalls, too, the stall webhook already knows
+		monitor.record_publication(vec![snapshot(u64::MAX, 1_002_000)]);
+		assert_eq!(monitor.check_publication_stall(1_002_100, max_lag), None);
+		assert_eq!(monitor.check_publication_stall(1_010_000, max_lag), None);
+	}
+}
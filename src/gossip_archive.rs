@@ -0,0 +1,333 @@
+//! An immutable, append-only log of all gossip received from peers, whether the graph accepted it
+//! or not, for researchers and for replaying the network's history.
+//!
+//! The log is a sequence of frames, each a 4-byte big-endian length followed by that many bytes of
+//! a Brotli stream. Decompressed, a frame holds records of the form
+//! `[4-byte length][4-byte unix timestamp][BigSize message type][wire message]`, where the length
+//! covers everything after it, and the timestamp is the time of receipt. As frames are only ever
+//! appended, a crash loses at most the records not yet flushed, and readers skip a frame that was
+//! cut short at the end of the log.
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::OpenOptions;
+use std::io::{self, Cursor, Read, Write};
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use lightning::{log_error, log_warn};
+use lightning::util::logger::Logger;
+use lightning::util::ser::{BigSize, Readable, Writeable};
+
+/// How many records may await the writer before further ones are dropped, so that a stalled disk
+/// never holds up gossip processing
+const WRITE_QUEUE_CAPACITY: usize = 10_000;
+
+/// How many records are compressed into a frame at most
+const RECORDS_PER_FRAME: usize = 1_000;
+
+/// How long records may wait for their frame to fill up before it's written regardless
+const FRAME_FLUSH_INTERVAL: Duration = Duration::from_secs(10);
+
+const BROTLI_QUALITY: u32 = 9;
+const BROTLI_WINDOW_BITS: u32 = 22;
+
+/// The gossip messages that are archived
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ArchivedMessageType {
+	ChannelAnnouncement,
+	NodeAnnouncement,
+	ChannelUpdate,
+}
+
+impl ArchivedMessageType {
+	/// The message's type on the wire, as defined by BOLT 7
+	pub fn wire_type(&self) -> u64 {
+		match self {
+			ArchivedMessageType::ChannelAnnouncement => 256,
+			ArchivedMessageType::NodeAnnouncement => 257,
+			ArchivedMessageType::ChannelUpdate => 258,
+		}
+	}
+
+	pub fn from_wire_type(wire_type: u64) -> Option<Self> {
+		match wire_type {
+			256 => Some(ArchivedMessageType::ChannelAnnouncement),
+			257 => Some(ArchivedMessageType::NodeAnnouncement),
+			258 => Some(ArchivedMessageType::ChannelUpdate),
+			_ => None,
+		}
+	}
+}
+
+impl fmt::Display for ArchivedMessageType {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		f.write_str(match self {
+			ArchivedMessageType::ChannelAnnouncement => "channel_announcement",
+			ArchivedMessageType::NodeAnnouncement => "node_announcement",
+			ArchivedMessageType::ChannelUpdate => "channel_update",
+		})
+	}
+}
+
+impl FromStr for ArchivedMessageType {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		match s {
+			"channel_announcement" => Ok(ArchivedMessageType::ChannelAnnouncement),
+			"node_announcement" => Ok(ArchivedMessageType::NodeAnnouncement),
+			"channel_update" => Ok(ArchivedMessageType::ChannelUpdate),
+			_ => Err(format!("unknown message type {:?}, expected channel_announcement, node_announcement, or channel_update", s)),
+		}
+	}
+}
+
+/// A message read back from the archive
+#[derive(Clone, Debug, PartialEq)]
+pub struct ArchiveRecord {
+	/// When the message was received, as a unix timestamp
+	pub timestamp: u32,
+	/// The message's wire type. Types other than those of [`ArchivedMessageType`] may be archived
+	/// by future versions.
+	pub message_type: u64,
+	/// The message as it was received, without its type prefix
+	pub message: Vec<u8>,
+}
+
+impl ArchiveRecord {
+	fn encode(&self) -> Vec<u8> {
+		let mut contents = self.timestamp.to_be_bytes().to_vec();
+		BigSize(self.message_type).write(&mut contents).expect("writing to a vector can't fail");
+		contents.extend_from_slice(&self.message);
+		let mut record = (contents.len() as u32).to_be_bytes().to_vec();
+		record.extend_from_slice(&contents);
+		record
+	}
+}
+
+/// Appends every message it's handed to the archive file on a dedicated thread
+pub(crate) struct GossipArchive {
+	/// Taken when dropped, so that the writer flushes its last frame and stops
+	sender: Option<SyncSender<Vec<u8>>>,
+	/// Records dropped because the writer fell behind, since the writer last reported them
+	dropped_record_count: Arc<AtomicU64>,
+	writer: Option<JoinHandle<()>>,
+}
+
+impl GossipArchive {
+	/// Open the archive file for appending, creating it if missing, and start its writer
+	pub(crate) fn open<L: Deref + Send + 'static>(path: &str, logger: L) -> io::Result<Self> where L::Target: Logger {
+		let file = OpenOptions::new().create(true).append(true).open(path)?;
+		Ok(Self::with_writer(file, logger))
+	}
+
+	fn with_writer<W: Write + Send + 'static, L: Deref + Send + 'static>(destination: W, logger: L) -> Self where L::Target: Logger {
+		let (sender, receiver) = mpsc::sync_channel(WRITE_QUEUE_CAPACITY);
+		let dropped_record_count = Arc::new(AtomicU64::new(0));
+		let writer_dropped_record_count = Arc::clone(&dropped_record_count);
+		let writer = thread::Builder::new()
+			.name("gossip-archive".to_string())
+			.spawn(move || write_frames(destination, receiver, writer_dropped_record_count, logger))
+			.expect("failed to spawn the gossip archive writer");
+		Self { sender: Some(sender), dropped_record_count, writer: Some(writer) }
+	}
+
+	/// Queue a received message for archival, dropping it if the writer fell too far behind
+	pub(crate) fn record<M: Writeable>(&self, message_type: ArchivedMessageType, message: &M) {
+		let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch").as_secs() as u32;
+		let record = ArchiveRecord { timestamp, message_type: message_type.wire_type(), message: message.encode() };
+		let sender = self.sender.as_ref().expect("the sender is only taken when dropped");
+		match sender.try_send(record.encode()) {
+			Ok(()) => {},
+			Err(TrySendError::Full(_)) | Err(TrySendError::Disconnected(_)) => {
+				self.dropped_record_count.fetch_add(1, Ordering::Relaxed);
+			},
+		}
+	}
+}
+
+impl Drop for GossipArchive {
+	fn drop(&mut self) {
+		drop(self.sender.take());
+		if let Some(writer) = self.writer.take() {
+			let _ = writer.join();
+		}
+	}
+}
+
+/// Compress the queued records into frames and append them, until the archive is dropped
+fn write_frames<W: Write, L: Deref>(mut destination: W, receiver: Receiver<Vec<u8>>, dropped_record_count: Arc<AtomicU64>, logger: L) where L::Target: Logger {
+	let mut frame = Vec::new();
+	let mut frame_record_count = 0;
+	let mut frame_started_at = Instant::now();
+	loop {
+		let received = if frame_record_count == 0 {
+			receiver.recv().map_err(|_| RecvTimeoutError::Disconnected)
+		} else {
+			receiver.recv_timeout(FRAME_FLUSH_INTERVAL.saturating_sub(frame_started_at.elapsed()))
+		};
+		let disconnected = match received {
+			Ok(record) => {
+				if frame_record_count == 0 {
+					frame_started_at = Instant::now();
+				}
+				frame.extend_from_slice(&record);
+				frame_record_count += 1;
+				if frame_record_count < RECORDS_PER_FRAME {
+					continue;
+				}
+				false
+			},
+			Err(RecvTimeoutError::Timeout) => false,
+			Err(RecvTimeoutError::Disconnected) => true,
+		};
+		if frame_record_count > 0 {
+			if let Err(e) = destination.write_all(&compress_frame(&frame)).and_then(|_| destination.flush()) {
+				log_error!(logger, "Failed to append {} records to the gossip archive: {}", frame_record_count, e);
+			}
+			frame.clear();
+			frame_record_count = 0;
+		}
+		let dropped_records = dropped_record_count.swap(0, Ordering::Relaxed);
+		if dropped_records > 0 {
+			log_warn!(logger, "Dropped {} records as the gossip archive fell behind", dropped_records);
+		}
+		if disconnected {
+			return;
+		}
+	}
+}
+
+/// Compress the given records into a length-prefixed frame, to be appended in a single write
+fn compress_frame(records: &[u8]) -> Vec<u8> {
+	let mut compressor = brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW_BITS);
+	compressor.write_all(records).expect("writing to a vector can't fail");
+	// finishes the stream
+	let compressed = compressor.into_inner();
+	let mut frame = (compressed.len() as u32).to_be_bytes().to_vec();
+	frame.extend_from_slice(&compressed);
+	frame
+}
+
+/// Reads the records of an archive file in the order they were appended
+pub struct ArchiveReader<R: Read> {
+	source: R,
+	/// The records of the latest frame that weren't yet returned
+	pending_records: VecDeque<ArchiveRecord>,
+}
+
+impl<R: Read> ArchiveReader<R> {
+	pub fn new(source: R) -> Self {
+		Self { source, pending_records: VecDeque::new() }
+	}
+
+	/// Read the next frame's records, returning false at the end of the archive. A frame that was
+	/// cut short, as by a crash while it was appended, ends the archive too.
+	fn read_frame(&mut self) -> io::Result<bool> {
+		let mut length_bytes = [0; 4];
+		match self.source.read_exact(&mut length_bytes) {
+			Ok(()) => {},
+			Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+			Err(e) => return Err(e),
+		}
+		let mut compressed = vec![0; u32::from_be_bytes(length_bytes) as usize];
+		match self.source.read_exact(&mut compressed) {
+			Ok(()) => {},
+			Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(false),
+			Err(e) => return Err(e),
+		}
+		let mut records = Vec::new();
+		brotli::Decompressor::new(&compressed[..], 4096).read_to_end(&mut records)?;
+
+		let mut offset = 0;
+		while offset < records.len() {
+			let malformed = |reason: &str| io::Error::new(io::ErrorKind::InvalidData, format!("malformed archive record: {}", reason));
+			let length_bytes = records.get(offset..offset + 4).ok_or_else(|| malformed("truncated length"))?;
+			let length = u32::from_be_bytes(length_bytes.try_into().expect("slice has four bytes")) as usize;
+			let contents = records.get(offset + 4..offset + 4 + length).ok_or_else(|| malformed("truncated contents"))?;
+			offset += 4 + length;
+
+			let mut cursor = Cursor::new(contents);
+			let timestamp: u32 = Readable::read(&mut cursor).map_err(|_| malformed("truncated timestamp"))?;
+			let message_type: BigSize = Readable::read(&mut cursor).map_err(|_| malformed("invalid message type"))?;
+			let message = contents[cursor.position() as usize..].to_vec();
+			self.pending_records.push_back(ArchiveRecord { timestamp, message_type: message_type.0, message });
+		}
+		Ok(true)
+	}
+}
+
+impl<R: Read> Iterator for ArchiveReader<R> {
+	type Item = io::Result<ArchiveRecord>;
+
+	fn next(&mut self) -> Option<Self::Item> {
+		loop {
+			if let Some(record) = self.pending_records.pop_front() {
+				return Some(Ok(record));
+			}
+			match self.read_frame() {
+				Ok(true) => continue,
+				Ok(false) => return None,
+				Err(e) => return Some(Err(e)),
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::Mutex;
+
+	use crate::types::tests::TestLogger;
+
+	/// A destination that can be inspected while the writer owns a handle to it
+	#[derive(Clone, Default)]
+	struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+	impl Write for SharedBuffer {
+		fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+			self.0.lock().unwrap().extend_from_slice(buf);
+			Ok(buf.len())
+		}
+
+		fn flush(&mut self) -> io::Result<()> {
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_archive_round_trip() {
+		let buffer = SharedBuffer::default();
+		let archive = GossipArchive::with_writer(buffer.clone(), Arc::new(TestLogger::with_id("gossip_archive".to_string())));
+		for index in 0..(RECORDS_PER_FRAME as u64 + 5) {
+			archive.record(ArchivedMessageType::ChannelUpdate, &index);
+		}
+		archive.record(ArchivedMessageType::NodeAnnouncement, &vec![1u8, 2, 3]);
+		// flushes the last, partial frame
+		drop(archive);
+
+		let mut archived = buffer.0.lock().unwrap().clone();
+		let records: Vec<ArchiveRecord> = ArchiveReader::new(&archived[..]).collect::<io::Result<_>>().unwrap();
+		assert_eq!(records.len(), RECORDS_PER_FRAME + 6);
+		assert!(records[..RECORDS_PER_FRAME + 5].iter().all(|record| record.message_type == 258));
+		assert_eq!(records[7].message, 7u64.encode());
+		let node_announcement = records.last().unwrap();
+		assert_eq!(ArchivedMessageType::from_wire_type(node_announcement.message_type), Some(ArchivedMessageType::NodeAnnouncement));
+		assert_eq!(node_announcement.message, vec![1u8, 2, 3].encode());
+
+		// a frame cut short ends the archive without an error
+		archived.extend_from_slice(&compress_frame(&archived[..64])[..10]);
+		assert_eq!(ArchiveReader::new(&archived[..]).count(), RECORDS_PER_FRAME + 6);
+
+		assert_eq!("channel_update".parse::<ArchivedMessageType>(), Ok(ArchivedMessageType::ChannelUpdate));
+		assert_eq!(ArchivedMessageType::ChannelAnnouncement.to_string(), "channel_announcement");
+		assert!("ping".parse::<ArchivedMessageType>().is_err());
+	}
+}
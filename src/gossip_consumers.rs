@@ -0,0 +1,242 @@
+//! Consumers of the gossip stream besides the database.
+//!
+//! The database persister is the default consumer of the messages the router accepts. Library users
+//! can add others through [`RapidSyncProcessor::add_gossip_consumer`], each of which receives a copy
+//! of every message handed to the persister through a bounded channel of its own, along with an
+//! event once the initial sync completes. Messages are delivered one at a time rather than in the
+//! persister's batches.
+//!
+//! Every consumer receives the messages in exactly the order the persister does, so all messages
+//! about the same short channel id, or the same node, arrive in the order the router accepted them.
+//! A consumer whose overflow policy is [`OverflowPolicy::Drop`] may miss messages, but never
+//! receives the remaining ones out of order. A consumer with [`OverflowPolicy::Block`] misses
+//! nothing, but stalls the router, and thereby the persister and all other consumers, while its
+//! channel is full.
+//!
+//! [`RapidSyncProcessor::add_gossip_consumer`]: crate::RapidSyncProcessor::add_gossip_consumer
+
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use tokio::sync::mpsc;
+use tokio::sync::mpsc::error::TrySendError;
+
+use crate::types::GossipMessage;
+
+/// What happens to messages for a consumer whose channel is full
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OverflowPolicy {
+	/// Wait for the consumer to make room, holding up the router until it does
+	Block,
+	/// Drop the message, counting it in [`GossipConsumer::dropped_count`]
+	Drop,
+}
+
+/// What consumers receive
+#[derive(Clone, Debug)]
+pub enum GossipEvent {
+	/// A message handed to the persister, which is never a [`GossipMessage::Batch`]
+	Message(GossipMessage),
+	/// The initial sync completed, after which snapshots start being generated
+	InitialSyncCompleted,
+}
+
+/// The receiving end of a consumer's channel
+pub struct GossipConsumer {
+	receiver: mpsc::Receiver<GossipEvent>,
+	dropped_count: Arc<AtomicU64>,
+}
+
+impl GossipConsumer {
+	/// The next event, or `None` once the server has stopped
+	pub async fn recv(&mut self) -> Option<GossipEvent> {
+		self.receiver.recv().await
+	}
+
+	/// The number of events dropped because the channel was full
+	pub fn dropped_count(&self) -> u64 {
+		self.dropped_count.load(Ordering::Acquire)
+	}
+}
+
+#[derive(Clone)]
+struct ConsumerSender {
+	sender: mpsc::Sender<GossipEvent>,
+	overflow_policy: OverflowPolicy,
+	dropped_count: Arc<AtomicU64>,
+}
+
+impl ConsumerSender {
+	/// Send an event, which must happen from within a multi-threaded Tokio runtime if the consumer
+	/// blocks, as waiting for capacity blocks the current thread
+	fn send(&self, event: GossipEvent) {
+		let event = match self.sender.try_send(event) {
+			Ok(()) => return,
+			// consumers that went away don't hold anything up
+			Err(TrySendError::Closed(_)) => return,
+			Err(TrySendError::Full(event)) => event,
+		};
+		match self.overflow_policy {
+			OverflowPolicy::Block => tokio::task::block_in_place(|| tokio::runtime::Handle::current().block_on(async {
+				let _ = self.sender.send(event).await;
+			})),
+			OverflowPolicy::Drop => {
+				self.dropped_count.fetch_add(1, Ordering::AcqRel);
+			},
+		}
+	}
+
+	async fn send_async(&self, event: GossipEvent) {
+		match self.overflow_policy {
+			OverflowPolicy::Block => {
+				let _ = self.sender.send(event).await;
+			},
+			OverflowPolicy::Drop => if let Err(TrySendError::Full(_)) = self.sender.try_send(event) {
+				self.dropped_count.fetch_add(1, Ordering::AcqRel);
+			},
+		}
+	}
+}
+
+/// The consumers registered besides the persister
+#[derive(Default)]
+pub(crate) struct GossipConsumers {
+	senders: RwLock<Vec<ConsumerSender>>,
+}
+
+impl GossipConsumers {
+	pub(crate) fn register(&self, capacity: usize, overflow_policy: OverflowPolicy) -> GossipConsumer {
+		let (sender, receiver) = mpsc::channel(capacity);
+		let dropped_count = Arc::new(AtomicU64::new(0));
+		self.senders.write().expect("gossip consumer lock poisoned").push(ConsumerSender { sender, overflow_policy, dropped_count: Arc::clone(&dropped_count) });
+		GossipConsumer { receiver, dropped_count }
+	}
+
+	/// Hand a copy of the message to every consumer. Callers must serialize their calls in the
+	/// order the persister receives the messages in.
+	pub(crate) fn publish(&self, message: &GossipMessage) {
+		let senders = self.senders.read().expect("gossip consumer lock poisoned");
+		if senders.is_empty() {
+			return;
+		}
+		for message in message.clone().into_messages() {
+			for sender in senders.iter() {
+				sender.send(GossipEvent::Message(message.clone()));
+			}
+		}
+	}
+
+	pub(crate) async fn publish_initial_sync_completion(&self) {
+		// the lock can't be held across awaiting
+		let senders = self.senders.read().expect("gossip consumer lock poisoned").clone();
+		for sender in senders {
+			sender.send_async(GossipEvent::InitialSyncCompleted).await;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::{Duration, Instant};
+	use bitcoin::blockdata::constants::ChainHash;
+	use bitcoin::secp256k1::ecdsa::Signature;
+	use lightning::ln::msgs::{ChannelUpdate, UnsignedChannelUpdate};
+	use crate::batcher::MessageBatcher;
+
+	fn update(short_channel_id: u64, timestamp: u32) -> GossipMessage {
+		GossipMessage::ChannelUpdate(ChannelUpdate {
+			signature: Signature::from_compact(&[1; 64]).unwrap(),
+			contents: UnsignedChannelUpdate {
+				chain_hash: ChainHash::BITCOIN,
+				short_channel_id,
+				timestamp,
+				flags: 0,
+				cltv_expiry_delta: 40,
+				htlc_minimum_msat: 0,
+				htlc_maximum_msat: 100_000_000,
+				fee_base_msat: 1000,
+				fee_proportional_millionths: 100,
+				excess_data: Vec::new(),
+			},
+		}, None)
+	}
+
+	fn update_key(event: &GossipEvent) -> (u64, u32) {
+		match event {
+			GossipEvent::Message(GossipMessage::ChannelUpdate(update, _)) => (update.contents.short_channel_id, update.contents.timestamp),
+			_ => panic!("expected a channel update, got {:?}", event),
+		}
+	}
+
+	#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+	async fn test_fan_out_preserves_order() {
+		let consumers = Arc::new(GossipConsumers::default());
+		let mut first_consumer = consumers.register(100, OverflowPolicy::Block);
+		let mut second_consumer = consumers.register(100, OverflowPolicy::Drop);
+		let (sender, mut persister_receiver) = mpsc::channel(10);
+		let mut batcher = MessageBatcher::with_limits(sender, 3, Duration::from_secs(60));
+		batcher.set_consumers(Arc::clone(&consumers));
+
+		let expected: Vec<(u64, u32)> = vec![(1, 100), (2, 100), (1, 101), (1, 102), (2, 101), (1, 103)];
+		for (short_channel_id, timestamp) in &expected {
+			batcher.push(update(*short_channel_id, *timestamp), Instant::now());
+		}
+		consumers.publish_initial_sync_completion().await;
+
+		// the persister gets the same messages in the same order, only batched
+		let mut persisted = Vec::new();
+		for _ in 0..2 {
+			for message in persister_receiver.recv().await.unwrap().into_messages() {
+				persisted.push(update_key(&GossipEvent::Message(message)));
+			}
+		}
+		assert_eq!(persisted, expected);
+		for consumer in [&mut first_consumer, &mut second_consumer] {
+			let mut received = Vec::new();
+			for _ in 0..expected.len() {
+				received.push(update_key(&consumer.recv().await.unwrap()));
+			}
+			assert_eq!(received, expected);
+			assert!(matches!(consumer.recv().await, Some(GossipEvent::InitialSyncCompleted)));
+			assert_eq!(consumer.dropped_count(), 0);
+		}
+	}
+
+	#[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+	async fn test_overflow_policies() {
+		let consumers = Arc::new(GossipConsumers::default());
+		let mut dropping_consumer = consumers.register(2, OverflowPolicy::Drop);
+		let mut blocking_consumer = consumers.register(2, OverflowPolicy::Block);
+
+		let publisher = {
+			let consumers = Arc::clone(&consumers);
+			tokio::spawn(async move {
+				for timestamp in 0..5 {
+					consumers.publish(&update(1, timestamp));
+				}
+			})
+		};
+		// the blocking consumer holds up publishing until it makes room
+		tokio::time::sleep(Duration::from_millis(100)).await;
+		assert!(!publisher.is_finished());
+		let mut blocking_received = Vec::new();
+		for _ in 0..5 {
+			blocking_received.push(update_key(&blocking_consumer.recv().await.unwrap()).1);
+		}
+		publisher.await.unwrap();
+		assert_eq!(blocking_received, vec![0, 1, 2, 3, 4]);
+		assert_eq!(blocking_consumer.dropped_count(), 0);
+
+		// the dropping consumer keeps what fit, in order, and counts the rest
+		assert_eq!(update_key(&dropping_consumer.recv().await.unwrap()).1, 0);
+		assert_eq!(update_key(&dropping_consumer.recv().await.unwrap()).1, 1);
+		assert!(dropping_consumer.receiver.try_recv().is_err());
+		assert_eq!(dropping_consumer.dropped_count(), 3);
+
+		// consumers that went away are skipped
+		drop(dropping_consumer);
+		consumers.publish(&update(1, 5));
+		assert_eq!(update_key(&blocking_consumer.recv().await.unwrap()).1, 5);
+	}
+}
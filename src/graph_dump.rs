@@ -0,0 +1,117 @@
+//! The network graph's topology and channel policies as JSON, optionally anonymized for sharing
+//! with researchers.
+//!
+//! Anonymization replaces every node id with its HMAC-SHA256 keyed with a seed, and numbers the
+//! channels sequentially in an order derived from the seed too, as short channel ids would tell
+//! where their funding outputs are. The same seed always yields the same pseudonyms and numbering,
+//! so that dumps taken at different times can be compared, while different seeds yield unrelated
+//! ones. Capacities and fees are kept as they are, and may well be enough to re-identify prominent
+//! nodes by correlating them with public gossip.
+
+use std::collections::HashMap;
+use std::ops::Deref;
+
+use bitcoin::hashes::{Hash, HashEngine, Hmac, HmacEngine, sha256};
+use hex_conservative::display::DisplayHex;
+use lightning::routing::gossip::{ChannelUpdateInfo, NetworkGraph, NodeId};
+use lightning::util::logger::Logger;
+use serde::Serialize;
+
+/// The graph's nodes, sorted by id, and channels, sorted by id
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct GraphDump {
+	pub nodes: Vec<DumpedNode>,
+	pub channels: Vec<DumpedChannel>,
+}
+
+/// A [`GraphDump`] whose node ids are pseudonyms, and whose channel ids are sequential numbers
+/// rather than short channel ids
+pub type AnonymizedGraph = GraphDump;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DumpedNode {
+	/// The hex-encoded node id, or its pseudonym
+	pub id: String,
+	pub channel_count: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DumpedChannel {
+	/// The short channel id, or the channel's sequential number
+	pub id: u64,
+	pub node_one: String,
+	pub node_two: String,
+	pub capacity_sats: Option<u64>,
+	/// The policy of payments forwarded from `node_one` to `node_two`, if known
+	pub one_to_two: Option<DumpedPolicy>,
+	pub two_to_one: Option<DumpedPolicy>,
+}
+
+/// A channel direction's latest policy, without its timestamp, which would link it to the gossip it
+/// came from
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct DumpedPolicy {
+	pub enabled: bool,
+	pub cltv_expiry_delta: u16,
+	pub htlc_minimum_msat: u64,
+	pub htlc_maximum_msat: u64,
+	pub fee_base_msat: u32,
+	pub fee_proportional_millionths: u32,
+}
+
+impl From<&ChannelUpdateInfo> for DumpedPolicy {
+	fn from(update: &ChannelUpdateInfo) -> Self {
+		Self {
+			enabled: update.enabled,
+			cltv_expiry_delta: update.cltv_expiry_delta,
+			htlc_minimum_msat: update.htlc_minimum_msat,
+			htlc_maximum_msat: update.htlc_maximum_msat,
+			fee_base_msat: update.fees.base_msat,
+			fee_proportional_millionths: update.fees.proportional_millionths,
+		}
+	}
+}
+
+/// Dump the graph as it is, identifying nodes by their ids and channels by their short channel ids
+pub fn dump_graph<L: Deref>(graph: &NetworkGraph<L>) -> GraphDump where L::Target: Logger {
+	build_dump(graph, |node_id| node_id.to_string(), |short_channel_id| short_channel_id)
+}
+
+/// Dump the graph with every node id replaced by a pseudonym, and every short channel id by a
+/// sequential number, both derived from the seed
+pub fn anonymize_graph<L: Deref>(graph: &NetworkGraph<L>, seed: &[u8; 32]) -> AnonymizedGraph where L::Target: Logger {
+	let mut short_channel_ids: Vec<u64> = graph.read_only().channels().unordered_iter().map(|(short_channel_id, _)| *short_channel_id).collect();
+	// numbering the channels in the order of their short channel ids would tell their relative age
+	short_channel_ids.sort_by_cached_key(|short_channel_id| pseudonym(seed, &short_channel_id.to_be_bytes()));
+	let channel_numbers: HashMap<u64, u64> = short_channel_ids.into_iter().enumerate()
+		.map(|(number, short_channel_id)| (short_channel_id, number as u64))
+		.collect();
+	build_dump(graph, |node_id| pseudonym(seed, node_id.as_slice()), |short_channel_id| channel_numbers[&short_channel_id])
+}
+
+/// The hex-encoded HMAC-SHA256 of an identifier, keyed with the seed
+fn pseudonym(seed: &[u8; 32], identifier: &[u8]) -> String {
+	let mut engine = HmacEngine::<sha256::Hash>::new(seed);
+	engine.input(identifier);
+	Hmac::<sha256::Hash>::from_engine(engine).to_byte_array().to_lower_hex_string()
+}
+
+fn build_dump<L: Deref, N: Fn(&NodeId) -> String, C: Fn(u64) -> u64>(graph: &NetworkGraph<L>, node_id: N, channel_id: C) -> GraphDump where L::Target: Logger {
+	let read_only_graph = graph.read_only();
+	let mut nodes: Vec<DumpedNode> = read_only_graph.nodes().unordered_iter()
+		.map(|(id, node)| DumpedNode { id: node_id(id), channel_count: node.channels.len() })
+		.collect();
+	nodes.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+	let mut channels: Vec<DumpedChannel> = read_only_graph.channels().unordered_iter()
+		.map(|(short_channel_id, channel)| DumpedChannel {
+			id: channel_id(*short_channel_id),
+			node_one: node_id(&channel.node_one),
+			node_two: node_id(&channel.node_two),
+			capacity_sats: channel.capacity_sats,
+			one_to_two: channel.one_to_two.as_ref().map(DumpedPolicy::from),
+			two_to_one: channel.two_to_one.as_ref().map(DumpedPolicy::from),
+		})
+		.collect();
+	channels.sort_unstable_by_key(|channel| channel.id);
+	GraphDump { nodes, channels }
+}
@@ -0,0 +1,290 @@
+//! Checking that a deployed server serves a valid and recent full snapshot, for the
+//! `rgs-healthcheck` binary.
+//!
+//! The snapshot and its checksum are downloaded from the symlinks directory the server is
+//! configured to serve, over plain HTTP, so servers behind a TLS terminator are checked through
+//! their internal address.
+
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use lightning_block_sync::http::HttpEndpoint;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::snapshot::CHECKSUM_EXTENSION;
+use crate::snapshot_reader;
+
+/// How long downloading the snapshot and its checksum may take altogether
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Responses beyond this size are cut off, which fails their checksum. Full mainnet snapshots are
+/// a fraction of it.
+const MAX_RESPONSE_BYTES: u64 = 256 * 1024 * 1024;
+
+/// The name the full snapshot is symlinked as
+const FULL_SNAPSHOT_FILENAME: &str = "0.bin";
+
+/// The outcome of checking a server, ordered by the exit codes the binary stops with
+#[derive(Clone, Debug, PartialEq)]
+pub enum HealthStatus {
+	Healthy,
+	/// The full snapshot's latest update is older than allowed
+	Stale { age_secs: u64 },
+	/// The full snapshot doesn't match its checksum, or can't be parsed
+	ChecksumFailure(String),
+	/// The snapshot or its checksum couldn't be downloaded
+	NetworkError(String),
+}
+
+impl HealthStatus {
+	/// 0 if healthy, 1 if the data is too old, 2 if the checksum failed, and 3 if the server
+	/// couldn't be reached
+	pub fn exit_code(&self) -> i32 {
+		match self {
+			HealthStatus::Healthy => 0,
+			HealthStatus::Stale { .. } => 1,
+			HealthStatus::ChecksumFailure(_) => 2,
+			HealthStatus::NetworkError(_) => 3,
+		}
+	}
+}
+
+/// What was found checking a server's full snapshot
+#[derive(Clone, Debug, PartialEq)]
+pub struct HealthReport {
+	pub server: String,
+	pub status: HealthStatus,
+	pub size_bytes: Option<u64>,
+	pub checksum: Option<String>,
+	pub node_count: Option<u32>,
+	pub channel_count: Option<u32>,
+	/// The unix timestamp of the latest update the snapshot includes
+	pub latest_seen: Option<u32>,
+	pub max_age_secs: u64,
+}
+
+impl HealthReport {
+	pub fn to_text(&self) -> String {
+		let mut output = format!("Server: {}\n", self.server);
+		if let (Some(size_bytes), Some(checksum)) = (self.size_bytes, &self.checksum) {
+			output.push_str(&format!("Full snapshot: {} bytes, SHA-256 {}\n", size_bytes, checksum));
+		}
+		if let (Some(node_count), Some(channel_count)) = (self.node_count, self.channel_count) {
+			output.push_str(&format!("Nodes: {}\nChannels: {}\n", node_count, channel_count));
+		}
+		if let Some(latest_seen) = self.latest_seen {
+			let age_secs = current_timestamp().saturating_sub(latest_seen as u64);
+			output.push_str(&format!("Latest update: {} ({}s ago, at most {}s allowed)\n", latest_seen, age_secs, self.max_age_secs));
+		}
+		let status = match &self.status {
+			HealthStatus::Healthy => "healthy".to_string(),
+			HealthStatus::Stale { age_secs } => format!("unhealthy, the latest update is {}s old", age_secs),
+			HealthStatus::ChecksumFailure(reason) => format!("unhealthy, {}", reason),
+			HealthStatus::NetworkError(reason) => format!("unreachable, {}", reason),
+		};
+		output.push_str(&format!("Status: {}\n", status));
+		output
+	}
+}
+
+/// Parse an `http://host[:port][/path]` URL of the directory the server's symlinks are served from
+pub(crate) fn parse_server_url(url: &str) -> Result<HttpEndpoint, String> {
+	let address = url.trim().strip_prefix("http://").ok_or_else(|| format!("{} is not an http:// URL", url))?;
+	let (authority, path) = match address.find('/') {
+		Some(index) => (&address[..index], address[index..].trim_end_matches('/')),
+		None => (address, ""),
+	};
+	let (host, port) = match authority.rsplit_once(':') {
+		Some((host, port)) => (host, port.parse::<u16>().map_err(|_| format!("{} has an invalid port", url))?),
+		None => (authority, 80),
+	};
+	if host.is_empty() {
+		return Err(format!("{} has no host", url));
+	}
+	Ok(HttpEndpoint::for_host(host.to_string()).with_port(port).with_path(path.to_string()))
+}
+
+/// Download the server's full snapshot and its checksum, and check them against each other and the
+/// allowed age of the latest update
+pub(crate) async fn check_server(server: &HttpEndpoint, max_age: Duration) -> HealthReport {
+	let mut report = HealthReport {
+		server: format!("http://{}:{}{}", server.host(), server.port(), server.path()),
+		status: HealthStatus::Healthy,
+		size_bytes: None,
+		checksum: None,
+		node_count: None,
+		channel_count: None,
+		latest_seen: None,
+		max_age_secs: max_age.as_secs(),
+	};
+
+	let snapshot_path = format!("{}/{}", server.path(), FULL_SNAPSHOT_FILENAME);
+	let checksum_path = format!("{}{}", snapshot_path, CHECKSUM_EXTENSION);
+	let downloads = async {
+		let snapshot = get(server, &snapshot_path).await?;
+		let checksum = get(server, &checksum_path).await?;
+		Ok::<_, String>((snapshot, checksum))
+	};
+	let (snapshot, expected_checksum) = match tokio::time::timeout(DOWNLOAD_TIMEOUT, downloads).await {
+		Ok(Ok(downloads)) => downloads,
+		Ok(Err(error)) => {
+			report.status = HealthStatus::NetworkError(error);
+			return report;
+		},
+		Err(_) => {
+			report.status = HealthStatus::NetworkError(format!("the downloads took over {}s", DOWNLOAD_TIMEOUT.as_secs()));
+			return report;
+		},
+	};
+
+	let checksum = Sha256::hash(&snapshot).to_string();
+	report.size_bytes = Some(snapshot.len() as u64);
+	report.checksum = Some(checksum.clone());
+	// the checksum may be followed by the file name, as written by `sha256sum`
+	let expected_checksum = String::from_utf8_lossy(&expected_checksum).split_whitespace().next().unwrap_or_default().to_lowercase();
+	if expected_checksum != checksum {
+		report.status = HealthStatus::ChecksumFailure(format!("the snapshot's checksum doesn't match the published {:?}", expected_checksum));
+		return report;
+	}
+
+	let summary = match snapshot_reader::parse_snapshot(&snapshot) {
+		Ok(summary) => summary,
+		Err(error) => {
+			report.status = HealthStatus::ChecksumFailure(format!("the snapshot is malformed at byte {}: {}", error.offset, error.reason));
+			return report;
+		},
+	};
+	report.node_count = Some(summary.node_count);
+	report.channel_count = Some(summary.announcement_count);
+	report.latest_seen = Some(summary.latest_seen);
+
+	let age_secs = current_timestamp().saturating_sub(summary.latest_seen as u64);
+	if age_secs > max_age.as_secs() {
+		report.status = HealthStatus::Stale { age_secs };
+	}
+	report
+}
+
+/// Fetch a file over HTTP/1.0, which rules out chunked responses
+async fn get(server: &HttpEndpoint, path: &str) -> Result<Vec<u8>, String> {
+	let mut stream = TcpStream::connect((server.host(), server.port())).await
+		.map_err(|error| format!("failed to connect to {}:{}: {}", server.host(), server.port(), error))?;
+	let request = format!("GET {} HTTP/1.0\r\nHost: {}:{}\r\nConnection: close\r\n\r\n", path, server.host(), server.port());
+	stream.write_all(request.as_bytes()).await.map_err(|error| format!("failed to request {}: {}", path, error))?;
+
+	let mut response = Vec::new();
+	stream.take(MAX_RESPONSE_BYTES).read_to_end(&mut response).await.map_err(|error| format!("failed to download {}: {}", path, error))?;
+	parse_response(&response).map_err(|error| format!("failed to download {}: {}", path, error))
+}
+
+/// The body of a successful response, checked against its `Content-Length`, if any
+fn parse_response(response: &[u8]) -> Result<Vec<u8>, String> {
+	let header_end = response.windows(4).position(|window| window == b"\r\n\r\n").ok_or("the response headers are incomplete")?;
+	let head = String::from_utf8_lossy(&response[..header_end]);
+	let mut lines = head.split("\r\n");
+	let status = lines.next().and_then(|status_line| status_line.split_whitespace().nth(1))
+		.and_then(|status| status.parse::<u16>().ok())
+		.ok_or("the response has no status")?;
+	if status != 200 {
+		return Err(format!("HTTP status {}", status));
+	}
+
+	let body = &response[header_end + 4..];
+	let content_length = lines.filter_map(|line| line.split_once(':'))
+		.find(|(name, _)| name.trim().eq_ignore_ascii_case("content-length"))
+		.map(|(_, value)| value.trim().parse::<usize>().map_err(|_| "the response has an invalid Content-Length"))
+		.transpose()?;
+	match content_length {
+		Some(content_length) if body.len() < content_length => Err(format!("the response was cut off after {} of {} bytes", body.len(), content_length)),
+		Some(content_length) => Ok(body[..content_length].to_vec()),
+		None => Ok(body.to_vec()),
+	}
+}
+
+fn current_timestamp() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch").as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::collections::HashMap;
+
+	use tokio::net::TcpListener;
+
+	use crate::test_utils::bench;
+	use crate::test_utils::synthetic::{SyntheticNetwork, SyntheticNetworkParams};
+
+	use super::*;
+
+	/// Serve the given files until the test ends, returning the server's URL
+	async fn serve(files: HashMap<String, Vec<u8>>) -> HttpEndpoint {
+		let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let port = listener.local_addr().unwrap().port();
+		tokio::spawn(async move {
+			while let Ok((mut stream, _)) = listener.accept().await {
+				let mut request = vec![0u8; 1024];
+				let request_length = stream.read(&mut request).await.unwrap();
+				let request = String::from_utf8_lossy(&request[..request_length]).to_string();
+				let path = request.split_whitespace().nth(1).unwrap_or_default();
+				let response = match files.get(path) {
+					Some(file) => [format!("HTTP/1.0 200 OK\r\nContent-Length: {}\r\n\r\n", file.len()).into_bytes(), file.clone()].concat(),
+					None => b"HTTP/1.0 404 Not Found\r\n\r\n".to_vec(),
+				};
+				let _ = stream.write_all(&response).await;
+			}
+		});
+		parse_server_url(&format!("http://127.0.0.1:{}/snapshots/", port)).unwrap()
+	}
+
+	#[test]
+	fn test_server_url_parsing() {
+		let server = parse_server_url("http://rgs.example.com:8080/snapshots/").unwrap();
+		assert_eq!((server.host(), server.port(), server.path()), ("rgs.example.com", 8080, "/snapshots"));
+		let server = parse_server_url("http://rgs.example.com").unwrap();
+		assert_eq!((server.host(), server.port(), server.path()), ("rgs.example.com", 80, ""));
+		assert!(parse_server_url("https://rgs.example.com").is_err());
+		assert!(parse_server_url("http://rgs.example.com:port").is_err());
+	}
+
+	#[test]
+	fn test_response_parsing() {
+		assert_eq!(parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 3\r\n\r\nabc").unwrap(), b"abc");
+		assert_eq!(parse_response(b"HTTP/1.0 200 OK\r\n\r\nabcd").unwrap(), b"abcd");
+		assert_eq!(parse_response(b"HTTP/1.1 404 Not Found\r\n\r\n").unwrap_err(), "HTTP status 404");
+		assert!(parse_response(b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nabc").unwrap_err().contains("cut off"));
+		assert!(parse_response(b"HTTP/1.1 200 OK\r\n").is_err());
+	}
+
+	#[tokio::test]
+	async fn test_check_server() {
+		let network = SyntheticNetwork::generate(&SyntheticNetworkParams { node_count: 20, channel_count: 40, updates_per_direction: 1, ..Default::default() });
+		let snapshot = bench::serialize_full_snapshot(&network);
+		let checksum = format!("{}\n", Sha256::hash(&snapshot));
+
+		let mut files = HashMap::new();
+		files.insert("/snapshots/0.bin".to_string(), snapshot.clone());
+		files.insert("/snapshots/0.bin.sha256".to_string(), checksum.clone().into_bytes());
+		let server = serve(files).await;
+		let report = check_server(&server, Duration::from_secs(24 * 3600)).await;
+		assert_eq!(report.status, HealthStatus::Healthy, "{}", report.to_text());
+		assert_eq!(report.channel_count, Some(40));
+		assert_eq!(report.size_bytes, Some(snapshot.len() as u64));
+		// the synthetic updates are at least a minute old
+		let report = check_server(&server, Duration::from_secs(1)).await;
+		assert!(matches!(report.status, HealthStatus::Stale { .. }));
+		assert_eq!(report.status.exit_code(), 1);
+
+		let mut corrupted_snapshot = snapshot.clone();
+		*corrupted_snapshot.last_mut().unwrap() ^= 1;
+		let mut files = HashMap::new();
+		files.insert("/snapshots/0.bin".to_string(), corrupted_snapshot);
+		files.insert("/snapshots/0.bin.sha256".to_string(), checksum.into_bytes());
+		let report = check_server(&serve(files).await, Duration::from_secs(24 * 3600)).await;
+		assert_eq!(report.status.exit_code(), 2, "{}", report.to_text());
+
+		let report = check_server(&serve(HashMap::new()).await, Duration::from_secs(24 * 3600)).await;
+		assert_eq!(report.status.exit_code(), 3, "{}", report.to_text());
+	}
+}
@@ -0,0 +1,100 @@
+//! Histograms counting values into fixed buckets, as exported in the Prometheus text exposition
+//! format
+
+use std::marker::PhantomData;
+
+use serde::Serialize;
+
+/// The buckets a [`Histogram`] counts values into, and the Prometheus metric it is exported as
+pub(crate) trait HistogramMetric {
+	/// Upper bounds of the buckets, in ascending order. Anything larger falls into an additional
+	/// overflow bucket.
+	const BUCKET_BOUNDS: &'static [u64];
+	/// The number of recorded units making up a second, as Prometheus expects durations in seconds
+	const UNITS_PER_SECOND: u64;
+	const NAME: &'static str;
+	const HELP: &'static str;
+}
+
+/// The number of recorded values per bucket, along with their sum
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(bound = "")]
+pub(crate) struct Histogram<M: HistogramMetric> {
+	bucket_counts: Vec<u64>,
+	sum: u64,
+	#[serde(skip)]
+	metric: PhantomData<M>,
+}
+
+impl<M: HistogramMetric> Default for Histogram<M> {
+	fn default() -> Self {
+		Self::from_parts(vec![0; Self::bucket_count()], 0)
+	}
+}
+
+impl<M: HistogramMetric> Histogram<M> {
+	pub(crate) fn bucket_count() -> usize {
+		M::BUCKET_BOUNDS.len() + 1
+	}
+
+	/// The index of the bucket a value falls into
+	pub(crate) fn bucket_index(value: u64) -> usize {
+		M::BUCKET_BOUNDS.iter().position(|bound| value <= *bound).unwrap_or(M::BUCKET_BOUNDS.len())
+	}
+
+	pub(crate) fn from_parts(bucket_counts: Vec<u64>, sum: u64) -> Self {
+		debug_assert_eq!(bucket_counts.len(), Self::bucket_count());
+		Self { bucket_counts, sum, metric: PhantomData }
+	}
+
+	pub(crate) fn record(&mut self, value: u64) {
+		self.bucket_counts[Self::bucket_index(value)] += 1;
+		self.sum += value;
+	}
+
+	pub(crate) fn count(&self) -> u64 {
+		self.bucket_counts.iter().sum()
+	}
+
+	/// The values recorded between an earlier copy and this one
+	pub(crate) fn since(&self, earlier: &Self) -> Self {
+		let bucket_counts = self.bucket_counts.iter().zip(earlier.bucket_counts.iter()).map(|(count, earlier_count)| count.saturating_sub(*earlier_count)).collect();
+		Self::from_parts(bucket_counts, self.sum.saturating_sub(earlier.sum))
+	}
+
+	/// The upper bound of the bucket containing the given percentile, or `None` if nothing was
+	/// recorded. Percentiles beyond the largest bucket yield `u64::MAX`.
+	pub(crate) fn percentile(&self, percentile: u64) -> Option<u64> {
+		let total_count = self.count();
+		if total_count == 0 {
+			return None;
+		}
+		// the rank of the sample at the requested percentile, rounded up
+		let rank = (total_count * percentile + 99) / 100;
+		let mut cumulative_count = 0;
+		for (index, count) in self.bucket_counts.iter().enumerate() {
+			cumulative_count += count;
+			if cumulative_count >= rank {
+				return Some(M::BUCKET_BOUNDS.get(index).copied().unwrap_or(u64::MAX));
+			}
+		}
+		Some(u64::MAX)
+	}
+
+	/// Serialize the histogram as a cumulative one in the Prometheus text exposition format
+	pub(crate) fn to_prometheus(&self) -> String {
+		let in_seconds = |value: u64| value as f64 / M::UNITS_PER_SECOND as f64;
+		let mut output = String::new();
+		output.push_str(&format!("# HELP {} {}\n", M::NAME, M::HELP));
+		output.push_str(&format!("# TYPE {} histogram\n", M::NAME));
+		let mut cumulative_count = 0;
+		for (bound, count) in M::BUCKET_BOUNDS.iter().zip(self.bucket_counts.iter()) {
+			cumulative_count += count;
+			output.push_str(&format!("{}_bucket{{le=\"{}\"}} {}\n", M::NAME, in_seconds(*bound), cumulative_count));
+		}
+		output.push_str(&format!("{}_bucket{{le=\"+Inf\"}} {}\n", M::NAME, self.count()));
+		output.push_str(&format!("{}_sum {}\n", M::NAME, in_seconds(self.sum)));
+		output.push_str(&format!("{}_count {}\n", M::NAME, self.count()));
+		output
+	}
+}
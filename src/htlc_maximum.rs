@@ -0,0 +1,54 @@
+//! The one policy for channel updates that don't advertise a usable HTLC maximum.
+//!
+//! LDK reads `htlc_maximum_msat` unconditionally, so updates from before the field became mandatory
+//! don't decode at all, but implementations that still don't set it send a zero in its place. As
+//! a zero maximum would make the direction unusable, the channel's capacity, as verified from its
+//! funding output, takes its place. It's substituted when the update is persisted, or once the
+//! capacity is learned, so that the database, the compact graph, and the snapshots served from
+//! them all agree, while the signed blob keeps what the peer sent.
+
+use crate::tables::Tables;
+
+/// Whether an update's HTLC maximum is absent, and thus subject to substitution
+pub(crate) fn is_absent(htlc_maximum_msat: u64) -> bool {
+	htlc_maximum_msat == 0
+}
+
+/// The HTLC maximum to go by for an update advertising the given one, on a channel of the given
+/// capacity, if known
+pub(crate) fn effective_htlc_maximum_msat(advertised_msat: u64, capacity_sats: Option<u64>) -> u64 {
+	match capacity_sats {
+		Some(capacity_sats) if is_absent(advertised_msat) => capacity_sats.saturating_mul(1000),
+		_ => advertised_msat,
+	}
+}
+
+/// The SQL expression persisting the HTLC maximum bound to `parameter` under this policy, for an
+/// insertion whose short channel id is bound to `$1`
+pub(crate) fn persisted_htlc_maximum_sql(parameter: &str, tables: &Tables) -> String {
+	format!("COALESCE(NULLIF({}::bigint, 0), (SELECT capacity_sats * 1000 FROM {} WHERE short_channel_id = $1), 0)", parameter, tables.channel_announcements())
+}
+
+/// The SQL statement substituting the absent HTLC maximums of all persisted updates whose channel's
+/// capacity is known
+pub(crate) fn backfill_sql(tables: &Tables) -> String {
+	format!("UPDATE {updates} AS updates SET htlc_maximum_msat = announcements.capacity_sats * 1000 \
+		FROM {announcements} AS announcements \
+		WHERE updates.short_channel_id = announcements.short_channel_id AND updates.htlc_maximum_msat = 0 AND announcements.capacity_sats IS NOT NULL",
+		updates = tables.channel_updates(), announcements = tables.channel_announcements())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_htlc_maximum_substitution() {
+		assert_eq!(effective_htlc_maximum_msat(0, Some(1_000_000)), 1_000_000_000);
+		// without a verified capacity, there's nothing to substitute
+		assert_eq!(effective_htlc_maximum_msat(0, None), 0);
+		// advertised maximums are kept, even if they exceed the capacity
+		assert_eq!(effective_htlc_maximum_msat(5_000, Some(1_000_000)), 5_000);
+		assert_eq!(effective_htlc_maximum_msat(2_000_000_000, Some(1_000_000)), 2_000_000_000);
+	}
+}
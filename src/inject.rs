@@ -0,0 +1,185 @@
+use std::collections::VecDeque;
+use std::io::Cursor;
+use std::ops::Deref;
+use std::sync::{Mutex, Weak};
+use std::time::{Duration, Instant};
+
+use lightning::ln::msgs::{ChannelAnnouncement, ChannelUpdate, DecodeError, ErrorAction, LightningError, NodeAnnouncement, RoutingMessageHandler};
+use lightning::log_info;
+use lightning::util::logger::Logger;
+use lightning::util::ser::Readable;
+use serde::Deserialize;
+use thiserror::Error;
+
+use crate::downloader::GossipRouter;
+use crate::hex_utils;
+
+/// The number of messages that may be injected within [`INJECTION_WINDOW`]
+const MAX_INJECTIONS_PER_WINDOW: usize = 10;
+const INJECTION_WINDOW: Duration = Duration::from_secs(60);
+
+/// The kinds of gossip messages that can be injected
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum InjectedMessageType {
+	ChannelAnnouncement,
+	ChannelUpdate,
+	NodeAnnouncement,
+}
+
+/// The JSON body of a `POST /admin/inject-gossip` request
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct InjectionRequest {
+	#[serde(rename = "type")]
+	pub message_type: InjectedMessageType,
+	/// The message as encoded on the wire, without the type prefix, which is also how dead letters
+	/// store it
+	pub hex: String,
+}
+
+/// What became of an injected message the router didn't reject
+#[derive(Clone, Debug, PartialEq)]
+pub enum InjectionOutcome {
+	/// The message was processed and handed to persistence
+	Accepted,
+	/// The router ignored the message, e. g. because it's already known, or because the channel
+	/// announcement's funding output is still being looked up
+	Ignored(String),
+}
+
+/// Everything that can prevent a message from being injected
+#[derive(Debug, Error)]
+pub enum InjectionError {
+	/// More than ten messages were injected within the past minute
+	#[error("too many injected messages, retry in {retry_after:?}")]
+	RateLimited {
+		retry_after: Duration,
+	},
+	/// The request body isn't a valid injection request
+	#[error("invalid injection request: {0}")]
+	InvalidRequest(String),
+	/// The message couldn't be decoded as the given type
+	#[error("failed to decode the {message_type:?}: {error}")]
+	Decode {
+		message_type: InjectedMessageType,
+		error: DecodeError,
+	},
+	/// The gossip download isn't running, so there is no router to inject into
+	#[error("the gossip download isn't running")]
+	NotRunning,
+	/// The router rejected the message, e. g. for an invalid signature
+	#[error("the message was rejected: {0}")]
+	Rejected(String),
+}
+
+/// Feeds messages into the running gossip router as if a peer had sent them, for operators to test
+/// the pipeline with specific messages or to replay dead letters. Injections are rate limited, as
+/// every message is verified and persisted like any other.
+pub(crate) struct GossipInjector<L: Deref + Clone + Send + Sync + 'static> where L::Target: Logger {
+	/// Weak, so that a restarted download's router replaces the previous one rather than the
+	/// previous one being kept alive
+	router: Mutex<Weak<GossipRouter<L>>>,
+	recent_injections: Mutex<VecDeque<Instant>>,
+	logger: L,
+}
+
+impl<L: Deref + Clone + Send + Sync + 'static> GossipInjector<L> where L::Target: Logger {
+	pub(crate) fn new(logger: L) -> Self {
+		Self { router: Mutex::new(Weak::new()), recent_injections: Mutex::new(VecDeque::new()), logger }
+	}
+
+	pub(crate) fn set_router(&self, router: Weak<GossipRouter<L>>) {
+		*self.router.lock().expect("injector lock poisoned") = router;
+	}
+
+	/// Parse a JSON injection request, and inject its message
+	pub(crate) fn inject_json(&self, request_body: &str) -> Result<InjectionOutcome, InjectionError> {
+		let request: InjectionRequest = serde_json::from_str(request_body).map_err(|error| InjectionError::InvalidRequest(error.to_string()))?;
+		self.inject(&request)
+	}
+
+	pub(crate) fn inject(&self, request: &InjectionRequest) -> Result<InjectionOutcome, InjectionError> {
+		self.check_rate_limit(Instant::now())?;
+		let message = DecodedMessage::decode(request)?;
+		let router = self.router.lock().expect("injector lock poisoned").upgrade().ok_or(InjectionError::NotRunning)?;
+		log_info!(self.logger, "Injecting {:?} {}", request.message_type, request.hex);
+		let result = match &message {
+			DecodedMessage::ChannelAnnouncement(announcement) => router.handle_channel_announcement(announcement),
+			DecodedMessage::ChannelUpdate(update) => router.handle_channel_update(update),
+			DecodedMessage::NodeAnnouncement(announcement) => router.handle_node_announcement(announcement),
+		};
+		match result {
+			Ok(_) => Ok(InjectionOutcome::Accepted),
+			Err(LightningError { err, action: ErrorAction::IgnoreError | ErrorAction::IgnoreAndLog(_) | ErrorAction::IgnoreDuplicateGossip }) => Ok(InjectionOutcome::Ignored(err)),
+			Err(LightningError { err, .. }) => Err(InjectionError::Rejected(err)),
+		}
+	}
+
+	/// Count an injection towards the limit, unless it's exceeded already
+	fn check_rate_limit(&self, now: Instant) -> Result<(), InjectionError> {
+		let mut recent_injections = self.recent_injections.lock().expect("injector lock poisoned");
+		while recent_injections.front().map_or(false, |injected_at| now.saturating_duration_since(*injected_at) >= INJECTION_WINDOW) {
+			recent_injections.pop_front();
+		}
+		if recent_injections.len() >= MAX_INJECTIONS_PER_WINDOW {
+			let oldest_injection = *recent_injections.front().expect("the limit is above zero");
+			return Err(InjectionError::RateLimited { retry_after: INJECTION_WINDOW - now.saturating_duration_since(oldest_injection) });
+		}
+		recent_injections.push_back(now);
+		Ok(())
+	}
+}
+
+enum DecodedMessage {
+	ChannelAnnouncement(ChannelAnnouncement),
+	ChannelUpdate(ChannelUpdate),
+	NodeAnnouncement(NodeAnnouncement),
+}
+
+impl DecodedMessage {
+	fn decode(request: &InjectionRequest) -> Result<Self, InjectionError> {
+		let bytes = Some(request.hex.trim()).filter(|hex| hex.len() % 2 == 0)
+			.and_then(hex_utils::to_vec)
+			.ok_or_else(|| InjectionError::InvalidRequest("hex must consist of an even number of hex digits".to_string()))?;
+		let mut reader = Cursor::new(&bytes);
+		let decode_error = |error| InjectionError::Decode { message_type: request.message_type, error };
+		let message = match request.message_type {
+			InjectedMessageType::ChannelAnnouncement => Self::ChannelAnnouncement(Readable::read(&mut reader).map_err(decode_error)?),
+			InjectedMessageType::ChannelUpdate => Self::ChannelUpdate(Readable::read(&mut reader).map_err(decode_error)?),
+			InjectedMessageType::NodeAnnouncement => Self::NodeAnnouncement(Readable::read(&mut reader).map_err(decode_error)?),
+		};
+		if reader.position() != bytes.len() as u64 {
+			return Err(InjectionError::InvalidRequest(format!("{} trailing bytes after the message", bytes.len() as u64 - reader.position())));
+		}
+		Ok(message)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use super::*;
+	use crate::types::tests::TestLogger;
+
+	#[test]
+	fn test_rate_limit() {
+		let injector: GossipInjector<Arc<TestLogger>> = GossipInjector::new(Arc::new(TestLogger::with_id("inject_rate_limit".to_string())));
+		let start = Instant::now();
+		for i in 0..MAX_INJECTIONS_PER_WINDOW {
+			injector.check_rate_limit(start + Duration::from_secs(i as u64)).unwrap();
+		}
+		let error = injector.check_rate_limit(start + Duration::from_secs(30)).unwrap_err();
+		assert!(matches!(error, InjectionError::RateLimited { retry_after } if retry_after == Duration::from_secs(30)));
+		// the first injection leaves the window
+		injector.check_rate_limit(start + INJECTION_WINDOW).unwrap();
+	}
+
+	#[test]
+	fn test_invalid_requests() {
+		let injector: GossipInjector<Arc<TestLogger>> = GossipInjector::new(Arc::new(TestLogger::with_id("inject_invalid_requests".to_string())));
+		assert!(matches!(injector.inject_json("{\"type\": \"ping\", \"hex\": \"00\"}"), Err(InjectionError::InvalidRequest(_))));
+		assert!(matches!(injector.inject_json("{\"type\": \"channel_update\", \"hex\": \"abc\"}"), Err(InjectionError::InvalidRequest(_))));
+		assert!(matches!(injector.inject_json("{\"type\": \"channel_update\", \"hex\": \"0000\"}"), Err(InjectionError::Decode { message_type: InjectedMessageType::ChannelUpdate, .. })));
+	}
+}
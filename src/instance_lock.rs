@@ -0,0 +1,120 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+
+use fs4::FileExt;
+use tokio_postgres::Client;
+
+use crate::error::{ErrorContext, ProcessorError};
+use crate::tables::Tables;
+
+/// The lock file in the cache directory, which also contains the snapshot directories
+const LOCK_FILENAME: &str = "rgs.lock";
+
+/// An advisory lock on a directory, held until dropped, which keeps two servers from generating
+/// snapshots into or caching the graph in the same directory at once. The lock file contains the
+/// PID of the process holding it, so that the server refusing to start can name it.
+pub(crate) struct DirectoryLock {
+	file: File,
+}
+
+impl DirectoryLock {
+	pub(crate) fn acquire(directory: &str) -> Result<Self, ProcessorError> {
+		fs::create_dir_all(directory).context(format!("Failed to create directory {}", directory))?;
+		let path = format!("{}/{}", directory, LOCK_FILENAME);
+		// the file must not be truncated before the lock is ours, lest the holder's PID be lost
+		let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(&path)
+			.context(format!("Failed to open lock file {}", path))?;
+		if let Err(error) = file.try_lock_exclusive() {
+			if error.kind() != fs4::lock_contended_error().kind() {
+				return Err(error).context(format!("Failed to lock {}", path));
+			}
+			let mut holder_pid = String::new();
+			let _ = file.read_to_string(&mut holder_pid);
+			let holder = match holder_pid.trim() {
+				"" => "unknown process".to_string(),
+				pid => format!("PID {}", pid),
+			};
+			return Err(ProcessorError::InstanceLocked { resource: directory.to_string(), holder });
+		}
+		file.set_len(0).and_then(|_| file.seek(SeekFrom::Start(0))).and_then(|_| write!(file, "{}", std::process::id()))
+			.context(format!("Failed to write lock file {}", path))?;
+		Ok(Self { file })
+	}
+}
+
+impl Drop for DirectoryLock {
+	fn drop(&mut self) {
+		// closing the file would release the lock as well, but the PID shouldn't linger
+		let _ = self.file.set_len(0);
+		// spelled out, as newer toolchains' own File::unlock would shadow the flock-based one
+		let _ = FileExt::unlock(&self.file);
+	}
+}
+
+/// Take a session-level advisory lock on the gossip tables, so that two servers can't persist
+/// into the same tables at once. The lock is held until it's released or the connection closes.
+pub(crate) async fn lock_database(client: &Client, tables: &Tables) -> Result<(), ProcessorError> {
+	match try_lock_database(client, tables).await? {
+		None => Ok(()),
+		Some(holder) => Err(ProcessorError::InstanceLocked { resource: format!("the database tables prefixed \"{}\"", tables.prefix()), holder }),
+	}
+}
+
+/// Which other connection holds the lock on the gossip tables, if any, without keeping the lock
+pub(crate) async fn database_lock_holder(client: &Client, tables: &Tables) -> Result<Option<String>, ProcessorError> {
+	let holder = try_lock_database(client, tables).await?;
+	if holder.is_none() {
+		unlock_database(client, tables).await?;
+	}
+	Ok(holder)
+}
+
+/// Take the lock if it's free, and otherwise describe the connection holding it
+async fn try_lock_database(client: &Client, tables: &Tables) -> Result<Option<String>, ProcessorError> {
+	let row = client.query_one("SELECT pg_try_advisory_lock(key), key FROM (SELECT hashtext(current_schema() || '.' || $1)::bigint AS key) AS lock_key", &[&tables.prefix()]).await
+		.context("Failed to lock the database")?;
+	if row.get::<_, bool>(0) {
+		return Ok(None);
+	}
+	let key = row.get::<_, i64>(1);
+	let holder = client.query_opt("
+		SELECT activity.pid, COALESCE(activity.client_addr::text, 'a local socket')
+		FROM pg_locks locks JOIN pg_stat_activity activity ON activity.pid = locks.pid
+		WHERE locks.locktype = 'advisory' AND locks.granted AND locks.objsubid = 1
+			AND locks.classid = (($1 >> 32) & 4294967295)::oid AND locks.objid = ($1 & 4294967295)::oid
+		", &[&key]).await
+		.ok().flatten()
+		.map_or("unknown connection".to_string(), |row| format!("Postgres backend PID {} connected from {}", row.get::<_, i32>(0), row.get::<_, String>(1)));
+	Ok(Some(holder))
+}
+
+/// Release the lock taken by [`lock_database`] on the same connection
+pub(crate) async fn unlock_database(client: &Client, tables: &Tables) -> Result<(), ProcessorError> {
+	client.execute("SELECT pg_advisory_unlock(hashtext(current_schema() || '.' || $1)::bigint)", &[&tables.prefix()]).await
+		.context("Failed to unlock the database")?;
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_directory_lock() {
+		let directory = format!("{}/rgs_instance_lock_{}", std::env::temp_dir().display(), std::process::id());
+		let lock = DirectoryLock::acquire(&directory).unwrap();
+		assert_eq!(fs::read_to_string(format!("{}/{}", directory, LOCK_FILENAME)).unwrap(), std::process::id().to_string());
+
+		// a second instance, which opens the lock file separately, is refused and told who holds it
+		match DirectoryLock::acquire(&directory) {
+			Err(ProcessorError::InstanceLocked { holder, .. }) => assert_eq!(holder, format!("PID {}", std::process::id())),
+			_ => panic!("the second instance must not acquire the lock"),
+		}
+
+		drop(lock);
+		assert_eq!(fs::read_to_string(format!("{}/{}", directory, LOCK_FILENAME)).unwrap(), "");
+		let lock = DirectoryLock::acquire(&directory).unwrap();
+		drop(lock);
+		fs::remove_dir_all(&directory).unwrap();
+	}
+}
@@ -0,0 +1,106 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Duration;
+
+use crate::histogram::{Histogram, HistogramMetric};
+
+/// Upper bounds of the histogram buckets, in microseconds. Anything slower falls into an
+/// additional overflow bucket.
+const LATENCY_BUCKET_BOUNDS_MICROS: [u64; 7] = [1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000];
+
+const BUCKET_COUNT: usize = LATENCY_BUCKET_BOUNDS_MICROS.len() + 1;
+
+/// The time from receiving a gossip message to persisting it, in microseconds
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct ProcessingLatency;
+
+impl HistogramMetric for ProcessingLatency {
+	const BUCKET_BOUNDS: &'static [u64] = &LATENCY_BUCKET_BOUNDS_MICROS;
+	const UNITS_PER_SECOND: u64 = 1_000_000;
+	const NAME: &'static str = "rgs_message_processing_latency_seconds";
+	const HELP: &'static str = "Time from receiving a gossip message to persisting it";
+}
+
+/// The state of a [`LatencyHistogram`] at a point in time
+pub(crate) type LatencySnapshot = Histogram<ProcessingLatency>;
+
+/// Tracks how long gossip messages take from being received from a peer to being persisted.
+///
+/// Latencies are recorded concurrently by the persistence tasks, so all counters are atomic.
+pub(crate) struct LatencyHistogram {
+	bucket_counts: [AtomicU64; BUCKET_COUNT],
+	sum_micros: AtomicU64,
+}
+
+impl LatencyHistogram {
+	pub(crate) fn new() -> Self {
+		Self {
+			bucket_counts: Default::default(),
+			sum_micros: AtomicU64::new(0),
+		}
+	}
+
+	pub(crate) fn record(&self, latency: Duration) {
+		let latency_micros = latency.as_micros() as u64;
+		self.bucket_counts[LatencySnapshot::bucket_index(latency_micros)].fetch_add(1, Ordering::Relaxed);
+		self.sum_micros.fetch_add(latency_micros, Ordering::Relaxed);
+	}
+
+	pub(crate) fn snapshot(&self) -> LatencySnapshot {
+		let bucket_counts = self.bucket_counts.iter().map(|bucket| bucket.load(Ordering::Relaxed)).collect();
+		LatencySnapshot::from_parts(bucket_counts, self.sum_micros.load(Ordering::Relaxed))
+	}
+}
+
+impl LatencySnapshot {
+	/// The upper bound of the bucket containing the given percentile, or `None` if nothing was
+	/// recorded. Percentiles beyond the largest bucket yield [`Duration::MAX`].
+	pub(crate) fn latency_percentile(&self, percentile: u64) -> Option<Duration> {
+		self.percentile(percentile).map(|bound_micros| {
+			if bound_micros == u64::MAX { Duration::MAX } else { Duration::from_micros(bound_micros) }
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_latency_percentiles() {
+		let histogram = LatencyHistogram::new();
+		assert_eq!(histogram.snapshot().latency_percentile(99), None);
+
+		for _ in 0..98 {
+			histogram.record(Duration::from_micros(500));
+		}
+		histogram.record(Duration::from_millis(5));
+		histogram.record(Duration::from_millis(70));
+		let first_snapshot = histogram.snapshot();
+		assert_eq!(first_snapshot.count(), 100);
+		assert_eq!(first_snapshot.latency_percentile(50), Some(Duration::from_millis(1)));
+		assert_eq!(first_snapshot.latency_percentile(99), Some(Duration::from_millis(5)));
+		assert_eq!(first_snapshot.latency_percentile(100), Some(Duration::from_millis(100)));
+
+		histogram.record(Duration::from_secs(3));
+		let window = histogram.snapshot().since(&first_snapshot);
+		assert_eq!(window.count(), 1);
+		assert_eq!(window.latency_percentile(99), Some(Duration::MAX));
+	}
+
+	#[test]
+	fn test_latency_prometheus_export() {
+		let histogram = LatencyHistogram::new();
+		histogram.record(Duration::from_millis(1));
+		histogram.record(Duration::from_millis(7));
+		histogram.record(Duration::from_secs(2));
+
+		let export = histogram.snapshot().to_prometheus();
+		assert!(export.contains("rgs_message_processing_latency_seconds_bucket{le=\"0.001\"} 1\n"));
+		assert!(export.contains("rgs_message_processing_latency_seconds_bucket{le=\"0.005\"} 1\n"));
+		assert!(export.contains("rgs_message_processing_latency_seconds_bucket{le=\"0.01\"} 2\n"));
+		assert!(export.contains("rgs_message_processing_latency_seconds_bucket{le=\"1\"} 2\n"));
+		assert!(export.contains("rgs_message_processing_latency_seconds_bucket{le=\"+Inf\"} 3\n"));
+		assert!(export.contains("rgs_message_processing_latency_seconds_sum 2.008\n"));
+		assert!(export.contains("rgs_message_processing_latency_seconds_count 3\n"));
+	}
+}
@@ -10,38 +10,140 @@
 extern crate core;
 
 use std::collections::{HashMap, HashSet};
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::BufReader;
+use std::net::SocketAddr;
 use std::ops::Deref;
-use std::sync::Arc;
+use std::path::Path;
+use std::process;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 use bitcoin::blockdata::constants::ChainHash;
-use lightning::log_info;
+use bitcoin::secp256k1::PublicKey;
+use lightning::{log_error, log_info, log_warn};
 
 use lightning::routing::gossip::{NetworkGraph, NodeId};
 use lightning::util::logger::Logger;
 use lightning::util::ser::{ReadableArgs, Writeable};
-use tokio::sync::mpsc;
+use lightning_block_sync::http::HttpEndpoint;
+use tokio::sync::{mpsc, Notify};
 use tokio_postgres::{Client, NoTls};
-use crate::config::SYMLINK_GRANULARITY_INTERVAL;
+use crate::canary::CanaryValidator;
+use crate::chain_stats::ChainBackendStats;
+use crate::change_tracker::ChangeTracker;
+use crate::clock::SnapshotClock;
+use crate::compact_graph::CompactGraph;
+use crate::config::{ConfigError, SYMLINK_GRANULARITY_INTERVAL};
+use crate::debug_dump::DebugState;
+use crate::downloader::GossipCounter;
+use crate::instance_lock::DirectoryLock;
+use crate::error::ErrorContext;
+use crate::freshness::FreshnessMonitor;
+use crate::gossip_archive::GossipArchive;
+use crate::gossip_consumers::GossipConsumers;
+use crate::inject::GossipInjector;
+use crate::latency::LatencyHistogram;
 use crate::lookup::DeltaSet;
+use crate::pacing::{LookupClient, LookupLoad, LookupPacing};
 
 use crate::persistence::GossipPersister;
-use crate::serialization::{SerializationSet, UpdateSerialization};
+use crate::role::RoleState;
+use crate::serialization::{FeeDamping, SerializationSet, UpdateSerialization, write_to_vec};
 use crate::snapshot::Snapshotter;
+use crate::stats_history::StatsFormat;
+use crate::supervisor::Supervisor;
+use crate::tables::Tables;
 use crate::types::RGSSLogger;
-
+use crate::verifier::FundingOutputs;
+
+pub use crate::analytics_export::ExportError;
+pub use crate::chain_check::{ChainReport, ChannelDivergence};
+pub use crate::client_usage::{ClientUsage, ClientUsageTotals};
+pub use crate::error::{EXIT_CLEAN_SHUTDOWN, EXIT_CONFIG_ERROR, EXIT_DATABASE_UNREACHABLE, EXIT_FAILURE, EXIT_NO_PEERS_CONNECTED, EXIT_WRONG_CHAIN, ProcessorError};
+pub use crate::fee_histogram::FeeHistogram;
+pub use crate::freshness::{FreshnessReport, SnapshotFreshness};
+pub use crate::graph_dump::{AnonymizedGraph, DumpedChannel, DumpedNode, DumpedPolicy, GraphDump, anonymize_graph, dump_graph};
+pub use crate::gossip_archive::{ArchiveReader, ArchiveRecord, ArchivedMessageType};
+pub use crate::gossip_consumers::{GossipConsumer, GossipEvent, OverflowPolicy};
+pub use crate::healthcheck::{HealthReport, HealthStatus};
+pub use crate::inject::{InjectedMessageType, InjectionError, InjectionOutcome, InjectionRequest};
+pub use crate::lookup::SignedGossipQuery;
+pub use crate::peer_registry::PeerStats;
+pub use crate::plugin::{BACKEND_PLUGIN_ABI_VERSION, BackendPlugin, PluginError, load_backend_plugin};
+pub use crate::exact_delta::{DeltaResponse, ExactDeltaMetrics, ExactDeltaServer};
+pub use crate::reachability::{ChannelReachability, LOW_REACHABILITY_THRESHOLD, ReachabilityScore};
+pub use crate::role::ServerRole;
+pub use crate::routing_hints::ChannelRoutingHints;
+pub use crate::scid::{ScidError, ShortChannelId};
+pub use crate::sync_progress::CatchupProgress;
+
+mod analytics_export;
+mod archive;
+mod batcher;
+mod canary;
+mod chain_check;
+mod chain_stats;
+mod change_tracker;
+mod client_usage;
+mod clock;
+mod catch_up;
+mod compact_graph;
+mod counting_handler;
+mod debug_dump;
+mod dedup;
+mod discovery;
+mod dormancy;
 mod downloader;
+mod early_updates;
+mod error;
+mod exact_delta;
+mod fee_histogram;
+mod freshness;
+mod gossip_archive;
+mod gossip_consumers;
+mod graph_dump;
+mod healthcheck;
+mod histogram;
+mod inject;
+mod instance_lock;
 mod tracking;
+mod latency;
 mod lookup;
+mod maintenance;
+mod new_channels;
+mod peer_connector;
+mod peer_registry;
+mod pacing;
 mod persistence;
+mod plugin;
+mod propagation;
+mod rate_limiter;
+mod reachability;
+mod role;
+mod routing_hints;
+mod scid;
 mod serialization;
 mod snapshot;
+mod snapshot_format;
+mod snapshot_reader;
+mod socket_stats;
 mod config;
 mod hex_utils;
+mod htlc_maximum;
 mod verifier;
+mod stats;
+mod stats_history;
+mod supervisor;
+mod sync_progress;
+mod tables;
+mod validation;
+mod webhook;
 
 pub mod types;
 
+#[cfg(feature = "test-utils")]
+pub mod test_utils;
+
 #[cfg(test)]
 mod tests;
 
@@ -51,8 +153,40 @@ mod tests;
 /// The fourth byte is the protocol version in case our format gets updated.
 const GOSSIP_PREFIX: [u8; 3] = [76, 68, 75];
 
+/// Node ids are serialized as compressed public keys
+const NODE_ID_SERIALIZED_LENGTH: usize = 33;
+/// Roughly how many bytes a node's changed addresses and features take up, and how many bytes the
+/// prefix, chain hash, timestamp, default node features and counts take up, for sizing snapshots
+/// ahead of serialization
+const ESTIMATED_NODE_MUTATION_SIZE: usize = 32;
+const ESTIMATED_HEADER_SIZE: usize = 128;
+
 pub struct RapidSyncProcessor<L: Deref> where L::Target: Logger {
 	network_graph: Arc<NetworkGraph<L>>,
+	latency_histogram: Arc<LatencyHistogram>,
+	chain_backend_stats: Arc<ChainBackendStats>,
+	gossip_counter: Arc<RwLock<GossipCounter>>,
+	freshness: Arc<FreshnessMonitor>,
+	debug_state: Arc<DebugState>,
+	injector: Arc<GossipInjector<L>>,
+	client_usage: Arc<ClientUsage>,
+	/// Counts the graph's changes between snapshot generations, which may warrant generating
+	/// snapshots ahead of schedule
+	change_tracker: Arc<ChangeTracker>,
+	/// Makes [`Self::start_sync`] stop all components and return
+	shutdown: Arc<Notify>,
+	/// Overrides the configured peer list if set
+	peers: Option<Vec<(PublicKey, SocketAddr)>>,
+	/// Overrides the configured bitcoind REST endpoint if set
+	chain_backend: Option<HttpEndpoint>,
+	/// A persistence sink, usually loaded from a shared library, that the persister hands every
+	/// message to alongside the database, if set
+	backend_plugin: Option<Arc<dyn BackendPlugin>>,
+	/// In-process subscribers registered by library users, each receiving the accepted messages
+	/// through a channel of its own as the router hands them to the persister
+	gossip_consumers: Arc<GossipConsumers>,
+	/// Whether snapshots are published, or held back until promotion
+	role: Arc<RoleState>,
 	logger: L
 }
 
@@ -77,74 +211,531 @@ impl<L: Deref + Clone + Send + Sync + 'static> RapidSyncProcessor<L> where L::Ta
 		let network_graph = if let Ok(file) = File::open(&config::network_graph_cache_path()) {
 			log_info!(logger, "Initializing from cached network graph…");
 			let mut buffered_reader = BufReader::new(file);
-			let network_graph_result = NetworkGraph::read(&mut buffered_reader, logger.clone());
-			if let Ok(network_graph) = network_graph_result {
-				log_info!(logger, "Initialized from cached network graph!");
-				network_graph
-			} else {
-				log_info!(logger, "Initialization from cached network graph failed: {}", network_graph_result.err().unwrap());
-				NetworkGraph::new(network, logger.clone())
+			match NetworkGraph::read(&mut buffered_reader, logger.clone()) {
+				Ok(network_graph) => {
+					log_info!(logger, "Initialized from cached network graph!");
+					network_graph
+				},
+				Err(error) => {
+					log_info!(logger, "Initialization from cached network graph failed: {}", error);
+					NetworkGraph::new(network, logger.clone())
+				}
 			}
 		} else {
 			NetworkGraph::new(network, logger.clone())
 		};
 		let arc_network_graph = Arc::new(network_graph);
+		let latency_histogram = Arc::new(LatencyHistogram::new());
+		let chain_backend_stats = Arc::new(ChainBackendStats::new());
+		let gossip_counter = Arc::new(RwLock::new(GossipCounter::new()));
+		let debug_state = Arc::new(DebugState::new(Arc::clone(&gossip_counter), Arc::clone(&latency_histogram), Arc::clone(&chain_backend_stats)));
 		Self {
 			network_graph: arc_network_graph,
+			latency_histogram,
+			chain_backend_stats,
+			gossip_counter,
+			freshness: Arc::new(FreshnessMonitor::new()),
+			debug_state,
+			injector: Arc::new(GossipInjector::new(logger.clone())),
+			client_usage: Arc::new(ClientUsage::new()),
+			change_tracker: Arc::new(ChangeTracker::new(config::snapshot_triggers())),
+			shutdown: Arc::new(Notify::new()),
+			peers: None,
+			chain_backend: None,
+			backend_plugin: None,
+			gossip_consumers: Arc::new(GossipConsumers::default()),
+			role: Arc::new(RoleState::new(config::server_role())),
 			logger
 		}
 	}
 
-	pub async fn start_sync(&self) {
+	/// Connect to these peers rather than the ones configured through `LN_PEERS` or
+	/// `LDK_RGS_PEERS_FILE`
+	pub fn set_peers(&mut self, peers: Vec<(PublicKey, SocketAddr)>) {
+		self.peers = Some(peers);
+	}
+
+	/// Verify channel announcements against this bitcoind REST endpoint rather than the one
+	/// configured through `BITCOIN_REST_DOMAIN`, `BITCOIN_REST_PORT`, and `BITCOIN_REST_PATH`
+	pub fn set_chain_backend(&mut self, chain_backend: HttpEndpoint) {
+		self.chain_backend = Some(chain_backend);
+	}
+
+	/// Persist all gossip to this backend in addition to the database, which snapshots keep being
+	/// generated from
+	pub fn set_backend_plugin(&mut self, backend_plugin: Arc<dyn BackendPlugin>) {
+		self.backend_plugin = Some(backend_plugin);
+	}
+
+	/// Receive a copy of every gossip message persisted from now on through a channel of the given
+	/// capacity, along with an event once the initial sync completes.
+	///
+	/// Each consumer receives the messages in the order the database persister does, so messages
+	/// about the same channel or node arrive in the order they were accepted in. Consumers that
+	/// drop messages on overflow never receive the remaining ones out of order, while consumers
+	/// that block stall the gossip download until they make room.
+	pub fn add_gossip_consumer(&mut self, capacity: usize, overflow_policy: OverflowPolicy) -> GossipConsumer {
+		self.gossip_consumers.register(capacity, overflow_policy)
+	}
+
+	/// Whether the server publishes snapshots, or is a warm standby that only persists gossip
+	pub fn role(&self) -> ServerRole {
+		self.role.role()
+	}
+
+	/// Turn a standby into the active server, which immediately generates snapshots, for the HTTP
+	/// front end to serve at `POST /admin/promote`, which must be restricted to administrators.
+	/// Sending the process SIGUSR1 does the same. Returns whether the server was a standby.
+	pub fn promote(&self) -> bool {
+		let was_standby = self.role.promote();
+		if was_standby {
+			log_info!(self.logger, "Promoted from standby to active");
+		}
+		was_standby
+	}
+
+	/// The time gossip messages take from being received to being persisted, as a Prometheus
+	/// histogram
+	pub fn message_latency_metrics(&self) -> String {
+		self.latency_histogram.snapshot().to_prometheus()
+	}
+
+	/// The time between accepted channel updates' timestamps and their receipt since catching up, as
+	/// a Prometheus histogram
+	pub fn propagation_delay_metrics(&self) -> String {
+		self.gossip_counter.read().expect("gossip counter lock poisoned").propagation_delays.to_prometheus()
+	}
+
+	/// Rolling latency percentiles and error rate of the requests to the chain backend, in the
+	/// Prometheus text exposition format
+	pub fn chain_backend_metrics(&self) -> String {
+		self.chain_backend_stats.summary().to_prometheus()
+	}
+
+	/// How far the catch-up with the network's gossip has progressed, for the HTTP front end to
+	/// serve at `GET /status/catchup`
+	pub fn catchup_progress(&self) -> CatchupProgress {
+		let counter = self.gossip_counter.read().expect("gossip counter lock poisoned");
+		sync_progress::estimate_catchup_progress(&counter, counter.connected_peers)
+	}
+
+	/// How far the newest gossip in the database and the newest published snapshots are behind,
+	/// for the HTTP front end's status page. The database is queried once a minute, not per call.
+	pub fn freshness_report(&self) -> FreshnessReport {
+		FreshnessReport { role: self.role.role(), ..self.freshness.report(current_timestamp() as u64) }
+	}
+
+	/// The timestamps and lags of [`Self::freshness_report`], as Prometheus gauges
+	pub fn freshness_metrics(&self) -> String {
+		self.freshness_report().to_prometheus()
+	}
+
+	/// The state of all components as pretty-printed JSON, which is also written to the cache
+	/// directory's `debug` folder upon SIGUSR2. The HTTP front end may serve it at an
+	/// authenticated `GET /debug/dump`.
+	pub fn debug_dump(&self) -> String {
+		serde_json::to_string_pretty(&self.debug_state.collect()).expect("debug dumps consist of plain values")
+	}
+
+	/// Cumulative communication statistics of a peer since startup, for the HTTP front end to serve
+	/// at `GET /admin/peers/{pubkey}/stats` using [`PeerStats::to_json`]. `None` for peers that were
+	/// never connected to.
+	pub fn peer_stats(&self, pubkey: &PublicKey) -> Option<PeerStats> {
+		self.debug_state.peer_stats()?.peer_stats(pubkey)
+	}
+
+	/// The [`Self::peer_stats`] of all peers connected to since startup, as Prometheus metrics
+	/// labeled by the peers' public keys
+	pub fn peer_metrics(&self) -> String {
+		let peer_stats = self.debug_state.peer_stats().map_or(Vec::new(), |peer_stats| peer_stats.all_peer_stats());
+		peer_registry::peer_stats_to_prometheus(&peer_stats)
+	}
+
+	/// Feed a gossip message into the running gossip download as if a peer had sent it, for the HTTP
+	/// front end to serve at `POST /admin/inject-gossip`, which must be restricted to administrators.
+	/// The request body is JSON of the form `{"type": "channel_update", "hex": "..."}`. At most ten
+	/// messages are accepted per minute.
+	pub fn inject_gossip(&self, request_body: &str) -> Result<InjectionOutcome, InjectionError> {
+		self.injector.inject_json(request_body)
+	}
+
+	/// The current route hints through a channel in both directions, for the HTTP front end to serve
+	/// at `GET /channels/{scid}/routing-hint` using [`ChannelRoutingHints::to_json`]. The path's
+	/// `{scid}` parses into a [`ShortChannelId`] in either of its forms.
+	pub fn channel_routing_hints(&self, short_channel_id: ShortChannelId) -> Option<ChannelRoutingHints> {
+		routing_hints::extract_routing_hints(short_channel_id.into(), &self.network_graph)
+	}
+
+	/// The aggregate of snapshot requests, which the HTTP front end reports every request it serves
+	/// to. The totals are published to the stats directory as `client_usage.json` and
+	/// `client_usage.prom`, and recorded in the stats history.
+	pub fn client_usage(&self) -> Arc<ClientUsage> {
+		Arc::clone(&self.client_usage)
+	}
+
+	/// Create a server for answering delta requests for arbitrary timestamps, which the HTTP
+	/// front end serving the snapshots can consult before falling back to the symlinked files.
+	pub fn exact_delta_server(&self) -> ExactDeltaServer<L> {
+		ExactDeltaServer::new(Arc::clone(&self.network_graph), self.logger.clone())
+	}
+
+	/// Log all configuration problems, and fail if any of them keep the server from starting.
+	/// Invalid settings take precedence over an unreachable database, which takes precedence over
+	/// the chain backend following the wrong chain, in determining the error.
+	async fn validate_config(&self, chain_backend: HttpEndpoint) -> Result<(), ProcessorError> {
+		let errors = config::validate(self.peers.as_deref(), chain_backend).await;
+		let mut fatal_error_count = 0;
+		let mut database_error = None;
+		let mut chain_error = None;
+		for error in errors {
+			match error {
+				ConfigError::Fatal(message) => {
+					fatal_error_count += 1;
+					log_error!(self.logger, "Invalid configuration: {}", message);
+				},
+				ConfigError::DatabaseUnreachable(message) => {
+					log_error!(self.logger, "Invalid configuration: {}", message);
+					database_error = Some(ProcessorError::DatabaseUnreachable(message));
+				},
+				ConfigError::WrongChain(message) => {
+					log_error!(self.logger, "Invalid configuration: {}", message);
+					chain_error = Some(ProcessorError::WrongChain(message));
+				},
+				ConfigError::Warning(message) => log_warn!(self.logger, "Degraded configuration: {}", message),
+			}
+		}
+		if fatal_error_count > 0 {
+			return Err(ProcessorError::Config(format!("{} fatal configuration errors, see the log for details", fatal_error_count)));
+		}
+		database_error.or(chain_error).map_or(Ok(()), Err)
+	}
+
+	/// Download and persist gossip, and generate snapshots from it. This only returns if one of
+	/// those tasks stops and can't be restarted, or, in tests, once the server is shut down.
+	pub async fn start_sync(&self) -> Result<(), ProcessorError> {
 		log_info!(self.logger, "Starting Rapid Gossip Sync Server");
+		// held until the server stops, covering the snapshot directories within the cache directory
+		let _cache_lock = DirectoryLock::acquire(&config::cache_path())?;
 		log_info!(self.logger, "Snapshot interval: {} seconds", config::snapshot_generation_interval());
+		log_info!(self.logger, "Role: {}", self.role.role());
 
 		// means to indicate sync completion status within this module
 		let (sync_completion_sender, mut sync_completion_receiver) = mpsc::channel::<()>(1);
-
-		if config::DOWNLOAD_NEW_GOSSIP {
+		let mut supervisor = Supervisor::new(config::component_restart_budget(), self.logger.clone());
+		tokio::spawn(DebugState::dump_on_user_signal(Arc::clone(&self.debug_state), self.logger.clone()));
+		tokio::spawn(RoleState::promote_on_user_signal(Arc::clone(&self.role), self.logger.clone()));
+
+		let persister = if config::DOWNLOAD_NEW_GOSSIP {
+			let chain_backend_override = self.chain_backend.as_ref()
+				.map(|endpoint| (endpoint.host().to_string(), endpoint.port(), endpoint.path().to_string()));
+			let chain_backend = move || match &chain_backend_override {
+				// endpoints can't be cloned, but they're trivial to rebuild
+				Some((host, port, path)) => HttpEndpoint::for_host(host.clone()).with_port(*port).with_path(path.clone()),
+				None => config::bitcoin_rest_endpoint(),
+			};
+			self.validate_config(chain_backend()).await?;
+			let peers = match &self.peers {
+				Some(peers) => peers.clone(),
+				None => {
+					let peers = config::load_ln_peers().map_err(ProcessorError::Config)?;
+					discovery::resolve_peer_list(peers, self.logger.clone()).await.map_err(ProcessorError::Config)?
+				},
+			};
 			let (mut persister, persistence_sender) = GossipPersister::new(self.network_graph.clone(), self.logger.clone());
+			persister.set_latency_histogram(Arc::clone(&self.latency_histogram));
+			let gossip_counter = Arc::clone(&self.gossip_counter);
+			persister.set_gossip_counter(Arc::clone(&gossip_counter));
+			persister.set_debug_state(Arc::clone(&self.debug_state));
+			persister.set_client_usage(Arc::clone(&self.client_usage));
+			persister.set_role(Arc::clone(&self.role));
+			if let Some(backend_plugin) = &self.backend_plugin {
+				persister.set_backend_plugin(Arc::clone(backend_plugin));
+			}
+			let funding_outputs = Arc::new(FundingOutputs::new());
+			persister.set_funding_outputs(Arc::clone(&funding_outputs));
+			self.debug_state.set_persistence_queue(persistence_sender.downgrade());
+			// the persister outlives its task, so that a restart resumes reading the same channel
+			let persister = Arc::new(tokio::sync::Mutex::new(persister));
 
 			log_info!(self.logger, "Starting gossip download");
-			tokio::spawn(tracking::download_gossip(persistence_sender, sync_completion_sender,
-				Arc::clone(&self.network_graph), self.logger.clone()));
+			// opened once, so that restarts of the download keep appending through the same writer
+			let gossip_archive = match config::gossip_archive_path() {
+				Some(path) => Some(Arc::new(GossipArchive::open(&path, self.logger.clone()).context(format!("Failed to open the gossip archive at {}", path))?)),
+				None => None,
+			};
+			let tracking_context = tracking::TrackingContext {
+				persistence_sender,
+				completion_sender: sync_completion_sender,
+				network_graph: Arc::clone(&self.network_graph),
+				counter: gossip_counter,
+				peers,
+				backend_stats: Arc::clone(&self.chain_backend_stats),
+				funding_outputs,
+				debug_state: Arc::clone(&self.debug_state),
+				injector: Arc::clone(&self.injector),
+				change_tracker: Arc::clone(&self.change_tracker),
+				gossip_archive,
+				gossip_consumers: Arc::clone(&self.gossip_consumers),
+				logger: self.logger.clone(),
+			};
+			supervisor.supervise("tracking", config::failure_policy("tracking"), move || {
+				tracking::download_gossip(tracking_context.clone(), chain_backend())
+			});
 			log_info!(self.logger, "Starting gossip db persistence listener");
-			tokio::spawn(async move { persister.persist_gossip().await; });
+			let supervised_persister = Arc::clone(&persister);
+			supervisor.supervise("persistence", config::failure_policy("persistence"), move || {
+				let persister = Arc::clone(&supervised_persister);
+				async move { persister.lock().await.persist_gossip().await }
+			});
+			Some(persister)
 		} else {
-			sync_completion_sender.send(()).await.unwrap();
-		}
+			sync_completion_sender.send(()).await.map_err(|_| ProcessorError::ChannelClosed("sync completion"))?;
+			None
+		};
 
-		let sync_completion = sync_completion_receiver.recv().await;
-		if sync_completion.is_none() {
-			panic!("Sync failed!");
+		let result: Result<(), ProcessorError> = async {
+			let sync_completion = tokio::select! {
+				sync_completion = sync_completion_receiver.recv() => sync_completion,
+				error = supervisor.run() => return Err(error),
+				_ = self.shutdown.notified() => return Ok(()),
+			};
+			if sync_completion.is_none() {
+				return Err(ProcessorError::SyncFailed);
+			}
+			log_info!(self.logger, "Initial sync complete!");
+			self.gossip_consumers.publish_initial_sync_completion().await;
+
+			tokio::spawn(stats::publish_fee_stats(Arc::clone(&self.network_graph), self.logger.clone()));
+			tokio::spawn(new_channels::publish_periodically(self.logger.clone()));
+			tokio::spawn(FreshnessMonitor::refresh_periodically(Arc::clone(&self.freshness), self.logger.clone()));
+			let client_usage = Arc::clone(&self.client_usage);
+			let logger = self.logger.clone();
+			tokio::spawn(async move { client_usage.publish_periodically(logger).await; });
+			let canary_validator = CanaryValidator::new(Arc::clone(&self.network_graph), self.logger.clone());
+			tokio::spawn(async move { canary_validator.validate_periodically().await; });
+
+			// start the gossip snapshotting service, which runs until some component stops for good
+			let network_graph = Arc::clone(&self.network_graph);
+			let freshness = Arc::clone(&self.freshness);
+			let debug_state = Arc::clone(&self.debug_state);
+			let change_tracker = Arc::clone(&self.change_tracker);
+			let role = Arc::clone(&self.role);
+			let logger = self.logger.clone();
+			supervisor.supervise("snapshot", config::failure_policy("snapshot"), move || {
+				let mut snapshotter = Snapshotter::new(Arc::clone(&network_graph), logger.clone());
+				snapshotter.set_freshness_monitor(Arc::clone(&freshness));
+				snapshotter.set_debug_state(Arc::clone(&debug_state));
+				snapshotter.set_change_tracker(Arc::clone(&change_tracker));
+				let role = Arc::clone(&role);
+				let logger = logger.clone();
+				async move {
+					if role.role() == ServerRole::Standby {
+						log_info!(logger, "Standing by, snapshots are generated once promoted");
+						role.wait_until_active().await;
+					}
+					// the snapshotting service generates snapshots as soon as it starts
+					snapshotter.snapshot_gossip().await
+				}
+			});
+			tokio::select! {
+				error = supervisor.run() => Err(error),
+				_ = self.shutdown.notified() => Ok(()),
+			}
+		}.await;
+
+		supervisor.shutdown().await;
+		if let Some(persister) = persister {
+			// the persister owns a runtime, which must not be dropped from an async context
+			let _ = tokio::task::spawn_blocking(move || drop(persister)).await;
 		}
-		log_info!(self.logger, "Initial sync complete!");
+		result
+	}
+}
 
-		// start the gossip snapshotting service
-		Snapshotter::new(Arc::clone(&self.network_graph), self.logger.clone()).snapshot_gossip().await;
+/// Exit the process once the server stopped, logging why, with the code telling whatever
+/// orchestrates the server whether restarting it can help: [`EXIT_CLEAN_SHUTDOWN`] if it
+/// was shut down on purpose, and otherwise [`ProcessorError::exit_code`].
+pub fn exit_after_stopping<L: Deref>(result: Result<(), ProcessorError>, logger: L) -> ! where L::Target: Logger {
+	let exit_code = match result {
+		Ok(()) => {
+			log_info!(logger, "Rapid Gossip Sync Server shut down");
+			EXIT_CLEAN_SHUTDOWN
+		},
+		Err(error) => {
+			let exit_code = error.exit_code();
+			log_error!(logger, "Rapid Gossip Sync Server stopped: {} (exit code {})", error, exit_code);
+			exit_code
+		},
+	};
+	process::exit(exit_code)
+}
+
+/// Load the backend plugin configured through `LDK_RGS_BACKEND_PLUGIN`, if any
+pub fn load_configured_backend_plugin() -> Result<Option<Arc<dyn BackendPlugin>>, PluginError> {
+	config::backend_plugin_path().map(|path| load_backend_plugin(&path)).transpose()
+}
+
+/// Summarize how the gossip statistics changed between consecutive stats history entries recorded
+/// since the given date, either as a human-readable table or as CSV.
+pub async fn stats_history_report(since: &str, csv: bool) -> Result<String, ProcessorError> {
+	let client = connect_to_db().await?;
+	let tables = Tables::from_config();
+	let records = stats_history::fetch_stats(&client, &tables, since).await.context("Failed to fetch stats history")?;
+	let deltas = stats_history::compute_deltas(&records);
+	let format = if csv { StatsFormat::Csv } else { StatsFormat::Table };
+	Ok(stats_history::format_deltas(&deltas, format))
+}
+
+/// Report how many channels were first seen in the last hour and day, and how long after their
+/// funding block they were, either human-readably or as JSON
+pub async fn new_channel_report(json: bool) -> Result<String, ProcessorError> {
+	let client = connect_to_db().await?;
+	let tables = Tables::from_config();
+	let new_channel_stats = new_channels::fetch_new_channel_stats(&client, &tables).await.context("Failed to fetch new channel stats")?;
+	Ok(if json { new_channel_stats.to_json() } else { new_channel_stats.to_text() })
+}
+
+/// Check that the server whose symlinks are served at the given `http://` URL serves a full
+/// snapshot matching its checksum, whose latest update is at most `max_age` old. Fails only if the
+/// URL is invalid, as every other problem is reported as the [`HealthStatus`].
+pub async fn check_server_health(server_url: &str, max_age: Duration) -> Result<HealthReport, String> {
+	let server = healthcheck::parse_server_url(server_url)?;
+	Ok(healthcheck::check_server(&server, max_age).await)
+}
+
+/// Parse the snapshot file at the given path the way clients do, and summarize its contents,
+/// either human-readably or as JSON. Extended snapshots are recognized by their header.
+pub fn summarize_snapshot(path: &str, json: bool) -> Result<String, ProcessorError> {
+	let data = fs::read(path).context(format!("Failed to read snapshot {}", path))?;
+	let parse = if data.starts_with(&serialization::EXTENDED_SNAPSHOT_MAGIC) { snapshot_reader::parse_extended_snapshot } else { snapshot_reader::parse_snapshot };
+	let mut summary = parse(&data)
+		.map_err(|error| ProcessorError::MalformedSnapshot { offset: error.offset, reason: error.reason })?;
+	summary.reference_timestamp = Path::new(path).file_name()
+		.and_then(|filename| snapshot_reader::reference_timestamp_from_filename(&filename.to_string_lossy()));
+	Ok(if json { summary.to_json() } else { summary.to_text() })
+}
+
+/// Check that a client applying the previous full snapshot and then the delta at the given paths
+/// ends up with the same channels as one applying the full snapshot
+pub fn verify_delta_chain(previous_full_snapshot_path: &str, delta_path: &str, full_snapshot_path: &str) -> Result<ChainReport, ProcessorError> {
+	let read = |path: &str| fs::read(path).context(format!("Failed to read snapshot {}", path));
+	chain_check::verify_chain(&read(previous_full_snapshot_path)?, &read(delta_path)?, &read(full_snapshot_path)?, &config::fee_damping())
+}
+
+/// Export the cached network graph as a Parquet file, without connecting to any peers. The
+/// database is only consulted for the reachability scores, which are left empty if it's unavailable.
+pub async fn export_cached_graph_parquet<L: Deref>(output_path: &str, logger: L) -> Result<(), ExportError> where L::Target: Logger {
+	let reachability = async {
+		let client = connect_to_db().await?;
+		reachability::fetch_reachability_scores(&client, &Tables::from_config(), None, current_timestamp()).await
+			.context("Failed to fetch reachability scores")
+	}.await;
+	let reachability = match reachability {
+		Ok(reachability) => Some(reachability),
+		Err(error) => {
+			log_warn!(logger, "Exporting without reachability scores: {}", error);
+			None
+		},
+	};
+
+	let cache_path = config::network_graph_cache_path();
+	let file = File::open(&cache_path).map_err(|source| ExportError::Io { path: cache_path.clone(), source })?;
+	let network_graph = NetworkGraph::read(&mut BufReader::new(file), logger)
+		.map_err(|error| ExportError::InvalidGraph { path: cache_path, error })?;
+	analytics_export::export_parquet(&network_graph, reachability.as_ref(), output_path)
+}
+
+/// Dump the cached network graph as JSON, without connecting to any peers, anonymized with the given
+/// seed if any
+pub fn dump_cached_graph<L: Deref>(anonymization_seed: Option<&[u8; 32]>, logger: L) -> Result<String, ExportError> where L::Target: Logger {
+	let cache_path = config::network_graph_cache_path();
+	let file = File::open(&cache_path).map_err(|source| ExportError::Io { path: cache_path.clone(), source })?;
+	let network_graph = NetworkGraph::read(&mut BufReader::new(file), logger)
+		.map_err(|error| ExportError::InvalidGraph { path: cache_path, error })?;
+	let dump = match anonymization_seed {
+		Some(seed) => graph_dump::anonymize_graph(&network_graph, seed),
+		None => graph_dump::dump_graph(&network_graph),
+	};
+	Ok(serde_json::to_string(&dump).expect("graph dumps serialize to JSON"))
+}
+
+/// Regenerate the snapshots and symlinks as they would have been generated at the given unix
+/// timestamp, from the gossip persisted by then, into `output_path`. The directory must be outside
+/// the cache directory, so that the snapshots being served are never touched.
+pub async fn generate_snapshots_as_of<L: Deref + Clone>(as_of: u64, output_path: &str, logger: L) -> Result<(), ProcessorError> where L::Target: Logger {
+	if as_of > current_timestamp() as u64 {
+		return Err(ProcessorError::Config(format!("snapshots can't be generated as of {}, which is in the future", as_of)));
 	}
+	let cache_path = config::cache_path();
+	fs::create_dir_all(output_path).context(format!("Failed to create output directory {}", output_path))?;
+	let canonical_output_path = fs::canonicalize(output_path).context(format!("Failed to resolve output directory {}", output_path))?;
+	if fs::canonicalize(&cache_path).map_or(false, |canonical_cache_path| canonical_output_path.starts_with(canonical_cache_path)) {
+		return Err(ProcessorError::Config(format!("the output directory {} must be outside the cache directory {}", output_path, cache_path)));
+	}
+
+	// the graph at the time is reconstructed from the database instead
+	let network_graph = Arc::new(NetworkGraph::new(config::network(), logger.clone()));
+	let snapshotter = Snapshotter::new(network_graph, logger.clone());
+	let snapshot_interval = config::snapshot_generation_interval() as u64;
+	let snapshot_scopes = snapshot::tiered_snapshot_scopes(snapshot_interval, config::full_snapshot_age());
+	let report = snapshotter.generate_snapshots_as_of(as_of, SYMLINK_GRANULARITY_INTERVAL as u64, snapshot_interval, &snapshot_scopes, output_path, None).await?;
+	log_info!(logger, "Generated {} snapshots as of {} into {}", report.size_reports.len(), as_of, output_path);
+	Ok(())
+}
+
+/// Score how reliably both directions of a channel have been receiving their periodic updates,
+/// for the HTTP front end to serve at `GET /channels/{scid}/reachability`
+pub async fn channel_reachability(short_channel_id: ShortChannelId) -> Result<ChannelReachability, ProcessorError> {
+	let short_channel_id = u64::from(short_channel_id);
+	let client = connect_to_db().await?;
+	let now = current_timestamp();
+	let scores = reachability::fetch_reachability_scores(&client, &Tables::from_config(), Some(short_channel_id), now).await
+		.context(format!("Failed to fetch the reachability of channel {}", short_channel_id))?;
+	Ok(ChannelReachability::from_scores(short_channel_id, &scores, now))
+}
+
+/// The distribution of the fees charged by the channel directions active between two unix
+/// timestamps, as of their latest updates within that range, for the HTTP front end to serve at
+/// `GET /stats/fee-histogram?since=<ts>&until=<ts>` using [`FeeHistogram::to_json`]
+pub async fn fee_histogram(since: u64, until: u64) -> Result<FeeHistogram, ProcessorError> {
+	let client = connect_to_db().await?;
+	fee_histogram::fetch_fee_histogram(&client, &Tables::from_config(), since, until).await
+		.context("Failed to compute the fee histogram")
+}
+
+/// Fetch a persisted gossip message's signed wire encoding, without the type prefix, for forensic
+/// debugging or serving it to peers verbatim. Returns `None` if no such message was persisted.
+pub async fn fetch_signed_gossip(query: &SignedGossipQuery) -> Result<Option<Vec<u8>>, ProcessorError> {
+	let client = connect_to_db().await?;
+	lookup::fetch_signed_message(&client, &Tables::from_config(), query).await
+}
+
+fn current_timestamp() -> u32 {
+	SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch").as_secs() as u32
 }
 
-pub(crate) async fn connect_to_db() -> Client {
+pub(crate) async fn connect_to_db() -> Result<Client, ProcessorError> {
 	let connection_config = config::db_connection_config();
-	let (client, connection) = connection_config.connect(NoTls).await.unwrap();
+	let (client, connection) = connection_config.connect(NoTls).await.map_err(|error| ProcessorError::DatabaseUnreachable(error.to_string()))?;
 
 	tokio::spawn(async move {
-		if let Err(e) = connection.await {
-			panic!("connection error: {}", e);
-		}
+		// there is no recovering from losing the database mid-query
+		connection.await.expect("database connection failed");
 	});
 
+	#[cfg(not(test))]
+	let schema_name = config::db_schema();
 	#[cfg(test)]
-	{
-		let schema_name = tests::db_test_schema();
+	let schema_name = Some(tests::db_test_schema());
+
+	if let Some(schema_name) = schema_name {
 		let schema_creation_command = format!("CREATE SCHEMA IF NOT EXISTS {}", schema_name);
-		client.execute(&schema_creation_command, &[]).await.unwrap();
-		client.execute(&format!("SET search_path TO {}", schema_name), &[]).await.unwrap();
+		client.execute(&schema_creation_command, &[]).await.context(format!("Failed to create schema {}", schema_name))?;
+		client.execute(&format!("SET search_path TO {}", schema_name), &[]).await.context(format!("Failed to select schema {}", schema_name))?;
 	}
 
-	client.execute("set time zone UTC", &[]).await.unwrap();
-	client
+	client.execute("set time zone UTC", &[]).await.context("Failed to set the time zone")?;
+	Ok(client)
 }
 
 /// This method generates a no-op blob that can be used as a delta where none exists.
@@ -162,44 +753,88 @@ pub(crate) async fn connect_to_db() -> Client {
 /// the client previously had, which could result in duplicated or omitted gossip down the line.
 fn serialize_empty_blob(current_timestamp: u64) -> Vec<u8> {
 	let mut blob = GOSSIP_PREFIX.to_vec();
+	// clients expect a version byte after the prefix. Version 1 is the one without any node section
+	// beyond the node count, as version 2 would also need a count of default node features
+	blob.push(1);
 
 	let network = config::network();
 	let chain_hash = ChainHash::using_genesis_block(network);
-	chain_hash.write(&mut blob).unwrap();
+	write_to_vec(&chain_hash, &mut blob);
 
 	let blob_timestamp = Snapshotter::<Arc<RGSSLogger>>::round_down_to_nearest_multiple(current_timestamp, SYMLINK_GRANULARITY_INTERVAL as u64) as u32;
-	blob_timestamp.write(&mut blob).unwrap();
+	write_to_vec(&blob_timestamp, &mut blob);
 
-	0u32.write(&mut blob).unwrap(); // node count
-	0u32.write(&mut blob).unwrap(); // announcement count
-	0u32.write(&mut blob).unwrap(); // update count
+	write_to_vec(&0u32, &mut blob); // node count
+	write_to_vec(&0u32, &mut blob); // announcement count
+	write_to_vec(&0u32, &mut blob); // update count
 
 	blob
 }
 
-async fn calculate_delta<L: Deref + Clone>(network_graph: Arc<NetworkGraph<L>>, last_sync_timestamp: u32, snapshot_reference_timestamp: Option<u64>, logger: L) -> SerializationSet where L::Target: Logger {
-	let client = connect_to_db().await;
-
+async fn calculate_delta<L: Deref + Clone>(network_graph: Arc<NetworkGraph<L>>, last_sync_timestamp: u32, clock: SnapshotClock, logger: L) -> Result<SerializationSet, ProcessorError> where L::Target: Logger {
 	network_graph.remove_stale_channels_and_tracking();
+	let compact_graph = CompactGraph::from_network_graph(&network_graph);
+	let (delta, _) = calculate_paced_delta(&compact_graph, last_sync_timestamp, clock, &LookupPacing::default(), &config::fee_damping(), logger).await?;
+	Ok(delta)
+}
+
+/// Calculate a delta while limiting the load the lookups put on the database, also returning the
+/// load they did cause
+async fn calculate_paced_delta<L: Deref + Clone>(compact_graph: &CompactGraph, last_sync_timestamp: u32, clock: SnapshotClock, pacing: &LookupPacing, fee_damping: &FeeDamping, logger: L) -> Result<(SerializationSet, LookupLoad), ProcessorError> where L::Target: Logger {
+	let client = LookupClient::connect(pacing.clone()).await?;
+	let tables = Tables::from_config();
 
 	// set a flag if the chain hash is prepended
 	// chain hash only necessary if either channel announcements or non-incremental updates are present
 	// for announcement-free incremental-only updates, chain hash can be skipped
 
 	let mut delta_set = DeltaSet::new();
-	lookup::fetch_channel_announcements(&mut delta_set, network_graph, &client, last_sync_timestamp, snapshot_reference_timestamp, logger.clone()).await;
+	lookup::fetch_channel_announcements(&mut delta_set, compact_graph, &client, &tables, last_sync_timestamp, clock, logger.clone()).await?;
 	log_info!(logger, "announcement channel count: {}", delta_set.len());
-	lookup::fetch_channel_updates(&mut delta_set, &client, last_sync_timestamp, logger.clone()).await;
+	let htlc_maximum_substitution_count = lookup::fetch_channel_updates(&mut delta_set, &client, &tables, last_sync_timestamp, clock, logger.clone()).await?;
 	log_info!(logger, "update-fetched channel count: {}", delta_set.len());
-	let node_delta_set = lookup::fetch_node_updates(&client, last_sync_timestamp, logger.clone()).await;
+	let node_delta_set = lookup::fetch_node_updates(&client, &tables, last_sync_timestamp, clock, logger.clone()).await?;
 	log_info!(logger, "update-fetched node count: {}", node_delta_set.len());
+	let mut omitted_stale_channel_count = 0;
+	if let (0, Some(horizon)) = (last_sync_timestamp, config::snapshot_stale_channel_horizon()) {
+		omitted_stale_channel_count = lookup::omit_stale_channels(&mut delta_set, clock, horizon, logger.clone());
+	}
 	lookup::filter_delta_set(&mut delta_set, logger.clone());
 	log_info!(logger, "update-filtered channel count: {}", delta_set.len());
-	serialization::serialize_delta_set(delta_set, node_delta_set, last_sync_timestamp)
+	let mut serialization_set = serialization::serialize_delta_set(delta_set, node_delta_set, last_sync_timestamp, clock, fee_damping);
+	serialization_set.omitted_stale_channel_count = omitted_stale_channel_count;
+	serialization_set.htlc_maximum_substitution_count = htlc_maximum_substitution_count;
+	Ok((serialization_set, client.load()))
 }
 
 fn serialize_delta<L: Deref + Clone>(serialization_details: &SerializationSet, serialization_version: u8, logger: L) -> SerializedResponse where L::Target: Logger {
-	let mut output: Vec<u8> = vec![];
+	// standard snapshots never carry capacities, lest they change for clients that didn't opt in
+	serialize_delta_variant(serialization_details, serialization_version, false, logger)
+}
+
+/// Serialize a version 2 snapshot carrying the known capacity of each announced channel, behind
+/// the header described at [`serialization::EXTENDED_SNAPSHOT_MAGIC`]
+fn serialize_extended_delta<L: Deref + Clone>(serialization_details: &SerializationSet, logger: L) -> SerializedResponse where L::Target: Logger {
+	let mut response = serialize_delta_variant(serialization_details, 2, true, logger);
+	let total_capacity_sats = serialization_details.announcements.iter()
+		.filter_map(|announcement| serialization_details.announcement_capacities.get(&announcement.short_channel_id))
+		.sum();
+	let totals = serialization::ExtendedHeaderTotals::new(total_capacity_sats, response.node_announcement_count, response.channel_announcement_count);
+	let mut data = Vec::with_capacity(serialization::EXTENDED_SNAPSHOT_MAGIC.len() + 1 + serialization::EXTENDED_HEADER_TOTALS_LENGTH + response.data.len());
+	data.extend_from_slice(&serialization::EXTENDED_SNAPSHOT_MAGIC);
+	data.push(serialization::EXTENDED_SNAPSHOT_VERSION);
+	totals.write(&mut data);
+	data.extend_from_slice(&response.data);
+	response.data = data;
+	response
+}
+
+fn serialize_delta_variant<L: Deref + Clone>(serialization_details: &SerializationSet, serialization_version: u8, include_capacities: bool, logger: L) -> SerializedResponse where L::Target: Logger {
+	// version 1 clients can't skip the additional data capacities are carried in
+	assert!(!include_capacities || serialization_version >= 2, "version 1 snapshots can't carry capacities");
+	// the node ids are only known once the channels are written, so the channels go into a buffer
+	// of their own, which is appended to the node ids in the end
+	let mut output: Vec<u8> = Vec::with_capacity(serialization_details.estimated_message_size());
 	let snapshot_interval = config::snapshot_generation_interval();
 
 	let mut node_id_set: HashSet<NodeId> = HashSet::new();
@@ -221,13 +856,13 @@ fn serialize_delta<L: Deref + Clone>(serialization_details: &SerializationSet, s
 	// process announcements
 	// write the number of channel announcements to the output
 	let announcement_count = serialization_details.announcements.len() as u32;
-	announcement_count.write(&mut output).unwrap();
+	write_to_vec(&announcement_count, &mut output);
 	let mut previous_announcement_scid = 0;
 	for current_announcement in &serialization_details.announcements {
 		let id_index_1 = get_node_id_index(current_announcement.node_id_1);
 		let id_index_2 = get_node_id_index(current_announcement.node_id_2);
-		let mut stripped_announcement = serialization::serialize_stripped_channel_announcement(&current_announcement, id_index_1, id_index_2, previous_announcement_scid);
-		output.append(&mut stripped_announcement);
+		let capacity_sats = serialization_details.announcement_capacities.get(&current_announcement.short_channel_id).copied().filter(|_| include_capacities);
+		serialization::serialize_stripped_channel_announcement(&current_announcement, id_index_1, id_index_2, previous_announcement_scid, capacity_sats, &mut output);
 
 		previous_announcement_scid = current_announcement.short_channel_id;
 	}
@@ -235,15 +870,15 @@ fn serialize_delta<L: Deref + Clone>(serialization_details: &SerializationSet, s
 	// process updates
 	let mut previous_update_scid = 0;
 	let update_count = serialization_details.updates.len() as u32;
-	update_count.write(&mut output).unwrap();
+	write_to_vec(&update_count, &mut output);
 
 	let default_update_values = &serialization_details.full_update_defaults;
 	if update_count > 0 {
-		default_update_values.cltv_expiry_delta.write(&mut output).unwrap();
-		default_update_values.htlc_minimum_msat.write(&mut output).unwrap();
-		default_update_values.fee_base_msat.write(&mut output).unwrap();
-		default_update_values.fee_proportional_millionths.write(&mut output).unwrap();
-		default_update_values.htlc_maximum_msat.write(&mut output).unwrap();
+		write_to_vec(&default_update_values.cltv_expiry_delta, &mut output);
+		write_to_vec(&default_update_values.htlc_minimum_msat, &mut output);
+		write_to_vec(&default_update_values.fee_base_msat, &mut output);
+		write_to_vec(&default_update_values.fee_proportional_millionths, &mut output);
+		write_to_vec(&default_update_values.htlc_maximum_msat, &mut output);
 	}
 
 	let mut update_count_full = 0;
@@ -258,8 +893,7 @@ fn serialize_delta<L: Deref + Clone>(serialization_details: &SerializationSet, s
 			}
 		};
 
-		let mut stripped_update = serialization::serialize_stripped_channel_update(&current_update, &default_update_values, previous_update_scid);
-		output.append(&mut stripped_update);
+		serialization::serialize_stripped_channel_update(&current_update, &default_update_values, previous_update_scid, &mut output);
 
 		previous_update_scid = current_update.scid();
 	}
@@ -267,42 +901,48 @@ fn serialize_delta<L: Deref + Clone>(serialization_details: &SerializationSet, s
 	// some stats
 	let message_count = announcement_count + update_count;
 
-	let mut prefixed_output = GOSSIP_PREFIX.to_vec();
+	if serialization_version >= 2 {
+		for mutated_node_id in serialization_details.node_mutations.keys() {
+			// consider mutated nodes outside channel announcements
+			get_node_id_index(mutated_node_id.clone());
+		}
+	}
+
+	// the header and node ids are followed by the channels, which are appended to them in the end
+	let estimated_node_size = node_ids.len() * NODE_ID_SERIALIZED_LENGTH + serialization_details.node_mutations.len() * ESTIMATED_NODE_MUTATION_SIZE;
+	let mut prefixed_output = Vec::with_capacity(ESTIMATED_HEADER_SIZE + estimated_node_size + output.len());
+	prefixed_output.extend_from_slice(&GOSSIP_PREFIX);
 	prefixed_output.push(serialization_version);
 
 	// always write the chain hash
-	serialization_details.chain_hash.write(&mut prefixed_output).unwrap();
+	write_to_vec(&serialization_details.chain_hash, &mut prefixed_output);
 	// always write the latest seen timestamp
 	let latest_seen_timestamp = serialization_details.latest_seen;
 	let overflow_seconds = latest_seen_timestamp % snapshot_interval;
 	let serialized_seen_timestamp = latest_seen_timestamp.saturating_sub(overflow_seconds);
-	serialized_seen_timestamp.write(&mut prefixed_output).unwrap();
+	write_to_vec(&serialized_seen_timestamp, &mut prefixed_output);
 
 	if serialization_version >= 2 { // serialize the most common node features
-		for mutated_node_id in serialization_details.node_mutations.keys() {
-			// consider mutated nodes outside channel announcements
-			get_node_id_index(mutated_node_id.clone());
-		}
-
 		let default_feature_count = serialization_details.node_announcement_feature_defaults.len() as u8;
 		debug_assert!(default_feature_count <= config::NODE_DEFAULT_FEATURE_COUNT, "Default feature count cannot exceed maximum");
-		default_feature_count.write(&mut prefixed_output).unwrap();
+		write_to_vec(&default_feature_count, &mut prefixed_output);
 
 		for current_feature in &serialization_details.node_announcement_feature_defaults {
-			current_feature.write(&mut prefixed_output).unwrap();
+			write_to_vec(current_feature, &mut prefixed_output);
 		}
 	}
 
 	let node_id_count = node_ids.len() as u32;
-	node_id_count.write(&mut prefixed_output).unwrap();
+	write_to_vec(&node_id_count, &mut prefixed_output);
 
 	let mut node_update_count = 0u32;
 	let mut node_feature_update_count = 0u32;
 	let mut node_address_update_count = 0u32;
 
 	for current_node_id in node_ids {
-		let mut current_node_delta_serialization: Vec<u8> = Vec::new();
-		current_node_id.write(&mut current_node_delta_serialization).unwrap();
+		// the node's details are written right after its id, whose first byte carries their bitmap
+		let node_id_position = prefixed_output.len();
+		write_to_vec(&current_node_id, &mut prefixed_output);
 
 		if serialization_version >= 2 {
 			if let Some(node_delta) = serialization_details.node_mutations.get(&current_node_id) {
@@ -320,11 +960,16 @@ fn serialize_delta<L: Deref + Clone>(serialization_details: &SerializationSet, s
 				if node_delta.has_address_set_changed {
 					node_address_update_count += 1;
 
-					let address_set = &node_delta.latest_details_after_seen.as_ref().unwrap().addresses;
-					let mut address_serialization = Vec::new();
+					let address_set = &node_delta.latest_details_after_seen.as_ref().expect("changed nodes have details after the last sync").addresses;
+
+					// signal the presence of node addresses
+					prefixed_output[node_id_position] |= 1 << 2;
 
-					// we don't know a priori how many are <= 255 bytes
+					// we don't know a priori how many are <= 255 bytes, so the count is filled in
+					// once the addresses are written
+					let address_count_position = prefixed_output.len();
 					let mut total_address_count = 0u8;
+					write_to_vec(&total_address_count, &mut prefixed_output);
 
 					for address in address_set.iter() {
 						if total_address_count == u8::MAX {
@@ -333,30 +978,25 @@ fn serialize_delta<L: Deref + Clone>(serialization_details: &SerializationSet, s
 						}
 						if let Ok(serialized_length) = u8::try_from(address.serialized_length()) {
 							total_address_count += 1;
-							serialized_length.write(&mut address_serialization).unwrap();
-							address.write(&mut address_serialization).unwrap();
+							write_to_vec(&serialized_length, &mut prefixed_output);
+							write_to_vec(address, &mut prefixed_output);
 						};
 					}
-
-					// signal the presence of node addresses
-					current_node_delta_serialization[0] |= 1 << 2;
-					// serialize the actual addresses and count
-					total_address_count.write(&mut current_node_delta_serialization).unwrap();
-					current_node_delta_serialization.append(&mut address_serialization);
+					prefixed_output[address_count_position] = total_address_count;
 				}
 
 				if node_delta.has_feature_set_changed {
 					node_feature_update_count += 1;
 
-					let latest_features = &node_delta.latest_details_after_seen.as_ref().unwrap().features;
+					let latest_features = &node_delta.latest_details_after_seen.as_ref().expect("changed nodes have details after the last sync").features;
 
 					// are these features among the most common ones?
 					if let Some(index) = serialization_details.node_announcement_feature_defaults.iter().position(|f| f == latest_features) {
 						// this feature set is among the 6 defaults
-						current_node_delta_serialization[0] |= ((index + 1) as u8) << 3;
+						prefixed_output[node_id_position] |= ((index + 1) as u8) << 3;
 					} else {
-						current_node_delta_serialization[0] |= 0b_0011_1000; // 7 << 3
-						latest_features.write(&mut current_node_delta_serialization).unwrap();
+						prefixed_output[node_id_position] |= 0b_0011_1000; // 7 << 3
+						write_to_vec(latest_features, &mut prefixed_output);
 					}
 				}
 
@@ -365,8 +1005,6 @@ fn serialize_delta<L: Deref + Clone>(serialization_details: &SerializationSet, s
 				}
 			}
 		}
-
-		prefixed_output.append(&mut current_node_delta_serialization);
 	}
 
 	prefixed_output.append(&mut output);
@@ -1,21 +1,42 @@
 use std::collections::{BTreeMap, HashMap, HashSet};
 use std::io::Cursor;
 use std::ops::Deref;
-use std::sync::Arc;
-use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant};
 
 use lightning::ln::msgs::{ChannelAnnouncement, ChannelUpdate, NodeAnnouncement, SocketAddress, UnsignedChannelAnnouncement, UnsignedChannelUpdate};
-use lightning::routing::gossip::{NetworkGraph, NodeId};
+use lightning::routing::gossip::NodeId;
 use lightning::util::ser::Readable;
-use tokio_postgres::Client;
 
-use futures::StreamExt;
 use lightning::{log_debug, log_gossip, log_info};
 use lightning::ln::features::NodeFeatures;
 use lightning::util::logger::Logger;
+use tokio_postgres::{Client, Row};
 
+use crate::clock::SnapshotClock;
+use crate::compact_graph::CompactGraph;
 use crate::config;
+use crate::error::{ErrorContext, ProcessorError};
+use crate::pacing::LookupClient;
+use crate::scid::ShortChannelId;
 use crate::serialization::MutatedProperties;
+use crate::tables::Tables;
+
+/// A persisted message to fetch the signed wire encoding of
+#[derive(Clone, Debug, PartialEq)]
+pub enum SignedGossipQuery {
+	ChannelAnnouncement {
+		short_channel_id: u64,
+	},
+	ChannelUpdate {
+		short_channel_id: u64,
+		direction: bool,
+		timestamp: u32,
+	},
+	NodeAnnouncement {
+		node_id: NodeId,
+		timestamp: u32,
+	},
+}
 
 /// The delta set needs to be a BTreeMap so the keys are sorted.
 /// That way, the scids in the response automatically grow monotonically
@@ -25,6 +46,8 @@ pub(super) type NodeDeltaSet = HashMap<NodeId, NodeDelta>;
 pub(super) struct AnnouncementDelta {
 	pub(super) seen: u32,
 	pub(super) announcement: UnsignedChannelAnnouncement,
+	/// The value of the funding output, as learned when verifying the announcement
+	pub(super) capacity_sats: Option<u64>,
 }
 
 pub(super) struct UpdateDelta {
@@ -56,14 +79,15 @@ pub(super) struct NodeDelta {
 	/// The most recently received, but new-to-the-client, node details
 	pub(super) latest_details_after_seen: Option<NodeDetails>,
 
-	/// Between last_details_before_seen and latest_details_after_seen, including any potential
-	/// intermediate updates that are not kept track of here, has the set of features this node
-	/// supports changed?
+	/// Does the set of features this node supports differ between last_details_before_seen and
+	/// latest_details_after_seen? Intermediate updates that were reverted don't count. For nodes the
+	/// client has never seen before, set whenever the features are non-empty.
 	pub(super) has_feature_set_changed: bool,
 
-	/// Between last_details_before_seen and latest_details_after_seen, including any potential
-	/// intermediate updates that are not kept track of here, has the set of socket addresses this
-	/// node listens on changed?
+	/// Does the set of socket addresses this node listens on differ between
+	/// last_details_before_seen and latest_details_after_seen? Intermediate updates that were
+	/// reverted don't count. For nodes the client has never seen before, set whenever the set is
+	/// non-empty.
 	pub(super) has_address_set_changed: bool,
 
 	/// The most recent node details that the client would have seen already
@@ -71,7 +95,6 @@ pub(super) struct NodeDelta {
 }
 
 pub(super) struct NodeDetails {
-	#[allow(unused)]
 	pub(super) seen: u32,
 	pub(super) features: NodeFeatures,
 	pub(super) addresses: HashSet<SocketAddress>
@@ -114,23 +137,15 @@ impl Default for DirectedUpdateDelta {
 /// whether they had been seen before.
 /// Also include all announcements for which the first update was announced
 /// after `last_sync_timestamp`
-pub(super) async fn fetch_channel_announcements<L: Deref>(delta_set: &mut DeltaSet, network_graph: Arc<NetworkGraph<L>>, client: &Client, last_sync_timestamp: u32, snapshot_reference_timestamp: Option<u64>, logger: L) where L::Target: Logger {
-	log_info!(logger, "Obtaining channel ids from network graph");
-	let channel_ids = {
-		let read_only_graph = network_graph.read_only();
-		log_info!(logger, "Retrieved read-only network graph copy");
-		let channel_iterator = read_only_graph.channels().unordered_iter();
-		channel_iterator
-			.filter(|c| c.1.announcement_message.is_some() && c.1.one_to_two.is_some() && c.1.two_to_one.is_some())
-			.map(|c| c.1.announcement_message.as_ref().unwrap().contents.short_channel_id as i64)
-			.collect::<Vec<_>>()
-	};
+pub(super) async fn fetch_channel_announcements<L: Deref>(delta_set: &mut DeltaSet, compact_graph: &CompactGraph, client: &LookupClient, tables: &Tables, last_sync_timestamp: u32, clock: SnapshotClock, logger: L) -> Result<(), ProcessorError> where L::Target: Logger {
+	log_info!(logger, "Obtaining channel ids from compact network graph");
+	let channel_ids = compact_graph.bidirectional_channel_ids();
 	#[cfg(test)]
 	log_info!(logger, "Channel IDs: {:?}", channel_ids);
 	log_info!(logger, "Last sync timestamp: {}", last_sync_timestamp);
 	let last_sync_timestamp_float = last_sync_timestamp as f64;
 
-	let current_timestamp = snapshot_reference_timestamp.unwrap_or(SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs());
+	let current_timestamp = clock.now();
 	log_info!(logger, "Current timestamp: {}", current_timestamp);
 
 	let include_reminders = {
@@ -153,23 +168,26 @@ pub(super) async fn fetch_channel_announcements<L: Deref>(delta_set: &mut DeltaS
 
 	log_info!(logger, "Obtaining corresponding database entries");
 	// get all the channel announcements that are currently in the network graph
-	let announcement_rows = client.query_raw("SELECT announcement_signed, CAST(EXTRACT('epoch' from seen) AS BIGINT) AS seen FROM channel_announcements WHERE short_channel_id = any($1) ORDER BY short_channel_id ASC", [&channel_ids]).await.unwrap();
-	let mut pinned_rows = Box::pin(announcement_rows);
+	let mut announcement_rows = client.query(&format!("SELECT announcement_signed, CAST(EXTRACT('epoch' from seen) AS BIGINT) AS seen, capacity_sats FROM {} WHERE short_channel_id = any($1) AND {} ORDER BY short_channel_id ASC", tables.channel_announcements(), clock.seen_condition("seen")), &[&channel_ids]).await.context("Failed to fetch channel announcements")?;
 
 	let mut announcement_count = 0;
-	while let Some(row_res) = pinned_rows.next().await {
-		let current_announcement_row = row_res.unwrap();
-		let blob: Vec<u8> = current_announcement_row.get("announcement_signed");
+	while let Some(row_res) = announcement_rows.next().await {
+		let current_announcement_row = row_res.context("Failed to read channel announcement row")?;
+		let blob: &[u8] = current_announcement_row.get("announcement_signed");
 		let mut readable = Cursor::new(blob);
-		let unsigned_announcement = ChannelAnnouncement::read(&mut readable).unwrap().contents;
+		let unsigned_announcement = ChannelAnnouncement::read(&mut readable).context("Failed to decode persisted channel announcement")?.contents;
 
 		let scid = unsigned_announcement.short_channel_id;
 		let current_seen_timestamp = current_announcement_row.get::<_, i64>("seen") as u32;
+		// announcements persisted before capacities were stored rely on the graph's
+		let capacity_sats = current_announcement_row.get::<_, Option<i64>>("capacity_sats").map(|capacity| capacity as u64)
+			.or_else(|| compact_graph.channel(ShortChannelId::from(scid)).and_then(|channel| channel.capacity_sats));
 
 		let current_channel_delta = delta_set.entry(scid).or_insert(ChannelDelta::default());
 		(*current_channel_delta).announcement = Some(AnnouncementDelta {
 			announcement: unsigned_announcement,
 			seen: current_seen_timestamp,
+			capacity_sats,
 		});
 
 		announcement_count += 1;
@@ -189,24 +207,23 @@ pub(super) async fn fetch_channel_announcements<L: Deref>(delta_set: &mut DeltaS
 		// `last_seen_timestamp` are added to the selection
 		let params: [&(dyn tokio_postgres::types::ToSql + Sync); 2] =
 			[&channel_ids, &last_sync_timestamp_float];
-		let newer_oldest_directional_updates = client.query_raw("
+		let mut newer_oldest_directional_updates = client.query(&format!("
 			SELECT short_channel_id, CAST(EXTRACT('epoch' from distinct_chans.seen) AS BIGINT) AS seen FROM (
 				SELECT DISTINCT ON (short_channel_id) *
 				FROM (
 					SELECT DISTINCT ON (short_channel_id, direction) short_channel_id, seen
-					FROM channel_updates
-					WHERE short_channel_id = any($1)
+					FROM {}
+					WHERE short_channel_id = any($1) AND {}
 					ORDER BY short_channel_id ASC, direction ASC, seen ASC
 				) AS directional_last_seens
 				ORDER BY short_channel_id ASC, seen DESC
 			) AS distinct_chans
 			WHERE distinct_chans.seen >= TO_TIMESTAMP($2)
-			", params).await.unwrap();
-		let mut pinned_updates = Box::pin(newer_oldest_directional_updates);
+			", tables.channel_updates(), clock.seen_condition("seen")), &params).await.context("Failed to fetch first directional channel updates")?;
 
 		let mut newer_oldest_directional_update_count = 0;
-		while let Some(row_res) = pinned_updates.next().await {
-			let current_row = row_res.unwrap();
+		while let Some(row_res) = newer_oldest_directional_updates.next().await {
+			let current_row = row_res.context("Failed to read first directional channel update row")?;
 
 			let scid: i64 = current_row.get("short_channel_id");
 			let current_seen_timestamp = current_row.get::<_, i64>("seen") as u32;
@@ -228,10 +245,10 @@ pub(super) async fn fetch_channel_announcements<L: Deref>(delta_set: &mut DeltaS
 		// Steps:
 		// — Obtain all updates, distinct by (scid, direction), ordered by seen DESC
 		// — From those updates, select distinct by (scid), ordered by seen ASC (to obtain the older one per direction)
-		let reminder_threshold_timestamp = current_timestamp.checked_sub(config::CHANNEL_REMINDER_AGE.as_secs()).unwrap() as f64;
+		let reminder_threshold_timestamp = current_timestamp.saturating_sub(config::CHANNEL_REMINDER_AGE.as_secs()) as f64;
 
 		log_info!(logger, "Fetch first time we saw the current value combination for each direction (prior mutations excepted)");
-		let reminder_lookup_threshold_timestamp = current_timestamp.checked_sub(config::CHANNEL_REMINDER_AGE.as_secs() * 3).unwrap() as f64;
+		let reminder_lookup_threshold_timestamp = current_timestamp.saturating_sub(config::CHANNEL_REMINDER_AGE.as_secs() * 3) as f64;
 		let params: [&(dyn tokio_postgres::types::ToSql + Sync); 2] = [&channel_ids, &reminder_lookup_threshold_timestamp];
 
 		/*
@@ -255,7 +272,7 @@ pub(super) async fn fetch_channel_announcements<L: Deref>(delta_set: &mut DeltaS
 		3x the timeframe that we consider necessitates reminders.
 		*/
 
-		let mutated_updates = client.query_raw("
+		let mut mutated_updates = client.query(&format!("
 		SELECT DISTINCT ON (short_channel_id, direction) short_channel_id, direction, blob_signed, CAST(EXTRACT('epoch' from seen) AS BIGINT) AS seen FROM (
 			SELECT short_channel_id, direction, timestamp, seen, blob_signed, COALESCE (
 				disable<>lead(disable) OVER w1
@@ -271,24 +288,23 @@ pub(super) async fn fetch_channel_announcements<L: Deref>(delta_set: &mut DeltaS
 				htlc_maximum_msat<>lead(htlc_maximum_msat) OVER w1,
 				TRUE
 			) has_distinct_successor
-			FROM channel_updates
-			WHERE short_channel_id = any($1) AND seen >= TO_TIMESTAMP($2)
+			FROM {}
+			WHERE short_channel_id = any($1) AND seen >= TO_TIMESTAMP($2) AND {}
 			WINDOW w1 AS (PARTITION BY short_channel_id, direction ORDER BY seen DESC)
 		) _
 		WHERE has_distinct_successor
 		ORDER BY short_channel_id ASC, direction ASC, timestamp DESC
-		", params).await.unwrap();
+		", tables.channel_updates(), clock.seen_condition("seen")), &params).await.context("Failed to fetch mutated channel updates")?;
 
-		let mut pinned_updates = Box::pin(mutated_updates);
 		let mut older_latest_directional_update_count = 0;
-		while let Some(row_res) = pinned_updates.next().await {
-			let current_row = row_res.unwrap();
+		while let Some(row_res) = mutated_updates.next().await {
+			let current_row = row_res.context("Failed to read mutated channel update row")?;
 			let seen = current_row.get::<_, i64>("seen") as u32;
 
 			if seen < reminder_threshold_timestamp as u32 {
-				let blob: Vec<u8> = current_row.get("blob_signed");
+				let blob: &[u8] = current_row.get("blob_signed");
 				let mut readable = Cursor::new(blob);
-				let unsigned_channel_update = ChannelUpdate::read(&mut readable).unwrap().contents;
+				let unsigned_channel_update = ChannelUpdate::read(&mut readable).context("Failed to decode persisted channel update")?.contents;
 
 				let scid = unsigned_channel_update.short_channel_id;
 				let direction: bool = current_row.get("direction");
@@ -299,19 +315,19 @@ pub(super) async fn fetch_channel_announcements<L: Deref>(delta_set: &mut DeltaS
 				(*current_channel_delta).requires_reminder = true;
 				older_latest_directional_update_count += 1;
 
-				if let Some(current_channel_info) = network_graph.read_only().channel(scid) {
-					if current_channel_info.one_to_two.is_none() || current_channel_info.two_to_one.is_none() {
+				if let Some(current_channel_info) = compact_graph.channel(ShortChannelId::from(scid)) {
+					if !current_channel_info.is_bidirectional() {
 						// we don't send reminders if we don't have bidirectional update data
 						continue;
 					}
 
-					if let Some(info) = current_channel_info.one_to_two.as_ref() {
+					if let Some(info) = current_channel_info.direction_0.as_ref() {
 						let flags: u8 = if info.enabled { 0 } else { 2 };
 						let current_update = (*current_channel_delta).updates.0.get_or_insert(DirectedUpdateDelta::default());
 						current_update.serialization_update_flags = Some(flags);
 					}
 
-					if let Some(info) = current_channel_info.two_to_one.as_ref() {
+					if let Some(info) = current_channel_info.direction_1.as_ref() {
 						let flags: u8 = if info.enabled { 1 } else { 3 };
 						let current_update = (*current_channel_delta).updates.1.get_or_insert(DirectedUpdateDelta::default());
 						current_update.serialization_update_flags = Some(flags);
@@ -326,29 +342,31 @@ pub(super) async fn fetch_channel_announcements<L: Deref>(delta_set: &mut DeltaS
 		}
 		log_info!(logger, "Fetched {} update rows of the latest update in the less recently updated direction", older_latest_directional_update_count);
 	}
+	Ok(())
 }
 
-pub(super) async fn fetch_channel_updates<L: Deref>(delta_set: &mut DeltaSet, client: &Client, last_sync_timestamp: u32, logger: L) where L::Target: Logger {
+/// Fetch the updates a client synced up to `last_sync_timestamp` needs, returning how many of the
+/// latest ones carry a substituted HTLC maximum
+pub(super) async fn fetch_channel_updates<L: Deref>(delta_set: &mut DeltaSet, client: &LookupClient, tables: &Tables, last_sync_timestamp: u32, clock: SnapshotClock, logger: L) -> Result<usize, ProcessorError> where L::Target: Logger {
 	let start = Instant::now();
 	let last_sync_timestamp_float = last_sync_timestamp as f64;
 
 	// get the latest channel update in each direction prior to last_sync_timestamp, provided
 	// there was an update in either direction that happened after the last sync (to avoid
 	// collecting too many reference updates)
-	let reference_rows = client.query_raw("
-		SELECT id, direction, CAST(EXTRACT('epoch' from seen) AS BIGINT) AS seen, blob_signed FROM channel_updates
+	let mut reference_rows = client.query(&format!("
+		SELECT id, direction, CAST(EXTRACT('epoch' from seen) AS BIGINT) AS seen, blob_signed, htlc_maximum_msat FROM {channel_updates}
 		WHERE id IN (
 			SELECT DISTINCT ON (short_channel_id, direction) id
-			FROM channel_updates
+			FROM {channel_updates}
 			WHERE seen < TO_TIMESTAMP($1) AND short_channel_id IN (
 				SELECT DISTINCT ON (short_channel_id) short_channel_id
-				FROM channel_updates
-				WHERE seen >= TO_TIMESTAMP($1)
+				FROM {channel_updates}
+				WHERE seen >= TO_TIMESTAMP($1) AND {seen_condition}
 			)
 			ORDER BY short_channel_id ASC, direction ASC, seen DESC
 		)
-		", [last_sync_timestamp_float]).await.unwrap();
-	let mut pinned_rows = Box::pin(reference_rows);
+		", channel_updates = tables.channel_updates(), seen_condition = clock.seen_condition("seen")), &[&last_sync_timestamp_float]).await.context("Failed to fetch reference channel updates")?;
 
 	log_info!(logger, "Fetched reference rows in {:?}", start.elapsed());
 
@@ -356,17 +374,15 @@ pub(super) async fn fetch_channel_updates<L: Deref>(delta_set: &mut DeltaSet, cl
 	let mut non_intermediate_ids: HashSet<i32> = HashSet::new();
 	let mut reference_row_count = 0;
 
-	while let Some(row_res) = pinned_rows.next().await {
-		let current_reference = row_res.unwrap();
+	while let Some(row_res) = reference_rows.next().await {
+		let current_reference = row_res.context("Failed to read reference channel update row")?;
 		let update_id: i32 = current_reference.get("id");
 		last_seen_update_ids.push(update_id);
 		non_intermediate_ids.insert(update_id);
 
 		let direction: bool = current_reference.get("direction");
 		let seen = current_reference.get::<_, i64>("seen") as u32;
-		let blob: Vec<u8> = current_reference.get("blob_signed");
-		let mut readable = Cursor::new(blob);
-		let unsigned_channel_update = ChannelUpdate::read(&mut readable).unwrap().contents;
+		let (unsigned_channel_update, _) = decode_persisted_update(&current_reference)?;
 		let scid = unsigned_channel_update.short_channel_id;
 
 		let current_channel_delta = delta_set.entry(scid).or_insert(ChannelDelta::default());
@@ -387,25 +403,31 @@ pub(super) async fn fetch_channel_updates<L: Deref>(delta_set: &mut DeltaSet, cl
 	log_info!(logger, "Processed {} reference rows (delta size: {}) in {:?}",
 		reference_row_count, delta_set.len(), start.elapsed());
 
+	let coverage = fetch_reference_coverage(client, tables, last_sync_timestamp).await?;
+	if let Some(coverage) = coverage {
+		log_debug!(logger, "Client reference {} covers updates up to {}", last_sync_timestamp, coverage);
+	}
+
 	// get all the intermediate channel updates
 	// (to calculate the set of mutated fields for snapshotting, where intermediate updates may
 	// have been omitted)
 
-	let intermediate_updates = client.query_raw("
-		SELECT id, direction, blob_signed, CAST(EXTRACT('epoch' from seen) AS BIGINT) AS seen
-		FROM channel_updates
-		WHERE seen >= TO_TIMESTAMP($1)
+	let mut intermediate_updates = client.query(&format!("
+		SELECT id, direction, blob_signed, htlc_maximum_msat, CAST(EXTRACT('epoch' from seen) AS BIGINT) AS seen
+		FROM {}
+		WHERE seen >= TO_TIMESTAMP($1) AND {}
 		ORDER BY short_channel_id ASC, timestamp DESC
-		", [last_sync_timestamp_float]).await.unwrap();
-	let mut pinned_updates = Box::pin(intermediate_updates);
+		", tables.channel_updates(), clock.seen_condition("seen")), &[&last_sync_timestamp_float]).await.context("Failed to fetch intermediate channel updates")?;
 	log_info!(logger, "Fetched intermediate rows in {:?}", start.elapsed());
 
 	let mut previous_scid = u64::MAX;
 	let mut previously_seen_directions = (false, false);
 
 	let mut intermediate_update_count = 0;
-	while let Some(row_res) = pinned_updates.next().await {
-		let intermediate_update = row_res.unwrap();
+	let mut backfilled_update_count = 0;
+	let mut htlc_maximum_substitution_count = 0;
+	while let Some(row_res) = intermediate_updates.next().await {
+		let intermediate_update = row_res.context("Failed to read intermediate channel update row")?;
 		let update_id: i32 = intermediate_update.get("id");
 		if non_intermediate_ids.contains(&update_id) {
 			continue;
@@ -414,9 +436,7 @@ pub(super) async fn fetch_channel_updates<L: Deref>(delta_set: &mut DeltaSet, cl
 
 		let direction: bool = intermediate_update.get("direction");
 		let current_seen_timestamp = intermediate_update.get::<_, i64>("seen") as u32;
-		let blob: Vec<u8> = intermediate_update.get("blob_signed");
-		let mut readable = Cursor::new(blob);
-		let unsigned_channel_update = ChannelUpdate::read(&mut readable).unwrap().contents;
+		let (unsigned_channel_update, is_htlc_maximum_substituted) = decode_persisted_update(&intermediate_update)?;
 
 		let scid = unsigned_channel_update.short_channel_id;
 		if scid != previous_scid {
@@ -432,74 +452,97 @@ pub(super) async fn fetch_channel_updates<L: Deref>(delta_set: &mut DeltaSet, cl
 			(*current_channel_delta).updates.1.get_or_insert(DirectedUpdateDelta::default())
 		};
 
-		{
-			// handle the latest deltas
-			if !direction && !previously_seen_directions.0 {
-				previously_seen_directions.0 = true;
-				update_delta.latest_update_after_seen = Some(UpdateDelta {
-					seen: current_seen_timestamp,
-					update: unsigned_channel_update.clone(),
-				});
-			} else if direction && !previously_seen_directions.1 {
-				previously_seen_directions.1 = true;
-				update_delta.latest_update_after_seen = Some(UpdateDelta {
-					seen: current_seen_timestamp,
-					update: unsigned_channel_update.clone(),
-				});
+		if let Some(coverage) = coverage {
+			// Rows that were seen late, e. g. because they were backfilled, but whose gossip is no
+			// newer than what the client already has for this direction, would only be rejected as
+			// stale. As rows are ordered by timestamp, any older rows for this direction follow.
+			let is_superseded = update_delta.last_update_before_seen.as_ref()
+				.map_or(false, |reference| unsigned_channel_update.timestamp <= reference.update.timestamp);
+			if is_superseded && unsigned_channel_update.timestamp <= coverage {
+				backfilled_update_count += 1;
+				continue;
 			}
 		}
 
-		// determine mutations
+		// determine mutations, against the update the client knows, before the update itself may be
+		// moved into the delta
 		if let Some(last_seen_update) = update_delta.last_update_before_seen.as_ref() {
-			if unsigned_channel_update.flags != last_seen_update.update.flags {
-				update_delta.mutated_properties.flags = true;
-			}
-			if unsigned_channel_update.cltv_expiry_delta != last_seen_update.update.cltv_expiry_delta {
-				update_delta.mutated_properties.cltv_expiry_delta = true;
-			}
-			if unsigned_channel_update.htlc_minimum_msat != last_seen_update.update.htlc_minimum_msat {
-				update_delta.mutated_properties.htlc_minimum_msat = true;
-			}
-			if unsigned_channel_update.fee_base_msat != last_seen_update.update.fee_base_msat {
-				update_delta.mutated_properties.fee_base_msat = true;
-			}
-			if unsigned_channel_update.fee_proportional_millionths != last_seen_update.update.fee_proportional_millionths {
-				update_delta.mutated_properties.fee_proportional_millionths = true;
-			}
-			if unsigned_channel_update.htlc_maximum_msat != last_seen_update.update.htlc_maximum_msat {
-				update_delta.mutated_properties.htlc_maximum_msat = true;
-			}
+			update_delta.mutated_properties.record_changes(&last_seen_update.update, &unsigned_channel_update);
+		}
+
+		// handle the latest deltas
+		let is_latest_for_direction = if !direction { &mut previously_seen_directions.0 } else { &mut previously_seen_directions.1 };
+		if !*is_latest_for_direction {
+			*is_latest_for_direction = true;
+			update_delta.latest_update_after_seen = Some(UpdateDelta {
+				seen: current_seen_timestamp,
+				update: unsigned_channel_update,
+			});
+			htlc_maximum_substitution_count += is_htlc_maximum_substituted as usize;
 		}
 	}
 	log_info!(logger, "Processed intermediate rows ({}) (delta size: {}): {:?}", intermediate_update_count, delta_set.len(), start.elapsed());
+	if backfilled_update_count > 0 {
+		log_info!(logger, "Skipped {} backfilled channel updates superseded by ones the client already has", backfilled_update_count);
+	}
+	Ok(htlc_maximum_substitution_count)
+}
+
+/// Decode a persisted channel update, going by its persisted HTLC maximum rather than the signed
+/// one, which differ where an absent maximum was substituted. Also returns whether it was.
+fn decode_persisted_update(row: &Row) -> Result<(UnsignedChannelUpdate, bool), ProcessorError> {
+	let blob: &[u8] = row.get("blob_signed");
+	let mut update = ChannelUpdate::read(&mut Cursor::new(blob)).context("Failed to decode persisted channel update")?.contents;
+	let persisted_htlc_maximum_msat = row.get::<_, i64>("htlc_maximum_msat") as u64;
+	let is_substituted = persisted_htlc_maximum_msat != update.htlc_maximum_msat;
+	update.htlc_maximum_msat = persisted_htlc_maximum_msat;
+	Ok((update, is_substituted))
 }
 
-pub(super) async fn fetch_node_updates<L: Deref>(client: &Client, last_sync_timestamp: u32, logger: L) -> NodeDeltaSet where L::Target: Logger {
+/// The newest gossip timestamp a client synced up to `last_sync_timestamp` can have, per the
+/// coverage recorded for the latest snapshot bucket at or before it. Earlier buckets cover less,
+/// so falling back to them never makes the delta skip anything the client lacks.
+async fn fetch_reference_coverage(client: &LookupClient, tables: &Tables, last_sync_timestamp: u32) -> Result<Option<u32>, ProcessorError> {
+	if last_sync_timestamp == 0 {
+		return Ok(None);
+	}
+	let mut rows = client.query(&format!("
+		SELECT newest_update_timestamp FROM {}
+		WHERE reference_timestamp <= $1
+		ORDER BY reference_timestamp DESC
+		LIMIT 1
+		", tables.snapshot_coverage()), &[&(last_sync_timestamp as i64)]).await.context("Failed to fetch snapshot coverage")?;
+	match rows.next().await {
+		Some(row) => Ok(Some(row.context("Failed to read snapshot coverage row")?.get::<_, i64>(0) as u32)),
+		None => Ok(None),
+	}
+}
+
+pub(super) async fn fetch_node_updates<L: Deref>(client: &LookupClient, tables: &Tables, last_sync_timestamp: u32, clock: SnapshotClock, logger: L) -> Result<NodeDeltaSet, ProcessorError> where L::Target: Logger {
 	let start = Instant::now();
 	let last_sync_timestamp_float = last_sync_timestamp as f64;
 
 	let mut delta_set = NodeDeltaSet::new();
 
 	// get the latest node updates prior to last_sync_timestamp
-	let reference_rows = client.query_raw("
+	let mut reference_rows = client.query(&format!("
 		SELECT DISTINCT ON (public_key) public_key, CAST(EXTRACT('epoch' from seen) AS BIGINT) AS seen, announcement_signed
-		FROM node_announcements
+		FROM {}
 		WHERE seen < TO_TIMESTAMP($1)
 		ORDER BY public_key ASC, seen DESC
-		", [last_sync_timestamp_float]).await.unwrap();
-	let mut pinned_rows = Box::pin(reference_rows);
+		", tables.node_announcements()), &[&last_sync_timestamp_float]).await.context("Failed to fetch reference node announcements")?;
 
 	log_info!(logger, "Fetched node announcement reference rows in {:?}", start.elapsed());
 
 	let mut reference_row_count = 0;
 
-	while let Some(row_res) = pinned_rows.next().await {
-		let current_reference = row_res.unwrap();
+	while let Some(row_res) = reference_rows.next().await {
+		let current_reference = row_res.context("Failed to read reference node announcement row")?;
 
 		let seen = current_reference.get::<_, i64>("seen") as u32;
-		let blob: Vec<u8> = current_reference.get("announcement_signed");
+		let blob: &[u8] = current_reference.get("announcement_signed");
 		let mut readable = Cursor::new(blob);
-		let unsigned_node_announcement = NodeAnnouncement::read(&mut readable).unwrap().contents;
+		let unsigned_node_announcement = NodeAnnouncement::read(&mut readable).context("Failed to decode persisted node announcement")?.contents;
 		let node_id = unsigned_node_announcement.node_id;
 
 		let current_node_delta = delta_set.entry(node_id).or_insert(NodeDelta::default());
@@ -520,87 +563,97 @@ pub(super) async fn fetch_node_updates<L: Deref>(client: &Client, last_sync_time
 	log_info!(logger, "Processed {} node announcement reference rows (delta size: {}) in {:?}",
 		reference_row_count, delta_set.len(), start.elapsed());
 
-	// get all the intermediate node updates
-	// (to calculate the set of mutated fields for snapshotting, where intermediate updates may
-	// have been omitted)
-	let intermediate_updates = client.query_raw("
-		SELECT announcement_signed, CAST(EXTRACT('epoch' from seen) AS BIGINT) AS seen
-		FROM node_announcements
-		WHERE seen >= TO_TIMESTAMP($1)
-		ORDER BY public_key ASC, timestamp DESC
-		", [last_sync_timestamp_float]).await.unwrap();
-	let mut pinned_updates = Box::pin(intermediate_updates);
-	log_info!(logger, "Fetched intermediate node announcement rows in {:?}", start.elapsed());
-
-	let mut previous_node_id: Option<NodeId> = None;
-
-	let mut intermediate_update_count = 0;
-	while let Some(row_res) = pinned_updates.next().await {
-		let intermediate_update = row_res.unwrap();
-		intermediate_update_count += 1;
-
-		let current_seen_timestamp = intermediate_update.get::<_, i64>("seen") as u32;
-		let blob: Vec<u8> = intermediate_update.get("announcement_signed");
+	// get the latest node updates since last_sync_timestamp
+	// (only the latest one matters: if intermediate updates were reverted, the client doesn't need
+	// to know about them)
+	let mut latest_updates = client.query(&format!("
+		SELECT DISTINCT ON (public_key) announcement_signed, CAST(EXTRACT('epoch' from seen) AS BIGINT) AS seen
+		FROM {}
+		WHERE seen >= TO_TIMESTAMP($1) AND {}
+		ORDER BY public_key ASC, timestamp DESC, seen DESC
+		", tables.node_announcements(), clock.seen_condition("seen")), &[&last_sync_timestamp_float]).await.context("Failed to fetch latest node announcements")?;
+	log_info!(logger, "Fetched latest node announcement rows in {:?}", start.elapsed());
+
+	let mut latest_update_count = 0;
+	while let Some(row_res) = latest_updates.next().await {
+		let latest_update = row_res.context("Failed to read latest node announcement row")?;
+		latest_update_count += 1;
+
+		let current_seen_timestamp = latest_update.get::<_, i64>("seen") as u32;
+		let blob: &[u8] = latest_update.get("announcement_signed");
 		let mut readable = Cursor::new(blob);
-		let unsigned_node_announcement = NodeAnnouncement::read(&mut readable).unwrap().contents;
+		let unsigned_node_announcement = NodeAnnouncement::read(&mut readable).context("Failed to decode persisted node announcement")?.contents;
 
 		let node_id = unsigned_node_announcement.node_id;
-		let is_previously_processed_node_id = Some(node_id) == previous_node_id;
-
-		// get this node's address set
 		let current_node_delta = delta_set.entry(node_id).or_insert(NodeDelta::default());
 		let address_set: HashSet<SocketAddress> = unsigned_node_announcement.addresses.into_iter().collect();
 
 		// determine mutations
 		if let Some(last_seen_update) = current_node_delta.last_details_before_seen.as_ref() {
-			if unsigned_node_announcement.features != last_seen_update.features {
-				current_node_delta.has_feature_set_changed = true;
-			}
-			if address_set != last_seen_update.addresses {
-				current_node_delta.has_address_set_changed = true;
-			}
-		} else if !is_previously_processed_node_id {
-			if current_node_delta.last_details_before_seen.is_none() {
-				if !address_set.is_empty() {
-					current_node_delta.has_address_set_changed = true;
-				}
-				if unsigned_node_announcement.features != NodeFeatures::empty() {
-					current_node_delta.has_feature_set_changed = true;
-				}
-			}
-		}
-
-		if !is_previously_processed_node_id {
-			(*current_node_delta).latest_details_after_seen.get_or_insert(NodeDetails {
-				seen: current_seen_timestamp,
-				features: unsigned_node_announcement.features,
-				addresses: address_set,
-			});
+			current_node_delta.has_feature_set_changed = unsigned_node_announcement.features != last_seen_update.features;
+			current_node_delta.has_address_set_changed = address_set != last_seen_update.addresses;
+		} else {
+			// the client has never seen this node, so only non-default details need to be sent
+			current_node_delta.has_feature_set_changed = unsigned_node_announcement.features != NodeFeatures::empty();
+			current_node_delta.has_address_set_changed = !address_set.is_empty();
 		}
 
-		previous_node_id = Some(node_id);
+		current_node_delta.latest_details_after_seen = Some(NodeDetails {
+			seen: current_seen_timestamp,
+			features: unsigned_node_announcement.features,
+			addresses: address_set,
+		});
+	}
+	log_info!(logger, "Processed latest node announcement rows ({}) (delta size: {}): {:?}", latest_update_count, delta_set.len(), start.elapsed());
+
+	if last_sync_timestamp == 0 {
+		// nodes that stopped re-broadcasting their announcement have likely gone away for good
+		let current_timestamp = clock.now();
+		let staleness_cutoff = current_timestamp.saturating_sub(config::NODE_ANNOUNCEMENT_MAX_AGE.as_secs());
+		let original_length = delta_set.len();
+		delta_set.retain(|_id, delta| {
+			delta.latest_details_after_seen.as_ref().map_or(false, |details| details.seen as u64 >= staleness_cutoff)
+		});
+		log_info!(logger, "Omitted {} stale nodes from full snapshot", original_length - delta_set.len());
 	}
-	log_info!(logger, "Processed intermediate node announcement rows ({}) (delta size: {}): {:?}", intermediate_update_count, delta_set.len(), start.elapsed());
 
-	delta_set
+	Ok(delta_set)
+}
+
+/// Omit channels from a full snapshot unless both directions have been updated within the horizon,
+/// returning how many were omitted. Clients prune channels missing a recent update in either
+/// direction, so sending them would only be wasted bytes. Deltas can't express removals, so they
+/// must keep including such channels' updates.
+pub(super) fn omit_stale_channels<L: Deref>(delta_set: &mut DeltaSet, clock: SnapshotClock, horizon: Duration, logger: L) -> usize where L::Target: Logger {
+	let current_timestamp = clock.now();
+	let staleness_cutoff = current_timestamp.saturating_sub(horizon.as_secs());
+	// like LDK, judge by the updates' own timestamps rather than when we received them
+	let is_direction_fresh = |update: &Option<DirectedUpdateDelta>| {
+		update.as_ref()
+			.and_then(|update| update.latest_update_after_seen.as_ref())
+			.map_or(false, |latest| latest.update.timestamp as u64 >= staleness_cutoff)
+	};
+
+	let original_length = delta_set.len();
+	delta_set.retain(|_scid, delta| is_direction_fresh(&delta.updates.0) && is_direction_fresh(&delta.updates.1));
+	let omitted_count = original_length - delta_set.len();
+	log_info!(logger, "Omitted {} stale channels from full snapshot", omitted_count);
+	omitted_count
 }
 
 pub(super) fn filter_delta_set<L: Deref>(delta_set: &mut DeltaSet, logger: L) where L::Target: Logger {
 	let original_length = delta_set.len();
-	let keys: Vec<u64> = delta_set.keys().cloned().collect();
-	for k in keys {
-		let v = delta_set.get(&k).unwrap();
+	delta_set.retain(|_, v| {
 		if v.announcement.is_none() {
 			// this channel is not currently in the network graph
-			delta_set.remove(&k);
-			continue;
+			return false;
 		}
 
 		let update_meets_criteria = |update: &Option<DirectedUpdateDelta>| {
-			if update.is_none() {
-				return false;
+			let update_reference = match update {
+				Some(update_reference) => update_reference,
+				None => return false,
 			};
-			let update_reference = update.as_ref().unwrap();
 			// update_reference.latest_update_after_seen.is_some() && !update_reference.intermediate_updates.is_empty()
 			// if there has been an update after the channel was first seen
 
@@ -610,13 +663,39 @@ pub(super) fn filter_delta_set<L: Deref>(delta_set: &mut DeltaSet, logger: L) wh
 		let direction_a_meets_criteria = update_meets_criteria(&v.updates.0);
 		let direction_b_meets_criteria = update_meets_criteria(&v.updates.1);
 
-		if !v.requires_reminder && !direction_a_meets_criteria && !direction_b_meets_criteria {
-			delta_set.remove(&k);
-		}
-	}
+		v.requires_reminder || direction_a_meets_criteria || direction_b_meets_criteria
+	});
 
 	let new_length = delta_set.len();
 	if original_length != new_length {
 		log_info!(logger, "length modified!");
 	}
 }
+
+/// Fetch a persisted message exactly as it was signed and received, without the type prefix. Every
+/// message is persisted along with its signed encoding, but the announcement tables' columns are
+/// nullable, so rows lacking one yield `None` just like unknown messages. Should a message have been
+/// persisted repeatedly, the most recently received copy is returned.
+pub(crate) async fn fetch_signed_message(client: &Client, tables: &Tables, query: &SignedGossipQuery) -> Result<Option<Vec<u8>>, ProcessorError> {
+	let row = match query {
+		SignedGossipQuery::ChannelAnnouncement { short_channel_id } => {
+			client.query_opt(&format!("SELECT announcement_signed FROM {} WHERE short_channel_id = $1", tables.channel_announcements()),
+				&[&(*short_channel_id as i64)]).await
+		},
+		SignedGossipQuery::ChannelUpdate { short_channel_id, direction, timestamp } => {
+			client.query_opt(&format!("
+				SELECT blob_signed FROM {}
+				WHERE short_channel_id = $1 AND direction = $2 AND timestamp = $3
+				ORDER BY seen DESC, id DESC LIMIT 1
+				", tables.channel_updates()), &[&(*short_channel_id as i64), direction, &(*timestamp as i64)]).await
+		},
+		SignedGossipQuery::NodeAnnouncement { node_id, timestamp } => {
+			client.query_opt(&format!("
+				SELECT announcement_signed FROM {}
+				WHERE public_key = $1 AND timestamp = $2
+				ORDER BY seen DESC, id DESC LIMIT 1
+				", tables.node_announcements()), &[&node_id.as_slice(), &(*timestamp as i64)]).await
+		},
+	}.context(format!("Failed to fetch signed message for {:?}", query))?;
+	Ok(row.and_then(|row| row.get::<_, Option<Vec<u8>>>(0)))
+}
@@ -1,9 +1,211 @@
+use std::env;
+use std::process;
 use std::sync::Arc;
+use hex_conservative::FromHex;
+use lightning::log_warn;
+use lightning::util::logger::Logger;
 use rapid_gossip_sync_server::RapidSyncProcessor;
 use rapid_gossip_sync_server::types::RGSSLogger;
 
+const USAGE: &str = "Usage: rapid-gossip-sync-server [stats (--since <date> [--csv] | --new-channels [--json]) | export-parquet --output <path> | dump-graph [--anonymize --seed <hex>] | validate <file> [--json] | verify-chain <previous full snapshot> <delta> <full snapshot> [--json] | generate --as-of <timestamp> --output <path>]";
+
 #[tokio::main]
 async fn main() {
+	let args: Vec<String> = env::args().skip(1).collect();
+	match args.first().map(|arg| arg.as_str()) {
+		None => {
+			let logger = Arc::new(RGSSLogger::new());
+			let mut processor = RapidSyncProcessor::new(logger.clone());
+			match rapid_gossip_sync_server::load_configured_backend_plugin() {
+				Ok(Some(backend_plugin)) => processor.set_backend_plugin(backend_plugin),
+				Ok(None) => {},
+				Err(error) => log_warn!(logger, "{}, persisting to the database only", error),
+			}
+			let result = processor.start_sync().await;
+			rapid_gossip_sync_server::exit_after_stopping(result, logger);
+		},
+		Some("stats") => print_stats_history(&args[1..]).await,
+		Some("export-parquet") => export_parquet(&args[1..]).await,
+		Some("dump-graph") => dump_graph(&args[1..]),
+		Some("validate") => validate_snapshot(&args[1..]),
+		Some("verify-chain") => verify_delta_chain(&args[1..]),
+		Some("generate") => generate_snapshots_as_of(&args[1..]).await,
+		Some(_) => {
+			eprintln!("{}", USAGE);
+			process::exit(1);
+		}
+	}
+}
+
+async fn print_stats_history(args: &[String]) {
+	let mut since = None;
+	let mut csv = false;
+	let mut new_channels = false;
+	let mut json = false;
+	let mut args = args.iter();
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"--since" => since = args.next(),
+			"--csv" => csv = true,
+			"--new-channels" => new_channels = true,
+			"--json" => json = true,
+			_ => {
+				eprintln!("{}", USAGE);
+				process::exit(1);
+			}
+		}
+	}
+	if new_channels {
+		if since.is_some() || csv {
+			eprintln!("{}", USAGE);
+			process::exit(1);
+		}
+		match rapid_gossip_sync_server::new_channel_report(json).await {
+			Ok(report) => print!("{}", report),
+			Err(error) => {
+				eprintln!("Failed to read new channel stats: {}", error);
+				process::exit(1);
+			}
+		}
+		return;
+	}
+	let since = match since {
+		Some(since) if !json => since,
+		_ => {
+			eprintln!("{}", USAGE);
+			process::exit(1);
+		}
+	};
+
+	match rapid_gossip_sync_server::stats_history_report(since, csv).await {
+		Ok(report) => print!("{}", report),
+		Err(error) => {
+			eprintln!("Failed to read stats history: {}", error);
+			process::exit(1);
+		}
+	}
+}
+
+async fn export_parquet(args: &[String]) {
+	let output = match args {
+		[flag, output] if flag == "--output" => output,
+		_ => {
+			eprintln!("{}", USAGE);
+			process::exit(1);
+		}
+	};
+
+	let logger = Arc::new(RGSSLogger::new());
+	if let Err(error) = rapid_gossip_sync_server::export_cached_graph_parquet(output, logger).await {
+		eprintln!("Failed to export network graph: {}", error);
+		process::exit(1);
+	}
+}
+
+fn dump_graph(args: &[String]) {
+	let mut anonymize = false;
+	let mut seed = None;
+	let mut args = args.iter();
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"--anonymize" => anonymize = true,
+			"--seed" => seed = args.next().and_then(|seed| <[u8; 32]>::from_hex(seed).ok()),
+			_ => {
+				eprintln!("{}", USAGE);
+				process::exit(1);
+			}
+		}
+	}
+	// the seed must be given along with --anonymize, lest a dump meant to be anonymous isn't
+	let seed = match (anonymize, seed) {
+		(true, Some(seed)) => Some(seed),
+		(false, None) => None,
+		_ => {
+			eprintln!("{}", USAGE);
+			eprintln!("--anonymize requires a --seed of 32 hex-encoded bytes");
+			process::exit(1);
+		}
+	};
+
+	let logger = Arc::new(RGSSLogger::new());
+	match rapid_gossip_sync_server::dump_cached_graph(seed.as_ref(), logger) {
+		Ok(dump) => println!("{}", dump),
+		Err(error) => {
+			eprintln!("Failed to dump network graph: {}", error);
+			process::exit(1);
+		}
+	}
+}
+
+fn validate_snapshot(args: &[String]) {
+	let (path, json) = match args {
+		[path] => (path, false),
+		[path, flag] | [flag, path] if flag == "--json" => (path, true),
+		_ => {
+			eprintln!("{}", USAGE);
+			process::exit(1);
+		}
+	};
+
+	match rapid_gossip_sync_server::summarize_snapshot(path, json) {
+		Ok(summary) => print!("{}", summary),
+		Err(error) => {
+			eprintln!("Invalid snapshot {}: {}", path, error);
+			process::exit(1);
+		}
+	}
+}
+
+fn verify_delta_chain(args: &[String]) {
+	let json = args.iter().any(|arg| arg == "--json");
+	let paths: Vec<&String> = args.iter().filter(|arg| *arg != "--json").collect();
+	let (previous_full_snapshot, delta, full_snapshot) = match paths[..] {
+		[previous_full_snapshot, delta, full_snapshot] => (previous_full_snapshot, delta, full_snapshot),
+		_ => {
+			eprintln!("{}", USAGE);
+			process::exit(1);
+		}
+	};
+
+	match rapid_gossip_sync_server::verify_delta_chain(previous_full_snapshot, delta, full_snapshot) {
+		Ok(report) => {
+			print!("{}", if json { report.to_json() } else { report.to_text() });
+			if !report.is_intact() {
+				process::exit(1);
+			}
+		},
+		Err(error) => {
+			eprintln!("Failed to verify the delta chain: {}", error);
+			process::exit(2);
+		}
+	}
+}
+
+async fn generate_snapshots_as_of(args: &[String]) {
+	let mut as_of = None;
+	let mut output = None;
+	let mut args = args.iter();
+	while let Some(arg) = args.next() {
+		match arg.as_str() {
+			"--as-of" => as_of = args.next().and_then(|as_of| as_of.parse::<u64>().ok()),
+			"--output" => output = args.next(),
+			_ => {
+				eprintln!("{}", USAGE);
+				process::exit(1);
+			}
+		}
+	}
+	let (as_of, output) = match (as_of, output) {
+		(Some(as_of), Some(output)) => (as_of, output),
+		_ => {
+			eprintln!("{}", USAGE);
+			process::exit(1);
+		}
+	};
+
 	let logger = Arc::new(RGSSLogger::new());
-	RapidSyncProcessor::new(logger).start_sync().await;
+	if let Err(error) = rapid_gossip_sync_server::generate_snapshots_as_of(as_of, output, logger).await {
+		eprintln!("Failed to generate snapshots as of {}: {}", as_of, error);
+		process::exit(1);
+	}
 }
@@ -0,0 +1,205 @@
+//! Optional upkeep of the gossip tables, whose indexes bloat under months of churn.
+//!
+//! Managed Postgres offerings may restrict the commands involved, so nothing here runs unless
+//! configured, and failures are only logged.
+
+use std::ops::Deref;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lightning::{log_info, log_warn};
+use lightning::util::logger::Logger;
+use tokio_postgres::GenericClient;
+
+use crate::snapshot;
+use crate::tables::Tables;
+
+/// How often the persister checks whether maintenance is due
+const CHECK_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Maintenance runs at most once a week
+const MAINTENANCE_INTERVAL_SECS: u64 = 7 * 24 * 3600;
+
+/// The hours of the day, in UTC, during which maintenance may start
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct MaintenanceWindow {
+	start_hour: u8,
+	/// The first hour after the window, which is less than the start hour if the window spans
+	/// midnight
+	end_hour: u8,
+}
+
+impl MaintenanceWindow {
+	/// Parse a window given as `<start hour>-<end hour>`, such as `3-5` for 03:00 to 05:00 UTC
+	pub(crate) fn parse(window: &str) -> Option<Self> {
+		let (start_hour, end_hour) = window.trim().split_once('-')?;
+		let start_hour = start_hour.trim().parse::<u8>().ok().filter(|hour| *hour < 24)?;
+		let end_hour = end_hour.trim().parse::<u8>().ok().filter(|hour| *hour <= 24)? % 24;
+		if start_hour == end_hour {
+			return None;
+		}
+		Some(Self { start_hour, end_hour })
+	}
+
+	pub(crate) fn contains(&self, timestamp: u64) -> bool {
+		let hour = (timestamp / 3600 % 24) as u8;
+		if self.start_hour < self.end_hour {
+			self.start_hour <= hour && hour < self.end_hour
+		} else {
+			hour >= self.start_hour || hour < self.end_hour
+		}
+	}
+}
+
+/// The size and dead rows of a gossip table, according to Postgres' statistics
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct TableBloat {
+	pub(crate) table: String,
+	pub(crate) live_rows: i64,
+	pub(crate) dead_rows: i64,
+	pub(crate) table_bytes: i64,
+	pub(crate) index_bytes: i64,
+}
+
+impl TableBloat {
+	pub(crate) fn dead_row_percent(&self) -> f64 {
+		let total_rows = self.live_rows + self.dead_rows;
+		if total_rows <= 0 {
+			return 0.0;
+		}
+		self.dead_rows as f64 * 100.0 / total_rows as f64
+	}
+
+	/// The index size per live row, which grows as the indexes bloat
+	pub(crate) fn index_bytes_per_row(&self) -> i64 {
+		self.index_bytes / self.live_rows.max(1)
+	}
+
+	pub(crate) fn to_text(&self) -> String {
+		format!("{}: {} live rows, {} dead rows ({:.1}%), {} MiB of data, {} MiB of indexes ({} bytes per row)",
+			self.table, self.live_rows, self.dead_rows, self.dead_row_percent(), self.table_bytes / (1 << 20), self.index_bytes / (1 << 20), self.index_bytes_per_row())
+	}
+}
+
+fn gossip_tables(tables: &Tables) -> [String; 3] {
+	[tables.channel_announcements(), tables.channel_updates(), tables.node_announcements()]
+}
+
+/// Estimate the bloat of the gossip tables from the statistics Postgres keeps anyway, which is far
+/// cheaper than inspecting the tables and indexes themselves
+pub(crate) async fn estimate_bloat<C: GenericClient>(client: &C, tables: &Tables) -> Result<Vec<TableBloat>, tokio_postgres::Error> {
+	let mut bloat = Vec::new();
+	for table in gossip_tables(tables) {
+		let row = client.query_opt("SELECT n_live_tup, n_dead_tup, pg_relation_size(relid), pg_indexes_size(relid) \
+			FROM pg_stat_user_tables WHERE relid = to_regclass($1)", &[&table]).await?;
+		if let Some(row) = row {
+			bloat.push(TableBloat { table, live_rows: row.get(0), dead_rows: row.get(1), table_bytes: row.get(2), index_bytes: row.get(3) });
+		}
+	}
+	Ok(bloat)
+}
+
+/// The unix timestamp of the least recent manual vacuum of any gossip table, which is 0 if one of
+/// them was never vacuumed manually
+async fn last_maintained_at<C: GenericClient>(client: &C, tables: &Tables) -> Result<u64, tokio_postgres::Error> {
+	let [announcements, updates, node_announcements] = gossip_tables(tables);
+	let row = client.query_one("SELECT COALESCE(MIN(COALESCE(EXTRACT(EPOCH FROM last_vacuum), 0)), 0)::bigint \
+		FROM pg_stat_user_tables WHERE relid IN (to_regclass($1), to_regclass($2), to_regclass($3))", &[&announcements, &updates, &node_announcements]).await?;
+	Ok(row.get::<_, i64>(0).max(0) as u64)
+}
+
+/// Rebuild the gossip tables' indexes without blocking writes, and vacuum and analyze the tables.
+/// Should a snapshot generation start in the meantime, the remaining tables are left for the next
+/// window.
+async fn maintain<C: GenericClient, L: Deref>(client: &C, tables: &Tables, logger: &L) where L::Target: Logger {
+	for table in gossip_tables(tables) {
+		if snapshot::is_generation_in_progress() {
+			log_info!(logger, "Postponing maintenance of {} while snapshots are being generated", table);
+			return;
+		}
+		log_info!(logger, "Reindexing and vacuuming {}", table);
+		// neither command may run inside a transaction, so each is sent on its own
+		if let Err(error) = client.batch_execute(&format!("REINDEX TABLE CONCURRENTLY {}", table)).await {
+			log_warn!(logger, "Failed to reindex {}: {}", table, error);
+		}
+		if let Err(error) = client.batch_execute(&format!("VACUUM (ANALYZE) {}", table)).await {
+			log_warn!(logger, "Failed to vacuum {}: {}", table, error);
+		}
+	}
+	log_info!(logger, "Finished database maintenance");
+}
+
+/// Check every ten minutes whether the gossip tables are due for maintenance, running it once a
+/// week within the window, unless snapshots are being generated
+pub(crate) async fn maintain_periodically<L: Deref>(window: MaintenanceWindow, logger: L) where L::Target: Logger {
+	let tables = Tables::from_config();
+	let mut interval = tokio::time::interval(CHECK_INTERVAL);
+	loop {
+		interval.tick().await;
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch").as_secs();
+		if !window.contains(now) {
+			continue;
+		}
+
+		let client = match crate::connect_to_db().await {
+			Ok(client) => client,
+			Err(error) => {
+				log_warn!(logger, "Failed to connect for database maintenance: {}", error);
+				continue;
+			},
+		};
+		match last_maintained_at(&client, &tables).await {
+			Ok(last_maintained_at) if now.saturating_sub(last_maintained_at) < MAINTENANCE_INTERVAL_SECS => continue,
+			Ok(_) => {},
+			Err(error) => {
+				log_warn!(logger, "Failed to look up when the database was last maintained: {}", error);
+				continue;
+			},
+		}
+		if snapshot::is_generation_in_progress() {
+			log_info!(logger, "Postponing database maintenance while snapshots are being generated");
+			continue;
+		}
+
+		match estimate_bloat(&client, &tables).await {
+			Ok(bloat) => for table_bloat in bloat {
+				log_info!(logger, "Before maintenance, {}", table_bloat.to_text());
+			},
+			Err(error) => log_warn!(logger, "Failed to estimate the gossip tables' bloat: {}", error),
+		}
+		maintain(&client, &tables, &logger).await;
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_maintenance_window() {
+		let window = MaintenanceWindow::parse("3-5").unwrap();
+		let day_start = 1_700_006_400;
+		assert!(!window.contains(day_start + 2 * 3600 + 3599));
+		assert!(window.contains(day_start + 3 * 3600));
+		assert!(window.contains(day_start + 4 * 3600 + 3599));
+		assert!(!window.contains(day_start + 5 * 3600));
+
+		// windows may span midnight
+		let window = MaintenanceWindow::parse("22-2").unwrap();
+		assert!(window.contains(day_start + 23 * 3600));
+		assert!(window.contains(day_start + 3600));
+		assert!(!window.contains(day_start + 12 * 3600));
+		assert_eq!(MaintenanceWindow::parse("22-24"), MaintenanceWindow::parse("22-0"));
+
+		assert!(MaintenanceWindow::parse("3").is_none());
+		assert!(MaintenanceWindow::parse("3-3").is_none());
+		assert!(MaintenanceWindow::parse("24-2").is_none());
+	}
+
+	#[test]
+	fn test_bloat_formatting() {
+		let bloat = TableBloat { table: "channel_updates".to_string(), live_rows: 3_000, dead_rows: 1_000, table_bytes: 8 << 20, index_bytes: 3 << 20 };
+		assert_eq!(bloat.dead_row_percent(), 25.0);
+		assert_eq!(bloat.to_text(), "channel_updates: 3000 live rows, 1000 dead rows (25.0%), 8 MiB of data, 3 MiB of indexes (1048 bytes per row)");
+		assert_eq!(TableBloat::default().dead_row_percent(), 0.0);
+	}
+}
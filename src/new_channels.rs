@@ -0,0 +1,160 @@
+use std::ops::Deref;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use lightning::{log_info, log_warn};
+use lightning::util::logger::Logger;
+use tokio_postgres::GenericClient;
+
+use crate::{config, stats};
+use crate::tables::Tables;
+
+/// How often the new channel statistics are published. The hourly count would lag behind too much
+/// if it were only refreshed as often as the fee statistics.
+const PUBLICATION_INTERVAL: Duration = Duration::from_secs(600);
+
+const HOUR_SECS: u64 = 3600;
+const DAY_SECS: u64 = 24 * HOUR_SECS;
+
+/// How quickly newly opened channels became visible to the server, and with that to snapshots. A
+/// channel is first seen when its announcement is first persisted, which happens once it has enough
+/// confirmations, so the delays include the confirmation depth peers and this server wait for.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct NewChannelStats {
+	pub(crate) first_seen_last_hour: u64,
+	pub(crate) first_seen_last_day: u64,
+	/// The seconds from the funding block's timestamp to the announcement being first seen, for
+	/// the channels first seen in the last day whose funding block time was recorded, ascending
+	pub(crate) visibility_delays_secs: Vec<u32>,
+}
+
+pub(crate) async fn fetch_new_channel_stats<C: GenericClient>(client: &C, tables: &Tables) -> Result<NewChannelStats, tokio_postgres::Error> {
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch").as_secs();
+	let hour_ago = now.saturating_sub(HOUR_SECS) as f64;
+	let day_ago = now.saturating_sub(DAY_SECS) as f64;
+
+	let counts = client.query_one(&format!("SELECT \
+			COUNT(*) FILTER (WHERE seen >= TO_TIMESTAMP($1)), \
+			COUNT(*) \
+		FROM {} WHERE seen >= TO_TIMESTAMP($2)", tables.channel_announcements()), &[&hour_ago, &day_ago]).await?;
+	// block timestamps may be up to two hours ahead of the actual time, so delays are clamped
+	let delay_rows = client.query(&format!("SELECT GREATEST(0, EXTRACT(EPOCH FROM seen - funding_block_time))::bigint AS delay \
+		FROM {} WHERE seen >= TO_TIMESTAMP($1) AND funding_block_time IS NOT NULL \
+		ORDER BY delay ASC", tables.channel_announcements()), &[&day_ago]).await?;
+
+	Ok(NewChannelStats {
+		first_seen_last_hour: counts.get::<_, i64>(0) as u64,
+		first_seen_last_day: counts.get::<_, i64>(1) as u64,
+		visibility_delays_secs: delay_rows.iter().map(|row| row.get::<_, i64>(0).min(u32::MAX as i64) as u32).collect(),
+	})
+}
+
+impl NewChannelStats {
+	fn delay_sum_secs(&self) -> u64 {
+		self.visibility_delays_secs.iter().map(|delay| *delay as u64).sum()
+	}
+
+	pub(crate) fn to_text(&self) -> String {
+		let mut output = format!("Channels first seen in the last hour: {}\nChannels first seen in the last day: {}\n", self.first_seen_last_hour, self.first_seen_last_day);
+		if self.visibility_delays_secs.is_empty() {
+			output.push_str("No funding block times were recorded for the channels first seen in the last day\n");
+			return output;
+		}
+		output.push_str(&format!(
+			"Delay from funding block to first seen, across {} channels: median {}, 90th percentile {}, 99th percentile {}, maximum {}\n",
+			self.visibility_delays_secs.len(),
+			format_delay(stats::percentile(&self.visibility_delays_secs, 50)),
+			format_delay(stats::percentile(&self.visibility_delays_secs, 90)),
+			format_delay(stats::percentile(&self.visibility_delays_secs, 99)),
+			format_delay(stats::percentile(&self.visibility_delays_secs, 100)),
+		));
+		output
+	}
+
+	pub(crate) fn to_json(&self) -> String {
+		format!(
+			"{{\"first_seen_last_hour\":{},\"first_seen_last_day\":{},\"visibility_delay_secs\":{{\"count\":{},\"median\":{},\"p90\":{},\"p99\":{},\"max\":{}}}}}",
+			self.first_seen_last_hour,
+			self.first_seen_last_day,
+			self.visibility_delays_secs.len(),
+			stats::percentile(&self.visibility_delays_secs, 50),
+			stats::percentile(&self.visibility_delays_secs, 90),
+			stats::percentile(&self.visibility_delays_secs, 99),
+			stats::percentile(&self.visibility_delays_secs, 100),
+		)
+	}
+
+	/// Serialize the statistics as Prometheus gauges and a summary in the text exposition format
+	pub(crate) fn to_prometheus(&self) -> String {
+		let mut output = String::new();
+		output.push_str("# HELP rgs_new_channels Channels whose announcement was first seen within the window\n");
+		output.push_str("# TYPE rgs_new_channels gauge\n");
+		output.push_str(&format!("rgs_new_channels{{window=\"1h\"}} {}\n", self.first_seen_last_hour));
+		output.push_str(&format!("rgs_new_channels{{window=\"24h\"}} {}\n", self.first_seen_last_day));
+		output.push_str("# HELP rgs_new_channel_visibility_delay_seconds Time from the funding block to first seeing the announcement, for channels first seen in the last day\n");
+		output.push_str("# TYPE rgs_new_channel_visibility_delay_seconds summary\n");
+		for (quantile, percentile) in [("0.5", 50), ("0.9", 90), ("0.99", 99)] {
+			output.push_str(&format!("rgs_new_channel_visibility_delay_seconds{{quantile=\"{}\"}} {}\n", quantile, stats::percentile(&self.visibility_delays_secs, percentile)));
+		}
+		output.push_str(&format!("rgs_new_channel_visibility_delay_seconds_sum {}\n", self.delay_sum_secs()));
+		output.push_str(&format!("rgs_new_channel_visibility_delay_seconds_count {}\n", self.visibility_delays_secs.len()));
+		output
+	}
+}
+
+fn format_delay(delay_secs: u32) -> String {
+	let minutes = delay_secs / 60;
+	if minutes < 60 {
+		return format!("{}m {}s", minutes, delay_secs % 60);
+	}
+	format!("{}h {}m", minutes / 60, minutes % 60)
+}
+
+/// Periodically publish the new channel statistics next to the fee statistics, so that they can be
+/// served as `/stats/new_channels` and scraped by Prometheus' textfile collector
+pub(crate) async fn publish_periodically<L: Deref>(logger: L) where L::Target: Logger {
+	let tables = Tables::from_config();
+	let mut interval = tokio::time::interval(PUBLICATION_INTERVAL);
+	loop {
+		interval.tick().await;
+
+		let client = match crate::connect_to_db().await {
+			Ok(client) => client,
+			Err(error) => {
+				log_warn!(logger, "Failed to publish new channel stats: {}", error);
+				continue;
+			},
+		};
+		let new_channel_stats = match fetch_new_channel_stats(&client, &tables).await {
+			Ok(new_channel_stats) => new_channel_stats,
+			Err(error) => {
+				log_warn!(logger, "Failed to look up new channel stats: {}", error);
+				continue;
+			},
+		};
+		log_info!(logger, "New channel stats: {}", new_channel_stats.to_json());
+		if let Err(error) = stats::write_stats_file(&config::stats_path(), "new_channels", &new_channel_stats.to_json(), &new_channel_stats.to_prometheus()) {
+			log_warn!(logger, "Failed to persist new channel stats: {}", error);
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_new_channel_stats_formats() {
+		let new_channel_stats = NewChannelStats {
+			first_seen_last_hour: 2,
+			first_seen_last_day: 5,
+			visibility_delays_secs: vec![600, 3_600, 4_000, 7_300],
+		};
+		assert_eq!(new_channel_stats.to_text(), "Channels first seen in the last hour: 2\nChannels first seen in the last day: 5\nDelay from funding block to first seen, across 4 channels: median 1h 0m, 90th percentile 2h 1m, 99th percentile 2h 1m, maximum 2h 1m\n");
+		assert_eq!(new_channel_stats.to_json(), "{\"first_seen_last_hour\":2,\"first_seen_last_day\":5,\"visibility_delay_secs\":{\"count\":4,\"median\":3600,\"p90\":7300,\"p99\":7300,\"max\":7300}}");
+		assert!(new_channel_stats.to_prometheus().contains("rgs_new_channel_visibility_delay_seconds_sum 15500\n"));
+
+		let empty_stats = NewChannelStats::default();
+		assert!(empty_stats.to_text().ends_with("No funding block times were recorded for the channels first seen in the last day\n"));
+		assert!(empty_stats.to_prometheus().contains("rgs_new_channel_visibility_delay_seconds{quantile=\"0.5\"} 0\n"));
+	}
+}
@@ -0,0 +1,154 @@
+use std::collections::VecDeque;
+use std::pin::Pin;
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use tokio_postgres::{Client, Row, RowStream};
+use tokio_postgres::types::ToSql;
+
+use crate::error::{ErrorContext, ProcessorError};
+
+/// Limits on the database load caused by the lookups of a snapshot generation round. By default,
+/// nothing is limited.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct LookupPacing {
+	/// Rows are read through cursors, in batches of a tenth of this, sleeping between batches as
+	/// necessary to stay below this rate
+	pub(crate) max_rows_per_second: Option<u64>,
+	/// Set as the session's `statement_timeout`, so that a runaway query is cancelled
+	pub(crate) statement_timeout: Option<Duration>,
+	/// Set as the session's `work_mem`, e. g. "16MB"
+	pub(crate) work_mem: Option<String>,
+}
+
+impl LookupPacing {
+	fn batch_size(max_rows_per_second: u64) -> u64 {
+		(max_rows_per_second / 10).max(1)
+	}
+}
+
+/// The database load caused by a delta calculation
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct LookupLoad {
+	pub(crate) rows_read: u64,
+	/// The number of cursor fetches, or, without pacing, the number of queries
+	pub(crate) batches_fetched: u64,
+	pub(crate) wall_time: Duration,
+}
+
+/// A database connection for delta lookups, which applies the pacing's session settings and read
+/// rate, and tallies up the load.
+pub(crate) struct LookupClient {
+	client: Client,
+	pacing: LookupPacing,
+	load: Mutex<LookupLoad>,
+	cursor_count: AtomicUsize,
+	connected_at: Instant,
+}
+
+impl LookupClient {
+	pub(crate) async fn connect(pacing: LookupPacing) -> Result<Self, ProcessorError> {
+		let connected_at = Instant::now();
+		let client = crate::connect_to_db().await?;
+		if let Some(statement_timeout) = pacing.statement_timeout {
+			let statement_timeout = statement_timeout.as_millis().to_string();
+			client.execute("SELECT set_config('statement_timeout', $1, false)", &[&statement_timeout]).await.context("Failed to set the lookup statement timeout")?;
+		}
+		if let Some(work_mem) = pacing.work_mem.as_ref() {
+			client.execute("SELECT set_config('work_mem', $1, false)", &[work_mem]).await.context("Failed to set the lookup work_mem")?;
+		}
+		Ok(Self { client, pacing, load: Mutex::new(LookupLoad::default()), cursor_count: AtomicUsize::new(0), connected_at })
+	}
+
+	/// Run a query whose rows are read one at a time. Without a read rate limit, the rows are
+	/// streamed as they arrive; otherwise, they're fetched in paced batches through a cursor.
+	pub(crate) async fn query(&self, statement: &str, params: &[&(dyn ToSql + Sync)]) -> Result<LookupRows<'_>, tokio_postgres::Error> {
+		self.load.lock().expect("lookup load lock poisoned").batches_fetched += 1;
+		let source = match self.pacing.max_rows_per_second {
+			None => RowSource::Stream(Box::pin(self.client.query_raw(statement, params.iter().copied()).await?)),
+			Some(max_rows_per_second) => {
+				// cursors only live as long as the transaction they're declared in
+				let name = format!("lookup_cursor_{}", self.cursor_count.fetch_add(1, Ordering::AcqRel));
+				self.client.batch_execute("BEGIN READ ONLY").await?;
+				self.client.execute(&format!("DECLARE {} NO SCROLL CURSOR FOR {}", name, statement), params).await?;
+				let batch_size = LookupPacing::batch_size(max_rows_per_second);
+				let rows = self.client.query(&format!("FETCH FORWARD {} FROM {}", batch_size, name), &[]).await?;
+				let is_exhausted = (rows.len() as u64) < batch_size;
+				if is_exhausted {
+					self.client.batch_execute(&format!("CLOSE {}; COMMIT", name)).await?;
+				}
+				RowSource::Cursor {
+					name,
+					batch_size,
+					max_rows_per_second,
+					buffer: rows.into(),
+					rows_fetched: 0,
+					declared_at: Instant::now(),
+					is_exhausted,
+				}
+			}
+		};
+		Ok(LookupRows { client: self, source })
+	}
+
+	/// The load caused so far, timed since connecting
+	pub(crate) fn load(&self) -> LookupLoad {
+		let mut load = self.load.lock().expect("lookup load lock poisoned").clone();
+		load.wall_time = self.connected_at.elapsed();
+		load
+	}
+}
+
+enum RowSource {
+	Stream(Pin<Box<RowStream>>),
+	Cursor {
+		name: String,
+		batch_size: u64,
+		max_rows_per_second: u64,
+		buffer: VecDeque<Row>,
+		/// The number of rows fetched prior to the buffered ones
+		rows_fetched: u64,
+		declared_at: Instant,
+		is_exhausted: bool,
+	},
+}
+
+pub(crate) struct LookupRows<'a> {
+	client: &'a LookupClient,
+	source: RowSource,
+}
+
+impl LookupRows<'_> {
+	pub(crate) async fn next(&mut self) -> Option<Result<Row, tokio_postgres::Error>> {
+		let row = match &mut self.source {
+			RowSource::Stream(stream) => stream.next().await,
+			RowSource::Cursor { name, batch_size, max_rows_per_second, buffer, rows_fetched, declared_at, is_exhausted } => {
+				if buffer.is_empty() && !*is_exhausted {
+					*rows_fetched += *batch_size;
+					let earliest_fetch_at = *declared_at + Duration::from_secs_f64(*rows_fetched as f64 / *max_rows_per_second as f64);
+					tokio::time::sleep_until(earliest_fetch_at.into()).await;
+
+					self.client.load.lock().expect("lookup load lock poisoned").batches_fetched += 1;
+					let rows = match self.client.client.query(&format!("FETCH FORWARD {} FROM {}", batch_size, name), &[]).await {
+						Ok(rows) => rows,
+						Err(error) => return Some(Err(error)),
+					};
+					*is_exhausted = (rows.len() as u64) < *batch_size;
+					if *is_exhausted {
+						if let Err(error) = self.client.client.batch_execute(&format!("CLOSE {}; COMMIT", name)).await {
+							return Some(Err(error));
+						}
+					}
+					buffer.extend(rows);
+				}
+				buffer.pop_front().map(Ok)
+			}
+		};
+		if let Some(Ok(_)) = row {
+			self.client.load.lock().expect("lookup load lock poisoned").rows_read += 1;
+		}
+		row
+	}
+}
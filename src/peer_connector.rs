@@ -0,0 +1,554 @@
+//! Maintains a connection to each configured peer, reconnecting whenever one drops.
+//!
+//! Connections are opened through a [`PeerDialer`], so that the reconnection and backoff behavior
+//! can be exercised without sockets.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, Weak};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bitcoin::secp256k1::PublicKey;
+use futures::future::BoxFuture;
+use hex_conservative::display::DisplayHex;
+use lightning::{log_info, log_warn};
+use lightning::util::logger::Logger;
+use tokio::sync::{mpsc, Semaphore};
+use tokio::task::JoinHandle;
+
+use crate::config;
+use crate::discovery;
+use crate::dormancy::{DormancyChange, DormancyTracker};
+use crate::peer_registry::{self, AddressBook, DisconnectResult, GossipPeerManagerExt, LocalBindAddresses, PeerConnectError, PeerConnectionStats};
+use crate::types::GossipPeerManager;
+
+/// How long to wait before reconnecting to a peer whose connection dropped
+const RECONNECT_DELAY: Duration = Duration::from_secs(10);
+/// The longest the delay between failed connection rounds grows to, unless an error calls for a
+/// longer one by itself
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60 * 60);
+/// Bounds the exponent of the backoff, which reaches the maximum delay long before
+const MAX_BACKOFF_DOUBLINGS: u32 = 10;
+/// How often a dormant peer checks whether its daily retry is due, or too few peers are connected
+const DORMANT_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// A connection to a peer whose handshake completed
+pub(crate) struct DialedConnection {
+	/// Resolves once the connection has been closed
+	pub(crate) closed: BoxFuture<'static, ()>,
+	/// A duplicate of the connection's socket for reading its byte counts, if available
+	pub(crate) socket: Option<std::net::TcpStream>,
+}
+
+/// Opens and closes connections to peers
+pub(crate) trait PeerDialer: Send + Sync + 'static {
+	/// Connect to a peer at the given address and complete the handshake
+	fn dial(&self, peer: (PublicKey, SocketAddr)) -> BoxFuture<'static, Result<DialedConnection, PeerConnectError>>;
+	/// Close the connection to the peer, if any
+	fn disconnect(&self, pubkey: &PublicKey) -> DisconnectResult;
+}
+
+/// Dials peers through LDK's peer manager
+pub(crate) struct LdkDialer<L: Deref + Clone + Send + Sync + 'static> where L::Target: Logger {
+	peer_manager: GossipPeerManager<L>,
+	local_addresses: LocalBindAddresses,
+}
+
+impl<L: Deref + Clone + Send + Sync + 'static> LdkDialer<L> where L::Target: Logger {
+	pub(crate) fn new(peer_manager: GossipPeerManager<L>, local_addresses: LocalBindAddresses) -> Self {
+		Self { peer_manager, local_addresses }
+	}
+}
+
+impl<L: Deref + Clone + Send + Sync + 'static> PeerDialer for LdkDialer<L> where L::Target: Logger {
+	fn dial(&self, peer: (PublicKey, SocketAddr)) -> BoxFuture<'static, Result<DialedConnection, PeerConnectError>> {
+		let peer_manager = Arc::clone(&self.peer_manager);
+		let local_addresses = self.local_addresses;
+		Box::pin(async move {
+			let (disconnection_future, socket) = peer_registry::connect_with_error_classification(&peer_manager, peer, &local_addresses).await?;
+			Ok(DialedConnection { closed: Box::pin(async move { let _ = disconnection_future.await; }), socket })
+		})
+	}
+
+	fn disconnect(&self, pubkey: &PublicKey) -> DisconnectResult {
+		self.peer_manager.safe_disconnect(pubkey)
+	}
+}
+
+/// What happened to the connection of a peer
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum ConnectorEvent {
+	Connected(PublicKey, SocketAddr),
+	Disconnected(PublicKey),
+	/// The first attempt to connect to the peer failed at every address, or was cancelled by the
+	/// peer's removal
+	FirstAttemptFailed(PublicKey),
+}
+
+/// Lengthens the delays between failed connection rounds while a peer stays unreachable
+#[derive(Debug, Default)]
+struct ReconnectBackoff {
+	consecutive_failures: u32,
+}
+
+impl ReconnectBackoff {
+	/// The delay before the next round after one that failed, given the shortest delay its errors
+	/// call for, which doubles with every consecutive failure
+	fn after_failure(&mut self, error_delay: Duration) -> Duration {
+		let doublings = self.consecutive_failures.min(MAX_BACKOFF_DOUBLINGS);
+		self.consecutive_failures += 1;
+		(error_delay * 2u32.pow(doublings)).min(MAX_RECONNECT_DELAY.max(error_delay))
+	}
+
+	fn reset(&mut self) {
+		self.consecutive_failures = 0;
+	}
+}
+
+/// Reports the progress of a peer's connection task, which it keeps doing should the task be
+/// aborted
+struct ConnectionTaskState {
+	pubkey: PublicKey,
+	is_first_attempt_pending: bool,
+	is_connected: bool,
+	connected_peer_count: Arc<AtomicUsize>,
+	event_sender: mpsc::UnboundedSender<ConnectorEvent>,
+}
+
+impl ConnectionTaskState {
+	fn connected(&mut self, address: SocketAddr) {
+		self.is_first_attempt_pending = false;
+		self.is_connected = true;
+		self.connected_peer_count.fetch_add(1, Ordering::AcqRel);
+		// nobody listening for events anymore is fine
+		let _ = self.event_sender.send(ConnectorEvent::Connected(self.pubkey, address));
+	}
+
+	fn disconnected(&mut self) {
+		if !self.is_connected {
+			return;
+		}
+		self.is_connected = false;
+		self.connected_peer_count.fetch_sub(1, Ordering::AcqRel);
+		let _ = self.event_sender.send(ConnectorEvent::Disconnected(self.pubkey));
+	}
+
+	fn attempt_failed(&mut self) {
+		if self.is_first_attempt_pending {
+			self.is_first_attempt_pending = false;
+			let _ = self.event_sender.send(ConnectorEvent::FirstAttemptFailed(self.pubkey));
+		}
+	}
+}
+
+impl Drop for ConnectionTaskState {
+	fn drop(&mut self) {
+		self.disconnected();
+		self.attempt_failed();
+	}
+}
+
+/// Owns a task per configured peer that maintains a connection to it, so that peers can be added
+/// and removed while running. The tasks stop once the connector is dropped.
+pub(crate) struct PeerConnector<D: PeerDialer, L: Deref + Clone + Send + Sync + 'static> where L::Target: Logger {
+	dialer: Arc<D>,
+	address_book: Arc<AddressBook>,
+	peer_stats: Arc<PeerConnectionStats>,
+	dormancy: Arc<DormancyTracker>,
+	connection_tasks: Mutex<HashMap<PublicKey, (SocketAddr, JoinHandle<()>)>>,
+	/// Limits how many connection attempts are made at once, so that large peer lists don't
+	/// result in a burst of outbound TCP connections
+	connection_limiter: Arc<Semaphore>,
+	connected_peer_count: Arc<AtomicUsize>,
+	event_sender: mpsc::UnboundedSender<ConnectorEvent>,
+	logger: L,
+}
+
+impl<D: PeerDialer, L: Deref + Clone + Send + Sync + 'static> PeerConnector<D, L> where L::Target: Logger {
+	/// Create a connector along with the receiver of its events
+	pub(crate) fn new(dialer: Arc<D>, address_book: Arc<AddressBook>, peer_stats: Arc<PeerConnectionStats>, dormancy: Arc<DormancyTracker>, connect_concurrency: usize, logger: L) -> (Self, mpsc::UnboundedReceiver<ConnectorEvent>) {
+		let (event_sender, event_receiver) = mpsc::unbounded_channel();
+		let connector = Self {
+			dialer,
+			address_book,
+			peer_stats,
+			dormancy,
+			connection_tasks: Mutex::new(HashMap::new()),
+			connection_limiter: Arc::new(Semaphore::new(connect_concurrency)),
+			connected_peer_count: Arc::new(AtomicUsize::new(0)),
+			event_sender,
+			logger,
+		};
+		(connector, event_receiver)
+	}
+
+	/// The number of peers whose connection tasks are connected
+	pub(crate) fn connected_peer_count(&self) -> usize {
+		self.connected_peer_count.load(Ordering::Acquire)
+	}
+
+	/// Spawn a task that connects to the peer, and reconnects whenever the connection drops,
+	/// replacing any previous task of the peer
+	pub(crate) fn add_peer(&self, peer: (PublicKey, SocketAddr)) {
+		self.address_book.track(peer.0, peer.1);
+		let state = ConnectionTaskState {
+			pubkey: peer.0,
+			is_first_attempt_pending: true,
+			is_connected: false,
+			connected_peer_count: Arc::clone(&self.connected_peer_count),
+			event_sender: self.event_sender.clone(),
+		};
+		let connection_task = tokio::spawn(maintain_connection(peer, Arc::clone(&self.dialer), Arc::clone(&self.address_book), Arc::clone(&self.peer_stats), Arc::clone(&self.dormancy), Arc::clone(&self.connection_limiter), state, self.logger.clone()));
+		let previous_task = self.connection_tasks.lock().expect("connection task lock poisoned").insert(peer.0, (peer.1, connection_task));
+		if let Some((_, previous_task)) = previous_task {
+			previous_task.abort();
+		}
+	}
+
+	/// Stop reconnecting to the peer, and close any open connection
+	pub(crate) fn remove_peer(&self, pubkey: &PublicKey) {
+		self.address_book.untrack(pubkey);
+		if let Some((_, connection_task)) = self.connection_tasks.lock().expect("connection task lock poisoned").remove(pubkey) {
+			// the task must be stopped first, lest it reconnect immediately
+			connection_task.abort();
+		}
+		if self.dialer.disconnect(pubkey) == DisconnectResult::AlreadyDisconnected {
+			log_info!(self.logger, "Peer {} was already disconnected", pubkey.serialize().to_lower_hex_string());
+		}
+	}
+
+	/// Re-read the peer list, connecting to new peers and disconnecting from removed ones, while
+	/// leaving connections to unchanged peers alone
+	pub(crate) async fn reload(&self) {
+		let new_peers = match config::load_ln_peers() {
+			Ok(peers) => discovery::resolve_peer_list(peers, self.logger.clone()).await,
+			Err(error) => Err(error),
+		};
+		let new_peers = match new_peers {
+			Ok(peers) => peers,
+			Err(error) => {
+				log_warn!(self.logger, "Not reloading peers: {}", error);
+				return;
+			}
+		};
+
+		let diff = {
+			let connection_tasks = self.connection_tasks.lock().expect("connection task lock poisoned");
+			let current_peers: HashMap<PublicKey, SocketAddr> = connection_tasks.iter().map(|(pubkey, (address, _))| (*pubkey, *address)).collect();
+			peer_registry::diff_peer_lists(&current_peers, &new_peers)
+		};
+
+		for pubkey in &diff.removed {
+			log_info!(self.logger, "Removing peer {}", pubkey.serialize().to_lower_hex_string());
+			self.remove_peer(pubkey);
+		}
+		for peer in &diff.added {
+			log_info!(self.logger, "Adding peer {}@{}", peer.0.serialize().to_lower_hex_string(), peer.1);
+			self.add_peer(*peer);
+		}
+		log_info!(self.logger, "Reloaded peers: {} added, {} removed, {} unchanged", diff.added.len(), diff.removed.len(), diff.unchanged_count);
+	}
+
+	/// Reload the peer list whenever the process receives SIGHUP, for as long as the connector
+	/// exists
+	pub(crate) async fn reload_on_hangup(connector: Weak<Self>, logger: L) {
+		let mut hangup_signal = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+			Ok(signal) => signal,
+			Err(error) => {
+				log_warn!(logger, "Failed to listen for SIGHUP, peers cannot be reloaded: {}", error);
+				return;
+			}
+		};
+		while hangup_signal.recv().await.is_some() {
+			let connector = match connector.upgrade() {
+				Some(connector) => connector,
+				None => return,
+			};
+			log_info!(logger, "Received SIGHUP, reloading peers…");
+			connector.reload().await;
+		}
+	}
+}
+
+impl<D: PeerDialer, L: Deref + Clone + Send + Sync + 'static> Drop for PeerConnector<D, L> where L::Target: Logger {
+	fn drop(&mut self) {
+		for (_, (_, connection_task)) in self.connection_tasks.lock().expect("connection task lock poisoned").drain() {
+			connection_task.abort();
+		}
+	}
+}
+
+async fn maintain_connection<D: PeerDialer, L: Deref + Clone + Send + Sync + 'static>(peer: (PublicKey, SocketAddr), dialer: Arc<D>, address_book: Arc<AddressBook>, peer_stats: Arc<PeerConnectionStats>, dormancy: Arc<DormancyTracker>, connection_limiter: Arc<Semaphore>, mut state: ConnectionTaskState, logger: L) where L::Target: Logger {
+	let peer_pubkey_hex = peer.0.serialize().to_lower_hex_string();
+	let mut backoff = ReconnectBackoff::default();
+	loop {
+		// dormant peers wait for their daily retry, unless too few peers are connected without them
+		while !dormancy.is_attempt_due(&peer.0, unix_timestamp(), state.connected_peer_count.load(Ordering::Acquire)) {
+			tokio::time::sleep(DORMANT_CHECK_INTERVAL).await;
+		}
+		let mut error_delay = None;
+		let connection_result = {
+			// the permit is only held while connecting, not for the lifetime of the connection
+			let _permit = connection_limiter.acquire().await.expect("the connection limiter is never closed");
+			let mut connection_result = None;
+			for address in address_book.candidates(&peer.0, peer.1) {
+				log_info!(logger, "Connecting to peer {}@{}...", peer_pubkey_hex, address);
+				match dialer.dial((peer.0, address)).await {
+					Ok(connection) => {
+						connection_result = Some((address, connection));
+						break;
+					},
+					Err(error) => {
+						log_warn!(logger, "Failed to connect to peer {}@{}: {}", peer_pubkey_hex, address, error);
+						peer_stats.record_connection_error(peer.0, &error);
+						// any of the addresses may work again soon
+						error_delay = Some(error_delay.map_or(error.retry_delay(), |delay: Duration| delay.min(error.retry_delay())));
+					},
+				}
+			}
+			connection_result
+		};
+		let retry_delay = if let Some((address, connection)) = connection_result {
+			log_info!(logger, "Connected to peer {}@{}!", peer_pubkey_hex, address);
+			peer_stats.record_connection(peer.0, address, connection.socket);
+			if dormancy.record_success(peer.0, unix_timestamp(), logger.clone()) == Some(DormancyChange::Reactivated) {
+				log_info!(logger, "Dormant peer {} is reachable again, and retried as usual from now on", peer_pubkey_hex);
+			}
+			state.connected(address);
+			connection.closed.await;
+			let is_dropped_idle = peer_stats.take_idle_disconnection(&peer.0);
+			peer_stats.record_disconnection(peer.0);
+			state.disconnected();
+			if is_dropped_idle {
+				// the peer may well be a black hole again, so reconnecting backs off as if it had failed
+				log_warn!(logger, "Disconnected from idle peer {}@{}", peer_pubkey_hex, address);
+				backoff.after_failure(RECONNECT_DELAY)
+			} else {
+				log_warn!(logger, "Disconnected from peer {}@{}", peer_pubkey_hex, address);
+				// failures to connect before don't matter anymore
+				backoff.reset();
+				RECONNECT_DELAY
+			}
+		} else {
+			state.attempt_failed();
+			if dormancy.record_failure(peer.0, unix_timestamp(), logger.clone()) == Some(DormancyChange::BecameDormant) {
+				let policy = dormancy.policy();
+				log_warn!(logger, "Peer {} failed {} consecutive connection rounds over at least {} days, and is only retried daily from now on", peer_pubkey_hex, policy.failure_threshold, policy.min_failing_duration.as_secs() / (24 * 60 * 60));
+			}
+			backoff.after_failure(error_delay.unwrap_or(RECONNECT_DELAY))
+		};
+		tokio::time::sleep(retry_delay).await;
+		log_warn!(logger, "Reconnecting to peer {} after {:?}...", peer_pubkey_hex, retry_delay);
+	}
+}
+
+fn unix_timestamp() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch").as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::collections::VecDeque;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+	use tokio::sync::oneshot;
+	use tokio::time::Instant;
+	use crate::types::tests::TestLogger;
+
+	/// What a scripted dial does
+	enum ScriptedDial {
+		/// Connect, staying connected until the sender is used or dropped
+		Connect(oneshot::Receiver<()>),
+		Fail(PeerConnectError),
+	}
+
+	/// Dials according to a script per peer, and hangs once a peer's script ran out
+	#[derive(Default)]
+	struct ScriptedDialer {
+		scripts: Mutex<HashMap<PublicKey, VecDeque<ScriptedDial>>>,
+		dial_times: Mutex<Vec<(PublicKey, Instant)>>,
+		disconnected: Mutex<Vec<PublicKey>>,
+	}
+
+	impl ScriptedDialer {
+		fn script(&self, pubkey: PublicKey, dial: ScriptedDial) {
+			self.scripts.lock().unwrap().entry(pubkey).or_default().push_back(dial);
+		}
+
+		/// The intervals between the dials of a peer
+		fn dial_intervals(&self, pubkey: &PublicKey) -> Vec<Duration> {
+			let dial_times: Vec<Instant> = self.dial_times.lock().unwrap().iter().filter(|(dialed, _)| dialed == pubkey).map(|(_, time)| *time).collect();
+			dial_times.windows(2).map(|times| times[1] - times[0]).collect()
+		}
+	}
+
+	impl PeerDialer for ScriptedDialer {
+		fn dial(&self, peer: (PublicKey, SocketAddr)) -> BoxFuture<'static, Result<DialedConnection, PeerConnectError>> {
+			self.dial_times.lock().unwrap().push((peer.0, Instant::now()));
+			let dial = self.scripts.lock().unwrap().get_mut(&peer.0).and_then(|script| script.pop_front());
+			Box::pin(async move {
+				match dial {
+					Some(ScriptedDial::Connect(closed)) => Ok(DialedConnection { closed: Box::pin(async move { let _ = closed.await; }), socket: None }),
+					Some(ScriptedDial::Fail(error)) => Err(error),
+					None => futures::future::pending().await,
+				}
+			})
+		}
+
+		fn disconnect(&self, pubkey: &PublicKey) -> DisconnectResult {
+			self.disconnected.lock().unwrap().push(*pubkey);
+			DisconnectResult::AlreadyDisconnected
+		}
+	}
+
+	fn peer(byte: u8) -> (PublicKey, SocketAddr) {
+		let pubkey = SecretKey::from_slice(&[byte; 32]).unwrap().public_key(&Secp256k1::new());
+		(pubkey, format!("127.0.0.1:{}", 9000 + byte as u16).parse().unwrap())
+	}
+
+	fn create_connector(dialer: &Arc<ScriptedDialer>, test_id: &str) -> (PeerConnector<ScriptedDialer, Arc<TestLogger>>, mpsc::UnboundedReceiver<ConnectorEvent>) {
+		let logger = Arc::new(TestLogger::with_id(test_id.to_string()));
+		let dormancy = Arc::new(DormancyTracker::new(config::dormancy_policy()));
+		PeerConnector::new(Arc::clone(dialer), Arc::new(AddressBook::new()), Arc::new(PeerConnectionStats::new()), dormancy, 4, logger)
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_first_attempts_and_connected_count() {
+		let dialer = Arc::new(ScriptedDialer::default());
+		let (reachable_peer, unreachable_peer) = (peer(1), peer(2));
+		let (_close_sender, close_receiver) = oneshot::channel();
+		dialer.script(reachable_peer.0, ScriptedDial::Connect(close_receiver));
+		dialer.script(unreachable_peer.0, ScriptedDial::Fail(PeerConnectError::TcpConnectionRefused));
+		let (connector, mut events) = create_connector(&dialer, "test_first_attempts_and_connected_count");
+
+		connector.add_peer(reachable_peer);
+		assert_eq!(events.recv().await, Some(ConnectorEvent::Connected(reachable_peer.0, reachable_peer.1)));
+		connector.add_peer(unreachable_peer);
+		assert_eq!(events.recv().await, Some(ConnectorEvent::FirstAttemptFailed(unreachable_peer.0)));
+		assert_eq!(connector.connected_peer_count(), 1);
+
+		// later failures aren't reported as first attempts again
+		dialer.script(unreachable_peer.0, ScriptedDial::Fail(PeerConnectError::TcpConnectionRefused));
+		tokio::time::sleep(Duration::from_secs(120)).await;
+		assert_eq!(dialer.dial_intervals(&unreachable_peer.0).len(), 2);
+		assert!(events.try_recv().is_err());
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_reconnect_after_disconnection() {
+		let dialer = Arc::new(ScriptedDialer::default());
+		let peer = peer(1);
+		let (close_sender, close_receiver) = oneshot::channel();
+		dialer.script(peer.0, ScriptedDial::Connect(close_receiver));
+		let (_second_close_sender, second_close_receiver) = oneshot::channel();
+		dialer.script(peer.0, ScriptedDial::Connect(second_close_receiver));
+		let (connector, mut events) = create_connector(&dialer, "test_reconnect_after_disconnection");
+
+		connector.add_peer(peer);
+		assert_eq!(events.recv().await, Some(ConnectorEvent::Connected(peer.0, peer.1)));
+		close_sender.send(()).unwrap();
+		assert_eq!(events.recv().await, Some(ConnectorEvent::Disconnected(peer.0)));
+		assert_eq!(connector.connected_peer_count(), 0);
+		assert_eq!(events.recv().await, Some(ConnectorEvent::Connected(peer.0, peer.1)));
+		assert_eq!(connector.connected_peer_count(), 1);
+		assert_eq!(dialer.dial_intervals(&peer.0), vec![RECONNECT_DELAY]);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_reconnect_backoff() {
+		let dialer = Arc::new(ScriptedDialer::default());
+		let peer = peer(1);
+		for _ in 0..3 {
+			dialer.script(peer.0, ScriptedDial::Fail(PeerConnectError::TcpTimeout));
+		}
+		let (close_sender, close_receiver) = oneshot::channel();
+		dialer.script(peer.0, ScriptedDial::Connect(close_receiver));
+		dialer.script(peer.0, ScriptedDial::Fail(PeerConnectError::TcpTimeout));
+		dialer.script(peer.0, ScriptedDial::Fail(PeerConnectError::TcpTimeout));
+		let (connector, mut events) = create_connector(&dialer, "test_reconnect_backoff");
+
+		connector.add_peer(peer);
+		assert_eq!(events.recv().await, Some(ConnectorEvent::FirstAttemptFailed(peer.0)));
+		assert_eq!(events.recv().await, Some(ConnectorEvent::Connected(peer.0, peer.1)));
+		close_sender.send(()).unwrap();
+		assert_eq!(events.recv().await, Some(ConnectorEvent::Disconnected(peer.0)));
+		// wait for both failures after the disconnection
+		tokio::time::sleep(Duration::from_secs(60)).await;
+
+		// the delays double with each failure, and start over once connected
+		let intervals = dialer.dial_intervals(&peer.0);
+		let seconds = |secs: u64| Duration::from_secs(secs);
+		assert_eq!(intervals, vec![seconds(5), seconds(10), seconds(20), RECONNECT_DELAY, seconds(5), seconds(10)]);
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_reconnect_after_idle_disconnection() {
+		let dialer = Arc::new(ScriptedDialer::default());
+		let peer = peer(1);
+		let mut close_senders = Vec::new();
+		for _ in 0..4 {
+			let (close_sender, close_receiver) = oneshot::channel();
+			dialer.script(peer.0, ScriptedDial::Connect(close_receiver));
+			close_senders.push(close_sender);
+		}
+		let (connector, mut events) = create_connector(&dialer, "test_reconnect_after_idle_disconnection");
+
+		connector.add_peer(peer);
+		// closing the connection ourselves twice backs off, while the peer closing it doesn't
+		for (close_sender, is_idle) in close_senders.drain(..3).zip([true, true, false]) {
+			assert_eq!(events.recv().await, Some(ConnectorEvent::Connected(peer.0, peer.1)));
+			if is_idle {
+				connector.peer_stats.record_idle_disconnection(peer.0);
+			}
+			close_sender.send(()).unwrap();
+			assert_eq!(events.recv().await, Some(ConnectorEvent::Disconnected(peer.0)));
+		}
+		assert_eq!(events.recv().await, Some(ConnectorEvent::Connected(peer.0, peer.1)));
+		assert_eq!(dialer.dial_intervals(&peer.0), vec![RECONNECT_DELAY, RECONNECT_DELAY * 2, RECONNECT_DELAY]);
+		assert!(!connector.peer_stats.take_idle_disconnection(&peer.0));
+	}
+
+	#[test]
+	fn test_backoff_limit() {
+		let mut backoff = ReconnectBackoff::default();
+		let delays: Vec<Duration> = (0..15).map(|_| backoff.after_failure(Duration::from_secs(30))).collect();
+		assert_eq!(delays[..4], [Duration::from_secs(30), Duration::from_secs(60), Duration::from_secs(120), Duration::from_secs(240)]);
+		assert_eq!(delays[14], MAX_RECONNECT_DELAY);
+		// errors calling for longer delays than the maximum get theirs
+		backoff.reset();
+		assert_eq!(backoff.after_failure(Duration::from_secs(2 * 60 * 60)), Duration::from_secs(2 * 60 * 60));
+	}
+
+	#[tokio::test(start_paused = true)]
+	async fn test_peer_removal() {
+		let dialer = Arc::new(ScriptedDialer::default());
+		let (connected_peer, hanging_peer) = (peer(1), peer(2));
+		let (_close_sender, close_receiver) = oneshot::channel();
+		dialer.script(connected_peer.0, ScriptedDial::Connect(close_receiver));
+		let (connector, mut events) = create_connector(&dialer, "test_peer_removal");
+
+		connector.add_peer(connected_peer);
+		assert_eq!(events.recv().await, Some(ConnectorEvent::Connected(connected_peer.0, connected_peer.1)));
+		connector.add_peer(hanging_peer);
+		tokio::task::yield_now().await;
+
+		// the cancelled first attempt counts as failed
+		connector.remove_peer(&hanging_peer.0);
+		assert_eq!(events.recv().await, Some(ConnectorEvent::FirstAttemptFailed(hanging_peer.0)));
+		connector.remove_peer(&connected_peer.0);
+		assert_eq!(events.recv().await, Some(ConnectorEvent::Disconnected(connected_peer.0)));
+		assert_eq!(connector.connected_peer_count(), 0);
+		assert_eq!(*dialer.disconnected.lock().unwrap(), vec![hanging_peer.0, connected_peer.0]);
+
+		// dropping the connector stops the remaining tasks
+		let (_close_sender, close_receiver) = oneshot::channel();
+		dialer.script(connected_peer.0, ScriptedDial::Connect(close_receiver));
+		connector.add_peer(connected_peer);
+		assert_eq!(events.recv().await, Some(ConnectorEvent::Connected(connected_peer.0, connected_peer.1)));
+		drop(connector);
+		assert_eq!(events.recv().await, Some(ConnectorEvent::Disconnected(connected_peer.0)));
+		assert_eq!(events.recv().await, None);
+	}
+}
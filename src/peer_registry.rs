@@ -0,0 +1,909 @@
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::net::{IpAddr, SocketAddr};
+use std::ops::Deref;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bitcoin::blockdata::constants::ChainHash;
+use bitcoin::secp256k1::PublicKey;
+use hex_conservative::display::DisplayHex;
+use lightning::{log_debug, log_warn};
+use lightning::events::MessageSendEvent;
+use lightning::ln::msgs::{QueryChannelRange, SocketAddress};
+use lightning::util::logger::Logger;
+use tokio::net::{TcpSocket, TcpStream};
+use tokio::task::JoinHandle;
+use thiserror::Error;
+use tokio_postgres::Client;
+
+use crate::config;
+use crate::downloader::GossipCounter;
+use crate::error::{ErrorContext, ProcessorError};
+use crate::persistence;
+use crate::socket_stats::{self, ByteCounts};
+use crate::tables::Tables;
+use crate::types::GossipPeerManager;
+
+/// A newly advertised address is trusted once this many consecutive node announcements agree on it
+const ADDRESS_CONFIRMATION_ANNOUNCEMENTS: u32 = 2;
+/// A newly advertised address is also trusted once no other address has been advertised for this long
+const ADDRESS_CONFIRMATION_WINDOW: Duration = Duration::from_secs(6 * 60 * 60);
+/// How long establishing the TCP connection to a peer may take
+const TCP_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long the Lightning handshake with a peer may take once the TCP connection is established
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long a silent peer has to answer a ping before it's disconnected
+const PING_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Resolves once the connection to a peer has been closed
+pub(crate) type DisconnectionFuture = JoinHandle<()>;
+
+/// Why connecting to a peer failed
+#[derive(Debug, Error)]
+pub(crate) enum PeerConnectError {
+	#[error("connection refused")]
+	TcpConnectionRefused,
+	#[error("TCP connection timed out")]
+	TcpTimeout,
+	#[error("TCP connection failed: {0}")]
+	TcpFailed(io::Error),
+	#[error("handshake failed: {reason}")]
+	HandshakeFailed { reason: String },
+	/// The peer hung up during the handshake, which is how peers react to being addressed with a
+	/// node id other than their own
+	#[error("peer closed the connection during the handshake, its node id is likely wrong")]
+	AuthenticationFailed,
+	/// Outbound connections are bound to local addresses, but none of the peer's address family
+	#[error("no local bind address is configured for the address family of {peer}")]
+	AddressFamilyMismatch { peer: SocketAddr },
+}
+
+impl PeerConnectError {
+	/// How long to wait before trying again. Network hiccups are retried sooner than failures that
+	/// won't go away by themselves, such as a misconfigured node id, which only a peer list reload
+	/// can fix.
+	pub(crate) fn retry_delay(&self) -> Duration {
+		match self {
+			PeerConnectError::TcpTimeout => Duration::from_secs(5),
+			PeerConnectError::TcpConnectionRefused | PeerConnectError::TcpFailed(_) => Duration::from_secs(30),
+			PeerConnectError::HandshakeFailed { .. } => Duration::from_secs(60),
+			PeerConnectError::AuthenticationFailed | PeerConnectError::AddressFamilyMismatch { .. } => Duration::from_secs(60 * 60),
+		}
+	}
+}
+
+/// The local addresses outbound connections to peers are bound to, so that they leave through a
+/// specific interface rather than the default route. If neither is set, the OS picks the address.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct LocalBindAddresses {
+	pub(crate) ipv4: Option<IpAddr>,
+	pub(crate) ipv6: Option<IpAddr>,
+}
+
+impl LocalBindAddresses {
+	/// Parse a comma separated list of at most one IPv4 and one IPv6 address
+	pub(crate) fn parse(list: &str) -> Result<Self, String> {
+		let mut addresses = Self::default();
+		for entry in list.split(',').map(str::trim).filter(|entry| !entry.is_empty()) {
+			let address: IpAddr = entry.parse().map_err(|_| format!("{} is not an IP address", entry))?;
+			let slot = if address.is_ipv4() { &mut addresses.ipv4 } else { &mut addresses.ipv6 };
+			if slot.replace(address).is_some() {
+				return Err(format!("more than one {} address is given", if address.is_ipv4() { "IPv4" } else { "IPv6" }));
+			}
+		}
+		Ok(addresses)
+	}
+
+	/// The address to bind the connection to the given peer to, if any
+	fn for_peer(&self, peer: SocketAddr) -> Result<Option<IpAddr>, PeerConnectError> {
+		if self.ipv4.is_none() && self.ipv6.is_none() {
+			return Ok(None);
+		}
+		let address = if peer.is_ipv4() { self.ipv4 } else { self.ipv6 };
+		address.map(Some).ok_or(PeerConnectError::AddressFamilyMismatch { peer })
+	}
+}
+
+/// The changes needed to go from one peer list to another
+#[derive(Debug, PartialEq)]
+pub(crate) struct PeerListDiff {
+	pub(crate) added: Vec<(PublicKey, SocketAddr)>,
+	/// Peers that are no longer listed, or whose address changed
+	pub(crate) removed: Vec<PublicKey>,
+	pub(crate) unchanged_count: usize,
+}
+
+pub(crate) fn diff_peer_lists(current_peers: &HashMap<PublicKey, SocketAddr>, new_peers: &[(PublicKey, SocketAddr)]) -> PeerListDiff {
+	let new_peer_map: HashMap<PublicKey, SocketAddr> = new_peers.iter().cloned().collect();
+	let mut removed: Vec<PublicKey> = current_peers.iter()
+		.filter(|(pubkey, address)| new_peer_map.get(pubkey) != Some(address))
+		.map(|(pubkey, _)| *pubkey)
+		.collect();
+	removed.sort_unstable();
+	let added: Vec<(PublicKey, SocketAddr)> = new_peer_map.iter()
+		.filter(|(pubkey, address)| current_peers.get(pubkey) != Some(address))
+		.map(|(pubkey, address)| (*pubkey, *address))
+		.collect();
+	let unchanged_count = new_peer_map.len() - added.len();
+	PeerListDiff { added, removed, unchanged_count }
+}
+
+/// The outcome of [`GossipPeerManagerExt::safe_disconnect`]
+#[derive(Debug, PartialEq)]
+pub(crate) enum DisconnectResult {
+	Disconnected,
+	/// The peer wasn't connected (anymore), so there was nothing to do
+	AlreadyDisconnected,
+}
+
+pub(crate) trait GossipPeerManagerExt {
+	/// Disconnect from the peer, if connected
+	fn safe_disconnect(&self, pubkey: &PublicKey) -> DisconnectResult;
+	/// The peers we have completed the handshake with, and their address if it is an IP address
+	fn connected_peers(&self) -> Vec<(PublicKey, SocketAddr)>;
+}
+
+impl<L: Deref + Clone + Send + Sync + 'static> GossipPeerManagerExt for GossipPeerManager<L> where L::Target: Logger {
+	fn safe_disconnect(&self, pubkey: &PublicKey) -> DisconnectResult {
+		if self.peer_by_node_id(pubkey).is_none() {
+			return DisconnectResult::AlreadyDisconnected;
+		}
+		// should the peer disconnect in the meantime, this is a no-op
+		self.disconnect_by_node_id(*pubkey);
+		DisconnectResult::Disconnected
+	}
+
+	fn connected_peers(&self) -> Vec<(PublicKey, SocketAddr)> {
+		self.list_peers().into_iter().filter_map(|peer| {
+			Some((peer.counterparty_node_id, ip_socket_address(&peer.socket_address?)?))
+		}).collect()
+	}
+}
+
+/// The address, if it is an IP address
+pub(crate) fn ip_socket_address(address: &SocketAddress) -> Option<SocketAddr> {
+	match address {
+		SocketAddress::TcpIpV4 { addr, port } => Some(SocketAddr::from((*addr, *port))),
+		SocketAddress::TcpIpV6 { addr, port } => Some(SocketAddr::from((*addr, *port))),
+		_ => None,
+	}
+}
+
+struct PendingAddress {
+	address: SocketAddr,
+	announcement_count: u32,
+	first_advertised_at: Instant,
+}
+
+#[derive(Default)]
+struct PeerAddresses {
+	/// The address the peer was configured with, if it's currently configured
+	configured: Option<SocketAddr>,
+	/// The most recently confirmed address from the peer's node announcements
+	learned: Option<SocketAddr>,
+	/// An advertised address that isn't trusted yet, lest a flapping announcement be followed
+	pending: Option<PendingAddress>,
+}
+
+impl PeerAddresses {
+	fn confirm_pending(&mut self, now: Instant) -> Option<SocketAddr> {
+		let pending = self.pending.as_ref()?;
+		if pending.announcement_count < ADDRESS_CONFIRMATION_ANNOUNCEMENTS && now.duration_since(pending.first_advertised_at) < ADDRESS_CONFIRMATION_WINDOW {
+			return None;
+		}
+		self.learned = self.pending.take().map(|pending| pending.address);
+		self.learned
+	}
+}
+
+/// Learns configured peers' current addresses from their node announcements, so that connections
+/// can follow peers that moved since they were configured
+pub(crate) struct AddressBook {
+	peers: Mutex<HashMap<PublicKey, PeerAddresses>>,
+}
+
+impl AddressBook {
+	pub(crate) fn new() -> Self {
+		Self { peers: Mutex::new(HashMap::new()) }
+	}
+
+	/// Start learning the addresses of a configured peer. A learned address is forgotten if the
+	/// configured address changed, as that's the operator's more deliberate choice.
+	pub(crate) fn track(&self, pubkey: PublicKey, configured: SocketAddr) {
+		let mut peers = self.peers.lock().expect("address book lock poisoned");
+		let addresses = peers.entry(pubkey).or_default();
+		if addresses.configured.map_or(false, |previous| previous != configured) {
+			addresses.learned = None;
+			addresses.pending = None;
+		}
+		addresses.configured = Some(configured);
+	}
+
+	pub(crate) fn untrack(&self, pubkey: &PublicKey) {
+		if let Some(addresses) = self.peers.lock().expect("address book lock poisoned").get_mut(pubkey) {
+			addresses.configured = None;
+		}
+	}
+
+	/// Record the addresses a configured peer's node announcement advertises, returning the
+	/// address that has just been confirmed, if any
+	pub(crate) fn observe_announcement(&self, pubkey: &PublicKey, advertised: &[SocketAddress]) -> Option<SocketAddr> {
+		let mut peers = self.peers.lock().expect("address book lock poisoned");
+		let addresses = peers.get_mut(pubkey).filter(|addresses| addresses.configured.is_some())?;
+		// announcements list addresses in order of preference
+		let advertised = advertised.iter().find_map(ip_socket_address)?;
+		let current = addresses.learned.or(addresses.configured);
+
+		let now = Instant::now();
+		if current == Some(advertised) {
+			addresses.pending = None;
+			return None;
+		}
+		match addresses.pending.as_mut() {
+			Some(pending) if pending.address == advertised => pending.announcement_count += 1,
+			_ => addresses.pending = Some(PendingAddress { address: advertised, announcement_count: 1, first_advertised_at: now }),
+		}
+		addresses.confirm_pending(now)
+	}
+
+	/// The addresses to try connecting to, freshest first, with the configured one as fallback
+	pub(crate) fn candidates(&self, pubkey: &PublicKey, configured: SocketAddr) -> Vec<SocketAddr> {
+		let mut peers = self.peers.lock().expect("address book lock poisoned");
+		let mut candidates = Vec::with_capacity(2);
+		if let Some(addresses) = peers.get_mut(pubkey) {
+			addresses.confirm_pending(Instant::now());
+			candidates.extend(addresses.learned);
+		}
+		if !candidates.contains(&configured) {
+			candidates.push(configured);
+		}
+		candidates
+	}
+
+	/// Restore the addresses learned before a restart
+	pub(crate) async fn load(&self, client: &Client, tables: &Tables) -> Result<usize, ProcessorError> {
+		let rows = client.query(&format!("SELECT public_key, address FROM {} WHERE address IS NOT NULL", tables.peer_state()), &[]).await
+			.context("Failed to read learned peer addresses")?;
+		let mut peers = self.peers.lock().expect("address book lock poisoned");
+		let mut loaded_count = 0;
+		for row in rows {
+			let pubkey = <[u8; 33]>::try_from(row.get::<_, &[u8]>("public_key")).ok()
+				.and_then(|bytes| persistence::bytes_to_pubkey(&bytes).ok());
+			let address = row.get::<_, &str>("address").parse::<SocketAddr>();
+			if let (Some(pubkey), Ok(address)) = (pubkey, address) {
+				peers.entry(pubkey).or_default().learned = Some(address);
+				loaded_count += 1;
+			}
+		}
+		Ok(loaded_count)
+	}
+}
+
+/// What's known about a configured peer's connection, for the debug dump
+#[derive(Clone, Debug, Default)]
+pub(crate) struct PeerConnectionRecord {
+	/// The address of the current or most recent connection
+	pub(crate) address: Option<SocketAddr>,
+	/// The unix timestamp since which the peer has been connected, if it's connected
+	pub(crate) connected_since: Option<u64>,
+	pub(crate) last_disconnected_at: Option<u64>,
+	/// The number of connections established after the first one
+	pub(crate) reconnect_count: u32,
+	pub(crate) last_connection_error: Option<String>,
+	/// The unix timestamp of the latest message handled on the peer's behalf. LDK doesn't tell
+	/// which peer gossip messages came from, so only handshakes and gossip queries and their
+	/// replies count.
+	pub(crate) last_message_at: Option<u64>,
+	/// The bytes received and sent over all closed connections
+	pub(crate) closed_connection_bytes: ByteCounts,
+	/// How long all closed connections lasted, in seconds
+	pub(crate) closed_connection_secs: u64,
+	/// The bytes the open connection had received as of the latest idleness check, and the unix
+	/// timestamp at which that count last grew
+	pub(crate) latest_receipt: Option<(u64, u64)>,
+	/// Whether the open connection was closed by us for being idle, rather than by the peer
+	pub(crate) is_dropped_idle: bool,
+}
+
+/// Connection records of all peers connected to since startup
+pub(crate) struct PeerConnectionStats {
+	peers: Mutex<HashMap<PublicKey, PeerConnectionRecord>>,
+	/// Duplicates of the open connections' sockets, only used to read their byte counts
+	sockets: Mutex<HashMap<PublicKey, std::net::TcpStream>>,
+}
+
+impl PeerConnectionStats {
+	pub(crate) fn new() -> Self {
+		Self { peers: Mutex::new(HashMap::new()), sockets: Mutex::new(HashMap::new()) }
+	}
+
+	pub(crate) fn record_connection(&self, pubkey: PublicKey, address: SocketAddr, socket: Option<std::net::TcpStream>) {
+		self.record_connection_at(pubkey, address, socket, unix_timestamp());
+	}
+
+	fn record_connection_at(&self, pubkey: PublicKey, address: SocketAddr, socket: Option<std::net::TcpStream>, now: u64) {
+		let mut peers = self.peers.lock().expect("peer connection stats lock poisoned");
+		let record = peers.entry(pubkey).or_default();
+		if record.connected_since.is_some() || record.last_disconnected_at.is_some() {
+			record.reconnect_count += 1;
+		}
+		record.address = Some(address);
+		record.connected_since = Some(now);
+		record.latest_receipt = Some((0, now));
+		record.is_dropped_idle = false;
+		let mut sockets = self.sockets.lock().expect("peer connection stats lock poisoned");
+		match socket {
+			Some(socket) => sockets.insert(pubkey, socket),
+			None => sockets.remove(&pubkey),
+		};
+	}
+
+	pub(crate) fn record_disconnection(&self, pubkey: PublicKey) {
+		self.record_disconnection_at(pubkey, unix_timestamp());
+	}
+
+	fn record_disconnection_at(&self, pubkey: PublicKey, now: u64) {
+		let mut peers = self.peers.lock().expect("peer connection stats lock poisoned");
+		let record = peers.entry(pubkey).or_default();
+		// the counters survive the connection closing for as long as the socket isn't dropped
+		let socket = self.sockets.lock().expect("peer connection stats lock poisoned").remove(&pubkey);
+		if let Some(byte_counts) = socket.as_ref().and_then(socket_stats::read_byte_counts) {
+			record.closed_connection_bytes.received += byte_counts.received;
+			record.closed_connection_bytes.sent += byte_counts.sent;
+		}
+		if let Some(connected_since) = record.connected_since.take() {
+			record.closed_connection_secs += now.saturating_sub(connected_since);
+		}
+		record.last_disconnected_at = Some(now);
+	}
+
+	pub(crate) fn record_connection_error(&self, pubkey: PublicKey, error: &PeerConnectError) {
+		self.peers.lock().expect("peer connection stats lock poisoned").entry(pubkey).or_default().last_connection_error = Some(error.to_string());
+	}
+
+	pub(crate) fn record_message(&self, pubkey: PublicKey) {
+		self.peers.lock().expect("peer connection stats lock poisoned").entry(pubkey).or_default().last_message_at = Some(unix_timestamp());
+	}
+
+	/// The connected peers whose connections received nothing at all in the last `threshold_secs`.
+	/// Connections whose byte counts aren't available are never considered idle.
+	pub(crate) fn idle_peers(&self, threshold_secs: u64) -> Vec<PublicKey> {
+		let received_bytes: Vec<(PublicKey, u64)> = self.sockets.lock().expect("peer connection stats lock poisoned").iter()
+			.filter_map(|(pubkey, socket)| Some((*pubkey, socket_stats::read_byte_counts(socket)?.received)))
+			.collect();
+		self.idle_peers_at(&received_bytes, unix_timestamp(), threshold_secs)
+	}
+
+	fn idle_peers_at(&self, received_bytes: &[(PublicKey, u64)], now: u64, threshold_secs: u64) -> Vec<PublicKey> {
+		let mut peers = self.peers.lock().expect("peer connection stats lock poisoned");
+		received_bytes.iter().filter(|(pubkey, received)| {
+			let record = match peers.get_mut(pubkey) {
+				Some(record) if record.connected_since.is_some() && !record.is_dropped_idle => record,
+				_ => return false,
+			};
+			match record.latest_receipt {
+				Some((latest_received, last_receipt_at)) if latest_received >= *received => now.saturating_sub(last_receipt_at) >= threshold_secs,
+				_ => {
+					record.latest_receipt = Some((*received, now));
+					false
+				},
+			}
+		}).map(|(pubkey, _)| *pubkey).collect()
+	}
+
+	/// Note that we're about to close the peer's connection for being idle
+	pub(crate) fn record_idle_disconnection(&self, pubkey: PublicKey) {
+		self.peers.lock().expect("peer connection stats lock poisoned").entry(pubkey).or_default().is_dropped_idle = true;
+	}
+
+	/// Whether the peer's latest connection was closed for being idle, forgetting that it was
+	pub(crate) fn take_idle_disconnection(&self, pubkey: &PublicKey) -> bool {
+		self.peers.lock().expect("peer connection stats lock poisoned").get_mut(pubkey)
+			.map_or(false, |record| std::mem::take(&mut record.is_dropped_idle))
+	}
+
+	fn last_message_at(&self, pubkey: &PublicKey) -> Option<u64> {
+		self.peers.lock().expect("peer connection stats lock poisoned").get(pubkey).and_then(|record| record.last_message_at)
+	}
+
+	/// The communication statistics of a peer connected to since startup
+	pub(crate) fn peer_stats(&self, pubkey: &PublicKey) -> Option<PeerStats> {
+		self.peer_stats_at(pubkey, unix_timestamp())
+	}
+
+	fn peer_stats_at(&self, pubkey: &PublicKey, now: u64) -> Option<PeerStats> {
+		let peers = self.peers.lock().expect("peer connection stats lock poisoned");
+		let record = peers.get(pubkey)?;
+		let open_connection_bytes = self.sockets.lock().expect("peer connection stats lock poisoned").get(pubkey)
+			.and_then(socket_stats::read_byte_counts).unwrap_or_default();
+		let open_connection_secs = record.connected_since.map_or(0, |connected_since| now.saturating_sub(connected_since));
+		Some(PeerStats {
+			connected: record.connected_since.is_some(),
+			bytes_received: record.closed_connection_bytes.received + open_connection_bytes.received,
+			bytes_sent: record.closed_connection_bytes.sent + open_connection_bytes.sent,
+			last_message_at: record.last_message_at,
+			connected_secs: record.closed_connection_secs + open_connection_secs,
+		})
+	}
+
+	/// The communication statistics of all peers connected to since startup, sorted by public key
+	pub(crate) fn all_peer_stats(&self) -> Vec<(PublicKey, PeerStats)> {
+		let mut pubkeys: Vec<PublicKey> = self.peers.lock().expect("peer connection stats lock poisoned").keys().copied().collect();
+		pubkeys.sort_unstable();
+		pubkeys.into_iter().filter_map(|pubkey| Some((pubkey, self.peer_stats(&pubkey)?))).collect()
+	}
+
+	/// A copy of all records, sorted by public key
+	pub(crate) fn snapshot(&self) -> Vec<(PublicKey, PeerConnectionRecord)> {
+		let mut records: Vec<(PublicKey, PeerConnectionRecord)> = self.peers.lock().expect("peer connection stats lock poisoned")
+			.iter().map(|(pubkey, record)| (*pubkey, record.clone())).collect();
+		records.sort_unstable_by_key(|(pubkey, _)| *pubkey);
+		records
+	}
+}
+
+/// Cumulative statistics of the communication with a peer since startup, across reconnections.
+/// LDK's `PeerManager` doesn't count bytes per peer, so they're read from the kernel's statistics
+/// of the connections' sockets, which only Linux reports, and are 0 elsewhere.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PeerStats {
+	pub connected: bool,
+	pub bytes_received: u64,
+	/// The bytes the peer acknowledged receiving
+	pub bytes_sent: u64,
+	/// The unix timestamp of the latest message handled on the peer's behalf, which only includes
+	/// handshakes and gossip queries and their replies, as LDK doesn't tell which peer gossip
+	/// messages came from
+	pub last_message_at: Option<u64>,
+	/// How long the peer has been connected in total, in seconds
+	pub connected_secs: u64,
+}
+
+impl PeerStats {
+	pub fn to_json(&self) -> String {
+		format!(
+			"{{\"connected\":{},\"bytes_received\":{},\"bytes_sent\":{},\"last_message_at\":{},\"connected_secs\":{}}}",
+			self.connected,
+			self.bytes_received,
+			self.bytes_sent,
+			self.last_message_at.map_or("null".to_string(), |timestamp| timestamp.to_string()),
+			self.connected_secs,
+		)
+	}
+}
+
+/// Serialize the statistics of all peers as Prometheus counters and gauges labeled by the peers'
+/// public keys in hex
+pub(crate) fn peer_stats_to_prometheus(peer_stats: &[(PublicKey, PeerStats)]) -> String {
+	let metrics: [(&str, &str, &str, fn(&PeerStats) -> Option<u64>); 4] = [
+		("rgs_peer_bytes_received_total", "counter", "Bytes received from the peer since startup", |stats| Some(stats.bytes_received)),
+		("rgs_peer_bytes_sent_total", "counter", "Bytes sent to and acknowledged by the peer since startup", |stats| Some(stats.bytes_sent)),
+		("rgs_peer_connected_seconds_total", "counter", "Time the peer has been connected since startup", |stats| Some(stats.connected_secs)),
+		("rgs_peer_last_message_timestamp_seconds", "gauge", "Unix timestamp of the latest message handled on the peer's behalf", |stats| stats.last_message_at),
+	];
+	let mut output = String::new();
+	for (name, metric_type, help, value) in metrics {
+		output.push_str(&format!("# HELP {} {}\n", name, help));
+		output.push_str(&format!("# TYPE {} {}\n", name, metric_type));
+		for (pubkey, stats) in peer_stats {
+			if let Some(value) = value(stats) {
+				output.push_str(&format!("{}{{peer=\"{}\"}} {}\n", name, pubkey.serialize().to_lower_hex_string(), value));
+			}
+		}
+	}
+	output
+}
+
+/// Messages for the router to hand to the peer manager, such as channel range queries sent to
+/// individual peers
+pub(crate) type PendingMessageQueue = Arc<Mutex<Vec<MessageSendEvent>>>;
+
+/// Whether a peer last heard from at `last_message_at` has been silent for at least
+/// `threshold_secs`
+fn is_silent(last_message_at: Option<u64>, now: u64, threshold_secs: u64) -> bool {
+	last_message_at.map_or(true, |last_message_at| now.saturating_sub(last_message_at) >= threshold_secs)
+}
+
+/// Pings peers that went silent, disconnecting those that don't answer, so that their connection
+/// tasks reconnect.
+///
+/// LDK answers BOLT 1 pings and consumes the pongs on its own, pinging every peer on each timer
+/// tick and disconnecting those whose pong doesn't arrive in time. That only catches dead
+/// connections though, as a peer whose gossip handling stalled still answers pings. The ping sent
+/// here is therefore a channel range query for the genesis block, which BOLT 7 requires peers
+/// supporting gossip queries to answer, and whose reply the router records. Peers not supporting
+/// gossip queries are left to LDK's pings.
+///
+/// Connections that received nothing at all for much longer, not even replies to LDK's pings, are
+/// black holes, and are closed regardless of the peer's features.
+pub(crate) struct SilentPeerPinger<L: Deref + Clone + Send + Sync + 'static> where L::Target: Logger {
+	peer_manager: GossipPeerManager<L>,
+	peer_stats: Arc<PeerConnectionStats>,
+	pending_messages: PendingMessageQueue,
+	counter: Arc<RwLock<GossipCounter>>,
+	/// Peers whose ping is awaiting an answer, which aren't pinged again in the meantime
+	awaiting_answer: Arc<Mutex<HashSet<PublicKey>>>,
+	logger: L,
+}
+
+impl<L: Deref + Clone + Send + Sync + 'static> SilentPeerPinger<L> where L::Target: Logger {
+	pub(crate) fn new(peer_manager: GossipPeerManager<L>, peer_stats: Arc<PeerConnectionStats>, pending_messages: PendingMessageQueue, counter: Arc<RwLock<GossipCounter>>, logger: L) -> Self {
+		Self { peer_manager, peer_stats, pending_messages, counter, awaiting_answer: Arc::new(Mutex::new(HashSet::new())), logger }
+	}
+
+	/// Ping the connected peers that sent no messages in the last `threshold_secs`, and disconnect
+	/// those that don't answer within [`PING_TIMEOUT`]
+	pub(crate) fn ping_silent_peers(&self, threshold_secs: u64) {
+		let now = unix_timestamp();
+		let silent_peers: Vec<PublicKey> = {
+			let mut awaiting_answer = self.awaiting_answer.lock().expect("ping lock poisoned");
+			self.peer_manager.list_peers().into_iter()
+				.filter(|peer| peer.init_features.supports_gossip_queries())
+				.map(|peer| peer.counterparty_node_id)
+				.filter(|pubkey| is_silent(self.peer_stats.last_message_at(pubkey), now, threshold_secs))
+				.filter(|pubkey| awaiting_answer.insert(*pubkey))
+				.collect()
+		};
+		if silent_peers.is_empty() {
+			return;
+		}
+
+		self.counter.write().expect("gossip counter lock poisoned").peers_pinged += silent_peers.len() as u64;
+		{
+			let mut pending_messages = self.pending_messages.lock().expect("range query lock poisoned");
+			for pubkey in &silent_peers {
+				log_debug!(self.logger, "Pinging peer {}, which sent nothing in {}s", pubkey.serialize().to_lower_hex_string(), threshold_secs);
+				pending_messages.push(MessageSendEvent::SendChannelRangeQuery {
+					node_id: *pubkey,
+					msg: QueryChannelRange { chain_hash: ChainHash::using_genesis_block(config::network()), first_blocknum: 0, number_of_blocks: 1 },
+				});
+			}
+		}
+		self.peer_manager.process_events();
+
+		for pubkey in silent_peers {
+			let peer_manager = Arc::clone(&self.peer_manager);
+			let peer_stats = Arc::clone(&self.peer_stats);
+			let counter = Arc::clone(&self.counter);
+			let awaiting_answer = Arc::clone(&self.awaiting_answer);
+			let logger = self.logger.clone();
+			tokio::spawn(async move {
+				tokio::time::sleep(PING_TIMEOUT).await;
+				awaiting_answer.lock().expect("ping lock poisoned").remove(&pubkey);
+				let is_answered = peer_stats.last_message_at(&pubkey).map_or(false, |last_message_at| last_message_at >= now);
+				if is_answered {
+					return;
+				}
+				// the connection task reconnects once the connection is closed
+				if peer_manager.safe_disconnect(&pubkey) == DisconnectResult::Disconnected {
+					log_warn!(logger, "Disconnecting peer {}, which didn't answer a ping within {:?}", pubkey.serialize().to_lower_hex_string(), PING_TIMEOUT);
+					counter.write().expect("gossip counter lock poisoned").peers_dropped_after_ping_timeout += 1;
+				}
+			});
+		}
+	}
+
+	/// Disconnect the connected peers whose connections received nothing in the last
+	/// `threshold_secs`, which their connection tasks reconnect after backing off
+	pub(crate) fn disconnect_idle_peers(&self, threshold_secs: u64) {
+		for pubkey in self.peer_stats.idle_peers(threshold_secs) {
+			// noted first, so that the connection task can tell who closed the connection
+			self.peer_stats.record_idle_disconnection(pubkey);
+			if self.peer_manager.safe_disconnect(&pubkey) == DisconnectResult::Disconnected {
+				log_warn!(self.logger, "Disconnecting peer {}, which sent not a single byte in {}s", pubkey.serialize().to_lower_hex_string(), threshold_secs);
+				self.counter.write().expect("gossip counter lock poisoned").peers_dropped_idle += 1;
+			} else {
+				self.peer_stats.take_idle_disconnection(&pubkey);
+			}
+		}
+	}
+}
+
+fn unix_timestamp() -> u64 {
+	SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch").as_secs()
+}
+
+/// Record a learned address, so that it survives restarts
+pub(crate) async fn persist_learned_address(client: &Client, tables: &Tables, pubkey: &PublicKey, address: SocketAddr) -> Result<(), ProcessorError> {
+	client.execute(&format!("INSERT INTO {} (public_key, address, learned_at) VALUES ($1, $2, NOW())
+		ON CONFLICT (public_key) DO UPDATE SET address = EXCLUDED.address, learned_at = EXCLUDED.learned_at", tables.peer_state()),
+		&[&persistence::pubkey_to_bytes(pubkey).to_vec(), &address.to_string()]).await
+		.context("Failed to persist learned peer address")?;
+	Ok(())
+}
+
+/// Open a TCP connection, from the given local address if any
+async fn connect_tcp(address: SocketAddr, local_address: Option<IpAddr>) -> io::Result<TcpStream> {
+	let local_address = match local_address {
+		Some(local_address) => local_address,
+		None => return TcpStream::connect(address).await,
+	};
+	let socket = if address.is_ipv4() { TcpSocket::new_v4()? } else { TcpSocket::new_v6()? };
+	socket.bind(SocketAddr::new(local_address, 0))?;
+	socket.connect(address).await
+}
+
+/// Connect to a peer, telling apart the ways in which that can fail. Once the handshake completed,
+/// the returned future resolves when the connection closes. It's returned along with a duplicate of
+/// the connection's socket for reading its byte counts, unless duplicating it failed.
+pub(crate) async fn connect_with_error_classification<L: Deref + Clone + Send + Sync + 'static>(peer_manager: &GossipPeerManager<L>, peer: (PublicKey, SocketAddr), local_addresses: &LocalBindAddresses) -> Result<(DisconnectionFuture, Option<std::net::TcpStream>), PeerConnectError> where L::Target: Logger {
+	let local_address = local_addresses.for_peer(peer.1)?;
+	let stream = match tokio::time::timeout(TCP_CONNECT_TIMEOUT, connect_tcp(peer.1, local_address)).await {
+		Ok(Ok(stream)) => stream,
+		Ok(Err(error)) if error.kind() == io::ErrorKind::ConnectionRefused => return Err(PeerConnectError::TcpConnectionRefused),
+		Ok(Err(error)) if error.kind() == io::ErrorKind::TimedOut => return Err(PeerConnectError::TcpTimeout),
+		Ok(Err(error)) => return Err(PeerConnectError::TcpFailed(error)),
+		Err(_) => return Err(PeerConnectError::TcpTimeout),
+	};
+	let stream = stream.into_std().map_err(|error| PeerConnectError::HandshakeFailed { reason: error.to_string() })?;
+	let socket = stream.try_clone().ok();
+	// the connection only makes progress while its future is polled
+	let mut disconnection_future = tokio::spawn(lightning_net_tokio::setup_outbound(Arc::clone(peer_manager), peer.0, stream));
+
+	let handshake = async {
+		while peer_manager.peer_by_node_id(&peer.0).is_none() {
+			tokio::time::sleep(Duration::from_millis(50)).await;
+		}
+	};
+	let error = tokio::select! {
+		_ = handshake => None,
+		_ = &mut disconnection_future => Some(PeerConnectError::AuthenticationFailed),
+		// the peer manager's timer disconnects peers stuck in the handshake by itself
+		_ = tokio::time::sleep(HANDSHAKE_TIMEOUT) => Some(PeerConnectError::HandshakeFailed { reason: format!("not completed within {:?}", HANDSHAKE_TIMEOUT) }),
+	};
+	match error {
+		Some(error) => Err(error),
+		None => Ok((disconnection_future, socket)),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use bitcoin::Network;
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+	use lightning::ln::peer_handler::{ErroringMessageHandler, MessageHandler, PeerManager};
+	use lightning::routing::gossip::NetworkGraph;
+	use lightning::sign::{KeysManager, NodeSigner, Recipient};
+	use std::str::FromStr;
+	use std::sync::RwLock;
+	use tokio::sync::mpsc;
+	use crate::chain_stats::ChainBackendStats;
+	use crate::counting_handler::CountingMessageHandler;
+	use crate::downloader::{GossipCounter, GossipRouter};
+	use crate::types::tests::TestLogger;
+
+	fn create_peer_manager(seed: u8, logger: Arc<TestLogger>) -> (GossipPeerManager<Arc<TestLogger>>, PublicKey) {
+		let network_graph = Arc::new(NetworkGraph::new(Network::Bitcoin, Arc::clone(&logger)));
+		let (persistence_sender, _) = mpsc::channel(1);
+		let counter = Arc::new(RwLock::new(GossipCounter::new()));
+		let router = Arc::new(GossipRouter::new(network_graph, persistence_sender, Arc::clone(&counter), config::bitcoin_rest_endpoint(), Arc::new(ChainBackendStats::new()), Arc::clone(&logger)));
+		let ignored_message_handler = Arc::new(CountingMessageHandler::new(counter, Arc::clone(&logger)));
+		let keys_manager = Arc::new(KeysManager::new(&[seed; 32], 0, 0));
+		let node_id = keys_manager.get_node_id(Recipient::Node).unwrap();
+		let message_handler = MessageHandler {
+			chan_handler: ErroringMessageHandler::new(),
+			route_handler: Arc::clone(&router),
+			onion_message_handler: Arc::clone(&ignored_message_handler),
+			custom_message_handler: ignored_message_handler,
+		};
+		let peer_manager = Arc::new(PeerManager::new(message_handler, 0, &[seed; 32], logger, keys_manager));
+		router.set_pm(Arc::clone(&peer_manager));
+		(peer_manager, node_id)
+	}
+
+	#[tokio::test]
+	async fn test_safe_disconnect() {
+		let logger = Arc::new(TestLogger::with_id("test_safe_disconnect".to_string()));
+		let (local_peer_manager, _) = create_peer_manager(1, Arc::clone(&logger));
+		let (remote_peer_manager, remote_node_id) = create_peer_manager(2, Arc::clone(&logger));
+
+		// a peer that was never connected
+		assert!(local_peer_manager.connected_peers().is_empty());
+		assert_eq!(local_peer_manager.safe_disconnect(&remote_node_id), DisconnectResult::AlreadyDisconnected);
+
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let remote_address = listener.local_addr().unwrap();
+		tokio::spawn(async move {
+			let (stream, _) = listener.accept().await.unwrap();
+			lightning_net_tokio::setup_inbound(remote_peer_manager, stream.into_std().unwrap()).await;
+		});
+		lightning_net_tokio::connect_outbound(Arc::clone(&local_peer_manager), remote_node_id, remote_address).await.unwrap();
+		for _ in 0..100 {
+			if !local_peer_manager.list_peers().is_empty() {
+				break;
+			}
+			tokio::time::sleep(Duration::from_millis(10)).await;
+		}
+
+		// a connected peer
+		assert_eq!(local_peer_manager.connected_peers(), vec![(remote_node_id, remote_address)]);
+		assert_eq!(local_peer_manager.safe_disconnect(&remote_node_id), DisconnectResult::Disconnected);
+		assert!(local_peer_manager.connected_peers().is_empty());
+
+		// a peer that was disconnected before
+		assert_eq!(local_peer_manager.safe_disconnect(&remote_node_id), DisconnectResult::AlreadyDisconnected);
+	}
+
+	#[tokio::test]
+	async fn test_connect_error_classification() {
+		let logger = Arc::new(TestLogger::with_id("test_connect_error_classification".to_string()));
+		let (local_peer_manager, _) = create_peer_manager(1, Arc::clone(&logger));
+		let (remote_peer_manager, remote_node_id) = create_peer_manager(2, Arc::clone(&logger));
+		let (_, other_node_id) = create_peer_manager(3, Arc::clone(&logger));
+
+		// nobody listening
+		let closed_address = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap().local_addr().unwrap();
+		let result = connect_with_error_classification(&local_peer_manager, (remote_node_id, closed_address), &LocalBindAddresses::default()).await;
+		assert!(matches!(result, Err(PeerConnectError::TcpConnectionRefused)));
+
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let remote_address = listener.local_addr().unwrap();
+		tokio::spawn(async move {
+			loop {
+				let (stream, _) = listener.accept().await.unwrap();
+				tokio::spawn(lightning_net_tokio::setup_inbound(Arc::clone(&remote_peer_manager), stream.into_std().unwrap()));
+			}
+		});
+
+		// the remote can't decrypt a handshake addressed to another node id
+		let result = connect_with_error_classification(&local_peer_manager, (other_node_id, remote_address), &LocalBindAddresses::default()).await;
+		assert!(matches!(result, Err(PeerConnectError::AuthenticationFailed)));
+		assert!(PeerConnectError::AuthenticationFailed.retry_delay() > PeerConnectError::TcpTimeout.retry_delay());
+
+		let result = connect_with_error_classification(&local_peer_manager, (remote_node_id, remote_address), &LocalBindAddresses::default()).await;
+		assert!(result.is_ok());
+		assert_eq!(local_peer_manager.connected_peers(), vec![(remote_node_id, remote_address)]);
+	}
+
+	#[tokio::test]
+	async fn test_local_bind_address() {
+		let local_addresses = LocalBindAddresses::parse("127.0.0.1, ::1").unwrap();
+		assert_eq!(local_addresses.ipv4, Some(IpAddr::from_str("127.0.0.1").unwrap()));
+		assert_eq!(local_addresses.ipv6, Some(IpAddr::from_str("::1").unwrap()));
+		assert!(LocalBindAddresses::parse("127.0.0.1,127.0.0.2").is_err());
+		assert!(LocalBindAddresses::parse("localhost").is_err());
+		assert_eq!(LocalBindAddresses::parse("").unwrap(), LocalBindAddresses::default());
+
+		let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+		let listener_address = listener.local_addr().unwrap();
+		let local_address = local_addresses.for_peer(listener_address).unwrap();
+		let stream = connect_tcp(listener_address, local_address).await.unwrap();
+		let (_, remote_peer_address) = listener.accept().await.unwrap();
+		assert_eq!(stream.local_addr().unwrap().ip(), local_address.unwrap());
+		assert_eq!(stream.local_addr().unwrap(), remote_peer_address);
+
+		// an IPv6 peer can't be reached from an IPv4 address
+		let ipv4_only = LocalBindAddresses::parse("127.0.0.1").unwrap();
+		let ipv6_peer = SocketAddr::from_str("[::1]:9735").unwrap();
+		assert!(matches!(ipv4_only.for_peer(ipv6_peer), Err(PeerConnectError::AddressFamilyMismatch { peer }) if peer == ipv6_peer));
+		assert_eq!(LocalBindAddresses::default().for_peer(ipv6_peer).unwrap(), None);
+	}
+
+	#[test]
+	fn test_address_learning() {
+		let secp_context = Secp256k1::new();
+		let pubkey = SecretKey::from_slice(&[1; 32]).unwrap().public_key(&secp_context);
+		let configured = SocketAddr::from_str("127.0.0.1:9735").unwrap();
+		let advertise = |port: u16| vec![SocketAddress::Hostname { hostname: "example.com".to_string().try_into().unwrap(), port }, SocketAddress::TcpIpV4 { addr: [10, 0, 0, 1], port }];
+		let learned = |port: u16| SocketAddr::from_str(&format!("10.0.0.1:{}", port)).unwrap();
+
+		let address_book = AddressBook::new();
+		// peers that aren't configured are ignored
+		assert_eq!(address_book.observe_announcement(&pubkey, &advertise(1)), None);
+		assert_eq!(address_book.observe_announcement(&pubkey, &advertise(1)), None);
+		assert_eq!(address_book.candidates(&pubkey, configured), vec![configured]);
+
+		address_book.track(pubkey, configured);
+		// a flapping address is never trusted
+		for port in [1, 2, 1, 2] {
+			assert_eq!(address_book.observe_announcement(&pubkey, &advertise(port)), None);
+		}
+		assert_eq!(address_book.candidates(&pubkey, configured), vec![configured]);
+
+		// a stable one is, with the configured address as fallback
+		assert_eq!(address_book.observe_announcement(&pubkey, &advertise(2)), Some(learned(2)));
+		assert_eq!(address_book.candidates(&pubkey, configured), vec![learned(2), configured]);
+		assert_eq!(address_book.observe_announcement(&pubkey, &advertise(2)), None);
+
+		// an address advertised only once is trusted after a while
+		assert_eq!(address_book.observe_announcement(&pubkey, &advertise(3)), None);
+		assert_eq!(address_book.candidates(&pubkey, configured), vec![learned(2), configured]);
+		let later = Instant::now() + ADDRESS_CONFIRMATION_WINDOW;
+		assert_eq!(address_book.peers.lock().unwrap().get_mut(&pubkey).unwrap().confirm_pending(later), Some(learned(3)));
+		assert_eq!(address_book.candidates(&pubkey, configured), vec![learned(3), configured]);
+
+		// reconfiguring the peer's address discards the learned one
+		let reconfigured = SocketAddr::from_str("127.0.0.1:9736").unwrap();
+		address_book.track(pubkey, reconfigured);
+		assert_eq!(address_book.candidates(&pubkey, reconfigured), vec![reconfigured]);
+	}
+
+	#[test]
+	fn test_peer_list_diff() {
+		let secp_context = Secp256k1::new();
+		let pubkey = |byte: u8| SecretKey::from_slice(&[byte; 32]).unwrap().public_key(&secp_context);
+		let address = |port: u16| SocketAddr::from_str(&format!("127.0.0.1:{}", port)).unwrap();
+
+		let current_peers: HashMap<PublicKey, SocketAddr> = [(pubkey(1), address(9735)), (pubkey(2), address(9735)), (pubkey(3), address(9735))].into_iter().collect();
+		// peer 1 is unchanged, peer 2 moved, peer 3 is dropped, and peer 4 is new
+		let new_peers = [(pubkey(1), address(9735)), (pubkey(2), address(9736)), (pubkey(4), address(9735))];
+		let mut diff = diff_peer_lists(&current_peers, &new_peers);
+		diff.added.sort_unstable();
+
+		let mut expected_added = vec![(pubkey(2), address(9736)), (pubkey(4), address(9735))];
+		expected_added.sort_unstable();
+		let mut expected_removed = vec![pubkey(2), pubkey(3)];
+		expected_removed.sort_unstable();
+		assert_eq!(diff, PeerListDiff { added: expected_added, removed: expected_removed, unchanged_count: 1 });
+
+		let unchanged_diff = diff_peer_lists(&current_peers, &current_peers.iter().map(|(pubkey, address)| (*pubkey, *address)).collect::<Vec<_>>());
+		assert_eq!(unchanged_diff, PeerListDiff { added: vec![], removed: vec![], unchanged_count: 3 });
+	}
+
+	#[test]
+	fn test_silence_detection() {
+		assert!(is_silent(None, 1_000, 600));
+		assert!(!is_silent(Some(500), 1_000, 600));
+		assert!(is_silent(Some(400), 1_000, 600));
+		// messages recorded after the pinger read the clock don't count as silence
+		assert!(!is_silent(Some(1_001), 1_000, 600));
+	}
+
+	#[test]
+	fn test_idle_detection() {
+		let secp_context = Secp256k1::new();
+		let pubkey = SecretKey::from_slice(&[1; 32]).unwrap().public_key(&secp_context);
+		let address = SocketAddr::from_str("127.0.0.1:9735").unwrap();
+		let peer_stats = PeerConnectionStats::new();
+		peer_stats.record_connection_at(pubkey, address, None, 1_000);
+
+		// receiving anything restarts the idle period
+		assert!(peer_stats.idle_peers_at(&[(pubkey, 500)], 1_100, 600).is_empty());
+		assert!(peer_stats.idle_peers_at(&[(pubkey, 500)], 1_699, 600).is_empty());
+		assert!(peer_stats.idle_peers_at(&[(pubkey, 600)], 1_800, 600).is_empty());
+		assert!(peer_stats.idle_peers_at(&[(pubkey, 600)], 2_399, 600).is_empty());
+		assert_eq!(peer_stats.idle_peers_at(&[(pubkey, 600)], 2_400, 600), vec![pubkey]);
+
+		// peers being disconnected aren't reported again
+		peer_stats.record_idle_disconnection(pubkey);
+		assert!(peer_stats.idle_peers_at(&[(pubkey, 600)], 2_430, 600).is_empty());
+		assert!(peer_stats.take_idle_disconnection(&pubkey));
+		assert!(!peer_stats.take_idle_disconnection(&pubkey));
+
+		// a new connection's count starts over
+		peer_stats.record_disconnection_at(pubkey, 2_440);
+		assert!(peer_stats.idle_peers_at(&[(pubkey, 0)], 2_500, 600).is_empty());
+		peer_stats.record_connection_at(pubkey, address, None, 3_000);
+		assert!(peer_stats.idle_peers_at(&[(pubkey, 0)], 3_599, 600).is_empty());
+		assert_eq!(peer_stats.idle_peers_at(&[(pubkey, 0)], 3_600, 600), vec![pubkey]);
+	}
+
+	#[test]
+	fn test_peer_stats_accumulation() {
+		let secp_context = Secp256k1::new();
+		let pubkey = SecretKey::from_slice(&[1; 32]).unwrap().public_key(&secp_context);
+		let address = SocketAddr::from_str("127.0.0.1:9735").unwrap();
+		let peer_stats = PeerConnectionStats::new();
+		assert_eq!(peer_stats.peer_stats_at(&pubkey, 1_000), None);
+
+		peer_stats.record_connection_at(pubkey, address, None, 1_000);
+		peer_stats.record_disconnection_at(pubkey, 1_300);
+		peer_stats.record_connection_at(pubkey, address, None, 2_000);
+		let stats = peer_stats.peer_stats_at(&pubkey, 2_050).unwrap();
+		assert!(stats.connected);
+		assert_eq!(stats.connected_secs, 350);
+		assert_eq!(stats.to_json(), "{\"connected\":true,\"bytes_received\":0,\"bytes_sent\":0,\"last_message_at\":null,\"connected_secs\":350}");
+
+		peer_stats.record_disconnection_at(pubkey, 2_100);
+		assert_eq!(peer_stats.peer_stats_at(&pubkey, 5_000).unwrap().connected_secs, 400);
+
+		let metrics = peer_stats_to_prometheus(&[(pubkey, PeerStats { bytes_received: 1_234, ..PeerStats::default() })]);
+		let pubkey_hex = pubkey.serialize().to_lower_hex_string();
+		assert!(metrics.contains("# TYPE rgs_peer_bytes_received_total counter\n"));
+		assert!(metrics.contains(&format!("rgs_peer_bytes_received_total{{peer=\"{}\"}} 1234\n", pubkey_hex)));
+		// peers never heard from have no last message timestamp
+		assert!(!metrics.contains(&format!("rgs_peer_last_message_timestamp_seconds{{peer=\"{}\"}}", pubkey_hex)));
+	}
+}
@@ -1,17 +1,32 @@
-use std::fs::OpenOptions;
-use std::io::{BufWriter, Write};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Cursor, Write};
 use std::ops::Deref;
-use std::sync::Arc;
-use std::time::{Duration, Instant};
-use lightning::log_info;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use bitcoin::secp256k1::{self, PublicKey};
+use lightning::{log_error, log_info, log_warn};
 use lightning::routing::gossip::NetworkGraph;
 use lightning::util::logger::Logger;
-use lightning::util::ser::Writeable;
+use lightning::ln::msgs::ChannelAnnouncement;
+use lightning::util::ser::{Readable, Writeable};
 use tokio::runtime::Runtime;
 use tokio::sync::{mpsc, Mutex, Semaphore};
+use tokio_postgres::{Client, GenericClient};
 
-use crate::config;
+use crate::{config, instance_lock, snapshot, stats_history};
+use crate::client_usage::ClientUsage;
+use crate::compact_graph::DirectionalCoverage;
+use crate::debug_dump::DebugState;
+use crate::downloader::GossipCounter;
+use crate::error::{ErrorContext, ProcessorError};
+use crate::htlc_maximum;
+use crate::latency::{LatencyHistogram, LatencySnapshot};
+use crate::maintenance;
+use crate::plugin::{self, BackendPlugin};
+use crate::role::{RoleState, ServerRole};
+use crate::tables::Tables;
 use crate::types::GossipMessage;
+use crate::verifier::{FundingOutput, FundingOutputs};
 
 const POSTGRES_INSERT_TIMEOUT: Duration = Duration::from_secs(15);
 const INSERT_PARALELLISM: usize = 16;
@@ -20,305 +35,807 @@ pub(crate) struct GossipPersister<L: Deref> where L::Target: Logger {
 	gossip_persistence_receiver: mpsc::Receiver<GossipMessage>,
 	network_graph: Arc<NetworkGraph<L>>,
 	tokio_runtime: Runtime,
+	tables: Tables,
+	latency_histogram: Arc<LatencyHistogram>,
+	gossip_counter: Option<Arc<RwLock<GossipCounter>>>,
+	/// Where the number of persisted messages and ongoing insertions are reported, if anywhere
+	debug_state: Option<Arc<DebugState>>,
+	/// Whether messages that fail to persist are written to the dead letter table
+	dead_letters_enabled: bool,
+	/// Receives every message in addition to the database, if loaded
+	backend_plugin: Option<Arc<dyn BackendPlugin>>,
+	/// The funding outputs the verifier learned, which are stored with the announcements
+	funding_outputs: Option<Arc<FundingOutputs>>,
+	/// The snapshot requests reported by the HTTP front end, which are recorded in the stats history
+	client_usage: Option<Arc<ClientUsage>>,
+	started_at: u64,
+	/// The connection holding the advisory lock on the gossip tables, if it's taken
+	database_lock: Option<Client>,
+	/// Standbys don't take the lock, and are active unless set
+	role: Option<Arc<RoleState>>,
 	logger: L
 }
 
-impl<L: Deref> GossipPersister<L> where L::Target: Logger {
+impl<L: Deref + Clone + Send + Sync + 'static> GossipPersister<L> where L::Target: Logger {
 	pub fn new(network_graph: Arc<NetworkGraph<L>>, logger: L) -> (Self, mpsc::Sender<GossipMessage>) {
 		let (gossip_persistence_sender, gossip_persistence_receiver) =
 			mpsc::channel::<GossipMessage>(100);
-		let runtime = Runtime::new().unwrap();
+		let runtime = Runtime::new().expect("failed to create the gossip persistence runtime");
 		(GossipPersister {
 			gossip_persistence_receiver,
 			network_graph,
 			tokio_runtime: runtime,
+			tables: Tables::from_config(),
+			latency_histogram: Arc::new(LatencyHistogram::new()),
+			gossip_counter: None,
+			debug_state: None,
+			dead_letters_enabled: config::dead_letters_enabled(),
+			backend_plugin: None,
+			funding_outputs: None,
+			client_usage: None,
+			started_at: SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch").as_secs(),
+			database_lock: None,
+			role: None,
 			logger
 		}, gossip_persistence_sender)
 	}
 
-	pub(crate) async fn persist_gossip(&mut self) {
+	/// Share the histogram the time from receiving to persisting each message is recorded in
+	pub(crate) fn set_latency_histogram(&mut self, latency_histogram: Arc<LatencyHistogram>) {
+		self.latency_histogram = latency_histogram;
+	}
+
+	/// Enable periodically recording the counter values in the stats history table, and counting
+	/// the messages that fail to persist
+	pub(crate) fn set_gossip_counter(&mut self, gossip_counter: Arc<RwLock<GossipCounter>>) {
+		self.gossip_counter = Some(gossip_counter);
+	}
+
+	pub(crate) fn set_debug_state(&mut self, debug_state: Arc<DebugState>) {
+		self.debug_state = Some(debug_state);
+	}
+
+	pub(crate) fn set_backend_plugin(&mut self, backend_plugin: Arc<dyn BackendPlugin>) {
+		self.backend_plugin = Some(backend_plugin);
+	}
+
+	pub(crate) fn set_funding_outputs(&mut self, funding_outputs: Arc<FundingOutputs>) {
+		self.funding_outputs = Some(funding_outputs);
+	}
+
+	pub(crate) fn set_client_usage(&mut self, client_usage: Arc<ClientUsage>) {
+		self.client_usage = Some(client_usage);
+	}
+
+	pub(crate) fn set_role(&mut self, role: Arc<RoleState>) {
+		self.role = Some(role);
+	}
+
+	/// Persist gossip messages until all senders are dropped, or until persistence fails
+	pub(crate) async fn persist_gossip(&mut self) -> Result<(), ProcessorError> {
+		self.lock_database().await?;
+
 		{ // initialize the database
 			// this client instance is only used once
-			let mut client = crate::connect_to_db().await;
+			let mut client = crate::connect_to_db().await?;
 
-			let initialization = client
-				.execute(config::db_config_table_creation_query(), &[])
-				.await;
-			if let Err(initialization_error) = initialization {
-				panic!("db init error: {}", initialization_error);
-			}
+			client
+				.execute(&config::db_config_table_creation_query(&self.tables), &[])
+				.await
+				.context("Failed to create the config table")?;
 
-			let cur_schema = client.query("SELECT db_schema FROM config WHERE id = $1", &[&1]).await.unwrap();
+			let cur_schema = client.query(&format!("SELECT db_schema FROM {} WHERE id = $1", self.tables.config()), &[&1]).await
+				.context("Failed to read the db schema version")?;
 			if !cur_schema.is_empty() {
-				config::upgrade_db(cur_schema[0].get(0), &mut client).await;
+				config::upgrade_db(cur_schema[0].get(0), &mut client, &self.tables).await?;
 			}
 
-			let preparation = client.execute("set time zone UTC", &[]).await;
-			if let Err(preparation_error) = preparation {
-				panic!("db preparation error: {}", preparation_error);
-			}
+			client.execute("set time zone UTC", &[]).await.context("Failed to set the time zone")?;
 
-			let initialization = client
+			client
 				.execute(
 					// TODO: figure out a way to fix the id value without Postgres complaining about
 					// its value not being default
-					"INSERT INTO config (id, db_schema) VALUES ($1, $2) ON CONFLICT (id) DO NOTHING",
+					&format!("INSERT INTO {} (id, db_schema) VALUES ($1, $2) ON CONFLICT (id) DO NOTHING", self.tables.config()),
 					&[&1, &config::SCHEMA_VERSION]
-				).await;
-			if let Err(initialization_error) = initialization {
-				panic!("db init error: {}", initialization_error);
-			}
+				).await
+				.context("Failed to record the db schema version")?;
 
 			let table_creation_queries = [
-				config::db_announcement_table_creation_query(),
-				config::db_channel_update_table_creation_query(),
-				config::db_channel_update_table_creation_query(),
-				config::db_node_announcement_table_creation_query()
+				config::db_announcement_table_creation_query(&self.tables),
+				config::db_channel_update_table_creation_query(&self.tables),
+				config::db_channel_update_table_creation_query(&self.tables),
+				config::db_node_announcement_table_creation_query(&self.tables),
+				config::db_channel_removal_table_creation_query(&self.tables),
+				config::db_stats_history_table_creation_query(&self.tables),
+				config::db_peer_state_table_creation_query(&self.tables),
+				config::db_snapshot_coverage_table_creation_query(&self.tables),
+				config::db_sync_totals_table_creation_query(&self.tables),
+				config::db_dead_letter_table_creation_query(&self.tables)
 			];
 
 			for current_table_creation_query in table_creation_queries {
-				let initialization = client
-					.execute(current_table_creation_query, &[])
-					.await;
-				if let Err(initialization_error) = initialization {
-					panic!("db init error: {}", initialization_error);
-				}
+				client
+					.execute(&current_table_creation_query, &[])
+					.await
+					.context("Failed to create the gossip tables")?;
 			}
 
-			let initialization = client
-				.batch_execute(config::db_index_creation_query())
-				.await;
-			if let Err(initialization_error) = initialization {
-				panic!("db init error: {}", initialization_error);
+			client
+				.batch_execute(&config::db_index_creation_query(&self.tables))
+				.await
+				.context("Failed to create the gossip table indices")?;
+
+			// snapshots can fall back to the graph's capacities, so failing to store them isn't fatal
+			match backfill_capacities(&client, &self.tables, &self.network_graph).await {
+				Ok(0) => {},
+				Ok(backfilled_count) => log_info!(self.logger, "Backfilled the capacities of {} announcements from the network graph", backfilled_count),
+				Err(error) => log_warn!(self.logger, "Failed to backfill announcement capacities: {}", error),
 			}
 		}
 
+		if let Some(maintenance_window) = config::db_maintenance_window() {
+			self.tokio_runtime.spawn(maintenance::maintain_periodically(maintenance_window, self.logger.clone()));
+		}
+
 		// print log statement every minute
 		let mut latest_persistence_log = Instant::now() - Duration::from_secs(60);
+		let mut latest_latency_snapshot = LatencySnapshot::default();
+		let max_p99_latency = config::max_p99_latency();
 		let mut i = 0u32;
 		let mut latest_graph_cache_time = Instant::now();
+		let mut latest_stats_history_time = Instant::now();
+		let stats_history_interval = config::stats_history_interval();
 		let insert_limiter = Arc::new(Semaphore::new(INSERT_PARALELLISM));
 		let connections_cache = Arc::new(Mutex::new(Vec::with_capacity(INSERT_PARALELLISM)));
-		#[cfg(test)]
+		// the insertions are only awaited for tests and benchmarks, which need the gossip in the
+		// database once this returns
+		#[cfg(any(test, feature = "test-utils"))]
 		let mut tasks_spawned = Vec::new();
+		// a standby takes the lock as soon as it's promoted, just as it would have if it had started
+		// out active
+		let mut standby_role = self.role.clone().filter(|role| role.role() == ServerRole::Standby);
 		// TODO: it would be nice to have some sort of timeout here so after 10 seconds of
 		// inactivity, some sort of message could be broadcast signaling the activation of request
 		// processing
-		while let Some(gossip_message) = self.gossip_persistence_receiver.recv().await {
-			i += 1; // count the persisted gossip messages
+		loop {
+			let gossip_message = if let Some(role) = standby_role.clone() {
+				tokio::select! {
+					gossip_message = self.gossip_persistence_receiver.recv() => gossip_message,
+					_ = role.wait_until_active() => {
+						standby_role = None;
+						log_info!(self.logger, "Promoted from standby, locking the database");
+						self.lock_database().await?;
+						continue;
+					},
+				}
+			} else {
+				self.gossip_persistence_receiver.recv().await
+			};
+			let gossip_message = match gossip_message {
+				Some(gossip_message) => gossip_message,
+				None => break,
+			};
+			// count the persisted gossip messages
+			i += match &gossip_message {
+				GossipMessage::Batch(messages, _) => messages.len() as u32,
+				_ => 1,
+			};
 
 			if latest_persistence_log.elapsed().as_secs() >= 60 {
 				log_info!(self.logger, "Persisting gossip message #{}", i);
 				latest_persistence_log = Instant::now();
+
+				let latency_snapshot = self.latency_histogram.snapshot();
+				let recent_latencies = latency_snapshot.since(&latest_latency_snapshot);
+				if let Some(p99_latency) = recent_latencies.latency_percentile(99) {
+					if p99_latency > max_p99_latency {
+						log_warn!(self.logger, "99th percentile gossip persistence latency over the last {} messages exceeds {:?}", recent_latencies.count(), max_p99_latency);
+					}
+				}
+				latest_latency_snapshot = latency_snapshot;
 			}
 
 			// has it been ten minutes? Just cache it
 			if latest_graph_cache_time.elapsed().as_secs() >= 600 {
-				self.persist_network_graph();
+				if let Err(error) = self.persist_network_graph() {
+					log_warn!(self.logger, "{}", error);
+				}
 				latest_graph_cache_time = Instant::now();
 			}
-			insert_limiter.acquire().await.unwrap().forget();
+			if latest_stats_history_time.elapsed() >= stats_history_interval {
+				self.persist_stats_history();
+				latest_stats_history_time = Instant::now();
+			}
+			insert_limiter.acquire().await.expect("the insert limiter is never closed").forget();
 
 			let limiter_ref = Arc::clone(&insert_limiter);
-			let client = {
+			let mut client = {
 				let mut connections_set = connections_cache.lock().await;
-				let client = if connections_set.is_empty() {
-					crate::connect_to_db().await
-				} else {
-					connections_set.pop().unwrap()
+				let client = match connections_set.pop() {
+					Some(client) => client,
+					None => crate::connect_to_db().await?,
 				};
 				client
 			};
 
 			let connections_cache_ref = Arc::clone(&connections_cache);
-			match gossip_message {
-				GossipMessage::NodeAnnouncement(announcement, seen_override) => {
-					let public_key_hex = announcement.contents.node_id.to_string();
-
-					let mut announcement_signed = Vec::new();
-					announcement.write(&mut announcement_signed).unwrap();
-
-					let features = announcement.contents.features.encode();
-					let timestamp = announcement.contents.timestamp as i64;
-
-					let mut serialized_addresses = Vec::new();
-					announcement.contents.addresses.write(&mut serialized_addresses).unwrap();
-
-					let _task = self.tokio_runtime.spawn(async move {
-						if cfg!(test) && seen_override.is_some() {
-							tokio::time::timeout(POSTGRES_INSERT_TIMEOUT, client
-								.execute("INSERT INTO node_announcements (\
-								public_key, \
-								features, \
-								socket_addresses, \
-								timestamp, \
-								announcement_signed, \
-								seen \
-							) VALUES ($1, $2, $3, $4, $5, TO_TIMESTAMP($6))", &[
-									&public_key_hex,
-									&features,
-									&serialized_addresses,
-									&timestamp,
-									&announcement_signed,
-									&(seen_override.unwrap() as f64)
-								])).await.unwrap().unwrap();
-						} else {
-							tokio::time::timeout(POSTGRES_INSERT_TIMEOUT, client
-								.execute("INSERT INTO node_announcements (\
-								public_key, \
-								features, \
-								socket_addresses, \
-								timestamp, \
-								announcement_signed \
-							) VALUES ($1, $2, $3, $4, $5)", &[
-									&public_key_hex,
-									&features,
-									&serialized_addresses,
-									&timestamp,
-									&announcement_signed,
-								])).await.unwrap().unwrap();
-						}
-						let mut connections_set = connections_cache_ref.lock().await;
-						connections_set.push(client);
-						limiter_ref.add_permits(1);
-					});
-					#[cfg(test)]
-					tasks_spawned.push(_task);
-				},
-				GossipMessage::ChannelAnnouncement(announcement, seen_override) => {
-					let scid = announcement.contents.short_channel_id as i64;
-
-					// start with the type prefix, which is already known a priori
-					let mut announcement_signed = Vec::new();
-					announcement.write(&mut announcement_signed).unwrap();
-
-					let _task = self.tokio_runtime.spawn(async move {
-						if cfg!(test) && seen_override.is_some() {
-							tokio::time::timeout(POSTGRES_INSERT_TIMEOUT, client
-								.execute("INSERT INTO channel_announcements (\
-								short_channel_id, \
-								announcement_signed, \
-								seen \
-							) VALUES ($1, $2, TO_TIMESTAMP($3)) ON CONFLICT (short_channel_id) DO NOTHING", &[
-									&scid,
-									&announcement_signed,
-									&(seen_override.unwrap() as f64)
-								])).await.unwrap().unwrap();
-						} else {
-							tokio::time::timeout(POSTGRES_INSERT_TIMEOUT, client
-								.execute("INSERT INTO channel_announcements (\
-								short_channel_id, \
-								announcement_signed \
-							) VALUES ($1, $2) ON CONFLICT (short_channel_id) DO NOTHING", &[
-									&scid,
-									&announcement_signed
-								])).await.unwrap().unwrap();
+			let tables = self.tables.clone();
+			let latency_histogram = Arc::clone(&self.latency_histogram);
+			let gossip_counter = self.gossip_counter.clone();
+			let debug_state = self.debug_state.clone();
+			let dead_letters_enabled = self.dead_letters_enabled;
+			let logger = self.logger.clone();
+			if let Some(debug_state) = &debug_state {
+				debug_state.insert_started();
+			}
+			if let Some(backend_plugin) = &self.backend_plugin {
+				// the plugin may block, and its failures don't concern the database
+				let backend_plugin = Arc::clone(backend_plugin);
+				let plugin_message = gossip_message.clone();
+				let logger = self.logger.clone();
+				self.tokio_runtime.spawn_blocking(move || {
+					for failure in plugin::persist_to_plugin(backend_plugin.as_ref(), plugin_message) {
+						log_warn!(logger, "Backend plugin failed to persist {}", failure);
+					}
+				});
+			}
+			let funding_outputs = self.funding_outputs.as_ref()
+				.map_or_else(Vec::new, |funding_outputs| take_funding_outputs(funding_outputs, &gossip_message));
+			let _task = self.tokio_runtime.spawn(async move {
+				let (message_count, receipt_times) = match &gossip_message {
+					GossipMessage::Batch(messages, receipt_times) => (messages.len(), receipt_times.clone()),
+					_ => (1, Vec::new()),
+				};
+				// a message that can't be persisted must not hold up the ones queued behind it
+				let failures = persist_message_isolating_failures(&mut client, &tables, gossip_message).await;
+				if !funding_outputs.is_empty() && !client.is_closed() {
+					if let Err(error) = record_funding_outputs(&client, &tables, &funding_outputs).await {
+						log_warn!(logger, "{}", error);
+					}
+				}
+				if let Some(debug_state) = &debug_state {
+					debug_state.insert_finished(message_count.saturating_sub(failures.len()) as u64);
+				}
+				for (message, error) in failures {
+					log_error!(logger, "Failed to persist {}, skipping it: {}", describe_message(&message), error);
+					if let Some(counter) = &gossip_counter {
+						counter.write().expect("gossip counter lock poisoned").persistence_failures += 1;
+					}
+					if dead_letters_enabled && !client.is_closed() {
+						if let Err(error) = insert_dead_letter(&client, &tables, &message, &error.to_string()).await {
+							log_warn!(logger, "{}", error);
 						}
-						let mut connections_set = connections_cache_ref.lock().await;
-						connections_set.push(client);
-						limiter_ref.add_permits(1);
-					});
-					#[cfg(test)]
-					tasks_spawned.push(_task);
+					}
 				}
-				GossipMessage::ChannelUpdate(update, seen_override) => {
-					let scid = update.contents.short_channel_id as i64;
-
-					let timestamp = update.contents.timestamp as i64;
-
-					let direction = (update.contents.flags & 1) == 1;
-					let disable = (update.contents.flags & 2) > 0;
-
-					let cltv_expiry_delta = update.contents.cltv_expiry_delta as i32;
-					let htlc_minimum_msat = update.contents.htlc_minimum_msat as i64;
-					let fee_base_msat = update.contents.fee_base_msat as i32;
-					let fee_proportional_millionths =
-						update.contents.fee_proportional_millionths as i32;
-					let htlc_maximum_msat = update.contents.htlc_maximum_msat as i64;
-
-					// start with the type prefix, which is already known a priori
-					let mut update_signed = Vec::new();
-					update.write(&mut update_signed).unwrap();
-
-					let insertion_statement = if cfg!(test) {
-						"INSERT INTO channel_updates (\
-							short_channel_id, \
-							timestamp, \
-							seen, \
-							channel_flags, \
-							direction, \
-							disable, \
-							cltv_expiry_delta, \
-							htlc_minimum_msat, \
-							fee_base_msat, \
-							fee_proportional_millionths, \
-							htlc_maximum_msat, \
-							blob_signed \
-						) VALUES ($1, $2, TO_TIMESTAMP($3), $4, $5, $6, $7, $8, $9, $10, $11, $12)  ON CONFLICT DO NOTHING"
-					} else {
-						"INSERT INTO channel_updates (\
-							short_channel_id, \
-							timestamp, \
-							channel_flags, \
-							direction, \
-							disable, \
-							cltv_expiry_delta, \
-							htlc_minimum_msat, \
-							fee_base_msat, \
-							fee_proportional_millionths, \
-							htlc_maximum_msat, \
-							blob_signed \
-						) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, $10, $11)  ON CONFLICT DO NOTHING"
-					};
-
-					// this may not be used outside test cfg
-					let _seen_timestamp = seen_override.unwrap_or(timestamp as u32) as f64;
-
-					let _task = self.tokio_runtime.spawn(async move {
-						tokio::time::timeout(POSTGRES_INSERT_TIMEOUT, client
-							.execute(insertion_statement, &[
-								&scid,
-								&timestamp,
-								#[cfg(test)]
-									&_seen_timestamp,
-								&(update.contents.flags as i16),
-								&direction,
-								&disable,
-								&cltv_expiry_delta,
-								&htlc_minimum_msat,
-								&fee_base_msat,
-								&fee_proportional_millionths,
-								&htlc_maximum_msat,
-								&update_signed
-							])).await.unwrap().unwrap();
-						let mut connections_set = connections_cache_ref.lock().await;
-						connections_set.push(client);
-						limiter_ref.add_permits(1);
-					});
-					#[cfg(test)]
-					tasks_spawned.push(_task);
+				for received_at in receipt_times {
+					latency_histogram.record(received_at.elapsed());
 				}
-			}
+				// a lost connection is replaced by the next task needing one
+				if !client.is_closed() {
+					let mut connections_set = connections_cache_ref.lock().await;
+					connections_set.push(client);
+				}
+				limiter_ref.add_permits(1);
+			});
+			#[cfg(any(test, feature = "test-utils"))]
+			tasks_spawned.push(_task);
 		}
-		#[cfg(test)]
+		#[cfg(any(test, feature = "test-utils"))]
 		for task in tasks_spawned {
-			task.await.unwrap();
+			task.await.expect("gossip insertion task panicked");
 		}
+		self.unlock_database().await;
+		Ok(())
 	}
 
-	fn persist_network_graph(&self) {
-		log_info!(self.logger, "Caching network graph…");
+	/// Append the current counters and the directional coverage of the graph's channels to the
+	/// stats history in the background, so as not to hold up gossip persistence
+	fn persist_stats_history(&self) {
+		let counter = match &self.gossip_counter {
+			Some(counter) => Arc::clone(counter),
+			None => return,
+		};
+		let directional_coverage = DirectionalCoverage::from_network_graph(&self.network_graph);
+		log_info!(self.logger, "Directional coverage of {} channels: {}", directional_coverage.channel_count(), directional_coverage);
+		let tables = self.tables.clone();
+		let started_at = self.started_at;
+		let client_usage = self.client_usage.as_ref().map(|client_usage| client_usage.totals());
+		let logger = self.logger.clone();
+		self.tokio_runtime.spawn(async move {
+			let counter_snapshot = {
+				let counter = counter.read().expect("gossip counter lock poisoned");
+				counter.clone()
+			};
+			let recording = async {
+				let client = crate::connect_to_db().await?;
+				let bloat = if config::db_bloat_reporting_enabled() {
+					match maintenance::estimate_bloat(&client, &tables).await {
+						Ok(bloat) => {
+							for table_bloat in &bloat {
+								log_info!(logger, "Bloat of {}", table_bloat.to_text());
+							}
+							Some(bloat)
+						},
+						Err(error) => {
+							log_warn!(logger, "Failed to estimate the gossip tables' bloat: {}", error);
+							None
+						},
+					}
+				} else {
+					None
+				};
+				stats_history::record_stats(&client, &tables, &counter_snapshot, &directional_coverage, bloat.as_deref(), client_usage.as_ref(), started_at).await.context("Failed to record stats history")?;
+				stats_history::prune_stats(&client, &tables, config::stats_history_retention_days()).await.context("Failed to prune stats history")
+			};
+			if let Err(error) = recording.await {
+				log_warn!(logger, "{}", error);
+			}
+		});
+	}
+
+	/// Make sure no other server persists into the same tables, unless servers are meant to share
+	/// them, which is what deduplicating gossip through Redis is for. A lock left over from a
+	/// previous attempt is kept. Standbys never take the lock, so that they can run alongside the
+	/// active server, but they're supposed to have a database of their own, as the standby wouldn't
+	/// be of much use if the database failed. A standby takes the lock as soon as it's promoted,
+	/// failing persistence if another server still holds it.
+	async fn lock_database(&mut self) -> Result<(), ProcessorError> {
+		if config::redis_client().is_some() || self.database_lock.as_ref().map_or(false, |client| !client.is_closed()) {
+			return Ok(());
+		}
+		let client = crate::connect_to_db().await?;
+		if self.role.as_ref().map_or(false, |role| role.role() == ServerRole::Standby) {
+			if let Some(holder) = instance_lock::database_lock_holder(&client, &self.tables).await? {
+				log_error!(self.logger, "The standby shares its database with the active server: {} persists into the tables prefixed \"{}\" as well. Give the standby a database of its own, or it won't survive the active server's database failing.", holder, self.tables.prefix());
+			}
+			return Ok(());
+		}
+		instance_lock::lock_database(&client, &self.tables).await?;
+		self.database_lock = Some(client);
+		Ok(())
+	}
+
+	async fn unlock_database(&mut self) {
+		if let Some(client) = self.database_lock.take() {
+			if let Err(error) = instance_lock::unlock_database(&client, &self.tables).await {
+				// the lock is released along with the connection regardless
+				log_warn!(self.logger, "{}", error);
+			}
+		}
+	}
+
+	fn persist_network_graph(&self) -> Result<(), ProcessorError> {
 		let cache_path = config::network_graph_cache_path();
-		let file = OpenOptions::new()
-			.create(true)
-			.write(true)
-			.truncate(true)
-			.open(&cache_path)
-			.unwrap();
+		let min_free_bytes = config::snapshot_retention_policy().min_free_bytes;
+		if let Some(available_bytes) = snapshot::available_disk_space(&config::cache_path()).filter(|available_bytes| *available_bytes < min_free_bytes) {
+			log_warn!(self.logger, "Skipping network graph caching: only {} bytes of disk space available, but at least {} are required", available_bytes, min_free_bytes);
+			return Ok(());
+		}
+		log_info!(self.logger, "Caching network graph…");
 		self.network_graph.remove_stale_channels_and_tracking();
-		let mut writer = BufWriter::new(file);
-		self.network_graph.write(&mut writer).unwrap();
-		writer.flush().unwrap();
+		replace_file(&cache_path, |writer| self.network_graph.write(writer))
+			.context(format!("Failed to write network graph cache {}", cache_path))?;
 		log_info!(self.logger, "Cached network graph!");
+		Ok(())
+	}
+}
+
+/// Replace a file by writing its new contents to a temporary file next to it first, which is only
+/// renamed over the original once it has fully reached the disk. Should writing fail, e. g. because
+/// the disk is full, the temporary file is deleted, and the original is left untouched.
+fn replace_file<F: FnOnce(&mut BufWriter<File>) -> io::Result<()>>(path: &str, write: F) -> io::Result<()> {
+	let temporary_path = format!("{}.tmp", path);
+	let result = File::create(&temporary_path).and_then(|file| {
+		let mut writer = BufWriter::new(file);
+		write(&mut writer)?;
+		let file = writer.into_inner().map_err(|error| error.into_error())?;
+		file.sync_all()?;
+		fs::rename(&temporary_path, path)
+	});
+	if result.is_err() {
+		let _ = fs::remove_file(&temporary_path);
+	}
+	result
+}
+
+/// Persist a gossip message. If a batch fails, its messages are persisted one by one, so that only
+/// the offending ones are lost. Returns the messages that couldn't be persisted, along with the
+/// reason.
+async fn persist_message_isolating_failures(client: &mut Client, tables: &Tables, gossip_message: GossipMessage) -> Vec<(GossipMessage, ProcessorError)> {
+	let error = match persist_message(client, tables, gossip_message.clone()).await {
+		Ok(()) => return Vec::new(),
+		Err(error) => error,
+	};
+	match gossip_message {
+		GossipMessage::Batch(..) => {
+			let mut failures = Vec::new();
+			for message in gossip_message.into_messages() {
+				if let Err(error) = persist_message(client, tables, message.clone()).await {
+					failures.push((message, error));
+				}
+			}
+			failures
+		},
+		message => vec![(message, error)],
+	}
+}
+
+/// A short description of a message for logging, identifying the channel or node it's about
+fn describe_message(gossip_message: &GossipMessage) -> String {
+	match gossip_message {
+		GossipMessage::NodeAnnouncement(announcement, _) => format!("node announcement for {}", announcement.contents.node_id),
+		GossipMessage::ChannelAnnouncement(announcement, _) => format!("channel announcement for {}", announcement.contents.short_channel_id),
+		GossipMessage::ChannelUpdate(update, _) => format!("channel update for {} in direction {} at {}", update.contents.short_channel_id, update.contents.flags & 1, update.contents.timestamp),
+		GossipMessage::Batch(messages, _) => format!("batch of {} messages", messages.len()),
+	}
+}
+
+/// Keep a signed message that couldn't be persisted or was rejected, along with the reason, for
+/// later inspection
+pub(crate) async fn insert_dead_letter(client: &Client, tables: &Tables, gossip_message: &GossipMessage, reason: &str) -> Result<(), ProcessorError> {
+	let (message_type, message_signed) = match gossip_message {
+		GossipMessage::NodeAnnouncement(announcement, _) => ("node_announcement", announcement.encode()),
+		GossipMessage::ChannelAnnouncement(announcement, _) => ("channel_announcement", announcement.encode()),
+		GossipMessage::ChannelUpdate(update, _) => ("channel_update", update.encode()),
+		// batches are broken up before their messages are dead-lettered
+		GossipMessage::Batch(..) => return Ok(()),
+	};
+	tokio::time::timeout(POSTGRES_INSERT_TIMEOUT, client
+		.execute(&format!("INSERT INTO {} (message_type, message_signed, error) VALUES ($1, $2, $3)", tables.dead_letters()), &[
+			&message_type,
+			&message_signed,
+			&reason
+		])).await.map_err(|_| ProcessorError::Timeout("dead letter insertion"))?.context("Failed to insert dead letter")?;
+	Ok(())
+}
+
+/// Replace the stored announcement of a channel that was announced again between different nodes,
+/// which the network graph only accepts once the funding output turns out to pay to the new keys,
+/// so the stored announcement was wrong. The updates the previous nodes signed are deleted, the
+/// removal is recorded in the `channel_removals` table, and the new announcement counts as newly
+/// seen, so that deltas announce the channel again.
+async fn replace_conflicting_announcement<C: GenericClient>(client: &C, tables: &Tables, announcement: &ChannelAnnouncement, seen_override: Option<u32>) -> Result<(), ProcessorError> {
+	let scid = announcement.contents.short_channel_id as i64;
+	let stored_row = tokio::time::timeout(POSTGRES_INSERT_TIMEOUT, client
+		.query_opt(&format!("SELECT announcement_signed FROM {} WHERE short_channel_id = $1", tables.channel_announcements()), &[&scid]))
+		.await.map_err(|_| ProcessorError::Timeout("stored channel announcement lookup"))?.context("Failed to look up the stored channel announcement")?;
+	let stored_announcement_signed = match stored_row.and_then(|row| row.get::<_, Option<Vec<u8>>>(0)) {
+		Some(stored_announcement_signed) => stored_announcement_signed,
+		None => return Ok(()),
+	};
+	let stored_announcement = ChannelAnnouncement::read(&mut Cursor::new(stored_announcement_signed)).context("Failed to decode the stored channel announcement")?;
+	let stored_nodes = (stored_announcement.contents.node_id_1, stored_announcement.contents.node_id_2);
+	if stored_nodes == (announcement.contents.node_id_1, announcement.contents.node_id_2) {
+		return Ok(());
+	}
+
+	let seen_override = seen_override.filter(|_| cfg!(test)).map(|seen_override| seen_override as f64);
+	// a single statement, so that the replacement is atomic outside of batches, too
+	tokio::time::timeout(POSTGRES_INSERT_TIMEOUT, client
+		.execute(&format!("WITH replaced AS (\
+				UPDATE {announcements} SET announcement_signed = $2, seen = COALESCE(TO_TIMESTAMP($3), NOW()), funding_block_time = NULL, capacity_sats = NULL \
+				WHERE short_channel_id = $1 RETURNING short_channel_id\
+			), deleted_updates AS (\
+				DELETE FROM {updates} WHERE short_channel_id IN (SELECT short_channel_id FROM replaced)\
+			) INSERT INTO {removals} (short_channel_id) SELECT short_channel_id FROM replaced",
+			announcements = tables.channel_announcements(), updates = tables.channel_updates(), removals = tables.channel_removals()), &[
+			&scid,
+			&announcement.encode(),
+			&seen_override
+		])).await.map_err(|_| ProcessorError::Timeout("channel announcement replacement"))?.context("Failed to replace conflicting channel announcement")?;
+	Ok(())
+}
+
+/// Insert a gossip message, or all messages of a batch within a single transaction
+async fn persist_message(client: &mut Client, tables: &Tables, gossip_message: GossipMessage) -> Result<(), ProcessorError> {
+	match gossip_message {
+		GossipMessage::Batch(..) => {
+			// a batch is persisted atomically, along with the watermark it advances
+			let transaction = client.transaction().await.context("Failed to start gossip batch transaction")?;
+			let messages = gossip_message.into_messages();
+			let watermark_override = seen_override_watermark(&messages);
+			for message in messages {
+				insert_gossip_message(&transaction, tables, message).await?;
+			}
+			advance_gossip_watermark(&transaction, tables, watermark_override).await?;
+			transaction.commit().await.context("Failed to commit gossip batch")
+		},
+		_ => {
+			let watermark_override = seen_override_watermark(std::slice::from_ref(&gossip_message));
+			insert_gossip_message(&*client, tables, gossip_message).await?;
+			// only advanced once the message itself is durable, so it can never run ahead
+			advance_gossip_watermark(&*client, tables, watermark_override).await
+		},
+	}
+}
+
+/// The watermark a set of messages with overridden seen values advances to, if all of them have one
+fn seen_override_watermark(messages: &[GossipMessage]) -> Option<u32> {
+	let mut watermark = None;
+	for message in messages {
+		let seen_override = match message {
+			GossipMessage::NodeAnnouncement(_, seen_override) => seen_override,
+			GossipMessage::ChannelAnnouncement(_, seen_override) => seen_override,
+			GossipMessage::ChannelUpdate(_, seen_override) => seen_override,
+			GossipMessage::Batch(..) => return None,
+		};
+		let seen_override = seen_override.filter(|_| cfg!(test))?;
+		watermark = watermark.max(Some(seen_override));
+	}
+	watermark
+}
+
+/// Take the funding outputs of the channels announced in a message
+fn take_funding_outputs(funding_outputs: &FundingOutputs, gossip_message: &GossipMessage) -> Vec<(u64, FundingOutput)> {
+	match gossip_message {
+		GossipMessage::ChannelAnnouncement(announcement, _) => {
+			let short_channel_id = announcement.contents.short_channel_id;
+			funding_outputs.take(short_channel_id).map(|funding_output| (short_channel_id, funding_output)).into_iter().collect()
+		},
+		GossipMessage::Batch(messages, _) => messages.iter().flat_map(|message| take_funding_outputs(funding_outputs, message)).collect(),
+		GossipMessage::NodeAnnouncement(..) | GossipMessage::ChannelUpdate(..) => Vec::new(),
+	}
+}
+
+/// Store the funding block times and capacities of freshly persisted announcements. Announcements
+/// persisted by earlier runs keep theirs, lest a reverification after a reorg overwrite them.
+async fn record_funding_outputs(client: &Client, tables: &Tables, funding_outputs: &[(u64, FundingOutput)]) -> Result<(), ProcessorError> {
+	for (short_channel_id, funding_output) in funding_outputs {
+		tokio::time::timeout(POSTGRES_INSERT_TIMEOUT, client
+			.execute(&format!("UPDATE {} SET funding_block_time = TO_TIMESTAMP($2), capacity_sats = $3 WHERE short_channel_id = $1 AND funding_block_time IS NULL", tables.channel_announcements()), &[
+				&(*short_channel_id as i64),
+				&(funding_output.block_time as f64),
+				&(funding_output.value_sats as i64)
+			])).await.map_err(|_| ProcessorError::Timeout("funding output update"))?.context("Failed to record the funding output")?;
+		// updates persisted before the capacity was known get it as their absent HTLC maximum now
+		tokio::time::timeout(POSTGRES_INSERT_TIMEOUT, client
+			.execute(&format!("UPDATE {} SET htlc_maximum_msat = $2 WHERE short_channel_id = $1 AND htlc_maximum_msat = 0", tables.channel_updates()), &[
+				&(*short_channel_id as i64),
+				&(htlc_maximum::effective_htlc_maximum_msat(0, Some(funding_output.value_sats)) as i64)
+			])).await.map_err(|_| ProcessorError::Timeout("HTLC maximum substitution"))?.context("Failed to substitute absent HTLC maximums")?;
+	}
+	Ok(())
+}
+
+/// Store the capacities the verifier left in the network graph for announcements persisted before
+/// capacities were, returning how many announcements got one
+pub(crate) async fn backfill_capacities<L: Deref>(client: &Client, tables: &Tables, network_graph: &NetworkGraph<L>) -> Result<u64, ProcessorError> where L::Target: Logger {
+	let (short_channel_ids, capacities): (Vec<i64>, Vec<i64>) = network_graph.read_only().channels().unordered_iter()
+		.filter_map(|(short_channel_id, channel)| Some((*short_channel_id as i64, channel.capacity_sats? as i64)))
+		.unzip();
+	if short_channel_ids.is_empty() {
+		return Ok(0);
+	}
+	client.execute(&format!("UPDATE {announcements} SET capacity_sats = verified.capacity_sats \
+		FROM unnest($1::bigint[], $2::bigint[]) AS verified(short_channel_id, capacity_sats) \
+		WHERE {announcements}.short_channel_id = verified.short_channel_id AND {announcements}.capacity_sats IS NULL", announcements = tables.channel_announcements()), &[
+			&short_channel_ids,
+			&capacities
+		]).await.context("Failed to backfill announcement capacities")
+}
+
+/// Move the watermark, i. e. the latest time gossip was persisted, forward to either the current
+/// time or the overridden seen value
+async fn advance_gossip_watermark<C: GenericClient>(client: &C, tables: &Tables, seen_override: Option<u32>) -> Result<(), ProcessorError> {
+	let seen_override = seen_override.map(|seen_override| seen_override as f64);
+	tokio::time::timeout(POSTGRES_INSERT_TIMEOUT, client
+		.execute(&format!("UPDATE {} SET gossip_watermark = GREATEST(gossip_watermark, COALESCE(TO_TIMESTAMP($1), NOW())) WHERE id = 1", tables.config()), &[
+			&seen_override
+		])).await.map_err(|_| ProcessorError::Timeout("gossip watermark update"))?.context("Failed to advance the gossip watermark")?;
+	Ok(())
+}
+
+/// Read the unix timestamp up to which gossip has been persisted, if any has been
+pub(crate) async fn fetch_gossip_watermark(client: &Client, tables: &Tables) -> Result<Option<u64>, ProcessorError> {
+	let rows = client.query(&format!("SELECT EXTRACT(EPOCH FROM gossip_watermark)::bigint FROM {} WHERE id = 1", tables.config()), &[]).await
+		.context("Failed to read the gossip watermark")?;
+	let watermark: Option<i64> = rows.first().and_then(|row| row.get(0));
+	Ok(watermark.map(|watermark| watermark as u64))
+}
+
+/// Record the newest gossip timestamp among the channel updates seen before a snapshot's reference
+/// timestamp, i. e. the newest update a client synced up to it can possibly have
+pub(crate) async fn record_snapshot_coverage(client: &Client, tables: &Tables, reference_timestamp: u64) -> Result<u32, ProcessorError> {
+	let reference_timestamp_float = reference_timestamp as f64;
+	let reference_timestamp = reference_timestamp as i64;
+	let row = client.query_one(&format!("
+		INSERT INTO {snapshot_coverage} (reference_timestamp, newest_update_timestamp)
+		SELECT $1, COALESCE(MAX(timestamp), 0) FROM {channel_updates} WHERE seen < TO_TIMESTAMP($2)
+		ON CONFLICT (reference_timestamp) DO UPDATE SET newest_update_timestamp = EXCLUDED.newest_update_timestamp
+		RETURNING newest_update_timestamp
+		", snapshot_coverage = tables.snapshot_coverage(), channel_updates = tables.channel_updates()), &[&reference_timestamp, &reference_timestamp_float]).await
+		.context("Failed to record snapshot coverage")?;
+	Ok(row.get::<_, i64>(0) as u32)
+}
+
+/// The compressed encoding public keys are persisted in, which takes half the space of their hex
+/// encoding
+pub(crate) fn pubkey_to_bytes(pk: &PublicKey) -> [u8; 33] {
+	pk.serialize()
+}
+
+pub(crate) fn bytes_to_pubkey(b: &[u8; 33]) -> Result<PublicKey, secp256k1::Error> {
+	PublicKey::from_slice(b)
+}
+
+/// Insert a single gossip message using either a plain client or a transaction
+async fn insert_gossip_message<C: GenericClient>(client: &C, tables: &Tables, gossip_message: GossipMessage) -> Result<(), ProcessorError> {
+	match gossip_message {
+		GossipMessage::NodeAnnouncement(announcement, seen_override) => {
+			// node ids are the same compressed public keys, which needn't be parsed to be persisted
+			let public_key = announcement.contents.node_id.as_slice();
+
+			let announcement_signed = announcement.encode();
+
+			let features = announcement.contents.features.encode();
+			let timestamp = announcement.contents.timestamp as i64;
+
+			let serialized_addresses = announcement.contents.addresses.encode();
+
+			if let Some(seen_override) = seen_override.filter(|_| cfg!(test)) {
+				tokio::time::timeout(POSTGRES_INSERT_TIMEOUT, client
+					.execute(&format!("INSERT INTO {} (\
+					public_key, \
+					features, \
+					socket_addresses, \
+					timestamp, \
+					announcement_signed, \
+					seen \
+				) VALUES ($1, $2, $3, $4, $5, TO_TIMESTAMP($6))", tables.node_announcements()), &[
+						&public_key,
+						&features,
+						&serialized_addresses,
+						&timestamp,
+						&announcement_signed,
+						&(seen_override as f64)
+					])).await.map_err(|_| ProcessorError::Timeout("node announcement insertion"))?.context("Failed to insert node announcement")?;
+			} else {
+				tokio::time::timeout(POSTGRES_INSERT_TIMEOUT, client
+					.execute(&format!("INSERT INTO {} (\
+					public_key, \
+					features, \
+					socket_addresses, \
+					timestamp, \
+					announcement_signed \
+				) VALUES ($1, $2, $3, $4, $5)", tables.node_announcements()), &[
+						&public_key,
+						&features,
+						&serialized_addresses,
+						&timestamp,
+						&announcement_signed,
+					])).await.map_err(|_| ProcessorError::Timeout("node announcement insertion"))?.context("Failed to insert node announcement")?;
+			}
+		},
+		GossipMessage::ChannelAnnouncement(announcement, seen_override) => {
+			let scid = announcement.contents.short_channel_id as i64;
+
+			// start with the type prefix, which is already known a priori
+			let announcement_signed = announcement.encode();
+
+			let inserted_rows = if let Some(seen_override) = seen_override.filter(|_| cfg!(test)) {
+				tokio::time::timeout(POSTGRES_INSERT_TIMEOUT, client
+					.execute(&format!("INSERT INTO {} (\
+					short_channel_id, \
+					announcement_signed, \
+					seen \
+				) VALUES ($1, $2, TO_TIMESTAMP($3)) ON CONFLICT (short_channel_id) DO NOTHING", tables.channel_announcements()), &[
+						&scid,
+						&announcement_signed,
+						&(seen_override as f64)
+					])).await.map_err(|_| ProcessorError::Timeout("channel announcement insertion"))?.context("Failed to insert channel announcement")?
+			} else {
+				tokio::time::timeout(POSTGRES_INSERT_TIMEOUT, client
+					.execute(&format!("INSERT INTO {} (\
+					short_channel_id, \
+					announcement_signed \
+				) VALUES ($1, $2) ON CONFLICT (short_channel_id) DO NOTHING", tables.channel_announcements()), &[
+						&scid,
+						&announcement_signed
+					])).await.map_err(|_| ProcessorError::Timeout("channel announcement insertion"))?.context("Failed to insert channel announcement")?
+			};
+			if inserted_rows == 0 {
+				replace_conflicting_announcement(client, tables, &announcement, seen_override).await?;
+			}
+		},
+		GossipMessage::ChannelUpdate(update, seen_override) => {
+			let scid = update.contents.short_channel_id as i64;
+
+			let timestamp = update.contents.timestamp as i64;
+
+			let direction = (update.contents.flags & 1) == 1;
+			let disable = (update.contents.flags & 2) > 0;
+
+			let cltv_expiry_delta = update.contents.cltv_expiry_delta as i32;
+			let htlc_minimum_msat = update.contents.htlc_minimum_msat as i64;
+			let fee_base_msat = update.contents.fee_base_msat as i32;
+			let fee_proportional_millionths =
+				update.contents.fee_proportional_millionths as i32;
+			let htlc_maximum_msat = update.contents.htlc_maximum_msat as i64;
+
+			// start with the type prefix, which is already known a priori
+			let update_signed = update.encode();
+
+			let insertion_statement = if cfg!(test) {
+				format!("INSERT INTO {} (\
+					short_channel_id, \
+					timestamp, \
+					seen, \
+					channel_flags, \
+					direction, \
+					disable, \
+					cltv_expiry_delta, \
+					htlc_minimum_msat, \
+					fee_base_msat, \
+					fee_proportional_millionths, \
+					htlc_maximum_msat, \
+					blob_signed \
+				) VALUES ($1, $2, TO_TIMESTAMP($3), $4, $5, $6, $7, $8, $9, $10, {}, $12)  ON CONFLICT DO NOTHING", tables.channel_updates(), htlc_maximum::persisted_htlc_maximum_sql("$11", tables))
+			} else {
+				format!("INSERT INTO {} (\
+					short_channel_id, \
+					timestamp, \
+					channel_flags, \
+					direction, \
+					disable, \
+					cltv_expiry_delta, \
+					htlc_minimum_msat, \
+					fee_base_msat, \
+					fee_proportional_millionths, \
+					htlc_maximum_msat, \
+					blob_signed \
+				) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9, {}, $11)  ON CONFLICT DO NOTHING", tables.channel_updates(), htlc_maximum::persisted_htlc_maximum_sql("$10", tables))
+			};
+
+			// this may not be used outside test cfg
+			let _seen_timestamp = seen_override.unwrap_or(timestamp as u32) as f64;
+
+			tokio::time::timeout(POSTGRES_INSERT_TIMEOUT, client
+				.execute(&insertion_statement, &[
+					&scid,
+					&timestamp,
+					#[cfg(test)]
+						&_seen_timestamp,
+					&(update.contents.flags as i16),
+					&direction,
+					&disable,
+					&cltv_expiry_delta,
+					&htlc_minimum_msat,
+					&fee_base_msat,
+					&fee_proportional_millionths,
+					&htlc_maximum_msat,
+					&update_signed
+				])).await.map_err(|_| ProcessorError::Timeout("channel update insertion"))?.context("Failed to insert channel update")?;
+		},
+		GossipMessage::Batch(messages, _) => {
+			// batches are unwrapped by the caller, and never nested
+			debug_assert!(false, "Unexpected nested batch of {} messages", messages.len());
+		}
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_replace_file_on_full_disk() {
+		let path = format!("{}/rgs_replace_file_{}", std::env::temp_dir().display(), std::process::id());
+		replace_file(&path, |writer| writer.write_all(b"previous")).unwrap();
+
+		// the disk fills up halfway through the new contents
+		let error = replace_file(&path, |writer| {
+			writer.write_all(b"part")?;
+			writer.flush()?;
+			Err(io::Error::from_raw_os_error(28)) // ENOSPC
+		}).unwrap_err();
+		assert_eq!(error.raw_os_error(), Some(28));
+		assert_eq!(fs::read(&path).unwrap(), b"previous");
+		assert!(fs::metadata(format!("{}.tmp", path)).is_err());
+
+		replace_file(&path, |writer| writer.write_all(b"next")).unwrap();
+		assert_eq!(fs::read(&path).unwrap(), b"next");
+		fs::remove_file(&path).unwrap();
 	}
 }
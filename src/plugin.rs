@@ -0,0 +1,146 @@
+//! Loading persistence backends from shared libraries.
+//!
+//! A backend plugin is a shared library exporting these two C functions:
+//!
+//! ```c
+//! uint32_t rgs_backend_plugin_abi_version(void);
+//! int32_t rgs_backend_plugin_persist(uint16_t message_type, const uint8_t *message, size_t message_len, uint32_t seen_override);
+//! ```
+//!
+//! `rgs_backend_plugin_abi_version` must return [`BACKEND_PLUGIN_ABI_VERSION`], or the plugin isn't
+//! loaded. `rgs_backend_plugin_persist` is called once for every gossip message, from any thread,
+//! and possibly concurrently, with the message's BOLT 7 type (256 for channel announcements, 257
+//! for node announcements, and 258 for channel updates), the message as encoded on the wire
+//! without the type prefix, and the unix timestamp the message should be recorded as seen at, or 0
+//! to record it as seen now. The message is only valid for the duration of the call. Returning 0
+//! indicates success, and anything else is logged as an error code.
+//!
+//! The ABI version is only incremented for changes that would break existing plugins, such as
+//! changing a function's signature or the meaning of its arguments. New message types may be
+//! passed without incrementing it, so plugins should ignore types they don't know.
+
+use std::sync::Arc;
+
+use lightning::util::ser::Writeable;
+use libloading::Library;
+use thiserror::Error;
+
+use crate::types::GossipMessage;
+
+/// The version of the plugin ABI this server implements
+pub const BACKEND_PLUGIN_ABI_VERSION: u32 = 1;
+
+const CHANNEL_ANNOUNCEMENT_TYPE: u16 = 256;
+const NODE_ANNOUNCEMENT_TYPE: u16 = 257;
+const CHANNEL_UPDATE_TYPE: u16 = 258;
+
+type AbiVersionFn = unsafe extern "C" fn() -> u32;
+type PersistFn = unsafe extern "C" fn(u16, *const u8, usize, u32) -> i32;
+
+/// A persistence backend that receives every gossip message in addition to the database, which
+/// snapshots keep being generated from
+pub trait BackendPlugin: Send + Sync {
+	/// Persist a message, given its BOLT 7 type, its wire encoding without the type prefix, and the
+	/// unix timestamp it should be recorded as seen at, if not now
+	fn persist(&self, message_type: u16, message: &[u8], seen_override: Option<u32>) -> Result<(), String>;
+}
+
+#[derive(Debug, Error)]
+pub enum PluginError {
+	#[error("failed to load plugin {path}: {error}")]
+	Load {
+		path: String,
+		error: libloading::Error,
+	},
+	#[error("plugin {path} implements ABI version {found}, but only version {supported} is supported")]
+	AbiMismatch {
+		path: String,
+		found: u32,
+		supported: u32,
+	},
+}
+
+/// A backend plugin loaded from a shared library
+struct DynamicBackendPlugin {
+	persist: PersistFn,
+	/// Keeps the library loaded for as long as `persist` may be called
+	_library: Library,
+}
+
+impl BackendPlugin for DynamicBackendPlugin {
+	#[allow(unsafe_code)]
+	fn persist(&self, message_type: u16, message: &[u8], seen_override: Option<u32>) -> Result<(), String> {
+		// SAFETY: the plugin promises to only read the message during the call
+		let result = unsafe { (self.persist)(message_type, message.as_ptr(), message.len(), seen_override.unwrap_or(0)) };
+		match result {
+			0 => Ok(()),
+			error_code => Err(format!("plugin returned error code {}", error_code)),
+		}
+	}
+}
+
+/// Load the backend plugin from the shared library at `path`, and check its ABI version
+#[allow(unsafe_code)]
+pub fn load_backend_plugin(path: &str) -> Result<Arc<dyn BackendPlugin>, PluginError> {
+	let load_error = |error| PluginError::Load { path: path.to_string(), error };
+	// SAFETY: loading a library runs its initializers, which operators vouch for by configuring it
+	let library = unsafe { Library::new(path) }.map_err(load_error)?;
+	// SAFETY: the symbols' signatures are what the ABI prescribes
+	let abi_version = unsafe { library.get::<AbiVersionFn>(b"rgs_backend_plugin_abi_version\0") }.map_err(load_error)?;
+	let found = unsafe { abi_version() };
+	if found != BACKEND_PLUGIN_ABI_VERSION {
+		return Err(PluginError::AbiMismatch { path: path.to_string(), found, supported: BACKEND_PLUGIN_ABI_VERSION });
+	}
+	let persist = *unsafe { library.get::<PersistFn>(b"rgs_backend_plugin_persist\0") }.map_err(load_error)?;
+	Ok(Arc::new(DynamicBackendPlugin { persist, _library: library }))
+}
+
+/// Hand the individual messages of a gossip message to the plugin, returning the descriptions of
+/// those it failed to persist, along with the errors
+pub(crate) fn persist_to_plugin(plugin: &dyn BackendPlugin, gossip_message: GossipMessage) -> Vec<String> {
+	let mut failures = Vec::new();
+	for message in gossip_message.into_messages() {
+		let (message_type, encoded, seen_override, description) = match &message {
+			GossipMessage::ChannelAnnouncement(announcement, seen_override) => (CHANNEL_ANNOUNCEMENT_TYPE, announcement.encode(), *seen_override, format!("channel announcement for {}", announcement.contents.short_channel_id)),
+			GossipMessage::NodeAnnouncement(announcement, seen_override) => (NODE_ANNOUNCEMENT_TYPE, announcement.encode(), *seen_override, format!("node announcement for {}", announcement.contents.node_id)),
+			GossipMessage::ChannelUpdate(update, seen_override) => (CHANNEL_UPDATE_TYPE, update.encode(), *seen_override, format!("channel update for {}", update.contents.short_channel_id)),
+			GossipMessage::Batch(..) => unreachable!(),
+		};
+		if let Err(error) = plugin.persist(message_type, &encoded, seen_override) {
+			failures.push(format!("{}: {}", description, error));
+		}
+	}
+	failures
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Mutex;
+
+	use super::*;
+
+	struct RecordingPlugin {
+		persisted: Mutex<Vec<(u16, usize)>>,
+	}
+
+	impl BackendPlugin for RecordingPlugin {
+		fn persist(&self, message_type: u16, message: &[u8], _seen_override: Option<u32>) -> Result<(), String> {
+			self.persisted.lock().unwrap().push((message_type, message.len()));
+			Ok(())
+		}
+	}
+
+	#[test]
+	fn test_missing_plugin() {
+		assert!(matches!(load_backend_plugin("/nonexistent/librgs_plugin.so"), Err(PluginError::Load { .. })));
+	}
+
+	#[test]
+	fn test_batches_are_unwrapped() {
+		let plugin = RecordingPlugin { persisted: Mutex::new(Vec::new()) };
+		let update = crate::tests::generate_update(1, false, 100, 40, 1, 1000, 0, 0);
+		let batch = GossipMessage::Batch(vec![GossipMessage::ChannelUpdate(update.clone(), None), GossipMessage::ChannelUpdate(update.clone(), Some(50))], Vec::new());
+		assert!(persist_to_plugin(&plugin, batch).is_empty());
+		assert_eq!(*plugin.persisted.lock().unwrap(), vec![(CHANNEL_UPDATE_TYPE, update.serialized_length()); 2]);
+	}
+}
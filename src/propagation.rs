@@ -0,0 +1,141 @@
+//! How far behind the network's gossip the server runs, measured as the time between a channel
+//! update's own timestamp and the moment it was received.
+//!
+//! A lagging median usually means the peers are poorly connected to the rest of the network, as
+//! updates reach them late, if at all.
+
+use crate::histogram::{Histogram, HistogramMetric};
+
+/// Upper bounds of the histogram buckets, in seconds. Anything slower falls into an additional
+/// overflow bucket.
+const PROPAGATION_BUCKET_BOUNDS: [u64; 4] = [60, 5 * 60, 30 * 60, 2 * 3600];
+
+/// The time from a channel update's timestamp to receiving it, in seconds
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct PropagationDelay;
+
+impl HistogramMetric for PropagationDelay {
+	const BUCKET_BOUNDS: &'static [u64] = &PROPAGATION_BUCKET_BOUNDS;
+	const UNITS_PER_SECOND: u64 = 1;
+	const NAME: &'static str = "rgs_gossip_propagation_delay_seconds";
+	const HELP: &'static str = "Time from a channel update's timestamp to receiving it";
+}
+
+/// The propagation delays of accepted channel updates
+pub(crate) type PropagationDelays = Histogram<PropagationDelay>;
+
+impl PropagationDelays {
+	/// Record the delay of an update timestamped `timestamp` and received at `received_at`. Updates
+	/// timestamped after their receipt are left out, as their clocks are off rather than fast.
+	pub(crate) fn record_update(&mut self, timestamp: u32, received_at: u64) {
+		if let Some(delay_secs) = received_at.checked_sub(timestamp as u64) {
+			self.record(delay_secs);
+		}
+	}
+}
+
+/// Format a percentile of [`PropagationDelays::percentile`] as the bucket it falls into
+pub(crate) fn format_percentile(percentile: Option<u64>) -> String {
+	match percentile {
+		None => "n/a".to_string(),
+		Some(u64::MAX) => format!("> {}s", PROPAGATION_BUCKET_BOUNDS[PROPAGATION_BUCKET_BOUNDS.len() - 1]),
+		Some(bound) => format!("<= {}s", bound),
+	}
+}
+
+/// Tells when the median propagation delay exceeded the threshold for several consecutive windows
+pub(crate) struct PropagationLagDetector {
+	threshold_secs: u64,
+	required_window_count: u32,
+	/// The number of consecutive windows whose median exceeded the threshold so far
+	lagging_window_count: u32,
+}
+
+impl PropagationLagDetector {
+	pub(crate) fn new(threshold_secs: u64, required_window_count: u32) -> Self {
+		Self { threshold_secs, required_window_count, lagging_window_count: 0 }
+	}
+
+	/// Account for a window's delays, returning whether its median made for the required number of
+	/// consecutive lagging windows. The median is the upper bound of its bucket, so it only exceeds
+	/// the threshold once its whole bucket does. Windows without updates don't tell either way and
+	/// are skipped.
+	pub(crate) fn record_window(&mut self, window: &PropagationDelays) -> bool {
+		let median_secs = match window.percentile(50) {
+			Some(median_secs) => median_secs,
+			None => return false,
+		};
+		if median_secs <= self.threshold_secs {
+			self.lagging_window_count = 0;
+			return false;
+		}
+		self.lagging_window_count += 1;
+		self.lagging_window_count == self.required_window_count
+	}
+
+	pub(crate) fn lagging_window_count(&self) -> u32 {
+		self.lagging_window_count
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const NOW: u64 = 1_700_000_000;
+
+	#[test]
+	fn test_propagation_percentiles() {
+		let mut delays = PropagationDelays::default();
+		assert_eq!(delays.percentile(50), None);
+
+		for _ in 0..90 {
+			delays.record_update((NOW - 30) as u32, NOW);
+		}
+		for _ in 0..9 {
+			delays.record_update((NOW - 600) as u32, NOW);
+		}
+		delays.record_update((NOW - 3 * 3600) as u32, NOW);
+		// future-dated updates are left out
+		delays.record_update((NOW + 60) as u32, NOW);
+		assert_eq!(delays.count(), 100);
+		assert_eq!(delays.percentile(50), Some(60));
+		assert_eq!(delays.percentile(95), Some(1800));
+		assert_eq!(delays.percentile(100), Some(u64::MAX));
+		assert_eq!(format_percentile(delays.percentile(100)), "> 7200s");
+		assert_eq!(format_percentile(delays.percentile(50)), "<= 60s");
+
+		let earlier = delays.clone();
+		delays.record_update((NOW - 200) as u32, NOW);
+		let window = delays.since(&earlier);
+		assert_eq!(window.count(), 1);
+		assert_eq!(window.percentile(50), Some(300));
+
+		let export = delays.to_prometheus();
+		assert!(export.contains("rgs_gossip_propagation_delay_seconds_bucket{le=\"60\"} 90\n"));
+		assert!(export.contains("rgs_gossip_propagation_delay_seconds_bucket{le=\"300\"} 91\n"));
+		assert!(export.contains("rgs_gossip_propagation_delay_seconds_bucket{le=\"+Inf\"} 101\n"));
+		assert!(export.contains("rgs_gossip_propagation_delay_seconds_sum 19100\n"));
+	}
+
+	#[test]
+	fn test_propagation_lag_detection() {
+		let mut lagging_window = PropagationDelays::default();
+		lagging_window.record_update((NOW - 3600) as u32, NOW);
+		let mut timely_window = PropagationDelays::default();
+		timely_window.record_update((NOW - 10) as u32, NOW);
+
+		let mut detector = PropagationLagDetector::new(1800, 3);
+		assert!(!detector.record_window(&lagging_window));
+		assert!(!detector.record_window(&lagging_window));
+		// a timely window resets the streak, while an empty one doesn't
+		assert!(!detector.record_window(&timely_window));
+		assert!(!detector.record_window(&lagging_window));
+		assert!(!detector.record_window(&PropagationDelays::default()));
+		assert!(!detector.record_window(&lagging_window));
+		assert!(detector.record_window(&lagging_window));
+		// the warning isn't repeated while the lag persists
+		assert!(!detector.record_window(&lagging_window));
+		assert_eq!(detector.lagging_window_count(), 4);
+	}
+}
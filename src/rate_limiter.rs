@@ -0,0 +1,180 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The rolling window the per-channel update limit applies to
+const RATE_LIMIT_WINDOW: Duration = Duration::from_secs(3600);
+/// How many of the highest-velocity channels each velocity report lists
+const VELOCITY_REPORT_SIZE: usize = 10;
+
+/// A channel direction, i. e. its short channel id and the direction bit of its updates' flags
+pub(crate) type ChannelDirection = (u64, u8);
+
+/// The update counts of a channel direction since the previous velocity report
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct UpdateVelocity {
+	pub(crate) accepted: u64,
+	pub(crate) dropped: u64,
+}
+
+impl UpdateVelocity {
+	fn total(&self) -> u64 {
+		self.accepted + self.dropped
+	}
+}
+
+#[derive(Default)]
+struct LimiterState {
+	/// When the updates accepted within the window were received, per channel direction
+	recent_updates: HashMap<ChannelDirection, VecDeque<Instant>>,
+	velocities: HashMap<ChannelDirection, UpdateVelocity>,
+}
+
+/// Limits how many channel updates each channel direction may have accepted per rolling hour, so
+/// that nodes that keep toggling their fees or their channels' availability can't inflate the
+/// snapshots.
+pub(crate) struct UpdateRateLimiter {
+	max_updates_per_window: usize,
+	state: Mutex<LimiterState>,
+}
+
+impl UpdateRateLimiter {
+	pub(crate) fn new(max_updates_per_window: usize) -> Self {
+		Self { max_updates_per_window, state: Mutex::new(LimiterState::default()) }
+	}
+
+	/// Whether an update for the channel direction, received at `now`, must be dropped, in which
+	/// case it's counted towards the channel direction's velocity right away
+	pub(crate) fn is_limited(&self, channel_direction: ChannelDirection, now: Instant) -> bool {
+		let mut state = self.state.lock().expect("rate limiter lock poisoned");
+		let recent_update_count = state.recent_updates.get_mut(&channel_direction).map_or(0, |recent_updates| {
+			expire(recent_updates, now);
+			recent_updates.len()
+		});
+		let is_limited = recent_update_count >= self.max_updates_per_window;
+		if is_limited {
+			state.velocities.entry(channel_direction).or_default().dropped += 1;
+		}
+		is_limited
+	}
+
+	/// Count an update that was accepted for the channel direction, received at `now`
+	pub(crate) fn record(&self, channel_direction: ChannelDirection, now: Instant) {
+		let mut state = self.state.lock().expect("rate limiter lock poisoned");
+		state.recent_updates.entry(channel_direction).or_default().push_back(now);
+		state.velocities.entry(channel_direction).or_default().accepted += 1;
+	}
+
+	/// The channel directions that received the most updates since the previous report, highest
+	/// first. Also forgets about channel directions without updates in the window.
+	pub(crate) fn take_velocity_report(&self, now: Instant) -> Vec<(ChannelDirection, UpdateVelocity)> {
+		let mut state = self.state.lock().expect("rate limiter lock poisoned");
+		state.recent_updates.retain(|_, recent_updates| {
+			expire(recent_updates, now);
+			!recent_updates.is_empty()
+		});
+		let mut velocities: Vec<(ChannelDirection, UpdateVelocity)> = state.velocities.drain().collect();
+		velocities.sort_unstable_by(|(direction_a, velocity_a), (direction_b, velocity_b)| {
+			velocity_b.total().cmp(&velocity_a.total()).then(direction_a.cmp(direction_b))
+		});
+		velocities.truncate(VELOCITY_REPORT_SIZE);
+		velocities
+	}
+}
+
+/// A token bucket for the bytes of gossip relayed to peers, which refills at a constant rate up to
+/// the burst size
+pub(crate) struct RelayBudget {
+	bytes_per_second: u64,
+	burst_bytes: u64,
+	/// The bytes available as of the given time
+	state: Mutex<(f64, Instant)>,
+}
+
+impl RelayBudget {
+	pub(crate) fn new(bytes_per_second: u64, burst_bytes: u64, now: Instant) -> Self {
+		Self { bytes_per_second, burst_bytes, state: Mutex::new((burst_bytes as f64, now)) }
+	}
+
+	/// Take the given number of bytes from the budget if it has that many left, returning whether
+	/// it had
+	pub(crate) fn try_consume(&self, bytes: u64, now: Instant) -> bool {
+		let mut state = self.state.lock().expect("relay budget lock poisoned");
+		let (available_bytes, refilled_at) = &mut *state;
+		let refill = now.saturating_duration_since(*refilled_at).as_secs_f64() * self.bytes_per_second as f64;
+		*available_bytes = (*available_bytes + refill).min(self.burst_bytes as f64);
+		*refilled_at = (*refilled_at).max(now);
+		if *available_bytes < bytes as f64 {
+			return false;
+		}
+		*available_bytes -= bytes as f64;
+		true
+	}
+}
+
+fn expire(recent_updates: &mut VecDeque<Instant>, now: Instant) {
+	while recent_updates.front().map_or(false, |received_at| now.duration_since(*received_at) >= RATE_LIMIT_WINDOW) {
+		recent_updates.pop_front();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_rolling_window() {
+		let limiter = UpdateRateLimiter::new(2);
+		let start = Instant::now();
+		let channel_direction = (42, 0);
+
+		for offset in [0, 10] {
+			let now = start + Duration::from_secs(offset);
+			assert!(!limiter.is_limited(channel_direction, now));
+			limiter.record(channel_direction, now);
+		}
+		assert!(limiter.is_limited(channel_direction, start + Duration::from_secs(20)));
+		// the other direction has its own budget
+		assert!(!limiter.is_limited((42, 1), start + Duration::from_secs(20)));
+		// once the first update leaves the window, another one is accepted
+		assert!(!limiter.is_limited(channel_direction, start + RATE_LIMIT_WINDOW));
+	}
+
+	#[test]
+	fn test_relay_budget() {
+		let start = Instant::now();
+		let budget = RelayBudget::new(100, 300, start);
+		assert!(budget.try_consume(200, start));
+		assert!(!budget.try_consume(200, start));
+		assert!(budget.try_consume(100, start));
+		// after a second, 100 bytes are available again
+		assert!(!budget.try_consume(101, start + Duration::from_secs(1)));
+		assert!(budget.try_consume(100, start + Duration::from_secs(1)));
+		// the budget never exceeds the burst size, no matter how long it went unused
+		assert!(!budget.try_consume(301, start + Duration::from_secs(3600)));
+		assert!(budget.try_consume(300, start + Duration::from_secs(3600)));
+	}
+
+	#[test]
+	fn test_velocity_report() {
+		let limiter = UpdateRateLimiter::new(3);
+		let now = Instant::now();
+		for short_channel_id in 0..(VELOCITY_REPORT_SIZE as u64 + 5) {
+			// channel n receives n updates
+			for _ in 0..short_channel_id {
+				if !limiter.is_limited((short_channel_id, 0), now) {
+					limiter.record((short_channel_id, 0), now);
+				}
+			}
+		}
+
+		let report = limiter.take_velocity_report(now);
+		assert_eq!(report.len(), VELOCITY_REPORT_SIZE);
+		assert_eq!(report[0], ((14, 0), UpdateVelocity { accepted: 3, dropped: 11 }));
+		assert_eq!(report[VELOCITY_REPORT_SIZE - 1].0, (5, 0));
+
+		// velocities start over after each report, but the window doesn't
+		assert!(limiter.take_velocity_report(now).is_empty());
+		assert!(limiter.is_limited((14, 0), now));
+	}
+}
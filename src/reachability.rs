@@ -0,0 +1,145 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use tokio_postgres::GenericClient;
+
+use crate::tables::Tables;
+
+/// The number of most recent update cycles a channel direction's reachability is scored over
+const REACHABILITY_CYCLES: u32 = 5;
+
+/// Directions scoring below this are likely no longer reachable
+pub const LOW_REACHABILITY_THRESHOLD: f64 = 0.3;
+
+/// Only updates this recent are considered, matching the horizon after which channels without
+/// updates are pruned
+const REACHABILITY_LOOKBACK: u32 = 60 * 60 * 24 * 14;
+
+/// The update cycle assumed for directions with too few updates to tell how often they're refreshed,
+/// as most implementations rebroadcast their channel updates daily
+const DEFAULT_UPDATE_INTERVAL: u32 = 60 * 60 * 24;
+
+/// Bursts of fee adjustments mustn't shrink the update cycle to nothing
+const MIN_UPDATE_INTERVAL: u32 = 60 * 60;
+
+/// How reliably a channel direction has been receiving its periodic updates
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReachabilityScore {
+	/// The fraction of the most recent update cycles in which an update was received, between 0
+	/// and 1
+	pub score: f64,
+	/// How often the direction is normally updated, i. e. the median gap between its updates
+	pub expected_update_interval: Duration,
+	/// The number of updates received within the lookback window
+	pub update_count: usize,
+}
+
+impl ReachabilityScore {
+	/// Score a direction by its update timestamps, which need not be sorted
+	pub(crate) fn from_update_timestamps(update_timestamps: &[u32], now: u32) -> Self {
+		let mut timestamps: Vec<u32> = update_timestamps.iter().copied()
+			.filter(|timestamp| *timestamp <= now && *timestamp > now.saturating_sub(REACHABILITY_LOOKBACK))
+			.collect();
+		timestamps.sort_unstable();
+		timestamps.dedup();
+
+		let mut gaps: Vec<u32> = timestamps.windows(2).map(|pair| pair[1] - pair[0]).collect();
+		gaps.sort_unstable();
+		let expected_update_interval = gaps.get(gaps.len() / 2).copied().unwrap_or(DEFAULT_UPDATE_INTERVAL)
+			.clamp(MIN_UPDATE_INTERVAL, REACHABILITY_LOOKBACK / REACHABILITY_CYCLES);
+
+		let cycles_with_updates = (0..REACHABILITY_CYCLES).filter(|cycle| {
+			let cycle_end = now.saturating_sub(cycle * expected_update_interval);
+			let cycle_start = cycle_end.saturating_sub(expected_update_interval);
+			timestamps.iter().any(|timestamp| *timestamp > cycle_start && *timestamp <= cycle_end)
+		}).count();
+
+		Self {
+			score: cycles_with_updates as f64 / REACHABILITY_CYCLES as f64,
+			expected_update_interval: Duration::from_secs(expected_update_interval as u64),
+			update_count: timestamps.len(),
+		}
+	}
+
+	/// Whether the direction has missed so many of its updates that it's likely unreachable
+	pub fn is_low(&self) -> bool {
+		self.score < LOW_REACHABILITY_THRESHOLD
+	}
+}
+
+/// The reachability of both directions of a channel
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelReachability {
+	pub short_channel_id: u64,
+	pub one_to_two: ReachabilityScore,
+	pub two_to_one: ReachabilityScore,
+}
+
+impl ChannelReachability {
+	pub(crate) fn from_scores(short_channel_id: u64, scores: &HashMap<(u64, bool), ReachabilityScore>, now: u32) -> Self {
+		let score = |direction: bool| scores.get(&(short_channel_id, direction)).cloned()
+			.unwrap_or_else(|| ReachabilityScore::from_update_timestamps(&[], now));
+		Self {
+			short_channel_id,
+			one_to_two: score(false),
+			two_to_one: score(true),
+		}
+	}
+}
+
+/// Score every channel direction with updates within the lookback window, keyed by short channel
+/// id and direction, or just those of a single channel.
+pub(crate) async fn fetch_reachability_scores<C: GenericClient>(client: &C, tables: &Tables, short_channel_id: Option<u64>, now: u32) -> Result<HashMap<(u64, bool), ReachabilityScore>, tokio_postgres::Error> {
+	let since = now.saturating_sub(REACHABILITY_LOOKBACK) as i64;
+	let short_channel_id = short_channel_id.map(|short_channel_id| short_channel_id as i64);
+	let rows = client.query(&format!("SELECT short_channel_id, direction, timestamp FROM {} \
+		WHERE timestamp > $1 AND ($2::bigint IS NULL OR short_channel_id = $2)", tables.channel_updates()), &[&since, &short_channel_id]).await?;
+
+	let mut update_timestamps: HashMap<(u64, bool), Vec<u32>> = HashMap::new();
+	for row in rows {
+		let short_channel_id: i64 = row.get("short_channel_id");
+		let direction: bool = row.get("direction");
+		let timestamp: i64 = row.get("timestamp");
+		update_timestamps.entry((short_channel_id as u64, direction)).or_default().push(timestamp as u32);
+	}
+	Ok(update_timestamps.into_iter()
+		.map(|(key, timestamps)| (key, ReachabilityScore::from_update_timestamps(&timestamps, now)))
+		.collect())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	const HOUR: u32 = 60 * 60;
+
+	#[test]
+	fn test_reachability_score() {
+		let now = 1_700_000_000;
+
+		// updates every 12 hours, right up until now
+		let reliable: Vec<u32> = (0..20).map(|cycle| now - cycle * 12 * HOUR).collect();
+		let score = ReachabilityScore::from_update_timestamps(&reliable, now);
+		assert_eq!(score.score, 1.0);
+		assert_eq!(score.expected_update_interval, Duration::from_secs(12 * HOUR as u64));
+		assert!(!score.is_low());
+
+		// the same schedule, but silent for the last five cycles
+		let silent: Vec<u32> = reliable.iter().map(|timestamp| timestamp - 5 * 12 * HOUR).collect();
+		let score = ReachabilityScore::from_update_timestamps(&silent, now);
+		assert_eq!(score.score, 0.0);
+		assert!(score.is_low());
+
+		// silent for the last three cycles
+		let lagging: Vec<u32> = reliable.iter().map(|timestamp| timestamp - 3 * 12 * HOUR).collect();
+		assert_eq!(ReachabilityScore::from_update_timestamps(&lagging, now).score, 0.4);
+
+		// a single recent update is scored against the default cycle
+		let score = ReachabilityScore::from_update_timestamps(&[now - HOUR], now);
+		assert_eq!(score.score, 0.2);
+		assert_eq!(score.expected_update_interval, Duration::from_secs(DEFAULT_UPDATE_INTERVAL as u64));
+
+		let score = ReachabilityScore::from_update_timestamps(&[], now);
+		assert_eq!((score.score, score.update_count), (0.0, 0));
+	}
+}
@@ -0,0 +1,123 @@
+//! Warm standby: a server in the standby role downloads, verifies, and persists gossip just like
+//! the active server, but doesn't generate or publish snapshots until it's promoted. Promotion
+//! happens at runtime, upon SIGUSR1 or through [`RapidSyncProcessor::promote`], and is followed by
+//! an immediate snapshot generation round, so that failing over doesn't wait for a restart or for
+//! the next snapshot interval.
+//!
+//! [`RapidSyncProcessor::promote`]: crate::RapidSyncProcessor::promote
+
+use std::fmt;
+use std::ops::Deref;
+use std::str::FromStr;
+use std::sync::Arc;
+
+use lightning::{log_info, log_warn};
+use lightning::util::logger::Logger;
+use tokio::sync::watch;
+
+/// Whether a server publishes snapshots
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ServerRole {
+	/// Generates and publishes snapshots
+	#[default]
+	Active,
+	/// Keeps its database and network graph up to date, but publishes nothing until promoted
+	Standby,
+}
+
+impl FromStr for ServerRole {
+	type Err = String;
+
+	fn from_str(role: &str) -> Result<Self, Self::Err> {
+		match role {
+			"active" => Ok(Self::Active),
+			"standby" => Ok(Self::Standby),
+			_ => Err(format!("unknown server role {}", role)),
+		}
+	}
+}
+
+impl fmt::Display for ServerRole {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Self::Active => write!(f, "active"),
+			Self::Standby => write!(f, "standby"),
+		}
+	}
+}
+
+/// The server's current role, which only ever changes from standby to active
+pub(crate) struct RoleState {
+	role: watch::Sender<ServerRole>,
+}
+
+impl RoleState {
+	pub(crate) fn new(role: ServerRole) -> Self {
+		Self { role: watch::Sender::new(role) }
+	}
+
+	pub(crate) fn role(&self) -> ServerRole {
+		*self.role.borrow()
+	}
+
+	/// Become active, returning whether the server was a standby until now
+	pub(crate) fn promote(&self) -> bool {
+		self.role.send_replace(ServerRole::Active) == ServerRole::Standby
+	}
+
+	/// Return once the server is active, which it may already be
+	pub(crate) async fn wait_until_active(&self) {
+		let mut receiver = self.role.subscribe();
+		// the sender is borrowed, so it can't be dropped while waiting
+		let _ = receiver.wait_for(|role| *role == ServerRole::Active).await;
+	}
+
+	/// Promote the server whenever it receives SIGUSR1. Active servers ignore the signal rather
+	/// than being terminated by it.
+	pub(crate) async fn promote_on_user_signal<L: Deref>(state: Arc<Self>, logger: L) where L::Target: Logger {
+		let mut user_signal = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1()) {
+			Ok(signal) => signal,
+			Err(error) => {
+				log_warn!(logger, "Failed to listen for SIGUSR1, the server can only be promoted through the library: {}", error);
+				return;
+			}
+		};
+		while user_signal.recv().await.is_some() {
+			if state.promote() {
+				log_info!(logger, "Received SIGUSR1, promoted from standby to active");
+			} else {
+				log_info!(logger, "Received SIGUSR1, but the server is already active");
+			}
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::time::Duration;
+
+	#[tokio::test]
+	async fn test_promotion() {
+		assert_eq!("active".parse::<ServerRole>(), Ok(ServerRole::Active));
+		assert_eq!("standby".parse::<ServerRole>(), Ok(ServerRole::Standby));
+		assert!("passive".parse::<ServerRole>().is_err());
+		assert_eq!(ServerRole::Standby.to_string(), "standby");
+
+		let state = Arc::new(RoleState::new(ServerRole::Standby));
+		let waiter = {
+			let state = Arc::clone(&state);
+			tokio::spawn(async move { state.wait_until_active().await })
+		};
+		tokio::time::sleep(Duration::from_millis(50)).await;
+		assert!(!waiter.is_finished());
+
+		assert!(state.promote());
+		tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
+		assert_eq!(state.role(), ServerRole::Active);
+
+		// promoting is idempotent, and active servers don't wait
+		assert!(!state.promote());
+		tokio::time::timeout(Duration::from_secs(1), state.wait_until_active()).await.unwrap();
+	}
+}
@@ -0,0 +1,73 @@
+use std::ops::Deref;
+
+use lightning::routing::gossip::{ChannelUpdateInfo, NetworkGraph, NodeId};
+use lightning::routing::router::RouteHintHop;
+use lightning::util::logger::Logger;
+
+/// The route hints for reaching either of a channel's nodes through the channel, as BOLT 11
+/// invoices include them. A hint only applies in one direction, so which one is needed depends on
+/// which side of the channel the invoice's recipient is on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelRoutingHints {
+	pub short_channel_id: u64,
+	/// The hop from node two to node one, following node two's latest channel update
+	pub towards_node_one: Option<RouteHintHop>,
+	/// The hop from node one to node two, following node one's latest channel update
+	pub towards_node_two: Option<RouteHintHop>,
+}
+
+impl ChannelRoutingHints {
+	pub fn to_json(&self) -> String {
+		format!(
+			"{{\"short_channel_id\":{},\"towards_node_one\":{},\"towards_node_two\":{}}}",
+			self.short_channel_id,
+			hop_to_json(self.towards_node_one.as_ref()),
+			hop_to_json(self.towards_node_two.as_ref()),
+		)
+	}
+}
+
+fn hop_to_json(hop: Option<&RouteHintHop>) -> String {
+	let hop = match hop {
+		Some(hop) => hop,
+		None => return "null".to_string(),
+	};
+	let optional_msat = |msat: Option<u64>| msat.map_or("null".to_string(), |msat| msat.to_string());
+	format!(
+		"{{\"src_node_id\":\"{}\",\"short_channel_id\":{},\"fee_base_msat\":{},\"fee_proportional_millionths\":{},\"cltv_expiry_delta\":{},\"htlc_minimum_msat\":{},\"htlc_maximum_msat\":{}}}",
+		hop.src_node_id,
+		hop.short_channel_id,
+		hop.fees.base_msat,
+		hop.fees.proportional_millionths,
+		hop.cltv_expiry_delta,
+		optional_msat(hop.htlc_minimum_msat),
+		optional_msat(hop.htlc_maximum_msat),
+	)
+}
+
+/// The hop through a channel from the node that signed `update`, unless the node disabled that
+/// direction, which makes it useless as a hint
+fn route_hint_hop(short_channel_id: u64, src_node_id: &NodeId, update: Option<&ChannelUpdateInfo>) -> Option<RouteHintHop> {
+	let update = update.filter(|update| update.enabled)?;
+	Some(RouteHintHop {
+		src_node_id: src_node_id.as_pubkey().ok()?,
+		short_channel_id,
+		fees: update.fees,
+		cltv_expiry_delta: update.cltv_expiry_delta,
+		htlc_minimum_msat: Some(update.htlc_minimum_msat),
+		htlc_maximum_msat: Some(update.htlc_maximum_msat),
+	})
+}
+
+/// Look up a channel's current fee and HTLC parameters as route hints, for wallets constructing
+/// invoices without keeping a graph of their own. Returns `None` for channels the graph doesn't
+/// know, and a channel's directions are `None` while they lack an update or are disabled.
+pub(crate) fn extract_routing_hints<L: Deref>(short_channel_id: u64, network_graph: &NetworkGraph<L>) -> Option<ChannelRoutingHints> where L::Target: Logger {
+	let read_only_graph = network_graph.read_only();
+	let channel = read_only_graph.channel(short_channel_id)?;
+	Some(ChannelRoutingHints {
+		short_channel_id,
+		towards_node_one: route_hint_hop(short_channel_id, &channel.node_two, channel.two_to_one.as_ref()),
+		towards_node_two: route_hint_hop(short_channel_id, &channel.node_one, channel.one_to_two.as_ref()),
+	})
+}
@@ -0,0 +1,127 @@
+//! Short channel ids, which locate a channel's funding output on chain by the height of its block,
+//! the index of its transaction within that block, and its index within that transaction.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+/// Block heights and transaction indices each take up three bytes of a short channel id
+const MAX_THREE_BYTE_VALUE: u32 = 0xffffff;
+
+/// Why a short channel id couldn't be composed or parsed
+#[derive(Clone, Debug, Error, PartialEq)]
+pub enum ScidError {
+	#[error("block height {0} doesn't fit into three bytes")]
+	BlockHeightOutOfRange(u32),
+	#[error("transaction index {0} doesn't fit into three bytes")]
+	TxIndexOutOfRange(u32),
+	/// The channel's funding block hasn't been mined yet
+	#[error("block height {block_height} is beyond the chain tip at {tip_height}")]
+	BeyondChainTip {
+		block_height: u32,
+		tip_height: u32,
+	},
+	#[error("invalid short channel id {0:?}, expected a number or the form <block>x<tx>x<output>")]
+	Unparseable(String),
+}
+
+/// A channel's short channel id. Every `u64` decodes to one, so ids read off the wire convert
+/// without validation, whereas composing one from its parts checks that they fit.
+#[derive(Clone, Copy, Debug, Deserialize, Eq, Hash, Ord, PartialEq, PartialOrd, Serialize)]
+pub struct ShortChannelId(u64);
+
+impl ShortChannelId {
+	pub fn new(block_height: u32, tx_index: u32, output_index: u16) -> Result<Self, ScidError> {
+		if block_height > MAX_THREE_BYTE_VALUE {
+			return Err(ScidError::BlockHeightOutOfRange(block_height));
+		}
+		if tx_index > MAX_THREE_BYTE_VALUE {
+			return Err(ScidError::TxIndexOutOfRange(tx_index));
+		}
+		Ok(Self((block_height as u64) << 40 | (tx_index as u64) << 16 | output_index as u64))
+	}
+
+	/// Like [`ShortChannelId::new`], but also checking that the funding block was mined as of the
+	/// given chain tip, for callers with access to the chain
+	pub fn new_as_of_tip(block_height: u32, tx_index: u32, output_index: u16, tip_height: u32) -> Result<Self, ScidError> {
+		if block_height > tip_height {
+			return Err(ScidError::BeyondChainTip { block_height, tip_height });
+		}
+		Self::new(block_height, tx_index, output_index)
+	}
+
+	pub fn block_height(&self) -> u32 {
+		(self.0 >> 40) as u32
+	}
+
+	pub fn tx_index(&self) -> u32 {
+		((self.0 >> 16) & MAX_THREE_BYTE_VALUE as u64) as u32
+	}
+
+	pub fn output_index(&self) -> u16 {
+		(self.0 & 0xffff) as u16
+	}
+}
+
+impl From<u64> for ShortChannelId {
+	fn from(short_channel_id: u64) -> Self {
+		Self(short_channel_id)
+	}
+}
+
+impl From<ShortChannelId> for u64 {
+	fn from(short_channel_id: ShortChannelId) -> Self {
+		short_channel_id.0
+	}
+}
+
+impl fmt::Display for ShortChannelId {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{}x{}x{}", self.block_height(), self.tx_index(), self.output_index())
+	}
+}
+
+/// Parses either the `<block>x<tx>x<output>` form or the plain integer encoding, so that request
+/// paths can use whichever their callers have at hand
+impl FromStr for ShortChannelId {
+	type Err = ScidError;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let unparseable = || ScidError::Unparseable(s.to_string());
+		let parts: Vec<&str> = s.split('x').collect();
+		match parts.as_slice() {
+			[integer] => integer.parse::<u64>().map(Self).map_err(|_| unparseable()),
+			[block_height, tx_index, output_index] => Self::new(
+				block_height.parse().map_err(|_| unparseable())?,
+				tx_index.parse().map_err(|_| unparseable())?,
+				output_index.parse().map_err(|_| unparseable())?,
+			),
+			_ => Err(unparseable()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_short_channel_id_parts() {
+		let short_channel_id = ShortChannelId::new(800_000, 1_234, 1).unwrap();
+		assert_eq!(u64::from(short_channel_id), 879_609_302_220_800_000 + (1_234 << 16) + 1);
+		assert_eq!((short_channel_id.block_height(), short_channel_id.tx_index(), short_channel_id.output_index()), (800_000, 1_234, 1));
+		assert_eq!(short_channel_id.to_string(), "800000x1234x1");
+		assert_eq!("800000x1234x1".parse::<ShortChannelId>(), Ok(short_channel_id));
+		assert_eq!(u64::from(short_channel_id).to_string().parse::<ShortChannelId>(), Ok(short_channel_id));
+		assert_eq!(serde_json::to_string(&short_channel_id).unwrap(), u64::from(short_channel_id).to_string());
+
+		assert_eq!(ShortChannelId::new(1 << 24, 0, 0), Err(ScidError::BlockHeightOutOfRange(1 << 24)));
+		assert_eq!(ShortChannelId::new(1, 1 << 24, 0), Err(ScidError::TxIndexOutOfRange(1 << 24)));
+		assert_eq!(ShortChannelId::new_as_of_tip(800_001, 0, 0, 800_000), Err(ScidError::BeyondChainTip { block_height: 800_001, tip_height: 800_000 }));
+		assert!(ShortChannelId::new_as_of_tip(800_000, 0, 0, 800_000).is_ok());
+		assert!("800000x1".parse::<ShortChannelId>().is_err());
+		assert!("800000x1x70000".parse::<ShortChannelId>().is_err());
+	}
+}
@@ -1,24 +1,123 @@
 use std::cmp::max;
 use std::collections::HashMap;
-use std::time::{SystemTime, UNIX_EPOCH};
 
 use bitcoin::Network;
 use bitcoin::blockdata::constants::ChainHash;
 use lightning::ln::features::NodeFeatures;
 use lightning::ln::msgs::{UnsignedChannelAnnouncement, UnsignedChannelUpdate};
 use lightning::util::ser::{BigSize, Writeable};
+use crate::clock::SnapshotClock;
 use crate::config;
 
 use crate::lookup::{DeltaSet, DirectedUpdateDelta, NodeDeltaSet};
 
+/// Set in the first node index of version 2 announcements that are followed by additional data
+pub(crate) const ANNOUNCEMENT_ADDITIONAL_DATA_FLAG: u64 = 1 << 63;
+/// The type of the TLV record carrying a channel's capacity in satoshis within an announcement's
+/// additional data. Being odd, clients that don't know it may ignore it.
+pub(crate) const CAPACITY_TLV_TYPE: u64 = 1;
+
+/// The first bytes of an extended snapshot. Standard snapshots never carry capacities, so that
+/// they stay byte-compatible with what clients have always been served. Extended snapshots, which
+/// are opt-in and published under their own file name suffix, consist of
+///
+/// 1. these four bytes, which differ from the standard `LDK` prefix, so that neither variant can
+///    be mistaken for the other,
+/// 2. a single byte holding the [`EXTENDED_SNAPSHOT_VERSION`],
+/// 3. since version 2, the [`ExtendedHeaderTotals`], for clients to judge whether the snapshot is
+///    plausible before applying it, and
+/// 4. a complete version 2 snapshot, in which every announcement whose channel capacity is known
+///    sets [`ANNOUNCEMENT_ADDITIONAL_DATA_FLAG`] and is followed by a BigSize length and a TLV
+///    stream, whose [`CAPACITY_TLV_TYPE`] record holds the capacity as an 8-byte big-endian
+///    number of satoshis.
+pub(crate) const EXTENDED_SNAPSHOT_MAGIC: [u8; 4] = *b"RGSX";
+/// The version of the extension header and whatever follows it, which is bumped whenever
+/// extended snapshots change in a way existing readers can't skip over
+pub(crate) const EXTENDED_SNAPSHOT_VERSION: u8 = 2;
+/// The length of the [`ExtendedHeaderTotals`]
+pub(crate) const EXTENDED_HEADER_TOTALS_LENGTH: usize = 11;
+/// The total capacity in the extended header is in units of 2^20 satoshis, about a hundredth of a
+/// bitcoin
+const TOTAL_CAPACITY_SHIFT: u32 = 20;
+/// The node count in the extended header takes up three bytes
+const MAX_HEADER_NODE_COUNT: u32 = (1 << 24) - 1;
+
+/// What an extended snapshot contains, summed up in its header, so that clients can read it without
+/// parsing the rest. The totals describe the snapshot itself, so incremental snapshots only count
+/// their delta.
+///
+/// In wire order, all big-endian, and saturating rather than overflowing:
+/// 1. `total_capacity_btc_approx` (4 bytes): the capacities the announcements carry, summed up and
+///    shifted right by 20 bits,
+/// 2. `node_count` (3 bytes): the number of entries in the node section, and
+/// 3. `channel_count` (4 bytes): the number of announcements.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct ExtendedHeaderTotals {
+	pub(crate) total_capacity_btc_approx: u32,
+	pub(crate) node_count: u32,
+	pub(crate) channel_count: u32,
+}
+
+impl ExtendedHeaderTotals {
+	pub(crate) fn new(total_capacity_sats: u64, node_count: u32, channel_count: u32) -> Self {
+		Self {
+			total_capacity_btc_approx: (total_capacity_sats >> TOTAL_CAPACITY_SHIFT).min(u32::MAX as u64) as u32,
+			node_count: node_count.min(MAX_HEADER_NODE_COUNT),
+			channel_count,
+		}
+	}
+
+	/// The total capacity in satoshis, rounded down to a multiple of 2^20
+	pub(crate) fn approximate_capacity_sats(&self) -> u64 {
+		(self.total_capacity_btc_approx as u64) << TOTAL_CAPACITY_SHIFT
+	}
+
+	pub(crate) fn write(&self, output: &mut Vec<u8>) {
+		write_to_vec(&self.total_capacity_btc_approx, output);
+		output.extend_from_slice(&self.node_count.to_be_bytes()[1..]);
+		write_to_vec(&self.channel_count, output);
+	}
+}
+
+/// The flags an update's serialization sets on top of the update's own flags: whether it's
+/// incremental, and which of its fields follow
+const INCREMENTAL_UPDATE_FLAG: u8 = 0b_1000_0000;
+const CLTV_EXPIRY_DELTA_FLAG: u8 = 0b_0100_0000;
+const HTLC_MINIMUM_MSAT_FLAG: u8 = 0b_0010_0000;
+const FEE_BASE_MSAT_FLAG: u8 = 0b_0001_0000;
+const FEE_PROPORTIONAL_MILLIONTHS_FLAG: u8 = 0b_0000_1000;
+const HTLC_MAXIMUM_MSAT_FLAG: u8 = 0b_0000_0100;
+
+/// Roughly how many bytes an announcement and an update take up in a snapshot, for sizing the
+/// output ahead of serialization. Announcements carrying a capacity take up twenty bytes more.
+const ESTIMATED_ANNOUNCEMENT_SIZE: usize = 10;
+const ESTIMATED_UPDATE_SIZE: usize = 8;
+
 pub(super) struct SerializationSet {
 	pub(super) announcements: Vec<UnsignedChannelAnnouncement>,
 	pub(super) updates: Vec<UpdateSerialization>,
 	pub(super) full_update_defaults: DefaultUpdateValues,
 	pub(super) node_announcement_feature_defaults: Vec<NodeFeatures>,
 	pub(super) node_mutations: NodeDeltaSet,
-	pub(super) latest_seen: u32,
+	pub(crate) latest_seen: u32,
 	pub(super) chain_hash: ChainHash,
+	/// The number of channels left out for lacking recent updates, which is only done for full
+	/// snapshots
+	pub(super) omitted_stale_channel_count: usize,
+	/// The known capacities of the announced channels, by short channel id
+	pub(super) announcement_capacities: HashMap<u64, u64>,
+	/// The number of fee-only updates left out by the fee damping policy
+	pub(super) damped_update_count: usize,
+	/// The number of latest updates whose absent HTLC maximum was substituted
+	pub(super) htlc_maximum_substitution_count: usize,
+}
+
+impl SerializationSet {
+	/// Roughly how many bytes the announcements and updates take up serialized, such that the
+	/// output rarely needs to grow while they're written
+	pub(super) fn estimated_message_size(&self) -> usize {
+		self.announcements.len() * ESTIMATED_ANNOUNCEMENT_SIZE + self.announcement_capacities.len() * 20 + self.updates.len() * ESTIMATED_UPDATE_SIZE
+	}
 }
 
 pub(super) struct DefaultUpdateValues {
@@ -64,6 +163,16 @@ impl Default for MutatedProperties {
 }
 
 impl MutatedProperties {
+	/// Mark the properties that differ between the update a client knows and a later one
+	pub(super) fn record_changes(&mut self, previous: &UnsignedChannelUpdate, later: &UnsignedChannelUpdate) {
+		self.flags |= previous.flags != later.flags;
+		self.cltv_expiry_delta |= previous.cltv_expiry_delta != later.cltv_expiry_delta;
+		self.htlc_minimum_msat |= previous.htlc_minimum_msat != later.htlc_minimum_msat;
+		self.fee_base_msat |= previous.fee_base_msat != later.fee_base_msat;
+		self.fee_proportional_millionths |= previous.fee_proportional_millionths != later.fee_proportional_millionths;
+		self.htlc_maximum_msat |= previous.htlc_maximum_msat != later.htlc_maximum_msat;
+	}
+
 	/// Does not include flags because the flag byte is always sent in full
 	fn len(&self) -> u8 {
 		let mut mutations = 0;
@@ -76,6 +185,50 @@ impl MutatedProperties {
 	}
 }
 
+/// Thresholds below which a channel direction's fee changes are left out of delta snapshots if no
+/// other field changed, as the fees clients already know remain good enough to route with. A change
+/// is small if it's within either the absolute threshold of its field, or the relative threshold.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct FeeDamping {
+	pub(crate) base_fee_msat: u32,
+	pub(crate) fee_rate_ppm: u32,
+	/// Relative to the fee the client knows, in percent
+	pub(crate) relative_percent: u32,
+}
+
+impl FeeDamping {
+	fn is_enabled(&self) -> bool {
+		self.base_fee_msat > 0 || self.fee_rate_ppm > 0 || self.relative_percent > 0
+	}
+
+	fn is_small_change(&self, previous: u32, latest: u32, absolute_threshold: u32) -> bool {
+		let change = previous.abs_diff(latest);
+		change <= absolute_threshold || change as u64 * 100 <= previous as u64 * self.relative_percent as u64
+	}
+
+	/// Whether the change from the update a client knows to the latest one may be left out
+	fn damps(&self, previous: &UnsignedChannelUpdate, latest: &UnsignedChannelUpdate, mutated_properties: &MutatedProperties) -> bool {
+		if !self.is_enabled() {
+			return false;
+		}
+		if mutated_properties.flags || mutated_properties.cltv_expiry_delta || mutated_properties.htlc_minimum_msat || mutated_properties.htlc_maximum_msat {
+			return false;
+		}
+		if !mutated_properties.fee_base_msat && !mutated_properties.fee_proportional_millionths {
+			return false;
+		}
+		self.damps_fee_change((previous.fee_base_msat, previous.fee_proportional_millionths), (latest.fee_base_msat, latest.fee_proportional_millionths))
+	}
+
+	/// Whether a change from the known to the latest base fee and fee rate, given in that order, is
+	/// small enough to be left out
+	pub(crate) fn damps_fee_change(&self, known_fees: (u32, u32), latest_fees: (u32, u32)) -> bool {
+		self.is_enabled()
+			&& self.is_small_change(known_fees.0, latest_fees.0, self.base_fee_msat)
+			&& self.is_small_change(known_fees.1, latest_fees.1, self.fee_rate_ppm)
+	}
+}
+
 pub(super) enum UpdateSerialization {
 	Full(UnsignedChannelUpdate),
 	Incremental(UnsignedChannelUpdate, MutatedProperties),
@@ -107,15 +260,26 @@ struct FullUpdateValueHistograms {
 	htlc_maximum_msat: HashMap<u64, usize>,
 }
 
-pub(super) fn serialize_delta_set(channel_delta_set: DeltaSet, node_delta_set: NodeDeltaSet, last_sync_timestamp: u32) -> SerializationSet {
+pub(super) fn serialize_delta_set(channel_delta_set: DeltaSet, node_delta_set: NodeDeltaSet, last_sync_timestamp: u32, clock: SnapshotClock, fee_damping: &FeeDamping) -> SerializationSet {
+	// in full snapshots, nearly every channel contributes its announcement and an update per
+	// direction, whereas in deltas, most channels only contribute an update
+	let (announcement_capacity, update_capacity) = if last_sync_timestamp == 0 {
+		(channel_delta_set.len(), channel_delta_set.len() * 2)
+	} else {
+		(0, channel_delta_set.len())
+	};
 	let mut serialization_set = SerializationSet {
-		announcements: vec![],
-		updates: vec![],
+		announcements: Vec::with_capacity(announcement_capacity),
+		updates: Vec::with_capacity(update_capacity),
 		full_update_defaults: Default::default(),
 		node_announcement_feature_defaults: vec![],
 		node_mutations: Default::default(),
 		chain_hash: ChainHash::using_genesis_block(Network::Bitcoin),
 		latest_seen: 0,
+		omitted_stale_channel_count: 0,
+		announcement_capacities: HashMap::new(),
+		damped_update_count: 0,
+		htlc_maximum_substitution_count: 0,
 	};
 
 	let mut chain_hash_set = false;
@@ -137,12 +301,16 @@ pub(super) fn serialize_delta_set(channel_delta_set: DeltaSet, node_delta_set: N
 	};
 
 	// if the previous seen update happened more than 6 days ago, the client may have pruned it, and an incremental update wouldn't work
-	let non_incremental_previous_update_threshold_timestamp = SystemTime::now().checked_sub(config::CHANNEL_REMINDER_AGE).unwrap().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+	let non_incremental_previous_update_threshold_timestamp = clock.now().saturating_sub(config::CHANNEL_REMINDER_AGE.as_secs()) as u32;
 
 	for (scid, channel_delta) in channel_delta_set.into_iter() {
 
 		// any announcement chain hash is gonna be the same value. Just set it from the first one.
-		let channel_announcement_delta = channel_delta.announcement.as_ref().unwrap();
+		let channel_announcement_delta = match channel_delta.announcement {
+			Some(announcement_delta) => announcement_delta,
+			// channels without announcements were removed by `filter_delta_set`
+			None => continue,
+		};
 		if !chain_hash_set {
 			chain_hash_set = true;
 			serialization_set.chain_hash = channel_announcement_delta.announcement.chain_hash;
@@ -158,7 +326,10 @@ pub(super) fn serialize_delta_set(channel_delta_set: DeltaSet, node_delta_set: N
 		let send_announcement = is_new_announcement || is_newly_included_announcement;
 		if send_announcement {
 			serialization_set.latest_seen = max(serialization_set.latest_seen, current_announcement_seen);
-			serialization_set.announcements.push(channel_delta.announcement.unwrap().announcement);
+			if let Some(capacity_sats) = channel_announcement_delta.capacity_sats {
+				serialization_set.announcement_capacities.insert(scid, capacity_sats);
+			}
+			serialization_set.announcements.push(channel_announcement_delta.announcement);
 		}
 
 		let direction_a_updates = channel_delta.updates.0;
@@ -182,6 +353,15 @@ pub(super) fn serialize_delta_set(channel_delta_set: DeltaSet, node_delta_set: N
 							// this way, the default values can be computed more efficiently
 							record_full_update_in_histograms(&latest_update);
 							serialization_set.updates.push(UpdateSerialization::Full(latest_update));
+						} else if fee_damping.damps(&update_delta.update, &latest_update, &mutated_properties) {
+							// damped updates were never candidates for the defaults, which only
+							// full updates count towards, but the channel may still be due a reminder
+							serialization_set.damped_update_count += 1;
+							if channel_delta.requires_reminder {
+								if let Some(flags) = updates.serialization_update_flags {
+									serialization_set.updates.push(UpdateSerialization::Reminder(scid, flags));
+								}
+							}
 						} else if mutated_properties.len() > 0 || mutated_properties.flags {
 							// we don't count flags as mutated properties
 							serialization_set.updates.push(
@@ -227,7 +407,7 @@ pub(super) fn serialize_delta_set(channel_delta_set: DeltaSet, node_delta_set: N
 
 	let mut node_feature_histogram: HashMap<&NodeFeatures, usize> = Default::default();
 	for (_id, delta) in serialization_set.node_mutations.iter() {
-		if delta.has_feature_set_changed || delta.last_details_before_seen.is_none() {
+		if delta.has_feature_set_changed {
 			if let Some(latest_details) = delta.latest_details_after_seen.as_ref() {
 				*node_feature_histogram.entry(&latest_details.features).or_insert(0) += 1;
 			};
@@ -238,103 +418,115 @@ pub(super) fn serialize_delta_set(channel_delta_set: DeltaSet, node_delta_set: N
 	serialization_set
 }
 
-pub fn serialize_stripped_channel_announcement(announcement: &UnsignedChannelAnnouncement, node_id_a_index: usize, node_id_b_index: usize, previous_scid: u64) -> Vec<u8> {
-	let mut stripped_announcement = vec![];
+/// Append a value's serialization to a buffer. Writing to a `Vec` cannot fail, so unlike
+/// [`Writeable::write`], this does not return a `Result`.
+pub(super) fn write_to_vec<W: Writeable>(value: &W, output: &mut Vec<u8>) {
+	value.write(output).expect("writing to a Vec is infallible");
+}
 
-	announcement.features.write(&mut stripped_announcement).unwrap();
+/// Append an announcement's serialization to the snapshot output, along with the channel's
+/// capacity if given, which only version 2 snapshots may carry
+pub fn serialize_stripped_channel_announcement(announcement: &UnsignedChannelAnnouncement, node_id_a_index: usize, node_id_b_index: usize, previous_scid: u64, capacity_sats: Option<u64>, output: &mut Vec<u8>) {
+	write_to_vec(&announcement.features, output);
 
-	if previous_scid > announcement.short_channel_id {
-		panic!("unsorted scids!");
-	}
+	assert!(previous_scid <= announcement.short_channel_id, "unsorted scids!");
 	let scid_delta = BigSize(announcement.short_channel_id - previous_scid);
-	scid_delta.write(&mut stripped_announcement).unwrap();
+	write_to_vec(&scid_delta, output);
 
 	// write indices of node ids rather than the node IDs themselves
-	BigSize(node_id_a_index as u64).write(&mut stripped_announcement).unwrap();
-	BigSize(node_id_b_index as u64).write(&mut stripped_announcement).unwrap();
-
-	// println!("serialized CA: {}, \n{:?}\n{:?}\n", announcement.short_channel_id, announcement.node_id_1, announcement.node_id_2);
-	stripped_announcement
-}
-
-pub(super) fn serialize_stripped_channel_update(update: &UpdateSerialization, default_values: &DefaultUpdateValues, previous_scid: u64) -> Vec<u8> {
-	let mut serialized_flags = update.flags();
-
-	if previous_scid > update.scid() {
-		panic!("unsorted scids!");
+	let mut node_id_a_index = node_id_a_index as u64;
+	if capacity_sats.is_some() {
+		node_id_a_index |= ANNOUNCEMENT_ADDITIONAL_DATA_FLAG;
 	}
+	write_to_vec(&BigSize(node_id_a_index), output);
+	write_to_vec(&BigSize(node_id_b_index as u64), output);
+
+	if let Some(capacity_sats) = capacity_sats {
+		// the additional data is a TLV stream, prefixed by its length for clients to skip it
+		let record_type = BigSize(CAPACITY_TLV_TYPE);
+		let record_length = BigSize(capacity_sats.serialized_length() as u64);
+		let additional_data_length = record_type.serialized_length() + record_length.serialized_length() + capacity_sats.serialized_length();
+		write_to_vec(&(additional_data_length as u16), output);
+		write_to_vec(&record_type, output);
+		write_to_vec(&record_length, output);
+		write_to_vec(&capacity_sats, output);
+	}
+}
 
-	let mut delta_serialization = Vec::new();
-	let mut prefixed_serialization = Vec::new();
+/// Append an update's serialization to the snapshot output. The flags precede the fields they
+/// announce, so they're determined before anything is written.
+pub(super) fn serialize_stripped_channel_update(update: &UpdateSerialization, default_values: &DefaultUpdateValues, previous_scid: u64, output: &mut Vec<u8>) {
+	assert!(previous_scid <= update.scid(), "unsorted scids!");
 
+	// the flags of the fields that follow, kept apart from the update's own flags
+	let mut field_flags = 0;
+	let mut written_update = None;
 	match update {
 		UpdateSerialization::Full(latest_update) => {
 			if latest_update.cltv_expiry_delta != default_values.cltv_expiry_delta {
-				serialized_flags |= 0b_0100_0000;
-				latest_update.cltv_expiry_delta.write(&mut delta_serialization).unwrap();
+				field_flags |= CLTV_EXPIRY_DELTA_FLAG;
 			}
-
 			if latest_update.htlc_minimum_msat != default_values.htlc_minimum_msat {
-				serialized_flags |= 0b_0010_0000;
-				latest_update.htlc_minimum_msat.write(&mut delta_serialization).unwrap();
+				field_flags |= HTLC_MINIMUM_MSAT_FLAG;
 			}
-
 			if latest_update.fee_base_msat != default_values.fee_base_msat {
-				serialized_flags |= 0b_0001_0000;
-				latest_update.fee_base_msat.write(&mut delta_serialization).unwrap();
+				field_flags |= FEE_BASE_MSAT_FLAG;
 			}
-
 			if latest_update.fee_proportional_millionths != default_values.fee_proportional_millionths {
-				serialized_flags |= 0b_0000_1000;
-				latest_update.fee_proportional_millionths.write(&mut delta_serialization).unwrap();
+				field_flags |= FEE_PROPORTIONAL_MILLIONTHS_FLAG;
 			}
-
 			if latest_update.htlc_maximum_msat != default_values.htlc_maximum_msat {
-				serialized_flags |= 0b_0000_0100;
-				latest_update.htlc_maximum_msat.write(&mut delta_serialization).unwrap();
+				field_flags |= HTLC_MAXIMUM_MSAT_FLAG;
 			}
+			written_update = Some(latest_update);
 		}
 		UpdateSerialization::Incremental(latest_update, mutated_properties) => {
 			// indicate that this update is incremental
-			serialized_flags |= 0b_1000_0000;
+			field_flags |= INCREMENTAL_UPDATE_FLAG;
 
 			if mutated_properties.cltv_expiry_delta {
-				serialized_flags |= 0b_0100_0000;
-				latest_update.cltv_expiry_delta.write(&mut delta_serialization).unwrap();
+				field_flags |= CLTV_EXPIRY_DELTA_FLAG;
 			}
-
 			if mutated_properties.htlc_minimum_msat {
-				serialized_flags |= 0b_0010_0000;
-				latest_update.htlc_minimum_msat.write(&mut delta_serialization).unwrap();
+				field_flags |= HTLC_MINIMUM_MSAT_FLAG;
 			}
-
 			if mutated_properties.fee_base_msat {
-				serialized_flags |= 0b_0001_0000;
-				latest_update.fee_base_msat.write(&mut delta_serialization).unwrap();
+				field_flags |= FEE_BASE_MSAT_FLAG;
 			}
-
 			if mutated_properties.fee_proportional_millionths {
-				serialized_flags |= 0b_0000_1000;
-				latest_update.fee_proportional_millionths.write(&mut delta_serialization).unwrap();
+				field_flags |= FEE_PROPORTIONAL_MILLIONTHS_FLAG;
 			}
-
 			if mutated_properties.htlc_maximum_msat {
-				serialized_flags |= 0b_0000_0100;
-				latest_update.htlc_maximum_msat.write(&mut delta_serialization).unwrap();
+				field_flags |= HTLC_MAXIMUM_MSAT_FLAG;
 			}
+			written_update = Some(latest_update);
 		},
 		UpdateSerialization::Reminder(_, _) => {
 			// indicate that this update is incremental
-			serialized_flags |= 0b_1000_0000;
+			field_flags |= INCREMENTAL_UPDATE_FLAG;
 		}
 	}
 	let scid_delta = BigSize(update.scid() - previous_scid);
-	scid_delta.write(&mut prefixed_serialization).unwrap();
-
-	serialized_flags.write(&mut prefixed_serialization).unwrap();
-	prefixed_serialization.append(&mut delta_serialization);
+	write_to_vec(&scid_delta, output);
+	write_to_vec(&(update.flags() | field_flags), output);
 
-	prefixed_serialization
+	if let Some(latest_update) = written_update {
+		if field_flags & CLTV_EXPIRY_DELTA_FLAG != 0 {
+			write_to_vec(&latest_update.cltv_expiry_delta, output);
+		}
+		if field_flags & HTLC_MINIMUM_MSAT_FLAG != 0 {
+			write_to_vec(&latest_update.htlc_minimum_msat, output);
+		}
+		if field_flags & FEE_BASE_MSAT_FLAG != 0 {
+			write_to_vec(&latest_update.fee_base_msat, output);
+		}
+		if field_flags & FEE_PROPORTIONAL_MILLIONTHS_FLAG != 0 {
+			write_to_vec(&latest_update.fee_proportional_millionths, output);
+		}
+		if field_flags & HTLC_MAXIMUM_MSAT_FLAG != 0 {
+			write_to_vec(&latest_update.htlc_maximum_msat, output);
+		}
+	}
 }
 
 pub(super) fn find_most_common_histogram_entry_with_default<T: Copy>(histogram: HashMap<T, usize>, default: T) -> T {
@@ -354,3 +546,239 @@ pub(super) fn find_leading_histogram_entries(histogram: HashMap<&NodeFeatures, u
 	entry_counts.sort_by(|a, b| b.1.cmp(&a.1));
 	entry_counts.into_iter().take(count).map(|(&features, _count)| features.clone()).collect()
 }
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use bitcoin::secp256k1::{PublicKey, Secp256k1, SecretKey};
+	use lightning::ln::features::ChannelFeatures;
+	use lightning::routing::gossip::NodeId;
+
+	use crate::lookup::{AnnouncementDelta, ChannelDelta, UpdateDelta};
+	use crate::types::tests::TestLogger;
+
+	use super::*;
+
+	fn node_id(secret_byte: u8) -> NodeId {
+		let secret_key = SecretKey::from_slice(&[secret_byte; 32]).unwrap();
+		NodeId::from_pubkey(&PublicKey::from_secret_key(&Secp256k1::new(), &secret_key))
+	}
+
+	fn update(short_channel_id: u64, timestamp: u32, fee_base_msat: u32, fee_proportional_millionths: u32, cltv_expiry_delta: u16) -> UnsignedChannelUpdate {
+		UnsignedChannelUpdate {
+			chain_hash: ChainHash::using_genesis_block(Network::Bitcoin),
+			short_channel_id,
+			timestamp,
+			flags: 0,
+			cltv_expiry_delta,
+			htlc_minimum_msat: 1_000,
+			htlc_maximum_msat: 1_000_000_000,
+			fee_base_msat,
+			fee_proportional_millionths,
+			excess_data: vec![],
+		}
+	}
+
+	/// A channel announced at `announced_at`, whose direction 0 was last updated at `previous_seen`
+	/// before the client's last sync, and again at `latest_seen`
+	fn channel_delta(previous: UnsignedChannelUpdate, previous_seen: u32, latest: UnsignedChannelUpdate, latest_seen: u32, announced_at: u32) -> ChannelDelta {
+		let announcement = UnsignedChannelAnnouncement {
+			features: ChannelFeatures::empty(),
+			chain_hash: ChainHash::using_genesis_block(Network::Bitcoin),
+			short_channel_id: latest.short_channel_id,
+			node_id_1: node_id(1),
+			node_id_2: node_id(2),
+			bitcoin_key_1: node_id(1),
+			bitcoin_key_2: node_id(2),
+			excess_data: vec![],
+		};
+		let mutated_properties = MutatedProperties {
+			flags: previous.flags != latest.flags,
+			cltv_expiry_delta: previous.cltv_expiry_delta != latest.cltv_expiry_delta,
+			htlc_minimum_msat: previous.htlc_minimum_msat != latest.htlc_minimum_msat,
+			fee_base_msat: previous.fee_base_msat != latest.fee_base_msat,
+			fee_proportional_millionths: previous.fee_proportional_millionths != latest.fee_proportional_millionths,
+			htlc_maximum_msat: previous.htlc_maximum_msat != latest.htlc_maximum_msat,
+		};
+		ChannelDelta {
+			announcement: Some(AnnouncementDelta { seen: announced_at, announcement, capacity_sats: None }),
+			updates: (Some(DirectedUpdateDelta {
+				last_update_before_seen: Some(UpdateDelta { seen: previous_seen, update: previous }),
+				latest_update_after_seen: Some(UpdateDelta { seen: latest_seen, update: latest }),
+				mutated_properties,
+				serialization_update_flags: None,
+			}), None),
+			first_bidirectional_updates_seen: Some(announced_at),
+			requires_reminder: false,
+		}
+	}
+
+	#[test]
+	fn test_stripped_serialization_bytes() {
+		// the bytes the per-message serializers produced before they wrote into a shared output
+		let announcement = channel_delta(update(1_000, 0, 0, 0, 0), 0, update(1_000, 0, 0, 0, 0), 0, 0).announcement.unwrap().announcement;
+		let mut output = vec![0xaa];
+		serialize_stripped_channel_announcement(&announcement, 0, 1, 0, None, &mut output);
+		assert_eq!(output, vec![0xaa, 0x00, 0x00, 0xfd, 0x03, 0xe8, 0x00, 0x01]);
+		output.clear();
+		serialize_stripped_channel_announcement(&announcement, 0, 1, 0, Some(5_000_000), &mut output);
+		assert_eq!(output, vec![
+			0x00, 0x00, 0xfd, 0x03, 0xe8,
+			0xff, 0x80, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x01,
+			0x00, 0x0a, 0x01, 0x08, 0x00, 0x00, 0x00, 0x00, 0x00, 0x4c, 0x4b, 0x40,
+		]);
+
+		let defaults = DefaultUpdateValues { cltv_expiry_delta: 144, htlc_minimum_msat: 1_000, fee_base_msat: 1_000, fee_proportional_millionths: 1, htlc_maximum_msat: 0 };
+		let mut full_update = update(1_000, 0, 1_000, 100, 40);
+		full_update.flags = 1;
+		full_update.htlc_maximum_msat = 1_000_000;
+		output.clear();
+		serialize_stripped_channel_update(&UpdateSerialization::Full(full_update), &defaults, 999, &mut output);
+		assert_eq!(output, vec![0x01, 0x4d, 0x00, 0x28, 0x00, 0x00, 0x00, 0x64, 0x00, 0x00, 0x00, 0x00, 0x00, 0x0f, 0x42, 0x40]);
+
+		let mutated_properties = MutatedProperties { fee_base_msat: true, ..Default::default() };
+		output.clear();
+		serialize_stripped_channel_update(&UpdateSerialization::Incremental(update(1_000, 0, 2_000, 100, 40), mutated_properties), &defaults, 999, &mut output);
+		assert_eq!(output, vec![0x01, 0x90, 0x00, 0x00, 0x07, 0xd0]);
+
+		output.clear();
+		serialize_stripped_channel_update(&UpdateSerialization::Reminder(1_000, 2), &defaults, 0, &mut output);
+		assert_eq!(output, vec![0xfd, 0x03, 0xe8, 0x82]);
+
+		// the update's own flags are passed through unmasked, as before, so a bit they set beyond the
+		// direction and disable bits reads as a field flag even though no field follows
+		let mut default_update = update(1_000, 0, 1_000, 1, 144);
+		default_update.flags = 0b_0000_0100;
+		default_update.htlc_maximum_msat = 0;
+		output.clear();
+		serialize_stripped_channel_update(&UpdateSerialization::Full(default_update), &defaults, 1_000, &mut output);
+		assert_eq!(output, vec![0x00, 0x04]);
+	}
+
+	/// The set the golden snapshots in `tests/fixtures` hold, as serialized before the serializers
+	/// wrote into a shared output: two announced channels and their full updates, or a delta with an
+	/// incremental update and a reminder
+	fn golden_serialization_set(is_full: bool) -> SerializationSet {
+		let announcement = |short_channel_id| UnsignedChannelAnnouncement {
+			features: ChannelFeatures::empty(),
+			chain_hash: ChainHash::using_genesis_block(Network::Bitcoin),
+			short_channel_id,
+			node_id_1: node_id(1),
+			node_id_2: node_id(2),
+			bitcoin_key_1: node_id(1),
+			bitcoin_key_2: node_id(2),
+			excess_data: vec![],
+		};
+		let (announcements, updates) = if is_full {
+			let mut reverse_update = update(1_000, 0, 1_500, 100, 40);
+			reverse_update.flags = 1;
+			(vec![announcement(1_000), announcement(1_005)], vec![
+				UpdateSerialization::Full(update(1_000, 0, 1_000, 100, 144)),
+				UpdateSerialization::Full(reverse_update),
+				UpdateSerialization::Full(update(1_005, 0, 1_000, 200, 144)),
+			])
+		} else {
+			let mutated_properties = MutatedProperties { fee_base_msat: true, ..Default::default() };
+			(Vec::new(), vec![
+				UpdateSerialization::Incremental(update(1_000, 0, 2_000, 100, 144), mutated_properties),
+				UpdateSerialization::Reminder(1_005, 1),
+			])
+		};
+		SerializationSet {
+			announcements,
+			updates,
+			full_update_defaults: DefaultUpdateValues { cltv_expiry_delta: 144, htlc_minimum_msat: 1_000, fee_base_msat: 1_000, fee_proportional_millionths: 100, htlc_maximum_msat: 1_000_000_000 },
+			node_announcement_feature_defaults: Vec::new(),
+			node_mutations: NodeDeltaSet::new(),
+			latest_seen: 1_699_920_000,
+			chain_hash: ChainHash::using_genesis_block(Network::Bitcoin),
+			omitted_stale_channel_count: 0,
+			announcement_capacities: HashMap::new(),
+			damped_update_count: 0,
+			htlc_maximum_substitution_count: 0,
+		}
+	}
+
+	#[test]
+	fn test_golden_snapshots() {
+		let golden_snapshots: [(bool, u8, &[u8]); 4] = [
+			(true, 1, include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/golden_full_v1.lngossip"))),
+			(true, 2, include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/golden_full_v2.lngossip"))),
+			(false, 1, include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/golden_delta_v1.lngossip"))),
+			(false, 2, include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/golden_delta_v2.lngossip"))),
+		];
+		for (is_full, version, golden_snapshot) in golden_snapshots {
+			let snapshot = crate::serialize_delta(&golden_serialization_set(is_full), version, Arc::new(TestLogger::new()));
+			assert_eq!(snapshot.data, golden_snapshot, "full: {}, version: {}", is_full, version);
+		}
+	}
+
+	#[test]
+	fn test_extended_header_totals() {
+		let mut output = Vec::new();
+		ExtendedHeaderTotals::new((5 << 20) + 12_345, 70_000, 3).write(&mut output);
+		assert_eq!(output, vec![0x00, 0x00, 0x00, 0x05, 0x01, 0x11, 0x70, 0x00, 0x00, 0x00, 0x03]);
+		assert_eq!(output.len(), EXTENDED_HEADER_TOTALS_LENGTH);
+
+		// totals that don't fit their fields saturate
+		let saturated = ExtendedHeaderTotals::new(u64::MAX, u32::MAX, u32::MAX);
+		assert_eq!((saturated.total_capacity_btc_approx, saturated.node_count), (u32::MAX, (1 << 24) - 1));
+		output.clear();
+		saturated.write(&mut output);
+		assert_eq!(output, vec![0xff; EXTENDED_HEADER_TOTALS_LENGTH]);
+	}
+
+	#[test]
+	fn test_fee_damping_thresholds() {
+		let damping = FeeDamping { base_fee_msat: 0, fee_rate_ppm: 5, relative_percent: 1 };
+		assert!(damping.is_small_change(1_000, 1_010, 0));
+		assert!(!damping.is_small_change(1_000, 1_011, 0));
+		assert!(damping.is_small_change(100, 95, 5));
+		// nothing is small relative to zero
+		assert!(!damping.is_small_change(0, 1, 0));
+		assert!(!FeeDamping::default().is_enabled());
+	}
+
+	#[test]
+	fn test_fee_damping_leaves_defaults_alone() {
+		let now = 1_700_000_000;
+		let clock = SnapshotClock::as_of(now as u64);
+		let last_sync_timestamp = now - 1_800;
+		let announced_at = now - 2 * 24 * 3600;
+		let stale_seen = now - 10 * 24 * 3600;
+
+		let delta_set = || {
+			let mut delta_set = DeltaSet::new();
+			// a small base fee change, which is damped
+			delta_set.insert(1, channel_delta(update(1, now - 3_600, 1_000, 100, 40), now - 3_600, update(1, now, 1_005, 100, 40), now - 60, announced_at));
+			// a fee rate change beyond the threshold
+			delta_set.insert(2, channel_delta(update(2, now - 3_600, 1_000, 100, 40), now - 3_600, update(2, now, 1_000, 200, 40), now - 60, announced_at));
+			// a small base fee change along with another field
+			delta_set.insert(3, channel_delta(update(3, now - 3_600, 1_000, 100, 40), now - 3_600, update(3, now, 1_005, 100, 80), now - 60, announced_at));
+			// a small base fee change, after an update clients may have pruned, which is sent in full
+			delta_set.insert(4, channel_delta(update(4, stale_seen, 1_000, 100, 144), stale_seen, update(4, now, 1_005, 100, 144), now - 60, announced_at));
+			// a small base fee change of a newly announced channel, which is sent in full
+			delta_set.insert(5, channel_delta(update(5, now - 3_600, 1_000, 100, 144), now - 3_600, update(5, now, 1_005, 100, 144), now - 60, now - 60));
+			delta_set
+		};
+
+		let damping = FeeDamping { base_fee_msat: 10, fee_rate_ppm: 5, relative_percent: 0 };
+		let damped = serialize_delta_set(delta_set(), NodeDeltaSet::new(), last_sync_timestamp, clock, &damping);
+		assert_eq!(damped.damped_update_count, 1);
+		assert_eq!(damped.updates.iter().map(|update| update.scid()).collect::<Vec<_>>(), vec![2, 3, 4, 5]);
+		assert!(matches!(damped.updates[0], UpdateSerialization::Incremental(..)));
+		assert!(matches!(damped.updates[2], UpdateSerialization::Full(..)));
+		assert!(matches!(damped.updates[3], UpdateSerialization::Full(..)));
+
+		// only full updates count towards the defaults, which damping never leaves out
+		let undamped = serialize_delta_set(delta_set(), NodeDeltaSet::new(), last_sync_timestamp, clock, &FeeDamping::default());
+		assert_eq!(undamped.damped_update_count, 0);
+		assert_eq!(undamped.updates.len(), 5);
+		assert_eq!(damped.full_update_defaults.fee_base_msat, 1_005);
+		assert_eq!(damped.full_update_defaults.cltv_expiry_delta, 144);
+		assert_eq!(damped.full_update_defaults.fee_base_msat, undamped.full_update_defaults.fee_base_msat);
+		assert_eq!(damped.full_update_defaults.cltv_expiry_delta, undamped.full_update_defaults.cltv_expiry_delta);
+		assert_eq!(damped.latest_seen, undamped.latest_seen);
+	}
+}
@@ -1,52 +1,240 @@
 use std::collections::HashMap;
-use std::fs;
+use std::fs::{self, File};
+use std::io::{self, Write};
 use std::ops::Deref;
 use std::os::unix::fs::symlink;
-use std::sync::Arc;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use lightning::log_info;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::sha256::Hash as Sha256;
+use lightning::{log_error, log_info, log_warn};
 
 use lightning::routing::gossip::NetworkGraph;
 use lightning::util::logger::Logger;
 
+use sysinfo::Disks;
+
+use crate::chain_check;
+use crate::change_tracker::ChangeTracker;
+use crate::clock::SnapshotClock;
+use crate::compact_graph::{self, CompactGraph, DirectionalCoverage};
 use crate::config;
 use crate::config::cache_path;
+use crate::debug_dump::{DebugState, SnapshotRoundSummary};
+use crate::error::{ErrorContext, ProcessorError};
+use crate::freshness::{FreshnessMonitor, SnapshotFreshness};
+use crate::pacing::{LookupLoad, LookupPacing};
+use crate::persistence;
+use crate::tables::Tables;
+use crate::webhook;
+
+/// Brotli's highest quality, as snapshots are compressed once and downloaded many times
+const BROTLI_QUALITY: u32 = 11;
+/// The base-2 logarithm of Brotli's window size
+const BROTLI_WINDOW_BITS: u32 = 22;
+/// Appended to the names of Brotli-compressed snapshot files and symlinks
+const BROTLI_EXTENSION: &str = ".br";
+/// Appended to the names of the files and symlinks holding the hex-encoded SHA-256 checksums of
+/// the uncompressed snapshots
+pub(crate) const CHECKSUM_EXTENSION: &str = ".sha256";
+/// Appended to the names of the version 2 snapshot files and symlinks whose extended variants carry
+/// channel capacities
+const EXTENDED_EXTENSION: &str = ".ext";
+
+/// How often the graph's changes since the latest generation are checked for significance while
+/// waiting for the next scheduled one
+const CHANGE_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// A change in the number of bidirectional channels by more than this since the previous
+/// generation round is logged as a warning
+const DIRECTIONAL_COVERAGE_SHIFT_WARNING_PERCENT: f64 = 10.0;
+
+/// The number of snapshot generation rounds that failed since startup, e. g. for running out of
+/// disk space
+static FAILED_GENERATION_COUNT: AtomicU64 = AtomicU64::new(0);
+/// The number of snapshot generations under way, which database maintenance waits for
+static GENERATIONS_IN_PROGRESS: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether snapshots are being generated, whose lookups maintenance would slow down
+pub(crate) fn is_generation_in_progress() -> bool {
+	GENERATIONS_IN_PROGRESS.load(Ordering::Acquire) > 0
+}
+
+/// Limits on the archived snapshot files kept on disk, enforced after every snapshot generation
+/// round. The served snapshots are replaced wholesale every round, so only the archive, which
+/// superseded generations are moved to, accumulates files.
+pub(crate) struct SnapshotRetentionPolicy {
+	/// The maximum number of snapshot files to keep per archive directory
+	pub(crate) max_files: usize,
+	/// Snapshot files older than this are deleted
+	pub(crate) max_age_days: u32,
+	/// If less disk space than this is available, archived snapshot files are deleted, and no new
+	/// snapshots are generated
+	pub(crate) min_free_bytes: u64,
+}
+
+/// The size of a snapshot generated for a given scope, compared to the previous generation
+#[derive(Debug, PartialEq)]
+pub(crate) struct SnapshotSizeReport {
+	pub(crate) scope: u64,
+	/// The size of the v2 snapshot, which is what current clients fetch
+	pub(crate) size_bytes: u64,
+	/// The size of the snapshot served for this scope before this generation, if any
+	pub(crate) previous_size_bytes: Option<u64>,
+	/// Whether the snapshot exceeded the maximum blob size, and was therefore not published
+	pub(crate) oversized: bool,
+	/// The database load caused by the snapshot's lookups
+	pub(crate) lookup_load: LookupLoad,
+	/// The number of channels left out of a full snapshot for lacking recent updates
+	pub(crate) omitted_stale_channel_count: usize,
+	/// The number of fee-only updates the fee damping policy left out of a delta snapshot
+	pub(crate) damped_update_count: usize,
+	/// The number of updates whose absent HTLC maximum was substituted with the channel capacity
+	pub(crate) htlc_maximum_substitution_count: usize,
+}
+
+impl SnapshotSizeReport {
+	/// The relative size change compared to the previous generation, in percent
+	pub(crate) fn size_change_percent(&self) -> Option<f64> {
+		let previous_size_bytes = self.previous_size_bytes.filter(|size| *size > 0)?;
+		Some((self.size_bytes as f64 - previous_size_bytes as f64) * 100.0 / previous_size_bytes as f64)
+	}
+}
+
+/// What a generation round produced, and the state of the graph it was generated from
+#[derive(Debug, PartialEq)]
+pub(crate) struct GenerationReport {
+	pub(crate) size_reports: Vec<SnapshotSizeReport>,
+	/// Channels updated in a single direction or none are left out of all snapshots until both
+	/// directions have been updated, so shifts here explain shifts in the snapshots' contents
+	pub(crate) directional_coverage: DirectionalCoverage,
+}
 
 pub(crate) struct Snapshotter<L: Deref + Clone> where L::Target: Logger {
 	network_graph: Arc<NetworkGraph<L>>,
+	retention_policy: SnapshotRetentionPolicy,
+	/// Whether superseded generations are archived rather than deleted
+	snapshot_archive_enabled: bool,
+	/// Snapshots larger than this are not published, lest a serialization bug or an update storm
+	/// hand clients blobs they can't handle
+	max_blob_bytes: u64,
+	/// Limits on the database load of each snapshot's lookups, so they don't starve ingestion
+	lookup_pacing: LookupPacing,
+	/// Whether to write a Brotli-compressed copy of every snapshot for web servers to serve to
+	/// clients accepting that encoding
+	brotli_enabled: bool,
+	/// Whether to write an extended variant of every version 2 snapshot, carrying the capacity of
+	/// each announced channel, for clients that opted into it
+	extended_snapshots_enabled: bool,
+	/// Whether every round ends by checking that the newest delta chains onto the previous
+	/// generation's full snapshot
+	chain_verification_enabled: bool,
+	/// Where the timestamps of published snapshots are recorded for the freshness metrics
+	freshness: Arc<FreshnessMonitor>,
+	/// Where a summary of each generation round is recorded for debug dumps, if anywhere
+	debug_state: Option<Arc<DebugState>>,
+	/// The directional coverage of the previous generation round, for reporting changes
+	previous_directional_coverage: Mutex<Option<DirectionalCoverage>>,
+	/// How much the graph changed since the latest generation, which may warrant the next one
+	/// starting ahead of schedule
+	change_tracker: Arc<ChangeTracker>,
+	/// The number of bytes that may still be written before writes fail as if the disk were full
+	#[cfg(test)]
+	write_budget: Mutex<Option<u64>>,
 	logger: L,
 }
 
 impl<L: Deref + Clone> Snapshotter<L> where L::Target: Logger {
 	pub fn new(network_graph: Arc<NetworkGraph<L>>, logger: L) -> Self {
-		Self { network_graph, logger }
+		let retention_policy = config::snapshot_retention_policy();
+		let snapshot_archive_enabled = config::snapshot_archive_enabled();
+		let max_blob_bytes = config::max_snapshot_blob_bytes();
+		let lookup_pacing = config::lookup_pacing();
+		let brotli_enabled = config::brotli_enabled();
+		let extended_snapshots_enabled = config::extended_snapshots_enabled();
+		let chain_verification_enabled = config::chain_verification_enabled();
+		Self {
+			network_graph,
+			retention_policy,
+			snapshot_archive_enabled,
+			max_blob_bytes,
+			lookup_pacing,
+			brotli_enabled,
+			extended_snapshots_enabled,
+			chain_verification_enabled,
+			freshness: Arc::new(FreshnessMonitor::new()),
+			debug_state: None,
+			previous_directional_coverage: Mutex::new(None),
+			change_tracker: Arc::new(ChangeTracker::new(config::snapshot_triggers())),
+			#[cfg(test)]
+			write_budget: Mutex::new(None),
+			logger,
+		}
+	}
+
+	pub(crate) fn set_freshness_monitor(&mut self, freshness: Arc<FreshnessMonitor>) {
+		self.freshness = freshness;
+	}
+
+	pub(crate) fn set_debug_state(&mut self, debug_state: Arc<DebugState>) {
+		self.debug_state = Some(debug_state);
 	}
 
-	pub(crate) async fn snapshot_gossip(&self) {
+	pub(crate) fn set_change_tracker(&mut self, change_tracker: Arc<ChangeTracker>) {
+		self.change_tracker = change_tracker;
+	}
+
+	#[cfg(test)]
+	pub(crate) fn set_snapshot_archive(&mut self, snapshot_archive_enabled: bool, retention_policy: SnapshotRetentionPolicy) {
+		self.snapshot_archive_enabled = snapshot_archive_enabled;
+		self.retention_policy = retention_policy;
+	}
+
+	#[cfg(test)]
+	pub(crate) fn set_brotli_enabled(&mut self, brotli_enabled: bool) {
+		self.brotli_enabled = brotli_enabled;
+	}
+
+	#[cfg(test)]
+	pub(crate) fn set_extended_snapshots_enabled(&mut self, extended_snapshots_enabled: bool) {
+		self.extended_snapshots_enabled = extended_snapshots_enabled;
+	}
+
+	#[cfg(test)]
+	pub(crate) fn set_max_blob_bytes(&mut self, max_blob_bytes: u64) {
+		self.max_blob_bytes = max_blob_bytes;
+	}
+
+	#[cfg(test)]
+	pub(crate) fn set_write_budget(&self, write_budget: Option<u64>) {
+		*self.write_budget.lock().unwrap() = write_budget;
+	}
+
+	pub(crate) async fn snapshot_gossip(&self) -> Result<(), ProcessorError> {
 		log_info!(self.logger, "Initiating snapshotting service");
 
 		let snapshot_interval = config::snapshot_generation_interval() as u64;
-		let mut snapshot_scopes = vec![];
-		{ // double the coefficient until it reaches the maximum (limited) snapshot scope
-			let mut current_scope = snapshot_interval;
-			loop {
-				snapshot_scopes.push(current_scope);
-				if current_scope >= config::MAX_SNAPSHOT_SCOPE as u64 {
-					snapshot_scopes.push(u64::MAX);
-					break;
-				}
-
-				// double the current factor
-				current_scope <<= 1;
-			}
-		}
+		let snapshot_scopes = tiered_snapshot_scopes(snapshot_interval, config::full_snapshot_age());
+		log_info!(self.logger, "Snapshot scopes: {:?}", snapshot_scopes);
 
 		// this is gonna be a never-ending background job
 		loop {
-			self.generate_snapshots(config::SYMLINK_GRANULARITY_INTERVAL as u64, snapshot_interval, &snapshot_scopes, &cache_path(), None).await;
+			let cache_path = cache_path();
+			match available_disk_space(&cache_path) {
+				Some(available_bytes) if available_bytes < self.retention_policy.min_free_bytes => {
+					log_warn!(self.logger, "Skipping snapshot generation: only {} bytes of disk space available, but at least {} are required", available_bytes, self.retention_policy.min_free_bytes);
+				},
+				_ => {
+					// changes during the generation count towards the next one
+					self.change_tracker.reset();
+					self.generate_snapshots(config::SYMLINK_GRANULARITY_INTERVAL as u64, snapshot_interval, &snapshot_scopes, &cache_path, None).await?;
+				}
+			}
 
 			// constructing the snapshots may have taken a while
-			let current_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+			let current_time = SnapshotClock::wall_clock().now();
 
 			// NOTE: we're waiting until the next multiple of snapshot_interval
 			// however, if the symlink granularity is lower, then during that time, no intermediate
@@ -58,21 +246,70 @@ impl<L: Deref + Clone> Snapshotter<L> where L::Target: Logger {
 			log_info!(self.logger, "Sleeping until next snapshot capture: {}s", time_until_next_generation);
 			// add in an extra five seconds to assure the rounding down works correctly
 			let sleep = tokio::time::sleep(Duration::from_secs(time_until_next_generation + 5));
-			sleep.await;
+			tokio::pin!(sleep);
+			loop {
+				tokio::select! {
+					_ = &mut sleep => break,
+					_ = tokio::time::sleep(CHANGE_CHECK_INTERVAL) => {},
+				}
+				let channel_count = self.network_graph.read_only().channels().len();
+				if let Some(change) = self.change_tracker.significant_change(channel_count) {
+					// the snapshots are regenerated for the current reference timestamp, now
+					// including the gossip seen since the previous generation
+					log_info!(self.logger, "Capturing snapshots ahead of schedule after {}", change);
+					break;
+				}
+			}
 		}
 	}
 
-	pub(crate) async fn generate_snapshots(&self, granularity_interval: u64, snapshot_interval: u64, snapshot_scopes: &[u64], cache_path: &str, max_symlink_count: Option<u64>) {
+	/// Generate and publish a new generation of snapshots and symlinks. If that fails, e. g. because
+	/// the disk is full, whatever was written of the new generation is deleted again, and the
+	/// previous generation keeps being served.
+	pub(crate) async fn generate_snapshots(&self, granularity_interval: u64, snapshot_interval: u64, snapshot_scopes: &[u64], cache_path: &str, max_symlink_count: Option<u64>) -> Result<(), ProcessorError> {
+		let started_at = SystemTime::now();
+		GENERATIONS_IN_PROGRESS.fetch_add(1, Ordering::AcqRel);
+		let result = self.generate_pending_snapshots(SnapshotClock::wall_clock(), granularity_interval, snapshot_interval, snapshot_scopes, cache_path, max_symlink_count).await;
+		GENERATIONS_IN_PROGRESS.fetch_sub(1, Ordering::AcqRel);
+		if let Err(error) = &result {
+			let failed_generation_count = FAILED_GENERATION_COUNT.fetch_add(1, Ordering::Relaxed) + 1;
+			log_error!(self.logger, "Snapshot generation failed ({} failures since startup), so the previous generation keeps being served: {}", failed_generation_count, error);
+			for pending_directory in [format!("{}/snapshots_pending", cache_path), format!("{}/symlinks_pending", cache_path)] {
+				if let Err(error) = fs::remove_dir_all(&pending_directory) {
+					if error.kind() != io::ErrorKind::NotFound {
+						log_warn!(self.logger, "Failed to remove {}: {}", pending_directory, error);
+					}
+				}
+			}
+			webhook::notify_snapshot_generation_failure(&error.to_string(), failed_generation_count, self.logger.clone()).await;
+		}
+		if let Some(debug_state) = &self.debug_state {
+			debug_state.record_snapshot_round(SnapshotRoundSummary::new(started_at, &result, FAILED_GENERATION_COUNT.load(Ordering::Relaxed)));
+		}
+		result.map(|_| ())
+	}
+
+	/// Regenerate the snapshots and symlinks as they would have been generated at `as_of`, from the
+	/// channels and gossip seen by then, into `output_path`. Nothing is recorded, published, or
+	/// notified, and the directories snapshots are served from are left alone.
+	pub(crate) async fn generate_snapshots_as_of(&self, as_of: u64, granularity_interval: u64, snapshot_interval: u64, snapshot_scopes: &[u64], output_path: &str, max_symlink_count: Option<u64>) -> Result<GenerationReport, ProcessorError> {
+		fs::create_dir_all(output_path).context(format!("Failed to create output directory {}", output_path))?;
+		self.generate_pending_snapshots(SnapshotClock::as_of(as_of), granularity_interval, snapshot_interval, snapshot_scopes, output_path, max_symlink_count).await
+	}
+
+	/// Generate the snapshots and symlinks of a new generation, reporting the sizes of its snapshots
+	async fn generate_pending_snapshots(&self, clock: SnapshotClock, granularity_interval: u64, snapshot_interval: u64, snapshot_scopes: &[u64], cache_path: &str, max_symlink_count: Option<u64>) -> Result<GenerationReport, ProcessorError> {
 		let pending_snapshot_directory = format!("{}/snapshots_pending", cache_path);
 		let pending_symlink_directory = format!("{}/symlinks_pending", cache_path);
 		let finalized_snapshot_directory = format!("{}/snapshots", cache_path);
 		let finalized_symlink_directory = format!("{}/symlinks", cache_path);
+		let archive_directory = format!("{}/snapshot_archive", cache_path);
 		let relative_symlink_to_snapshot_path = "../snapshots";
 
 		// 1. get the current timestamp
-		let snapshot_generation_time = SystemTime::now();
-		let snapshot_generation_timestamp = snapshot_generation_time.duration_since(UNIX_EPOCH).unwrap().as_secs();
+		let snapshot_generation_timestamp = clock.now();
 		let reference_timestamp = Self::round_down_to_nearest_multiple(snapshot_generation_timestamp, snapshot_interval);
+		let reference_clock = clock.at_reference(reference_timestamp);
 		log_info!(self.logger, "Capturing snapshots at {} for: {}", snapshot_generation_timestamp, reference_timestamp);
 
 		// 2. sleep until the next round interval
@@ -98,13 +335,13 @@ impl<L: Deref + Clone> Snapshotter<L> where L::Target: Logger {
 			let versioned_symlink_directory = format!("{}{}", pending_symlink_directory, suffix);
 
 			if fs::metadata(&versioned_snapshot_directory).is_ok() {
-				fs::remove_dir_all(&versioned_snapshot_directory).expect("Failed to remove pending snapshot directory.");
+				fs::remove_dir_all(&versioned_snapshot_directory).context("Failed to remove pending snapshot directory")?;
 			}
 			if fs::metadata(&versioned_symlink_directory).is_ok() {
-				fs::remove_dir_all(&versioned_symlink_directory).expect("Failed to remove pending symlink directory.");
+				fs::remove_dir_all(&versioned_symlink_directory).context("Failed to remove pending symlink directory")?;
 			}
-			fs::create_dir_all(&versioned_snapshot_directory).expect("Failed to create pending snapshot directory");
-			fs::create_dir_all(&versioned_symlink_directory).expect("Failed to create pending symlink directory");
+			fs::create_dir_all(&versioned_snapshot_directory).context("Failed to create pending snapshot directory")?;
+			fs::create_dir_all(&versioned_symlink_directory).context("Failed to create pending symlink directory")?;
 		}
 
 		let mut snapshot_sync_timestamps: Vec<(u64, u64)> = Vec::new();
@@ -114,38 +351,158 @@ impl<L: Deref + Clone> Snapshotter<L> where L::Target: Logger {
 		};
 
 		let mut snapshot_filenames_by_scope: HashMap<u64, String> = HashMap::with_capacity(10);
+		let mut size_reports = Vec::with_capacity(snapshot_sync_timestamps.len());
+		let mut published_snapshots = Vec::with_capacity(snapshot_sync_timestamps.len());
+
+		// every scope's lookups share a single copy of the graph's channels
+		let compact_graph = if clock.is_historical() {
+			let client = crate::connect_to_db().await?;
+			CompactGraph::from_database(&client, &Tables::from_config(), clock).await?
+		} else {
+			self.network_graph.remove_stale_channels_and_tracking();
+			CompactGraph::from_network_graph(&self.network_graph)
+		};
+		log_info!(self.logger, "Compacted {} channels into approximately {} bytes, compared to the network graph's at least {} bytes", compact_graph.channel_count(), compact_graph.estimated_memory_bytes(), compact_graph::estimate_network_graph_memory_bytes(&self.network_graph));
+		let directional_coverage = compact_graph.directional_coverage();
+		let fee_damping = config::fee_damping();
 
 		for (current_scope, current_last_sync_timestamp) in &snapshot_sync_timestamps {
-			let network_graph_clone = self.network_graph.clone();
 			{
 				log_info!(self.logger, "Calculating {}-second snapshot", current_scope);
 				// calculate the snapshot
-				let delta = super::calculate_delta(network_graph_clone.clone(), current_last_sync_timestamp.clone() as u32, Some(reference_timestamp), self.logger.clone()).await;
+				let (delta, lookup_load) = super::calculate_paced_delta(&compact_graph, current_last_sync_timestamp.clone() as u32, reference_clock, &self.lookup_pacing, &fee_damping, self.logger.clone()).await?;
 				let snapshot_v1 = super::serialize_delta(&delta, 1, self.logger.clone());
 				let snapshot_v2 = super::serialize_delta(&delta, 2, self.logger.clone());
+				// not held to the maximum size, as only the standard snapshots are served to every client
+				let snapshot_extended = if self.extended_snapshots_enabled {
+					Some(super::serialize_extended_delta(&delta, self.logger.clone()))
+				} else {
+					None
+				};
+
+				let previous_filename = previous_snapshot_filename(&finalized_snapshot_directory, *current_scope);
+				let previous_size_bytes = previous_filename.as_ref()
+					.and_then(|filename| fs::metadata(format!("{}/v2/{}", finalized_snapshot_directory, filename)).ok())
+					.map(|metadata| metadata.len());
+				let size_bytes = snapshot_v2.data.len() as u64;
+				let oversized = snapshot_v1.data.len() as u64 > self.max_blob_bytes || size_bytes > self.max_blob_bytes;
+				size_reports.push(SnapshotSizeReport { scope: *current_scope, size_bytes, previous_size_bytes, oversized, lookup_load, omitted_stale_channel_count: delta.omitted_stale_channel_count, damped_update_count: delta.damped_update_count, htlc_maximum_substitution_count: delta.htlc_maximum_substitution_count });
+
+				if oversized {
+					log_error!(self.logger, "The {}-second snapshot is {} bytes, exceeding the maximum of {} bytes, so it won't be published", current_scope, size_bytes, self.max_blob_bytes);
+					self.record_oversized_snapshot(cache_path, reference_timestamp, *current_scope, size_bytes);
+					if !clock.is_historical() {
+						webhook::notify_oversized_snapshot(*current_scope, size_bytes, self.max_blob_bytes, self.logger.clone()).await;
+					}
+
+					// keep serving the previous generation's snapshot for this scope
+					match previous_filename {
+						Some(previous_filename) => {
+							for (suffix, _) in suffixes {
+								let previous_path = format!("{}{}/{}", finalized_snapshot_directory, suffix, previous_filename);
+								let pending_path = format!("{}{}/{}", pending_snapshot_directory, suffix, previous_filename);
+								let previous_snapshot = fs::read(&previous_path).context(format!("Failed to read snapshot {}", previous_path))?;
+								self.write_file(&pending_path, &previous_snapshot).context(format!("Failed to retain snapshot {}", previous_path))?;
+								self.write_checksum(&pending_path, &previous_snapshot)?;
+								if self.brotli_enabled {
+									let previous_compressed_path = format!("{}{}", previous_path, BROTLI_EXTENSION);
+									let pending_compressed_path = format!("{}{}", pending_path, BROTLI_EXTENSION);
+									// the previous generation may have predated enabling Brotli
+									match fs::read(&previous_compressed_path) {
+										Ok(previous_compressed) => self.write_file(&pending_compressed_path, &previous_compressed).context(format!("Failed to retain snapshot {}", previous_compressed_path))?,
+										Err(_) => { self.write_brotli_copy(&pending_path, &previous_snapshot)?; },
+									}
+								}
+								// the previous generation may have predated enabling extended snapshots too,
+								// in which case there is nothing to retain
+								let previous_extended_path = format!("{}{}", previous_path, EXTENDED_EXTENSION);
+								if self.extended_snapshots_enabled {
+									if let Ok(previous_extended) = fs::read(&previous_extended_path) {
+										let pending_extended_path = format!("{}{}", pending_path, EXTENDED_EXTENSION);
+										self.write_file(&pending_extended_path, &previous_extended).context(format!("Failed to retain snapshot {}", previous_extended_path))?;
+										self.write_checksum(&pending_extended_path, &previous_extended)?;
+									}
+								}
+							}
+							log_warn!(self.logger, "Continuing to serve {} for the {}-second scope", previous_filename, current_scope);
+							snapshot_filenames_by_scope.insert(*current_scope, previous_filename);
+						},
+						None => {
+							log_error!(self.logger, "There is no previous {}-second snapshot to fall back to, so none will be served", current_scope);
+						}
+					}
+					continue;
+				}
 
 				// persist the snapshot and update the symlink
 				let snapshot_filename = format!("snapshot__calculated-at:{}__range:{}-scope__previous-sync:{}.lngossip", reference_timestamp, current_scope, current_last_sync_timestamp);
 				let snapshot_path_v1 = format!("{}/{}", pending_snapshot_directory, snapshot_filename);
 				let snapshot_path_v2 = format!("{}/v2/{}", pending_snapshot_directory, snapshot_filename);
 				log_info!(self.logger, "Persisting {}-second snapshot: {} ({} messages, {} announcements, {} updates ({} full, {} incremental))", current_scope, snapshot_filename, snapshot_v1.message_count, snapshot_v1.channel_announcement_count, snapshot_v1.update_count, snapshot_v1.update_count_full, snapshot_v1.update_count_incremental);
-				fs::write(&snapshot_path_v1, snapshot_v1.data).unwrap();
-				fs::write(&snapshot_path_v2, snapshot_v2.data).unwrap();
+				self.write_file(&snapshot_path_v1, &snapshot_v1.data).context(format!("Failed to write snapshot {}", snapshot_path_v1))?;
+				self.write_file(&snapshot_path_v2, &snapshot_v2.data).context(format!("Failed to write snapshot {}", snapshot_path_v2))?;
+				self.write_checksum(&snapshot_path_v1, &snapshot_v1.data)?;
+				self.write_checksum(&snapshot_path_v2, &snapshot_v2.data)?;
+				if let Some(snapshot_extended) = &snapshot_extended {
+					let snapshot_path_extended = format!("{}{}", snapshot_path_v2, EXTENDED_EXTENSION);
+					self.write_file(&snapshot_path_extended, &snapshot_extended.data).context(format!("Failed to write snapshot {}", snapshot_path_extended))?;
+					self.write_checksum(&snapshot_path_extended, &snapshot_extended.data)?;
+					log_info!(self.logger, "Extended {}-second snapshot: {} bytes, {} bytes more than the standard one", current_scope, snapshot_extended.data.len(), snapshot_extended.data.len().saturating_sub(snapshot_v2.data.len()));
+				}
+				if self.brotli_enabled {
+					self.write_brotli_copy(&snapshot_path_v1, &snapshot_v1.data)?;
+					let compressed_size = self.write_brotli_copy(&snapshot_path_v2, &snapshot_v2.data)?;
+					log_info!(self.logger, "Brotli-compressed {}-second snapshot: {} bytes ({:.1}% of {} bytes)", current_scope, compressed_size, compressed_size as f64 * 100.0 / (size_bytes.max(1) as f64), size_bytes);
+				}
 				snapshot_filenames_by_scope.insert(current_scope.clone(), snapshot_filename);
+				published_snapshots.push(SnapshotFreshness { scope: *current_scope, reference_timestamp, latest_seen_timestamp: delta.latest_seen as u64 });
 			}
 		}
 
+		for report in &size_reports {
+			let size_change = match report.size_change_percent() {
+				Some(percent) => format!("{:+.1}% vs previous generation", percent),
+				None => "no previous generation".to_string(),
+			};
+			log_info!(self.logger, "Snapshot size for {}-second scope: {} bytes ({}){}", report.scope, report.size_bytes, size_change, if report.oversized { ", oversized" } else { "" });
+			log_info!(self.logger, "Lookups for {}-second scope: {} rows read in {} batches over {:?}", report.scope, report.lookup_load.rows_read, report.lookup_load.batches_fetched, report.lookup_load.wall_time);
+			if report.omitted_stale_channel_count > 0 {
+				log_info!(self.logger, "Omitted {} stale channels from {}-second scope", report.omitted_stale_channel_count, report.scope);
+			}
+			if report.damped_update_count > 0 {
+				log_info!(self.logger, "Damped {} fee updates in {}-second scope", report.damped_update_count, report.scope);
+			}
+		}
+		let damped_update_count: usize = size_reports.iter().map(|report| report.damped_update_count).sum();
+		if damped_update_count > 0 {
+			log_info!(self.logger, "Damped {} fee updates across all scopes", damped_update_count);
+		}
+		let htlc_maximum_substitution_count: usize = size_reports.iter().map(|report| report.htlc_maximum_substitution_count).sum();
+		if htlc_maximum_substitution_count > 0 {
+			log_info!(self.logger, "Substituted absent HTLC maximums of {} updates across all scopes", htlc_maximum_substitution_count);
+		}
+
+		self.report_directional_coverage(&directional_coverage);
+
+		if !clock.is_historical() {
+			self.record_coverage(reference_timestamp).await;
+		}
+
 		{
 			// create dummy symlink
 			let dummy_filename = "empty_delta.lngossip";
 			let dummy_snapshot = super::serialize_empty_blob(reference_timestamp);
 			let dummy_snapshot_path = format!("{}/{}", pending_snapshot_directory, dummy_filename);
-			fs::write(&dummy_snapshot_path, dummy_snapshot).unwrap();
+			self.write_file(&dummy_snapshot_path, &dummy_snapshot).context("Failed to write empty snapshot")?;
+			self.write_checksum(&dummy_snapshot_path, &dummy_snapshot)?;
+			if self.brotli_enabled {
+				self.write_brotli_copy(&dummy_snapshot_path, &dummy_snapshot)?;
+			}
 
 			let dummy_symlink_path = format!("{}/{}.bin", pending_symlink_directory, reference_timestamp);
 			let relative_dummy_snapshot_path = format!("{}/{}", relative_symlink_to_snapshot_path, dummy_filename);
 			log_info!(self.logger, "Symlinking dummy: {} -> {}", dummy_symlink_path, relative_dummy_snapshot_path);
-			symlink(&relative_dummy_snapshot_path, &dummy_symlink_path).unwrap();
+			self.create_symlinks(&relative_dummy_snapshot_path, &dummy_symlink_path)?;
 		}
 
 		// Number of intervals since Jan 1, 2022, a few months before RGS server was released.
@@ -163,30 +520,16 @@ impl<L: Deref + Clone> Snapshotter<L> where L::Target: Logger {
 				// special-case 0 to always refer to a full/initial sync
 				u64::MAX
 			} else {
-				/*
-				We have snapshots for 6-day- and 7-day-intervals, but the next interval is
-				14 days. So if somebody requests an update with a timestamp that is 10 days old,
-				there is no longer a snapshot for that specific interval.
-
-				The correct snapshot will be the next highest interval, i. e. for 14 days.
-
-				The `snapshot_sync_day_factors` array is sorted ascendingly, so find() will
-				return on the first iteration that is at least equal to the requested interval.
-
-				Note, however, that the last value in the array is u64::max, which means that
-				multiplying it with snapshot_interval will overflow. To avoid that, we use
-				saturating_mul.
-				 */
-
-				// find min(x) in snapshot_scopes where i * granularity <= x (the current scope)
-				snapshot_scopes.iter().find(|current_scope| {
-					i * granularity_interval <= **current_scope
-				}).unwrap().clone()
+				referenced_scope(snapshot_scopes, i * granularity_interval)
 			};
 			log_info!(self.logger, "i: {}, referenced scope: {}", i, referenced_scope);
 
+			let snapshot_filename = match snapshot_filenames_by_scope.get(&referenced_scope) {
+				Some(snapshot_filename) => snapshot_filename,
+				// the snapshot was oversized, and there was no previous one to fall back to
+				None => continue,
+			};
 			for (suffix, path_to_root) in suffixes {
-				let snapshot_filename = snapshot_filenames_by_scope.get(&referenced_scope).unwrap();
 				let relative_snapshot_path = format!("{}{}{}/{}", path_to_root, relative_symlink_to_snapshot_path, suffix, snapshot_filename);
 
 				let canonical_last_sync_timestamp = if i == 0 {
@@ -198,22 +541,292 @@ impl<L: Deref + Clone> Snapshotter<L> where L::Target: Logger {
 				let symlink_path = format!("{}{}/{}.bin", pending_symlink_directory, suffix, canonical_last_sync_timestamp);
 
 				log_info!(self.logger, "Symlinking: {} -> {} ({} -> {}", i, referenced_scope, symlink_path, relative_snapshot_path);
-				symlink(&relative_snapshot_path, &symlink_path).unwrap();
+				self.create_symlinks(&relative_snapshot_path, &symlink_path)?;
+
+				// only version 2 snapshots have extended variants, and retained ones may lack them
+				let pending_extended_path = format!("{}{}/{}{}", pending_snapshot_directory, suffix, snapshot_filename, EXTENDED_EXTENSION);
+				if self.extended_snapshots_enabled && fs::metadata(&pending_extended_path).is_ok() {
+					let relative_extended_path = format!("{}{}", relative_snapshot_path, EXTENDED_EXTENSION);
+					let extended_symlink_path = format!("{}{}", symlink_path, EXTENDED_EXTENSION);
+					self.create_extended_symlinks(&relative_extended_path, &extended_symlink_path)?;
+				}
 			}
 		}
 
 		let update_time_path = format!("{}/update_time.txt", pending_symlink_directory);
-		let update_time = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
-		fs::write(&update_time_path, format!("{}", update_time)).unwrap();
+		let update_time = clock.now();
+		self.write_file(&update_time_path, format!("{}", update_time).as_bytes()).context("Failed to write update time")?;
+
+		// the previous generation is about to be replaced, and only its full snapshot is needed, in
+		// the version LDK's client applies
+		let previous_full_snapshot = if self.chain_verification_enabled && !clock.is_historical() {
+			fs::read(format!("{}/0.bin", finalized_symlink_directory)).ok()
+		} else {
+			None
+		};
 
+		// regenerating a past generation leaves the archive of the served ones alone
+		let is_archiving = self.snapshot_archive_enabled && !clock.is_historical();
+		if is_archiving {
+			for (suffix, _) in suffixes {
+				self.archive_snapshots(&format!("{}{}", finalized_snapshot_directory, suffix), &format!("{}{}", archive_directory, suffix));
+			}
+		}
 		if fs::metadata(&finalized_snapshot_directory).is_ok() {
-			fs::remove_dir_all(&finalized_snapshot_directory).expect("Failed to remove finalized snapshot directory.");
+			fs::remove_dir_all(&finalized_snapshot_directory).context("Failed to remove finalized snapshot directory")?;
 		}
 		if fs::metadata(&finalized_symlink_directory).is_ok() {
-			fs::remove_dir_all(&finalized_symlink_directory).expect("Failed to remove pending symlink directory.");
+			fs::remove_dir_all(&finalized_symlink_directory).context("Failed to remove finalized symlink directory")?;
+		}
+		fs::rename(&pending_snapshot_directory, &finalized_snapshot_directory).context("Failed to finalize snapshot directory")?;
+		fs::rename(&pending_symlink_directory, &finalized_symlink_directory).context("Failed to finalize symlink directory")?;
+		if !clock.is_historical() {
+			self.freshness.record_publication(published_snapshots);
+		}
+
+		for (suffix, _) in suffixes {
+			if is_archiving {
+				self.enforce_retention_policy(&format!("{}{}", archive_directory, suffix));
+			}
+			self.remove_dangling_symlinks(&format!("{}{}", finalized_symlink_directory, suffix));
+		}
+
+		if let Some(previous_full_snapshot) = previous_full_snapshot {
+			self.verify_delta_chain(previous_full_snapshot, &finalized_symlink_directory, reference_timestamp).await;
+		}
+		Ok(GenerationReport { size_reports, directional_coverage })
+	}
+
+	/// Check that clients which applied the previous generation's full snapshot end up with the
+	/// newest full snapshot's channels once they apply the delta they'd be served next, alerting if
+	/// they don't. The generation is already published by then, so this only delays the next one.
+	async fn verify_delta_chain(&self, previous_full_snapshot: Vec<u8>, symlink_directory: &str, reference_timestamp: u64) {
+		let previous_latest_seen = match chain_check::latest_seen_timestamp(&previous_full_snapshot) {
+			Some(previous_latest_seen) => previous_latest_seen,
+			None => {
+				log_warn!(self.logger, "The previous full snapshot is malformed, so the delta chain can't be verified");
+				return;
+			}
+		};
+		// clients request the delta for the latest seen timestamp of the snapshot they applied last
+		let delta_path = format!("{}/{}.bin", symlink_directory, previous_latest_seen);
+		let full_snapshot_path = format!("{}/0.bin", symlink_directory);
+		let (delta, full_snapshot) = match (fs::read(&delta_path), fs::read(&full_snapshot_path)) {
+			(Ok(delta), Ok(full_snapshot)) => (delta, full_snapshot),
+			_ => {
+				log_warn!(self.logger, "There is no delta from {} to verify against the full snapshot at {}", previous_latest_seen, reference_timestamp);
+				return;
+			}
+		};
+
+		let fee_damping = config::fee_damping();
+		let verification = tokio::task::spawn_blocking(move || chain_check::verify_chain(&previous_full_snapshot, &delta, &full_snapshot, &fee_damping)).await;
+		let reason = match verification {
+			Ok(Ok(report)) if report.is_intact() => {
+				log_info!(self.logger, "The delta from {} chains onto the previous full snapshot: {} channels match the full snapshot at {} ({} lingering, {} damped fee differences)", previous_latest_seen, report.channel_count, reference_timestamp, report.lingering_channel_count, report.damped_fee_count);
+				return;
+			},
+			Ok(Ok(report)) => format!("{} of {} channels diverge: {}", report.divergences.len(), report.channel_count, report.describe_divergences()),
+			Ok(Err(error)) => error.to_string(),
+			Err(error) => format!("the verification panicked: {}", error),
+		};
+		log_error!(self.logger, "The delta from {} doesn't chain onto the previous full snapshot to match the full snapshot at {}: {}", previous_latest_seen, reference_timestamp, reason);
+		webhook::notify_chain_divergence(reference_timestamp, &reason, self.logger.clone()).await;
+	}
+
+	/// Log how many channels have updates in which directions, along with the change since the
+	/// previous round, warning if the number of bidirectional channels shifted sharply
+	fn report_directional_coverage(&self, directional_coverage: &DirectionalCoverage) {
+		let previous_coverage = self.previous_directional_coverage.lock().expect("directional coverage lock poisoned").replace(*directional_coverage);
+		let previous_coverage = match previous_coverage {
+			Some(previous_coverage) => previous_coverage,
+			None => {
+				log_info!(self.logger, "Directional coverage: {}", directional_coverage);
+				return;
+			}
+		};
+		let changes: Vec<String> = directional_coverage.values().iter().zip(previous_coverage.values().iter())
+			.map(|(count, previous_count)| format!("{:+}", *count as i64 - *previous_count as i64))
+			.collect();
+		log_info!(self.logger, "Directional coverage: {} (changes vs previous generation: {})", directional_coverage, changes.join(", "));
+		let bidirectional_change_percent = (directional_coverage.bidirectional as f64 - previous_coverage.bidirectional as f64) * 100.0 / previous_coverage.bidirectional.max(1) as f64;
+		if bidirectional_change_percent.abs() > DIRECTIONAL_COVERAGE_SHIFT_WARNING_PERCENT {
+			log_warn!(self.logger, "The number of bidirectional channels changed by {:+.1}% since the previous generation, from {} to {}", bidirectional_change_percent, previous_coverage.bidirectional, directional_coverage.bidirectional);
+		}
+	}
+
+	/// Record which gossip clients synced up to this generation's reference timestamp already have,
+	/// so that later deltas can leave out backfilled gossip that is older. Without it, those deltas
+	/// merely include more than necessary, so failing to record it isn't fatal.
+	async fn record_coverage(&self, reference_timestamp: u64) {
+		let result = async {
+			let client = crate::connect_to_db().await?;
+			persistence::record_snapshot_coverage(&client, &Tables::from_config(), reference_timestamp).await
+		}.await;
+		match result {
+			Ok(coverage) => log_info!(self.logger, "Snapshots at {} cover channel updates up to {}", reference_timestamp, coverage),
+			Err(error) => log_warn!(self.logger, "Failed to record the coverage of snapshots at {}: {}", reference_timestamp, error),
+		}
+	}
+
+	/// Write a Brotli-compressed copy of a snapshot next to it, returning the compressed size
+	fn write_brotli_copy(&self, snapshot_path: &str, data: &[u8]) -> Result<u64, ProcessorError> {
+		let compressed_path = format!("{}{}", snapshot_path, BROTLI_EXTENSION);
+		let compressed = compress_brotli(data);
+		self.write_file(&compressed_path, &compressed).context(format!("Failed to write snapshot {}", compressed_path))?;
+		Ok(compressed.len() as u64)
+	}
+
+	/// Write a file and wait for its contents to reach the disk, as running out of space may
+	/// otherwise only surface once the file is closed, leaving it truncated without an error
+	fn write_file(&self, path: &str, data: &[u8]) -> io::Result<()> {
+		#[cfg(test)]
+		if let Some(write_budget) = self.write_budget.lock().unwrap().as_mut() {
+			if data.len() as u64 > *write_budget {
+				return Err(io::Error::from_raw_os_error(28)); // ENOSPC
+			}
+			*write_budget -= data.len() as u64;
+		}
+		let mut file = File::create(path)?;
+		file.write_all(data)?;
+		file.sync_all()
+	}
+
+	/// Write the checksum of a snapshot next to it, for health checks to verify downloads against
+	fn write_checksum(&self, snapshot_path: &str, data: &[u8]) -> Result<(), ProcessorError> {
+		let checksum_path = format!("{}{}", snapshot_path, CHECKSUM_EXTENSION);
+		self.write_file(&checksum_path, format!("{}\n", Sha256::hash(data)).as_bytes()).context(format!("Failed to write checksum {}", checksum_path))
+	}
+
+	/// Symlink a snapshot and its checksum, and, if enabled, its Brotli-compressed copy, which web
+	/// servers serving precompressed files look for under the symlink's name with the extension
+	/// appended
+	fn create_symlinks(&self, relative_snapshot_path: &str, symlink_path: &str) -> Result<(), ProcessorError> {
+		symlink(relative_snapshot_path, symlink_path).context(format!("Failed to create symlink {}", symlink_path))?;
+		let checksum_symlink_path = format!("{}{}", symlink_path, CHECKSUM_EXTENSION);
+		let relative_checksum_path = format!("{}{}", relative_snapshot_path, CHECKSUM_EXTENSION);
+		symlink(&relative_checksum_path, &checksum_symlink_path).context(format!("Failed to create symlink {}", checksum_symlink_path))?;
+		if self.brotli_enabled {
+			let compressed_symlink_path = format!("{}{}", symlink_path, BROTLI_EXTENSION);
+			let relative_compressed_path = format!("{}{}", relative_snapshot_path, BROTLI_EXTENSION);
+			symlink(&relative_compressed_path, &compressed_symlink_path).context(format!("Failed to create symlink {}", compressed_symlink_path))?;
+		}
+		Ok(())
+	}
+
+	/// Symlink an extended snapshot and its checksum, which have no Brotli-compressed copies
+	fn create_extended_symlinks(&self, relative_extended_path: &str, symlink_path: &str) -> Result<(), ProcessorError> {
+		symlink(relative_extended_path, symlink_path).context(format!("Failed to create symlink {}", symlink_path))?;
+		let checksum_symlink_path = format!("{}{}", symlink_path, CHECKSUM_EXTENSION);
+		let relative_checksum_path = format!("{}{}", relative_extended_path, CHECKSUM_EXTENSION);
+		symlink(&relative_checksum_path, &checksum_symlink_path).context(format!("Failed to create symlink {}", checksum_symlink_path))?;
+		Ok(())
+	}
+
+	/// Append an oversized snapshot to the log in the stats directory, so that such events can be
+	/// reviewed after the fact
+	fn record_oversized_snapshot(&self, cache_path: &str, reference_timestamp: u64, scope: u64, size_bytes: u64) {
+		let stats_directory = format!("{}/stats", cache_path);
+		let entry = format!("{{\"calculated_at\":{},\"scope\":{},\"size_bytes\":{},\"max_bytes\":{}}}\n", reference_timestamp, scope, size_bytes, self.max_blob_bytes);
+		let result = fs::create_dir_all(&stats_directory).and_then(|_| {
+			let mut file = fs::OpenOptions::new().create(true).append(true).open(format!("{}/oversized_snapshots.jsonl", stats_directory))?;
+			file.write_all(entry.as_bytes())
+		});
+		if let Err(error) = result {
+			log_warn!(self.logger, "Failed to record oversized snapshot: {}", error);
+		}
+	}
+
+	/// Move the files of a superseded generation's snapshot directory to the archive. Renaming keeps
+	/// their modification times, which the retention policy goes by. Failures only cost the
+	/// archive some files, so they don't fail the generation.
+	fn archive_snapshots(&self, snapshot_directory: &str, archive_directory: &str) {
+		let entries = match fs::read_dir(snapshot_directory) {
+			Ok(entries) => entries,
+			// there is no superseded generation before the first one
+			Err(_) => return,
+		};
+		if let Err(error) = fs::create_dir_all(archive_directory) {
+			log_warn!(self.logger, "Failed to create snapshot archive directory {}: {}", archive_directory, error);
+			return;
+		}
+		for entry in entries.flatten() {
+			if !entry.file_type().map_or(false, |file_type| file_type.is_file()) {
+				continue;
+			}
+			let archive_path = Path::new(archive_directory).join(entry.file_name());
+			if let Err(error) = fs::rename(entry.path(), &archive_path) {
+				log_warn!(self.logger, "Failed to archive snapshot file {}: {}", entry.path().display(), error);
+			}
+		}
+	}
+
+	/// Delete the oldest archived snapshot files that violate the retention policy
+	fn enforce_retention_policy(&self, snapshot_directory: &str) {
+		let entries = match fs::read_dir(snapshot_directory) {
+			Ok(entries) => entries,
+			Err(error) => {
+				log_warn!(self.logger, "Failed to read snapshot directory {}: {}", snapshot_directory, error);
+				return;
+			}
+		};
+
+		let mut snapshot_files = Vec::new();
+		for entry in entries.flatten() {
+			let metadata = match entry.metadata() {
+				Ok(metadata) => metadata,
+				Err(_) => continue,
+			};
+			if !metadata.is_file() {
+				continue;
+			}
+			if let Ok(modified) = metadata.modified() {
+				snapshot_files.push((modified, entry.path()));
+			}
+		}
+		// oldest first
+		snapshot_files.sort();
+
+		let max_age = Duration::from_secs(self.retention_policy.max_age_days as u64 * 24 * 3600);
+		let mut remaining_file_count = snapshot_files.len();
+		for (modified, path) in snapshot_files {
+			let is_expired = modified.elapsed().map_or(false, |age| age > max_age);
+			let is_excess = remaining_file_count > self.retention_policy.max_files;
+			let is_disk_full = available_disk_space(snapshot_directory)
+				.map_or(false, |available_bytes| available_bytes < self.retention_policy.min_free_bytes);
+			if !is_expired && !is_excess && !is_disk_full {
+				// the files are sorted oldest first, so none of the newer ones violate the policy
+				break;
+			}
+
+			match fs::remove_file(&path) {
+				Ok(()) => {
+					remaining_file_count -= 1;
+					log_info!(self.logger, "Deleted snapshot file {} (expired: {}, excess: {}, disk full: {})", path.display(), is_expired, is_excess, is_disk_full);
+				},
+				Err(error) => {
+					log_warn!(self.logger, "Failed to delete snapshot file {}: {}", path.display(), error);
+				}
+			}
+		}
+	}
+
+	fn remove_dangling_symlinks(&self, symlink_directory: &str) {
+		let entries = match fs::read_dir(symlink_directory) {
+			Ok(entries) => entries,
+			Err(_) => return,
+		};
+		for entry in entries.flatten() {
+			let path = entry.path();
+			let is_symlink = fs::symlink_metadata(&path).map_or(false, |metadata| metadata.file_type().is_symlink());
+			// fs::metadata follows the symlink, and fails if its target no longer exists
+			if is_symlink && fs::metadata(&path).is_err() {
+				match fs::remove_file(&path) {
+					Ok(()) => log_info!(self.logger, "Deleted dangling symlink {}", path.display()),
+					Err(error) => log_warn!(self.logger, "Failed to delete dangling symlink {}: {}", path.display(), error),
+				}
+			}
 		}
-		fs::rename(&pending_snapshot_directory, &finalized_snapshot_directory).expect("Failed to finalize snapshot directory.");
-		fs::rename(&pending_symlink_directory, &finalized_symlink_directory).expect("Failed to finalize symlink directory.");
 	}
 
 	pub(super) fn round_down_to_nearest_multiple(number: u64, multiple: u64) -> u64 {
@@ -221,3 +834,135 @@ impl<L: Deref + Clone> Snapshotter<L> where L::Target: Logger {
 		number - round_multiple_delta
 	}
 }
+
+/// The name of the most recent snapshot file for `scope` in the given snapshot directory
+fn previous_snapshot_filename(snapshot_directory: &str, scope: u64) -> Option<String> {
+	let scope_infix = format!("__range:{}-scope__", scope);
+	fs::read_dir(snapshot_directory).ok()?
+		.flatten()
+		.filter_map(|entry| entry.file_name().into_string().ok())
+		.filter(|filename| filename.starts_with("snapshot__calculated-at:") && filename.contains(&scope_infix))
+		// compressed copies, checksums, and extended variants share the snapshot's name
+		.filter(|filename| !filename.ends_with(BROTLI_EXTENSION) && !filename.ends_with(CHECKSUM_EXTENSION) && !filename.ends_with(EXTENDED_EXTENSION))
+		// the calculation timestamps all have the same number of digits, so they sort lexicographically
+		.max()
+}
+
+fn compress_brotli(data: &[u8]) -> Vec<u8> {
+	let mut compressor = brotli::CompressorWriter::new(Vec::new(), 4096, BROTLI_QUALITY, BROTLI_WINDOW_BITS);
+	compressor.write_all(data).expect("writing to a vector can't fail");
+	// finishes the stream
+	compressor.into_inner()
+}
+
+/// The disk space available to the file system `path` resides on, if it can be determined
+pub(crate) fn available_disk_space(path: &str) -> Option<u64> {
+	let path = fs::canonicalize(path).ok()?;
+	let disks = Disks::new_with_refreshed_list();
+	disks.list().iter()
+		.filter(|disk| path.starts_with(disk.mount_point()))
+		.max_by_key(|disk| disk.mount_point().as_os_str().len())
+		.map(|disk| disk.available_space())
+}
+
+/// The scopes to generate snapshots for: the fine tier, which doubles from the snapshot interval
+/// up to the maximum snapshot scope, followed by the full snapshot. Scopes beyond
+/// `full_snapshot_age` are left out, as clients that haven't synced for that long are served the
+/// full snapshot instead, which is barely larger.
+pub(crate) fn tiered_snapshot_scopes(snapshot_interval: u64, full_snapshot_age: Option<u64>) -> Vec<u64> {
+	let mut snapshot_scopes = vec![];
+	let mut current_scope = snapshot_interval;
+	while full_snapshot_age.map_or(true, |full_snapshot_age| current_scope <= full_snapshot_age) {
+		snapshot_scopes.push(current_scope);
+		if current_scope >= config::MAX_SNAPSHOT_SCOPE as u64 {
+			break;
+		}
+		// double the current factor
+		current_scope <<= 1;
+	}
+	snapshot_scopes.push(u64::MAX);
+	snapshot_scopes
+}
+
+/// The scope of the snapshot serving clients that last synced `lookback` seconds before the
+/// reference timestamp
+pub(crate) fn referenced_scope(snapshot_scopes: &[u64], lookback: u64) -> u64 {
+	/*
+	We have snapshots for 6-day- and 7-day-intervals, but the next interval is
+	14 days. So if somebody requests an update with a timestamp that is 10 days old,
+	there is no longer a snapshot for that specific interval.
+
+	The correct snapshot will be the next highest interval, i. e. for 14 days.
+
+	The `snapshot_scopes` array is sorted ascendingly, so find() will return on the first
+	iteration that is at least equal to the requested interval. Lookbacks beyond the fine tier
+	thus end up with the full snapshot, u64::MAX, which is always last.
+	 */
+
+	// find min(x) in snapshot_scopes where lookback <= x (the current scope)
+	*snapshot_scopes.iter().find(|current_scope| {
+		lookback <= **current_scope
+	}).expect("snapshot scopes end with u64::MAX, which covers any interval")
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_tiered_snapshot_scopes() {
+		const HOUR: u64 = 3600;
+		const DAY: u64 = 24 * HOUR;
+		let untiered_scopes = tiered_snapshot_scopes(3 * HOUR, None);
+		assert_eq!(untiered_scopes, vec![3 * HOUR, 6 * HOUR, 12 * HOUR, DAY, 2 * DAY, 4 * DAY, 8 * DAY, 16 * DAY, 32 * DAY, u64::MAX]);
+		assert_eq!(referenced_scope(&untiered_scopes, 20 * DAY), 32 * DAY);
+
+		let tiered_scopes = tiered_snapshot_scopes(3 * HOUR, Some(8 * DAY));
+		assert_eq!(tiered_scopes, vec![3 * HOUR, 6 * HOUR, 12 * HOUR, DAY, 2 * DAY, 4 * DAY, 8 * DAY, u64::MAX]);
+		assert_eq!(referenced_scope(&tiered_scopes, 3 * HOUR), 3 * HOUR);
+		assert_eq!(referenced_scope(&tiered_scopes, 3 * HOUR + 1), 6 * HOUR);
+		// the last fine-grained scope still covers lookbacks up to the cutoff
+		assert_eq!(referenced_scope(&tiered_scopes, 8 * DAY), 8 * DAY);
+		assert_eq!(referenced_scope(&tiered_scopes, 8 * DAY + 3 * HOUR), u64::MAX);
+		assert_eq!(referenced_scope(&tiered_scopes, 400 * DAY), u64::MAX);
+
+		// a cutoff between two scopes drops the larger one
+		assert!(tiered_snapshot_scopes(3 * HOUR, Some(30 * DAY)).ends_with(&[16 * DAY, u64::MAX]));
+		// a cutoff below the snapshot interval leaves only the full snapshot
+		assert_eq!(tiered_snapshot_scopes(3 * HOUR, Some(HOUR)), vec![u64::MAX]);
+	}
+
+	#[test]
+	fn test_size_change_percent() {
+		let report = |size_bytes: u64, previous_size_bytes: Option<u64>| SnapshotSizeReport { scope: 10800, size_bytes, previous_size_bytes, oversized: false, lookup_load: LookupLoad::default(), omitted_stale_channel_count: 0, damped_update_count: 0, htlc_maximum_substitution_count: 0 };
+		assert_eq!(report(1500, Some(1000)).size_change_percent(), Some(50.0));
+		assert_eq!(report(900, Some(1200)).size_change_percent(), Some(-25.0));
+		assert_eq!(report(900, Some(0)).size_change_percent(), None);
+		assert_eq!(report(900, None).size_change_percent(), None);
+	}
+
+	#[test]
+	fn test_brotli_round_trip() {
+		let data: Vec<u8> = (0..10_000u32).flat_map(|index| (index % 97).to_be_bytes()).collect();
+		let compressed = compress_brotli(&data);
+		assert!(compressed.len() < data.len());
+
+		let mut decompressed = Vec::new();
+		std::io::Read::read_to_end(&mut brotli::Decompressor::new(&compressed[..], 4096), &mut decompressed).unwrap();
+		assert_eq!(decompressed, data);
+	}
+
+	#[test]
+	fn test_empty_snapshot_is_applied_by_clients() {
+		let current_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs();
+		let empty_snapshot = crate::serialize_empty_blob(current_timestamp);
+		assert_eq!(&empty_snapshot[..4], &[76, 68, 75, 1]);
+
+		let logger = Arc::new(crate::types::tests::TestLogger::new());
+		let client_graph = NetworkGraph::new(config::network(), logger.clone());
+		let rgs = lightning_rapid_gossip_sync::RapidGossipSync::new(&client_graph, logger);
+		let latest_seen = rgs.update_network_graph_no_std(&empty_snapshot, Some(current_timestamp)).unwrap();
+		assert_eq!(latest_seen as u64, current_timestamp - current_timestamp % config::SYMLINK_GRANULARITY_INTERVAL as u64);
+		assert_eq!(client_graph.read_only().channels().len(), 0);
+	}
+}
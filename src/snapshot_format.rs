@@ -0,0 +1,181 @@
+//! A description of the snapshot wire format.
+//!
+//! The types in this module are never instantiated. They only exist to document, field by field
+//! and in wire order, what [`crate::serialize_delta`] writes. The build script turns their doc
+//! comments into the Markdown returned by [`SnapshotFormat::describe`], so any change to the
+//! serialization must be reflected here.
+//!
+//! All integers are big-endian. `BigSize` is the variable-length integer encoding from BOLT 1.
+#![allow(dead_code)]
+
+use bitcoin::blockdata::constants::ChainHash;
+use lightning::ln::features::{ChannelFeatures, NodeFeatures};
+use lightning::ln::msgs::SocketAddress;
+use lightning::util::ser::BigSize;
+
+/// The most recent snapshot serialization version
+pub(crate) const LATEST_SNAPSHOT_VERSION: u8 = 2;
+
+/// A complete snapshot, consisting of a header, the node section, the channel announcement
+/// section, and the channel update section. There is no trailer: the snapshot ends after the
+/// last channel update.
+#[repr(C)]
+pub(crate) struct SnapshotFormat {
+	/// See [`Header`]
+	header: Header,
+	/// See [`NodeSection`]
+	nodes: NodeSection,
+	/// See [`AnnouncementSection`]
+	announcements: AnnouncementSection,
+	/// See [`UpdateSection`]
+	updates: UpdateSection,
+}
+
+/// The fixed-size header at the start of every snapshot
+#[repr(C)]
+pub(crate) struct Header {
+	/// The ASCII bytes `LDK`
+	prefix: [u8; 3],
+	/// The serialization version, currently either 1 or 2
+	version: u8,
+	/// The chain hash of the network the snapshot is for
+	chain_hash: ChainHash,
+	/// The latest `seen` timestamp of any included message, rounded down to the snapshot
+	/// interval. Clients pass it as their last sync timestamp on the next request.
+	latest_seen: u32,
+}
+
+/// All node IDs referenced by channel announcements, plus (from version 2 onwards) any nodes
+/// whose announcement details changed
+#[repr(C)]
+pub(crate) struct NodeSection {
+	/// Version 2 and later only: the number of default feature sets that follow, at most 6
+	default_feature_count: u8,
+	/// Version 2 and later only: the most common feature sets among changed nodes, each prefixed
+	/// by its u16 length. Nodes reference them by their 1-based index.
+	default_features: Vec<NodeFeatures>,
+	/// The number of node entries that follow
+	node_count: u32,
+	/// See [`NodeEntry`]. Channel announcements reference nodes by their index in this list.
+	node_entries: Vec<NodeEntry>,
+}
+
+/// A single node
+#[repr(C)]
+pub(crate) struct NodeEntry {
+	/// The compressed public key. Version 1 sets the first byte to 2 or 3 as usual. Version 2
+	/// uses its upper bits as flags: bit 7 means extra data follows (a u16 length and that many
+	/// bytes), bits 5-3 hold the index of the node's new features among the defaults (0: no
+	/// change, 7: the features follow explicitly), and bit 2 means its addresses changed. Bits 1
+	/// and 0 retain the key's parity prefix.
+	node_id: [u8; 33],
+	/// Present if bit 2 is set: the number of addresses that follow
+	address_count: u8,
+	/// Present if bit 2 is set: each address prefixed by its u8 length
+	addresses: Vec<SocketAddress>,
+	/// Present if bits 5-3 are all set: the node's features, prefixed by their u16 length
+	features: NodeFeatures,
+}
+
+/// Channel announcements, in ascending short channel ID order
+#[repr(C)]
+pub(crate) struct AnnouncementSection {
+	/// The number of announcements that follow
+	announcement_count: u32,
+	/// See [`ChannelAnnouncementEntry`]
+	announcements: Vec<ChannelAnnouncementEntry>,
+}
+
+/// A channel announcement stripped of signatures and bitcoin keys
+#[repr(C)]
+pub(crate) struct ChannelAnnouncementEntry {
+	/// The channel features, prefixed by their u16 length
+	features: ChannelFeatures,
+	/// The difference between this short channel ID and the previous announcement's (or zero)
+	short_channel_id_delta: BigSize,
+	/// The index of the first node in the node section. In version 2, bit 63 means additional
+	/// data follows the second index.
+	node_id_1_index: BigSize,
+	/// The index of the second node in the node section
+	node_id_2_index: BigSize,
+	/// Present if bit 63 of the first index is set: the length of the additional data
+	additional_data_length: u16,
+	/// Present if bit 63 of the first index is set: a TLV stream, which may hold the channel's
+	/// capacity in satoshis as a u64 record of type 1. Clients skip records they don't know. The
+	/// server only writes it in extended snapshots, which wrap a version 2 snapshot in a header.
+	additional_data: Vec<u8>,
+}
+
+/// Channel updates, in ascending short channel ID order
+#[repr(C)]
+pub(crate) struct UpdateSection {
+	/// The number of updates that follow
+	update_count: u32,
+	/// Omitted if there are no updates. Full updates only include values that differ from these.
+	defaults: UpdateDefaults,
+	/// See [`ChannelUpdateEntry`]
+	updates: Vec<ChannelUpdateEntry>,
+}
+
+/// The most common value of each field among full updates
+#[repr(C)]
+pub(crate) struct UpdateDefaults {
+	/// The default CLTV expiry delta
+	cltv_expiry_delta: u16,
+	/// The default HTLC minimum in millisatoshis
+	htlc_minimum_msat: u64,
+	/// The default base fee in millisatoshis
+	fee_base_msat: u32,
+	/// The default proportional fee in millionths
+	fee_proportional_millionths: u32,
+	/// The default HTLC maximum in millisatoshis
+	htlc_maximum_msat: u64,
+}
+
+/// A channel update with all unchanged or default fields omitted
+#[repr(C)]
+pub(crate) struct ChannelUpdateEntry {
+	/// The difference between this short channel ID and the previous update's (or zero)
+	short_channel_id_delta: BigSize,
+	/// Bit 7: the update is incremental, i. e. omitted fields are unchanged rather than defaults.
+	/// Bits 6-2: the CLTV expiry delta, HTLC minimum, base fee, proportional fee, and HTLC maximum
+	/// follow, respectively. Bits 1-0: the update's channel flags (disabled, direction). An
+	/// incremental update with no fields is a reminder that the channel is still alive.
+	flags: u8,
+	/// Present if bit 6 is set
+	cltv_expiry_delta: u16,
+	/// Present if bit 5 is set
+	htlc_minimum_msat: u64,
+	/// Present if bit 4 is set
+	fee_base_msat: u32,
+	/// Present if bit 3 is set
+	fee_proportional_millionths: u32,
+	/// Present if bit 2 is set
+	htlc_maximum_msat: u64,
+}
+
+impl SnapshotFormat {
+	pub(crate) fn schema_version() -> u8 {
+		LATEST_SNAPSHOT_VERSION
+	}
+
+	/// A Markdown description of the format, generated from this module at build time
+	pub(crate) fn describe() -> &'static str {
+		include_str!(concat!(env!("OUT_DIR"), "/snapshot_format.md"))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_format_description() {
+		let description = SnapshotFormat::describe();
+		for section in ["SnapshotFormat", "Header", "NodeSection", "NodeEntry", "AnnouncementSection", "ChannelAnnouncementEntry", "UpdateSection", "UpdateDefaults", "ChannelUpdateEntry"] {
+			assert!(description.contains(&format!("## {}\n", section)), "missing section {}", section);
+		}
+		assert!(description.contains("| `latest_seen` | `u32` |"));
+		assert!(description.find("`prefix`").unwrap() < description.find("`chain_hash`").unwrap());
+	}
+}
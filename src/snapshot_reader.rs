@@ -0,0 +1,549 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io::Cursor;
+
+use bitcoin::blockdata::constants::ChainHash;
+use hex_conservative::DisplayHex;
+use lightning::ln::features::{ChannelFeatures, NodeFeatures};
+use lightning::util::ser::{BigSize, Readable};
+
+use crate::GOSSIP_PREFIX;
+use crate::scid::ShortChannelId;
+use crate::serialization::{ANNOUNCEMENT_ADDITIONAL_DATA_FLAG, CAPACITY_TLV_TYPE, EXTENDED_SNAPSHOT_MAGIC, EXTENDED_SNAPSHOT_VERSION, ExtendedHeaderTotals};
+use crate::snapshot_format::LATEST_SNAPSHOT_VERSION;
+
+/// How many of the largest contributors a summary lists
+const LARGEST_CONTRIBUTOR_COUNT: usize = 10;
+
+/// Where and why a snapshot failed to parse
+#[derive(Debug, PartialEq)]
+pub(crate) struct SnapshotParseError {
+	/// The offset of the first byte of the field that couldn't be read
+	pub(crate) offset: u64,
+	pub(crate) reason: String,
+}
+
+/// How many full updates left each field to the defaults
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct DefaultedFields {
+	pub(crate) cltv_expiry_delta: u32,
+	pub(crate) htlc_minimum_msat: u32,
+	pub(crate) fee_base_msat: u32,
+	pub(crate) fee_proportional_millionths: u32,
+	pub(crate) htlc_maximum_msat: u32,
+}
+
+/// Something a snapshot's bytes are attributed to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub(crate) enum Contributor {
+	/// A node entry, by its public key
+	Node([u8; 33]),
+	/// A channel's announcement and updates, by its short channel id
+	Channel(u64),
+}
+
+impl fmt::Display for Contributor {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		match self {
+			Contributor::Node(node_id) => write!(f, "node {}", node_id.as_hex()),
+			Contributor::Channel(short_channel_id) => write!(f, "channel {}", ShortChannelId::from(*short_channel_id)),
+		}
+	}
+}
+
+/// What a snapshot contains, as read back from its serialization
+#[derive(Debug, PartialEq)]
+pub(crate) struct SnapshotSummary {
+	pub(crate) version: u8,
+	/// The version of the extension header, for extended snapshots
+	pub(crate) extension_version: Option<u8>,
+	/// What the extension header sums up, since extension version 2
+	pub(crate) header_totals: Option<ExtendedHeaderTotals>,
+	pub(crate) chain_hash: ChainHash,
+	/// When the snapshot was calculated, which only its file name records
+	pub(crate) reference_timestamp: Option<u64>,
+	pub(crate) latest_seen: u32,
+	pub(crate) size_bytes: u64,
+	pub(crate) node_count: u32,
+	/// Version 2 only: the number of default feature sets
+	pub(crate) default_feature_count: u8,
+	/// Version 2 only: the nodes whose features changed
+	pub(crate) node_feature_update_count: u32,
+	/// Version 2 only: the nodes whose addresses changed
+	pub(crate) node_address_update_count: u32,
+	pub(crate) announcement_count: u32,
+	/// Version 2 only: the capacities announcements carried, by short channel id, ascending
+	pub(crate) channel_capacities: Vec<(u64, u64)>,
+	pub(crate) full_update_count: u32,
+	/// Includes reminders
+	pub(crate) incremental_update_count: u32,
+	/// Incremental updates without any fields
+	pub(crate) reminder_count: u32,
+	pub(crate) defaulted_fields: DefaultedFields,
+	/// The contributors of the most bytes, largest first
+	pub(crate) largest_contributors: Vec<(Contributor, u64)>,
+}
+
+/// A cursor that reports the offset of whatever it fails to read
+struct SnapshotCursor<'a> {
+	cursor: Cursor<&'a [u8]>,
+}
+
+impl SnapshotCursor<'_> {
+	fn position(&self) -> u64 {
+		self.cursor.position()
+	}
+
+	fn read<T: Readable>(&mut self, field: &str) -> Result<T, SnapshotParseError> {
+		let offset = self.position();
+		T::read(&mut self.cursor).map_err(|error| SnapshotParseError { offset, reason: format!("failed to read {}: {:?}", field, error) })
+	}
+
+	fn skip(&mut self, length: u64, field: &str) -> Result<(), SnapshotParseError> {
+		let offset = self.position();
+		if offset.saturating_add(length) > self.cursor.get_ref().len() as u64 {
+			return Err(SnapshotParseError { offset, reason: format!("{} is {} bytes long, but the snapshot ends first", field, length) });
+		}
+		self.cursor.set_position(offset + length);
+		Ok(())
+	}
+}
+
+/// Parse a snapshot the way clients do, tallying up what it contains
+pub(crate) fn parse_snapshot(data: &[u8]) -> Result<SnapshotSummary, SnapshotParseError> {
+	let mut cursor = SnapshotCursor { cursor: Cursor::new(data) };
+	let mut contributions: HashMap<Contributor, u64> = HashMap::new();
+
+	let prefix: [u8; 3] = cursor.read("prefix")?;
+	if prefix != GOSSIP_PREFIX {
+		return Err(SnapshotParseError { offset: 0, reason: format!("unexpected prefix {}", prefix.as_hex()) });
+	}
+	let version: u8 = cursor.read("version")?;
+	if version == 0 || version > LATEST_SNAPSHOT_VERSION {
+		return Err(SnapshotParseError { offset: 3, reason: format!("unknown version {}", version) });
+	}
+	let chain_hash: ChainHash = cursor.read("chain hash")?;
+	let latest_seen: u32 = cursor.read("latest seen timestamp")?;
+
+	let mut default_feature_count = 0;
+	if version >= 2 {
+		default_feature_count = cursor.read("default feature count")?;
+		for _ in 0..default_feature_count {
+			let _: NodeFeatures = cursor.read("default features")?;
+		}
+	}
+
+	let node_count: u32 = cursor.read("node count")?;
+	let mut node_ids = Vec::with_capacity(node_count.min(1 << 16) as usize);
+	let mut node_feature_update_count = 0;
+	let mut node_address_update_count = 0;
+	for _ in 0..node_count {
+		let entry_start = cursor.position();
+		let mut node_id: [u8; 33] = cursor.read("node id")?;
+		if version >= 2 {
+			let flags = node_id[0];
+			node_id[0] &= 0b11;
+			if flags & (1 << 2) != 0 {
+				node_address_update_count += 1;
+				let address_count: u8 = cursor.read("address count")?;
+				for _ in 0..address_count {
+					let address_length: u8 = cursor.read("address length")?;
+					cursor.skip(address_length as u64, "address")?;
+				}
+			}
+			let feature_index = (flags >> 3) & 0b111;
+			if feature_index != 0 {
+				node_feature_update_count += 1;
+				if feature_index == 0b111 {
+					let _: NodeFeatures = cursor.read("node features")?;
+				} else if feature_index > default_feature_count {
+					return Err(SnapshotParseError { offset: entry_start, reason: format!("node refers to default feature set {} of {}", feature_index, default_feature_count) });
+				}
+			}
+			if flags & (1 << 7) != 0 {
+				let extra_data_length: u16 = cursor.read("extra data length")?;
+				cursor.skip(extra_data_length as u64, "extra data")?;
+			}
+		}
+		if node_id[0] != 2 && node_id[0] != 3 {
+			return Err(SnapshotParseError { offset: entry_start, reason: format!("invalid public key prefix {}", node_id[0]) });
+		}
+		*contributions.entry(Contributor::Node(node_id)).or_default() += cursor.position() - entry_start;
+		node_ids.push(node_id);
+	}
+
+	let announcement_count: u32 = cursor.read("announcement count")?;
+	let mut channel_capacities = Vec::new();
+	let mut short_channel_id = 0u64;
+	for _ in 0..announcement_count {
+		let entry_start = cursor.position();
+		let _: ChannelFeatures = cursor.read("channel features")?;
+		let short_channel_id_delta: BigSize = cursor.read("short channel id delta")?;
+		short_channel_id = short_channel_id.checked_add(short_channel_id_delta.0)
+			.ok_or_else(|| SnapshotParseError { offset: entry_start, reason: "short channel id overflows".to_string() })?;
+		let mut has_additional_data = false;
+		for index_position in 0..2 {
+			let index_offset = cursor.position();
+			let mut node_index: BigSize = cursor.read("node index")?;
+			if version >= 2 && index_position == 0 {
+				has_additional_data = node_index.0 & ANNOUNCEMENT_ADDITIONAL_DATA_FLAG != 0;
+				node_index.0 &= !ANNOUNCEMENT_ADDITIONAL_DATA_FLAG;
+			}
+			if node_index.0 >= node_ids.len() as u64 {
+				return Err(SnapshotParseError { offset: index_offset, reason: format!("node index {} is out of bounds for {} nodes", node_index.0, node_ids.len()) });
+			}
+		}
+		if has_additional_data {
+			let additional_data_length: u16 = cursor.read("announcement additional data length")?;
+			let additional_data_start = cursor.position();
+			cursor.skip(additional_data_length as u64, "announcement additional data")?;
+			let additional_data = &data[additional_data_start as usize..cursor.position() as usize];
+			if let Some(capacity_sats) = read_capacity_record(additional_data, additional_data_start)? {
+				channel_capacities.push((short_channel_id, capacity_sats));
+			}
+		}
+		*contributions.entry(Contributor::Channel(short_channel_id)).or_default() += cursor.position() - entry_start;
+	}
+
+	let update_count: u32 = cursor.read("update count")?;
+	if update_count > 0 {
+		let _: u16 = cursor.read("default CLTV expiry delta")?;
+		let _: u64 = cursor.read("default HTLC minimum")?;
+		let _: u32 = cursor.read("default base fee")?;
+		let _: u32 = cursor.read("default proportional fee")?;
+		let _: u64 = cursor.read("default HTLC maximum")?;
+	}
+	let mut full_update_count = 0;
+	let mut incremental_update_count = 0;
+	let mut reminder_count = 0;
+	let mut defaulted_fields = DefaultedFields::default();
+	let mut short_channel_id = 0u64;
+	for _ in 0..update_count {
+		let entry_start = cursor.position();
+		let short_channel_id_delta: BigSize = cursor.read("short channel id delta")?;
+		short_channel_id = short_channel_id.checked_add(short_channel_id_delta.0)
+			.ok_or_else(|| SnapshotParseError { offset: entry_start, reason: "short channel id overflows".to_string() })?;
+		let flags: u8 = cursor.read("update flags")?;
+		let is_incremental = flags & 0b_1000_0000 != 0;
+		if is_incremental {
+			incremental_update_count += 1;
+			if flags & 0b_0111_1100 == 0 {
+				reminder_count += 1;
+			}
+		} else {
+			full_update_count += 1;
+		}
+
+		let fields: [(u8, &mut u32, u64, &str); 5] = [
+			(0b_0100_0000, &mut defaulted_fields.cltv_expiry_delta, 2, "CLTV expiry delta"),
+			(0b_0010_0000, &mut defaulted_fields.htlc_minimum_msat, 8, "HTLC minimum"),
+			(0b_0001_0000, &mut defaulted_fields.fee_base_msat, 4, "base fee"),
+			(0b_0000_1000, &mut defaulted_fields.fee_proportional_millionths, 4, "proportional fee"),
+			(0b_0000_0100, &mut defaulted_fields.htlc_maximum_msat, 8, "HTLC maximum"),
+		];
+		for (bit, defaulted_count, length, field) in fields {
+			if flags & bit != 0 {
+				cursor.skip(length, field)?;
+			} else if !is_incremental {
+				*defaulted_count += 1;
+			}
+		}
+		*contributions.entry(Contributor::Channel(short_channel_id)).or_default() += cursor.position() - entry_start;
+	}
+
+	if cursor.position() != data.len() as u64 {
+		return Err(SnapshotParseError { offset: cursor.position(), reason: format!("{} trailing bytes after the last update", data.len() as u64 - cursor.position()) });
+	}
+
+	let mut largest_contributors: Vec<(Contributor, u64)> = contributions.into_iter().collect();
+	largest_contributors.sort_unstable_by(|(contributor_a, bytes_a), (contributor_b, bytes_b)| bytes_b.cmp(bytes_a).then(contributor_a.cmp(contributor_b)));
+	largest_contributors.truncate(LARGEST_CONTRIBUTOR_COUNT);
+
+	Ok(SnapshotSummary {
+		version,
+		extension_version: None,
+		header_totals: None,
+		chain_hash,
+		reference_timestamp: None,
+		latest_seen,
+		size_bytes: data.len() as u64,
+		node_count,
+		default_feature_count,
+		node_feature_update_count,
+		node_address_update_count,
+		announcement_count,
+		channel_capacities,
+		full_update_count,
+		incremental_update_count,
+		reminder_count,
+		defaulted_fields,
+		largest_contributors,
+	})
+}
+
+/// Parse an extended snapshot, which is a version 2 snapshot behind a header of its own, checking
+/// that the header's totals match what the snapshot contains. Offsets in errors are relative to the
+/// start of the extended snapshot.
+pub(crate) fn parse_extended_snapshot(data: &[u8]) -> Result<SnapshotSummary, SnapshotParseError> {
+	let mut cursor = SnapshotCursor { cursor: Cursor::new(data) };
+	let magic: [u8; 4] = cursor.read("extension magic")?;
+	if magic != EXTENDED_SNAPSHOT_MAGIC {
+		return Err(SnapshotParseError { offset: 0, reason: format!("unexpected extension magic {}", magic.as_hex()) });
+	}
+	let extension_version: u8 = cursor.read("extension version")?;
+	if extension_version == 0 || extension_version > EXTENDED_SNAPSHOT_VERSION {
+		return Err(SnapshotParseError { offset: 4, reason: format!("unknown extension version {}", extension_version) });
+	}
+	let totals_start = cursor.position();
+	let header_totals = if extension_version >= 2 {
+		let total_capacity_btc_approx: u32 = cursor.read("total capacity")?;
+		let node_count: [u8; 3] = cursor.read("node count")?;
+		let channel_count: u32 = cursor.read("channel count")?;
+		let node_count = u32::from_be_bytes([0, node_count[0], node_count[1], node_count[2]]);
+		Some(ExtendedHeaderTotals { total_capacity_btc_approx, node_count, channel_count })
+	} else {
+		None
+	};
+
+	let snapshot_start = cursor.position();
+	let mut summary = parse_snapshot(&data[snapshot_start as usize..])
+		.map_err(|error| SnapshotParseError { offset: error.offset + snapshot_start, ..error })?;
+	if summary.version != 2 {
+		return Err(SnapshotParseError { offset: snapshot_start + 3, reason: format!("extended snapshots must wrap version 2, not {}", summary.version) });
+	}
+	if let Some(header_totals) = header_totals {
+		let contained_totals = ExtendedHeaderTotals::new(summary.total_capacity_sats(), summary.node_count, summary.announcement_count);
+		if header_totals != contained_totals {
+			return Err(SnapshotParseError { offset: totals_start, reason: format!("the header totals {:?} don't match the contents {:?}", header_totals, contained_totals) });
+		}
+	}
+	summary.extension_version = Some(extension_version);
+	summary.header_totals = header_totals;
+	summary.size_bytes = data.len() as u64;
+	Ok(summary)
+}
+
+/// Find the capacity record among an announcement's additional TLV records, which start at the
+/// given offset
+fn read_capacity_record(additional_data: &[u8], offset: u64) -> Result<Option<u64>, SnapshotParseError> {
+	let mut cursor = SnapshotCursor { cursor: Cursor::new(additional_data) };
+	// errors are reported at their offset within the snapshot
+	let within_snapshot = |error: SnapshotParseError| SnapshotParseError { offset: offset + error.offset, ..error };
+	let mut capacity_sats = None;
+	while cursor.position() < additional_data.len() as u64 {
+		let record_start = cursor.position();
+		let record_type: BigSize = cursor.read("additional data record type").map_err(within_snapshot)?;
+		let record_length: BigSize = cursor.read("additional data record length").map_err(within_snapshot)?;
+		if record_type.0 == CAPACITY_TLV_TYPE {
+			if record_length.0 != 8 {
+				return Err(SnapshotParseError { offset: offset + record_start, reason: format!("capacity record is {} bytes long rather than 8", record_length.0) });
+			}
+			capacity_sats = Some(cursor.read::<u64>("channel capacity").map_err(within_snapshot)?);
+		} else {
+			cursor.skip(record_length.0, "additional data record").map_err(within_snapshot)?;
+		}
+	}
+	Ok(capacity_sats)
+}
+
+/// The reference timestamp recorded in the names of the snapshot files the server writes
+pub(crate) fn reference_timestamp_from_filename(filename: &str) -> Option<u64> {
+	let (_, calculated_at) = filename.split_once("calculated-at:")?;
+	calculated_at.split("__").next()?.parse().ok()
+}
+
+impl SnapshotSummary {
+	pub(crate) fn total_capacity_sats(&self) -> u64 {
+		self.channel_capacities.iter().map(|(_, capacity_sats)| capacity_sats).sum()
+	}
+
+	pub(crate) fn to_text(&self) -> String {
+		let defaulted = &self.defaulted_fields;
+		let mut text = String::new();
+		text.push_str(&format!("version: {}\n", self.version));
+		if let Some(extension_version) = self.extension_version {
+			text.push_str(&format!("extension version: {}\n", extension_version));
+		}
+		if let Some(totals) = &self.header_totals {
+			text.push_str(&format!("header totals: {} nodes, {} channels, about {} sats\n", totals.node_count, totals.channel_count, totals.approximate_capacity_sats()));
+		}
+		text.push_str(&format!("chain hash: {}\n", self.chain_hash));
+		text.push_str(&format!("reference timestamp: {}\n", self.reference_timestamp.map_or("unknown".to_string(), |timestamp| timestamp.to_string())));
+		text.push_str(&format!("latest seen timestamp: {}\n", self.latest_seen));
+		text.push_str(&format!("size: {} bytes\n", self.size_bytes));
+		text.push_str(&format!("nodes: {}\n", self.node_count));
+		if self.version >= 2 {
+			text.push_str(&format!("\tdefault feature sets: {}\n\tfeature updates: {}\n\taddress updates: {}\n", self.default_feature_count, self.node_feature_update_count, self.node_address_update_count));
+		}
+		text.push_str(&format!("announcements: {}\n", self.announcement_count));
+		if !self.channel_capacities.is_empty() {
+			text.push_str(&format!("\twith capacities: {} ({} sats in total)\n", self.channel_capacities.len(), self.total_capacity_sats()));
+		}
+		text.push_str(&format!("updates: {}\n", self.full_update_count + self.incremental_update_count));
+		text.push_str(&format!("\tfull: {}\n", self.full_update_count));
+		text.push_str(&format!("\tincremental: {} ({} reminders)\n", self.incremental_update_count, self.reminder_count));
+		text.push_str(&format!("defaulted fields in full updates:\n\tCLTV expiry delta: {}\n\tHTLC minimum: {}\n\tbase fee: {}\n\tproportional fee: {}\n\tHTLC maximum: {}\n",
+			defaulted.cltv_expiry_delta, defaulted.htlc_minimum_msat, defaulted.fee_base_msat, defaulted.fee_proportional_millionths, defaulted.htlc_maximum_msat));
+		text.push_str("largest contributors:\n");
+		for (contributor, bytes) in &self.largest_contributors {
+			text.push_str(&format!("\t{}: {} bytes\n", contributor, bytes));
+		}
+		text
+	}
+
+	pub(crate) fn to_json(&self) -> String {
+		let defaulted = &self.defaulted_fields;
+		let contributors: Vec<String> = self.largest_contributors.iter()
+			.map(|(contributor, bytes)| format!("{{\"contributor\":\"{}\",\"bytes\":{}}}", contributor, bytes))
+			.collect();
+		format!("{{\"version\":{},\"chain_hash\":\"{}\",\"reference_timestamp\":{},\"latest_seen\":{},\"size_bytes\":{},\"extension_version\":{},\"header_totals\":{},\"nodes\":{},\"default_feature_sets\":{},\"node_feature_updates\":{},\"node_address_updates\":{},\"announcements\":{},\"announced_capacities\":{},\"total_capacity_sats\":{},\"full_updates\":{},\"incremental_updates\":{},\"reminders\":{},\"defaulted_fields\":{{\"cltv_expiry_delta\":{},\"htlc_minimum_msat\":{},\"fee_base_msat\":{},\"fee_proportional_millionths\":{},\"htlc_maximum_msat\":{}}},\"largest_contributors\":[{}]}}\n",
+			self.version, self.chain_hash, self.reference_timestamp.map_or("null".to_string(), |timestamp| timestamp.to_string()), self.latest_seen, self.size_bytes,
+			self.extension_version.map_or("null".to_string(), |version| version.to_string()),
+			self.header_totals.map_or("null".to_string(), |totals| format!("{{\"nodes\":{},\"channels\":{},\"total_capacity_btc_approx\":{}}}", totals.node_count, totals.channel_count, totals.total_capacity_btc_approx)),
+			self.node_count, self.default_feature_count, self.node_feature_update_count, self.node_address_update_count,
+			self.announcement_count, self.channel_capacities.len(), self.total_capacity_sats(), self.full_update_count, self.incremental_update_count, self.reminder_count,
+			defaulted.cltv_expiry_delta, defaulted.htlc_minimum_msat, defaulted.fee_base_msat, defaulted.fee_proportional_millionths, defaulted.htlc_maximum_msat,
+			contributors.join(","))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use bitcoin::Network;
+
+	use crate::test_utils::bench;
+	use crate::test_utils::MOCK_CHANNEL_CAPACITY_SATS;
+	use crate::test_utils::synthetic::{SyntheticNetwork, SyntheticNetworkParams};
+
+	use super::*;
+
+	/// Three nodes, two of which changed in different ways, two channels, and full, incremental,
+	/// and reminder updates
+	const FIXTURE_V2: &[u8] = include_bytes!(concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures/snapshot_v2.lngossip"));
+
+	fn node_id(parity: u8, fill: u8) -> [u8; 33] {
+		let mut node_id = [fill; 33];
+		node_id[0] = parity;
+		node_id
+	}
+
+	#[test]
+	fn test_fixture_summary() {
+		let summary = parse_snapshot(FIXTURE_V2).unwrap();
+		assert_eq!(summary.version, 2);
+		assert_eq!(summary.chain_hash, ChainHash::using_genesis_block(Network::Bitcoin));
+		assert_eq!(summary.latest_seen, 1699920000);
+		assert_eq!(summary.size_bytes, 235);
+		assert_eq!((summary.node_count, summary.default_feature_count, summary.node_feature_update_count, summary.node_address_update_count), (3, 1, 2, 1));
+		assert_eq!(summary.announcement_count, 2);
+		assert_eq!((summary.full_update_count, summary.incremental_update_count, summary.reminder_count), (2, 2, 1));
+		assert_eq!(summary.defaulted_fields, DefaultedFields { cltv_expiry_delta: 2, htlc_minimum_msat: 2, fee_base_msat: 1, fee_proportional_millionths: 2, htlc_maximum_msat: 2 });
+
+		let short_channel_id = 700000 << 40 | 1 << 16;
+		assert_eq!(summary.largest_contributors, vec![
+			(Contributor::Node(node_id(3, 0x22)), 42),
+			(Contributor::Node(node_id(2, 0x33)), 36),
+			(Contributor::Node(node_id(2, 0x11)), 33),
+			(Contributor::Channel(short_channel_id), 29),
+			(Contributor::Channel(short_channel_id + 1), 13),
+		]);
+		assert_eq!(Contributor::Channel(short_channel_id).to_string(), "channel 700000x1x0");
+
+		let json = summary.to_json();
+		assert!(json.starts_with("{\"version\":2,\"chain_hash\":\"6fe28c0ab6f1b372c1a6a246ae63f74f931e8365e15a089c68d6190000000000\",\"reference_timestamp\":null,"));
+		assert!(json.contains("\"largest_contributors\":[{\"contributor\":\"node 03"));
+		assert!(summary.to_text().contains("\tincremental: 2 (1 reminders)\n"));
+	}
+
+	#[test]
+	fn test_parse_errors() {
+		// truncated within the third update's proportional fee
+		let error = parse_snapshot(&FIXTURE_V2[..231]).unwrap_err();
+		assert_eq!(error.offset, 229);
+		assert!(error.reason.contains("proportional fee"), "{}", error.reason);
+
+		let mut trailing = FIXTURE_V2.to_vec();
+		trailing.push(0);
+		assert_eq!(parse_snapshot(&trailing).unwrap_err(), SnapshotParseError { offset: 235, reason: "1 trailing bytes after the last update".to_string() });
+
+		let mut unknown_version = FIXTURE_V2.to_vec();
+		unknown_version[3] = 3;
+		assert_eq!(parse_snapshot(&unknown_version).unwrap_err().offset, 3);
+	}
+
+	#[test]
+	fn test_empty_snapshot_round_trip() {
+		let summary = parse_snapshot(&crate::serialize_empty_blob(1699920000)).unwrap();
+		assert_eq!(summary.latest_seen, 1699920000);
+		assert_eq!((summary.node_count, summary.announcement_count, summary.full_update_count), (0, 0, 0));
+		assert!(summary.largest_contributors.is_empty());
+	}
+
+	#[test]
+	fn test_channel_capacity_round_trip() {
+		let params = SyntheticNetworkParams { node_count: 4, channel_count: 6, updates_per_direction: 1, ..Default::default() };
+		let network = SyntheticNetwork::generate(&params);
+		// standard snapshots leave the capacities out
+		let standard = bench::serialize_full_snapshot(&network);
+		let standard_summary = parse_snapshot(&standard).unwrap();
+		assert!(standard_summary.channel_capacities.is_empty());
+		assert!(parse_extended_snapshot(&standard).is_err());
+
+		let extended = bench::serialize_full_extended_snapshot(&network);
+		assert_eq!(&extended[..4], b"RGSX");
+		assert_eq!(extended[4], 2);
+		let summary = parse_extended_snapshot(&extended).unwrap();
+		assert_eq!(summary.extension_version, Some(2));
+		// the header totals are what the snapshot contains
+		let header_totals = summary.header_totals.unwrap();
+		assert_eq!(u32::from_be_bytes(extended[5..9].try_into().unwrap()), ((6 * MOCK_CHANNEL_CAPACITY_SATS) >> 20) as u32);
+		assert_eq!(u32::from_be_bytes([0, extended[9], extended[10], extended[11]]), standard_summary.node_count);
+		assert_eq!(u32::from_be_bytes(extended[12..16].try_into().unwrap()), 6);
+		assert_eq!((header_totals.node_count, header_totals.channel_count), (standard_summary.node_count, 6));
+		assert!(header_totals.approximate_capacity_sats() <= 6 * MOCK_CHANNEL_CAPACITY_SATS);
+		assert!(header_totals.approximate_capacity_sats() > 6 * MOCK_CHANNEL_CAPACITY_SATS - (1 << 20));
+		assert_eq!(summary.size_bytes, extended.len() as u64);
+		assert_eq!(summary.announcement_count, 6);
+		assert_eq!(summary.full_update_count, standard_summary.full_update_count);
+		let expected_capacities: Vec<(u64, u64)> = network.announcements.iter()
+			.map(|announcement| (announcement.contents.short_channel_id, MOCK_CHANNEL_CAPACITY_SATS))
+			.collect();
+		assert_eq!(summary.channel_capacities, expected_capacities);
+		assert_eq!(summary.total_capacity_sats(), 6 * MOCK_CHANNEL_CAPACITY_SATS);
+		assert!(summary.to_json().contains("\"announced_capacities\":6,"));
+		assert!(summary.to_json().contains("\"extension_version\":2,\"header_totals\":{\"nodes\":"));
+		assert!(summary.to_text().contains("extension version: 2\n"));
+		assert!(summary.to_text().contains(" 6 channels, "));
+
+		// errors within the wrapped snapshot are reported at their offset within the extended one
+		let error = parse_extended_snapshot(&extended[..extended.len() - 1]).unwrap_err();
+		assert_eq!(error.offset, parse_snapshot(&extended[16..extended.len() - 1]).unwrap_err().offset + 16);
+		let mut unknown_extension = extended.clone();
+		unknown_extension[4] = 3;
+		assert_eq!(parse_extended_snapshot(&unknown_extension).unwrap_err().offset, 4);
+		// headers that misrepresent the contents are rejected
+		let mut inflated_channel_count = extended.clone();
+		inflated_channel_count[15] += 1;
+		assert_eq!(parse_extended_snapshot(&inflated_channel_count).unwrap_err().offset, 5);
+		// version 1 headers carry no totals
+		let mut first_version = extended[..5].to_vec();
+		first_version[4] = 1;
+		first_version.extend_from_slice(&extended[16..]);
+		assert_eq!(parse_extended_snapshot(&first_version).unwrap().header_totals, None);
+
+		// announcements without a capacity serialize as they always did
+		let announcement = &network.announcements[0].contents;
+		let mut plain = Vec::new();
+		crate::serialization::serialize_stripped_channel_announcement(announcement, 0, 1, 0, None, &mut plain);
+		let mut extended = Vec::new();
+		crate::serialization::serialize_stripped_channel_announcement(announcement, 0, 1, 0, Some(MOCK_CHANNEL_CAPACITY_SATS), &mut extended);
+		// the flagged first index takes nine bytes, and the additional data twelve more
+		assert_eq!(extended.len(), plain.len() + 8 + 2 + 10);
+	}
+
+	#[test]
+	fn test_reference_timestamp_from_filename() {
+		assert_eq!(reference_timestamp_from_filename("snapshot__calculated-at:1700006400__range:86400-scope__previous-sync:1699920000.lngossip"), Some(1700006400));
+		assert_eq!(reference_timestamp_from_filename("1700006400.bin"), None);
+	}
+}
@@ -0,0 +1,72 @@
+//! Byte counts of peer connections, which LDK's `PeerManager` doesn't keep, read from the kernel's
+//! statistics of the connections' sockets.
+
+use std::net::TcpStream;
+
+/// The bytes a connection received and sent so far
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct ByteCounts {
+	pub(crate) received: u64,
+	/// Only bytes the peer acknowledged count, so anything still buffered isn't included
+	pub(crate) sent: u64,
+}
+
+/// The leading fields of Linux' `struct tcp_info` up to its byte counters, which kernels fill in
+/// since 4.2
+#[cfg(target_os = "linux")]
+#[repr(C)]
+#[derive(Default)]
+struct TcpInfo {
+	_state_and_options: [u8; 8],
+	_timings_and_segment_counts: [u32; 24],
+	_pacing_rates: [u64; 2],
+	bytes_acked: u64,
+	bytes_received: u64,
+}
+
+/// The byte counts of the socket's connection, or `None` where the kernel doesn't report them
+#[cfg(target_os = "linux")]
+#[allow(unsafe_code)]
+pub(crate) fn read_byte_counts(socket: &TcpStream) -> Option<ByteCounts> {
+	use std::os::unix::io::AsRawFd;
+
+	let mut info = TcpInfo::default();
+	let mut length = std::mem::size_of::<TcpInfo>() as libc::socklen_t;
+	// SAFETY: the kernel writes at most `length` bytes into `info`, and reports how many it wrote
+	let result = unsafe { libc::getsockopt(socket.as_raw_fd(), libc::IPPROTO_TCP, libc::TCP_INFO, &mut info as *mut TcpInfo as *mut libc::c_void, &mut length) };
+	// older kernels write a shorter struct, without the byte counters
+	if result != 0 || (length as usize) < std::mem::size_of::<TcpInfo>() {
+		return None;
+	}
+	Some(ByteCounts { received: info.bytes_received, sent: info.bytes_acked })
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn read_byte_counts(_socket: &TcpStream) -> Option<ByteCounts> {
+	None
+}
+
+#[cfg(all(test, target_os = "linux"))]
+mod tests {
+	use super::*;
+	use std::io::{Read, Write};
+	use std::net::TcpListener;
+
+	#[test]
+	fn test_byte_counts() {
+		let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+		let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+		let (mut server, _) = listener.accept().unwrap();
+
+		client.write_all(&[0; 1000]).unwrap();
+		let mut received = [0; 1000];
+		server.read_exact(&mut received).unwrap();
+		server.write_all(&[0; 10]).unwrap();
+		client.read_exact(&mut received[..10]).unwrap();
+
+		// the client only reads the reply once its bytes were acknowledged
+		let client_counts = read_byte_counts(&client).unwrap();
+		assert_eq!(client_counts, ByteCounts { received: 10, sent: 1000 });
+		assert_eq!(read_byte_counts(&server.try_clone().unwrap()).unwrap().received, 1000);
+	}
+}
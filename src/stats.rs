@@ -0,0 +1,169 @@
+use std::fs;
+use std::ops::Deref;
+use std::sync::Arc;
+
+use lightning::{log_info, log_warn};
+use lightning::routing::gossip::NetworkGraph;
+use lightning::util::logger::Logger;
+
+use crate::config;
+
+/// Distribution of the routing fees presently advertised across all known channel directions.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct FeeStats {
+	pub(crate) median_base_fee_msat: u32,
+	pub(crate) p90_base_fee_msat: u32,
+	pub(crate) median_fee_rate_ppm: u32,
+	pub(crate) p90_fee_rate_ppm: u32,
+	/// The number of channels whose every known direction charges neither a base fee nor a
+	/// proportional fee
+	pub(crate) zero_fee_channel_count: u64,
+	/// The number of channel directions the percentiles were calculated from
+	pub(crate) direction_count: u64,
+	pub(crate) base_fee_msat_sum: u64,
+	pub(crate) fee_rate_ppm_sum: u64,
+}
+
+pub(crate) fn compute_fee_stats<L: Deref>(graph: &NetworkGraph<L>) -> FeeStats where L::Target: Logger {
+	let mut base_fees = Vec::new();
+	let mut fee_rates = Vec::new();
+	let mut zero_fee_channel_count = 0;
+
+	{
+		let read_only_graph = graph.read_only();
+		for (_, channel) in read_only_graph.channels().unordered_iter() {
+			let mut has_known_direction = false;
+			let mut is_zero_fee = true;
+			for info in [&channel.one_to_two, &channel.two_to_one].into_iter().flatten() {
+				has_known_direction = true;
+				base_fees.push(info.fees.base_msat);
+				fee_rates.push(info.fees.proportional_millionths);
+				if info.fees.base_msat != 0 || info.fees.proportional_millionths != 0 {
+					is_zero_fee = false;
+				}
+			}
+			if has_known_direction && is_zero_fee {
+				zero_fee_channel_count += 1;
+			}
+		}
+	}
+
+	base_fees.sort_unstable();
+	fee_rates.sort_unstable();
+
+	FeeStats {
+		median_base_fee_msat: percentile(&base_fees, 50),
+		p90_base_fee_msat: percentile(&base_fees, 90),
+		median_fee_rate_ppm: percentile(&fee_rates, 50),
+		p90_fee_rate_ppm: percentile(&fee_rates, 90),
+		zero_fee_channel_count,
+		direction_count: base_fees.len() as u64,
+		base_fee_msat_sum: base_fees.iter().map(|fee| *fee as u64).sum(),
+		fee_rate_ppm_sum: fee_rates.iter().map(|rate| *rate as u64).sum(),
+	}
+}
+
+/// Nearest-rank percentile of an ascendingly sorted slice, or the default value if it's empty
+pub(crate) fn percentile<T: Copy + Default>(sorted_values: &[T], percentile: usize) -> T {
+	if sorted_values.is_empty() {
+		return T::default();
+	}
+	let rank = (percentile * sorted_values.len() + 99) / 100;
+	sorted_values[rank.saturating_sub(1)]
+}
+
+impl FeeStats {
+	pub(crate) fn to_json(&self) -> String {
+		format!(
+			"{{\"median_base_fee_msat\":{},\"p90_base_fee_msat\":{},\"median_fee_rate_ppm\":{},\"p90_fee_rate_ppm\":{},\"zero_fee_channel_count\":{}}}",
+			self.median_base_fee_msat,
+			self.p90_base_fee_msat,
+			self.median_fee_rate_ppm,
+			self.p90_fee_rate_ppm,
+			self.zero_fee_channel_count
+		)
+	}
+
+	/// Serialize the statistics as Prometheus summaries in the text exposition format
+	pub(crate) fn to_prometheus(&self) -> String {
+		let mut output = String::new();
+		output.push_str("# HELP rgs_channel_base_fee_msat Base fee advertised per channel direction\n");
+		output.push_str("# TYPE rgs_channel_base_fee_msat summary\n");
+		output.push_str(&format!("rgs_channel_base_fee_msat{{quantile=\"0.5\"}} {}\n", self.median_base_fee_msat));
+		output.push_str(&format!("rgs_channel_base_fee_msat{{quantile=\"0.9\"}} {}\n", self.p90_base_fee_msat));
+		output.push_str(&format!("rgs_channel_base_fee_msat_sum {}\n", self.base_fee_msat_sum));
+		output.push_str(&format!("rgs_channel_base_fee_msat_count {}\n", self.direction_count));
+		output.push_str("# HELP rgs_channel_fee_rate_ppm Proportional fee advertised per channel direction\n");
+		output.push_str("# TYPE rgs_channel_fee_rate_ppm summary\n");
+		output.push_str(&format!("rgs_channel_fee_rate_ppm{{quantile=\"0.5\"}} {}\n", self.median_fee_rate_ppm));
+		output.push_str(&format!("rgs_channel_fee_rate_ppm{{quantile=\"0.9\"}} {}\n", self.p90_fee_rate_ppm));
+		output.push_str(&format!("rgs_channel_fee_rate_ppm_sum {}\n", self.fee_rate_ppm_sum));
+		output.push_str(&format!("rgs_channel_fee_rate_ppm_count {}\n", self.direction_count));
+		output.push_str("# HELP rgs_zero_fee_channels Channels charging no fees in any known direction\n");
+		output.push_str("# TYPE rgs_zero_fee_channels gauge\n");
+		output.push_str(&format!("rgs_zero_fee_channels {}\n", self.zero_fee_channel_count));
+		output
+	}
+}
+
+/// Periodically recompute the fee statistics and publish them next to the snapshots, so that they
+/// can be served as `/stats/fees` and scraped by Prometheus' textfile collector.
+pub(crate) async fn publish_fee_stats<L: Deref>(network_graph: Arc<NetworkGraph<L>>, logger: L) where L::Target: Logger {
+	let mut interval = tokio::time::interval(config::fee_stats_interval());
+	loop {
+		interval.tick().await;
+
+		let fee_stats = compute_fee_stats(&network_graph);
+		log_info!(logger, "Fee stats across {} channel directions: {}", fee_stats.direction_count, fee_stats.to_json());
+
+		if let Err(error) = write_stats_file(&config::stats_path(), "fees", &fee_stats.to_json(), &fee_stats.to_prometheus()) {
+			log_warn!(logger, "Failed to persist fee stats: {}", error);
+		}
+	}
+}
+
+/// Publish the gossip message rate next to the fee statistics, as `/stats/gossip_rate` and as a
+/// Prometheus gauge
+pub(crate) fn publish_message_rate(message_rate_hz: f64) -> std::io::Result<()> {
+	write_stats_file(&config::stats_path(), "gossip_rate", &format!("{{\"message_rate_hz\":{{\"60s\":{:.3}}}}}", message_rate_hz), &message_rate_prometheus(message_rate_hz))
+}
+
+fn message_rate_prometheus(message_rate_hz: f64) -> String {
+	let mut output = String::new();
+	output.push_str("# HELP rgs_message_rate_hz Gossip announcements and updates received per second\n");
+	output.push_str("# TYPE rgs_message_rate_hz gauge\n");
+	output.push_str(&format!("rgs_message_rate_hz{{window=\"60s\"}} {:.3}\n", message_rate_hz));
+	output
+}
+
+pub(crate) fn write_stats_file(stats_directory: &str, name: &str, json: &str, prometheus: &str) -> std::io::Result<()> {
+	fs::create_dir_all(stats_directory)?;
+	// write to a pending file first so readers never observe a partially written file
+	for (extension, contents) in [("json", json), ("prom", prometheus)] {
+		let pending_path = format!("{}/{}.{}.pending", stats_directory, name, extension);
+		let finalized_path = format!("{}/{}.{}", stats_directory, name, extension);
+		fs::write(&pending_path, contents)?;
+		fs::rename(&pending_path, &finalized_path)?;
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn test_message_rate_prometheus() {
+		assert_eq!(message_rate_prometheus(12.25), "# HELP rgs_message_rate_hz Gossip announcements and updates received per second\n# TYPE rgs_message_rate_hz gauge\nrgs_message_rate_hz{window=\"60s\"} 12.250\n");
+	}
+
+	#[test]
+	fn test_percentile() {
+		assert_eq!(percentile(&[], 50), 0);
+		assert_eq!(percentile(&[7], 90), 7);
+		let values: Vec<u32> = (1..=10).collect();
+		assert_eq!(percentile(&values, 50), 5);
+		assert_eq!(percentile(&values, 90), 9);
+		assert_eq!(percentile(&values, 100), 10);
+	}
+}
@@ -0,0 +1,273 @@
+use tokio_postgres::GenericClient;
+
+use crate::client_usage::ClientUsageTotals;
+use crate::compact_graph::DirectionalCoverage;
+use crate::downloader::GossipCounter;
+use crate::maintenance::TableBloat;
+use crate::tables::Tables;
+
+/// Names of the [`GossipCounter`] values, in the order they are stored in [`StatsRecord::counters`]
+const COUNTER_COLUMNS: [&str; 7] = [
+	"node_announcements",
+	"channel_announcements",
+	"channel_updates",
+	"channel_updates_without_htlc_max_msats",
+	"channel_announcements_with_mismatched_scripts",
+	"ignored_onion_messages",
+	"ignored_custom_messages",
+];
+
+/// Names of the tables whose (estimated) row counts are stored in [`StatsRecord::row_counts`]
+const ROW_COUNT_COLUMNS: [&str; 3] = [
+	"channel_announcement_rows",
+	"channel_update_rows",
+	"node_announcement_rows",
+];
+
+/// Names of the [`DirectionalCoverage`] counts, in the order they are stored in
+/// [`StatsRecord::directional_coverage`]
+const COVERAGE_COLUMNS: [&str; 4] = [
+	"bidirectional_channels",
+	"direction_0_only_channels",
+	"direction_1_only_channels",
+	"channels_without_updates",
+];
+
+/// A single row of the stats history table
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct StatsRecord {
+	pub(crate) recorded_at: String,
+	/// When the process that recorded this row started. Counters reset whenever this changes.
+	pub(crate) started_at: i64,
+	pub(crate) counters: [i64; COUNTER_COLUMNS.len()],
+	pub(crate) connected_peers: i64,
+	pub(crate) row_counts: [i64; ROW_COUNT_COLUMNS.len()],
+	pub(crate) directional_coverage: [i64; COVERAGE_COLUMNS.len()],
+}
+
+/// The change between two consecutive stats history rows
+#[derive(Debug, PartialEq)]
+pub(crate) struct StatsDelta {
+	pub(crate) recorded_at: String,
+	/// Whether the process restarted since the previous row, in which case the counter deltas only
+	/// cover the time since the restart
+	pub(crate) restarted: bool,
+	pub(crate) counters: [i64; COUNTER_COLUMNS.len()],
+	pub(crate) connected_peers: i64,
+	pub(crate) row_counts: [i64; ROW_COUNT_COLUMNS.len()],
+	/// Not a change, but the coverage at the time of the current row, like the connected peers
+	pub(crate) directional_coverage: [i64; COVERAGE_COLUMNS.len()],
+}
+
+pub(crate) enum StatsFormat {
+	Table,
+	Csv,
+}
+
+/// Append the current counter values, table sizes, and directional coverage of the network graph's
+/// channels to the stats history, along with the gossip tables' dead rows and index sizes if their
+/// bloat was estimated, and the totals of the snapshot requests if the HTTP front end reports them.
+///
+/// Row counts are the planner's estimates, because counting the update table exactly would take
+/// far longer than the rest of the write.
+pub(crate) async fn record_stats<C: GenericClient>(client: &C, tables: &Tables, counter: &GossipCounter, directional_coverage: &DirectionalCoverage, bloat: Option<&[TableBloat]>, client_usage: Option<&ClientUsageTotals>, started_at: u64) -> Result<(), tokio_postgres::Error> {
+	let counters = counter_values(counter);
+	let coverage = directional_coverage.values().map(|count| count as i64);
+	let connected_peers = counter.connected_peers as i64;
+	let dead_rows = bloat.map(|bloat| bloat.iter().map(|table_bloat| table_bloat.dead_rows).sum::<i64>());
+	let index_bytes = bloat.map(|bloat| bloat.iter().map(|table_bloat| table_bloat.index_bytes).sum::<i64>());
+	let ok_responses = client_usage.map(|client_usage| client_usage.ok_responses as i64);
+	let not_modified_responses = client_usage.map(|client_usage| client_usage.not_modified_responses as i64);
+	let bytes_served = client_usage.map(|client_usage| client_usage.bytes_served as i64);
+	let row_estimate = |parameter_index: usize| format!("(SELECT GREATEST(COALESCE((SELECT reltuples::bigint FROM pg_class WHERE oid = to_regclass(${})), 0), 0))", parameter_index);
+	client.execute(&format!("INSERT INTO {} (\
+		started_at, \
+		{}, \
+		connected_peers, \
+		{}, \
+		{}, \
+		dead_rows, \
+		index_bytes, \
+		snapshot_downloads, \
+		not_modified_responses, \
+		bytes_served \
+	) VALUES (TO_TIMESTAMP($1), $2, $3, $4, $5, $6, $7, $8, $9, {}, {}, {}, $13, $14, $15, $16, $17, $18, $19, $20, $21)",
+		tables.stats_history(), COUNTER_COLUMNS.join(", "), ROW_COUNT_COLUMNS.join(", "), COVERAGE_COLUMNS.join(", "),
+		row_estimate(10), row_estimate(11), row_estimate(12)
+	), &[
+		&(started_at as f64),
+		&counters[0], &counters[1], &counters[2], &counters[3], &counters[4], &counters[5], &counters[6],
+		&connected_peers,
+		&tables.channel_announcements(),
+		&tables.channel_updates(),
+		&tables.node_announcements(),
+		&coverage[0], &coverage[1], &coverage[2], &coverage[3],
+		&dead_rows,
+		&index_bytes,
+		&ok_responses,
+		&not_modified_responses,
+		&bytes_served,
+	]).await?;
+	Ok(())
+}
+
+/// Delete stats history rows older than the retention period
+pub(crate) async fn prune_stats<C: GenericClient>(client: &C, tables: &Tables, retention_days: u32) -> Result<u64, tokio_postgres::Error> {
+	client.execute(&format!("DELETE FROM {} WHERE recorded_at < NOW() - make_interval(days => $1::int)", tables.stats_history()), &[&(retention_days as i32)]).await
+}
+
+/// Fetch all stats history rows recorded at or after `since`, which may be any timestamp
+/// PostgreSQL can parse, such as `2024-01-31`
+pub(crate) async fn fetch_stats<C: GenericClient>(client: &C, tables: &Tables, since: &str) -> Result<Vec<StatsRecord>, tokio_postgres::Error> {
+	let rows = client.query(&format!("
+		SELECT to_char(recorded_at, 'YYYY-MM-DD HH24:MI:SS') AS recorded_at, CAST(EXTRACT('epoch' from started_at) AS BIGINT) AS started_at, {}, connected_peers, {}, {}
+		FROM {}
+		WHERE recorded_at >= $1::text::timestamp
+		ORDER BY recorded_at ASC, id ASC
+		", COUNTER_COLUMNS.join(", "), ROW_COUNT_COLUMNS.join(", "), COVERAGE_COLUMNS.join(", "), tables.stats_history()), &[&since]).await?;
+
+	Ok(rows.iter().map(|row| {
+		let mut counters = [0; COUNTER_COLUMNS.len()];
+		for (value, column) in counters.iter_mut().zip(COUNTER_COLUMNS.iter()) {
+			*value = row.get(*column);
+		}
+		let mut row_counts = [0; ROW_COUNT_COLUMNS.len()];
+		for (value, column) in row_counts.iter_mut().zip(ROW_COUNT_COLUMNS.iter()) {
+			*value = row.get(*column);
+		}
+		let mut directional_coverage = [0; COVERAGE_COLUMNS.len()];
+		for (value, column) in directional_coverage.iter_mut().zip(COVERAGE_COLUMNS.iter()) {
+			*value = row.get(*column);
+		}
+		StatsRecord {
+			recorded_at: row.get("recorded_at"),
+			started_at: row.get("started_at"),
+			counters,
+			connected_peers: row.get("connected_peers"),
+			row_counts,
+			directional_coverage,
+		}
+	}).collect())
+}
+
+pub(crate) fn compute_deltas(records: &[StatsRecord]) -> Vec<StatsDelta> {
+	records.windows(2).map(|pair| {
+		let (previous, current) = (&pair[0], &pair[1]);
+		let restarted = current.started_at != previous.started_at;
+		let mut counters = current.counters;
+		if !restarted {
+			for (value, previous_value) in counters.iter_mut().zip(previous.counters.iter()) {
+				*value -= previous_value;
+			}
+		}
+		let mut row_counts = current.row_counts;
+		for (value, previous_value) in row_counts.iter_mut().zip(previous.row_counts.iter()) {
+			*value -= previous_value;
+		}
+		StatsDelta {
+			recorded_at: current.recorded_at.clone(),
+			restarted,
+			counters,
+			connected_peers: current.connected_peers,
+			row_counts,
+			directional_coverage: current.directional_coverage,
+		}
+	}).collect()
+}
+
+pub(crate) fn format_deltas(deltas: &[StatsDelta], format: StatsFormat) -> String {
+	let mut header = vec!["recorded_at", "restarted"];
+	header.extend_from_slice(&COUNTER_COLUMNS);
+	header.push("connected_peers");
+	header.extend_from_slice(&ROW_COUNT_COLUMNS);
+	header.extend_from_slice(&COVERAGE_COLUMNS);
+
+	let rows: Vec<Vec<String>> = deltas.iter().map(|delta| {
+		let mut row = vec![delta.recorded_at.clone(), delta.restarted.to_string()];
+		row.extend(delta.counters.iter().map(|value| value.to_string()));
+		row.push(delta.connected_peers.to_string());
+		row.extend(delta.row_counts.iter().map(|value| value.to_string()));
+		row.extend(delta.directional_coverage.iter().map(|value| value.to_string()));
+		row
+	}).collect();
+
+	let mut output = String::new();
+	match format {
+		StatsFormat::Csv => {
+			output.push_str(&header.join(","));
+			output.push('\n');
+			for row in rows {
+				output.push_str(&row.join(","));
+				output.push('\n');
+			}
+		},
+		StatsFormat::Table => {
+			let widths: Vec<usize> = header.iter().enumerate().map(|(index, title)| {
+				rows.iter().map(|row| row[index].len()).max().unwrap_or(0).max(title.len())
+			}).collect();
+			let header_row: Vec<String> = header.iter().map(|title| title.to_string()).collect();
+			for row in [header_row].iter().chain(rows.iter()) {
+				let padded_cells: Vec<String> = row.iter().zip(widths.iter()).map(|(cell, width)| format!("{:>width$}", cell, width = width)).collect();
+				output.push_str(padded_cells.join(" | ").trim_end());
+				output.push('\n');
+			}
+		}
+	}
+	output
+}
+
+fn counter_values(counter: &GossipCounter) -> [i64; COUNTER_COLUMNS.len()] {
+	[
+		counter.node_announcements as i64,
+		counter.channel_announcements as i64,
+		counter.channel_updates as i64,
+		counter.channel_updates_without_htlc_max_msats as i64,
+		counter.channel_announcements_with_mismatched_scripts as i64,
+		counter.ignored_onion_messages as i64,
+		counter.ignored_custom_messages as i64,
+	]
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn record(recorded_at: &str, started_at: i64, channel_updates: i64, update_rows: i64) -> StatsRecord {
+		StatsRecord {
+			recorded_at: recorded_at.to_string(),
+			started_at,
+			counters: [0, 10, channel_updates, 0, 0, 0, 0],
+			connected_peers: 5,
+			row_counts: [10, update_rows, 0],
+			directional_coverage: [90, 4, 5, 1],
+		}
+	}
+
+	#[test]
+	fn test_stats_deltas_across_restart() {
+		let records = [
+			record("2024-01-01 00:00:00", 100, 500, 1000),
+			record("2024-01-01 01:00:00", 100, 800, 1300),
+			record("2024-01-01 02:00:00", 7300, 50, 1350),
+		];
+		let deltas = compute_deltas(&records);
+		assert_eq!(deltas.len(), 2);
+		assert!(!deltas[0].restarted);
+		assert_eq!(deltas[0].counters, [0, 0, 300, 0, 0, 0, 0]);
+		assert_eq!(deltas[0].row_counts, [0, 300, 0]);
+		// counters reset upon restart, so the new values are the delta
+		assert!(deltas[1].restarted);
+		assert_eq!(deltas[1].counters, [0, 10, 50, 0, 0, 0, 0]);
+		assert_eq!(deltas[1].row_counts, [0, 50, 0]);
+
+		let csv = format_deltas(&deltas, StatsFormat::Csv);
+		let mut lines = csv.lines();
+		assert!(lines.next().unwrap().starts_with("recorded_at,restarted,node_announcements,channel_announcements,channel_updates,"));
+		assert_eq!(lines.next().unwrap(), "2024-01-01 01:00:00,false,0,0,300,0,0,0,0,5,0,300,0,90,4,5,1");
+		assert_eq!(lines.next().unwrap(), "2024-01-01 02:00:00,true,0,10,50,0,0,0,0,5,0,50,0,90,4,5,1");
+
+		let table = format_deltas(&deltas, StatsFormat::Table);
+		assert_eq!(table.lines().count(), 3);
+		assert!(table.lines().all(|line| line.len() == table.lines().next().unwrap().len()));
+	}
+}
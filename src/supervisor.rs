@@ -0,0 +1,233 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::future::Future;
+use std::ops::Deref;
+use std::pin::Pin;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use futures::future;
+use lightning::{log_error, log_info, log_warn};
+use lightning::util::logger::Logger;
+use tokio::task::{JoinError, JoinHandle};
+
+use crate::error::ProcessorError;
+
+/// What to do once a supervised component stops, be it by failing, panicking, or returning
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum FailurePolicy {
+	/// Start the component again, unless it has used up its restart budget
+	Restart,
+	/// Stop the whole server, so that it can be restarted cleanly from the outside
+	Exit,
+}
+
+impl FromStr for FailurePolicy {
+	type Err = String;
+
+	fn from_str(policy: &str) -> Result<Self, Self::Err> {
+		match policy {
+			"restart" => Ok(Self::Restart),
+			"exit" => Ok(Self::Exit),
+			_ => Err(format!("unknown failure policy {}", policy)),
+		}
+	}
+}
+
+/// How often each component may be restarted before the server gives up on it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct RestartBudget {
+	pub(crate) max_restarts: usize,
+	pub(crate) window: Duration,
+}
+
+type ComponentFuture = Pin<Box<dyn Future<Output = Result<(), ProcessorError>> + Send>>;
+
+struct Component {
+	name: &'static str,
+	policy: FailurePolicy,
+	start: Box<dyn FnMut() -> ComponentFuture + Send>,
+	handle: JoinHandle<Result<(), ProcessorError>>,
+	/// When the component was restarted within the budget's window
+	restarts: VecDeque<Instant>,
+}
+
+/// Owns the server's long-running tasks, and notices when any of them stops. Nothing is supposed
+/// to stop, so whenever something does, it's either restarted, or the server is taken down rather
+/// than left running without, say, persisting gossip or publishing snapshots.
+pub(crate) struct Supervisor<L: Deref> where L::Target: Logger {
+	components: Vec<Component>,
+	restart_budget: RestartBudget,
+	logger: L,
+}
+
+impl<L: Deref> Supervisor<L> where L::Target: Logger {
+	pub(crate) fn new(restart_budget: RestartBudget, logger: L) -> Self {
+		Self { components: Vec::new(), restart_budget, logger }
+	}
+
+	/// Spawn a component, which `start` is called again for if it's restarted
+	pub(crate) fn supervise<F, Fut>(&mut self, name: &'static str, policy: FailurePolicy, mut start: F)
+		where F: FnMut() -> Fut + Send + 'static, Fut: Future<Output = Result<(), ProcessorError>> + Send + 'static
+	{
+		let mut start: Box<dyn FnMut() -> ComponentFuture + Send> = Box::new(move || Box::pin(start()));
+		let handle = tokio::spawn(start());
+		self.components.push(Component { name, policy, start, handle, restarts: VecDeque::new() });
+	}
+
+	/// Watch the components, restarting them as their policies allow, and only return once one of
+	/// them stopped for good. This can be cancelled, and called again, at any point.
+	pub(crate) async fn run(&mut self) -> ProcessorError {
+		loop {
+			if self.components.is_empty() {
+				future::pending::<()>().await;
+			}
+			let (outcome, index, _) = future::select_all(self.components.iter_mut().map(|component| &mut component.handle)).await;
+			let component = &mut self.components[index];
+			let (reason, cause) = describe_outcome(outcome);
+			log_error!(self.logger, "The {} component stopped: {}", component.name, reason);
+
+			if component.policy == FailurePolicy::Exit {
+				return ProcessorError::ComponentStopped { component: component.name, reason, cause: cause.map(Box::new) };
+			}
+
+			let now = Instant::now();
+			while component.restarts.front().map_or(false, |restart| now.duration_since(*restart) >= self.restart_budget.window) {
+				component.restarts.pop_front();
+			}
+			if component.restarts.len() >= self.restart_budget.max_restarts {
+				log_error!(self.logger, "The {} component already restarted {} times in the last {:?}, giving up", component.name, component.restarts.len(), self.restart_budget.window);
+				return ProcessorError::ComponentStopped { component: component.name, reason, cause: cause.map(Box::new) };
+			}
+			component.restarts.push_back(now);
+			log_warn!(self.logger, "Restarting the {} component ({} of {} restarts in {:?})", component.name, component.restarts.len(), self.restart_budget.max_restarts, self.restart_budget.window);
+			component.handle = tokio::spawn((component.start)());
+		}
+	}
+
+	/// Stop all components, and wait for their tasks to be dropped
+	pub(crate) async fn shutdown(self) {
+		for component in &self.components {
+			component.handle.abort();
+		}
+		for component in self.components {
+			let _ = component.handle.await;
+			log_info!(self.logger, "Stopped the {} component", component.name);
+		}
+	}
+}
+
+/// Describe why a component stopped, along with the error it failed with, if any
+fn describe_outcome(outcome: Result<Result<(), ProcessorError>, JoinError>) -> (String, Option<ProcessorError>) {
+	match outcome {
+		Ok(Ok(())) => ("it returned unexpectedly".to_string(), None),
+		Ok(Err(error)) => (format!("it failed: {}", error), Some(error)),
+		Err(error) if error.is_panic() => (format!("it panicked: {}", panic_message(error.into_panic())), None),
+		Err(error) => (format!("its task was cancelled: {}", error), None),
+	}
+}
+
+fn panic_message(payload: Box<dyn Any + Send>) -> String {
+	match payload.downcast::<String>() {
+		Ok(message) => *message,
+		Err(payload) => match payload.downcast::<&'static str>() {
+			Ok(message) => message.to_string(),
+			Err(_) => "unknown panic payload".to_string(),
+		},
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+	use std::sync::atomic::{AtomicUsize, Ordering};
+
+	use crate::types::tests::TestLogger;
+	use super::*;
+
+	const BUDGET: RestartBudget = RestartBudget { max_restarts: 3, window: Duration::from_secs(3600) };
+
+	/// A component that panics the first `panic_count` times it's started, and runs forever after
+	fn flaky_component(start_count: Arc<AtomicUsize>, panic_count: usize) -> impl FnMut() -> future::BoxFuture<'static, Result<(), ProcessorError>> + Send + 'static {
+		move || {
+			let start_count = Arc::clone(&start_count);
+			Box::pin(async move {
+				if start_count.fetch_add(1, Ordering::AcqRel) < panic_count {
+					panic!("injected failure");
+				}
+				future::pending().await
+			})
+		}
+	}
+
+	#[tokio::test]
+	async fn test_restart_policy() {
+		let logger = Arc::new(TestLogger::with_id("supervisor_restart".to_string()));
+		let start_count = Arc::new(AtomicUsize::new(0));
+		let mut supervisor = Supervisor::new(BUDGET, logger.clone());
+		supervisor.supervise("persister", FailurePolicy::Restart, flaky_component(Arc::clone(&start_count), 2));
+
+		// the component recovers within its budget, so the supervisor keeps watching it
+		let run_result = tokio::time::timeout(Duration::from_millis(500), supervisor.run()).await;
+		assert!(run_result.is_err());
+		assert_eq!(start_count.load(Ordering::Acquire), 3);
+		logger.assert_log_contains("rapid_gossip_sync_server::supervisor", "The persister component stopped: it panicked: injected failure", 2);
+		logger.assert_log_contains("rapid_gossip_sync_server::supervisor", "Restarting the persister component", 2);
+		supervisor.shutdown().await;
+	}
+
+	#[tokio::test]
+	async fn test_exhausted_restart_budget() {
+		let logger = Arc::new(TestLogger::with_id("supervisor_budget".to_string()));
+		let start_count = Arc::new(AtomicUsize::new(0));
+		let mut supervisor = Supervisor::new(BUDGET, logger.clone());
+		supervisor.supervise("snapshotter", FailurePolicy::Restart, flaky_component(Arc::clone(&start_count), usize::MAX));
+
+		let error = supervisor.run().await;
+		assert!(matches!(error, ProcessorError::ComponentStopped { component: "snapshotter", .. }));
+		// the initial start, plus the budgeted restarts
+		assert_eq!(start_count.load(Ordering::Acquire), 4);
+		logger.assert_log_contains("rapid_gossip_sync_server::supervisor", "giving up", 1);
+		supervisor.shutdown().await;
+	}
+
+	#[tokio::test]
+	async fn test_exit_policy() {
+		let logger = Arc::new(TestLogger::with_id("supervisor_exit".to_string()));
+		let healthy_start_count = Arc::new(AtomicUsize::new(0));
+		let failing_start_count = Arc::new(AtomicUsize::new(0));
+		let mut supervisor = Supervisor::new(BUDGET, logger.clone());
+		supervisor.supervise("tracking", FailurePolicy::Exit, flaky_component(Arc::clone(&healthy_start_count), 0));
+		supervisor.supervise("persister", FailurePolicy::Exit, flaky_component(Arc::clone(&failing_start_count), 1));
+
+		let error = supervisor.run().await;
+		assert_eq!(error.to_string(), "the persister component stopped: it panicked: injected failure");
+		assert_eq!(failing_start_count.load(Ordering::Acquire), 1);
+		logger.assert_log_contains("rapid_gossip_sync_server::supervisor", "Restarting", 0);
+		supervisor.shutdown().await;
+	}
+
+	#[tokio::test]
+	async fn test_exit_code_of_failed_component() {
+		let logger = Arc::new(TestLogger::with_id("supervisor_exit_code".to_string()));
+		let mut supervisor = Supervisor::new(BUDGET, logger.clone());
+		supervisor.supervise("tracking", FailurePolicy::Exit, || async { Err(ProcessorError::NoPeersConnected) });
+
+		let error = supervisor.run().await;
+		assert_eq!(error.exit_code(), crate::error::EXIT_NO_PEERS_CONNECTED);
+		supervisor.shutdown().await;
+
+		// panics carry no cause, so they're worth a restart
+		let mut supervisor = Supervisor::new(BUDGET, logger.clone());
+		supervisor.supervise("persister", FailurePolicy::Exit, flaky_component(Arc::new(AtomicUsize::new(0)), 1));
+		assert_eq!(supervisor.run().await.exit_code(), crate::error::EXIT_FAILURE);
+		supervisor.shutdown().await;
+	}
+
+	#[test]
+	fn test_policy_parsing() {
+		assert_eq!("restart".parse::<FailurePolicy>(), Ok(FailurePolicy::Restart));
+		assert_eq!("exit".parse::<FailurePolicy>(), Ok(FailurePolicy::Exit));
+		assert!("ignore".parse::<FailurePolicy>().is_err());
+	}
+}
@@ -0,0 +1,257 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use bitcoin::secp256k1::PublicKey;
+use tokio_postgres::GenericClient;
+
+use crate::config;
+use crate::downloader::GossipCounter;
+use crate::tables::Tables;
+
+/// The window over which the channel ingestion rate is measured to estimate the remaining time
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+struct EstimateState {
+	/// The short channel ids counted so far for each peer whose range replies are still incomplete
+	pending_counts: HashMap<PublicKey, u64>,
+	/// The highest channel count any peer reported
+	total: Option<u64>,
+}
+
+/// Estimates the network's channel count from peers' replies to queries for their full channel
+/// range. Peers may know of different numbers of channels, so the highest count wins.
+pub(crate) struct ChannelCountEstimate {
+	state: Mutex<EstimateState>,
+}
+
+impl ChannelCountEstimate {
+	pub(crate) fn new() -> Self {
+		Self { state: Mutex::new(EstimateState::default()) }
+	}
+
+	/// Start counting the channels of a peer that was just asked for its full channel range
+	pub(crate) fn query_sent(&self, peer: PublicKey) {
+		self.state.lock().expect("channel count estimate lock poisoned").pending_counts.insert(peer, 0);
+	}
+
+	/// Count the short channel ids of a peer's range reply, returning the peer's channel count once
+	/// its replies are complete. Replies to queries that weren't sent are ignored.
+	pub(crate) fn record_reply(&self, peer: &PublicKey, short_channel_id_count: usize, sync_complete: bool) -> Option<u64> {
+		let mut state = self.state.lock().expect("channel count estimate lock poisoned");
+		let pending_count = state.pending_counts.get_mut(peer)?;
+		*pending_count += short_channel_id_count as u64;
+		if !sync_complete {
+			return None;
+		}
+		let peer_total = state.pending_counts.remove(peer)?;
+		state.total = Some(state.total.map_or(peer_total, |total| total.max(peer_total)));
+		Some(peer_total)
+	}
+
+	/// The estimated channel count, unless no peer answered the range query yet
+	pub(crate) fn total(&self) -> Option<u64> {
+		self.state.lock().expect("channel count estimate lock poisoned").total
+	}
+}
+
+/// Progress towards the estimated channel count, as of a tracking iteration
+#[derive(Debug, PartialEq)]
+pub(crate) struct ProgressReport {
+	pub(crate) channel_count: u64,
+	pub(crate) estimated_total: Option<u64>,
+	/// The estimated time until the total is reached, at the recent ingestion rate
+	pub(crate) remaining_time: Option<Duration>,
+}
+
+impl fmt::Display for ProgressReport {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		let estimated_total = match self.estimated_total {
+			Some(estimated_total) => estimated_total,
+			None => return write!(f, "{} channels of an unknown total", self.channel_count),
+		};
+		let percent = self.channel_count.min(estimated_total) as f64 * 100.0 / estimated_total.max(1) as f64;
+		write!(f, "{}/{} channels ({:.1}%), ", self.channel_count, estimated_total, percent)?;
+		match self.remaining_time {
+			Some(remaining_time) => write!(f, "about {} minutes remaining", (remaining_time.as_secs() + 59) / 60),
+			None => write!(f, "remaining time unknown"),
+		}
+	}
+}
+
+/// Measures the recent channel ingestion rate to report the progress of the initial sync
+pub(crate) struct SyncProgress {
+	/// The channel counts of recent tracking iterations, oldest first
+	samples: VecDeque<(Instant, u64)>,
+}
+
+impl SyncProgress {
+	pub(crate) fn new() -> Self {
+		Self { samples: VecDeque::new() }
+	}
+
+	pub(crate) fn tick(&mut self, channel_count: u64, estimated_total: Option<u64>, now: Instant) -> ProgressReport {
+		self.samples.push_back((now, channel_count));
+		while self.samples.front().map_or(false, |(sampled_at, _)| now.duration_since(*sampled_at) > RATE_WINDOW) {
+			self.samples.pop_front();
+		}
+
+		let remaining_time = estimated_total.and_then(|estimated_total| {
+			let (oldest_sampled_at, oldest_channel_count) = *self.samples.front()?;
+			let elapsed = now.duration_since(oldest_sampled_at).as_secs_f64();
+			let ingested = channel_count.saturating_sub(oldest_channel_count);
+			if ingested == 0 || elapsed == 0.0 {
+				return None;
+			}
+			let remaining = estimated_total.saturating_sub(channel_count);
+			Some(Duration::from_secs_f64(remaining as f64 * elapsed / ingested as f64))
+		});
+		ProgressReport { channel_count, estimated_total, remaining_time }
+	}
+}
+
+/// A rough estimate of how far the catch-up with the network's gossip has progressed, which needs
+/// nothing but the gossip counter, unlike the [`ProgressReport`] based on peers' range replies
+#[derive(Clone, Debug, PartialEq)]
+pub struct CatchupProgress {
+	/// The connected peers times the average number of channels each one is assumed to know of
+	pub estimated_total_channels: u64,
+	/// The channel announcements received so far
+	pub current_channels: u64,
+	/// The share of the estimated total received so far, capped at 100
+	pub progress_pct: f64,
+	/// The estimated seconds until the total is reached, at the latest tracking iteration's message
+	/// rate, unless no messages are arriving
+	pub eta_secs: Option<u64>,
+}
+
+impl fmt::Display for CatchupProgress {
+	fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+		write!(f, "{:.1}% of ~{} channels, ETA ", self.progress_pct, self.estimated_total_channels)?;
+		match self.eta_secs {
+			Some(eta_secs) => write!(f, "{}s", eta_secs),
+			None => write!(f, "unknown"),
+		}
+	}
+}
+
+pub(crate) fn estimate_catchup_progress(counter: &GossipCounter, connected_peers: usize) -> CatchupProgress {
+	estimate_catchup_progress_with_average(counter, connected_peers, config::avg_channels_per_peer())
+}
+
+fn estimate_catchup_progress_with_average(counter: &GossipCounter, connected_peers: usize, avg_channels_per_peer: u64) -> CatchupProgress {
+	let estimated_total_channels = connected_peers as u64 * avg_channels_per_peer;
+	let current_channels = counter.channel_announcements;
+	let progress_pct = if estimated_total_channels == 0 {
+		0.0
+	} else {
+		current_channels.min(estimated_total_channels) as f64 * 100.0 / estimated_total_channels as f64
+	};
+	let remaining_channels = estimated_total_channels.saturating_sub(current_channels);
+	let eta_secs = if counter.recent_message_rate > 0.0 {
+		Some((remaining_channels as f64 / counter.recent_message_rate).ceil() as u64)
+	} else {
+		None
+	};
+	CatchupProgress { estimated_total_channels, current_channels, progress_pct, eta_secs }
+}
+
+/// The channel counts at the end of an earlier initial sync
+#[derive(Debug, PartialEq)]
+pub(crate) struct SyncTotal {
+	pub(crate) estimated_total: Option<u64>,
+	pub(crate) channel_count: u64,
+}
+
+/// Record the channel counts at the end of an initial sync, so that later syncs can compare
+pub(crate) async fn record_sync_total<C: GenericClient>(client: &C, tables: &Tables, sync_total: &SyncTotal) -> Result<(), tokio_postgres::Error> {
+	client.execute(&format!("INSERT INTO {} (estimated_channel_count, channel_count) VALUES ($1, $2)", tables.sync_totals()), &[
+		&sync_total.estimated_total.map(|estimated_total| estimated_total as i64),
+		&(sync_total.channel_count as i64),
+	]).await?;
+	Ok(())
+}
+
+/// The channel counts at the end of the latest initial sync, if any
+pub(crate) async fn fetch_previous_sync_total<C: GenericClient>(client: &C, tables: &Tables) -> Result<Option<SyncTotal>, tokio_postgres::Error> {
+	let row = client.query_opt(&format!("SELECT estimated_channel_count, channel_count FROM {} ORDER BY id DESC LIMIT 1", tables.sync_totals()), &[]).await?;
+	Ok(row.map(|row| SyncTotal {
+		estimated_total: row.get::<_, Option<i64>>(0).map(|estimated_total| estimated_total as u64),
+		channel_count: row.get::<_, i64>(1) as u64,
+	}))
+}
+
+#[cfg(test)]
+mod tests {
+	use bitcoin::secp256k1::{Secp256k1, SecretKey};
+
+	use super::*;
+
+	fn peer(byte: u8) -> PublicKey {
+		PublicKey::from_secret_key(&Secp256k1::new(), &SecretKey::from_slice(&[byte; 32]).unwrap())
+	}
+
+	#[test]
+	fn test_channel_count_estimate() {
+		let estimate = ChannelCountEstimate::new();
+		assert_eq!(estimate.total(), None);
+		// replies nobody asked for don't count
+		assert_eq!(estimate.record_reply(&peer(1), 100, true), None);
+
+		estimate.query_sent(peer(1));
+		estimate.query_sent(peer(2));
+		assert_eq!(estimate.record_reply(&peer(1), 8000, false), None);
+		assert_eq!(estimate.total(), None);
+		assert_eq!(estimate.record_reply(&peer(1), 2000, true), Some(10000));
+		assert_eq!(estimate.total(), Some(10000));
+
+		// the peer knowing of the most channels determines the estimate
+		assert_eq!(estimate.record_reply(&peer(2), 12000, true), Some(12000));
+		assert_eq!(estimate.total(), Some(12000));
+		estimate.query_sent(peer(1));
+		assert_eq!(estimate.record_reply(&peer(1), 11000, true), Some(11000));
+		assert_eq!(estimate.total(), Some(12000));
+	}
+
+	#[test]
+	fn test_progress_report() {
+		let mut progress = SyncProgress::new();
+		let start = Instant::now();
+		let report = progress.tick(1000, None, start);
+		assert_eq!(report.to_string(), "1000 channels of an unknown total");
+
+		let report = progress.tick(2000, Some(10000), start + Duration::from_secs(5));
+		assert_eq!(report.remaining_time, Some(Duration::from_secs(40)));
+		assert_eq!(report.to_string(), "2000/10000 channels (20.0%), about 1 minutes remaining");
+
+		// only the recent rate counts
+		progress.tick(2000, Some(10000), start + Duration::from_secs(65));
+		let report = progress.tick(2000, Some(10000), start + Duration::from_secs(70));
+		assert_eq!(report.remaining_time, None);
+		assert_eq!(report.to_string(), "2000/10000 channels (20.0%), remaining time unknown");
+	}
+
+	#[test]
+	fn test_catchup_progress() {
+		let mut counter = GossipCounter::new();
+		let progress = estimate_catchup_progress_with_average(&counter, 0, 75_000);
+		assert_eq!(progress, CatchupProgress { estimated_total_channels: 0, current_channels: 0, progress_pct: 0.0, eta_secs: None });
+		assert_eq!(progress.to_string(), "0.0% of ~0 channels, ETA unknown");
+
+		counter.channel_announcements = 30_000;
+		counter.recent_message_rate = 400.0;
+		let progress = estimate_catchup_progress_with_average(&counter, 2, 75_000);
+		assert_eq!(progress.estimated_total_channels, 150_000);
+		assert_eq!(progress.progress_pct, 20.0);
+		assert_eq!(progress.eta_secs, Some(300));
+		assert_eq!(progress.to_string(), "20.0% of ~150000 channels, ETA 300s");
+
+		// peers may know of more channels than assumed
+		counter.channel_announcements = 80_000;
+		let progress = estimate_catchup_progress_with_average(&counter, 1, 75_000);
+		assert_eq!(progress.progress_pct, 100.0);
+		assert_eq!(progress.eta_secs, Some(0));
+	}
+}
@@ -0,0 +1,74 @@
+use crate::config;
+
+/// Names of the database tables and indexes.
+///
+/// All names carry the configured table prefix, which allows multiple deployments (or other
+/// services' tables) to share a single schema. Every query must obtain its table names from here.
+#[derive(Clone, Debug)]
+pub(crate) struct Tables {
+	prefix: String,
+}
+
+impl Tables {
+	pub(crate) fn new(prefix: String) -> Self {
+		assert!(config::is_valid_identifier(&prefix), "Table prefix may only contain lowercase ASCII letters, digits, and underscores: {}", prefix);
+		Self { prefix }
+	}
+
+	pub(crate) fn from_config() -> Self {
+		Self::new(config::db_table_prefix())
+	}
+
+	pub(crate) fn prefix(&self) -> &str {
+		&self.prefix
+	}
+
+	pub(crate) fn config(&self) -> String {
+		self.prefixed("config")
+	}
+
+	pub(crate) fn channel_announcements(&self) -> String {
+		self.prefixed("channel_announcements")
+	}
+
+	pub(crate) fn channel_updates(&self) -> String {
+		self.prefixed("channel_updates")
+	}
+
+	pub(crate) fn node_announcements(&self) -> String {
+		self.prefixed("node_announcements")
+	}
+
+	pub(crate) fn channel_removals(&self) -> String {
+		self.prefixed("channel_removals")
+	}
+
+	pub(crate) fn stats_history(&self) -> String {
+		self.prefixed("stats_history")
+	}
+
+	pub(crate) fn peer_state(&self) -> String {
+		self.prefixed("peer_state")
+	}
+
+	pub(crate) fn snapshot_coverage(&self) -> String {
+		self.prefixed("snapshot_coverage")
+	}
+
+	pub(crate) fn sync_totals(&self) -> String {
+		self.prefixed("sync_totals")
+	}
+
+	pub(crate) fn dead_letters(&self) -> String {
+		self.prefixed("dead_letters")
+	}
+
+	/// Index and constraint names share a namespace with tables, so they need to be prefixed, too
+	pub(crate) fn index(&self, name: &str) -> String {
+		self.prefixed(name)
+	}
+
+	fn prefixed(&self, name: &str) -> String {
+		format!("{}{}", self.prefix, name)
+	}
+}
@@ -0,0 +1,227 @@
+//! The code paths the benchmarks measure, set up around synthetic gossip.
+//!
+//! Like the test server, [`BenchDatabase`] persists to Postgres through the same
+//! `RAPID_GOSSIP_SYNC_SERVER_DB_*` environment variables as the server itself, with every instance
+//! using a schema of its own. Everything else runs in memory.
+
+use std::collections::HashSet;
+use std::env;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use lightning::ln::msgs::{ChannelAnnouncement, ChannelUpdate, RoutingMessageHandler};
+use lightning::routing::gossip::NetworkGraph;
+use lightning::util::logger::{Logger, Record};
+use lightning::util::ser::{ReadableArgs, Writeable};
+use lightning_block_sync::http::HttpEndpoint;
+use tokio::runtime::Runtime;
+use tokio::sync::mpsc;
+
+use crate::chain_stats::ChainBackendStats;
+use crate::clock::SnapshotClock;
+use crate::config;
+use crate::downloader::{GossipCounter, GossipRouter};
+use crate::lookup::{AnnouncementDelta, ChannelDelta, DeltaSet, DirectedUpdateDelta, NodeDeltaSet, UpdateDelta};
+use crate::persistence::GossipPersister;
+use crate::serialization::{self, FeeDamping, SerializationSet};
+use crate::test_utils::{drop_db_schema, INSTANCE_COUNT, MOCK_CHANNEL_CAPACITY_SATS};
+use crate::test_utils::synthetic::SyntheticNetwork;
+use crate::types::GossipMessage;
+
+/// Discards all log records, lest printing them dominate the measurements
+pub struct SilentLogger;
+
+impl Logger for SilentLogger {
+	fn log(&self, _record: Record) {}
+}
+
+/// A network graph knowing all of a synthetic network's channels, and their latest updates
+pub fn network_graph(network: &SyntheticNetwork) -> Arc<NetworkGraph<Arc<SilentLogger>>> {
+	let network_graph = Arc::new(NetworkGraph::new(config::network(), Arc::new(SilentLogger)));
+	for announcement in &network.announcements {
+		network_graph.update_channel_from_announcement_no_lookup(announcement).expect("synthetic announcements must be valid");
+	}
+	for update in network.latest_updates() {
+		network_graph.update_channel_unsigned(&update.contents).expect("synthetic updates must be valid");
+	}
+	network_graph
+}
+
+/// Serialize the full snapshot of a synthetic network, from its latest updates, as seen at their
+/// timestamps. This skips the database lookups, leaving only the serialization to be measured.
+pub fn serialize_full_snapshot(network: &SyntheticNetwork) -> Vec<u8> {
+	crate::serialize_delta(&full_serialization_set(network), 2, Arc::new(SilentLogger)).data
+}
+
+/// Serialize [`serialize_full_snapshot`] in the first version, which is the one LDK's client applies
+pub fn serialize_full_v1_snapshot(network: &SyntheticNetwork) -> Vec<u8> {
+	crate::serialize_delta(&full_serialization_set(network), 1, Arc::new(SilentLogger)).data
+}
+
+/// Serialize the extended variant of [`serialize_full_snapshot`], carrying every channel's capacity
+pub fn serialize_full_extended_snapshot(network: &SyntheticNetwork) -> Vec<u8> {
+	crate::serialize_extended_delta(&full_serialization_set(network), Arc::new(SilentLogger)).data
+}
+
+fn full_serialization_set(network: &SyntheticNetwork) -> SerializationSet {
+	let mut delta_set = DeltaSet::new();
+	for announcement in &network.announcements {
+		delta_set.insert(announcement.contents.short_channel_id, ChannelDelta::default());
+	}
+	for update in network.latest_updates() {
+		let channel_delta = delta_set.get_mut(&update.contents.short_channel_id).expect("every update's channel is announced");
+		let directed_update_delta = DirectedUpdateDelta {
+			latest_update_after_seen: Some(UpdateDelta { seen: update.contents.timestamp, update: update.contents.clone() }),
+			..Default::default()
+		};
+		if update.contents.flags & 1 == 0 {
+			channel_delta.updates.0 = Some(directed_update_delta);
+		} else {
+			channel_delta.updates.1 = Some(directed_update_delta);
+		}
+		// channels are announced along with their first update
+		let first_seen = channel_delta.first_bidirectional_updates_seen.map_or(update.contents.timestamp, |seen| seen.min(update.contents.timestamp));
+		channel_delta.first_bidirectional_updates_seen = Some(first_seen);
+	}
+	for announcement in &network.announcements {
+		let channel_delta = delta_set.get_mut(&announcement.contents.short_channel_id).expect("every channel was just inserted");
+		let seen = channel_delta.first_bidirectional_updates_seen.unwrap_or_default();
+		channel_delta.announcement = Some(AnnouncementDelta { seen, announcement: announcement.contents.clone(), capacity_sats: Some(MOCK_CHANNEL_CAPACITY_SATS) });
+	}
+
+	serialization::serialize_delta_set(delta_set, NodeDeltaSet::new(), 0, SnapshotClock::wall_clock(), &FeeDamping::default())
+}
+
+/// Sets up routers for measuring how fast channel updates are ingested, which is everything
+/// between a peer's message arriving and its batch being handed to the persister
+pub struct IngestBench {
+	/// A graph knowing all of the network's channels, but none of their updates
+	serialized_graph: Vec<u8>,
+	update_count: usize,
+}
+
+impl IngestBench {
+	pub fn new(network: &SyntheticNetwork) -> Self {
+		let network_graph = NetworkGraph::new(config::network(), Arc::new(SilentLogger));
+		for announcement in &network.announcements {
+			network_graph.update_channel_from_announcement_no_lookup(announcement).expect("synthetic announcements must be valid");
+		}
+		Self { serialized_graph: network_graph.encode(), update_count: network.updates.len() }
+	}
+
+	/// A router around a fresh copy of the graph, whose persistence channel can hold all of the
+	/// network's updates, so that ingesting them once never waits for the channel to be drained
+	pub fn router(&self) -> IngestRouter {
+		let logger = Arc::new(SilentLogger);
+		let network_graph = Arc::new(NetworkGraph::read(&mut &self.serialized_graph[..], Arc::clone(&logger)).expect("the graph was just serialized"));
+		let (sender, receiver) = mpsc::channel(self.update_count / config::batch_size() + 2);
+		// updates don't need the chain backend, so its address doesn't matter
+		let chain_backend = HttpEndpoint::for_host("127.0.0.1".to_string()).with_port(1);
+		let router = GossipRouter::new(network_graph, sender, Arc::new(RwLock::new(GossipCounter::new())), chain_backend, Arc::new(ChainBackendStats::new()), logger);
+		IngestRouter { router, _receiver: receiver }
+	}
+}
+
+pub struct IngestRouter {
+	router: GossipRouter<Arc<SilentLogger>>,
+	/// Keeps the persistence channel open, standing in for the persister
+	_receiver: mpsc::Receiver<GossipMessage>,
+}
+
+impl IngestRouter {
+	/// Feed updates through the router as if a peer had sent them, returning how many of them the
+	/// graph accepted. Updates beyond `LDK_RGS_MAX_UPDATES_PER_SCID_PER_HOUR` per channel direction
+	/// are dropped by the rate limiter.
+	pub fn ingest(&self, updates: &[ChannelUpdate]) -> usize {
+		updates.iter().filter(|update| self.router.handle_channel_update(update).is_ok()).count()
+	}
+}
+
+/// A schema of its own for persisting gossip and generating snapshots from it, which is dropped
+/// along with the instance
+///
+/// The schema is configured through the environment, so instances must not be used concurrently.
+pub struct BenchDatabase {
+	db_schema: String,
+	network_graph: Arc<NetworkGraph<Arc<SilentLogger>>>,
+	runtime: Runtime,
+}
+
+impl BenchDatabase {
+	/// Set up a schema for the network, whose graph snapshots are generated from
+	pub fn new(network_graph: Arc<NetworkGraph<Arc<SilentLogger>>>) -> Self {
+		let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch");
+		let instance_index = INSTANCE_COUNT.fetch_add(1, Ordering::AcqRel);
+		let db_schema = format!("rgs_bench_{}_{}_{}", std::process::id(), timestamp.as_secs(), instance_index);
+		env::set_var("LDK_RGS_DB_SCHEMA", &db_schema);
+		let runtime = Runtime::new().expect("failed to create the benchmark runtime");
+		Self { db_schema, network_graph, runtime }
+	}
+
+	/// Persist gossip the way the router hands it to the persister, in full batches, returning once
+	/// all of it has been inserted. The gossip tables are created first, if they don't exist yet.
+	pub fn persist<'a>(&self, announcements: &[ChannelAnnouncement], updates: impl IntoIterator<Item = &'a ChannelUpdate>) {
+		let messages: Vec<GossipMessage> = announcements.iter().map(|announcement| GossipMessage::ChannelAnnouncement(announcement.clone(), None))
+			.chain(updates.into_iter().map(|update| GossipMessage::ChannelUpdate(update.clone(), None)))
+			.collect();
+		let (mut persister, sender) = GossipPersister::new(Arc::clone(&self.network_graph), Arc::new(SilentLogger));
+		self.runtime.block_on(async {
+			let batch_size = config::batch_size();
+			// the persistence channel only holds a few batches, so they're sent while persisting
+			let sending = tokio::spawn(async move {
+				let mut messages = messages.into_iter().peekable();
+				while messages.peek().is_some() {
+					let batch: Vec<GossipMessage> = messages.by_ref().take(batch_size).collect();
+					let receipt_times = vec![Instant::now(); batch.len()];
+					if sender.send(GossipMessage::Batch(batch, receipt_times)).await.is_err() {
+						break;
+					}
+				}
+			});
+			persister.persist_gossip().await.expect("failed to persist the synthetic gossip");
+			sending.await.expect("the batch sender panicked");
+		});
+		// the persister owns a runtime, which must not be dropped from an async context
+		drop(persister);
+	}
+
+	/// Persist a network's announcements and older updates, and after a second, its latest
+	/// updates, returning the unix timestamp of the client sync a delta snapshot would only
+	/// consist of the latest updates for
+	pub fn populate(&self, network: &SyntheticNetwork) -> u32 {
+		let latest_updates = network.latest_updates();
+		let latest_update_keys: HashSet<(u64, u8, u32)> = latest_updates.iter()
+			.map(|update| (update.contents.short_channel_id, update.contents.flags & 1, update.contents.timestamp))
+			.collect();
+		let older_updates = network.updates.iter()
+			.filter(|update| !latest_update_keys.contains(&(update.contents.short_channel_id, update.contents.flags & 1, update.contents.timestamp)));
+		self.persist(&network.announcements, older_updates);
+
+		// the seen timestamps snapshots are generated from have a granularity of a second
+		let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch");
+		thread::sleep(Duration::from_secs(1) - Duration::from_nanos(now.subsec_nanos() as u64));
+		let last_sync_timestamp = now.as_secs() as u32 + 1;
+		self.persist(&[], latest_updates);
+		last_sync_timestamp
+	}
+
+	/// Generate the snapshot for clients last synced at the given unix timestamp, looking the
+	/// gossip up from the database like the snapshotter does
+	pub fn generate_snapshot(&self, last_sync_timestamp: u32) -> Vec<u8> {
+		self.runtime.block_on(async {
+			let delta = crate::calculate_delta(Arc::clone(&self.network_graph), last_sync_timestamp, SnapshotClock::wall_clock(), Arc::new(SilentLogger)).await
+				.expect("failed to look up the synthetic gossip");
+			crate::serialize_delta(&delta, 2, Arc::new(SilentLogger)).data
+		})
+	}
+}
+
+impl Drop for BenchDatabase {
+	fn drop(&mut self) {
+		if let Err(error) = self.runtime.block_on(drop_db_schema(&self.db_schema)) {
+			eprintln!("Failed to drop benchmark schema {}: {}", self.db_schema, error);
+		}
+	}
+}
@@ -0,0 +1,414 @@
+//! A real server instance for tests of crates building on top of it, available with the
+//! `test-utils` feature.
+//!
+//! The server persists gossip to Postgres, which is configured through the same
+//! `RAPID_GOSSIP_SYNC_SERVER_DB_*` environment variables as the server itself, with every instance
+//! using a schema of its own. Its only peer is an in-process LDK node, and channel announcements
+//! are verified against an in-process mock of bitcoind's REST interface, both on random ports.
+//!
+//! Synthetic gossip to feed it, or the benchmarks, is generated by [`synthetic`], and [`bench`]
+//! sets up the code paths the benchmarks measure.
+
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bitcoin::absolute::LockTime;
+use bitcoin::blockdata::block::{Block, Header, Version};
+use bitcoin::blockdata::transaction::{Transaction, TxOut};
+use bitcoin::hashes::Hash;
+use bitcoin::secp256k1::PublicKey;
+use bitcoin::{BlockHash, CompactTarget, TxMerkleNode};
+use lightning::events::{MessageSendEvent, MessageSendEventsProvider};
+use lightning::ln::chan_utils::make_funding_redeemscript;
+use lightning::ln::features::{InitFeatures, NodeFeatures};
+use lightning::ln::msgs::{ChannelAnnouncement, ChannelUpdate, Init, LightningError, NodeAnnouncement, QueryChannelRange, QueryShortChannelIds, ReplyChannelRange, ReplyShortChannelIdsEnd, RoutingMessageHandler};
+use lightning::ln::peer_handler::{ErroringMessageHandler, IgnoringMessageHandler, MessageHandler, PeerManager};
+use lightning::log_error;
+use lightning::routing::gossip::{NetworkGraph, NodeId, P2PGossipSync};
+use lightning::routing::utxo::UtxoLookup;
+use lightning::sign::{KeysManager, NodeSigner, Recipient};
+use lightning_block_sync::http::HttpEndpoint;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Notify;
+use tokio_postgres::NoTls;
+
+use crate::config;
+use crate::downloader::GossipCounter;
+use crate::types::RGSSLogger;
+use crate::RapidSyncProcessor;
+
+pub mod bench;
+pub mod synthetic;
+
+/// The height of the mock chain's tip. Injected channels must be funded sufficiently far below it.
+pub const MOCK_CHAIN_TIP_HEIGHT: u32 = 10_000;
+
+/// The capacity of every injected channel, which their updates' maximum HTLC amounts must not exceed
+pub const MOCK_CHANNEL_CAPACITY_SATS: u64 = 1_000_000;
+
+/// Distinguishes the schemas and cache directories of the instances started by one process
+static INSTANCE_COUNT: AtomicU32 = AtomicU32::new(0);
+
+type PeerGossipSync = P2PGossipSync<Arc<NetworkGraph<Arc<RGSSLogger>>>, Arc<dyn UtxoLookup + Send + Sync>, Arc<RGSSLogger>>;
+
+/// A server syncing gossip from a mock peer, which injected gossip is fed through. It's shut down,
+/// and its schema and cache directory are removed, when it's dropped.
+///
+/// The server's configuration is read from the environment, so instances started concurrently
+/// within the same process must not be configured differently.
+pub struct TestGossipServer {
+	network_graph: Arc<NetworkGraph<Arc<RGSSLogger>>>,
+	gossip_counter: Arc<RwLock<GossipCounter>>,
+	peer: Arc<MockPeerRouter>,
+	chain: Arc<MockChain>,
+	cache_path: String,
+	shutdown: Arc<Notify>,
+	server_thread: Option<JoinHandle<()>>,
+}
+
+impl TestGossipServer {
+	pub fn new() -> Self {
+		let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch");
+		let instance_index = INSTANCE_COUNT.fetch_add(1, Ordering::AcqRel);
+		let instance_id = format!("{}_{}_{}", std::process::id(), timestamp.as_secs(), instance_index);
+		let db_schema = format!("rgs_test_{}", instance_id);
+		env::set_var("LDK_RGS_DB_SCHEMA", &db_schema);
+		env::set_var("RAPID_GOSSIP_SYNC_SERVER_CACHES_PATH", format!("{}/rgs_test_{}", env::temp_dir().display(), instance_id));
+		let cache_path = config::cache_path();
+
+		let logger = Arc::new(RGSSLogger::new());
+		let mut processor = RapidSyncProcessor::new(Arc::clone(&logger));
+		let network_graph = Arc::clone(&processor.network_graph);
+		let gossip_counter = Arc::clone(&processor.gossip_counter);
+		let shutdown = Arc::clone(&processor.shutdown);
+		let peer = Arc::new(MockPeerRouter::new(Arc::clone(&logger)));
+		let chain = Arc::new(MockChain::new());
+
+		let mut peer_seed = [0u8; 32];
+		peer_seed[..16].copy_from_slice(&timestamp.as_nanos().to_be_bytes());
+		peer_seed[16..20].copy_from_slice(&instance_index.to_be_bytes());
+		let server_peer = Arc::clone(&peer);
+		let server_chain = Arc::clone(&chain);
+		// the server's persister owns a runtime, which must not be dropped from an async context,
+		// so the server gets a runtime of its own, on a thread of its own
+		let server_thread = thread::spawn(move || {
+			let runtime = tokio::runtime::Runtime::new().expect("failed to create the test server runtime");
+			runtime.block_on(async {
+				let chain_address = MockChain::spawn(server_chain).await;
+				let peer_address = MockPeerRouter::spawn(server_peer, peer_seed, Arc::clone(&logger)).await;
+				processor.set_peers(vec![peer_address]);
+				processor.set_chain_backend(HttpEndpoint::for_host(chain_address.ip().to_string())
+					.with_port(chain_address.port())
+					.with_path("/rest/".to_string()));
+				if let Err(error) = processor.start_sync().await {
+					log_error!(logger, "Test server stopped: {}", error);
+				}
+				drop(processor);
+				if let Err(error) = drop_db_schema(&db_schema).await {
+					log_error!(logger, "Failed to drop test schema {}: {}", db_schema, error);
+				}
+			});
+		});
+
+		Self { network_graph, gossip_counter, peer, chain, cache_path, shutdown, server_thread: Some(server_thread) }
+	}
+
+	/// Where the snapshots are published, with the one for clients that have never synced at
+	/// `{base_url}/0.bin`. The HTTP front end would serve the same files.
+	pub fn base_url(&self) -> String {
+		format!("file://{}/symlinks", self.cache_path)
+	}
+
+	/// The server's network graph
+	pub fn graph(&self) -> Arc<NetworkGraph<Arc<RGSSLogger>>> {
+		Arc::clone(&self.network_graph)
+	}
+
+	/// Have the mock peer announce a channel, whose funding output is added to the mock chain.
+	/// The announcement must be validly signed, and funded at least the configured number of
+	/// confirmations below [`MOCK_CHAIN_TIP_HEIGHT`].
+	pub fn inject_announcement(&self, announcement: ChannelAnnouncement) {
+		self.chain.fund(&announcement);
+		self.peer.inject(MessageSendEvent::BroadcastChannelAnnouncement { msg: announcement, update_msg: None });
+	}
+
+	/// Have the mock peer relay an update for a previously injected channel
+	pub fn inject_update(&self, update: ChannelUpdate) {
+		self.peer.inject(MessageSendEvent::BroadcastChannelUpdate { msg: update });
+	}
+
+	/// Wait for the server to consider itself caught up with the injected gossip, which takes at
+	/// least two of its tracking iterations once any has been injected, panicking on timeout
+	pub fn wait_for_catchup(&self, timeout: Duration) {
+		let started_at = Instant::now();
+		while !self.gossip_counter.read().expect("gossip counter lock poisoned").is_caught_up {
+			assert!(started_at.elapsed() < timeout, "the test server didn't catch up with gossip within {:?}", timeout);
+			thread::sleep(Duration::from_millis(100));
+		}
+	}
+}
+
+impl Drop for TestGossipServer {
+	fn drop(&mut self) {
+		self.shutdown.notify_one();
+		if let Some(server_thread) = self.server_thread.take() {
+			let _ = server_thread.join();
+		}
+		let _ = fs::remove_dir_all(&self.cache_path);
+	}
+}
+
+async fn drop_db_schema(db_schema: &str) -> Result<(), tokio_postgres::Error> {
+	let (client, connection) = config::db_connection_config().connect(NoTls).await?;
+	tokio::spawn(connection);
+	client.execute(&format!("DROP SCHEMA IF EXISTS {} CASCADE", db_schema), &[]).await?;
+	Ok(())
+}
+
+/// The mock peer's gossip handler, which keeps injected gossip to serve to peers connecting
+/// later, and relays it to the connected ones
+struct MockPeerRouter {
+	network_graph: Arc<NetworkGraph<Arc<RGSSLogger>>>,
+	native_router: PeerGossipSync,
+	injected_events: Mutex<Vec<MessageSendEvent>>,
+}
+
+impl MockPeerRouter {
+	fn new(logger: Arc<RGSSLogger>) -> Self {
+		let network_graph = Arc::new(NetworkGraph::new(config::network(), Arc::clone(&logger)));
+		let native_router = P2PGossipSync::new(Arc::clone(&network_graph), None::<Arc<dyn UtxoLookup + Send + Sync>>, logger);
+		Self { network_graph, native_router, injected_events: Mutex::new(Vec::new()) }
+	}
+
+	fn inject(&self, event: MessageSendEvent) {
+		match &event {
+			MessageSendEvent::BroadcastChannelAnnouncement { msg, .. } => {
+				self.network_graph.update_channel_from_announcement_no_lookup(msg).expect("injected channel announcement must be valid");
+			},
+			MessageSendEvent::BroadcastChannelUpdate { msg } => {
+				self.network_graph.update_channel(msg).expect("injected channel update must be valid");
+			},
+			_ => unreachable!(),
+		}
+		self.injected_events.lock().expect("injected event lock poisoned").push(event);
+	}
+
+	/// Accept connections on a random port, returning the peer's node id and address
+	async fn spawn(router: Arc<Self>, seed: [u8; 32], logger: Arc<RGSSLogger>) -> (PublicKey, SocketAddr) {
+		let keys_manager = Arc::new(KeysManager::new(&seed, 0, 0));
+		let node_id = keys_manager.get_node_id(Recipient::Node).expect("the node id must be derivable");
+		let message_handler = MessageHandler {
+			chan_handler: ErroringMessageHandler::new(),
+			route_handler: router,
+			onion_message_handler: IgnoringMessageHandler {},
+			custom_message_handler: IgnoringMessageHandler {},
+		};
+		let peer_manager = Arc::new(PeerManager::new(message_handler, 0, &seed, logger, keys_manager));
+
+		let listener = TcpListener::bind("127.0.0.1:0").await.expect("failed to bind the mock peer");
+		let address = listener.local_addr().expect("the mock peer must have an address");
+		let accepting_peer_manager = Arc::clone(&peer_manager);
+		tokio::spawn(async move {
+			while let Ok((stream, _)) = listener.accept().await {
+				if let Ok(stream) = stream.into_std() {
+					tokio::spawn(lightning_net_tokio::setup_inbound(Arc::clone(&accepting_peer_manager), stream));
+				}
+			}
+		});
+		// gossip is only sent as the peer's events are processed
+		tokio::spawn(async move {
+			let mut interval = tokio::time::interval(Duration::from_millis(100));
+			loop {
+				interval.tick().await;
+				peer_manager.process_events();
+			}
+		});
+		(node_id, address)
+	}
+}
+
+impl MessageSendEventsProvider for MockPeerRouter {
+	fn get_and_clear_pending_msg_events(&self) -> Vec<MessageSendEvent> {
+		let mut msg_events = self.native_router.get_and_clear_pending_msg_events();
+		msg_events.append(&mut self.injected_events.lock().expect("injected event lock poisoned"));
+		msg_events
+	}
+}
+
+impl RoutingMessageHandler for MockPeerRouter {
+	fn handle_node_announcement(&self, msg: &NodeAnnouncement) -> Result<bool, LightningError> {
+		self.native_router.handle_node_announcement(msg)
+	}
+
+	fn handle_channel_announcement(&self, msg: &ChannelAnnouncement) -> Result<bool, LightningError> {
+		self.native_router.handle_channel_announcement(msg)
+	}
+
+	fn handle_channel_update(&self, msg: &ChannelUpdate) -> Result<bool, LightningError> {
+		self.native_router.handle_channel_update(msg)
+	}
+
+	fn get_next_channel_announcement(&self, starting_point: u64) -> Option<(ChannelAnnouncement, Option<ChannelUpdate>, Option<ChannelUpdate>)> {
+		self.native_router.get_next_channel_announcement(starting_point)
+	}
+
+	fn get_next_node_announcement(&self, starting_point: Option<&NodeId>) -> Option<NodeAnnouncement> {
+		self.native_router.get_next_node_announcement(starting_point)
+	}
+
+	fn peer_connected(&self, their_node_id: &PublicKey, init: &Init, inbound: bool) -> Result<(), ()> {
+		self.native_router.peer_connected(their_node_id, init, inbound)
+	}
+
+	fn handle_reply_channel_range(&self, their_node_id: &PublicKey, msg: ReplyChannelRange) -> Result<(), LightningError> {
+		self.native_router.handle_reply_channel_range(their_node_id, msg)
+	}
+
+	fn handle_reply_short_channel_ids_end(&self, their_node_id: &PublicKey, msg: ReplyShortChannelIdsEnd) -> Result<(), LightningError> {
+		self.native_router.handle_reply_short_channel_ids_end(their_node_id, msg)
+	}
+
+	fn handle_query_channel_range(&self, their_node_id: &PublicKey, msg: QueryChannelRange) -> Result<(), LightningError> {
+		self.native_router.handle_query_channel_range(their_node_id, msg)
+	}
+
+	fn handle_query_short_channel_ids(&self, their_node_id: &PublicKey, msg: QueryShortChannelIds) -> Result<(), LightningError> {
+		self.native_router.handle_query_short_channel_ids(their_node_id, msg)
+	}
+
+	fn processing_queue_high(&self) -> bool {
+		self.native_router.processing_queue_high()
+	}
+
+	fn provided_node_features(&self) -> NodeFeatures {
+		self.native_router.provided_node_features()
+	}
+
+	fn provided_init_features(&self, their_node_id: &PublicKey) -> InitFeatures {
+		self.native_router.provided_init_features(their_node_id)
+	}
+}
+
+/// The blocks of the mock chain, which are empty unless they fund an injected channel
+struct MockChain {
+	blocks: Mutex<HashMap<u32, Block>>,
+}
+
+impl MockChain {
+	fn new() -> Self {
+		Self { blocks: Mutex::new(HashMap::new()) }
+	}
+
+	/// Add the funding output of a channel to the block and transaction its short channel id
+	/// points to
+	fn fund(&self, announcement: &ChannelAnnouncement) {
+		let short_channel_id = announcement.contents.short_channel_id;
+		let height = (short_channel_id >> 40) as u32;
+		let transaction_index = ((short_channel_id >> 16) & 0xffffff) as usize;
+		let output_index = (short_channel_id & 0xffff) as usize;
+		assert!(height <= MOCK_CHAIN_TIP_HEIGHT, "channel {} is funded above the mock chain tip", short_channel_id);
+
+		let bitcoin_key = |node_id: &NodeId| node_id.as_pubkey().expect("injected channel must have valid bitcoin keys");
+		let funding_script = make_funding_redeemscript(&bitcoin_key(&announcement.contents.bitcoin_key_1), &bitcoin_key(&announcement.contents.bitcoin_key_2)).to_v0_p2wsh();
+		let funding_output = TxOut { value: MOCK_CHANNEL_CAPACITY_SATS, script_pubkey: funding_script };
+
+		let mut blocks = self.blocks.lock().expect("mock chain lock poisoned");
+		let block = blocks.entry(height).or_insert_with(|| empty_block(height));
+		while block.txdata.len() <= transaction_index {
+			block.txdata.push(Transaction { version: 2, lock_time: LockTime::ZERO, input: vec![], output: vec![] });
+		}
+		let transaction = &mut block.txdata[transaction_index];
+		while transaction.output.len() <= output_index {
+			// placeholders keep the transactions distinct
+			transaction.output.push(TxOut { value: transaction.output.len() as u64, script_pubkey: Default::default() });
+		}
+		transaction.output[output_index] = funding_output;
+	}
+
+	fn block_at(&self, height: u32) -> Option<Block> {
+		if height > MOCK_CHAIN_TIP_HEIGHT {
+			return None;
+		}
+		let mut blocks = self.blocks.lock().expect("mock chain lock poisoned");
+		Some(blocks.entry(height).or_insert_with(|| empty_block(height)).clone())
+	}
+
+	/// Serve the few bitcoind REST requests channel verification makes on a random port,
+	/// returning its address
+	async fn spawn(chain: Arc<Self>) -> SocketAddr {
+		let listener = TcpListener::bind("127.0.0.1:0").await.expect("failed to bind the mock chain backend");
+		let address = listener.local_addr().expect("the mock chain backend must have an address");
+		tokio::spawn(async move {
+			while let Ok((stream, _)) = listener.accept().await {
+				tokio::spawn(Self::serve_rest_connection(Arc::clone(&chain), stream));
+			}
+		});
+		address
+	}
+
+	async fn serve_rest_connection(chain: Arc<Self>, stream: TcpStream) {
+		let (reader, mut writer) = stream.into_split();
+		let mut lines = BufReader::new(reader).lines();
+		// the client keeps its connection alive across requests
+		while let Ok(Some(request_line)) = lines.next_line().await {
+			// GET requests have no body, so skipping past the headers consumes the entire request
+			loop {
+				match lines.next_line().await {
+					Ok(Some(header)) if !header.trim().is_empty() => continue,
+					Ok(Some(_)) => break,
+					_ => return,
+				}
+			}
+
+			let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+			let (status, body) = match chain.rest_response(path) {
+				Some(body) => ("200 OK", body),
+				None => ("404 Not Found", b"not found".to_vec()),
+			};
+			let head = format!("HTTP/1.1 {}\r\nContent-Length: {}\r\n\r\n", status, body.len());
+			if writer.write_all(head.as_bytes()).await.is_err() || writer.write_all(&body).await.is_err() {
+				return;
+			}
+		}
+	}
+
+	fn rest_response(&self, path: &str) -> Option<Vec<u8>> {
+		let resource = path.strip_prefix("/rest/")?;
+		if resource == "chaininfo.json" {
+			let tip_hash = self.block_at(MOCK_CHAIN_TIP_HEIGHT)?.block_hash();
+			return Some(format!("{{\"chain\":\"{}\",\"blocks\":{},\"bestblockhash\":\"{}\"}}", config::network().to_core_arg(), MOCK_CHAIN_TIP_HEIGHT, tip_hash).into_bytes());
+		}
+		if let Some(height) = resource.strip_prefix("blockhashbyheight/").and_then(|resource| resource.strip_suffix(".bin")) {
+			let block = self.block_at(height.parse().ok()?)?;
+			return Some(block.block_hash().to_byte_array().to_vec());
+		}
+		if let Some(block_hash) = resource.strip_prefix("block/").and_then(|resource| resource.strip_suffix(".bin")) {
+			let blocks = self.blocks.lock().expect("mock chain lock poisoned");
+			let block = blocks.values().find(|block| block.block_hash().to_string() == block_hash)?;
+			return Some(bitcoin::consensus::encode::serialize(block));
+		}
+		None
+	}
+}
+
+fn empty_block(height: u32) -> Block {
+	Block {
+		header: Header {
+			version: Version::ONE,
+			prev_blockhash: BlockHash::all_zeros(),
+			merkle_root: TxMerkleNode::all_zeros(),
+			// keeps the block hashes distinct
+			time: height,
+			bits: CompactTarget::from_consensus(0x207fffff),
+			nonce: 0,
+		},
+		txdata: vec![],
+	}
+}
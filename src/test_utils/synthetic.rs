@@ -0,0 +1,228 @@
+//! Deterministic synthetic gossip resembling mainnet's, shared by the benchmarks and end-to-end
+//! tests.
+//!
+//! Nodes' degrees follow a power law, with few hubs and many small nodes. Channels are funded
+//! mostly in recent blocks, and mostly early within them. Each channel direction is updated about
+//! daily, and most updates keep the previous fees, while the rest move to common fee levels.
+
+use std::collections::HashSet;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bitcoin::blockdata::constants::ChainHash;
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use bitcoin::secp256k1::{All, Message, PublicKey, Secp256k1, SecretKey};
+use bitcoin::secp256k1::ecdsa::Signature;
+use lightning::ln::features::ChannelFeatures;
+use lightning::ln::msgs::{ChannelAnnouncement, ChannelUpdate, UnsignedChannelAnnouncement, UnsignedChannelUpdate};
+use lightning::routing::gossip::NodeId;
+use lightning::util::ser::Writeable;
+
+use crate::config;
+use crate::test_utils::{MOCK_CHAIN_TIP_HEIGHT, MOCK_CHANNEL_CAPACITY_SATS};
+
+const DAY_SECS: u32 = 24 * 3600;
+
+/// Proportional fees commonly seen on mainnet, and how often each is chosen
+const FEE_RATE_WEIGHTS: [(u32, u32); 8] = [(1, 10), (10, 15), (50, 15), (100, 20), (200, 15), (500, 12), (1000, 8), (2500, 5)];
+const CLTV_EXPIRY_DELTA_WEIGHTS: [(u16, u32); 3] = [(40, 40), (80, 30), (144, 30)];
+
+/// The shape of the generated network
+#[derive(Clone, Debug)]
+pub struct SyntheticNetworkParams {
+	pub node_count: usize,
+	pub channel_count: usize,
+	/// The number of updates per channel direction, about a day apart. Beyond
+	/// `LDK_RGS_MAX_UPDATES_PER_SCID_PER_HOUR`, a server receiving them at once drops the rest.
+	pub updates_per_direction: usize,
+	/// The timestamp the latest updates precede by less than an hour
+	pub latest_timestamp: u32,
+	/// The highest block channels are funded in, which must leave room for the required
+	/// confirmations below [`MOCK_CHAIN_TIP_HEIGHT`] for the test server to accept them
+	pub max_funding_height: u32,
+	/// Networks generated with the same parameters, including the seed, are identical
+	pub seed: u64,
+}
+
+impl Default for SyntheticNetworkParams {
+	fn default() -> Self {
+		Self {
+			node_count: 1_000,
+			channel_count: 4_000,
+			updates_per_direction: 3,
+			latest_timestamp: SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch").as_secs() as u32 - 60,
+			max_funding_height: MOCK_CHAIN_TIP_HEIGHT - 100,
+			seed: 1,
+		}
+	}
+}
+
+/// Validly signed gossip for a synthetic network
+pub struct SyntheticNetwork {
+	/// The channel announcements, ordered by short channel id
+	pub announcements: Vec<ChannelAnnouncement>,
+	/// The updates of all channel directions, ordered by timestamp as peers would relay them
+	pub updates: Vec<ChannelUpdate>,
+}
+
+impl SyntheticNetwork {
+	pub fn generate(params: &SyntheticNetworkParams) -> Self {
+		assert!(params.node_count >= 2, "a channel needs two nodes");
+		assert!(params.max_funding_height >= 1, "channels can't be funded in the genesis block");
+		let secp_context = Secp256k1::new();
+		let mut rng = Xorshift::new(params.seed);
+		let node_keys: Vec<(SecretKey, PublicKey)> = (0..params.node_count).map(|_| {
+			let secret_key = loop {
+				let mut key_bytes = [0u8; 32];
+				for chunk in key_bytes.chunks_mut(8) {
+					chunk.copy_from_slice(&rng.next_u64().to_be_bytes());
+				}
+				if let Ok(secret_key) = SecretKey::from_slice(&key_bytes) {
+					break secret_key;
+				}
+			};
+			(secret_key, secret_key.public_key(&secp_context))
+		}).collect();
+
+		let mut short_channel_ids = HashSet::new();
+		let mut announcements = Vec::with_capacity(params.channel_count);
+		let mut updates = Vec::with_capacity(params.channel_count * 2 * params.updates_per_direction);
+		for _ in 0..params.channel_count {
+			let short_channel_id = loop {
+				// most channels are recent, and funded early within their block
+				let height = params.max_funding_height - ((params.max_funding_height - 1) as f64 * rng.unit().powi(2)) as u32;
+				let transaction_index = (3_000.0 * rng.unit().powi(3)) as u64;
+				let output_index = if rng.below(10) < 7 { 0 } else { 1 };
+				let short_channel_id = ((height as u64) << 40) | (transaction_index << 16) | output_index;
+				if short_channel_ids.insert(short_channel_id) {
+					break short_channel_id;
+				}
+			};
+
+			// few hubs take part in most channels
+			let node_a = (params.node_count as f64 * rng.unit().powi(3)) as usize;
+			let node_b = loop {
+				let node_b = (params.node_count as f64 * rng.unit().powi(3)) as usize;
+				if node_b != node_a {
+					break node_b;
+				}
+			};
+			// announcements must list the lexicographically lesser node first
+			let mut keys = [node_keys[node_a], node_keys[node_b]];
+			keys.sort_by_key(|(_, public_key)| public_key.serialize());
+
+			let contents = UnsignedChannelAnnouncement {
+				features: ChannelFeatures::empty(),
+				chain_hash: ChainHash::using_genesis_block(config::network()),
+				short_channel_id,
+				node_id_1: NodeId::from_pubkey(&keys[0].1),
+				node_id_2: NodeId::from_pubkey(&keys[1].1),
+				bitcoin_key_1: NodeId::from_pubkey(&keys[0].1),
+				bitcoin_key_2: NodeId::from_pubkey(&keys[1].1),
+				excess_data: vec![],
+			};
+			let signatures = keys.map(|(secret_key, _)| sign(&secp_context, &contents, &secret_key));
+			announcements.push(ChannelAnnouncement {
+				node_signature_1: signatures[0],
+				node_signature_2: signatures[1],
+				bitcoin_signature_1: signatures[0],
+				bitcoin_signature_2: signatures[1],
+				contents,
+			});
+
+			for direction in 0..2u8 {
+				let mut fee_base_msat = match rng.below(100) {
+					0..=59 => 1_000,
+					60..=84 => 0,
+					85..=94 => 1,
+					_ => rng.below(5_000) as u32,
+				};
+				let mut fee_proportional_millionths = rng.weighted(&FEE_RATE_WEIGHTS);
+				let cltv_expiry_delta = rng.weighted(&CLTV_EXPIRY_DELTA_WEIGHTS);
+				let htlc_minimum_msat = if rng.below(10) < 8 { 1_000 } else { 1 };
+				let htlc_maximum_msat = MOCK_CHANNEL_CAPACITY_SATS * 10 * [50, 90, 99][rng.below(3) as usize];
+				for update_index in (0..params.updates_per_direction as u32).rev() {
+					if update_index + 1 < params.updates_per_direction as u32 && rng.below(10) < 3 {
+						fee_base_msat = if rng.below(2) == 0 { 0 } else { 1_000 };
+						fee_proportional_millionths = rng.weighted(&FEE_RATE_WEIGHTS);
+					}
+					let disabled = if rng.below(20) == 0 { 2 } else { 0 };
+					let contents = UnsignedChannelUpdate {
+						chain_hash: ChainHash::using_genesis_block(config::network()),
+						short_channel_id,
+						// the jitter stays below the spacing, so that each update is newer
+						timestamp: params.latest_timestamp - update_index * DAY_SECS - rng.below(3_600) as u32,
+						flags: direction | disabled,
+						cltv_expiry_delta,
+						htlc_minimum_msat,
+						htlc_maximum_msat,
+						fee_base_msat,
+						fee_proportional_millionths,
+						excess_data: vec![],
+					};
+					let signature = sign(&secp_context, &contents, &keys[direction as usize].0);
+					updates.push(ChannelUpdate { signature, contents });
+				}
+			}
+		}
+
+		announcements.sort_by_key(|announcement| announcement.contents.short_channel_id);
+		updates.sort_by_key(|update| update.contents.timestamp);
+		Self { announcements, updates }
+	}
+
+	/// The latest update of every channel direction
+	pub fn latest_updates(&self) -> Vec<&ChannelUpdate> {
+		let mut latest_updates = std::collections::HashMap::new();
+		for update in &self.updates {
+			// updates are ordered by timestamp, so later ones replace earlier ones
+			latest_updates.insert((update.contents.short_channel_id, update.contents.flags & 1), update);
+		}
+		let mut latest_updates: Vec<&ChannelUpdate> = latest_updates.into_values().collect();
+		latest_updates.sort_by_key(|update| (update.contents.short_channel_id, update.contents.flags & 1));
+		latest_updates
+	}
+}
+
+fn sign<T: Writeable>(secp_context: &Secp256k1<All>, contents: &T, key: &SecretKey) -> Signature {
+	let msg_hash = Message::from_slice(&Sha256dHash::hash(&contents.encode()[..])[..]).expect("hashes are valid messages");
+	secp_context.sign_ecdsa(&msg_hash, key)
+}
+
+/// A small deterministic generator, as the data only needs to look random
+struct Xorshift(u64);
+
+impl Xorshift {
+	fn new(seed: u64) -> Self {
+		// the state must not be zero
+		Self(seed.wrapping_mul(0x9e37_79b9_7f4a_7c15) | 1)
+	}
+
+	fn next_u64(&mut self) -> u64 {
+		self.0 ^= self.0 << 13;
+		self.0 ^= self.0 >> 7;
+		self.0 ^= self.0 << 17;
+		self.0
+	}
+
+	fn below(&mut self, bound: u64) -> u64 {
+		self.next_u64() % bound
+	}
+
+	/// A uniformly distributed value in [0, 1)
+	fn unit(&mut self) -> f64 {
+		(self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+	}
+
+	fn weighted<T: Copy>(&mut self, weights: &[(T, u32)]) -> T {
+		let total: u32 = weights.iter().map(|(_, weight)| weight).sum();
+		let mut choice = self.below(total as u64) as u32;
+		for (value, weight) in weights {
+			if choice < *weight {
+				return *value;
+			}
+			choice -= weight;
+		}
+		unreachable!("the choice is below the total weight")
+	}
+}
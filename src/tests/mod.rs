@@ -1,31 +1,67 @@
 //! Multi-module tests that use database fixtures
 
 use std::cell::RefCell;
-use std::sync::Arc;
+use std::collections::HashMap;
+use std::io::Cursor;
+use std::net::SocketAddr;
+use std::sync::{Arc, RwLock};
 use std::{fs, thread};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 use bitcoin::blockdata::constants::ChainHash;
-use bitcoin::Network;
+use bitcoin::{Network, Transaction, TxOut};
+use bitcoin::absolute::LockTime;
 use bitcoin::secp256k1::ecdsa::Signature;
 use bitcoin::secp256k1::{Secp256k1, SecretKey};
 use bitcoin::hashes::Hash;
 use bitcoin::hashes::sha256d::Hash as Sha256dHash;
 use hex_conservative::DisplayHex;
+use lightning::ln::chan_utils::make_funding_redeemscript;
 use lightning::ln::features::{ChannelFeatures, NodeFeatures};
-use lightning::ln::msgs::{ChannelAnnouncement, ChannelUpdate, NodeAnnouncement, SocketAddress, UnsignedChannelAnnouncement, UnsignedChannelUpdate, UnsignedNodeAnnouncement};
+use lightning::ln::msgs::{ChannelAnnouncement, ChannelUpdate, NodeAnnouncement, RoutingMessageHandler, SocketAddress, UnsignedChannelAnnouncement, UnsignedChannelUpdate, UnsignedNodeAnnouncement};
 use lightning::routing::gossip::{NetworkGraph, NodeAlias, NodeId};
-use lightning::util::ser::Writeable;
+use lightning::routing::utxo::{UtxoLookup, UtxoResult};
+use lightning::util::ser::{Readable, Writeable};
+use lightning_block_sync::http::HttpEndpoint;
 use lightning_rapid_gossip_sync::RapidGossipSync;
-use crate::{calculate_delta, config, serialize_delta};
-use crate::persistence::GossipPersister;
-use crate::snapshot::Snapshotter;
+use tokio::sync::mpsc;
+use crate::{calculate_delta, config, serialize_delta, SignedGossipQuery};
+use crate::analytics_export;
+use crate::batcher::MessageBatcher;
+use crate::chain_stats::ChainBackendStats;
+use crate::client_usage::ClientUsageTotals;
+use crate::clock::SnapshotClock;
+use crate::dormancy::{self, DormancyPolicy, DormancyTracker, FailureHistory};
+use crate::downloader::{GossipCounter, GossipRouter};
+use crate::error::ProcessorError;
+use crate::freshness;
+use crate::graph_dump;
+use crate::maintenance::TableBloat;
+use crate::canary::{self, CanaryFailure};
+use crate::chain_check::{self, ChannelDivergence};
+use crate::compact_graph::{self, CompactGraph, DirectionalCoverage};
+use crate::pacing::LookupPacing;
+use crate::peer_registry::{self, AddressBook};
+use crate::persistence::{self, GossipPersister};
+use crate::reachability::{self, ReachabilityScore};
+use crate::role::{RoleState, ServerRole};
+use crate::routing_hints;
+use crate::scid::ShortChannelId;
+use crate::serialization::FeeDamping;
+use crate::snapshot::{SnapshotRetentionPolicy, Snapshotter};
+use crate::snapshot_reader;
+use crate::{stats, stats_history};
+use crate::sync_progress::{self, SyncTotal};
+use crate::tables::Tables;
 use crate::types::{GossipMessage, tests::TestLogger};
+use crate::validation::{ValidationPool, ValidationResult};
+use crate::verifier::tests::{scripted_verifier, ScriptedChain};
 
 const CLIENT_BACKDATE_INTERVAL: u32 = 3600 * 24 * 7; // client backdates RGS by a week
 
 thread_local! {
 	static DB_TEST_SCHEMA: RefCell<Option<String>> = RefCell::new(None);
 	static IS_TEST_SCHEMA_CLEAN: RefCell<Option<bool>> = RefCell::new(None);
+	static DB_TEST_TABLE_PREFIX: RefCell<Option<String>> = RefCell::new(None);
 }
 
 fn blank_signature() -> Signature {
@@ -47,6 +83,16 @@ pub(crate) fn db_test_schema() -> String {
 	})
 }
 
+pub(crate) fn db_test_table_prefix() -> Option<String> {
+	DB_TEST_TABLE_PREFIX.with(|prefix_reference| prefix_reference.borrow().clone())
+}
+
+fn set_db_test_table_prefix(prefix: Option<&str>) {
+	DB_TEST_TABLE_PREFIX.with(|prefix_reference| {
+		*prefix_reference.borrow_mut() = prefix.map(|prefix| prefix.to_string());
+	});
+}
+
 fn generate_node_announcement(private_key: Option<SecretKey>) -> NodeAnnouncement {
 	let secp_context = Secp256k1::new();
 
@@ -76,13 +122,15 @@ fn generate_node_announcement(private_key: Option<SecretKey>) -> NodeAnnouncemen
 
 
 fn generate_channel_announcement(short_channel_id: u64) -> ChannelAnnouncement {
+	generate_channel_announcement_with_keys(short_channel_id, SecretKey::from_slice(&[1; 32]).unwrap(), SecretKey::from_slice(&[2; 32]).unwrap())
+}
+
+fn generate_channel_announcement_with_keys(short_channel_id: u64, random_private_key_1: SecretKey, random_private_key_2: SecretKey) -> ChannelAnnouncement {
 	let secp_context = Secp256k1::new();
 
-	let random_private_key_1 = SecretKey::from_slice(&[1; 32]).unwrap();
 	let random_public_key_1 = random_private_key_1.public_key(&secp_context);
 	let node_id_1 = NodeId::from_pubkey(&random_public_key_1);
 
-	let random_private_key_2 = SecretKey::from_slice(&[2; 32]).unwrap();
 	let random_public_key_2 = random_private_key_2.public_key(&secp_context);
 	let node_id_2 = NodeId::from_pubkey(&random_public_key_2);
 
@@ -110,7 +158,7 @@ fn generate_channel_announcement(short_channel_id: u64) -> ChannelAnnouncement {
 	}
 }
 
-fn generate_update(scid: u64, direction: bool, timestamp: u32, expiry_delta: u16, min_msat: u64, max_msat: u64, base_msat: u32, fee_rate: u32) -> ChannelUpdate {
+pub(crate) fn generate_update(scid: u64, direction: bool, timestamp: u32, expiry_delta: u16, min_msat: u64, max_msat: u64, base_msat: u32, fee_rate: u32) -> ChannelUpdate {
 	let flag_mask = if direction { 1 } else { 0 };
 	ChannelUpdate {
 		signature: blank_signature(),
@@ -194,8 +242,55 @@ impl Drop for CacheSanitizer {
 }
 
 
+/// Decode the node section of a v2 snapshot into each node's address and feature mutations, in
+/// the same manner as the client does
+fn decode_node_mutations(snapshot: &[u8]) -> HashMap<NodeId, (Option<Vec<SocketAddress>>, Option<NodeFeatures>)> {
+	assert_eq!(&snapshot[..4], &[76, 68, 75, 2]);
+	// skip the chain hash and the latest seen timestamp
+	let mut reader = Cursor::new(&snapshot[4 + 32 + 4..]);
+
+	let default_feature_count: u8 = Readable::read(&mut reader).unwrap();
+	let mut default_features = Vec::new();
+	for _ in 0..default_feature_count {
+		let features: NodeFeatures = Readable::read(&mut reader).unwrap();
+		default_features.push(features);
+	}
+
+	let mut mutations = HashMap::new();
+	let node_id_count: u32 = Readable::read(&mut reader).unwrap();
+	for _ in 0..node_id_count {
+		let mut key_bytes = [0u8; 33];
+		std::io::Read::read_exact(&mut reader, &mut key_bytes).unwrap();
+		let flags = key_bytes[0];
+		key_bytes[0] &= 0b_0000_0011;
+		let node_id = NodeId::from_slice(&key_bytes).unwrap();
+
+		let mut addresses = None;
+		if flags & (1 << 2) != 0 {
+			let address_count: u8 = Readable::read(&mut reader).unwrap();
+			let mut node_addresses = Vec::new();
+			for _ in 0..address_count {
+				let _address_length: u8 = Readable::read(&mut reader).unwrap();
+				let address: SocketAddress = Readable::read(&mut reader).unwrap();
+				node_addresses.push(address);
+			}
+			addresses = Some(node_addresses);
+		}
+
+		let feature_index = (flags & 0b_0011_1000) >> 3;
+		let features = match feature_index {
+			0 => None,
+			7 => Some(Readable::read(&mut reader).unwrap()),
+			index => Some(default_features[index as usize - 1].clone()),
+		};
+
+		mutations.insert(node_id, (addresses, features));
+	}
+	mutations
+}
+
 async fn clean_test_db() {
-	let client = crate::connect_to_db().await;
+	let client = crate::connect_to_db().await.unwrap();
 	let schema = db_test_schema();
 	client.execute(&format!("DROP SCHEMA IF EXISTS {} CASCADE", schema), &[]).await.unwrap();
 	IS_TEST_SCHEMA_CLEAN.with(|cleanliness_reference| {
@@ -204,6 +299,109 @@ async fn clean_test_db() {
 	});
 }
 
+#[test]
+fn test_gossip_message_json() {
+	let announcement = generate_channel_announcement(1);
+	let update = generate_update(1, false, current_time(), 0, 0, 0, 5, 0);
+	let node_announcement = generate_node_announcement(None);
+	let message = GossipMessage::Batch(vec![
+		GossipMessage::ChannelAnnouncement(announcement.clone(), Some(1700000000)),
+		GossipMessage::ChannelUpdate(update.clone(), None),
+		GossipMessage::NodeAnnouncement(node_announcement.clone(), None),
+	], vec![Instant::now(); 3]);
+
+	let json = serde_json::to_value(&message).unwrap();
+	assert_eq!(json["type"], "batch");
+	assert_eq!(json["messages"][0]["type"], "channel_announcement");
+	assert_eq!(json["messages"][0]["hex"], announcement.encode().to_lower_hex_string());
+	assert_eq!(json["messages"][0]["seen_override"], 1700000000);
+	assert_eq!(json["messages"][1]["type"], "channel_update");
+	assert_eq!(json["messages"][2]["type"], "node_announcement");
+
+	let decoded: GossipMessage = serde_json::from_value(json).unwrap();
+	match decoded.into_messages().as_slice() {
+		[GossipMessage::ChannelAnnouncement(decoded_announcement, Some(1700000000)), GossipMessage::ChannelUpdate(decoded_update, None), GossipMessage::NodeAnnouncement(decoded_node_announcement, None)] => {
+			assert_eq!(decoded_announcement, &announcement);
+			assert_eq!(decoded_update, &update);
+			assert_eq!(decoded_node_announcement, &node_announcement);
+		},
+		messages => panic!("unexpected messages: {:?}", messages),
+	}
+
+	let error = serde_json::from_str::<GossipMessage>("{\"type\":\"channel_update\",\"hex\":\"0102\",\"seen_override\":null}").unwrap_err();
+	assert!(error.to_string().starts_with("undecodable message"), "{}", error);
+}
+
+#[test]
+fn test_compact_graph() {
+	let logger = Arc::new(TestLogger::with_id("test_compact_graph".to_string()));
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let timestamp = current_time() - 10;
+	for scid in 1..=100 {
+		network_graph.update_channel_from_announcement_no_lookup(&generate_channel_announcement(scid)).unwrap();
+		network_graph.update_channel_unsigned(&generate_update(scid, false, timestamp, 0, 0, 0, 5, 0).contents).unwrap();
+		// every other channel is only updated in one direction
+		if scid % 2 == 0 {
+			network_graph.update_channel_unsigned(&generate_update(scid, true, timestamp, 0, 0, 0, 10, 0).contents).unwrap();
+		}
+	}
+
+	let compact_graph = CompactGraph::from_network_graph(&network_graph);
+	assert_eq!(compact_graph.channel_count(), 100);
+	let mut bidirectional_channel_ids = compact_graph.bidirectional_channel_ids();
+	bidirectional_channel_ids.sort_unstable();
+	assert_eq!(bidirectional_channel_ids, (1..=50).map(|index| index * 2).collect::<Vec<i64>>());
+
+	let channel = compact_graph.channel(ShortChannelId::from(2)).unwrap();
+	let channel_info = network_graph.read_only().channel(2).unwrap().clone();
+	assert_eq!(channel.node1, channel_info.node_one);
+	assert_eq!(channel.node2, channel_info.node_two);
+	assert_eq!(channel.direction_0.as_ref().unwrap().fee_base_msat, 5);
+	assert_eq!(channel.direction_1.as_ref().unwrap().fee_base_msat, 10);
+	assert_eq!(channel.direction_1.as_ref().unwrap().last_update, timestamp);
+	assert!(compact_graph.channel(ShortChannelId::from(1)).unwrap().direction_1.is_none());
+	assert!(compact_graph.channel(ShortChannelId::from(101)).is_none());
+
+	let coverage = compact_graph.directional_coverage();
+	assert_eq!(coverage, DirectionalCoverage { bidirectional: 50, direction_0_only: 50, direction_1_only: 0, none: 0 });
+	assert_eq!(coverage, DirectionalCoverage::from_network_graph(&network_graph));
+	network_graph.update_channel_from_announcement_no_lookup(&generate_channel_announcement(101)).unwrap();
+	assert_eq!(DirectionalCoverage::from_network_graph(&network_graph).none, 1);
+
+	assert!(compact_graph.estimated_memory_bytes() < compact_graph::estimate_network_graph_memory_bytes(&network_graph));
+}
+
+#[test]
+fn test_routing_hints() {
+	let logger = Arc::new(TestLogger::with_id("test_routing_hints".to_string()));
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let timestamp = current_time() - 10;
+	network_graph.update_channel_from_announcement_no_lookup(&generate_channel_announcement(1)).unwrap();
+	network_graph.update_channel_unsigned(&generate_update(1, false, timestamp, 40, 1000, 500_000_000, 5, 100).contents).unwrap();
+
+	let hints = routing_hints::extract_routing_hints(1, &network_graph).unwrap();
+	let channel_info = network_graph.read_only().channel(1).unwrap().clone();
+	// node two hasn't sent an update yet, so there is no hint towards node one
+	assert!(hints.towards_node_one.is_none());
+	let hop = hints.towards_node_two.clone().unwrap();
+	assert_eq!(hop.src_node_id, channel_info.node_one.as_pubkey().unwrap());
+	assert_eq!(hop.short_channel_id, 1);
+	assert_eq!(hop.fees.base_msat, 5);
+	assert_eq!(hop.fees.proportional_millionths, 100);
+	assert_eq!(hop.cltv_expiry_delta, 40);
+	assert_eq!(hop.htlc_minimum_msat, Some(1000));
+	assert_eq!(hop.htlc_maximum_msat, Some(500_000_000));
+	assert!(hints.to_json().contains("\"towards_node_one\":null"));
+
+	// disabling a direction withdraws its hint
+	let mut disabling_update = generate_update(1, true, timestamp, 40, 1000, 500_000_000, 10, 0);
+	disabling_update.contents.flags |= 2;
+	network_graph.update_channel_unsigned(&disabling_update.contents).unwrap();
+	assert!(routing_hints::extract_routing_hints(1, &network_graph).unwrap().towards_node_one.is_none());
+
+	assert!(routing_hints::extract_routing_hints(2, &network_graph).is_none());
+}
+
 #[tokio::test]
 async fn test_persistence_runtime() {
 	let _sanitizer = SchemaSanitizer::new();
@@ -245,10 +443,10 @@ async fn test_trivial_setup() {
 		receiver.send(GossipMessage::ChannelUpdate(update_1, None)).await.unwrap();
 		receiver.send(GossipMessage::ChannelUpdate(update_2, None)).await.unwrap();
 		drop(receiver);
-		persister.persist_gossip().await;
+		persister.persist_gossip().await.unwrap();
 	}
 
-	let delta = calculate_delta(network_graph_arc.clone(), 0, None, logger.clone()).await;
+	let delta = calculate_delta(network_graph_arc.clone(), 0, SnapshotClock::wall_clock(), logger.clone()).await.unwrap();
 	let serialization = serialize_delta(&delta, 1, logger.clone());
 	logger.assert_log_contains("rapid_gossip_sync_server", "announcement channel count: 1", 1);
 	clean_test_db().await;
@@ -323,14 +521,24 @@ async fn test_node_announcement_persistence() {
 				port: 4,
 			});
 		}
-		receiver.send(GossipMessage::NodeAnnouncement(announcement, Some(12345))).await.unwrap();
+		receiver.send(GossipMessage::NodeAnnouncement(announcement.clone(), Some(12345))).await.unwrap();
 
 		drop(receiver);
-		persister.persist_gossip().await;
+		persister.persist_gossip().await.unwrap();
 
 		tokio::task::spawn_blocking(move || {
 			drop(persister);
 		}).await.unwrap();
+
+		// public keys are persisted in their compressed encoding
+		let client = crate::connect_to_db().await.unwrap();
+		let tables = Tables::from_config();
+		let rows = client.query(&format!("SELECT DISTINCT public_key FROM {}", tables.node_announcements()), &[]).await.unwrap();
+		assert_eq!(rows.len(), 1);
+		let public_key: Vec<u8> = rows[0].get(0);
+		assert_eq!(public_key, announcement.contents.node_id.as_slice());
+		let public_key = crate::persistence::bytes_to_pubkey(&public_key.try_into().unwrap()).unwrap();
+		assert_eq!(crate::persistence::pubkey_to_bytes(&public_key), announcement.contents.node_id.as_slice());
 	}
 	clean_test_db().await;
 }
@@ -396,14 +604,14 @@ async fn test_node_announcement_delta_detection() {
 		}
 
 		drop(receiver);
-		persister.persist_gossip().await;
+		persister.persist_gossip().await.unwrap();
 
 		tokio::task::spawn_blocking(move || {
 			drop(persister);
 		}).await.unwrap();
 	}
 
-	let delta = calculate_delta(network_graph_arc.clone(), timestamp - 5, None, logger.clone()).await;
+	let delta = calculate_delta(network_graph_arc.clone(), timestamp - 5, SnapshotClock::wall_clock(), logger.clone()).await.unwrap();
 	let serialization = serialize_delta(&delta, 2, logger.clone());
 	clean_test_db().await;
 
@@ -414,6 +622,197 @@ async fn test_node_announcement_delta_detection() {
 	assert_eq!(serialization.node_address_update_count, 1);
 }
 
+#[tokio::test]
+async fn test_node_announcement_delta_round_trip() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+
+	let timestamp = current_time() - 10;
+	let reverting_node_key = SecretKey::from_slice(&[1; 32]).unwrap();
+	let address_node_key = SecretKey::from_slice(&[2; 32]).unwrap();
+	let feature_node_key = SecretKey::from_slice(&[3; 32]).unwrap();
+	let new_node_key = SecretKey::from_slice(&[4; 32]).unwrap();
+	let address = SocketAddress::TcpIpV4 { addr: [127, 0, 0, 1], port: 9735 };
+	let features = NodeFeatures::from_be_bytes(vec![23, 48]);
+
+	{ // seed the db
+		{ // features flip-flop, but end up where the client last saw them
+			let mut announcement = generate_node_announcement(Some(reverting_node_key));
+			announcement.contents.timestamp = 1;
+			receiver.send(GossipMessage::NodeAnnouncement(announcement.clone(), Some(timestamp - 10))).await.unwrap();
+			announcement.contents.timestamp = 2;
+			announcement.contents.features = features.clone();
+			receiver.send(GossipMessage::NodeAnnouncement(announcement.clone(), Some(timestamp - 2))).await.unwrap();
+			announcement.contents.timestamp = 3;
+			announcement.contents.features = NodeFeatures::empty();
+			receiver.send(GossipMessage::NodeAnnouncement(announcement, Some(timestamp))).await.unwrap();
+		}
+
+		{ // only the addresses change
+			let mut announcement = generate_node_announcement(Some(address_node_key));
+			announcement.contents.timestamp = 1;
+			receiver.send(GossipMessage::NodeAnnouncement(announcement.clone(), Some(timestamp - 10))).await.unwrap();
+			announcement.contents.timestamp = 2;
+			announcement.contents.addresses.push(address.clone());
+			receiver.send(GossipMessage::NodeAnnouncement(announcement, Some(timestamp))).await.unwrap();
+		}
+
+		{ // only the features change
+			let mut announcement = generate_node_announcement(Some(feature_node_key));
+			announcement.contents.timestamp = 1;
+			receiver.send(GossipMessage::NodeAnnouncement(announcement.clone(), Some(timestamp - 10))).await.unwrap();
+			announcement.contents.timestamp = 2;
+			announcement.contents.features = features.clone();
+			receiver.send(GossipMessage::NodeAnnouncement(announcement, Some(timestamp))).await.unwrap();
+		}
+
+		{ // the client has never seen this node, which announces features but no addresses
+			let mut announcement = generate_node_announcement(Some(new_node_key));
+			announcement.contents.features = features.clone();
+			receiver.send(GossipMessage::NodeAnnouncement(announcement, Some(timestamp))).await.unwrap();
+		}
+
+		{ // necessary for the node announcements to be considered relevant
+			let announcement = generate_channel_announcement(1);
+			let update_1 = generate_update(1, false, timestamp, 0, 0, 0, 6, 0);
+			let update_2 = generate_update(1, true, timestamp, 0, 0, 0, 6, 0);
+
+			network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+			network_graph_arc.update_channel_unsigned(&update_1.contents).unwrap();
+			network_graph_arc.update_channel_unsigned(&update_2.contents).unwrap();
+
+			receiver.send(GossipMessage::ChannelAnnouncement(announcement, Some(timestamp))).await.unwrap();
+			receiver.send(GossipMessage::ChannelUpdate(update_1, Some(timestamp))).await.unwrap();
+			receiver.send(GossipMessage::ChannelUpdate(update_2, Some(timestamp))).await.unwrap();
+		}
+
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+
+		tokio::task::spawn_blocking(move || {
+			drop(persister);
+		}).await.unwrap();
+	}
+
+	let delta = calculate_delta(network_graph_arc.clone(), timestamp - 5, SnapshotClock::wall_clock(), logger.clone()).await.unwrap();
+	let serialization_v1 = serialize_delta(&delta, 1, logger.clone());
+	let serialization_v2 = serialize_delta(&delta, 2, logger.clone());
+	clean_test_db().await;
+
+	assert_eq!(serialization_v2.node_update_count, 3);
+	assert_eq!(serialization_v2.node_feature_update_count, 2);
+	assert_eq!(serialization_v2.node_address_update_count, 1);
+
+	let secp_context = Secp256k1::new();
+	let node_id = |key: &SecretKey| NodeId::from_pubkey(&key.public_key(&secp_context));
+	let mutations = decode_node_mutations(&serialization_v2.data);
+	assert_eq!(mutations.len(), 4);
+	assert_eq!(mutations[&node_id(&reverting_node_key)], (None, None));
+	assert_eq!(mutations[&node_id(&address_node_key)], (Some(vec![address]), None));
+	assert_eq!(mutations[&node_id(&feature_node_key)], (None, Some(features.clone())));
+	// only the non-empty details of nodes new to the client are sent
+	assert_eq!(mutations[&node_id(&new_node_key)], (None, Some(features)));
+
+	// node mutations must not interfere with clients parsing the channel data
+	let client_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let client_graph_arc = Arc::new(client_graph);
+	let rgs = RapidGossipSync::new(client_graph_arc.clone(), logger.clone());
+	rgs.update_network_graph(&serialization_v1.data).unwrap();
+	assert_eq!(client_graph_arc.read_only().channels().len(), 1);
+}
+
+#[tokio::test]
+async fn test_full_snapshot_omits_stale_nodes() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+
+	let timestamp = current_time();
+	let stale_node_key = SecretKey::from_slice(&[4; 32]).unwrap();
+	let recent_node_key = SecretKey::from_slice(&[5; 32]).unwrap();
+
+	{ // seed the db
+		let stale_announcement = generate_node_announcement(Some(stale_node_key));
+		let stale_seen = timestamp - config::NODE_ANNOUNCEMENT_MAX_AGE.as_secs() as u32 - 3600;
+		receiver.send(GossipMessage::NodeAnnouncement(stale_announcement, Some(stale_seen))).await.unwrap();
+
+		let recent_announcement = generate_node_announcement(Some(recent_node_key));
+		receiver.send(GossipMessage::NodeAnnouncement(recent_announcement, Some(timestamp - 3600 * 24))).await.unwrap();
+
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+
+		tokio::task::spawn_blocking(move || {
+			drop(persister);
+		}).await.unwrap();
+	}
+
+	let delta = calculate_delta(network_graph_arc.clone(), 0, SnapshotClock::wall_clock(), logger.clone()).await.unwrap();
+	let serialization = serialize_delta(&delta, 2, logger.clone());
+	clean_test_db().await;
+
+	logger.assert_log_contains("rapid_gossip_sync_server::lookup", "Omitted 1 stale nodes from full snapshot", 1);
+	let secp_context = Secp256k1::new();
+	let mutations = decode_node_mutations(&serialization.data);
+	assert_eq!(mutations.len(), 1);
+	assert!(mutations.contains_key(&NodeId::from_pubkey(&recent_node_key.public_key(&secp_context))));
+}
+
+#[tokio::test]
+async fn test_full_snapshot_omits_stale_channels() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+
+	let timestamp = current_time() - 10;
+
+	{ // seed the db
+		for short_channel_id in 1..=2 {
+			let announcement = generate_channel_announcement(short_channel_id);
+			let update_1 = generate_update(short_channel_id, false, timestamp, 0, 0, 0, 5, 0);
+			let update_2 = generate_update(short_channel_id, true, timestamp, 0, 0, 0, 10, 0);
+
+			network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+			network_graph_arc.update_channel_unsigned(&update_1.contents).unwrap();
+			network_graph_arc.update_channel_unsigned(&update_2.contents).unwrap();
+
+			receiver.send(GossipMessage::ChannelAnnouncement(announcement, None)).await.unwrap();
+			receiver.send(GossipMessage::ChannelUpdate(update_1, None)).await.unwrap();
+			// the second channel has only ever been updated in one direction as far as the
+			// database is concerned
+			if short_channel_id == 1 {
+				receiver.send(GossipMessage::ChannelUpdate(update_2, None)).await.unwrap();
+			}
+		}
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+
+		tokio::task::spawn_blocking(move || {
+			drop(persister);
+		}).await.unwrap();
+	}
+
+	let delta = calculate_delta(network_graph_arc.clone(), 0, SnapshotClock::wall_clock(), logger.clone()).await.unwrap();
+	let serialization = serialize_delta(&delta, 1, logger.clone());
+	assert_eq!(delta.omitted_stale_channel_count, 1);
+	logger.assert_log_contains("rapid_gossip_sync_server::lookup", "Omitted 1 stale channels from full snapshot", 1);
+	assert_eq!(serialization.channel_announcement_count, 1);
+	assert_eq!(serialization.update_count, 2);
+
+	// deltas can't tell clients to remove channels, so they mustn't omit any
+	let delta = calculate_delta(network_graph_arc.clone(), timestamp - 3600, SnapshotClock::wall_clock(), logger.clone()).await.unwrap();
+	clean_test_db().await;
+	assert_eq!(delta.omitted_stale_channel_count, 0);
+	logger.assert_log_contains("rapid_gossip_sync_server::lookup", "stale channels from full snapshot", 1);
+}
+
 /// If a channel has only seen updates in one direction, it should not be announced
 #[tokio::test]
 async fn test_unidirectional_intermediate_update_consideration() {
@@ -444,7 +843,7 @@ async fn test_unidirectional_intermediate_update_consideration() {
 		receiver.send(GossipMessage::ChannelUpdate(update_2, None)).await.unwrap();
 		receiver.send(GossipMessage::ChannelUpdate(update_3, None)).await.unwrap();
 		drop(receiver);
-		persister.persist_gossip().await;
+		persister.persist_gossip().await.unwrap();
 	}
 
 	let channel_count = network_graph_arc.read_only().channels().len();
@@ -454,7 +853,7 @@ async fn test_unidirectional_intermediate_update_consideration() {
 	let client_graph_arc = Arc::new(client_graph);
 	let rgs = RapidGossipSync::new(client_graph_arc.clone(), logger.clone());
 
-	let delta = calculate_delta(network_graph_arc.clone(), timestamp + 1, None, logger.clone()).await;
+	let delta = calculate_delta(network_graph_arc.clone(), timestamp + 1, SnapshotClock::wall_clock(), logger.clone()).await.unwrap();
 	let serialization = serialize_delta(&delta, 1, logger.clone());
 
 	logger.assert_log_contains("rapid_gossip_sync_server::lookup", "Fetched 1 update rows of the first update in a new direction", 1);
@@ -516,13 +915,13 @@ async fn test_bidirectional_intermediate_update_consideration() {
 		receiver.send(GossipMessage::ChannelUpdate(update_3, None)).await.unwrap();
 		receiver.send(GossipMessage::ChannelUpdate(update_4, None)).await.unwrap();
 		drop(receiver);
-		persister.persist_gossip().await;
+		persister.persist_gossip().await.unwrap();
 	}
 
 	let channel_count = network_graph_arc.read_only().channels().len();
 	assert_eq!(channel_count, 1);
 
-	let delta = calculate_delta(network_graph_arc.clone(), timestamp + 1, None, logger.clone()).await;
+	let delta = calculate_delta(network_graph_arc.clone(), timestamp + 1, SnapshotClock::wall_clock(), logger.clone()).await.unwrap();
 	let serialization = serialize_delta(&delta, 1, logger.clone());
 
 	logger.assert_log_contains("rapid_gossip_sync_server::lookup", "Fetched 0 update rows of the first update in a new direction", 1);
@@ -542,6 +941,83 @@ async fn test_bidirectional_intermediate_update_consideration() {
 	clean_test_db().await;
 }
 
+/// Updates that are seen late, but were signed before what a client already has, e. g. because
+/// they were backfilled from another source, must only be skipped once the coverage of the
+/// client's reference timestamp is known, and never if they're news to the client
+#[tokio::test]
+async fn test_backfilled_update_coverage() {
+	let _sanitizer = SchemaSanitizer::new();
+
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+
+	let reference_timestamp = current_time() - 3600;
+
+	{ // seed the db
+		for (short_channel_id, update_timestamp) in [(1, reference_timestamp - 500), (2, reference_timestamp - 600)] {
+			let announcement = generate_channel_announcement(short_channel_id);
+			let update_1 = generate_update(short_channel_id, false, update_timestamp, 0, 0, 0, 5, 0);
+			let update_2 = generate_update(short_channel_id, true, update_timestamp, 0, 0, 0, 5, 0);
+
+			network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+			network_graph_arc.update_channel_unsigned(&update_1.contents).unwrap();
+			network_graph_arc.update_channel_unsigned(&update_2.contents).unwrap();
+
+			receiver.send(GossipMessage::ChannelAnnouncement(announcement, Some(reference_timestamp - 1000))).await.unwrap();
+			receiver.send(GossipMessage::ChannelUpdate(update_1, Some(update_timestamp))).await.unwrap();
+			receiver.send(GossipMessage::ChannelUpdate(update_2, Some(update_timestamp))).await.unwrap();
+		}
+
+		// backfilled after the reference timestamp, but older than what the client has
+		let backfilled_update = generate_update(1, false, reference_timestamp - 800, 0, 0, 0, 7, 0);
+		receiver.send(GossipMessage::ChannelUpdate(backfilled_update, Some(reference_timestamp + 100))).await.unwrap();
+
+		// propagated late, and older than the coverage, but newer than what the client has
+		let late_update = generate_update(2, false, reference_timestamp - 550, 0, 0, 0, 7, 0);
+		network_graph_arc.update_channel_unsigned(&late_update.contents).unwrap();
+		receiver.send(GossipMessage::ChannelUpdate(late_update, Some(reference_timestamp + 100))).await.unwrap();
+
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+	}
+
+	{ // without recorded coverage, nothing is skipped
+		let delta = calculate_delta(network_graph_arc.clone(), reference_timestamp, SnapshotClock::wall_clock(), logger.clone()).await.unwrap();
+		let serialization = serialize_delta(&delta, 1, logger.clone());
+		assert_eq!(serialization.channel_announcement_count, 0);
+		assert_eq!(serialization.update_count, 2);
+		logger.assert_log_contains("rapid_gossip_sync_server::lookup", "backfilled channel updates", 0);
+	}
+
+	let client = crate::connect_to_db().await.unwrap();
+	let tables = Tables::from_config();
+	let coverage = persistence::record_snapshot_coverage(&client, &tables, reference_timestamp as u64).await.unwrap();
+	assert_eq!(coverage, reference_timestamp - 500);
+
+	{ // only the late update is news to the client
+		let delta = calculate_delta(network_graph_arc.clone(), reference_timestamp, SnapshotClock::wall_clock(), logger.clone()).await.unwrap();
+		let serialization = serialize_delta(&delta, 1, logger.clone());
+		assert_eq!(serialization.channel_announcement_count, 0);
+		assert_eq!(serialization.update_count, 1);
+		assert_eq!(serialization.update_count_incremental, 1);
+		logger.assert_log_contains("rapid_gossip_sync_server::lookup", "Skipped 1 backfilled channel updates", 1);
+	}
+
+	{ // clients synced before any coverage was recorded keep receiving everything
+		let delta = calculate_delta(network_graph_arc.clone(), reference_timestamp - 1, SnapshotClock::wall_clock(), logger.clone()).await.unwrap();
+		let serialization = serialize_delta(&delta, 1, logger.clone());
+		assert_eq!(serialization.update_count, 2);
+	}
+
+	tokio::task::spawn_blocking(move || {
+		drop(persister);
+	}).await.unwrap();
+
+	clean_test_db().await;
+}
+
 #[tokio::test]
 async fn test_channel_reminders() {
 	let _sanitizer = SchemaSanitizer::new();
@@ -600,13 +1076,13 @@ async fn test_channel_reminders() {
 			receiver.send(GossipMessage::ChannelUpdate(update_8, Some(timestamp - channel_reminder_delta + 20))).await.unwrap();
 		}
 		drop(receiver);
-		persister.persist_gossip().await;
+		persister.persist_gossip().await.unwrap();
 	}
 
 	let channel_count = network_graph_arc.read_only().channels().len();
 	assert_eq!(channel_count, 2);
 
-	let delta = calculate_delta(network_graph_arc.clone(), timestamp - channel_reminder_delta + 15, None, logger.clone()).await;
+	let delta = calculate_delta(network_graph_arc.clone(), timestamp - channel_reminder_delta + 15, SnapshotClock::wall_clock(), logger.clone()).await.unwrap();
 	let serialization = serialize_delta(&delta, 1, logger.clone());
 
 	logger.assert_log_contains("rapid_gossip_sync_server::lookup", "Fetched 0 update rows of the first update in a new direction", 1);
@@ -665,7 +1141,7 @@ async fn test_full_snapshot_recency() {
 		}
 
 		drop(receiver);
-		persister.persist_gossip().await;
+		persister.persist_gossip().await.unwrap();
 
 		tokio::task::spawn_blocking(move || {
 			drop(persister);
@@ -676,7 +1152,7 @@ async fn test_full_snapshot_recency() {
 	let client_graph_arc = Arc::new(client_graph);
 
 	{ // sync after initial seed
-		let delta = calculate_delta(network_graph_arc.clone(), 0, None, logger.clone()).await;
+		let delta = calculate_delta(network_graph_arc.clone(), 0, SnapshotClock::wall_clock(), logger.clone()).await.unwrap();
 		let serialization = serialize_delta(&delta, 1, logger.clone());
 		logger.assert_log_contains("rapid_gossip_sync_server", "announcement channel count: 1", 1);
 
@@ -746,7 +1222,7 @@ async fn test_full_snapshot_recency_with_wrong_seen_order() {
 		}
 
 		drop(receiver);
-		persister.persist_gossip().await;
+		persister.persist_gossip().await.unwrap();
 
 		tokio::task::spawn_blocking(move || {
 			drop(persister);
@@ -757,7 +1233,7 @@ async fn test_full_snapshot_recency_with_wrong_seen_order() {
 	let client_graph_arc = Arc::new(client_graph);
 
 	{ // sync after initial seed
-		let delta = calculate_delta(network_graph_arc.clone(), 0, None, logger.clone()).await;
+		let delta = calculate_delta(network_graph_arc.clone(), 0, SnapshotClock::wall_clock(), logger.clone()).await.unwrap();
 		let serialization = serialize_delta(&delta, 1, logger.clone());
 		logger.assert_log_contains("rapid_gossip_sync_server", "announcement channel count: 1", 1);
 
@@ -826,7 +1302,7 @@ async fn test_full_snapshot_recency_with_wrong_propagation_order() {
 		}
 
 		drop(receiver);
-		persister.persist_gossip().await;
+		persister.persist_gossip().await.unwrap();
 
 		tokio::task::spawn_blocking(move || {
 			drop(persister);
@@ -837,7 +1313,7 @@ async fn test_full_snapshot_recency_with_wrong_propagation_order() {
 	let client_graph_arc = Arc::new(client_graph);
 
 	{ // sync after initial seed
-		let delta = calculate_delta(network_graph_arc.clone(), 0, None, logger.clone()).await;
+		let delta = calculate_delta(network_graph_arc.clone(), 0, SnapshotClock::wall_clock(), logger.clone()).await.unwrap();
 		let serialization = serialize_delta(&delta, 1, logger.clone());
 		logger.assert_log_contains("rapid_gossip_sync_server", "announcement channel count: 1", 1);
 
@@ -960,7 +1436,7 @@ async fn test_full_snapshot_mutiny_scenario() {
 		}
 
 		drop(receiver);
-		persister.persist_gossip().await;
+		persister.persist_gossip().await.unwrap();
 
 		tokio::task::spawn_blocking(move || {
 			drop(persister);
@@ -971,7 +1447,7 @@ async fn test_full_snapshot_mutiny_scenario() {
 	let client_graph_arc = Arc::new(client_graph);
 
 	{ // sync after initial seed
-		let delta = calculate_delta(network_graph_arc.clone(), 0, None, logger.clone()).await;
+		let delta = calculate_delta(network_graph_arc.clone(), 0, SnapshotClock::wall_clock(), logger.clone()).await.unwrap();
 		let serialization = serialize_delta(&delta, 1, logger.clone());
 		logger.assert_log_contains("rapid_gossip_sync_server", "announcement channel count: 1", 1);
 
@@ -1074,7 +1550,7 @@ async fn test_full_snapshot_interlaced_channel_timestamps() {
 		}
 
 		drop(receiver);
-		persister.persist_gossip().await;
+		persister.persist_gossip().await.unwrap();
 
 		tokio::task::spawn_blocking(move || {
 			drop(persister);
@@ -1085,7 +1561,7 @@ async fn test_full_snapshot_interlaced_channel_timestamps() {
 	let client_graph_arc = Arc::new(client_graph);
 
 	{ // sync after initial seed
-		let delta = calculate_delta(network_graph_arc.clone(), 0, None, logger.clone()).await;
+		let delta = calculate_delta(network_graph_arc.clone(), 0, SnapshotClock::wall_clock(), logger.clone()).await.unwrap();
 		let serialization = serialize_delta(&delta, 1, logger.clone());
 		logger.assert_log_contains("rapid_gossip_sync_server", "announcement channel count: 2", 1);
 
@@ -1150,7 +1626,7 @@ async fn test_full_snapshot_persistence() {
 
 
 		drop(receiver);
-		persister.persist_gossip().await;
+		persister.persist_gossip().await.unwrap();
 
 		tokio::task::spawn_blocking(move || {
 			drop(persister);
@@ -1162,7 +1638,7 @@ async fn test_full_snapshot_persistence() {
 
 	// generate snapshots
 	{
-		snapshotter.generate_snapshots(20, 5, &[5, u64::MAX], &cache_path, Some(10)).await;
+		snapshotter.generate_snapshots(20, 5, &[5, u64::MAX], &cache_path, Some(10)).await.unwrap();
 
 		let symlinked_data = fs::read(&symlink_path).unwrap();
 		let client_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
@@ -1195,7 +1671,7 @@ async fn test_full_snapshot_persistence() {
 		}
 
 		drop(receiver);
-		persister.persist_gossip().await;
+		persister.persist_gossip().await.unwrap();
 
 		tokio::task::spawn_blocking(move || {
 			drop(persister);
@@ -1204,7 +1680,7 @@ async fn test_full_snapshot_persistence() {
 
 	// regenerate snapshots
 	{
-		snapshotter.generate_snapshots(20, 5, &[5, u64::MAX], &cache_path, Some(10)).await;
+		snapshotter.generate_snapshots(20, 5, &[5, u64::MAX], &cache_path, Some(10)).await.unwrap();
 
 		let symlinked_data = fs::read(&symlink_path).unwrap();
 		let client_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
@@ -1230,3 +1706,1519 @@ async fn test_full_snapshot_persistence() {
 	// clean up afterwards
 	clean_test_db().await;
 }
+
+#[tokio::test]
+async fn test_snapshot_generation_as_of() {
+	let schema_sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let snapshotter = Snapshotter::new(network_graph_arc.clone(), logger.clone());
+	let cache_sanitizer = CacheSanitizer::new(&schema_sanitizer);
+
+	let short_channel_id = 1;
+	let timestamp = current_time();
+
+	{ // seed the db
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		let announcement = generate_channel_announcement(short_channel_id);
+		network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+		receiver.send(GossipMessage::ChannelAnnouncement(announcement, None)).await.unwrap();
+
+		let update = generate_update(short_channel_id, true, timestamp, 0, 0, 0, 0, 10);
+		network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+		receiver.send(GossipMessage::ChannelUpdate(update, None)).await.unwrap();
+
+		let update = generate_update(short_channel_id, false, timestamp - 1, 0, 0, 0, 0, 38);
+		network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+		receiver.send(GossipMessage::ChannelUpdate(update, None)).await.unwrap();
+
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+
+		tokio::task::spawn_blocking(move || {
+			drop(persister);
+		}).await.unwrap();
+	}
+
+	let cache_path = cache_sanitizer.cache_path();
+	snapshotter.generate_snapshots(20, 5, &[5, u64::MAX], &cache_path, Some(10)).await.unwrap();
+	let read_snapshots = |snapshot_directory: String| -> HashMap<String, Vec<u8>> {
+		fs::read_dir(snapshot_directory).unwrap()
+			.map(|entry| entry.unwrap().path())
+			.filter(|path| path.extension().map_or(false, |extension| extension == "lngossip"))
+			.map(|path| (path.file_name().unwrap().to_string_lossy().to_string(), fs::read(&path).unwrap()))
+			.collect()
+	};
+	let archived_snapshots = read_snapshots(format!("{}/snapshots/v2", cache_path));
+	let archived_full_snapshot = fs::read(format!("{}/symlinks/v2/0.bin", cache_path)).unwrap();
+	let reference_timestamp = archived_snapshots.keys()
+		.find_map(|filename| snapshot_reader::reference_timestamp_from_filename(filename))
+		.unwrap();
+
+	{ // gossip seen after the archived generation
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		let update = generate_update(short_channel_id, false, timestamp + 30, 0, 0, 0, 0, 39);
+		network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+		receiver.send(GossipMessage::ChannelUpdate(update, Some(reference_timestamp as u32 + 3600))).await.unwrap();
+
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+
+		tokio::task::spawn_blocking(move || {
+			drop(persister);
+		}).await.unwrap();
+	}
+
+	// the archived generation ran within the snapshot interval following its reference timestamp
+	let output_path = format!("{}as_of", cache_path);
+	snapshotter.generate_snapshots_as_of(reference_timestamp + 4, 20, 5, &[5, u64::MAX], &output_path, Some(10)).await.unwrap();
+
+	let regenerated_snapshots = read_snapshots(format!("{}/snapshots/v2", output_path));
+	assert_eq!(regenerated_snapshots.len(), archived_snapshots.len());
+	for (filename, archived_snapshot) in &archived_snapshots {
+		assert_eq!(regenerated_snapshots.get(filename), Some(archived_snapshot), "{} differs from the archived snapshot", filename);
+	}
+	// the snapshots being served are left alone
+	assert_eq!(fs::read(format!("{}/symlinks/v2/0.bin", cache_path)).unwrap(), archived_full_snapshot);
+
+	clean_test_db().await;
+}
+
+#[tokio::test]
+async fn test_oversized_snapshot_fallback() {
+	let schema_sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let mut snapshotter = Snapshotter::new(network_graph_arc.clone(), logger.clone());
+	let cache_sanitizer = CacheSanitizer::new(&schema_sanitizer);
+
+	let short_channel_id = 1;
+	let timestamp = current_time();
+
+	{ // seed the db
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		let announcement = generate_channel_announcement(short_channel_id);
+		network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+		receiver.send(GossipMessage::ChannelAnnouncement(announcement, None)).await.unwrap();
+
+		let update = generate_update(short_channel_id, false, timestamp, 0, 0, 0, 0, 38);
+		network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+		receiver.send(GossipMessage::ChannelUpdate(update, None)).await.unwrap();
+
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+
+		tokio::task::spawn_blocking(move || {
+			drop(persister);
+		}).await.unwrap();
+	}
+
+	let cache_path = cache_sanitizer.cache_path();
+	let symlink_path = format!("{}/symlinks/0.bin", cache_path);
+	let oversize_log_path = format!("{}/stats/oversized_snapshots.jsonl", cache_path);
+
+	snapshotter.generate_snapshots(20, 5, &[5, u64::MAX], &cache_path, Some(10)).await.unwrap();
+	let good_snapshot = fs::read(&symlink_path).unwrap();
+	assert!(fs::metadata(&oversize_log_path).is_err());
+
+	{ // update the db
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+
+		let update = generate_update(short_channel_id, false, timestamp + 30, 0, 0, 0, 0, 39);
+		network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+		receiver.send(GossipMessage::ChannelUpdate(update, None)).await.unwrap();
+
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+
+		tokio::task::spawn_blocking(move || {
+			drop(persister);
+		}).await.unwrap();
+	}
+
+	// every snapshot of the next generation exceeds the limit
+	snapshotter.set_max_blob_bytes(1);
+	snapshotter.generate_snapshots(20, 5, &[5, u64::MAX], &cache_path, Some(10)).await.unwrap();
+
+	// the symlinks must still point at the previous generation's snapshots
+	assert_eq!(fs::read(&symlink_path).unwrap(), good_snapshot);
+	let client_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+	let rgs = RapidGossipSync::new(client_graph_arc.clone(), logger.clone());
+	rgs.update_network_graph(&good_snapshot).unwrap();
+	let readonly_graph = client_graph_arc.read_only();
+	let first_channel = readonly_graph.channels().get(&short_channel_id).unwrap();
+	assert_eq!(first_channel.one_to_two.as_ref().unwrap().fees.proportional_millionths, 38);
+
+	let oversize_log = fs::read_to_string(&oversize_log_path).unwrap();
+	assert_eq!(oversize_log.lines().count(), 2);
+	assert!(oversize_log.lines().all(|line| line.contains("\"max_bytes\":1}")));
+	logger.assert_log_contains("rapid_gossip_sync_server::snapshot", "exceeding the maximum of 1 bytes, so it won't be published", 2);
+
+	// once the limit is lifted, the new generation is published again
+	snapshotter.set_max_blob_bytes(u64::MAX);
+	snapshotter.generate_snapshots(20, 5, &[5, u64::MAX], &cache_path, Some(10)).await.unwrap();
+	assert_ne!(fs::read(&symlink_path).unwrap(), good_snapshot);
+
+	clean_test_db().await;
+}
+
+#[tokio::test]
+async fn test_snapshot_generation_on_full_disk() {
+	let schema_sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let snapshotter = Snapshotter::new(network_graph_arc.clone(), logger.clone());
+	let cache_sanitizer = CacheSanitizer::new(&schema_sanitizer);
+
+	let short_channel_id = 1;
+	let timestamp = current_time();
+
+	{ // seed the db
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		let announcement = generate_channel_announcement(short_channel_id);
+		network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+		receiver.send(GossipMessage::ChannelAnnouncement(announcement, None)).await.unwrap();
+
+		let update = generate_update(short_channel_id, false, timestamp, 0, 0, 0, 0, 38);
+		network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+		receiver.send(GossipMessage::ChannelUpdate(update, None)).await.unwrap();
+
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+
+		tokio::task::spawn_blocking(move || {
+			drop(persister);
+		}).await.unwrap();
+	}
+
+	let cache_path = cache_sanitizer.cache_path();
+	let symlink_path = format!("{}/symlinks/0.bin", cache_path);
+	snapshotter.generate_snapshots(20, 5, &[5, u64::MAX], &cache_path, Some(10)).await.unwrap();
+	let good_snapshot = fs::read(&symlink_path).unwrap();
+	let good_snapshot_filenames = fs::read_dir(format!("{}/snapshots", cache_path)).unwrap()
+		.map(|entry| entry.unwrap().file_name())
+		.collect::<Vec<_>>();
+
+	// the disk is full by the time the next generation is written
+	snapshotter.set_write_budget(Some(0));
+	let error = snapshotter.generate_snapshots(20, 5, &[5, u64::MAX], &cache_path, Some(10)).await.unwrap_err();
+	assert!(error.to_string().contains("Failed to write snapshot"));
+	logger.assert_log_contains("rapid_gossip_sync_server::snapshot", "so the previous generation keeps being served", 1);
+
+	// the previous generation survives intact, and nothing of the failed one is left behind
+	assert_eq!(fs::read(&symlink_path).unwrap(), good_snapshot);
+	let snapshot_filenames = fs::read_dir(format!("{}/snapshots", cache_path)).unwrap()
+		.map(|entry| entry.unwrap().file_name())
+		.collect::<Vec<_>>();
+	assert_eq!(snapshot_filenames, good_snapshot_filenames);
+	assert!(fs::metadata(format!("{}/snapshots_pending", cache_path)).is_err());
+	assert!(fs::metadata(format!("{}/symlinks_pending", cache_path)).is_err());
+
+	// once there's space again, generation recovers
+	snapshotter.set_write_budget(None);
+	snapshotter.generate_snapshots(20, 5, &[5, u64::MAX], &cache_path, Some(10)).await.unwrap();
+	assert!(!fs::read(&symlink_path).unwrap().is_empty());
+
+	clean_test_db().await;
+}
+
+#[tokio::test]
+async fn test_snapshot_archive_retention() {
+	let schema_sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let mut snapshotter = Snapshotter::new(network_graph_arc.clone(), logger.clone());
+	snapshotter.set_snapshot_archive(true, SnapshotRetentionPolicy { max_files: 1, max_age_days: 30, min_free_bytes: 0 });
+	let cache_sanitizer = CacheSanitizer::new(&schema_sanitizer);
+
+	let short_channel_id = 1;
+	let timestamp = current_time();
+
+	{ // seed the db
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		let announcement = generate_channel_announcement(short_channel_id);
+		network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+		receiver.send(GossipMessage::ChannelAnnouncement(announcement, None)).await.unwrap();
+
+		let update = generate_update(short_channel_id, false, timestamp, 0, 0, 0, 0, 38);
+		network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+		receiver.send(GossipMessage::ChannelUpdate(update, None)).await.unwrap();
+
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+
+		tokio::task::spawn_blocking(move || {
+			drop(persister);
+		}).await.unwrap();
+	}
+
+	let cache_path = cache_sanitizer.cache_path();
+	let archive_path = format!("{}/snapshot_archive/v2", cache_path);
+	let count_files = |directory: &str| fs::read_dir(directory).unwrap().count();
+
+	// the first generation supersedes nothing
+	snapshotter.generate_snapshots(20, 5, &[5, u64::MAX], &cache_path, Some(10)).await.unwrap();
+	assert!(fs::metadata(&archive_path).is_err());
+	let generation_file_count = count_files(&format!("{}/snapshots/v2", cache_path));
+	assert!(generation_file_count > 1);
+
+	// the second one archives the first one's files, all but one of which exceed the limit
+	snapshotter.generate_snapshots(20, 5, &[5, u64::MAX], &cache_path, Some(10)).await.unwrap();
+	assert_eq!(count_files(&archive_path), 1);
+	// the v1 archive holds the v2 one besides its single file
+	assert_eq!(count_files(&format!("{}/snapshot_archive", cache_path)), 2);
+
+	// the served generation is left alone
+	assert_eq!(count_files(&format!("{}/snapshots/v2", cache_path)), generation_file_count);
+	assert!(!fs::read(format!("{}/symlinks/0.bin", cache_path)).unwrap().is_empty());
+
+	clean_test_db().await;
+}
+
+#[tokio::test]
+async fn test_sync_totals() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+	{ // create the tables
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+		tokio::task::spawn_blocking(move || {
+			drop(persister);
+		}).await.unwrap();
+	}
+
+	let client = crate::connect_to_db().await.unwrap();
+	let tables = Tables::from_config();
+	assert_eq!(sync_progress::fetch_previous_sync_total(&client, &tables).await.unwrap(), None);
+
+	let first_sync = SyncTotal { estimated_total: None, channel_count: 40000 };
+	sync_progress::record_sync_total(&client, &tables, &first_sync).await.unwrap();
+	let second_sync = SyncTotal { estimated_total: Some(52000), channel_count: 51000 };
+	sync_progress::record_sync_total(&client, &tables, &second_sync).await.unwrap();
+	assert_eq!(sync_progress::fetch_previous_sync_total(&client, &tables).await.unwrap(), Some(second_sync));
+
+	clean_test_db().await;
+}
+
+#[tokio::test]
+async fn test_second_persister_refused() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+
+	let (mut first_persister, first_sender) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+	let first_instance = tokio::spawn(async move {
+		first_persister.persist_gossip().await.unwrap();
+		first_persister
+	});
+	// wait for the first instance to take the lock
+	tokio::time::sleep(Duration::from_millis(500)).await;
+
+	let (mut second_persister, second_sender) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+	drop(second_sender);
+	let error = second_persister.persist_gossip().await.unwrap_err();
+	assert!(matches!(error, ProcessorError::InstanceLocked { .. }));
+	assert!(error.to_string().contains("is in use by another instance of the server (Postgres backend PID"));
+
+	// once the first instance stops, the lock is released
+	drop(first_sender);
+	let first_persister = first_instance.await.unwrap();
+	second_persister.persist_gossip().await.unwrap();
+
+	tokio::task::spawn_blocking(move || {
+		drop(first_persister);
+		drop(second_persister);
+	}).await.unwrap();
+	clean_test_db().await;
+}
+
+#[tokio::test]
+async fn test_promoted_standby_locks_database() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+	let role = Arc::new(RoleState::new(ServerRole::Standby));
+
+	let (mut standby_persister, standby_sender) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+	standby_persister.set_role(Arc::clone(&role));
+	let standby_instance = tokio::spawn(async move {
+		standby_persister.persist_gossip().await.unwrap();
+		standby_persister
+	});
+	// wait for the standby to start persisting, which it does without the lock
+	tokio::time::sleep(Duration::from_millis(500)).await;
+	let (mut active_persister, active_sender) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+	drop(active_sender);
+	active_persister.persist_gossip().await.unwrap();
+
+	// once promoted, the standby takes the lock without persistence having to restart
+	assert!(role.promote());
+	tokio::time::sleep(Duration::from_millis(500)).await;
+	let error = active_persister.persist_gossip().await.unwrap_err();
+	assert!(matches!(error, ProcessorError::InstanceLocked { .. }));
+
+	drop(standby_sender);
+	let standby_persister = standby_instance.await.unwrap();
+	tokio::task::spawn_blocking(move || {
+		drop(standby_persister);
+		drop(active_persister);
+	}).await.unwrap();
+	clean_test_db().await;
+}
+
+#[tokio::test]
+async fn test_table_prefix_isolation() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let timestamp = current_time() - 10;
+	let tenants = [("tenant_a_", 1), ("tenant_b_", 2)];
+
+	for (prefix, short_channel_id) in tenants {
+		set_db_test_table_prefix(Some(prefix));
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+
+		let announcement = generate_channel_announcement(short_channel_id);
+		let update_1 = generate_update(short_channel_id, false, timestamp, 0, 0, 0, 5, 0);
+		let update_2 = generate_update(short_channel_id, true, timestamp, 0, 0, 0, 10, 0);
+
+		network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+		network_graph_arc.update_channel_unsigned(&update_1.contents).unwrap();
+		network_graph_arc.update_channel_unsigned(&update_2.contents).unwrap();
+
+		receiver.send(GossipMessage::ChannelAnnouncement(announcement, None)).await.unwrap();
+		receiver.send(GossipMessage::ChannelUpdate(update_1, None)).await.unwrap();
+		receiver.send(GossipMessage::ChannelUpdate(update_2, None)).await.unwrap();
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+
+		tokio::task::spawn_blocking(move || {
+			drop(persister);
+		}).await.unwrap();
+	}
+
+	// the graph contains both channels, but each tenant must only serve its own
+	for (prefix, short_channel_id) in tenants {
+		set_db_test_table_prefix(Some(prefix));
+		let delta = calculate_delta(network_graph_arc.clone(), 0, SnapshotClock::wall_clock(), logger.clone()).await.unwrap();
+		let serialization = serialize_delta(&delta, 1, logger.clone());
+		assert_eq!(serialization.channel_announcement_count, 1);
+		assert_eq!(serialization.update_count, 2);
+
+		let client_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+		let client_graph_arc = Arc::new(client_graph);
+		let rgs = RapidGossipSync::new(client_graph_arc.clone(), logger.clone());
+		rgs.update_network_graph(&serialization.data).unwrap();
+		let readonly_graph = client_graph_arc.read_only();
+		assert_eq!(readonly_graph.channels().len(), 1);
+		assert!(readonly_graph.channels().get(&short_channel_id).is_some());
+	}
+
+	let client = crate::connect_to_db().await.unwrap();
+	for (prefix, _) in tenants {
+		let announcement_count: i64 = client.query_one(&format!("SELECT COUNT(*) FROM {}channel_announcements", prefix), &[]).await.unwrap().get(0);
+		let update_count: i64 = client.query_one(&format!("SELECT COUNT(*) FROM {}channel_updates", prefix), &[]).await.unwrap().get(0);
+		assert_eq!(announcement_count, 1);
+		assert_eq!(update_count, 2);
+	}
+
+	set_db_test_table_prefix(None);
+	clean_test_db().await;
+}
+
+#[tokio::test]
+async fn test_gossip_watermark() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let timestamp = current_time() - 10;
+
+	{ // persist a batch along with an older straggler
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		let announcement = generate_channel_announcement(1);
+		let update_1 = generate_update(1, false, timestamp, 0, 0, 0, 5, 0);
+		let update_2 = generate_update(1, true, timestamp, 0, 0, 0, 10, 0);
+		network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+		network_graph_arc.update_channel_unsigned(&update_1.contents).unwrap();
+		network_graph_arc.update_channel_unsigned(&update_2.contents).unwrap();
+
+		let batch = vec![
+			GossipMessage::ChannelAnnouncement(announcement, Some(timestamp - 3600)),
+			GossipMessage::ChannelUpdate(update_1, Some(timestamp - 1800)),
+		];
+		receiver.send(GossipMessage::Batch(batch, vec![Instant::now(); 2])).await.unwrap();
+		receiver.send(GossipMessage::ChannelUpdate(update_2, Some(timestamp - 7200))).await.unwrap();
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+
+		tokio::task::spawn_blocking(move || {
+			drop(persister);
+		}).await.unwrap();
+	}
+
+	// the watermark never moves backwards
+	let client = crate::connect_to_db().await.unwrap();
+	let watermark = persistence::fetch_gossip_watermark(&client, &Tables::from_config()).await.unwrap();
+	assert_eq!(watermark, Some((timestamp - 1800) as u64));
+
+	clean_test_db().await;
+}
+
+#[tokio::test]
+async fn test_database_freshness() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let timestamp = current_time() - 10;
+
+	{ // persist an update newer than any other, but received long before the rest
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		let announcement = generate_channel_announcement(1);
+		let update_1 = generate_update(1, false, timestamp, 0, 0, 0, 5, 0);
+		let update_2 = generate_update(1, true, timestamp - 100, 0, 0, 0, 10, 0);
+		let early_update = generate_update(1, true, timestamp + 50, 0, 0, 0, 15, 0);
+		network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+		network_graph_arc.update_channel_unsigned(&update_1.contents).unwrap();
+		network_graph_arc.update_channel_unsigned(&update_2.contents).unwrap();
+
+		receiver.send(GossipMessage::ChannelAnnouncement(announcement, Some(timestamp - 3600))).await.unwrap();
+		receiver.send(GossipMessage::ChannelUpdate(update_1, Some(timestamp - 1800))).await.unwrap();
+		receiver.send(GossipMessage::ChannelUpdate(update_2, Some(timestamp - 600))).await.unwrap();
+		receiver.send(GossipMessage::ChannelUpdate(early_update, Some(timestamp - 3 * 86400))).await.unwrap();
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+
+		tokio::task::spawn_blocking(move || {
+			drop(persister);
+		}).await.unwrap();
+	}
+
+	// only updates seen within a day of the latest one count towards the newest gossip timestamp
+	let client = crate::connect_to_db().await.unwrap();
+	let (max_gossip_timestamp, max_seen_timestamp) = freshness::fetch_database_timestamps(&client, &Tables::from_config()).await.unwrap();
+	assert_eq!(max_gossip_timestamp, Some(timestamp as u64));
+	assert_eq!(max_seen_timestamp, Some((timestamp - 600) as u64));
+
+	clean_test_db().await;
+}
+
+/// Create the gossip tables, and make channel updates reference their channel's announcement, as a
+/// stand-in for any reason a message may fail to persist
+async fn create_tables_with_update_foreign_key(network_graph_arc: Arc<NetworkGraph<Arc<TestLogger>>>, logger: Arc<TestLogger>) {
+	let (mut persister, receiver) = GossipPersister::new(network_graph_arc, logger);
+	drop(receiver);
+	persister.persist_gossip().await.unwrap();
+	tokio::task::spawn_blocking(move || drop(persister)).await.unwrap();
+
+	let tables = Tables::from_config();
+	let client = crate::connect_to_db().await.unwrap();
+	client.execute(&format!("ALTER TABLE {} ADD CONSTRAINT {} FOREIGN KEY (short_channel_id) REFERENCES {} (short_channel_id)",
+		tables.channel_updates(), tables.index("channel_updates_announcement_fkey"), tables.channel_announcements()), &[]).await.unwrap();
+}
+
+#[tokio::test]
+async fn test_persistence_dead_letters() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+	create_tables_with_update_foreign_key(network_graph_arc.clone(), logger.clone()).await;
+	let timestamp = current_time() - 10;
+
+	let gossip_counter = Arc::new(RwLock::new(GossipCounter::new()));
+	let (mut persister, sender) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+	persister.set_gossip_counter(Arc::clone(&gossip_counter));
+	{ // the second channel is never announced, so neither its update, nor a batch containing it, fit
+		let orphaned_update = generate_update(2, false, timestamp, 0, 0, 0, 10, 0);
+		sender.send(GossipMessage::ChannelAnnouncement(generate_channel_announcement(1), None)).await.unwrap();
+		sender.send(GossipMessage::ChannelUpdate(orphaned_update.clone(), None)).await.unwrap();
+		let batch = vec![
+			GossipMessage::ChannelUpdate(generate_update(1, false, timestamp, 0, 0, 0, 5, 0), None),
+			GossipMessage::ChannelUpdate(generate_update(2, true, timestamp, 0, 0, 0, 15, 0), None),
+		];
+		sender.send(GossipMessage::Batch(batch, vec![Instant::now(); 2])).await.unwrap();
+		// gossip behind the bad messages is still persisted
+		sender.send(GossipMessage::ChannelUpdate(generate_update(1, true, timestamp, 0, 0, 0, 20, 0), None)).await.unwrap();
+		drop(sender);
+		persister.persist_gossip().await.unwrap();
+		tokio::task::spawn_blocking(move || drop(persister)).await.unwrap();
+
+		let tables = Tables::from_config();
+		let client = crate::connect_to_db().await.unwrap();
+		let update_count: i64 = client.query_one(&format!("SELECT COUNT(*) FROM {} WHERE short_channel_id = 1", tables.channel_updates()), &[]).await.unwrap().get(0);
+		assert_eq!(update_count, 2);
+
+		let dead_letters = client.query(&format!("SELECT message_type, message_signed, error FROM {} ORDER BY id", tables.dead_letters()), &[]).await.unwrap();
+		assert_eq!(dead_letters.len(), 2);
+		for dead_letter in &dead_letters {
+			assert_eq!(dead_letter.get::<_, String>("message_type"), "channel_update");
+			assert!(dead_letter.get::<_, String>("error").contains("foreign key"));
+		}
+		let dead_lettered_updates = dead_letters.iter().map(|dead_letter| ChannelUpdate::read(&mut Cursor::new(dead_letter.get::<_, Vec<u8>>("message_signed"))).unwrap()).collect::<Vec<_>>();
+		assert!(dead_lettered_updates.contains(&orphaned_update));
+		assert!(dead_lettered_updates.iter().all(|update| update.contents.short_channel_id == 2));
+	}
+	assert_eq!(gossip_counter.read().unwrap().persistence_failures, 2);
+	logger.assert_log_contains("rapid_gossip_sync_server::persistence", "Failed to persist channel update for 2", 2);
+
+	clean_test_db().await;
+}
+
+#[tokio::test]
+async fn test_channel_reachability() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let now = current_time();
+	let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+
+	// the first direction updates every 12 hours, the second went quiet three days ago
+	receiver.send(GossipMessage::ChannelAnnouncement(generate_channel_announcement(1), None)).await.unwrap();
+	for cycle in 0..10 {
+		receiver.send(GossipMessage::ChannelUpdate(generate_update(1, false, now - cycle * 3600 * 12, 0, 0, 0, 5, 0), None)).await.unwrap();
+		receiver.send(GossipMessage::ChannelUpdate(generate_update(1, true, now - (cycle + 6) * 3600 * 12, 0, 0, 0, 5, 0), None)).await.unwrap();
+	}
+	receiver.send(GossipMessage::ChannelUpdate(generate_update(2, false, now, 0, 0, 0, 5, 0), None)).await.unwrap();
+	drop(receiver);
+	persister.persist_gossip().await.unwrap();
+	tokio::task::spawn_blocking(move || drop(persister)).await.unwrap();
+
+	let reachability = crate::channel_reachability(ShortChannelId::from(1)).await.unwrap();
+	assert_eq!(reachability.one_to_two.score, 1.0);
+	assert_eq!(reachability.one_to_two.update_count, 10);
+	assert_eq!(reachability.two_to_one.score, 0.0);
+	assert!(reachability.two_to_one.is_low());
+
+	// other channels' updates are only fetched when scoring all of them
+	let client = crate::connect_to_db().await.unwrap();
+	let scores = reachability::fetch_reachability_scores(&client, &Tables::from_config(), None, now).await.unwrap();
+	assert_eq!(scores.len(), 3);
+	assert_eq!(scores[&(2, false)].score, 0.2);
+
+	clean_test_db().await;
+}
+
+#[tokio::test]
+async fn test_fee_histogram() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let now = current_time();
+	let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+
+	receiver.send(GossipMessage::ChannelAnnouncement(generate_channel_announcement(1), None)).await.unwrap();
+	// only the latest update within the range counts
+	receiver.send(GossipMessage::ChannelUpdate(generate_update(1, false, now - 3000, 0, 0, 0, 5000, 100), None)).await.unwrap();
+	receiver.send(GossipMessage::ChannelUpdate(generate_update(1, false, now - 2000, 0, 0, 0, 1000, 0), None)).await.unwrap();
+	// an update after the range supersedes none within it
+	receiver.send(GossipMessage::ChannelUpdate(generate_update(1, false, now, 0, 0, 0, 1, 1), None)).await.unwrap();
+	receiver.send(GossipMessage::ChannelUpdate(generate_update(1, true, now - 2000, 0, 0, 0, 0, 1 << 30), None)).await.unwrap();
+	let mut disabled_update = generate_update(2, false, now - 2000, 0, 0, 0, 1000, 0);
+	disabled_update.contents.flags |= 2;
+	receiver.send(GossipMessage::ChannelAnnouncement(generate_channel_announcement(2), None)).await.unwrap();
+	receiver.send(GossipMessage::ChannelUpdate(disabled_update, None)).await.unwrap();
+	// updates preceding the range mean the direction wasn't active within it
+	receiver.send(GossipMessage::ChannelUpdate(generate_update(2, true, now - 5000, 0, 0, 0, 1000, 0), None)).await.unwrap();
+	drop(receiver);
+	persister.persist_gossip().await.unwrap();
+	tokio::task::spawn_blocking(move || drop(persister)).await.unwrap();
+
+	let histogram = crate::fee_histogram((now - 4000) as u64, (now - 1000) as u64).await.unwrap();
+	clean_test_db().await;
+
+	assert_eq!(histogram.direction_count, 2);
+	assert_eq!(histogram.base_fee_msat_buckets[0], 1);
+	assert_eq!(histogram.base_fee_msat_buckets[11], 1);
+	assert_eq!(histogram.fee_rate_ppm_buckets[0], 1);
+	assert_eq!(histogram.fee_rate_ppm_buckets[histogram.fee_rate_ppm_buckets.len() - 1], 1);
+}
+
+#[tokio::test]
+async fn test_signed_gossip_lookup() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+	let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+	let timestamp = current_time();
+
+	let announcement = generate_channel_announcement(1);
+	let update = generate_update(1, true, timestamp, 0, 0, 0, 5, 0);
+	let node_announcement = generate_node_announcement(None);
+	receiver.send(GossipMessage::ChannelAnnouncement(announcement.clone(), None)).await.unwrap();
+	receiver.send(GossipMessage::ChannelUpdate(generate_update(1, true, timestamp - 10, 0, 0, 0, 5, 0), None)).await.unwrap();
+	receiver.send(GossipMessage::ChannelUpdate(update.clone(), None)).await.unwrap();
+	receiver.send(GossipMessage::NodeAnnouncement(node_announcement.clone(), None)).await.unwrap();
+	drop(receiver);
+	persister.persist_gossip().await.unwrap();
+	tokio::task::spawn_blocking(move || drop(persister)).await.unwrap();
+
+	let signed_announcement = crate::fetch_signed_gossip(&SignedGossipQuery::ChannelAnnouncement { short_channel_id: 1 }).await.unwrap();
+	assert_eq!(signed_announcement, Some(announcement.encode()));
+	let signed_update = crate::fetch_signed_gossip(&SignedGossipQuery::ChannelUpdate { short_channel_id: 1, direction: true, timestamp }).await.unwrap();
+	assert_eq!(signed_update, Some(update.encode()));
+	let node_id = node_announcement.contents.node_id;
+	let signed_node_announcement = crate::fetch_signed_gossip(&SignedGossipQuery::NodeAnnouncement { node_id, timestamp: node_announcement.contents.timestamp }).await.unwrap();
+	assert_eq!(signed_node_announcement, Some(node_announcement.encode()));
+
+	// the other direction, a different timestamp, and unknown channels have nothing persisted
+	assert_eq!(crate::fetch_signed_gossip(&SignedGossipQuery::ChannelUpdate { short_channel_id: 1, direction: false, timestamp }).await.unwrap(), None);
+	assert_eq!(crate::fetch_signed_gossip(&SignedGossipQuery::ChannelUpdate { short_channel_id: 1, direction: true, timestamp: timestamp - 5 }).await.unwrap(), None);
+	assert_eq!(crate::fetch_signed_gossip(&SignedGossipQuery::ChannelAnnouncement { short_channel_id: 2 }).await.unwrap(), None);
+
+	clean_test_db().await;
+}
+
+/// Funds every channel announced by [`generate_channel_announcement`] with an output of the given
+/// value
+struct FundedChannelLookup(u64);
+
+impl UtxoLookup for FundedChannelLookup {
+	fn get_utxo(&self, _chain_hash: &ChainHash, short_channel_id: u64) -> UtxoResult {
+		let announcement = generate_channel_announcement(short_channel_id).contents;
+		let bitcoin_key = |node_id: &NodeId| node_id.as_pubkey().unwrap();
+		let script_pubkey = make_funding_redeemscript(&bitcoin_key(&announcement.bitcoin_key_1), &bitcoin_key(&announcement.bitcoin_key_2)).to_v0_p2wsh();
+		UtxoResult::Sync(Ok(TxOut { value: self.0, script_pubkey }))
+	}
+}
+
+#[tokio::test]
+async fn test_absent_htlc_maximum_substitution() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+	let capacity_sats = 1_000_000;
+	let timestamp = current_time() - 10;
+
+	let announcement = generate_channel_announcement(1);
+	// the peer of direction 0 doesn't set the maximum
+	let legacy_update = generate_update(1, false, timestamp, 0, 0, 0, 5, 0);
+	let update = generate_update(1, true, timestamp, 0, 0, 500_000_000, 10, 0);
+	network_graph_arc.update_channel_from_unsigned_announcement(&announcement.contents, &Some(&FundedChannelLookup(capacity_sats))).unwrap();
+	network_graph_arc.update_channel_unsigned(&legacy_update.contents).unwrap();
+	network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+
+	{ // the announcement's funding output is verified before the updates are persisted
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		receiver.send(GossipMessage::ChannelAnnouncement(announcement, None)).await.unwrap();
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+		tokio::task::spawn_blocking(move || drop(persister)).await.unwrap();
+	}
+	let client = crate::connect_to_db().await.unwrap();
+	let tables = Tables::from_config();
+	client.execute(&format!("UPDATE {} SET capacity_sats = $1 WHERE short_channel_id = 1", tables.channel_announcements()), &[&(capacity_sats as i64)]).await.unwrap();
+	{
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		receiver.send(GossipMessage::ChannelUpdate(legacy_update.clone(), None)).await.unwrap();
+		receiver.send(GossipMessage::ChannelUpdate(update, None)).await.unwrap();
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+		tokio::task::spawn_blocking(move || drop(persister)).await.unwrap();
+	}
+
+	let persisted_maximums: Vec<(bool, i64)> = client.query(&format!("SELECT direction, htlc_maximum_msat FROM {} ORDER BY direction", tables.channel_updates()), &[]).await.unwrap()
+		.iter().map(|row| (row.get("direction"), row.get("htlc_maximum_msat"))).collect();
+	assert_eq!(persisted_maximums, vec![(false, 1_000_000_000), (true, 500_000_000)]);
+
+	// the compact graph agrees, whether copied from the network graph or rebuilt from the database
+	let live_graph = CompactGraph::from_network_graph(&network_graph_arc);
+	let historical_graph = CompactGraph::from_database(&client, &tables, SnapshotClock::wall_clock()).await.unwrap();
+	for compact_graph in [&live_graph, &historical_graph] {
+		let channel = compact_graph.channel(ShortChannelId::from(1)).unwrap();
+		assert_eq!(channel.direction_0.as_ref().unwrap().htlc_maximum_msat, 1_000_000_000);
+		assert_eq!(channel.direction_1.as_ref().unwrap().htlc_maximum_msat, 500_000_000);
+	}
+
+	let delta = calculate_delta(network_graph_arc.clone(), 0, SnapshotClock::wall_clock(), logger.clone()).await.unwrap();
+	assert_eq!(delta.htlc_maximum_substitution_count, 1);
+	let serialization = serialize_delta(&delta, 1, logger.clone());
+	// the signed update keeps what the peer sent
+	let signed_update = crate::fetch_signed_gossip(&SignedGossipQuery::ChannelUpdate { short_channel_id: 1, direction: false, timestamp }).await.unwrap();
+	assert_eq!(signed_update, Some(legacy_update.encode()));
+	clean_test_db().await;
+
+	// and so do the snapshot's clients
+	let client_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+	RapidGossipSync::new(client_graph_arc.clone(), logger.clone()).update_network_graph(&serialization.data).unwrap();
+	let client_channel = client_graph_arc.read_only().channel(1).unwrap().clone();
+	assert_eq!(client_channel.one_to_two.unwrap().htlc_maximum_msat, 1_000_000_000);
+	assert_eq!(client_channel.two_to_one.unwrap().htlc_maximum_msat, 500_000_000);
+}
+
+#[tokio::test]
+async fn test_extended_snapshot_generation() {
+	let schema_sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+	let mut snapshotter = Snapshotter::new(network_graph_arc.clone(), logger.clone());
+	snapshotter.set_extended_snapshots_enabled(true);
+	let cache_sanitizer = CacheSanitizer::new(&schema_sanitizer);
+	let capacity_sats = 1_000_000;
+	let timestamp = current_time() - 10;
+
+	{ // the announcement is persisted without a capacity, like those predating the column
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		let announcement = generate_channel_announcement(1);
+		network_graph_arc.update_channel_from_unsigned_announcement(&announcement.contents, &Some(&FundedChannelLookup(capacity_sats))).unwrap();
+		receiver.send(GossipMessage::ChannelAnnouncement(announcement, None)).await.unwrap();
+		for direction in [false, true] {
+			let update = generate_update(1, direction, timestamp, 0, 0, 500_000_000, 10, 0);
+			network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+			receiver.send(GossipMessage::ChannelUpdate(update, None)).await.unwrap();
+		}
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+		tokio::task::spawn_blocking(move || drop(persister)).await.unwrap();
+	}
+
+	let client = crate::connect_to_db().await.unwrap();
+	let tables = Tables::from_config();
+	assert_eq!(persistence::backfill_capacities(&client, &tables, &network_graph_arc).await.unwrap(), 1);
+	// announcements that have a capacity keep it
+	assert_eq!(persistence::backfill_capacities(&client, &tables, &network_graph_arc).await.unwrap(), 0);
+	let persisted_capacity: Option<i64> = client.query_one(&format!("SELECT capacity_sats FROM {} WHERE short_channel_id = 1", tables.channel_announcements()), &[]).await.unwrap().get(0);
+	assert_eq!(persisted_capacity, Some(capacity_sats as i64));
+
+	let cache_path = cache_sanitizer.cache_path();
+	snapshotter.generate_snapshots(20, 5, &[5, u64::MAX], &cache_path, Some(10)).await.unwrap();
+	clean_test_db().await;
+
+	// the standard snapshot is what clients have always been served
+	let standard_snapshot = fs::read(format!("{}/symlinks/v2/0.bin", cache_path)).unwrap();
+	let standard_summary = snapshot_reader::parse_snapshot(&standard_snapshot).unwrap();
+	assert!(standard_summary.channel_capacities.is_empty());
+	let client_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+	RapidGossipSync::new(client_graph_arc.clone(), logger.clone()).update_network_graph(&standard_snapshot).unwrap();
+	assert!(client_graph_arc.read_only().channel(1).is_some());
+
+	let extended_path = format!("{}/symlinks/v2/0.bin.ext", cache_path);
+	let extended_summary = snapshot_reader::parse_extended_snapshot(&fs::read(&extended_path).unwrap()).unwrap();
+	assert_eq!(extended_summary.channel_capacities, vec![(1, capacity_sats)]);
+	assert_eq!(extended_summary.announcement_count, standard_summary.announcement_count);
+	let header_totals = extended_summary.header_totals.unwrap();
+	assert_eq!((header_totals.node_count, header_totals.channel_count), (2, 1));
+	assert_eq!(header_totals.approximate_capacity_sats(), 0, "a million satoshis are less than one unit");
+	assert!(fs::metadata(format!("{}.sha256", extended_path)).is_ok());
+	// version 1 snapshots have no extended variant
+	assert!(fs::symlink_metadata(format!("{}/symlinks/0.bin.ext", cache_path)).is_err());
+
+	// the validation subcommand recognizes extended snapshots by their header
+	let summary = crate::summarize_snapshot(&extended_path, true).unwrap();
+	assert!(summary.contains("\"extension_version\":2,"), "{}", summary);
+	assert!(summary.contains("\"announced_capacities\":1,"), "{}", summary);
+}
+
+#[tokio::test]
+async fn test_delta_chain_verification() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+	let timestamp = current_time();
+	// a couple of snapshot intervals earlier, lest both generations share their latest seen timestamp
+	let previous_timestamp = timestamp - 2 * config::snapshot_generation_interval();
+
+	{ // the previous generation knows two channels
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		for short_channel_id in [1, 2] {
+			let announcement = generate_channel_announcement(short_channel_id);
+			network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+			receiver.send(GossipMessage::ChannelAnnouncement(announcement, Some(previous_timestamp))).await.unwrap();
+			for direction in [false, true] {
+				let update = generate_update(short_channel_id, direction, previous_timestamp, 0, 0, 0, 5, 38);
+				network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+				receiver.send(GossipMessage::ChannelUpdate(update, Some(previous_timestamp))).await.unwrap();
+			}
+		}
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+		tokio::task::spawn_blocking(move || drop(persister)).await.unwrap();
+	}
+	// LDK's client only applies version 1 snapshots
+	let previous_full_snapshot = serialize_delta(&calculate_delta(network_graph_arc.clone(), 0, SnapshotClock::wall_clock(), logger.clone()).await.unwrap(), 1, logger.clone()).data;
+	let previous_latest_seen = chain_check::latest_seen_timestamp(&previous_full_snapshot).unwrap();
+
+	{ // since then, the first channel's base fee rose, and a third channel was announced
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		let update = generate_update(1, false, timestamp, 0, 0, 0, 10, 38);
+		network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+		receiver.send(GossipMessage::ChannelUpdate(update, None)).await.unwrap();
+		let announcement = generate_channel_announcement(3);
+		network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+		receiver.send(GossipMessage::ChannelAnnouncement(announcement, None)).await.unwrap();
+		for direction in [false, true] {
+			let update = generate_update(3, direction, timestamp, 0, 0, 0, 5, 38);
+			network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+			receiver.send(GossipMessage::ChannelUpdate(update, None)).await.unwrap();
+		}
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+		tokio::task::spawn_blocking(move || drop(persister)).await.unwrap();
+	}
+	let delta = serialize_delta(&calculate_delta(network_graph_arc.clone(), previous_latest_seen, SnapshotClock::wall_clock(), logger.clone()).await.unwrap(), 1, logger.clone()).data;
+	let full_snapshot = serialize_delta(&calculate_delta(network_graph_arc.clone(), 0, SnapshotClock::wall_clock(), logger.clone()).await.unwrap(), 1, logger.clone()).data;
+	clean_test_db().await;
+
+	let report = chain_check::verify_chain(&previous_full_snapshot, &delta, &full_snapshot, &FeeDamping::default()).unwrap();
+	assert!(report.is_intact(), "{}", report.to_text());
+	assert_eq!((report.previous_latest_seen, report.channel_count, report.lingering_channel_count), (previous_latest_seen, 3, 0));
+
+	// a delta that lost the gossip since the previous generation diverges on exactly that gossip
+	let lossy_delta = crate::serialize_empty_blob(report.latest_seen as u64);
+	let report = chain_check::verify_chain(&previous_full_snapshot, &lossy_delta, &full_snapshot, &FeeDamping::default()).unwrap();
+	assert_eq!(report.divergences, vec![(1, ChannelDivergence::Policy { direction: 0 }), (3, ChannelDivergence::MissingFromChain)]);
+	assert!(report.describe_divergences().starts_with("0x0x1 (different direction 0 policy), "), "{}", report.describe_divergences());
+
+	// unless fee damping may have left the fee change out
+	let fee_damping = FeeDamping { base_fee_msat: 5, ..Default::default() };
+	let report = chain_check::verify_chain(&previous_full_snapshot, &lossy_delta, &full_snapshot, &fee_damping).unwrap();
+	assert_eq!(report.divergences, vec![(3, ChannelDivergence::MissingFromChain)]);
+	assert_eq!(report.damped_fee_count, 1);
+}
+
+#[tokio::test]
+async fn test_fee_damping_spares_full_snapshots() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+	let timestamp = current_time() / 3600 * 3600 - 24 * 3600;
+	// clients resync from the hour of the latest gossip they know, so they know an earlier update
+	let updates = [(timestamp - 7200, 1_000), (timestamp - 3600, 1_000), (timestamp, 1_004), (timestamp + 3600, 1_008), (timestamp + 7200, 1_012)];
+
+	{ // a base fee rising by 4 msat every hour
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		let announcement = generate_channel_announcement(1);
+		network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+		receiver.send(GossipMessage::ChannelAnnouncement(announcement, Some(updates[0].0))).await.unwrap();
+		for (seen, base_fee) in updates {
+			for direction in [false, true] {
+				let update = generate_update(1, direction, seen, 0, 0, 0, base_fee, 38);
+				network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+				receiver.send(GossipMessage::ChannelUpdate(update, Some(seen))).await.unwrap();
+			}
+		}
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+		tokio::task::spawn_blocking(move || drop(persister)).await.unwrap();
+	}
+
+	let fee_damping = FeeDamping { base_fee_msat: 10, ..Default::default() };
+	let compact_graph = CompactGraph::from_network_graph(&network_graph_arc);
+	let client_base_fee = |client_graph: &NetworkGraph<Arc<TestLogger>>| client_graph.read_only().channel(1).unwrap().one_to_two.as_ref().unwrap().fees.base_msat;
+	let client_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+	let rgs = RapidGossipSync::new(client_graph_arc.clone(), logger.clone());
+
+	let (full_snapshot, _) = crate::calculate_paced_delta(&compact_graph, 0, SnapshotClock::as_of(updates[1].0 as u64), &LookupPacing::default(), &fee_damping, logger.clone()).await.unwrap();
+	let mut last_sync_timestamp = rgs.update_network_graph(&serialize_delta(&full_snapshot, 1, logger.clone()).data).unwrap();
+	assert_eq!(client_base_fee(&client_graph_arc), 1_000);
+
+	// every delta only compares the latest fees to those of the update preceding the last sync
+	let mut damped_update_counts = Vec::new();
+	for (seen, _) in &updates[2..] {
+		let clock = SnapshotClock::as_of(*seen as u64);
+		let (delta, _) = crate::calculate_paced_delta(&compact_graph, last_sync_timestamp, clock, &LookupPacing::default(), &fee_damping, logger.clone()).await.unwrap();
+		damped_update_counts.push(delta.damped_update_count);
+		last_sync_timestamp = rgs.update_network_graph(&serialize_delta(&delta, 1, logger.clone()).data).unwrap();
+	}
+	assert_eq!(damped_update_counts, vec![2, 2, 2]);
+	assert_eq!(client_base_fee(&client_graph_arc), 1_000);
+
+	// whereas full snapshots carry the latest fees regardless
+	let (full_snapshot, _) = crate::calculate_paced_delta(&compact_graph, 0, SnapshotClock::as_of(updates[4].0 as u64), &LookupPacing::default(), &fee_damping, logger.clone()).await.unwrap();
+	clean_test_db().await;
+	assert_eq!(full_snapshot.damped_update_count, 0);
+	let fresh_client_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+	RapidGossipSync::new(fresh_client_graph_arc.clone(), logger.clone()).update_network_graph(&serialize_delta(&full_snapshot, 1, logger.clone()).data).unwrap();
+	assert_eq!(client_base_fee(&fresh_client_graph_arc), 1_012);
+}
+
+#[tokio::test]
+async fn test_stats_history() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+
+	{ // initialize the db
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+
+		tokio::task::spawn_blocking(move || {
+			drop(persister);
+		}).await.unwrap();
+	}
+
+	let client = crate::connect_to_db().await.unwrap();
+	let tables = Tables::from_config();
+	let mut counter = GossipCounter::new();
+	counter.channel_updates = 500;
+	counter.connected_peers = 3;
+	let coverage = DirectionalCoverage { bidirectional: 90, direction_0_only: 4, direction_1_only: 5, none: 1 };
+	stats_history::record_stats(&client, &tables, &counter, &coverage, None, None, 1000).await.unwrap();
+	counter.channel_updates = 800;
+	stats_history::record_stats(&client, &tables, &counter, &coverage, None, None, 1000).await.unwrap();
+	counter.channel_updates = 20;
+	let bloat = [
+		TableBloat { table: tables.channel_updates(), live_rows: 20, dead_rows: 5, table_bytes: 8192, index_bytes: 16384 },
+		TableBloat { table: tables.channel_announcements(), live_rows: 10, dead_rows: 1, table_bytes: 8192, index_bytes: 8192 },
+	];
+	let client_usage = ClientUsageTotals { ok_responses: 7, not_modified_responses: 3, bytes_served: 70_000, ..Default::default() };
+	stats_history::record_stats(&client, &tables, &counter, &coverage, Some(&bloat), Some(&client_usage), 2000).await.unwrap();
+	let recorded_bloat = client.query(&format!("SELECT dead_rows, index_bytes FROM {} ORDER BY id", tables.stats_history()), &[]).await.unwrap()
+		.iter().map(|row| (row.get::<_, Option<i64>>(0), row.get::<_, Option<i64>>(1))).collect::<Vec<_>>();
+	assert_eq!(recorded_bloat, vec![(None, None), (None, None), (Some(6), Some(24576))]);
+	let recorded_usage = client.query(&format!("SELECT snapshot_downloads, not_modified_responses, bytes_served FROM {} ORDER BY id", tables.stats_history()), &[]).await.unwrap()
+		.iter().map(|row| (row.get::<_, Option<i64>>(0), row.get::<_, Option<i64>>(1), row.get::<_, Option<i64>>(2))).collect::<Vec<_>>();
+	assert_eq!(recorded_usage, vec![(None, None, None), (None, None, None), (Some(7), Some(3), Some(70_000))]);
+
+	// nothing is old enough to be pruned
+	assert_eq!(stats_history::prune_stats(&client, &tables, 1).await.unwrap(), 0);
+	let records = stats_history::fetch_stats(&client, &tables, "1970-01-01").await.unwrap();
+	let future_records = stats_history::fetch_stats(&client, &tables, "2999-01-01").await.unwrap();
+	clean_test_db().await;
+
+	assert_eq!(records.len(), 3);
+	assert!(future_records.is_empty());
+	assert_eq!(records[0].started_at, 1000);
+	assert_eq!(records[0].connected_peers, 3);
+	assert_eq!(records[0].directional_coverage, [90, 4, 5, 1]);
+
+	let deltas = stats_history::compute_deltas(&records);
+	assert_eq!(deltas.len(), 2);
+	assert!(!deltas[0].restarted);
+	assert_eq!(deltas[0].counters[2], 300);
+	assert!(deltas[1].restarted);
+	assert_eq!(deltas[1].counters[2], 20);
+}
+
+#[tokio::test]
+async fn test_learned_peer_address_persistence() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+
+	{ // initialize the db
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+
+		tokio::task::spawn_blocking(move || {
+			drop(persister);
+		}).await.unwrap();
+	}
+
+	let client = crate::connect_to_db().await.unwrap();
+	let tables = Tables::from_config();
+	let secp_context = Secp256k1::new();
+	let pubkey = SecretKey::from_slice(&[1; 32]).unwrap().public_key(&secp_context);
+	let configured: SocketAddr = "127.0.0.1:9735".parse().unwrap();
+	let learned: SocketAddr = "10.0.0.1:9735".parse().unwrap();
+	peer_registry::persist_learned_address(&client, &tables, &pubkey, "10.0.0.2:9735".parse().unwrap()).await.unwrap();
+	// the latest learned address replaces earlier ones
+	peer_registry::persist_learned_address(&client, &tables, &pubkey, learned).await.unwrap();
+
+	let address_book = AddressBook::new();
+	let loaded_count = address_book.load(&client, &tables).await.unwrap();
+	clean_test_db().await;
+
+	assert_eq!(loaded_count, 1);
+	assert_eq!(address_book.candidates(&pubkey, configured), vec![learned, configured]);
+}
+
+#[tokio::test]
+async fn test_peer_failure_history_persistence() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let network_graph_arc = Arc::new(network_graph);
+	let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+
+	{ // initialize the db
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+
+		tokio::task::spawn_blocking(move || {
+			drop(persister);
+		}).await.unwrap();
+	}
+
+	let client = crate::connect_to_db().await.unwrap();
+	let tables = Tables::from_config();
+	let secp_context = Secp256k1::new();
+	let learned_peer = SecretKey::from_slice(&[1; 32]).unwrap().public_key(&secp_context);
+	let failing_peer = SecretKey::from_slice(&[2; 32]).unwrap().public_key(&secp_context);
+	let learned: SocketAddr = "10.0.0.1:9735".parse().unwrap();
+	let now = current_time() as u64;
+	let dormant_history = FailureHistory { consecutive_failures: 30, failing_since: Some(now - 10 * 24 * 3600), last_success_at: Some(now - 11 * 24 * 3600), last_attempt_at: None };
+	peer_registry::persist_learned_address(&client, &tables, &learned_peer, learned).await.unwrap();
+	// failure histories leave learned addresses alone, and get rows of their own otherwise
+	dormancy::persist_failure_history(&client, &tables, &learned_peer, &FailureHistory { consecutive_failures: 2, failing_since: Some(now), ..FailureHistory::default() }).await.unwrap();
+	dormancy::persist_failure_history(&client, &tables, &failing_peer, &dormant_history).await.unwrap();
+
+	let address_book = AddressBook::new();
+	let loaded_address_count = address_book.load(&client, &tables).await.unwrap();
+	let policy = DormancyPolicy { failure_threshold: 20, min_failing_duration: Duration::from_secs(7 * 24 * 3600), target_connected_peers: 5 };
+	let dormancy = DormancyTracker::new(policy);
+	let loaded_history_count = dormancy.load(&client, &tables).await.unwrap();
+	clean_test_db().await;
+
+	assert_eq!(loaded_address_count, 1);
+	assert_eq!(address_book.candidates(&learned_peer, "127.0.0.1:9735".parse().unwrap())[0], learned);
+	assert_eq!(loaded_history_count, 2);
+	assert_eq!(dormancy.dormant_peers(now), vec![(failing_peer, dormant_history)]);
+	// a restart allows dormant peers one attempt right away
+	assert!(dormancy.is_attempt_due(&failing_peer, now, 5));
+}
+
+#[test]
+fn test_fee_stats() {
+	let logger = Arc::new(TestLogger::with_id("test_fee_stats".to_string()));
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let timestamp = current_time();
+
+	{ // a channel charging fees in both directions
+		let announcement = generate_channel_announcement(1);
+		network_graph.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+		network_graph.update_channel_unsigned(&generate_update(1, false, timestamp, 0, 0, 0, 5, 100).contents).unwrap();
+		network_graph.update_channel_unsigned(&generate_update(1, true, timestamp, 0, 0, 0, 10, 200).contents).unwrap();
+	}
+
+	{ // a zero-fee channel
+		let announcement = generate_channel_announcement(2);
+		network_graph.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+		network_graph.update_channel_unsigned(&generate_update(2, false, timestamp, 0, 0, 0, 0, 0).contents).unwrap();
+		network_graph.update_channel_unsigned(&generate_update(2, true, timestamp, 0, 0, 0, 0, 0).contents).unwrap();
+	}
+
+	{ // a channel without any updates must not count as zero-fee
+		let announcement = generate_channel_announcement(3);
+		network_graph.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+	}
+
+	let fee_stats = stats::compute_fee_stats(&network_graph);
+	assert_eq!(fee_stats.direction_count, 4);
+	assert_eq!(fee_stats.zero_fee_channel_count, 1);
+	assert_eq!(fee_stats.median_base_fee_msat, 0);
+	assert_eq!(fee_stats.p90_base_fee_msat, 10);
+	assert_eq!(fee_stats.median_fee_rate_ppm, 0);
+	assert_eq!(fee_stats.p90_fee_rate_ppm, 200);
+	assert_eq!(fee_stats.base_fee_msat_sum, 15);
+	assert_eq!(fee_stats.fee_rate_ppm_sum, 300);
+}
+
+#[test]
+fn test_canary_check() {
+	let logger = Arc::new(TestLogger::with_id("test_canary_check".to_string()));
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let current_time = current_time();
+	let stale_timestamp = current_time - canary::CANARY_MAX_UPDATE_AGE - 1;
+
+	{ // a healthy canary, with only one recently updated direction
+		let announcement = generate_channel_announcement(1);
+		network_graph.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+		network_graph.update_channel_unsigned(&generate_update(1, false, stale_timestamp, 0, 0, 0, 5, 0).contents).unwrap();
+		network_graph.update_channel_unsigned(&generate_update(1, true, current_time - 10, 0, 0, 0, 5, 0).contents).unwrap();
+	}
+
+	{ // a canary whose updates are all stale
+		let announcement = generate_channel_announcement(2);
+		network_graph.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+		network_graph.update_channel_unsigned(&generate_update(2, false, stale_timestamp, 0, 0, 0, 5, 0).contents).unwrap();
+	}
+
+	{ // a canary without any updates
+		let announcement = generate_channel_announcement(3);
+		network_graph.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+	}
+
+	let failures = canary::check_canaries(&network_graph, &[1, 2, 3, 4], current_time);
+	assert_eq!(failures, vec![
+		CanaryFailure::Stale { short_channel_id: 2, last_update: stale_timestamp },
+		CanaryFailure::Stale { short_channel_id: 3, last_update: 0 },
+		CanaryFailure::Missing(4),
+	]);
+}
+
+#[test]
+fn test_parquet_export() {
+	let logger = Arc::new(TestLogger::with_id("test_parquet_export".to_string()));
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let timestamp = current_time();
+
+	{ // a channel with an update in one direction only
+		let announcement = generate_channel_announcement(2);
+		network_graph.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+		network_graph.update_channel_unsigned(&generate_update(2, true, timestamp, 0, 0, 100_000, 10, 200).contents).unwrap();
+	}
+
+	{ // a channel without any updates
+		let announcement = generate_channel_announcement(1);
+		network_graph.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+	}
+
+	let columns = analytics_export::collect_channel_directions(&network_graph, None);
+	assert_eq!(columns.scid, vec![1, 1, 2, 2]);
+	assert_eq!(columns.node1[0], columns.node2[1]);
+	assert_eq!(columns.node2[0], columns.node1[1]);
+	assert_eq!(columns.last_update_ts, vec![None, None, None, Some(timestamp)]);
+	assert_eq!(columns.base_fee_msat, vec![None, None, None, Some(10)]);
+	assert_eq!(columns.fee_rate_ppm, vec![None, None, None, Some(200)]);
+	assert_eq!(columns.htlc_max_msat, vec![None, None, None, Some(100_000)]);
+	assert_eq!(columns.is_disabled, vec![None, None, None, Some(false)]);
+	assert_eq!(columns.reachability_score, vec![None; 4]);
+
+	// only the updated direction has a score, so all others are unreachable
+	let reachability = HashMap::from([((2, true), ReachabilityScore::from_update_timestamps(&[timestamp], timestamp))]);
+	let columns = analytics_export::collect_channel_directions(&network_graph, Some(&reachability));
+	assert_eq!(columns.reachability_score, vec![Some(0.0), Some(0.0), Some(0.0), Some(0.2)]);
+
+	let export_path = format!("{}/test_parquet_export_{}.parquet", std::env::temp_dir().display(), timestamp);
+	analytics_export::export_parquet(&network_graph, Some(&reachability), &export_path).unwrap();
+	let metadata = arrow2::io::parquet::read::read_metadata(&mut fs::File::open(&export_path).unwrap()).unwrap();
+	assert_eq!(metadata.num_rows, 4);
+	fs::remove_file(&export_path).unwrap();
+}
+
+#[test]
+fn test_graph_anonymization() {
+	let logger = Arc::new(TestLogger::with_id("test_graph_anonymization".to_string()));
+	let network_graph = NetworkGraph::new(Network::Bitcoin, logger.clone());
+	let timestamp = current_time();
+	for short_channel_id in [5, 7, 9] {
+		let announcement = generate_channel_announcement(short_channel_id);
+		network_graph.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+		network_graph.update_channel_unsigned(&generate_update(short_channel_id, false, timestamp, 0, 0, 100_000, 10, 200).contents).unwrap();
+	}
+
+	let plain = graph_dump::dump_graph(&network_graph);
+	let anonymized = graph_dump::anonymize_graph(&network_graph, &[1; 32]);
+	assert_eq!(plain.channels.iter().map(|channel| channel.id).collect::<Vec<_>>(), vec![5, 7, 9]);
+	assert_eq!(anonymized.channels.iter().map(|channel| channel.id).collect::<Vec<_>>(), vec![0, 1, 2]);
+	assert_eq!(anonymized.nodes.len(), plain.nodes.len());
+
+	// no node id survives anonymization, while the topology and policies do
+	let plain_node_ids: Vec<&String> = plain.nodes.iter().map(|node| &node.id).collect();
+	assert!(anonymized.nodes.iter().all(|node| !plain_node_ids.contains(&&node.id)));
+	for channel in &anonymized.channels {
+		assert!(anonymized.nodes.iter().any(|node| node.id == channel.node_one));
+		assert!(anonymized.nodes.iter().any(|node| node.id == channel.node_two));
+		assert_eq!(channel.one_to_two.as_ref().map(|policy| policy.fee_base_msat), Some(10));
+		assert_eq!(channel.two_to_one, None);
+	}
+	let mut channel_counts: Vec<usize> = anonymized.nodes.iter().map(|node| node.channel_count).collect();
+	let mut plain_channel_counts: Vec<usize> = plain.nodes.iter().map(|node| node.channel_count).collect();
+	channel_counts.sort_unstable();
+	plain_channel_counts.sort_unstable();
+	assert_eq!(channel_counts, plain_channel_counts);
+
+	// pseudonyms are stable for a seed, and change with it
+	assert_eq!(graph_dump::anonymize_graph(&network_graph, &[1; 32]), anonymized);
+	let reseeded = graph_dump::anonymize_graph(&network_graph, &[2; 32]);
+	assert!(reseeded.nodes.iter().all(|node| !anonymized.nodes.contains(node)));
+	assert!(serde_json::to_string(&anonymized).unwrap().contains("\"channels\":[{\"id\":0,"));
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_paced_lookups() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+	let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+	let timestamp = current_time() - 10;
+
+	{ // seed the db
+		for short_channel_id in 1..=3 {
+			let announcement = generate_channel_announcement(short_channel_id);
+			network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+			receiver.send(GossipMessage::ChannelAnnouncement(announcement, Some(timestamp - 20))).await.unwrap();
+			for direction in [false, true] {
+				let update_1 = generate_update(short_channel_id, direction, timestamp - 20, 0, 0, 0, 5, 0);
+				let update_2 = generate_update(short_channel_id, direction, timestamp, 0, 0, 0, 10, 0);
+				network_graph_arc.update_channel_unsigned(&update_2.contents).unwrap();
+				receiver.send(GossipMessage::ChannelUpdate(update_1, Some(timestamp - 20))).await.unwrap();
+				receiver.send(GossipMessage::ChannelUpdate(update_2, Some(timestamp))).await.unwrap();
+			}
+		}
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+	}
+
+	let (unpaced_delta, unpaced_load) = crate::calculate_paced_delta(&CompactGraph::from_network_graph(&network_graph_arc), timestamp - 10, SnapshotClock::wall_clock(), &LookupPacing::default(), &FeeDamping::default(), logger.clone()).await.unwrap();
+	let pacing = LookupPacing {
+		max_rows_per_second: Some(50),
+		statement_timeout: Some(Duration::from_secs(10)),
+		work_mem: Some("4MB".to_string()),
+	};
+	let (paced_delta, paced_load) = crate::calculate_paced_delta(&CompactGraph::from_network_graph(&network_graph_arc), timestamp - 10, SnapshotClock::wall_clock(), &pacing, &FeeDamping::default(), logger.clone()).await.unwrap();
+	clean_test_db().await;
+
+	// pacing must only change how the rows are read, not which
+	assert_eq!(serialize_delta(&paced_delta, 2, logger.clone()).data, serialize_delta(&unpaced_delta, 2, logger.clone()).data);
+	assert_eq!(paced_load.rows_read, unpaced_load.rows_read);
+	assert!(paced_load.rows_read > 0);
+	// batches of five rows each, plus a final short one per query
+	assert!(paced_load.batches_fetched > unpaced_load.batches_fetched);
+
+	tokio::task::spawn_blocking(move || drop(persister)).await.unwrap();
+}
+
+/// Compare the time taken to persist a burst of 10,000 messages arriving at 10,000 msgs/sec when
+/// they're forwarded one by one vs. in batches. Run with `cargo test -- --ignored --nocapture`.
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn bench_batched_persistence() {
+	const MESSAGE_COUNT: u64 = 10_000;
+	const MESSAGE_INTERVAL: Duration = Duration::from_micros(100);
+
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+	let timestamp = current_time();
+
+	let per_message_duration = {
+		let (mut persister, sender) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		let started_at = Instant::now();
+		let producer = tokio::spawn(async move {
+			let mut interval = tokio::time::interval(MESSAGE_INTERVAL);
+			for scid in 0..MESSAGE_COUNT {
+				interval.tick().await;
+				let update = generate_update(scid, false, timestamp, 0, 0, 0, 5, 0);
+				sender.send(GossipMessage::ChannelUpdate(update, None)).await.unwrap();
+			}
+		});
+		persister.persist_gossip().await.unwrap();
+		producer.await.unwrap();
+		let duration = started_at.elapsed();
+		tokio::task::spawn_blocking(move || drop(persister)).await.unwrap();
+		duration
+	};
+
+	let batched_duration = {
+		let (mut persister, sender) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		let batcher = Arc::new(MessageBatcher::with_limits(sender, 100, Duration::from_millis(50)));
+		let started_at = Instant::now();
+		let producer = tokio::spawn(async move {
+			let mut interval = tokio::time::interval(MESSAGE_INTERVAL);
+			for scid in MESSAGE_COUNT..(2 * MESSAGE_COUNT) {
+				interval.tick().await;
+				let update = generate_update(scid, false, timestamp, 0, 0, 0, 5, 0);
+				batcher.push(GossipMessage::ChannelUpdate(update, None), Instant::now());
+			}
+			batcher.flush().await.unwrap();
+		});
+		persister.persist_gossip().await.unwrap();
+		producer.await.unwrap();
+		let duration = started_at.elapsed();
+		tokio::task::spawn_blocking(move || drop(persister)).await.unwrap();
+		duration
+	};
+
+	let client = crate::connect_to_db().await.unwrap();
+	let persisted_count: i64 = client.query_one("SELECT COUNT(*) FROM channel_updates", &[]).await.unwrap().get(0);
+	clean_test_db().await;
+
+	println!("per-message persistence: {:?}", per_message_duration);
+	println!("batched persistence: {:?}", batched_duration);
+	assert_eq!(persisted_count, 2 * MESSAGE_COUNT as i64);
+}
+
+/// Compare the time taken to verify the signatures of a burst of 10,000 channel updates on a single
+/// validation thread vs. on four. Run with `cargo test -- --ignored --nocapture`.
+#[tokio::test(flavor = "multi_thread")]
+#[ignore]
+async fn bench_parallel_validation() {
+	const UPDATE_COUNT: u32 = 10_000;
+	const CHANNEL_COUNT: u64 = 100;
+
+	let secp_context = Secp256k1::new();
+	let node_key_1 = SecretKey::from_slice(&[1; 32]).unwrap();
+	let node_key_2 = SecretKey::from_slice(&[2; 32]).unwrap();
+	let logger = Arc::new(TestLogger::with_id("bench_parallel_validation".to_string()));
+	let network_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+	for scid in 0..CHANNEL_COUNT {
+		network_graph_arc.add_channel_from_partial_announcement(scid, 0, ChannelFeatures::empty(), node_key_1.public_key(&secp_context), node_key_2.public_key(&secp_context)).unwrap();
+	}
+
+	let timestamp = current_time();
+	let updates: Vec<ChannelUpdate> = (0..UPDATE_COUNT).map(|index| {
+		let mut update = generate_update(index as u64 % CHANNEL_COUNT, false, timestamp + index, 0, 0, 0, 5, 0);
+		let msg_hash = bitcoin::secp256k1::Message::from_slice(&Sha256dHash::hash(&update.contents.encode()[..])[..]).unwrap();
+		update.signature = secp_context.sign_ecdsa(&msg_hash, &node_key_1);
+		update
+	}).collect();
+
+	let mut durations = Vec::new();
+	for thread_count in [1, 4] {
+		let pool = ValidationPool::with_threads(network_graph_arc.clone(), thread_count, thread_count);
+		let started_at = Instant::now();
+		let validations: Vec<_> = updates.iter()
+			.map(|update| pool.validate_async(GossipMessage::ChannelUpdate(update.clone(), None)))
+			.collect();
+		for validation in validations {
+			assert_eq!(validation.await, ValidationResult::Valid);
+		}
+		durations.push(started_at.elapsed());
+	}
+
+	println!("1-thread validation: {:?}", durations[0]);
+	println!("4-thread validation: {:?}", durations[1]);
+	println!("speedup: {:.2}x", durations[0].as_secs_f64() / durations[1].as_secs_f64());
+}
+
+#[tokio::test]
+async fn test_conflicting_announcement_replaces_stored_one() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+	let timestamp = current_time() - 10;
+	let conflicting_announcement = generate_channel_announcement_with_keys(1, SecretKey::from_slice(&[3; 32]).unwrap(), SecretKey::from_slice(&[4; 32]).unwrap());
+
+	{ // the network graph only hands the persister conflicting announcements it verified on chain
+		let (mut persister, sender) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		sender.send(GossipMessage::ChannelAnnouncement(generate_channel_announcement(1), Some(timestamp - 5))).await.unwrap();
+		// duplicates between the same nodes change nothing
+		sender.send(GossipMessage::ChannelAnnouncement(generate_channel_announcement(1), Some(timestamp - 4))).await.unwrap();
+		sender.send(GossipMessage::ChannelUpdate(generate_update(1, false, timestamp - 3, 0, 0, 0, 5, 0), None)).await.unwrap();
+		sender.send(GossipMessage::ChannelAnnouncement(conflicting_announcement.clone(), Some(timestamp))).await.unwrap();
+		drop(sender);
+		persister.persist_gossip().await.unwrap();
+		tokio::task::spawn_blocking(move || drop(persister)).await.unwrap();
+	}
+
+	let tables = Tables::from_config();
+	let client = crate::connect_to_db().await.unwrap();
+	let row = client.query_one(&format!("SELECT announcement_signed, EXTRACT(EPOCH FROM seen)::bigint AS seen, funding_block_time IS NULL AS unfunded FROM {} WHERE short_channel_id = 1", tables.channel_announcements()), &[]).await.unwrap();
+	let stored_announcement = ChannelAnnouncement::read(&mut Cursor::new(row.get::<_, Vec<u8>>("announcement_signed"))).unwrap();
+	assert_eq!(stored_announcement, conflicting_announcement);
+	assert_eq!(row.get::<_, i64>("seen"), timestamp as i64);
+	assert!(row.get::<_, bool>("unfunded"));
+	// the previous nodes' updates don't apply to the new ones
+	let update_count: i64 = client.query_one(&format!("SELECT COUNT(*) FROM {} WHERE short_channel_id = 1", tables.channel_updates()), &[]).await.unwrap().get(0);
+	assert_eq!(update_count, 0);
+	let removal_count: i64 = client.query_one(&format!("SELECT COUNT(*) FROM {} WHERE short_channel_id = 1", tables.channel_removals()), &[]).await.unwrap().get(0);
+	assert_eq!(removal_count, 1);
+
+	clean_test_db().await;
+}
+
+#[tokio::test]
+async fn test_conflicting_announcement_keeps_stored_one() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+	let stored_announcement = generate_channel_announcement(1);
+	{ // create the tables, and store the known announcement
+		let (mut persister, sender) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		sender.send(GossipMessage::ChannelAnnouncement(stored_announcement.clone(), None)).await.unwrap();
+		drop(sender);
+		persister.persist_gossip().await.unwrap();
+		tokio::task::spawn_blocking(move || drop(persister)).await.unwrap();
+	}
+	network_graph_arc.update_channel_from_announcement_no_lookup(&stored_announcement).unwrap();
+
+	// nothing answers on the chain backend's port, so the new announcement's funding output never checks out
+	let counter = Arc::new(RwLock::new(GossipCounter::new()));
+	let chain_backend = HttpEndpoint::for_host("127.0.0.1".to_string()).with_port(1);
+	let (sender, _receiver) = mpsc::channel(10);
+	let router = GossipRouter::new(network_graph_arc.clone(), sender, Arc::clone(&counter), chain_backend, Arc::new(ChainBackendStats::new()), logger.clone());
+	let conflicting_announcement = generate_channel_announcement_with_keys(1, SecretKey::from_slice(&[3; 32]).unwrap(), SecretKey::from_slice(&[4; 32]).unwrap());
+	let _ = router.handle_channel_announcement(&stored_announcement);
+	let _ = router.handle_channel_announcement(&conflicting_announcement);
+
+	let tables = Tables::from_config();
+	let client = crate::connect_to_db().await.unwrap();
+	let dead_letter_query = format!("SELECT message_signed, error FROM {} WHERE message_type = 'channel_announcement'", tables.dead_letters());
+	let mut dead_letters = Vec::new();
+	for _ in 0..100 {
+		dead_letters = client.query(&dead_letter_query, &[]).await.unwrap();
+		if !dead_letters.is_empty() {
+			break;
+		}
+		tokio::time::sleep(Duration::from_millis(50)).await;
+	}
+	assert_eq!(dead_letters.len(), 1);
+	let dead_lettered_announcement = ChannelAnnouncement::read(&mut Cursor::new(dead_letters[0].get::<_, Vec<u8>>("message_signed"))).unwrap();
+	assert_eq!(dead_lettered_announcement, conflicting_announcement);
+	assert!(dead_letters[0].get::<_, String>("error").contains("conflicts with the known announcement"));
+	assert_eq!(counter.read().unwrap().conflicting_announcements, 1);
+	logger.assert_log_contains("rapid_gossip_sync_server::downloader", "Channel 1 is known between nodes", 1);
+
+	// neither the graph nor the database adopted the new nodes
+	{
+		let read_only_graph = network_graph_arc.read_only();
+		let channel = read_only_graph.channel(1).unwrap();
+		assert_eq!((channel.node_one, channel.node_two), (stored_announcement.contents.node_id_1, stored_announcement.contents.node_id_2));
+	}
+	let announcement_signed: Vec<u8> = client.query_one(&format!("SELECT announcement_signed FROM {} WHERE short_channel_id = 1", tables.channel_announcements()), &[]).await.unwrap().get(0);
+	assert_eq!(ChannelAnnouncement::read(&mut Cursor::new(announcement_signed)).unwrap(), stored_announcement);
+
+	clean_test_db().await;
+}
+
+#[tokio::test]
+async fn test_reorged_out_channel_removal() {
+	let _sanitizer = SchemaSanitizer::new();
+	let logger = Arc::new(TestLogger::new());
+	let network_graph_arc = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+	let timestamp = current_time() - 10;
+	// both channels are funded in the same block, the second one in its second transaction
+	let kept_scid = u64::from(ShortChannelId::new(101, 0, 0).unwrap());
+	let reorged_scid = u64::from(ShortChannelId::new(101, 1, 0).unwrap());
+
+	let announcement = generate_channel_announcement(kept_scid).contents;
+	let bitcoin_key = |node_id: &NodeId| node_id.as_pubkey().unwrap();
+	let funding_script = make_funding_redeemscript(&bitcoin_key(&announcement.bitcoin_key_1), &bitcoin_key(&announcement.bitcoin_key_2)).to_v0_p2wsh();
+	let funding_transaction = |value: u64| Transaction { version: 2, lock_time: LockTime::ZERO, input: vec![], output: vec![TxOut { value, script_pubkey: funding_script.clone() }] };
+	let chain = Arc::new(ScriptedChain::new(100));
+	chain.mine(vec![funding_transaction(1), funding_transaction(2)]);
+
+	{
+		let (mut persister, receiver) = GossipPersister::new(network_graph_arc.clone(), logger.clone());
+		for short_channel_id in [kept_scid, reorged_scid] {
+			let announcement = generate_channel_announcement(short_channel_id);
+			network_graph_arc.update_channel_from_announcement_no_lookup(&announcement).unwrap();
+			receiver.send(GossipMessage::ChannelAnnouncement(announcement, None)).await.unwrap();
+			let update = generate_update(short_channel_id, false, timestamp, 0, 0, 0, 5, 0);
+			network_graph_arc.update_channel_unsigned(&update.contents).unwrap();
+			receiver.send(GossipMessage::ChannelUpdate(update, None)).await.unwrap();
+		}
+		drop(receiver);
+		persister.persist_gossip().await.unwrap();
+		tokio::task::spawn_blocking(move || drop(persister)).await.unwrap();
+	}
+
+	// the competing block only confirms the first funding transaction
+	chain.reorg(101);
+	chain.mine(vec![funding_transaction(1)]);
+	let verifier = scripted_verifier(network_graph_arc.clone(), chain, logger.clone());
+	verifier.heal_reorg(101).await;
+
+	let read_only_graph = network_graph_arc.read_only();
+	assert!(read_only_graph.channel(kept_scid).is_some());
+	assert!(read_only_graph.channel(reorged_scid).is_none());
+	drop(read_only_graph);
+	let reorg_counter = verifier.reorg_counter.read().unwrap().clone();
+	assert_eq!((reorg_counter.reorgs_detected, reorg_counter.channels_invalidated), (1, 1));
+
+	let tables = Tables::from_config();
+	let client = crate::connect_to_db().await.unwrap();
+	for (short_channel_id, expected_counts) in [(kept_scid, [1, 1, 0]), (reorged_scid, [0, 0, 1])] {
+		for (table, expected_count) in [tables.channel_announcements(), tables.channel_updates(), tables.channel_removals()].into_iter().zip(expected_counts) {
+			let count: i64 = client.query_one(&format!("SELECT COUNT(*) FROM {} WHERE short_channel_id = $1", table), &[&(short_channel_id as i64)]).await.unwrap().get(0);
+			assert_eq!(count, expected_count, "{} rows of channel {}", table, short_channel_id);
+		}
+	}
+
+	clean_test_db().await;
+}
@@ -1,31 +1,108 @@
+use std::collections::HashSet;
 use std::collections::hash_map::RandomState;
 use std::hash::{BuildHasher, Hasher};
 use std::net::SocketAddr;
 use std::ops::Deref;
-use std::sync::Arc;
-use std::time::{Duration, Instant};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use bitcoin::secp256k1::PublicKey;
 use hex_conservative::display::DisplayHex;
 use lightning::ln::peer_handler::{
-	ErroringMessageHandler, IgnoringMessageHandler, MessageHandler, PeerManager,
+	ErroringMessageHandler, MessageHandler, PeerManager,
 };
 use lightning::{log_info, log_warn};
 use lightning::routing::gossip::NetworkGraph;
 use lightning::sign::KeysManager;
 use lightning::util::logger::Logger;
+use lightning_block_sync::http::HttpEndpoint;
 use tokio::sync::mpsc;
 use tokio::task::JoinSet;
 
-use crate::config;
-use crate::downloader::GossipRouter;
-use crate::types::{GossipMessage, GossipPeerManager};
+use crate::batcher::MessageBatcher;
+use crate::catch_up::{self, CatchUpEvent, CatchUpTracker};
+use crate::chain_stats::ChainBackendStats;
+use crate::change_tracker::ChangeTracker;
+use crate::{config, persistence, stats, webhook};
+use crate::counting_handler::CountingMessageHandler;
+use crate::debug_dump::{CatchUpDebugInfo, DebugState, VerifierDebugInfo};
+use crate::dormancy::DormancyTracker;
+use crate::downloader::{GossipCounter, GossipRouter};
+use crate::error::{ErrorContext, ProcessorError};
+use crate::gossip_archive::GossipArchive;
+use crate::gossip_consumers::GossipConsumers;
+use crate::inject::GossipInjector;
+use crate::peer_connector::{ConnectorEvent, LdkDialer, PeerConnector};
+use crate::peer_registry::{AddressBook, SilentPeerPinger};
+use crate::propagation::{self, PropagationDelays, PropagationLagDetector};
+use crate::sync_progress::{self, SyncProgress, SyncTotal};
+use crate::tables::Tables;
+use crate::types::GossipMessage;
+use crate::verifier::{ChainVerifier, FundingOutputs};
+
+/// How far before the watermark gossip is requested after a restart, to account for peers whose
+/// clocks are slightly behind
+const WATERMARK_RESUME_MARGIN: Duration = Duration::from_secs(60 * 10);
+
+/// How many tracking iterations pass between pings of silent peers and checks for idle ones, i. e.
+/// every 30 seconds
+const SILENT_PEER_PING_ITERATIONS: u32 = 6;
+
+/// How many tracking iterations make up a window of propagation delays, i. e. five minutes
+const PROPAGATION_WINDOW_ITERATIONS: u32 = 60;
+
+/// How many consecutive windows the median propagation delay must exceed its threshold for before
+/// it's warned about
+const PROPAGATION_LAG_WINDOWS: u32 = 3;
+
+/// How often the channels with the most updates are logged
+const VELOCITY_REPORT_INTERVAL: Duration = Duration::from_secs(3600 * 24);
+
+/// How often the dormant peers are logged, for operators to clean up their peer lists
+const DORMANT_PEER_REPORT_INTERVAL: Duration = Duration::from_secs(3600 * 24 * 7);
+
+/// Runs a closure once dropped
+struct OnDrop<F: FnOnce()>(Option<F>);
+
+impl<F: FnOnce()> OnDrop<F> {
+	fn new(on_drop: F) -> Self {
+		Self(Some(on_drop))
+	}
+}
+
+impl<F: FnOnce()> Drop for OnDrop<F> {
+	fn drop(&mut self) {
+		if let Some(on_drop) = self.0.take() {
+			on_drop();
+		}
+	}
+}
+
+/// The processor's state a run of [`download_gossip`] feeds, which outlives restarts of the run
+#[derive(Clone)]
+pub(crate) struct TrackingContext<L: Deref + Clone + Send + Sync + 'static> where L::Target: Logger {
+	pub(crate) persistence_sender: mpsc::Sender<GossipMessage>,
+	/// Signalled once the initial sync has completed
+	pub(crate) completion_sender: mpsc::Sender<()>,
+	pub(crate) network_graph: Arc<NetworkGraph<L>>,
+	pub(crate) counter: Arc<RwLock<GossipCounter>>,
+	pub(crate) peers: Vec<(PublicKey, SocketAddr)>,
+	pub(crate) backend_stats: Arc<ChainBackendStats>,
+	pub(crate) funding_outputs: Arc<FundingOutputs>,
+	pub(crate) debug_state: Arc<DebugState>,
+	pub(crate) injector: Arc<GossipInjector<L>>,
+	pub(crate) change_tracker: Arc<ChangeTracker>,
+	pub(crate) gossip_archive: Option<Arc<GossipArchive>>,
+	pub(crate) gossip_consumers: Arc<GossipConsumers>,
+	pub(crate) logger: L,
+}
+
+pub(crate) async fn download_gossip<L: Deref + Clone + Send + Sync + 'static>(context: TrackingContext<L>, chain_backend: HttpEndpoint) -> Result<(), ProcessorError> where L::Target: Logger {
+	let TrackingContext {
+		persistence_sender, completion_sender, network_graph, counter, peers, backend_stats, funding_outputs, debug_state,
+		injector, change_tracker, gossip_archive, gossip_consumers, logger,
+	} = context;
 
-pub(crate) async fn download_gossip<L: Deref + Clone + Send + Sync + 'static>(persistence_sender: mpsc::Sender<GossipMessage>,
-	completion_sender: mpsc::Sender<()>,
-	network_graph: Arc<NetworkGraph<L>>,
-	logger: L,
-) where L::Target: Logger {
 	let mut key = [42; 32];
 	let mut random_data = [43; 32];
 	// Get something psuedo-random from std.
@@ -36,27 +113,62 @@ pub(crate) async fn download_gossip<L: Deref + Clone + Send + Sync + 'static>(pe
 	rand_hasher.write_u8(2);
 	random_data[0..8].copy_from_slice(&rand_hasher.finish().to_ne_bytes());
 
-	let keys_manager = Arc::new(KeysManager::new(&key, 0xdeadbeef, 0xdeadbeef));
+	// The KeysManager derives the keys of every channel it's asked for from the seed, the starting
+	// time, and a counter, so the starting time must differ across restarts with the same seed. We
+	// don't open channels, but the actual time keeps that true should that ever change.
+	let starting_time = SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch");
+	let keys_manager = Arc::new(KeysManager::new(&key, starting_time.as_secs(), starting_time.subsec_nanos()));
 
-	let router = Arc::new(GossipRouter::new(network_graph, persistence_sender.clone(), logger.clone()));
+	let resume_timestamp = gossip_resume_timestamp(&logger).await;
+	let mut router = GossipRouter::new(Arc::clone(&network_graph), persistence_sender.clone(), counter, chain_backend, Arc::clone(&backend_stats), logger.clone());
+	if let Some(resume_timestamp) = resume_timestamp {
+		router.set_resume_timestamp(resume_timestamp);
+	}
+	router.verifier.set_funding_outputs(funding_outputs);
+	router.set_change_tracker(change_tracker);
+	if let Some(gossip_archive) = gossip_archive {
+		router.set_gossip_archive(gossip_archive);
+	}
+	router.set_gossip_consumers(gossip_consumers);
+	let router = Arc::new(router);
+	injector.set_router(Arc::downgrade(&router));
+	restore_learned_addresses(&router.address_book, &logger).await;
+	debug_state.set_peer_stats(Arc::clone(&router.peer_stats));
+
+	let mut ignored_message_handler = CountingMessageHandler::new(Arc::clone(&router.counter), logger.clone());
+	if let Some(query_replies) = router.query_replies() {
+		ignored_message_handler.set_query_replies(query_replies);
+	}
+	let ignored_message_handler = Arc::new(ignored_message_handler);
 
 	let message_handler = MessageHandler {
 		chan_handler: ErroringMessageHandler::new(),
 		route_handler: Arc::clone(&router),
-		onion_message_handler: IgnoringMessageHandler {},
-		custom_message_handler: IgnoringMessageHandler {},
+		onion_message_handler: Arc::clone(&ignored_message_handler),
+		custom_message_handler: ignored_message_handler,
 	};
 	let peer_handler = Arc::new(PeerManager::new(
 		message_handler,
-		0xdeadbeef,
+		// the timestamp our own gossip, which we never send, would be based on
+		starting_time.as_secs() as u32,
 		&random_data,
 		logger.clone(),
 		keys_manager,
 	));
 	router.set_pm(Arc::clone(&peer_handler));
+	// once tracking stops, be it to be restarted, its connections and background tasks mustn't keep
+	// the router and the PeerManager alive, lest they keep handling gossip next to their successors
+	let _peer_disconnector = OnDrop::new({
+		let peer_handler = Arc::clone(&peer_handler);
+		move || peer_handler.disconnect_all_peers()
+	});
+	let mut background_tasks = JoinSet::new();
+	background_tasks.spawn(ChainVerifier::probe_backend_latency(Arc::clone(&router.verifier)));
+	background_tasks.spawn(ChainVerifier::follow_chain_tip(Arc::clone(&router.verifier)));
+	background_tasks.spawn(MessageBatcher::flush_periodically(Arc::clone(&router.batcher)));
 
 	let ph_timer = Arc::clone(&peer_handler);
-	tokio::spawn(async move {
+	background_tasks.spawn(async move {
 		let mut intvl = tokio::time::interval(Duration::from_secs(10));
 		loop {
 			intvl.tick().await;
@@ -64,136 +176,346 @@ pub(crate) async fn download_gossip<L: Deref + Clone + Send + Sync + 'static>(pe
 		}
 	});
 
-	log_info!(logger, "Connecting to Lightning peers...");
-	let peers = config::ln_peers();
-	let mut handles = JoinSet::new();
+	let connect_concurrency = config::peer_connect_concurrency();
+	log_info!(logger, "Connecting to {} Lightning peers, at most {} at a time...", peers.len(), connect_concurrency);
 	let mut connected_peer_count = 0;
 
 	if peers.len() <= config::CONNECTED_PEER_ASSERTION_LIMIT {
 		log_warn!(logger, "Peer assertion threshold is {}, but only {} peers specified.", config::CONNECTED_PEER_ASSERTION_LIMIT, peers.len());
 	}
 
+	let dialer = Arc::new(LdkDialer::new(Arc::clone(&peer_handler), config::local_bind_addresses()));
+	let dormancy = Arc::new(DormancyTracker::persisted(config::dormancy_policy(), Tables::from_config()));
+	restore_failure_histories(&dormancy, &logger).await;
+	let (peer_connector, mut connector_events) = PeerConnector::new(dialer, Arc::clone(&router.address_book), Arc::clone(&router.peer_stats), Arc::clone(&dormancy), connect_concurrency, logger.clone());
+	let peer_connector = Arc::new(peer_connector);
+	let mut pending_first_attempts: HashSet<PublicKey> = peers.iter().map(|(pubkey, _)| *pubkey).collect();
 	for current_peer in peers {
-		let peer_handler_clone = peer_handler.clone();
-		let logger_clone = logger.clone();
-		handles.spawn(async move {
-			connect_peer(current_peer, peer_handler_clone, logger_clone).await
-		});
+		peer_connector.add_peer(current_peer);
 	}
+	background_tasks.spawn(PeerConnector::reload_on_hangup(Arc::downgrade(&peer_connector), logger.clone()));
+	let silent_peer_pinger = SilentPeerPinger::new(Arc::clone(&peer_handler), Arc::clone(&router.peer_stats), Arc::clone(&router.pending_range_queries), Arc::clone(&router.counter), logger.clone());
+	let peer_silence_threshold_secs = config::peer_silence_threshold_secs();
+	let max_peer_idle_secs = config::max_peer_idle_secs();
 
-	while let Some(connection_result) = handles.join_next().await {
-		if let Ok(connection) = connection_result {
-			if connection {
-				connected_peer_count += 1;
-				if connected_peer_count >= config::CONNECTED_PEER_ASSERTION_LIMIT {
-					break;
+	while !pending_first_attempts.is_empty() && connected_peer_count < config::CONNECTED_PEER_ASSERTION_LIMIT {
+		match connector_events.recv().await {
+			Some(ConnectorEvent::Connected(pubkey, _)) => {
+				if pending_first_attempts.remove(&pubkey) {
+					connected_peer_count += 1;
 				}
-			}
+			},
+			Some(ConnectorEvent::FirstAttemptFailed(pubkey)) => {
+				pending_first_attempts.remove(&pubkey);
+			},
+			Some(ConnectorEvent::Disconnected(_)) => {},
+			None => break,
 		}
 	}
+	// the connection tasks log whatever happens to the connections from here on
+	drop(connector_events);
 
 	if connected_peer_count < 1 {
-		panic!("Failed to connect to any peer.");
+		return Err(ProcessorError::NoPeersConnected);
 	}
 
 	log_info!(logger, "Connected to {} Lightning peers!", connected_peer_count);
+	let connection_time = Instant::now();
+	let max_catch_up_time = config::max_catch_up_time();
+	let min_channel_count = config::min_channels_for_valid_graph();
 
-	let mut previous_announcement_count = 0u64;
-	let mut previous_update_count = 0u64;
-	let mut is_caught_up_with_gossip = false;
+	if resume_timestamp.is_some() {
+		// the graph is recent enough that the missed gossip can be caught up on while snapshotting
+		let _ = completion_sender.try_send(());
+	}
 
+	let previous_sync_total = fetch_previous_sync_total(&logger).await;
+	let mut catch_up_tracker = CatchUpTracker::new();
+	let mut sync_progress = SyncProgress::new();
+	let mut is_initial_sync = true;
 	let mut i = 0u32;
-	let mut latest_new_gossip_time = Instant::now();
-	let mut needs_to_notify_persister = false;
+	let mut latest_tick_time = Instant::now();
+	let mut latest_velocity_report_time = Instant::now();
+	let mut latest_dormant_peer_report_time = Instant::now();
+	let mut propagation_lag_detector = PropagationLagDetector::new(config::propagation_lag_threshold_secs(), PROPAGATION_LAG_WINDOWS);
+	let mut propagation_window_start = PropagationDelays::default();
+	let mut latest_propagation_window = PropagationDelays::default();
 
 	loop {
 		i += 1; // count the background activity
 		let sleep = tokio::time::sleep(Duration::from_secs(5));
 		sleep.await;
 
-		{
-			let counter = router.counter.read().unwrap();
-			let total_message_count = counter.channel_announcements + counter.channel_updates;
-			let new_message_count = total_message_count - previous_announcement_count - previous_update_count;
-
-			let was_previously_caught_up_with_gossip = is_caught_up_with_gossip;
-			// TODO: make new message threshold (20) adjust based on connected peer count
-			is_caught_up_with_gossip = new_message_count < 20 && previous_announcement_count > 0 && previous_update_count > 0;
-			if new_message_count > 0 {
-				latest_new_gossip_time = Instant::now();
+		router.release_parked_announcements();
+		router.expire_early_updates();
+		if i % SILENT_PEER_PING_ITERATIONS == 0 {
+			silent_peer_pinger.ping_silent_peers(peer_silence_threshold_secs);
+			silent_peer_pinger.disconnect_idle_peers(max_peer_idle_secs);
+		}
+		let (message_rate, is_propagation_lagging) = {
+			let mut counter = router.counter.write().expect("gossip counter lock poisoned");
+			counter.connected_peers = peer_connector.connected_peer_count();
+			counter.sample_message_count(Instant::now());
+			let mut is_propagation_lagging = false;
+			if i % PROPAGATION_WINDOW_ITERATIONS == 0 {
+				latest_propagation_window = counter.propagation_delays.since(&propagation_window_start);
+				propagation_window_start = counter.propagation_delays.clone();
+				if propagation_lag_detector.record_window(&latest_propagation_window) {
+					counter.propagation_lag_warnings += 1;
+					is_propagation_lagging = true;
+				}
 			}
+			(counter.message_rate_hz(60), is_propagation_lagging)
+		};
+		if is_propagation_lagging {
+			log_warn!(logger, "The median gossip propagation delay exceeded {}s for {} consecutive windows of five minutes, so our peers may be poorly connected", config::propagation_lag_threshold_secs(), propagation_lag_detector.lagging_window_count());
+		}
+		if let Err(error) = stats::publish_message_rate(message_rate) {
+			log_warn!(logger, "Failed to publish the gossip message rate: {}", error);
+		}
+
+		let elapsed = latest_tick_time.elapsed();
+		let (events, progress) = {
+			// point-in-time copies, so that no lock is held while logging
+			let counter = router.counter_snapshot();
+			let reorg_counter = router.verifier.reorg_counter.read().expect("reorg counter lock poisoned").clone();
+			let events = catch_up_tracker.tick(counter.channel_announcements, counter.channel_updates, counter.connected_peers, elapsed);
+			latest_tick_time = Instant::now();
+			let channel_count = network_graph.read_only().channels().len() as u64;
+			let progress = sync_progress.tick(channel_count, router.channel_count_estimate.total(), latest_tick_time);
 
 			// if we either aren't caught up, or just stopped/started being caught up
-			if !is_caught_up_with_gossip || (is_caught_up_with_gossip != was_previously_caught_up_with_gossip) {
+			if !catch_up_tracker.is_caught_up() || events.contains(&CatchUpEvent::BecameCaughtUp) {
+				let backend_summary = backend_stats.summary();
+				let format_latency = |latency: Option<Duration>| latency.map_or("n/a".to_string(), |latency| format!("{:?}", latency));
 				log_info!(
 					logger,
-					"gossip count (iteration {}): {} (delta: {}):\n\tannouncements: {}\n\t\tmismatched scripts: {}\n\tupdates: {}\n\t\tno HTLC max: {}\n",
+					"gossip count (iteration {}): {} (delta: {}, {}):\n\tsync progress: {}\n\tmessage rate: {:.1} msgs/sec over last 60s\n\tannouncements: {}\n\t\tmismatched scripts: {}\n\t\tconflicting node pairs: {}\n\tupdates: {}\n\t\tno HTLC max: {}\n\t\trate limited: {}\n\t\tfuture-dated: {}\n\t\tearly: {} replayed, {} expired\n\treorgs: {}\n\t\tinvalidated channels: {}\n\tparked announcements: {}\n\trelays dropped for bandwidth: {}\n\tpersistence failures: {}\n\tsilent peers pinged: {}\n\t\tdropped after ping timeout: {}\n\tidle peers dropped: {}\n\tignored messages:\n\t\tonion: {}\n\t\tcustom: {}\n\tchain backend requests: {}\n\t\tp50 latency: {}\n\t\tp99 latency: {}\n\t\terror rate: {:.1}%\n",
 					i,
-					total_message_count,
-					new_message_count,
+					counter.channel_announcements + counter.channel_updates,
+					catch_up_tracker.new_message_count(),
+					sync_progress::estimate_catchup_progress(&counter, counter.connected_peers),
+					if catch_up_tracker.is_caught_up() { "caught up".to_string() } else { progress.to_string() },
+					message_rate,
 					counter.channel_announcements,
 					counter.channel_announcements_with_mismatched_scripts,
+					counter.conflicting_announcements,
 					counter.channel_updates,
-					counter.channel_updates_without_htlc_max_msats
+					counter.channel_updates_without_htlc_max_msats,
+					counter.rate_limited_updates,
+					counter.future_timestamped_updates,
+					counter.replayed_early_updates,
+					counter.expired_early_updates,
+					reorg_counter.reorgs_detected,
+					reorg_counter.channels_invalidated,
+					router.verifier.parked_announcement_count(),
+					counter.rate_limited_relays,
+					counter.persistence_failures,
+					counter.peers_pinged,
+					counter.peers_dropped_after_ping_timeout,
+					counter.peers_dropped_idle,
+					counter.ignored_onion_messages,
+					counter.ignored_custom_messages,
+					backend_summary.total_requests,
+					format_latency(backend_summary.p50_latency),
+					format_latency(backend_summary.p99_latency),
+					backend_summary.error_rate * 100.0
 				);
 			} else {
-				log_info!(logger, "Monitoring for gossip…")
+				log_info!(
+					logger,
+					"Monitoring for gossip… ({:.1} msgs/sec over last 60s, propagation delay p50 {}, p95 {})",
+					message_rate,
+					propagation::format_percentile(latest_propagation_window.percentile(50)),
+					propagation::format_percentile(latest_propagation_window.percentile(95))
+				)
 			}
+			(events, progress)
+		};
+		{
+			let mut counter = router.counter.write().expect("gossip counter lock poisoned");
+			counter.is_caught_up = catch_up_tracker.is_caught_up();
+			counter.recent_message_rate = catch_up_tracker.new_message_count() as f64 / elapsed.as_secs_f64().max(f64::EPSILON);
+		}
+		let (reorgs_detected, channels_invalidated) = {
+			let reorg_counter = router.verifier.reorg_counter.read().expect("reorg counter lock poisoned");
+			(reorg_counter.reorgs_detected, reorg_counter.channels_invalidated)
+		};
+		debug_state.record_tracking_iteration(CatchUpDebugInfo {
+			iteration: i,
+			is_caught_up: catch_up_tracker.is_caught_up(),
+			// the initial sync only ends with this iteration's events
+			is_initial_sync: is_initial_sync && !events.contains(&CatchUpEvent::BecameCaughtUp),
+			new_message_count: catch_up_tracker.new_message_count(),
+			seconds_since_new_gossip: catch_up_tracker.time_since_new_gossip().as_secs(),
+		}, VerifierDebugInfo {
+			parked_announcement_count: router.verifier.parked_announcement_count(),
+			pending_validation_count: router.validation_pool.pending_count(),
+			validation_thread_count: router.validation_pool.thread_count(),
+			reorgs_detected,
+			channels_invalidated,
+		});
 
-			if is_caught_up_with_gossip && !was_previously_caught_up_with_gossip {
-				log_info!(logger, "caught up with gossip!");
-				needs_to_notify_persister = true;
-			} else if !is_caught_up_with_gossip && was_previously_caught_up_with_gossip {
-				log_info!(logger, "Received new messages since catching up with gossip!");
-			}
+		if latest_velocity_report_time.elapsed() >= VELOCITY_REPORT_INTERVAL {
+			latest_velocity_report_time = Instant::now();
+			let report = router.rate_limiter.take_velocity_report(latest_velocity_report_time);
+			let report_lines: Vec<String> = report.iter()
+				.map(|((short_channel_id, direction), velocity)| format!("\t{}/{}: {} updates ({} rate limited)", short_channel_id, direction, velocity.accepted + velocity.dropped, velocity.dropped))
+				.collect();
+			log_info!(logger, "Channels with the most updates over the past day:\n{}", report_lines.join("\n"));
+		}
 
-			let continuous_caught_up_duration = latest_new_gossip_time.elapsed();
-			if continuous_caught_up_duration.as_secs() > 600 {
-				log_warn!(logger, "No new gossip messages in 10 minutes! Something's amiss!");
-			}
+		if latest_dormant_peer_report_time.elapsed() >= DORMANT_PEER_REPORT_INTERVAL {
+			latest_dormant_peer_report_time = Instant::now();
+			log_dormant_peers(&dormancy, &logger);
+		}
 
-			previous_announcement_count = counter.channel_announcements;
-			previous_update_count = counter.channel_updates;
+		for event in events {
+			match event {
+				CatchUpEvent::BecameCaughtUp => {
+					log_info!(logger, "caught up with gossip!\n{}", router);
+					if is_initial_sync {
+						log_info!(logger, "Caught up with {} channels, against a minimum of {} for a valid graph", progress.channel_count, min_channel_count);
+						if let Err(reason) = catch_up::check_caught_up_channel_count(progress.channel_count, min_channel_count) {
+							log_warn!(logger, "The initial sync {}, so our peers are unlikely to know of the whole network", reason);
+							webhook::notify_stall(&reason, logger.clone()).await;
+							return Err(ProcessorError::CatchUpFailed(reason));
+						}
+						is_initial_sync = false;
+						let sync_total = SyncTotal { estimated_total: progress.estimated_total, channel_count: progress.channel_count };
+						log_sync_total(&sync_total, previous_sync_total.as_ref(), &logger);
+						record_sync_total(&sync_total, &logger).await;
+					}
+					// only the initial sync awaits this notification, so don't block on later ones
+					let _ = completion_sender.try_send(());
+				},
+				CatchUpEvent::FellBehind => {
+					log_info!(logger, "Received new messages since catching up with gossip!\n{}", router);
+				},
+				CatchUpEvent::StaleGossip(silence_duration) => {
+					log_warn!(logger, "No new gossip messages in {} minutes! Something's amiss! Reconnecting to peers.", silence_duration.as_secs() / 60);
+					// the per-peer connection tasks reestablish dropped connections
+					peer_handler.disconnect_all_peers();
+					let reason = format!("No new gossip messages in {} seconds", silence_duration.as_secs());
+					webhook::notify_stall(&reason, logger.clone()).await;
+				},
+			}
 		}
 
-		if needs_to_notify_persister {
-			needs_to_notify_persister = false;
-			completion_sender.send(()).await.unwrap();
+		if catch_up::is_catch_up_overdue(is_initial_sync, connection_time.elapsed(), max_catch_up_time) {
+			let reason = format!("not caught up within {} seconds of connecting to peers", connection_time.elapsed().as_secs());
+			log_warn!(logger, "The initial sync is {}", reason);
+			webhook::notify_stall(&reason, logger.clone()).await;
+			return Err(ProcessorError::CatchUpFailed(reason));
 		}
 	}
 }
 
-async fn connect_peer<L: Deref + Clone + Send + Sync + 'static>(current_peer: (PublicKey, SocketAddr), peer_manager: GossipPeerManager<L>, logger: L) -> bool where L::Target: Logger {
-	// we seek to find out if the first connection attempt was successful
-	let (sender, mut receiver) = mpsc::channel::<bool>(1);
-	tokio::spawn(async move {
-		let current_peer_pubkey_hex = current_peer.0.serialize().to_lower_hex_string();
-		log_info!(logger, "Connecting to peer {}@{}...", current_peer_pubkey_hex, current_peer.1);
-		let mut is_first_iteration = true;
-		loop {
-			if let Some(disconnection_future) = lightning_net_tokio::connect_outbound(
-				Arc::clone(&peer_manager),
-				current_peer.0,
-				current_peer.1,
-			).await {
-				log_info!(logger, "Connected to peer {}@{}!", current_peer_pubkey_hex, current_peer.1);
-				if is_first_iteration {
-					sender.send(true).await.unwrap();
-				}
-				disconnection_future.await;
-				log_warn!(logger, "Disconnected from peer {}@{}", current_peer_pubkey_hex, current_peer.1);
-			} else {
-				log_warn!(logger, "Failed to connect to peer {}@{}!", current_peer_pubkey_hex, current_peer.1);
-				if is_first_iteration {
-					sender.send(false).await.unwrap();
-				}
-			}
-			is_first_iteration = false;
-			tokio::time::sleep(Duration::from_secs(10)).await;
-			log_warn!(logger, "Reconnecting to peer {}@{}...", current_peer_pubkey_hex, current_peer.1);
-		}
-	});
+/// Compare the channel counts at the end of the initial sync with those of the previous run's
+fn log_sync_total<L: Deref>(sync_total: &SyncTotal, previous_sync_total: Option<&SyncTotal>, logger: &L) where L::Target: Logger {
+	let format_estimate = |estimated_total: Option<u64>| estimated_total.map_or("unknown".to_string(), |estimated_total| estimated_total.to_string());
+	log_info!(logger, "Initial sync completed with {} channels, of an estimated {}", sync_total.channel_count, format_estimate(sync_total.estimated_total));
+	if let Some(previous_sync_total) = previous_sync_total {
+		log_info!(logger, "The previous initial sync completed with {} channels, of an estimated {}", previous_sync_total.channel_count, format_estimate(previous_sync_total.estimated_total));
+	}
+}
+
+async fn fetch_previous_sync_total<L: Deref>(logger: &L) -> Option<SyncTotal> where L::Target: Logger {
+	let result = async {
+		let client = crate::connect_to_db().await?;
+		sync_progress::fetch_previous_sync_total(&client, &Tables::from_config()).await
+			.context("Failed to fetch the previous sync total")
+	}.await;
+	match result {
+		Ok(previous_sync_total) => previous_sync_total,
+		// on the very first start, the table may not have been created yet
+		Err(error) => {
+			log_warn!(logger, "{}", error);
+			None
+		},
+	}
+}
+
+async fn record_sync_total<L: Deref>(sync_total: &SyncTotal, logger: &L) where L::Target: Logger {
+	let result = async {
+		let client = crate::connect_to_db().await?;
+		sync_progress::record_sync_total(&client, &Tables::from_config(), sync_total).await
+			.context("Failed to record the sync total")
+	}.await;
+	if let Err(error) = result {
+		log_warn!(logger, "{}", error);
+	}
+}
 
-	let success = receiver.recv().await.unwrap();
-	success
+/// Restore the peer addresses learned from node announcements before the restart
+async fn restore_learned_addresses<L: Deref>(address_book: &AddressBook, logger: &L) where L::Target: Logger {
+	let result = async {
+		let client = crate::connect_to_db().await?;
+		address_book.load(&client, &Tables::from_config()).await
+	}.await;
+	match result {
+		Ok(address_count) => log_info!(logger, "Restored {} learned peer addresses", address_count),
+		// on the very first start, the table may not have been created yet
+		Err(error) => log_warn!(logger, "Failed to restore learned peer addresses: {}", error),
+	}
+}
+
+/// Restore the peers' connection failure histories from before the restart, so that peers that
+/// were dormant stay so
+async fn restore_failure_histories<L: Deref>(dormancy: &DormancyTracker, logger: &L) where L::Target: Logger {
+	let result = async {
+		let client = crate::connect_to_db().await?;
+		dormancy.load(&client, &Tables::from_config()).await
+	}.await;
+	match result {
+		Ok(history_count) => {
+			log_info!(logger, "Restored {} peer failure histories", history_count);
+			log_dormant_peers(dormancy, logger);
+		},
+		// on the very first start, the table may not have been created yet
+		Err(error) => log_warn!(logger, "Failed to restore peer failure histories: {}", error),
+	}
+}
+
+/// List the dormant peers, which are likely gone for good and worth removing from the peer list
+fn log_dormant_peers<L: Deref>(dormancy: &DormancyTracker, logger: &L) where L::Target: Logger {
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch").as_secs();
+	let dormant_peers = dormancy.dormant_peers(now);
+	if dormant_peers.is_empty() {
+		return;
+	}
+	let format_timestamp = |timestamp: Option<u64>| timestamp.map_or("never".to_string(), |timestamp| timestamp.to_string());
+	let peer_lines: Vec<String> = dormant_peers.iter()
+		.map(|(pubkey, history)| format!("\t{}: {} consecutive failures since {}, last connected at {}", pubkey.serialize().to_lower_hex_string(), history.consecutive_failures, format_timestamp(history.failing_since), format_timestamp(history.last_success_at)))
+		.collect();
+	log_warn!(logger, "{} configured peers are dormant, and only retried daily. Consider removing them from the peer list:\n{}", dormant_peers.len(), peer_lines.join("\n"));
+}
+
+/// Determine from the gossip watermark whether the previous run ended recently enough that only
+/// the gossip missed since needs to be requested, rather than performing a full initial sync
+async fn gossip_resume_timestamp<L: Deref>(logger: &L) -> Option<u32> where L::Target: Logger {
+	let watermark = async {
+		let client = crate::connect_to_db().await?;
+		persistence::fetch_gossip_watermark(&client, &Tables::from_config()).await
+	}.await;
+	let watermark = match watermark {
+		Ok(Some(watermark)) => watermark,
+		Ok(None) => {
+			log_info!(logger, "No gossip watermark found, performing a full initial sync");
+			return None;
+		},
+		Err(error) => {
+			log_warn!(logger, "Failed to read the gossip watermark, performing a full initial sync: {}", error);
+			return None;
+		},
+	};
+
+	let now = SystemTime::now().duration_since(UNIX_EPOCH).expect("system clock must be set after the unix epoch").as_secs();
+	let watermark_age = Duration::from_secs(now.saturating_sub(watermark));
+	if watermark_age > config::max_gossip_watermark_age() {
+		log_info!(logger, "Gossip watermark {} is {} minutes old, performing a full initial sync", watermark, watermark_age.as_secs() / 60);
+		return None;
+	}
+	log_info!(logger, "Resuming from watermark {} ({} minutes ago)", watermark, watermark_age.as_secs() / 60);
+	Some(watermark.saturating_sub(WATERMARK_RESUME_MARGIN.as_secs()) as u32)
 }
@@ -1,23 +1,101 @@
+use std::io::Cursor;
 use std::sync::Arc;
+use std::time::Instant;
 
+use bitcoin::hashes::hex::FromHex;
+use hex_conservative::DisplayHex;
 use lightning::sign::KeysManager;
-use lightning::ln::msgs::{ChannelAnnouncement, ChannelUpdate, NodeAnnouncement};
-use lightning::ln::peer_handler::{ErroringMessageHandler, IgnoringMessageHandler, PeerManager};
+use lightning::ln::msgs::{ChannelAnnouncement, ChannelUpdate, DecodeError, NodeAnnouncement};
+use lightning::ln::peer_handler::{ErroringMessageHandler, PeerManager};
 use lightning::util::logger::{Logger, Record};
+use lightning::util::ser::{Readable, Writeable};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error as _;
 use crate::config;
 
+use crate::counting_handler::CountingMessageHandler;
 use crate::downloader::GossipRouter;
 use crate::verifier::ChainVerifier;
 
 pub(crate) type GossipChainAccess<L> = Arc<ChainVerifier<L>>;
-pub(crate) type GossipPeerManager<L> = Arc<PeerManager<lightning_net_tokio::SocketDescriptor, ErroringMessageHandler, Arc<GossipRouter<L>>, IgnoringMessageHandler, L, IgnoringMessageHandler, Arc<KeysManager>>>;
+pub(crate) type GossipPeerManager<L> = Arc<PeerManager<lightning_net_tokio::SocketDescriptor, ErroringMessageHandler, Arc<GossipRouter<L>>, Arc<CountingMessageHandler<L>>, L, Arc<CountingMessageHandler<L>>, Arc<KeysManager>>>;
 
-#[derive(Debug)]
-pub(crate) enum GossipMessage {
+/// A gossip message on its way to being persisted
+#[derive(Clone, Debug)]
+pub enum GossipMessage {
 	NodeAnnouncement(NodeAnnouncement, Option<u32>),
 	// the second element is an optional override for the seen value
 	ChannelAnnouncement(ChannelAnnouncement, Option<u32>),
 	ChannelUpdate(ChannelUpdate, Option<u32>),
+	/// Multiple messages that are persisted atomically, along with the time each was received
+	Batch(Vec<GossipMessage>, Vec<Instant>),
+}
+
+impl GossipMessage {
+	/// Unwrap any (nested) batches into their individual messages
+	pub(crate) fn into_messages(self) -> Vec<GossipMessage> {
+		match self {
+			GossipMessage::Batch(messages, _) => messages.into_iter().flat_map(|message| message.into_messages()).collect(),
+			message => vec![message],
+		}
+	}
+}
+
+/// The JSON representation of a [`GossipMessage`], for debugging tools. Messages are hex-encoded in
+/// their wire format, and a batch's receipt times, which only serve latency measurements, are left
+/// out.
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum JsonGossipMessage {
+	NodeAnnouncement { hex: String, seen_override: Option<u32> },
+	ChannelAnnouncement { hex: String, seen_override: Option<u32> },
+	ChannelUpdate { hex: String, seen_override: Option<u32> },
+	Batch { messages: Vec<JsonGossipMessage> },
+}
+
+impl From<&GossipMessage> for JsonGossipMessage {
+	fn from(message: &GossipMessage) -> Self {
+		match message {
+			GossipMessage::NodeAnnouncement(announcement, seen_override) => Self::NodeAnnouncement { hex: announcement.encode().to_lower_hex_string(), seen_override: *seen_override },
+			GossipMessage::ChannelAnnouncement(announcement, seen_override) => Self::ChannelAnnouncement { hex: announcement.encode().to_lower_hex_string(), seen_override: *seen_override },
+			GossipMessage::ChannelUpdate(update, seen_override) => Self::ChannelUpdate { hex: update.encode().to_lower_hex_string(), seen_override: *seen_override },
+			GossipMessage::Batch(messages, _) => Self::Batch { messages: messages.iter().map(Self::from).collect() },
+		}
+	}
+}
+
+impl TryFrom<JsonGossipMessage> for GossipMessage {
+	type Error = String;
+
+	fn try_from(message: JsonGossipMessage) -> Result<Self, Self::Error> {
+		fn decode<T: Readable>(hex: &str) -> Result<T, String> {
+			let bytes = Vec::<u8>::from_hex(hex).map_err(|error| format!("invalid hex: {}", error))?;
+			Readable::read(&mut Cursor::new(bytes)).map_err(|error: DecodeError| format!("undecodable message: {}", error))
+		}
+		Ok(match message {
+			JsonGossipMessage::NodeAnnouncement { hex, seen_override } => GossipMessage::NodeAnnouncement(decode(&hex)?, seen_override),
+			JsonGossipMessage::ChannelAnnouncement { hex, seen_override } => GossipMessage::ChannelAnnouncement(decode(&hex)?, seen_override),
+			JsonGossipMessage::ChannelUpdate { hex, seen_override } => GossipMessage::ChannelUpdate(decode(&hex)?, seen_override),
+			JsonGossipMessage::Batch { messages } => {
+				let messages = messages.into_iter().map(GossipMessage::try_from).collect::<Result<Vec<_>, _>>()?;
+				// the original receipt times are lost, so latencies are measured from decoding
+				let received_at = vec![Instant::now(); messages.len()];
+				GossipMessage::Batch(messages, received_at)
+			},
+		})
+	}
+}
+
+impl Serialize for GossipMessage {
+	fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		JsonGossipMessage::from(self).serialize(serializer)
+	}
+}
+
+impl<'de> Deserialize<'de> for GossipMessage {
+	fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		GossipMessage::try_from(JsonGossipMessage::deserialize(deserializer)?).map_err(D::Error::custom)
+	}
 }
 
 #[derive(Clone, Copy)]
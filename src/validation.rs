@@ -0,0 +1,280 @@
+use std::future::Future;
+use std::ops::Deref;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bitcoin::hashes::Hash;
+use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+use bitcoin::secp256k1::{Message, PublicKey, Secp256k1, VerifyOnly};
+use lightning::ln::msgs::ChannelUpdate;
+use lightning::routing::gossip::{NetworkGraph, verify_channel_announcement, verify_node_announcement};
+use lightning::util::logger::Logger;
+use lightning::util::ser::Writeable;
+use tokio::sync::oneshot;
+
+use crate::config;
+use crate::types::GossipMessage;
+
+/// Once more than this many messages per thread are awaiting validation, the pool is grown
+const QUEUE_DEPTH_PER_THREAD: usize = 64;
+
+/// How long the pool must have been idle before it's shrunk back to its initial size
+const SHRINK_DELAY: Duration = Duration::from_secs(30);
+
+#[derive(Debug, PartialEq)]
+pub(crate) enum ValidationResult {
+	Valid,
+	/// A signature didn't check out, along with the reason
+	Invalid(String),
+	/// The signer of a channel update can't be determined, as its channel is unknown
+	UnknownChannel(u64),
+}
+
+struct PoolState {
+	pool: Arc<rayon::ThreadPool>,
+	thread_count: usize,
+	/// When a message was last submitted while others were still awaiting validation
+	last_busy_at: Instant,
+}
+
+/// Verifies gossip signatures on a dedicated thread pool, so that bursts of gossip don't tie up
+/// the Tokio runtime's worker threads. The pool starts out with [`config::validation_threads`]
+/// threads, doubles in size while messages are piling up, up to one thread per CPU, and shrinks
+/// back once it's been idle for a while.
+pub(crate) struct ValidationPool<L: Deref + Clone + Send + Sync + 'static> where L::Target: Logger {
+	network_graph: Arc<NetworkGraph<L>>,
+	secp_ctx: Arc<Secp256k1<VerifyOnly>>,
+	state: Mutex<PoolState>,
+	/// The number of messages submitted, but not yet validated
+	pending: Arc<AtomicUsize>,
+	min_threads: usize,
+	max_threads: usize,
+}
+
+impl<L: Deref + Clone + Send + Sync + 'static> ValidationPool<L> where L::Target: Logger {
+	pub(crate) fn new(network_graph: Arc<NetworkGraph<L>>) -> Self {
+		let max_threads = std::thread::available_parallelism().map_or(1, |parallelism| parallelism.get());
+		Self::with_threads(network_graph, config::validation_threads(), max_threads)
+	}
+
+	pub(crate) fn with_threads(network_graph: Arc<NetworkGraph<L>>, min_threads: usize, max_threads: usize) -> Self {
+		let max_threads = max_threads.max(min_threads);
+		Self {
+			network_graph,
+			secp_ctx: Arc::new(Secp256k1::verification_only()),
+			state: Mutex::new(PoolState {
+				pool: Arc::new(build_thread_pool(min_threads)),
+				thread_count: min_threads,
+				last_busy_at: Instant::now(),
+			}),
+			pending: Arc::new(AtomicUsize::new(0)),
+			min_threads,
+			max_threads,
+		}
+	}
+
+	pub(crate) fn thread_count(&self) -> usize {
+		self.state.lock().expect("validation pool lock poisoned").thread_count
+	}
+
+	/// The number of messages submitted, but not yet validated
+	pub(crate) fn pending_count(&self) -> usize {
+		self.pending.load(Ordering::Acquire)
+	}
+
+	/// Verify the message's signatures on the pool. Channel update signers are looked up in the
+	/// network graph upfront, so their channels must already be known.
+	pub(crate) fn validate_async(&self, msg: GossipMessage) -> impl Future<Output = ValidationResult> {
+		let (result_sender, result_receiver) = oneshot::channel();
+		let validation = self.resolve_update_signers(&msg).map(|update_signers| {
+			let secp_ctx = Arc::clone(&self.secp_ctx);
+			move || validate_message(&msg, &update_signers, &secp_ctx)
+		});
+
+		match validation {
+			Ok(validation) => {
+				let pool = self.pool_for_submission();
+				let pending = Arc::clone(&self.pending);
+				pool.spawn(move || {
+					let _ = result_sender.send(validation());
+					pending.fetch_sub(1, Ordering::AcqRel);
+				});
+			},
+			Err(unknown_channel) => {
+				let _ = result_sender.send(unknown_channel);
+			},
+		}
+
+		async move {
+			result_receiver.await.unwrap_or(ValidationResult::Invalid("validation was abandoned".to_string()))
+		}
+	}
+
+	/// The pool to submit the next message to, resized first if the load calls for it
+	fn pool_for_submission(&self) -> Arc<rayon::ThreadPool> {
+		let pending = self.pending.fetch_add(1, Ordering::AcqRel) + 1;
+		let mut state = self.state.lock().expect("validation pool lock poisoned");
+		if pending > 1 {
+			state.last_busy_at = Instant::now();
+		}
+		let target_thread_count = if pending > state.thread_count * QUEUE_DEPTH_PER_THREAD {
+			(state.thread_count * 2).min(self.max_threads)
+		} else if pending == 1 && state.last_busy_at.elapsed() > SHRINK_DELAY {
+			self.min_threads
+		} else {
+			state.thread_count
+		};
+		if target_thread_count != state.thread_count {
+			// jobs already queued on the previous pool still run to completion once it's dropped
+			state.pool = Arc::new(build_thread_pool(target_thread_count));
+			state.thread_count = target_thread_count;
+		}
+		Arc::clone(&state.pool)
+	}
+
+	/// The signing keys of all channel updates contained in the message, in order
+	fn resolve_update_signers(&self, msg: &GossipMessage) -> Result<Vec<PublicKey>, ValidationResult> {
+		let read_only_graph = self.network_graph.read_only();
+		let mut signers = Vec::new();
+		for message in messages(msg) {
+			if let GossipMessage::ChannelUpdate(update, _) = message {
+				let short_channel_id = update.contents.short_channel_id;
+				let channel = read_only_graph.channel(short_channel_id)
+					.ok_or(ValidationResult::UnknownChannel(short_channel_id))?;
+				let signer = if update.contents.flags & 1 == 0 { channel.node_one } else { channel.node_two };
+				signers.push(signer.as_pubkey()
+					.map_err(|_| ValidationResult::Invalid(format!("invalid node key for channel {}", short_channel_id)))?);
+			}
+		}
+		Ok(signers)
+	}
+}
+
+fn build_thread_pool(thread_count: usize) -> rayon::ThreadPool {
+	rayon::ThreadPoolBuilder::new()
+		.num_threads(thread_count)
+		.thread_name(|index| format!("gossip-validation-{}", index))
+		.build()
+		.expect("failed to start gossip validation threads")
+}
+
+/// The individual messages, descending into (nested) batches
+fn messages(msg: &GossipMessage) -> Box<dyn Iterator<Item = &GossipMessage> + '_> {
+	match msg {
+		GossipMessage::Batch(batch, _) => Box::new(batch.iter().flat_map(messages)),
+		message => Box::new(std::iter::once(message)),
+	}
+}
+
+fn validate_message(msg: &GossipMessage, update_signers: &[PublicKey], secp_ctx: &Secp256k1<VerifyOnly>) -> ValidationResult {
+	let mut update_signers = update_signers.iter();
+	for message in messages(msg) {
+		let result = match message {
+			GossipMessage::NodeAnnouncement(announcement, _) => verify_node_announcement(announcement, secp_ctx),
+			GossipMessage::ChannelAnnouncement(announcement, _) => verify_channel_announcement(announcement, secp_ctx),
+			GossipMessage::ChannelUpdate(update, _) => {
+				let signer = update_signers.next().expect("a signer must have been resolved for every update");
+				if !is_update_signed_by(update, signer, secp_ctx) {
+					return ValidationResult::Invalid(format!("invalid signature for update of channel {}", update.contents.short_channel_id));
+				}
+				continue;
+			},
+			GossipMessage::Batch(..) => unreachable!(),
+		};
+		if let Err(error) = result {
+			return ValidationResult::Invalid(error.err);
+		}
+	}
+	ValidationResult::Valid
+}
+
+fn is_update_signed_by(update: &ChannelUpdate, signer: &PublicKey, secp_ctx: &Secp256k1<VerifyOnly>) -> bool {
+	let msg_hash = Message::from_slice(&Sha256dHash::hash(&update.contents.encode()[..])[..])
+		.expect("a double SHA-256 hash is a valid message");
+	secp_ctx.verify_ecdsa(&msg_hash, &update.signature, signer).is_ok()
+}
+
+#[cfg(test)]
+mod tests {
+	use std::sync::Arc;
+
+	use bitcoin::Network;
+	use bitcoin::blockdata::constants::ChainHash;
+	use bitcoin::hashes::Hash;
+	use bitcoin::hashes::sha256d::Hash as Sha256dHash;
+	use bitcoin::secp256k1::{Message, Secp256k1, SecretKey};
+	use lightning::ln::features::ChannelFeatures;
+	use lightning::ln::msgs::{ChannelUpdate, UnsignedChannelUpdate};
+	use lightning::routing::gossip::NetworkGraph;
+	use lightning::util::ser::Writeable;
+
+	use crate::types::GossipMessage;
+	use crate::types::tests::TestLogger;
+	use super::{ValidationPool, ValidationResult};
+
+	fn signed_update(short_channel_id: u64, timestamp: u32, signer: &SecretKey) -> ChannelUpdate {
+		let contents = UnsignedChannelUpdate {
+			chain_hash: ChainHash::using_genesis_block(Network::Bitcoin),
+			short_channel_id,
+			timestamp,
+			flags: 0,
+			cltv_expiry_delta: 40,
+			htlc_minimum_msat: 1,
+			htlc_maximum_msat: 100_000,
+			fee_base_msat: 1000,
+			fee_proportional_millionths: 100,
+			excess_data: vec![],
+		};
+		let msg_hash = Message::from_slice(&Sha256dHash::hash(&contents.encode()[..])[..]).unwrap();
+		ChannelUpdate { signature: Secp256k1::new().sign_ecdsa(&msg_hash, signer), contents }
+	}
+
+	#[tokio::test]
+	async fn test_update_validation() {
+		let secp_ctx = Secp256k1::new();
+		let node_key_1 = SecretKey::from_slice(&[1; 32]).unwrap();
+		let node_key_2 = SecretKey::from_slice(&[2; 32]).unwrap();
+		let logger = Arc::new(TestLogger::with_id("validation".to_string()));
+		let network_graph = Arc::new(NetworkGraph::new(Network::Bitcoin, logger));
+		network_graph.add_channel_from_partial_announcement(1, 0, ChannelFeatures::empty(), node_key_1.public_key(&secp_ctx), node_key_2.public_key(&secp_ctx)).unwrap();
+		let pool = ValidationPool::with_threads(network_graph, 1, 2);
+
+		let update = signed_update(1, 100, &node_key_1);
+		assert_eq!(pool.validate_async(GossipMessage::ChannelUpdate(update.clone(), None)).await, ValidationResult::Valid);
+
+		// the second direction's key must not be able to sign for the first direction
+		let forged_update = signed_update(1, 100, &node_key_2);
+		assert!(matches!(pool.validate_async(GossipMessage::ChannelUpdate(forged_update.clone(), None)).await, ValidationResult::Invalid(_)));
+
+		let unknown_update = signed_update(2, 100, &node_key_1);
+		assert_eq!(pool.validate_async(GossipMessage::ChannelUpdate(unknown_update, None)).await, ValidationResult::UnknownChannel(2));
+
+		// a single forgery invalidates an entire batch
+		let batch = GossipMessage::Batch(vec![GossipMessage::ChannelUpdate(update, None), GossipMessage::ChannelUpdate(forged_update, None)], vec![]);
+		assert!(matches!(pool.validate_async(batch).await, ValidationResult::Invalid(_)));
+	}
+
+	#[tokio::test]
+	async fn test_pool_growth() {
+		let secp_ctx = Secp256k1::new();
+		let node_key_1 = SecretKey::from_slice(&[1; 32]).unwrap();
+		let node_key_2 = SecretKey::from_slice(&[2; 32]).unwrap();
+		let logger = Arc::new(TestLogger::with_id("validation".to_string()));
+		let network_graph = Arc::new(NetworkGraph::new(Network::Bitcoin, logger));
+		network_graph.add_channel_from_partial_announcement(1, 0, ChannelFeatures::empty(), node_key_1.public_key(&secp_ctx), node_key_2.public_key(&secp_ctx)).unwrap();
+		let pool = ValidationPool::with_threads(network_graph, 1, 4);
+		assert_eq!(pool.thread_count(), 1);
+
+		// submitting everything before awaiting any results builds up a backlog
+		let updates: Vec<ChannelUpdate> = (0..1000).map(|timestamp| signed_update(1, timestamp, &node_key_1)).collect();
+		let validations: Vec<_> = updates.into_iter()
+			.map(|update| pool.validate_async(GossipMessage::ChannelUpdate(update, None)))
+			.collect();
+		assert!(pool.thread_count() > 1);
+		assert!(pool.thread_count() <= 4);
+		for validation in validations {
+			assert_eq!(validation.await, ValidationResult::Valid);
+		}
+	}
+}
@@ -1,53 +1,257 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
 use std::io::ErrorKind;
 use std::ops::Deref;
+use std::pin::Pin;
 use std::sync::Arc;
-use std::sync::Mutex;
+use std::sync::{Mutex, RwLock};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::future::Future;
+use std::time::{Duration, Instant};
 
 use bitcoin::blockdata::constants::ChainHash;
-use bitcoin::{BlockHash, TxOut};
+use bitcoin::{BlockHash, ScriptBuf, TxOut};
 use bitcoin::blockdata::block::Block;
 use bitcoin::hashes::Hash;
-use lightning::log_error;
+use lightning::{log_error, log_info, log_warn};
+use lightning::ln::chan_utils::make_funding_redeemscript;
+use lightning::ln::msgs::{ChannelAnnouncement, ChannelUpdate};
 use lightning::routing::gossip::{NetworkGraph, P2PGossipSync};
 use lightning::routing::utxo::{UtxoFuture, UtxoLookup, UtxoResult, UtxoLookupError};
 use lightning::util::logger::Logger;
-use lightning_block_sync::{BlockData, BlockSource};
-use lightning_block_sync::http::BinaryResponse;
+use lightning_block_sync::{BlockData, BlockSource, BlockSourceError};
+use lightning_block_sync::http::{BinaryResponse, HttpEndpoint};
 use lightning_block_sync::rest::RestClient;
 
+use crate::chain_stats::ChainBackendStats;
 use crate::config;
+use crate::scid::{ScidError, ShortChannelId};
+use crate::tables::Tables;
 use crate::types::GossipPeerManager;
 
+/// How frequently the chain tip is polled to detect reorgs
+const CHAIN_TIP_POLL_INTERVAL: Duration = Duration::from_secs(30);
+/// The number of most recent block hashes kept around to compare new chain tips against
+const REORG_TRACKING_DEPTH: usize = 144;
+/// The maximum number of announcements held back while awaiting sufficient confirmations
+const MAX_PARKED_ANNOUNCEMENTS: usize = 10_000;
+/// How far below the chain tip the block retrieved by the startup probe is. Older blocks are less
+/// likely to be cached, and thus more representative of funding output lookups.
+const PROBE_BLOCK_DEPTH: u32 = 10_000;
+/// The maximum number of funding outputs awaiting their announcement's persistence
+const MAX_PENDING_FUNDING_OUTPUTS: usize = 10_000;
+
+#[derive(Clone)]
+pub(crate) struct ReorgCounter {
+	pub(crate) reorgs_detected: u64,
+	pub(crate) channels_invalidated: u64,
+}
+
+/// The chain backend, as far as the verifier is concerned: LDK's block source, which the chain tip
+/// is followed through, plus looking up block hashes by height, which funding outputs are located
+/// by. Tests substitute a scripted chain for bitcoind's REST interface.
+pub(crate) trait ChainSource: BlockSource + Send + Sync + 'static {
+	/// The raw hash of the best chain's block at the given height, as served by bitcoind's
+	/// `blockhashbyheight` REST resource
+	fn get_block_hash_by_height<'a>(&'a self, block_height: u32) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<u8>>> + Send + 'a>>;
+}
+
+impl ChainSource for RestClient {
+	fn get_block_hash_by_height<'a>(&'a self, block_height: u32) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<u8>>> + Send + 'a>> {
+		Box::pin(async move {
+			let uri = format!("blockhashbyheight/{}.bin", block_height);
+			self.request_resource::<BinaryResponse, RestBinaryResponse>(&uri).await.map(|response| response.0)
+		})
+	}
+}
+
+/// A channel announcement whose funding output does not have enough confirmations yet
+pub(crate) struct ParkedAnnouncement {
+	pub(crate) announcement: ChannelAnnouncement,
+	/// The latest update received in either direction while the announcement was parked
+	pub(crate) updates: (Option<ChannelUpdate>, Option<ChannelUpdate>),
+}
+
+/// What verifying a channel's announcement revealed about its funding output
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct FundingOutput {
+	/// The timestamp of the block the output was confirmed in
+	pub(crate) block_time: u32,
+	/// The output's value, i. e. the channel's capacity
+	pub(crate) value_sats: u64,
+}
+
+/// The funding outputs of channels, as learned when verifying their announcements, until the
+/// persister stores them along with the announcements. Outputs of announcements that are never
+/// persisted, such as those with forged signatures, are evicted once too many outputs were
+/// recorded since.
+pub(crate) struct FundingOutputs {
+	pending: Mutex<PendingFundingOutputs>,
+}
+
+#[derive(Default)]
+struct PendingFundingOutputs {
+	/// The funding output of each channel, and the sequence number it was recorded with
+	outputs: HashMap<u64, (FundingOutput, u64)>,
+	/// The channels in the order their outputs were recorded, including those taken since, which
+	/// are skipped once they come up for eviction
+	recording_order: VecDeque<(u64, u64)>,
+	next_sequence_number: u64,
+}
+
+impl FundingOutputs {
+	pub(crate) fn new() -> Self {
+		Self { pending: Mutex::new(PendingFundingOutputs::default()) }
+	}
+
+	pub(crate) fn record(&self, short_channel_id: u64, funding_output: FundingOutput) {
+		let mut pending = self.pending.lock().expect("funding output lock poisoned");
+		let sequence_number = pending.next_sequence_number;
+		pending.next_sequence_number += 1;
+		pending.outputs.insert(short_channel_id, (funding_output, sequence_number));
+		pending.recording_order.push_back((short_channel_id, sequence_number));
+		while pending.recording_order.len() > MAX_PENDING_FUNDING_OUTPUTS {
+			let (evicted_scid, evicted_sequence_number) = pending.recording_order.pop_front().expect("the order isn't empty");
+			if pending.outputs.get(&evicted_scid).map_or(false, |(_, sequence_number)| *sequence_number == evicted_sequence_number) {
+				pending.outputs.remove(&evicted_scid);
+			}
+		}
+	}
+
+	/// Take the funding output of a channel whose announcement is being persisted
+	pub(crate) fn take(&self, short_channel_id: u64) -> Option<FundingOutput> {
+		let mut pending = self.pending.lock().expect("funding output lock poisoned");
+		pending.outputs.remove(&short_channel_id).map(|(funding_output, _)| funding_output)
+	}
+}
+
 pub(crate) struct ChainVerifier<L: Deref + Clone + Send + Sync + 'static> where L::Target: Logger {
-	rest_client: Arc<RestClient>,
+	chain_source: Arc<dyn ChainSource>,
+	backend_stats: Arc<ChainBackendStats>,
 	graph: Arc<NetworkGraph<L>>,
 	outbound_gossiper: Arc<P2PGossipSync<Arc<NetworkGraph<L>>, Arc<Self>, L>>,
 	peer_handler: Mutex<Option<GossipPeerManager<L>>>,
+	pub(crate) reorg_counter: RwLock<ReorgCounter>,
+	/// The highest chain tip seen by the chain tip follower, or 0 if it's not known yet
+	best_block_height: AtomicU32,
+	min_confirmations: u32,
+	/// Unlike LDK's pending lookups, which are bounded tightly to apply backpressure on peers,
+	/// parked announcements are kept here until their funding output is buried deeply enough.
+	parked_announcements: Mutex<HashMap<u64, ParkedAnnouncement>>,
+	/// Where the funding outputs of verified channels are handed to the persister, if anywhere
+	funding_outputs: Mutex<Option<Arc<FundingOutputs>>>,
 	logger: L
 }
 
-struct RestBinaryResponse(Vec<u8>);
+pub(crate) struct RestBinaryResponse(pub(crate) Vec<u8>);
 
 impl<L: Deref + Clone + Send + Sync + 'static> ChainVerifier<L> where L::Target: Logger {
-	pub(crate) fn new(graph: Arc<NetworkGraph<L>>, outbound_gossiper: Arc<P2PGossipSync<Arc<NetworkGraph<L>>, Arc<Self>, L>>, logger: L) -> Self {
+	pub(crate) fn new(graph: Arc<NetworkGraph<L>>, outbound_gossiper: Arc<P2PGossipSync<Arc<NetworkGraph<L>>, Arc<Self>, L>>, chain_backend: HttpEndpoint, backend_stats: Arc<ChainBackendStats>, logger: L) -> Self {
+		let rest_client = RestClient::new(chain_backend).expect("BITCOIN_REST_DOMAIN, BITCOIN_REST_PORT, and BITCOIN_REST_PATH must form a valid REST endpoint");
+		Self::with_chain_source(graph, outbound_gossiper, Arc::new(rest_client), backend_stats, logger)
+	}
+
+	pub(crate) fn with_chain_source(graph: Arc<NetworkGraph<L>>, outbound_gossiper: Arc<P2PGossipSync<Arc<NetworkGraph<L>>, Arc<Self>, L>>, chain_source: Arc<dyn ChainSource>, backend_stats: Arc<ChainBackendStats>, logger: L) -> Self {
 		ChainVerifier {
-			rest_client: Arc::new(RestClient::new(config::bitcoin_rest_endpoint()).unwrap()),
+			chain_source,
+			backend_stats,
 			outbound_gossiper,
 			graph,
 			peer_handler: Mutex::new(None),
+			reorg_counter: RwLock::new(ReorgCounter { reorgs_detected: 0, channels_invalidated: 0 }),
+			best_block_height: AtomicU32::new(0),
+			min_confirmations: config::min_funding_confirmations(),
+			parked_announcements: Mutex::new(HashMap::new()),
+			funding_outputs: Mutex::new(None),
 			logger
 		}
 	}
 	pub(crate) fn set_ph(&self, peer_handler: GossipPeerManager<L>) {
-		*self.peer_handler.lock().unwrap() = Some(peer_handler);
+		*self.peer_handler.lock().expect("peer handler lock poisoned") = Some(peer_handler);
+	}
+
+	pub(crate) fn set_funding_outputs(&self, funding_outputs: Arc<FundingOutputs>) {
+		*self.funding_outputs.lock().expect("funding output lock poisoned") = Some(funding_outputs);
+	}
+
+	/// Whether the funding output of the given channel lacks the configured confirmation depth.
+	/// As long as the chain tip is unknown, all channels are considered sufficiently confirmed.
+	pub(crate) fn is_insufficiently_confirmed(&self, short_channel_id: u64) -> bool {
+		let tip_height = self.best_block_height.load(Ordering::Acquire);
+		if tip_height == 0 {
+			return false;
+		}
+		let funding_height = ShortChannelId::from(short_channel_id).block_height();
+		!has_sufficient_confirmations(funding_height, tip_height, self.min_confirmations)
+	}
+
+	pub(crate) fn park_announcement(&self, announcement: ChannelAnnouncement) {
+		let short_channel_id = announcement.contents.short_channel_id;
+		let mut parked_announcements = self.parked_announcements.lock().expect("parked announcement lock poisoned");
+		if !parked_announcements.contains_key(&short_channel_id) && parked_announcements.len() >= MAX_PARKED_ANNOUNCEMENTS {
+			log_warn!(self.logger, "Too many parked announcements, dropping announcement for channel {}", short_channel_id);
+			return;
+		}
+		parked_announcements.entry(short_channel_id).or_insert(ParkedAnnouncement { announcement, updates: (None, None) });
+	}
+
+	/// Drop a parked announcement, along with any updates held for it, after its signatures turned
+	/// out to be forged.
+	pub(crate) fn discard_parked_announcement(&self, short_channel_id: u64, reason: &str) {
+		if self.parked_announcements.lock().expect("parked announcement lock poisoned").remove(&short_channel_id).is_some() {
+			log_warn!(self.logger, "Discarding parked announcement for channel {}: {}", short_channel_id, reason);
+		}
+	}
+
+	/// Hold on to an update for a parked channel, returning whether the channel was parked.
+	pub(crate) fn park_update(&self, update: &ChannelUpdate) -> bool {
+		let mut parked_announcements = self.parked_announcements.lock().expect("parked announcement lock poisoned");
+		if let Some(parked_announcement) = parked_announcements.get_mut(&update.contents.short_channel_id) {
+			let held_update = if update.contents.flags & 1 == 0 {
+				&mut parked_announcement.updates.0
+			} else {
+				&mut parked_announcement.updates.1
+			};
+			if held_update.as_ref().map_or(true, |held| held.contents.timestamp < update.contents.timestamp) {
+				*held_update = Some(update.clone());
+			}
+			return true;
+		}
+		false
+	}
+
+	/// Remove and return all parked announcements that have since been buried deeply enough.
+	pub(crate) fn take_matured_announcements(&self) -> Vec<ParkedAnnouncement> {
+		let mut parked_announcements = self.parked_announcements.lock().expect("parked announcement lock poisoned");
+		let matured_scids: Vec<u64> = parked_announcements.keys()
+			.filter(|scid| !self.is_insufficiently_confirmed(**scid))
+			.cloned()
+			.collect();
+		matured_scids.iter().filter_map(|scid| parked_announcements.remove(scid)).collect()
 	}
 
-	async fn retrieve_utxo(client: Arc<RestClient>, short_channel_id: u64, logger: L) -> Result<TxOut, UtxoLookupError> {
-		let block_height = (short_channel_id >> 5 * 8) as u32; // block height is most significant three bytes
-		let transaction_index = ((short_channel_id >> 2 * 8) & 0xffffff) as u32;
-		let output_index = (short_channel_id & 0xffff) as u16;
+	pub(crate) fn parked_announcement_count(&self) -> usize {
+		self.parked_announcements.lock().expect("parked announcement lock poisoned").len()
+	}
+
+	/// Look up a channel's funding output, along with the timestamp of the block it was confirmed in.
+	/// Channels claiming to be funded beyond the chain tip, if it's known, aren't looked up at all.
+	async fn retrieve_utxo(client: &dyn ChainSource, backend_stats: &ChainBackendStats, short_channel_id: u64, tip_height: u32, logger: L) -> Result<(TxOut, u32), UtxoLookupError> {
+		let parsed_short_channel_id = validate_funding_height(short_channel_id, tip_height).map_err(|error| {
+			log_warn!(logger, "Not looking up the funding output of channel {}: {}", ShortChannelId::from(short_channel_id), error);
+			UtxoLookupError::UnknownChain
+		})?;
+		let block_height = parsed_short_channel_id.block_height();
+		let transaction_index = parsed_short_channel_id.tx_index();
+		let output_index = parsed_short_channel_id.output_index();
 
-		let mut block = Self::retrieve_block(client, block_height, logger.clone()).await?;
+		let lookup_start = Instant::now();
+		let block_result = Self::retrieve_block(client, backend_stats, block_height, logger.clone()).await;
+		let lookup_duration = lookup_start.elapsed();
+		if lookup_duration > backend_stats.slow_lookup_threshold {
+			log_warn!(logger, "Slow chain lookup for channel {} at block height {}: {:?}", short_channel_id, block_height, lookup_duration);
+		}
+
+		let mut block = block_result?;
 		if transaction_index as usize >= block.txdata.len() {
 			log_error!(logger, "Could't find transaction {} in block {}", transaction_index, block_height);
 			return Err(UtxoLookupError::UnknownTx);
@@ -57,13 +261,11 @@ impl<L: Deref + Clone + Send + Sync + 'static> ChainVerifier<L> where L::Target:
 			log_error!(logger, "Could't find output {} in transaction {}", output_index, transaction.txid());
 			return Err(UtxoLookupError::UnknownTx);
 		}
-		Ok(transaction.output.swap_remove(output_index as usize))
+		Ok((transaction.output.swap_remove(output_index as usize), block.header.time))
 	}
 
-	async fn retrieve_block(client: Arc<RestClient>, block_height: u32, logger: L) -> Result<Block, UtxoLookupError> {
-		let uri = format!("blockhashbyheight/{}.bin", block_height);
-		let block_hash_result =
-			client.request_resource::<BinaryResponse, RestBinaryResponse>(&uri).await;
+	async fn retrieve_block(client: &dyn ChainSource, backend_stats: &ChainBackendStats, block_height: u32, logger: L) -> Result<Block, UtxoLookupError> {
+		let block_hash_result = timed_request(backend_stats, client.get_block_hash_by_height(block_height)).await;
 		let block_hash: Vec<u8> = block_hash_result.map_err(|error| {
 			match error.kind() {
 				ErrorKind::InvalidData => {
@@ -75,10 +277,13 @@ impl<L: Deref + Clone + Send + Sync + 'static> ChainVerifier<L> where L::Target:
 				}
 			}
 			UtxoLookupError::UnknownChain
-		})?.0;
-		let block_hash = BlockHash::from_slice(&block_hash).unwrap();
+		})?;
+		let block_hash = BlockHash::from_slice(&block_hash).map_err(|_| {
+			log_error!(logger, "Could't find block hash at height {}: Invalid block hash length {}", block_height, block_hash.len());
+			UtxoLookupError::UnknownChain
+		})?;
 
-		let block_result = client.get_block(&block_hash).await;
+		let block_result = timed_request(backend_stats, client.get_block(&block_hash)).await;
 		match block_result {
 			Ok(BlockData::FullBlock(block)) => {
 				Ok(block)
@@ -90,6 +295,222 @@ impl<L: Deref + Clone + Send + Sync + 'static> ChainVerifier<L> where L::Target:
 			}
 		}
 	}
+
+	/// Measure how long retrieving a block from the chain backend takes, and warn if it's slow
+	/// enough to hold up the initial sync, which is usually due to bitcoind's storage.
+	pub(crate) async fn probe_backend_latency(verifier: Arc<Self>) {
+		let tip_height = match timed_request(&verifier.backend_stats, verifier.chain_source.get_best_block()).await {
+			Ok((_, Some(tip_height))) => tip_height,
+			Ok((_, None)) => {
+				log_warn!(verifier.logger, "Failed to probe chain backend latency: chain tip height unknown");
+				return;
+			},
+			Err(error) => {
+				log_warn!(verifier.logger, "Failed to probe chain backend latency: {:?}", error);
+				return;
+			}
+		};
+
+		let probe_height = tip_height.saturating_sub(PROBE_BLOCK_DEPTH);
+		let probe_start = Instant::now();
+		if Self::retrieve_block(&*verifier.chain_source, &verifier.backend_stats, probe_height, verifier.logger.clone()).await.is_err() {
+			// the failure itself has already been logged
+			return;
+		}
+		let probe_latency = probe_start.elapsed();
+
+		let max_probe_latency = config::max_chain_backend_probe_latency();
+		if probe_latency > max_probe_latency {
+			log_warn!(verifier.logger, "Chain backend is slow: retrieving block {} took {:?}, more than {:?}. Verifying channels will slow down the initial sync.", probe_height, probe_latency, max_probe_latency);
+		} else {
+			log_info!(verifier.logger, "Chain backend baseline latency: retrieving block {} took {:?}", probe_height, probe_latency);
+		}
+	}
+
+	/// Follow the chain tip, and whenever a reorg is detected, re-verify all the channels that
+	/// were funded at or above the fork point.
+	pub(crate) async fn follow_chain_tip(verifier: Arc<Self>) {
+		let mut recent_blocks: BTreeMap<u32, BlockHash> = BTreeMap::new();
+		let mut interval = tokio::time::interval(CHAIN_TIP_POLL_INTERVAL);
+		loop {
+			interval.tick().await;
+			match verifier.poll_chain_tip(&mut recent_blocks).await {
+				Ok(Some(fork_height)) => verifier.heal_reorg(fork_height).await,
+				Ok(None) => {},
+				Err(error) => {
+					log_warn!(verifier.logger, "Failed to poll chain tip: {:?}", error);
+				}
+			}
+		}
+	}
+
+	/// Compare the current chain tip against the recently seen blocks, returning the height of the
+	/// first replaced block if a reorg occurred.
+	async fn poll_chain_tip(&self, recent_blocks: &mut BTreeMap<u32, BlockHash>) -> Result<Option<u32>, BlockSourceError> {
+		let (tip_hash, tip_height) = timed_request(&self.backend_stats, self.chain_source.get_best_block()).await?;
+		let tip_height = tip_height.ok_or_else(|| BlockSourceError::transient("Chain tip height unknown"))?;
+		self.best_block_height.fetch_max(tip_height, Ordering::AcqRel);
+
+		let (known_tip_height, known_tip_hash) = match recent_blocks.iter().next_back() {
+			Some((height, hash)) => (*height, *hash),
+			None => {
+				recent_blocks.insert(tip_height, tip_hash);
+				return Ok(None);
+			}
+		};
+
+		if tip_hash == known_tip_hash {
+			return Ok(None);
+		}
+		if tip_height <= known_tip_height {
+			// the backend may be restarting or still syncing, so wait for it to catch up before
+			// drawing any conclusions
+			log_info!(self.logger, "Chain backend is behind ({} at height {}, previously {} at {})", tip_hash, tip_height, known_tip_hash, known_tip_height);
+			return Ok(None);
+		}
+		if (tip_height - known_tip_height) as usize > REORG_TRACKING_DEPTH {
+			log_warn!(self.logger, "Chain tip jumped from height {} to {}, restarting reorg tracking", known_tip_height, tip_height);
+			recent_blocks.clear();
+			recent_blocks.insert(tip_height, tip_hash);
+			return Ok(None);
+		}
+
+		// walk back from the new tip until we connect to a block we already know
+		let oldest_tracked_height = recent_blocks.keys().next().copied().unwrap_or(known_tip_height);
+		let mut new_blocks = Vec::new();
+		let mut current_hash = tip_hash;
+		let mut current_height = tip_height;
+		let fork_height = loop {
+			if recent_blocks.get(&current_height) == Some(&current_hash) {
+				break current_height + 1;
+			}
+			if current_height < oldest_tracked_height {
+				// the reorg is deeper than our tracking window, so everything we know is suspect
+				break oldest_tracked_height;
+			}
+			let header = timed_request(&self.backend_stats, self.chain_source.get_header(&current_hash, Some(current_height))).await?;
+			new_blocks.push((current_height, current_hash));
+			current_hash = header.header.prev_blockhash;
+			current_height -= 1;
+		};
+
+		let is_reorg = recent_blocks.range(fork_height..).next().is_some();
+		recent_blocks.retain(|height, _| *height < fork_height);
+		recent_blocks.extend(new_blocks);
+		while recent_blocks.len() > REORG_TRACKING_DEPTH {
+			recent_blocks.pop_first();
+		}
+
+		if is_reorg {
+			log_warn!(self.logger, "Detected reorg replacing blocks from height {} (new tip {} at height {})", fork_height, tip_hash, tip_height);
+			return Ok(Some(fork_height));
+		}
+		Ok(None)
+	}
+
+	/// Re-verify the funding outputs of all channels confirmed at or after `fork_height`, and
+	/// remove those that no longer exist from both the network graph and the database.
+	///
+	/// Channels removed from the graph are no longer included in subsequent snapshots, and each
+	/// removal is recorded in the `channel_removals` table, which only the compact graph reads. The
+	/// snapshot format has no way to express removals, so delta snapshots can't carry them: clients
+	/// that already know a reorged-out channel keep it until they prune it as stale themselves.
+	pub(crate) async fn heal_reorg(&self, fork_height: u32) {
+		self.reorg_counter.write().expect("reorg counter lock poisoned").reorgs_detected += 1;
+
+		let affected_channels: Vec<(u64, Option<ScriptBuf>)> = {
+			let read_only_graph = self.graph.read_only();
+			read_only_graph.channels().unordered_iter()
+				.filter(|(scid, _)| (**scid >> 5 * 8) as u32 >= fork_height)
+				.map(|(scid, channel)| {
+					let expected_script = channel.announcement_message.as_ref().and_then(|announcement| {
+						let bitcoin_key_1 = announcement.contents.bitcoin_key_1.as_pubkey().ok()?;
+						let bitcoin_key_2 = announcement.contents.bitcoin_key_2.as_pubkey().ok()?;
+						Some(make_funding_redeemscript(&bitcoin_key_1, &bitcoin_key_2).to_v0_p2wsh())
+					});
+					(*scid, expected_script)
+				})
+				.collect()
+		};
+		log_info!(self.logger, "Re-verifying {} channels confirmed at or after height {}", affected_channels.len(), fork_height);
+
+		let mut invalidated_scids = Vec::new();
+		for (scid, expected_script) in affected_channels {
+			let tip_height = self.best_block_height.load(Ordering::Acquire);
+			match Self::retrieve_utxo(&*self.chain_source, &self.backend_stats, scid, tip_height, self.logger.clone()).await {
+				Ok((output, _)) => {
+					if let Some(expected_script) = expected_script {
+						if output.script_pubkey != expected_script {
+							invalidated_scids.push(scid);
+						}
+					}
+				},
+				Err(UtxoLookupError::UnknownTx) => invalidated_scids.push(scid),
+				Err(UtxoLookupError::UnknownChain) => {
+					// the backend may not have caught up with the new chain yet
+					log_warn!(self.logger, "Could not re-verify channel {} after reorg", scid);
+				}
+			}
+		}
+
+		if invalidated_scids.is_empty() {
+			return;
+		}
+
+		for scid in &invalidated_scids {
+			log_warn!(self.logger, "Removing channel {} whose funding output was reorged out", scid);
+			self.graph.channel_failed_permanent(*scid);
+		}
+		self.reorg_counter.write().expect("reorg counter lock poisoned").channels_invalidated += invalidated_scids.len() as u64;
+
+		let scids: Vec<i64> = invalidated_scids.iter().map(|scid| *scid as i64).collect();
+		let tables = Tables::from_config();
+		let mut client = match crate::connect_to_db().await {
+			Ok(client) => client,
+			Err(error) => {
+				log_error!(self.logger, "Failed to remove reorged channels from the database: {}", error);
+				return;
+			}
+		};
+		let removal_result = async {
+			let tx = client.transaction().await?;
+			tx.execute(&format!("DELETE FROM {} WHERE short_channel_id = any($1)", tables.channel_announcements()), &[&scids]).await?;
+			tx.execute(&format!("DELETE FROM {} WHERE short_channel_id = any($1)", tables.channel_updates()), &[&scids]).await?;
+			tx.execute(&format!("INSERT INTO {} (short_channel_id) SELECT unnest($1::bigint[])", tables.channel_removals()), &[&scids]).await?;
+			tx.commit().await
+		}.await;
+		if let Err(error) = removal_result {
+			log_error!(self.logger, "Failed to remove reorged channels from the database: {}", error);
+		}
+	}
+}
+
+/// Await a request to the chain backend, recording its duration and whether it succeeded
+async fn timed_request<T, E, F: Future<Output = Result<T, E>>>(backend_stats: &ChainBackendStats, request: F) -> Result<T, E> {
+	let request_start = Instant::now();
+	let result = request.await;
+	backend_stats.record(request_start.elapsed(), result.is_ok());
+	result
+}
+
+/// Whether an output confirmed in the block at `funding_height` has at least `min_confirmations`
+/// confirmations given a chain tip at `tip_height`
+pub(crate) fn has_sufficient_confirmations(funding_height: u32, tip_height: u32, min_confirmations: u32) -> bool {
+	if tip_height < funding_height {
+		return min_confirmations == 0;
+	}
+	tip_height - funding_height + 1 >= min_confirmations
+}
+
+/// Check that a channel's funding block was mined as of the chain tip at `tip_height`, unless the
+/// tip is unknown, i. e. 0. Channels can't be funded in blocks that don't exist yet, so looking
+/// them up would only burden the chain backend.
+fn validate_funding_height(short_channel_id: u64, tip_height: u32) -> Result<ShortChannelId, ScidError> {
+	let short_channel_id = ShortChannelId::from(short_channel_id);
+	if tip_height == 0 {
+		return Ok(short_channel_id);
+	}
+	ShortChannelId::new_as_of_tip(short_channel_id.block_height(), short_channel_id.tx_index(), short_channel_id.output_index(), tip_height)
 }
 
 impl<L: Deref + Clone + Send + Sync + 'static> UtxoLookup for ChainVerifier<L> where L::Target: Logger {
@@ -97,12 +518,22 @@ impl<L: Deref + Clone + Send + Sync + 'static> UtxoLookup for ChainVerifier<L> w
 		let res = UtxoFuture::new();
 		let fut = res.clone();
 		let graph_ref = Arc::clone(&self.graph);
-		let client_ref = Arc::clone(&self.rest_client);
+		let client_ref = Arc::clone(&self.chain_source);
+		let backend_stats_ref = Arc::clone(&self.backend_stats);
 		let gossip_ref = Arc::clone(&self.outbound_gossiper);
-		let pm_ref = self.peer_handler.lock().unwrap().clone();
+		let pm_ref = self.peer_handler.lock().expect("peer handler lock poisoned").clone();
+		let funding_outputs_ref = self.funding_outputs.lock().expect("funding output lock poisoned").clone();
+		let tip_height = self.best_block_height.load(Ordering::Acquire);
 		let logger_ref = self.logger.clone();
 		tokio::spawn(async move {
-			let res = Self::retrieve_utxo(client_ref, short_channel_id, logger_ref).await;
+			let res = Self::retrieve_utxo(&*client_ref, &backend_stats_ref, short_channel_id, tip_height, logger_ref).await;
+			// recorded before resolving, which is what lets the announcement through to the persister
+			let res = res.map(|(output, block_time)| {
+				if let Some(funding_outputs) = funding_outputs_ref {
+					funding_outputs.record(short_channel_id, FundingOutput { block_time, value_sats: output.value });
+				}
+				output
+			});
 			fut.resolve(&*graph_ref, &*gossip_ref, res);
 			if let Some(pm) = pm_ref { pm.process_events(); }
 		});
@@ -117,3 +548,249 @@ impl TryInto<RestBinaryResponse> for BinaryResponse {
 		Ok(RestBinaryResponse(self.0))
 	}
 }
+
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+
+	use bitcoin::{CompactTarget, Network, Transaction, TxMerkleNode};
+	use bitcoin::blockdata::block::{Header, Version};
+	use lightning_block_sync::{AsyncBlockSourceResult, BlockHeaderData};
+
+	use crate::types::tests::TestLogger;
+
+	/// A chain backend serving a chain that tests extend, reorganize, and hold back at will
+	pub(crate) struct ScriptedChain {
+		state: Mutex<ScriptedChainState>,
+	}
+
+	struct ScriptedChainState {
+		/// The blocks of the chain the backend follows, by height
+		best_chain: BTreeMap<u32, Block>,
+		/// Every block ever mined, including those of abandoned forks, whose headers remain
+		/// retrievable, like bitcoind's
+		blocks_by_hash: HashMap<BlockHash, (u32, Block)>,
+		/// The height the backend reports as its tip, which lags behind the best chain while the
+		/// backend pretends to be syncing
+		reported_tip_height: u32,
+	}
+
+	impl ScriptedChain {
+		/// A chain whose first block, which no reorg can replace, is at the given height
+		pub(crate) fn new(base_height: u32) -> Self {
+			let chain = Self { state: Mutex::new(ScriptedChainState { best_chain: BTreeMap::new(), blocks_by_hash: HashMap::new(), reported_tip_height: base_height }) };
+			chain.mine_at(base_height, vec![]);
+			chain
+		}
+
+		/// Extend the best chain by a block holding the given transactions, returning its height
+		pub(crate) fn mine(&self, txdata: Vec<Transaction>) -> u32 {
+			let height = self.state.lock().unwrap().best_chain.keys().next_back().expect("the chain has a base block") + 1;
+			self.mine_at(height, txdata);
+			height
+		}
+
+		fn mine_at(&self, height: u32, txdata: Vec<Transaction>) {
+			let mut state = self.state.lock().unwrap();
+			let prev_blockhash = state.best_chain.get(&(height - 1)).map_or(BlockHash::all_zeros(), |block| block.block_hash());
+			let block = Block {
+				header: Header {
+					version: Version::ONE,
+					prev_blockhash,
+					merkle_root: TxMerkleNode::all_zeros(),
+					time: height,
+					bits: CompactTarget::from_consensus(0x207fffff),
+					// keeps the blocks of competing forks distinct
+					nonce: state.blocks_by_hash.len() as u32,
+				},
+				txdata,
+			};
+			state.blocks_by_hash.insert(block.block_hash(), (height, block.clone()));
+			state.best_chain.insert(height, block);
+			state.reported_tip_height = height;
+		}
+
+		/// Disconnect the blocks from the given height on, to be replaced by newly mined ones
+		pub(crate) fn reorg(&self, fork_height: u32) {
+			let mut state = self.state.lock().unwrap();
+			assert!(state.best_chain.keys().next().map_or(false, |base_height| *base_height < fork_height), "the base block can't be reorged out");
+			state.best_chain.retain(|height, _| *height < fork_height);
+			state.reported_tip_height = fork_height - 1;
+		}
+
+		/// Report the best chain's block at the given height as the tip, as a backend still
+		/// syncing or restoring from a backup would
+		pub(crate) fn report_tip_height(&self, height: u32) {
+			self.state.lock().unwrap().reported_tip_height = height;
+		}
+
+		pub(crate) fn hash_at(&self, height: u32) -> BlockHash {
+			self.state.lock().unwrap().best_chain[&height].block_hash()
+		}
+	}
+
+	impl BlockSource for ScriptedChain {
+		fn get_header<'a>(&'a self, header_hash: &'a BlockHash, _height_hint: Option<u32>) -> AsyncBlockSourceResult<'a, BlockHeaderData> {
+			Box::pin(async move {
+				let state = self.state.lock().unwrap();
+				let (height, block) = state.blocks_by_hash.get(header_hash).ok_or_else(|| BlockSourceError::persistent("unknown block"))?;
+				Ok(BlockHeaderData { header: block.header, height: *height, chainwork: block.header.work() })
+			})
+		}
+
+		fn get_block<'a>(&'a self, header_hash: &'a BlockHash) -> AsyncBlockSourceResult<'a, BlockData> {
+			Box::pin(async move {
+				let state = self.state.lock().unwrap();
+				let (_, block) = state.blocks_by_hash.get(header_hash).ok_or_else(|| BlockSourceError::persistent("unknown block"))?;
+				Ok(BlockData::FullBlock(block.clone()))
+			})
+		}
+
+		fn get_best_block<'a>(&'a self) -> AsyncBlockSourceResult<'a, (BlockHash, Option<u32>)> {
+			Box::pin(async move {
+				let state = self.state.lock().unwrap();
+				let tip_height = state.reported_tip_height;
+				Ok((state.best_chain[&tip_height].block_hash(), Some(tip_height)))
+			})
+		}
+	}
+
+	impl ChainSource for ScriptedChain {
+		fn get_block_hash_by_height<'a>(&'a self, block_height: u32) -> Pin<Box<dyn Future<Output = std::io::Result<Vec<u8>>> + Send + 'a>> {
+			Box::pin(async move {
+				let state = self.state.lock().unwrap();
+				match state.best_chain.get(&block_height) {
+					Some(block) if block_height <= state.reported_tip_height => Ok(block.block_hash().to_byte_array().to_vec()),
+					_ => Err(std::io::Error::new(ErrorKind::NotFound, "block height out of range")),
+				}
+			})
+		}
+	}
+
+	/// A verifier of the given graph's channels, backed by a scripted chain
+	pub(crate) fn scripted_verifier(graph: Arc<NetworkGraph<Arc<TestLogger>>>, chain: Arc<ScriptedChain>, logger: Arc<TestLogger>) -> ChainVerifier<Arc<TestLogger>> {
+		let outbound_gossiper = Arc::new(P2PGossipSync::new(Arc::clone(&graph), None, logger.clone()));
+		ChainVerifier::with_chain_source(graph, outbound_gossiper, chain, Arc::new(ChainBackendStats::new()), logger)
+	}
+
+	fn tracking_verifier(chain: &Arc<ScriptedChain>) -> ChainVerifier<Arc<TestLogger>> {
+		let logger = Arc::new(TestLogger::with_id("verifier_reorg".to_string()));
+		let graph = Arc::new(NetworkGraph::new(Network::Bitcoin, logger.clone()));
+		scripted_verifier(graph, Arc::clone(chain), logger)
+	}
+
+	#[tokio::test]
+	async fn test_one_block_reorg() {
+		let chain = Arc::new(ScriptedChain::new(100));
+		chain.mine(vec![]);
+		let verifier = tracking_verifier(&chain);
+		let mut recent_blocks = BTreeMap::new();
+		assert_eq!(verifier.poll_chain_tip(&mut recent_blocks).await.unwrap(), None);
+		assert_eq!(recent_blocks, BTreeMap::from([(101, chain.hash_at(101))]));
+
+		// extending the chain is no reorg
+		chain.mine(vec![]);
+		assert_eq!(verifier.poll_chain_tip(&mut recent_blocks).await.unwrap(), None);
+		assert_eq!(recent_blocks.len(), 2);
+
+		// a competing block at the same height looks no different from a lagging backend, so the
+		// reorg is only detected once the new fork overtakes the old one
+		let replaced_hash = chain.hash_at(102);
+		chain.reorg(102);
+		chain.mine(vec![]);
+		assert_eq!(verifier.poll_chain_tip(&mut recent_blocks).await.unwrap(), None);
+		assert_eq!(recent_blocks[&102], replaced_hash);
+		chain.mine(vec![]);
+		assert_eq!(verifier.poll_chain_tip(&mut recent_blocks).await.unwrap(), Some(102));
+		assert_eq!(recent_blocks, BTreeMap::from([(101, chain.hash_at(101)), (102, chain.hash_at(102)), (103, chain.hash_at(103))]));
+		assert_eq!(verifier.best_block_height.load(Ordering::Acquire), 103);
+	}
+
+	#[tokio::test]
+	async fn test_reorg_beyond_tracking_depth() {
+		let chain = Arc::new(ScriptedChain::new(1_000));
+		for _ in 0..200 {
+			chain.mine(vec![]);
+		}
+		let verifier = tracking_verifier(&chain);
+		let oldest_tracked_height = 1_200 - REORG_TRACKING_DEPTH as u32 + 1;
+		let mut recent_blocks: BTreeMap<u32, BlockHash> = (oldest_tracked_height..=1_200).map(|height| (height, chain.hash_at(height))).collect();
+
+		// the fork point predates all tracked blocks, so all of them are considered replaced
+		chain.reorg(1_001);
+		for _ in 0..201 {
+			chain.mine(vec![]);
+		}
+		assert_eq!(verifier.poll_chain_tip(&mut recent_blocks).await.unwrap(), Some(oldest_tracked_height));
+		assert_eq!(recent_blocks.len(), REORG_TRACKING_DEPTH);
+		assert_eq!(recent_blocks.iter().next_back(), Some((&1_201, &chain.hash_at(1_201))));
+		assert!(recent_blocks.iter().all(|(height, hash)| *hash == chain.hash_at(*height)));
+	}
+
+	#[tokio::test]
+	async fn test_lagging_backend_and_tip_jump() {
+		let chain = Arc::new(ScriptedChain::new(100));
+		chain.mine(vec![]);
+		let verifier = tracking_verifier(&chain);
+		let mut recent_blocks = BTreeMap::new();
+		assert_eq!(verifier.poll_chain_tip(&mut recent_blocks).await.unwrap(), None);
+
+		// a backend falling behind isn't taken for a reorg, nor does it lower the known tip
+		chain.report_tip_height(100);
+		assert_eq!(verifier.poll_chain_tip(&mut recent_blocks).await.unwrap(), None);
+		assert_eq!(recent_blocks, BTreeMap::from([(101, chain.hash_at(101))]));
+		assert_eq!(verifier.best_block_height.load(Ordering::Acquire), 101);
+
+		// once it has caught up, tracking continues where it left off
+		chain.mine(vec![]);
+		assert_eq!(verifier.poll_chain_tip(&mut recent_blocks).await.unwrap(), None);
+		assert_eq!(recent_blocks, BTreeMap::from([(101, chain.hash_at(101)), (102, chain.hash_at(102))]));
+
+		// a tip too far ahead to walk back from restarts the tracking
+		for _ in 0..=REORG_TRACKING_DEPTH {
+			chain.mine(vec![]);
+		}
+		assert_eq!(verifier.poll_chain_tip(&mut recent_blocks).await.unwrap(), None);
+		let tip_height = 102 + REORG_TRACKING_DEPTH as u32 + 1;
+		assert_eq!(recent_blocks, BTreeMap::from([(tip_height, chain.hash_at(tip_height))]));
+	}
+
+	#[test]
+	fn test_confirmation_depth_boundary() {
+		// an output in the tip block has one confirmation
+		assert!(has_sufficient_confirmations(100, 100, 1));
+		assert!(!has_sufficient_confirmations(100, 100, 2));
+
+		// exactly six confirmations
+		assert!(!has_sufficient_confirmations(100, 104, 6));
+		assert!(has_sufficient_confirmations(100, 105, 6));
+		assert!(has_sufficient_confirmations(100, 106, 6));
+
+		// outputs beyond our chain tip are never sufficiently confirmed unless no depth is required
+		assert!(!has_sufficient_confirmations(100, 99, 1));
+		assert!(has_sufficient_confirmations(100, 99, 0));
+	}
+
+	#[test]
+	fn test_funding_height_validation() {
+		let short_channel_id = u64::from(ShortChannelId::new(800_000, 1, 0).unwrap());
+		assert_eq!(validate_funding_height(short_channel_id, 800_000), Ok(ShortChannelId::from(short_channel_id)));
+		assert_eq!(validate_funding_height(short_channel_id, 799_999), Err(ScidError::BeyondChainTip { block_height: 800_000, tip_height: 799_999 }));
+		// until the chain tip is known, every channel is looked up
+		assert!(validate_funding_height(short_channel_id, 0).is_ok());
+	}
+
+	#[test]
+	fn test_funding_output_eviction() {
+		let funding_output = FundingOutput { block_time: 1_700_000_000, value_sats: 100_000 };
+		let funding_outputs = FundingOutputs::new();
+		for short_channel_id in 0..=MAX_PENDING_FUNDING_OUTPUTS as u64 {
+			funding_outputs.record(short_channel_id, funding_output);
+		}
+		// the oldest pending output made room for the newest
+		assert_eq!(funding_outputs.take(0), None);
+		assert_eq!(funding_outputs.take(1), Some(funding_output));
+		assert_eq!(funding_outputs.take(1), None);
+		assert_eq!(funding_outputs.take(MAX_PENDING_FUNDING_OUTPUTS as u64), Some(funding_output));
+	}
+}
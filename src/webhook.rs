@@ -0,0 +1,88 @@
+use std::ops::Deref;
+use std::time::Duration;
+
+use lightning::{log_info, log_warn};
+use lightning::util::logger::Logger;
+use lightning_block_sync::http::HttpEndpoint;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+use crate::config;
+
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Notify the configured stall webhook, if any, that gossip processing appears to have stalled.
+///
+/// Failures to deliver the notification are logged, but otherwise ignored.
+pub(crate) async fn notify_stall<L: Deref>(reason: &str, logger: L) where L::Target: Logger {
+	let body = format!("{{\"event\":\"stall\",\"reason\":\"{}\"}}", escape_json(reason));
+	notify(&body, logger).await;
+}
+
+/// Notify the configured stall webhook, if any, that a snapshot exceeded the maximum blob size and
+/// was not published
+pub(crate) async fn notify_oversized_snapshot<L: Deref>(scope: u64, size_bytes: u64, max_bytes: u64, logger: L) where L::Target: Logger {
+	let body = format!("{{\"event\":\"oversized_snapshot\",\"scope\":{},\"size_bytes\":{},\"max_bytes\":{}}}", scope, size_bytes, max_bytes);
+	notify(&body, logger).await;
+}
+
+/// Notify the configured stall webhook, if any, that a snapshot generation round failed, and the
+/// previous generation keeps being served
+pub(crate) async fn notify_snapshot_generation_failure<L: Deref>(reason: &str, failure_count: u64, logger: L) where L::Target: Logger {
+	let body = format!("{{\"event\":\"snapshot_generation_failed\",\"reason\":\"{}\",\"failure_count\":{}}}", escape_json(reason), failure_count);
+	notify(&body, logger).await;
+}
+
+/// Notify the configured stall webhook, if any, that the newest published snapshot fell behind,
+/// even though gossip is still being received
+pub(crate) async fn notify_publication_stall<L: Deref>(publication_lag_secs: u64, seen_lag_secs: u64, logger: L) where L::Target: Logger {
+	let body = format!("{{\"event\":\"snapshot_publication_stalled\",\"publication_lag_secs\":{},\"seen_lag_secs\":{}}}", publication_lag_secs, seen_lag_secs);
+	notify(&body, logger).await;
+}
+
+/// Notify the configured stall webhook, if any, that the newest delta doesn't chain onto the
+/// previous generation's full snapshot
+pub(crate) async fn notify_chain_divergence<L: Deref>(reference_timestamp: u64, reason: &str, logger: L) where L::Target: Logger {
+	let body = format!("{{\"event\":\"delta_chain_diverged\",\"reference_timestamp\":{},\"reason\":\"{}\"}}", reference_timestamp, escape_json(reason));
+	notify(&body, logger).await;
+}
+
+async fn notify<L: Deref>(body: &str, logger: L) where L::Target: Logger {
+	let endpoint = match config::stall_webhook_endpoint() {
+		Some(endpoint) => endpoint,
+		None => return,
+	};
+	match tokio::time::timeout(WEBHOOK_TIMEOUT, post(&endpoint, body)).await {
+		Ok(Ok(status_line)) => log_info!(logger, "Stall webhook responded with {}", status_line),
+		Ok(Err(error)) => log_warn!(logger, "Failed to call stall webhook: {}", error),
+		Err(_) => log_warn!(logger, "Stall webhook timed out"),
+	}
+}
+
+async fn post(endpoint: &HttpEndpoint, body: &str) -> std::io::Result<String> {
+	let mut stream = TcpStream::connect((endpoint.host(), endpoint.port())).await?;
+	let request = format!(
+		"POST {} HTTP/1.1\r\nHost: {}:{}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+		endpoint.path(), endpoint.host(), endpoint.port(), body.len(), body
+	);
+	stream.write_all(request.as_bytes()).await?;
+
+	let mut response = Vec::new();
+	stream.read_to_end(&mut response).await?;
+	let response = String::from_utf8_lossy(&response);
+	Ok(response.lines().next().unwrap_or_default().to_string())
+}
+
+fn escape_json(value: &str) -> String {
+	let mut escaped = String::with_capacity(value.len());
+	for character in value.chars() {
+		match character {
+			'"' => escaped.push_str("\\\""),
+			'\\' => escaped.push_str("\\\\"),
+			'\n' => escaped.push_str("\\n"),
+			character if character.is_control() => escaped.push_str(&format!("\\u{:04x}", character as u32)),
+			character => escaped.push(character),
+		}
+	}
+	escaped
+}
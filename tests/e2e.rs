@@ -0,0 +1,81 @@
+//! End-to-end test of the whole pipeline: the test server's mock peer feeds gossip over a real
+//! connection, the server verifies it against the mock chain backend, persists it, and generates
+//! snapshots, and the full snapshot is then applied to a fresh network graph.
+//!
+//! Like the database tests, this requires a Postgres instance, which is configured through the
+//! same `RAPID_GOSSIP_SYNC_SERVER_DB_*` environment variables as the server itself.
+
+use std::fs;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bitcoin::Network;
+use lightning::routing::gossip::NetworkGraph;
+use lightning::util::test_utils::TestLogger;
+use lightning_rapid_gossip_sync::RapidGossipSync;
+use rapid_gossip_sync_server::test_utils::TestGossipServer;
+use rapid_gossip_sync_server::test_utils::synthetic::{SyntheticNetwork, SyntheticNetworkParams};
+
+/// How long the server gets to sync and publish its first round of snapshots
+const SNAPSHOT_TIMEOUT: Duration = Duration::from_secs(180);
+
+#[tokio::test(flavor = "multi_thread")]
+async fn test_snapshot_pipeline() {
+	// a single update per channel direction, so that every update must make it into the snapshot
+	let network = SyntheticNetwork::generate(&SyntheticNetworkParams {
+		node_count: 4,
+		channel_count: 6,
+		updates_per_direction: 1,
+		..Default::default()
+	});
+
+	let server = TestGossipServer::new();
+	for announcement in &network.announcements {
+		server.inject_announcement(announcement.clone());
+	}
+	for update in &network.updates {
+		server.inject_update(update.clone());
+	}
+
+	// the symlink for clients that have never synced points to the full snapshot
+	let full_snapshot_path = format!("{}/0.bin", server.base_url().trim_start_matches("file://"));
+	let started_at = Instant::now();
+	let snapshot = loop {
+		if let Ok(snapshot) = fs::read(&full_snapshot_path) {
+			break snapshot;
+		}
+		assert!(started_at.elapsed() < SNAPSHOT_TIMEOUT, "no snapshot was generated within {:?}", SNAPSHOT_TIMEOUT);
+		tokio::time::sleep(Duration::from_secs(1)).await;
+	};
+	assert_eq!(server.graph().read_only().channels().len(), network.announcements.len());
+
+	let logger = Arc::new(TestLogger::new());
+	let client_graph = Arc::new(NetworkGraph::new(Network::Bitcoin, Arc::clone(&logger)));
+	let rapid_sync = RapidGossipSync::new(Arc::clone(&client_graph), Arc::clone(&logger));
+	rapid_sync.update_network_graph(&snapshot).unwrap();
+
+	let client_graph = client_graph.read_only();
+	assert_eq!(client_graph.channels().len(), network.announcements.len());
+	for announcement in &network.announcements {
+		let contents = &announcement.contents;
+		let channel = client_graph.channels().get(&contents.short_channel_id)
+			.unwrap_or_else(|| panic!("channel {} is missing from the snapshot", contents.short_channel_id));
+		assert_eq!((channel.node_one, channel.node_two), (contents.node_id_1, contents.node_id_2));
+	}
+	for update in &network.updates {
+		let contents = &update.contents;
+		let channel = client_graph.channels().get(&contents.short_channel_id).unwrap();
+		let direction = if contents.flags & 1 == 0 { &channel.one_to_two } else { &channel.two_to_one };
+		let direction = direction.as_ref()
+			.unwrap_or_else(|| panic!("channel {} lacks the update with flags {}", contents.short_channel_id, contents.flags));
+		assert_eq!(direction.enabled, contents.flags & 2 == 0);
+		assert_eq!(direction.cltv_expiry_delta, contents.cltv_expiry_delta);
+		assert_eq!(direction.htlc_minimum_msat, contents.htlc_minimum_msat);
+		assert_eq!(direction.htlc_maximum_msat, contents.htlc_maximum_msat);
+		assert_eq!(direction.fees.base_msat, contents.fee_base_msat);
+		assert_eq!(direction.fees.proportional_millionths, contents.fee_proportional_millionths);
+	}
+
+	// the server must not be dropped from an async context, as it waits for its own runtime to stop
+	tokio::task::spawn_blocking(move || drop(server)).await.unwrap();
+}
@@ -0,0 +1,42 @@
+//! The exit codes the server binary stops with when it can't start, which orchestration relies on
+//! to tell configuration problems from transient failures.
+
+use std::env;
+use std::process::{Command, Output};
+
+use rapid_gossip_sync_server::{EXIT_CONFIG_ERROR, EXIT_DATABASE_UNREACHABLE};
+
+const PEER: &str = "035e4ff418fc8b5554c5d9eea66396c227bd429a3251c8cbc711002ba215bfc226@127.0.0.1:9735";
+
+/// Start the server with a fresh cache directory, a chain backend nothing listens on, and a
+/// database behind a Unix socket that doesn't exist, unless overridden
+fn run_server(test_name: &str, overrides: &[(&str, &str)]) -> Output {
+	let cache_path = format!("{}/rgs_exit_codes_{}_{}", env::temp_dir().display(), test_name, std::process::id());
+	let mut command = Command::new(env!("CARGO_BIN_EXE_rapid-gossip-sync-server"));
+	command
+		.env_remove("LDK_RGS_PEERS_FILE")
+		.env("RAPID_GOSSIP_SYNC_SERVER_CACHES_PATH", &cache_path)
+		.env("RAPID_GOSSIP_SYNC_SERVER_DB_HOST", "/nonexistent/rgs_exit_codes")
+		.env("BITCOIN_REST_DOMAIN", "127.0.0.1")
+		.env("BITCOIN_REST_PORT", "1")
+		.env("LN_PEERS", PEER);
+	for (variable, value) in overrides {
+		command.env(variable, value);
+	}
+	let output = command.output().expect("failed to run the server binary");
+	let _ = std::fs::remove_dir_all(&cache_path);
+	output
+}
+
+#[test]
+fn test_config_error_exit_code() {
+	// an unreachable database doesn't matter while the configuration itself is invalid
+	let output = run_server("config", &[("LN_PEERS", "")]);
+	assert_eq!(output.status.code(), Some(EXIT_CONFIG_ERROR), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn test_unreachable_database_exit_code() {
+	let output = run_server("database", &[]);
+	assert_eq!(output.status.code(), Some(EXIT_DATABASE_UNREACHABLE), "stderr: {}", String::from_utf8_lossy(&output.stderr));
+}
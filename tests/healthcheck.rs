@@ -0,0 +1,26 @@
+//! Checks a deployed server with the `rgs-healthcheck` binary, when built with the `integration`
+//! feature. The server's symlinks directory URL is read from `RGS_HEALTHCHECK_SERVER`.
+#![cfg(feature = "integration")]
+
+use std::env;
+use std::process::Command;
+
+#[test]
+fn test_deployed_server_is_healthy() {
+	let server = env::var("RGS_HEALTHCHECK_SERVER").expect("RGS_HEALTHCHECK_SERVER must be set to the server's URL for integration tests");
+	let output = Command::new(env!("CARGO_BIN_EXE_rgs-healthcheck"))
+		.args(["--server", &server])
+		.output()
+		.expect("failed to run the health check binary");
+	assert_eq!(output.status.code(), Some(0), "stdout: {}\nstderr: {}", String::from_utf8_lossy(&output.stdout), String::from_utf8_lossy(&output.stderr));
+}
+
+#[test]
+fn test_unreachable_server() {
+	// nothing listens on port 1
+	let output = Command::new(env!("CARGO_BIN_EXE_rgs-healthcheck"))
+		.args(["--server", "http://127.0.0.1:1/snapshots"])
+		.output()
+		.expect("failed to run the health check binary");
+	assert_eq!(output.status.code(), Some(3));
+}